@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::{benchmark::BenchmarkOptions, test_scenes::TestScene};
+
+pub const USAGE: &str = "\
+Usage: neritigen [--scene NAME] [--obj PATH]
+       neritigen --benchmark [--frames N] [--resolution WIDTHxHEIGHT] [--replay PATH]
+                 [--output PATH] [--scene NAME] [--obj PATH]
+
+--scene draws a built-in test scene instead of the usual one: spheres, room or columns.
+--obj drops a Wavefront OBJ model into the middle of the scene, with the materials of any MTL
+libraries beside it.
+
+--benchmark draws the scene headlessly, once around it, and writes how long each frame took on
+the CPU and each pass on the GPU. Output ending in .json is JSON, and anything else CSV, to stdout
+by default. --replay draws a recording saved with NERITIGEN_RECORD instead of going around the
+scene.";
+
+#[derive(Error, Debug)]
+pub enum ArgsError {
+    #[error("Unexpected argument {0}")]
+    Unexpected(String),
+    #[error("{0} needs a value")]
+    MissingValue(&'static str),
+    #[error("Couldn't parse {value} as the value of {flag}")]
+    Invalid { flag: &'static str, value: String },
+    #[error("{0} only applies with --benchmark")]
+    WithoutBenchmark(&'static str),
+}
+
+#[derive(Clone, Debug)]
+pub struct Args {
+    pub benchmark: Option<BenchmarkOptions>,
+    pub obj: Option<PathBuf>,
+    pub scene: Option<TestScene>, // the usual scene if None
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ArgsError> {
+        let mut args = args.into_iter();
+        let mut benchmark = false;
+        let mut benchmark_options = BenchmarkOptions::default();
+        let mut benchmark_only = None; // the first flag given that needs --benchmark
+        let mut obj = None;
+        let mut scene = None;
+        while let Some(arg) = args.next() {
+            let flag = match arg.as_str() {
+                "--benchmark" => {
+                    benchmark = true;
+                    continue;
+                }
+                "--frames" => "--frames",
+                "--obj" => "--obj",
+                "--output" => "--output",
+                "--replay" => "--replay",
+                "--resolution" => "--resolution",
+                "--scene" => "--scene",
+                _ => return Err(ArgsError::Unexpected(arg)),
+            };
+            let value = args.next().ok_or(ArgsError::MissingValue(flag))?;
+            let invalid = || ArgsError::Invalid {
+                flag,
+                value: value.clone(),
+            };
+            if flag != "--scene" && flag != "--obj" {
+                benchmark_only.get_or_insert(flag);
+            }
+            match flag {
+                "--frames" => {
+                    benchmark_options.frames = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "--obj" => obj = Some(value.into()),
+                "--output" => benchmark_options.output = Some(value.into()),
+                "--replay" => benchmark_options.replay = Some(value.into()),
+                "--scene" => scene = Some(TestScene::from_name(&value).ok_or_else(invalid)?),
+                _ => {
+                    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+                    let parse = |side: &str| side.parse().ok().filter(|&side| side > 0);
+                    let size = parse(width).zip(parse(height)).ok_or_else(invalid)?;
+                    benchmark_options.resolution = [size.0, size.1];
+                }
+            }
+        }
+        match (benchmark, benchmark_only) {
+            (false, Some(flag)) => Err(ArgsError::WithoutBenchmark(flag)),
+            _ => Ok(Self {
+                benchmark: Some(benchmark_options).filter(|_| benchmark),
+                obj,
+                scene,
+            }),
+        }
+    }
+}
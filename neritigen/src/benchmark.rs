@@ -0,0 +1,133 @@
+use std::f32::consts::TAU;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use nalgebra as na;
+use ng_render::{
+    AssetStreamer, Attachment, BenchmarkResults, Camera, ObjModel, Recording, Renderer,
+    RendererError, ReplayError,
+};
+use thiserror::Error;
+
+use crate::{create_scene, set_up_renderer, test_scenes::TestScene};
+
+const DEFAULT_FRAMES: usize = 600;
+const DEFAULT_RESOLUTION: [u32; 2] = [1920, 1080];
+// Between draws, as far as animation's concerned
+const FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+#[derive(Error, Debug)]
+pub enum BenchmarkError {
+    #[error("Renderer error")]
+    Renderer(#[from] RendererError),
+    #[error("Couldn't load the recording")]
+    Replay(#[from] ReplayError),
+    #[error("Couldn't write the results")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchmarkOptions {
+    pub frames: Option<usize>, // all of a recording's by default
+    pub output: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub resolution: [u32; 2],
+}
+
+impl Default for BenchmarkOptions {
+    fn default() -> Self {
+        Self {
+            frames: None,
+            output: None,
+            replay: None,
+            resolution: DEFAULT_RESOLUTION,
+        }
+    }
+}
+
+// Goes around the test scene, if given, rather than the usual one, with the model in the middle
+pub fn run(
+    options: &BenchmarkOptions,
+    test_scene: Option<TestScene>,
+    model: Option<&ObjModel>,
+) -> Result<(), BenchmarkError> {
+    let [width, height] = options.resolution;
+    let mut renderer = Renderer::new_headless(width, height, Default::default())?;
+    renderer.set_deterministic(Some(FRAME_TIME));
+    set_up_renderer(&mut renderer, test_scene);
+
+    let results = match &options.replay {
+        Some(path) => {
+            let recording = Recording::load(path)?;
+            let recorded = recording.frames();
+            let frames = options.frames.unwrap_or(recorded.len()).min(recorded.len());
+            renderer.benchmark(frames, |renderer, frame| recorded[frame].draw(renderer))?
+        }
+        None => {
+            let mut scene = match test_scene {
+                Some(test_scene) => test_scene.create(),
+                None => {
+                    let streamer = AssetStreamer::new(1);
+                    let (mut scene, floor_node, floor) = create_scene(&streamer);
+                    // Rather than drawing a placeholder for however long it takes to generate
+                    while !floor.is_loaded() {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    if let Some(Attachment::Mesh(instance)) =
+                        scene.attachments_mut(floor_node).first_mut()
+                    {
+                        instance.material = Some(floor.get());
+                    }
+                    scene
+                }
+            };
+            if let Some(model) = model {
+                model.add_to(&mut scene, None, na::Matrix4::identity().into());
+            }
+            scene.update();
+            let meshes = scene.mesh_instances();
+            let frames = options.frames.unwrap_or(DEFAULT_FRAMES);
+            renderer.benchmark(frames, |renderer, frame| {
+                renderer.draw(&orbit(frame, frames), &meshes)
+            })?
+        }
+    };
+
+    match &options.output {
+        Some(path) => write(
+            &results,
+            path.extension(),
+            BufWriter::new(File::create(path)?),
+        )?,
+        None => write(&results, None, io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+fn write(
+    results: &BenchmarkResults,
+    extension: Option<&std::ffi::OsStr>,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    if extension.map_or(false, |extension| extension == "json") {
+        results.write_json(&mut writer)?;
+    } else {
+        results.write_csv(&mut writer)?;
+    }
+    writer.flush()
+}
+
+// Once around the scene over the run, from a little above it, looking toward its middle
+fn orbit(frame: usize, frames: usize) -> Camera {
+    let angle = TAU * frame as f32 / frames as f32;
+    let position = na::Vector3::new(3.0 * angle.cos(), 3.0 * angle.sin(), 1.5);
+    let toward = -position.normalize();
+    // Cameras look along +x
+    let rotation =
+        na::UnitQuaternion::from_euler_angles(0.0, -toward.z.asin(), toward.y.atan2(toward.x));
+    let isometry = na::Isometry3::from_parts(position.into(), rotation);
+    Camera::new(isometry.to_homogeneous().into())
+}
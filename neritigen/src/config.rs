@@ -0,0 +1,146 @@
+use std::path::Path;
+use std::{fs, io};
+
+use ng_render::{
+    DisplayMode, PresentModePreference, Renderer, ShadowFilter, ShadowSettings, Upscaling,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use winit::window::Window;
+
+use crate::input::Bindings;
+use crate::player::LookSettings;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Couldn't read or write the config file")]
+    Io(#[from] io::Error),
+    #[error("Couldn't parse the config file")]
+    Ron(#[from] ron::Error),
+}
+
+// Settings kept between runs, saved and loaded as RON. Anything a file leaves out keeps its
+// default.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub window: WindowConfig,
+    pub vsync: Vsync,
+    pub render_scale: Option<f32>, // upscaled from, if any
+    pub shadow_quality: ShadowQuality,
+    pub bindings: Bindings,
+    pub look: LookSettings,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowConfig {
+    pub size: Option<[u32; 2]>, // while windowed; up to the platform if None
+    pub fullscreen: bool,       // borderless
+}
+
+// Which present mode to ask for, from lowest latency to least tearing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Vsync {
+    Off,
+    #[default]
+    Mailbox, // never tears, but still draws as fast as it can
+    On,
+    Adaptive, // only tears when frames are late
+}
+
+impl From<Vsync> for PresentModePreference {
+    fn from(vsync: Vsync) -> Self {
+        match vsync {
+            Vsync::Off => Self::Immediate,
+            Vsync::Mailbox => Self::Mailbox,
+            Vsync::On => Self::Fifo,
+            Vsync::Adaptive => Self::FifoRelaxed,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShadowQuality {
+    Low,
+    Medium,
+    #[default]
+    High, // the renderer's defaults
+}
+
+impl ShadowQuality {
+    const ALL: [Self; 3] = [Self::Low, Self::Medium, Self::High];
+
+    pub fn settings(self) -> ShadowSettings {
+        let high = ShadowSettings::default();
+        match self {
+            Self::Low => ShadowSettings {
+                resolution: 1024,
+                cascade_count: 2,
+                distance: 30.0,
+                point_resolution: 256,
+                point_count: 1,
+                filter: ShadowFilter::Unfiltered,
+                ..high
+            },
+            Self::Medium => ShadowSettings {
+                resolution: 2048,
+                cascade_count: 3,
+                point_resolution: 512,
+                point_count: 2,
+                ..high
+            },
+            Self::High => high,
+        }
+    }
+
+    // None for settings that aren't one of the presets
+    pub fn of(settings: ShadowSettings) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|quality| quality.settings() == settings)
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let mut config: Self = ron::de::from_str(&fs::read_to_string(path)?)?;
+        config.bindings.fill_defaults();
+        Ok(config)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
+        let ron = ron::ser::to_string_pretty(self, Default::default())?;
+        fs::write(path, ron)?;
+        Ok(())
+    }
+
+    pub fn apply(&self, renderer: &mut Renderer) {
+        renderer.set_present_mode(self.vsync.into());
+        renderer.set_upscaling(self.render_scale.map(|render_scale| Upscaling {
+            render_scale,
+            ..Default::default()
+        }));
+        renderer.set_shadow_settings(self.shadow_quality.settings());
+        if self.window.fullscreen {
+            renderer.set_display_mode(DisplayMode::Borderless);
+        }
+    }
+
+    // Takes whatever was changed while running. Shadow settings tweaked into something other
+    // than a preset keep the quality they started at.
+    pub fn update(&mut self, window: &Window, renderer: &Renderer) {
+        let display_mode = renderer.display_mode();
+        self.window.fullscreen = display_mode != DisplayMode::Windowed;
+        let size = window.inner_size();
+        // Minimized windows have no size
+        if !self.window.fullscreen && size.width > 0 && size.height > 0 {
+            self.window.size = Some([size.width, size.height]);
+        }
+        self.render_scale = renderer.upscaling().map(|upscaling| upscaling.render_scale);
+        if let Some(shadow_quality) = ShadowQuality::of(renderer.shadow_settings()) {
+            self.shadow_quality = shadow_quality;
+        }
+    }
+}
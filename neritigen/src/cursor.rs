@@ -0,0 +1,49 @@
+use winit::event::{ElementState, MouseButton, WindowEvent};
+use winit::window::Window;
+
+// Grabs and hides the cursor while the mouse looks around, so it neither leaves the window nor
+// wanders over the UI meanwhile. Where grabbing isn't supported, the cursor's only hidden.
+#[derive(Default)]
+pub struct CursorCapture {
+    captured: bool,
+}
+
+impl CursorCapture {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn is_captured(&self) -> bool {
+        self.captured
+    }
+
+    pub fn capture(&mut self, window: &Window) {
+        if !self.captured {
+            let _ = window.set_cursor_grab(true);
+            window.set_cursor_visible(false);
+            self.captured = true;
+        }
+    }
+
+    pub fn release(&mut self, window: &Window) {
+        if self.captured {
+            let _ = window.set_cursor_grab(false);
+            window.set_cursor_visible(true);
+            self.captured = false;
+        }
+    }
+
+    // Captures on clicks, which should only be those the UI didn't take. Releases on losing
+    // focus, which would otherwise leave the cursor stuck wherever the other window wants it.
+    pub fn handle_window_event(&mut self, window: &Window, event: &WindowEvent) {
+        match event {
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } => self.capture(window),
+            WindowEvent::Focused(false) => self.release(window),
+            _ => (),
+        }
+    }
+}
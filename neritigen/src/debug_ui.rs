@@ -0,0 +1,448 @@
+use std::time::Duration;
+
+use ng_render::{
+    egui, CascadeFit, CullingMode, DepthOfField, DisplayMode, FrameLimit, LightCulling, MotionBlur,
+    Renderer, ShadowFilter, ShadowSettings, Sky, TimeOfDay, TonemappingOperator, Upscaling,
+    MAX_CASCADES, MAX_POINT_SHADOWS,
+};
+
+use crate::input::{Action, Binding, InputState};
+use crate::player::LookSettings;
+
+// Renderer stats and settings, drawn with egui over the scene
+pub struct DebugUi {
+    culling_mode: CullingMode,
+    depth_of_field: Option<DepthOfField>,
+    display_mode: DisplayMode,
+    frame_limit: Option<f32>, // frames per second
+    light_culling: LightCulling,
+    limit_uploads: bool,
+    motion_blur: Option<MotionBlur>,
+    shadow_settings: ShadowSettings,
+    show_bounds: bool,
+    sky: Option<Sky>,
+    time_of_day: Option<TimeOfDay>,
+    tonemapping: TonemappingOperator,
+    upscaling: Option<Upscaling>,
+}
+
+impl DebugUi {
+    // Starting from whatever the renderer was set up with
+    pub fn new(renderer: &Renderer) -> Self {
+        Self {
+            culling_mode: Default::default(),
+            depth_of_field: None,
+            display_mode: renderer.display_mode(),
+            frame_limit: None,
+            light_culling: Default::default(),
+            limit_uploads: false,
+            motion_blur: None,
+            shadow_settings: renderer.shadow_settings(),
+            show_bounds: false,
+            sky: None,
+            time_of_day: None,
+            tonemapping: Default::default(),
+            upscaling: renderer.upscaling(),
+        }
+    }
+
+    pub fn show(
+        &mut self,
+        ctx: &egui::CtxRef,
+        renderer: &mut Renderer,
+        input_state: &mut InputState,
+        look_settings: &mut LookSettings,
+    ) {
+        let stats = renderer.frame_stats();
+        let renderer_stats = renderer.stats();
+        let validation = renderer.validation_enabled();
+        let culling_mode = self.culling_mode;
+        let depth_of_field = self.depth_of_field;
+        let display_mode = self.display_mode;
+        let frame_limit = self.frame_limit;
+        let light_culling = self.light_culling;
+        let limit_uploads = self.limit_uploads;
+        let motion_blur = self.motion_blur;
+        let shadow_settings = self.shadow_settings;
+        let sky = self.sky;
+        // The renderer moves the hour on by itself
+        self.time_of_day = renderer.time_of_day();
+        let time_of_day = self.time_of_day;
+        let tonemapping = self.tonemapping;
+        let upscaling = self.upscaling;
+
+        egui::Window::new("Renderer").show(ctx, |ui| {
+            let ms = |duration: Duration| 1000.0 * duration.as_secs_f32();
+            ui.label(format!("Frame: {:.2} ms", ms(stats.frame_time)));
+            ui.label(format!("CPU: {:.2} ms", ms(stats.cpu_time)));
+            match stats.gpu {
+                Some(gpu) => {
+                    ui.label(format!("GPU: {:.2} ms", ms(gpu.total())));
+                    ui.label(format!(
+                        "  Render targets: {:.2} ms",
+                        ms(gpu.render_targets)
+                    ));
+                    ui.label(format!("  Geometry: {:.2} ms", ms(gpu.geometry)));
+                    ui.label(format!("  Shadow: {:.2} ms", ms(gpu.shadow)));
+                    ui.label(format!(
+                        "  Light clusters: {:.2} ms",
+                        ms(gpu.light_clusters)
+                    ));
+                    ui.label(format!("  Lighting: {:.2} ms", ms(gpu.lighting)));
+                    ui.label(format!("  Water: {:.2} ms", ms(gpu.water)));
+                    ui.label(format!("  Transparency: {:.2} ms", ms(gpu.transparency)));
+                    ui.label(format!("  Debug lines: {:.2} ms", ms(gpu.debug_draw)));
+                    ui.label(format!(
+                        "  Depth of field: {:.2} ms",
+                        ms(gpu.depth_of_field)
+                    ));
+                    ui.label(format!("  Motion blur: {:.2} ms", ms(gpu.motion_blur)));
+                    ui.label(format!("  Tonemapping: {:.2} ms", ms(gpu.tonemapping)));
+                    ui.label(format!("  Upscaling: {:.2} ms", ms(gpu.upscaling)));
+                    ui.label(format!("  UI: {:.2} ms", ms(gpu.ui)));
+                }
+                None => {
+                    ui.label("GPU: unavailable");
+                }
+            }
+            ui.label(format!(
+                "Draws: {} ({} triangles)",
+                renderer_stats.draw_calls, renderer_stats.triangles
+            ));
+            let mib = |bytes: u64| bytes as f32 / (1 << 20) as f32;
+            ui.label(format!(
+                "Memory: {:.1} MiB buffers, {:.1} MiB images",
+                mib(renderer_stats.buffer_memory),
+                mib(renderer_stats.image_memory)
+            ));
+            ui.label(format!(
+                "Swapchain images: {}",
+                renderer_stats.swapchain_images
+            ));
+            ui.label(if validation {
+                "Validation: enabled"
+            } else {
+                "Validation: disabled"
+            });
+
+            ui.separator();
+            self.tonemapping_ui(ui);
+            self.depth_of_field_ui(ui);
+            self.motion_blur_ui(ui);
+            self.upscaling_ui(ui);
+            self.sky_ui(ui);
+            self.time_of_day_ui(ui);
+
+            ui.separator();
+            ui.add(
+                egui::Slider::new(&mut self.shadow_settings.cascade_count, 1..=MAX_CASCADES)
+                    .text("Shadow cascades"),
+            );
+            ui.add(
+                egui::Slider::new(&mut self.shadow_settings.distance, 5.0..=200.0)
+                    .text("Shadow distance"),
+            );
+            ui.add(
+                egui::Slider::new(&mut self.shadow_settings.point_count, 0..=MAX_POINT_SHADOWS)
+                    .text("Point light shadows"),
+            );
+            self.shadow_filter_ui(ui);
+            let mut stable = self.shadow_settings.cascade_fits[0] == CascadeFit::Stable;
+            ui.checkbox(&mut stable, "Stable shadow cascades");
+            self.shadow_settings.cascade_fits = [if stable {
+                CascadeFit::Stable
+            } else {
+                CascadeFit::Tight
+            }; MAX_CASCADES];
+            ui.checkbox(
+                &mut self.shadow_settings.cache_static,
+                "Cache static shadow casters",
+            );
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Culling:");
+                ui.radio_value(&mut self.culling_mode, CullingMode::Cpu, "CPU");
+                ui.radio_value(&mut self.culling_mode, CullingMode::Gpu, "GPU");
+            });
+            ui.checkbox(&mut self.show_bounds, "Show bounds");
+            ui.horizontal(|ui| {
+                ui.label("Lights:");
+                ui.radio_value(
+                    &mut self.light_culling,
+                    LightCulling::Clustered,
+                    "Clustered",
+                );
+                ui.radio_value(
+                    &mut self.light_culling,
+                    LightCulling::StencilVolumes,
+                    "Stencil volumes",
+                );
+            });
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Display:");
+                ui.radio_value(&mut self.display_mode, DisplayMode::Windowed, "Windowed");
+                ui.radio_value(
+                    &mut self.display_mode,
+                    DisplayMode::Borderless,
+                    "Borderless",
+                );
+                ui.radio_value(
+                    &mut self.display_mode,
+                    DisplayMode::Exclusive { refresh_rate: None },
+                    "Exclusive",
+                );
+            });
+            self.frame_limit_ui(ui);
+            if self.frame_limit.is_some() {
+                ui.label(format!("Missed deadlines: {}", stats.missed_deadlines));
+            }
+            ui.checkbox(&mut self.limit_uploads, "Limit uploads to 1 MiB per frame");
+            ui.label(format!("Deferred uploads: {}", stats.deferred_uploads));
+
+            #[cfg(feature = "renderdoc")]
+            if ui.button("Capture frame with RenderDoc").clicked() {
+                renderer.capture_next_frame_with_renderdoc();
+            }
+        });
+
+        if self.shadow_settings != shadow_settings {
+            renderer.set_shadow_settings(self.shadow_settings);
+        }
+        if self.culling_mode != culling_mode {
+            renderer.set_culling_mode(self.culling_mode);
+        }
+        if self.light_culling != light_culling {
+            renderer.set_light_culling(self.light_culling);
+        }
+        if self.tonemapping != tonemapping {
+            renderer.set_tonemapping(self.tonemapping);
+        }
+        if self.depth_of_field != depth_of_field {
+            renderer.set_depth_of_field(self.depth_of_field);
+        }
+        if self.motion_blur != motion_blur {
+            renderer.set_motion_blur(self.motion_blur);
+        }
+        if self.upscaling != upscaling {
+            renderer.set_upscaling(self.upscaling);
+        }
+        if self.sky != sky {
+            renderer.set_sky(self.sky);
+        }
+        if self.time_of_day != time_of_day {
+            renderer.set_time_of_day(self.time_of_day);
+        }
+        if self.display_mode != display_mode {
+            renderer.set_display_mode(self.display_mode);
+        }
+        if self.frame_limit != frame_limit {
+            renderer.set_frame_limit(self.frame_limit.map(FrameLimit::Fps));
+        }
+        if self.limit_uploads != limit_uploads {
+            renderer.set_upload_budget(Some(1 << 20).filter(|_| self.limit_uploads));
+        }
+
+        Self::controls_ui(ctx, input_state, look_settings);
+    }
+
+    // Each action's bindings, and a button to bind it to whatever's pressed next instead
+    fn controls_ui(
+        ctx: &egui::CtxRef,
+        input_state: &mut InputState,
+        look_settings: &mut LookSettings,
+    ) {
+        egui::Window::new("Controls").show(ctx, |ui| {
+            ui.add(
+                egui::Slider::new(&mut look_settings.sensitivity, 0.0001..=0.01)
+                    .logarithmic(true)
+                    .text("Look sensitivity"),
+            );
+            ui.checkbox(&mut look_settings.invert_y, "Invert Y");
+            ui.add(egui::Slider::new(&mut look_settings.smoothing, 0.0..=0.95).text("Smoothing"));
+
+            let rebinding = input_state.rebinding();
+            egui::Grid::new("bindings").show(ui, |ui| {
+                for &action in &Action::ALL {
+                    ui.label(format!("{:?}", action));
+                    let bindings: Vec<_> = input_state
+                        .bindings()
+                        .of(action)
+                        .iter()
+                        .map(|binding| match binding {
+                            Binding::Key(key) => format!("{:?}", key),
+                            Binding::Mouse(button) => format!("{:?} mouse button", button),
+                            Binding::Gamepad(button) => format!("{:?} gamepad button", button),
+                        })
+                        .collect();
+                    ui.label(bindings.join(", "));
+                    // Clicks count too, so there's nothing to click to cancel
+                    if rebinding == Some(action) {
+                        ui.label("Press something...");
+                    } else if ui.button("Rebind").clicked() {
+                        input_state.set_rebinding(Some(action));
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    pub fn show_bounds(&self) -> bool {
+        self.show_bounds
+    }
+
+    // Presets from cheapest to softest
+    fn shadow_filter_ui(&mut self, ui: &mut egui::Ui) {
+        let filters = [
+            ("Unfiltered", ShadowFilter::Unfiltered),
+            ("PCF", ShadowFilter::Pcf),
+            ("Poisson PCF", ShadowFilter::PoissonPcf { radius: 2.0 }),
+            ("PCSS", ShadowFilter::Pcss { sun_radius: 0.02 }),
+        ];
+        let selected = filters
+            .iter()
+            .find(|(_, filter)| *filter == self.shadow_settings.filter)
+            .map_or("", |(name, _)| name);
+
+        egui::ComboBox::from_label("Shadow filter")
+            .selected_text(selected)
+            .show_ui(ui, |ui| {
+                for (name, filter) in filters {
+                    ui.selectable_value(&mut self.shadow_settings.filter, filter, name);
+                }
+            });
+    }
+
+    fn depth_of_field_ui(&mut self, ui: &mut egui::Ui) {
+        let mut enabled = self.depth_of_field.is_some();
+        ui.checkbox(&mut enabled, "Depth of field");
+        self.depth_of_field = match (enabled, self.depth_of_field) {
+            (true, None) => Some(Default::default()),
+            (false, _) => None,
+            (true, depth_of_field) => depth_of_field,
+        };
+
+        if let Some(depth_of_field) = &mut self.depth_of_field {
+            ui.add(
+                egui::Slider::new(&mut depth_of_field.focal_distance, 0.5..=50.0)
+                    .logarithmic(true)
+                    .text("Focal distance"),
+            );
+            ui.add(egui::Slider::new(&mut depth_of_field.aperture, 0.0..=0.05).text("Aperture"));
+        }
+    }
+
+    fn frame_limit_ui(&mut self, ui: &mut egui::Ui) {
+        let mut enabled = self.frame_limit.is_some();
+        ui.checkbox(&mut enabled, "Limit frame rate");
+        self.frame_limit = match (enabled, self.frame_limit) {
+            (true, None) => Some(60.0),
+            (false, _) => None,
+            (true, frame_limit) => frame_limit,
+        };
+
+        if let Some(fps) = &mut self.frame_limit {
+            ui.add(egui::Slider::new(fps, 10.0..=240.0).text("FPS"));
+        }
+    }
+
+    fn motion_blur_ui(&mut self, ui: &mut egui::Ui) {
+        let mut enabled = self.motion_blur.is_some();
+        ui.checkbox(&mut enabled, "Motion blur");
+        self.motion_blur = match (enabled, self.motion_blur) {
+            (true, None) => Some(Default::default()),
+            (false, _) => None,
+            (true, motion_blur) => motion_blur,
+        };
+
+        if let Some(motion_blur) = &mut self.motion_blur {
+            ui.add(egui::Slider::new(&mut motion_blur.shutter, 0.0..=1.0).text("Shutter"));
+        }
+    }
+
+    fn sky_ui(&mut self, ui: &mut egui::Ui) {
+        let mut enabled = self.sky.is_some();
+        ui.checkbox(&mut enabled, "Sky");
+        self.sky = match (enabled, self.sky) {
+            (true, None) => Some(Default::default()),
+            (false, _) => None,
+            (true, sky) => sky,
+        };
+
+        if let Some(sky) = &mut self.sky {
+            ui.add(egui::Slider::new(&mut sky.turbidity, 1.5..=10.0).text("Turbidity"));
+        }
+    }
+
+    fn time_of_day_ui(&mut self, ui: &mut egui::Ui) {
+        let mut enabled = self.time_of_day.is_some();
+        ui.checkbox(&mut enabled, "Time of day");
+        self.time_of_day = match (enabled, self.time_of_day) {
+            (true, None) => Some(Default::default()),
+            (false, _) => None,
+            (true, time_of_day) => time_of_day,
+        };
+
+        if let Some(time_of_day) = &mut self.time_of_day {
+            ui.add(egui::Slider::new(&mut time_of_day.hour, 0.0..=23.99).text("Hour"));
+            ui.add(
+                egui::Slider::new(&mut time_of_day.hours_per_second, 0.0..=2.0)
+                    .text("Hours per second"),
+            );
+        }
+    }
+
+    fn upscaling_ui(&mut self, ui: &mut egui::Ui) {
+        let mut enabled = self.upscaling.is_some();
+        ui.checkbox(&mut enabled, "Upscaling");
+        self.upscaling = match (enabled, self.upscaling) {
+            (true, None) => Some(Default::default()),
+            (false, _) => None,
+            (true, upscaling) => upscaling,
+        };
+
+        if let Some(upscaling) = &mut self.upscaling {
+            ui.add(egui::Slider::new(&mut upscaling.render_scale, 0.5..=1.0).text("Render scale"));
+            ui.add(egui::Slider::new(&mut upscaling.sharpening, 0.0..=1.0).text("Sharpening"));
+        }
+    }
+
+    fn tonemapping_ui(&mut self, ui: &mut egui::Ui) {
+        let mut exposure = match self.tonemapping {
+            TonemappingOperator::Linear { exposure }
+            | TonemappingOperator::Reinhard { exposure }
+            | TonemappingOperator::Aces { exposure } => exposure,
+        };
+        let operators = [
+            ("Linear", TonemappingOperator::Linear { exposure }),
+            ("Reinhard", TonemappingOperator::Reinhard { exposure }),
+            ("ACES", TonemappingOperator::Aces { exposure }),
+        ];
+        let selected = operators
+            .iter()
+            .find(|(_, operator)| *operator == self.tonemapping)
+            .map_or("", |(name, _)| name);
+
+        egui::ComboBox::from_label("Tonemapping")
+            .selected_text(selected)
+            .show_ui(ui, |ui| {
+                for (name, operator) in operators {
+                    ui.selectable_value(&mut self.tonemapping, operator, name);
+                }
+            });
+        ui.add(
+            egui::Slider::new(&mut exposure, 0.1..=10.0)
+                .logarithmic(true)
+                .text("Exposure"),
+        );
+
+        self.tonemapping = match self.tonemapping {
+            TonemappingOperator::Linear { .. } => TonemappingOperator::Linear { exposure },
+            TonemappingOperator::Reinhard { .. } => TonemappingOperator::Reinhard { exposure },
+            TonemappingOperator::Aces { .. } => TonemappingOperator::Aces { exposure },
+        };
+    }
+}
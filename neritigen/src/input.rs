@@ -1,21 +1,206 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Instant;
+
 use nalgebra as na;
-use winit::event::{DeviceEvent, ElementState, Event, VirtualKeyCode, WindowEvent};
+use serde::{Deserialize, Serialize};
+use winit::event::{
+    DeviceEvent, ElementState, Event, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent,
+};
+
+mod gamepad;
+
+pub use gamepad::GamepadSettings;
+use gamepad::{GamepadChange, Gamepads};
+
+// Roughly what a scroll wheel's notch scrolls on platforms that report pixels
+const PIXELS_PER_LINE: f64 = 20.0;
+
+// What the controls do, whatever they're bound to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    Up,
+    Down,
+    Exit,
+    Profiler, // shows or hides it
+}
+
+impl Action {
+    pub const ALL: [Self; 8] = [
+        Self::Forward,
+        Self::Backward,
+        Self::Left,
+        Self::Right,
+        Self::Up,
+        Self::Down,
+        Self::Exit,
+        Self::Profiler,
+    ];
+}
+
+// Look axes are how far to look since last taken. Move axes are where they're held at, -1..1, so
+// only take sticks, and add to the movement actions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Axis {
+    LookX,       // rightwards
+    LookY,       // downwards
+    MoveForward, // like Forward, less Backward
+    MoveLeft,    // like Left, less Right
+    MoveUp,      // like Up, less Down
+}
+
+impl Axis {
+    fn is_look(self) -> bool {
+        matches!(self, Self::LookX | Self::LookY)
+    }
+}
+
+// Something pressed and released
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Binding {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+    Gamepad(gilrs::Button),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AxisBinding {
+    MouseX,
+    MouseY,
+    Wheel, // lines, upwards
+    // Held at -1..1, which look axes turn into a rate, per GamepadSettings::look_sensitivity
+    Stick { axis: gilrs::Axis, inverted: bool },
+}
+
+// What drives each action and axis, kept in the config. Anything can be bound to more than
+// one action, and any action to more than one thing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Bindings {
+    pub actions: BTreeMap<Action, Vec<Binding>>,
+    pub axes: BTreeMap<Axis, Vec<AxisBinding>>,
+    pub gamepad: GamepadSettings,
+}
+
+impl Bindings {
+    // For whatever actions and axes were left out, so files saved before they were added still
+    // load
+    pub fn fill_defaults(&mut self) {
+        let defaults = Self::default();
+        for (action, default) in defaults.actions {
+            self.actions.entry(action).or_insert(default);
+        }
+        for (axis, default) in defaults.axes {
+            self.axes.entry(axis).or_insert(default);
+        }
+    }
+
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        let bindings = self.actions.entry(action).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    // From every action it was bound to
+    pub fn unbind(&mut self, binding: Binding) {
+        for bindings in self.actions.values_mut() {
+            bindings.retain(|&bound| bound != binding);
+        }
+    }
+
+    pub fn bind_axis(&mut self, axis: Axis, binding: AxisBinding) {
+        let bindings = self.axes.entry(axis).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    pub fn of(&self, action: Action) -> &[Binding] {
+        self.actions.get(&action).map_or(&[], Vec::as_slice)
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        use gilrs::{Axis as Stick, Button};
+        use VirtualKeyCode as Key;
+
+        let mut bindings = Self {
+            actions: BTreeMap::new(),
+            axes: BTreeMap::new(),
+            gamepad: Default::default(),
+        };
+        for &(action, binding) in &[
+            (Action::Forward, Binding::Key(Key::W)),
+            (Action::Backward, Binding::Key(Key::S)),
+            (Action::Left, Binding::Key(Key::A)),
+            (Action::Right, Binding::Key(Key::D)),
+            (Action::Up, Binding::Key(Key::Space)),
+            (Action::Up, Binding::Gamepad(Button::South)),
+            (Action::Down, Binding::Key(Key::LControl)),
+            (Action::Down, Binding::Gamepad(Button::East)),
+            (Action::Exit, Binding::Key(Key::Escape)),
+            (Action::Exit, Binding::Gamepad(Button::Start)),
+            (Action::Profiler, Binding::Key(Key::F3)),
+        ] {
+            bindings.bind(action, binding);
+        }
+        // Sticks are up for positive Y
+        let stick = |axis, inverted| AxisBinding::Stick { axis, inverted };
+        for &(axis, binding) in &[
+            (Axis::LookX, AxisBinding::MouseX),
+            (Axis::LookX, stick(Stick::RightStickX, false)),
+            (Axis::LookY, AxisBinding::MouseY),
+            (Axis::LookY, stick(Stick::RightStickY, true)),
+            (Axis::MoveForward, stick(Stick::LeftStickY, false)),
+            (Axis::MoveLeft, stick(Stick::LeftStickX, true)),
+        ] {
+            bindings.bind_axis(axis, binding);
+        }
+        bindings
+    }
+}
 
 #[derive(Default)]
 pub struct InputState {
-    pub mouse: na::Vector2<f64>,
-    pub forward: bool,
-    pub backward: bool,
-    pub left: bool,
-    pub right: bool,
-    pub up: bool,
-    pub down: bool,
-    pub escape: bool,
+    axes: HashMap<Axis, f64>, // look moved since last taken
+    bindings: Bindings,
+    gamepads: Option<Gamepads>,
+    held: HashSet<Binding>,
+    last_poll: Option<Instant>,
+    positions: HashMap<Axis, f64>, // where move axes were held at when last polled
+    pressed: HashSet<Binding>,     // since last taken
+    rebinding: Option<Action>,     // to whatever's pressed next
+    rebinding_to: Option<Binding>, // pressed since rebinding started, bound once released
 }
 
 impl InputState {
-    pub fn new() -> Self {
-        Default::default()
+    pub fn new(bindings: Bindings) -> Self {
+        Self {
+            bindings,
+            gamepads: Gamepads::new(),
+            ..Default::default()
+        }
+    }
+
+    pub fn bindings(&self) -> &Bindings {
+        &self.bindings
+    }
+
+    // Binds the next key or button pressed to the action, in place of what it was bound to, and
+    // takes it from any other action. It's bound once it's released, so it does nothing
+    // meanwhile. None cancels.
+    pub fn set_rebinding(&mut self, action: Option<Action>) {
+        self.rebinding = action;
+        self.rebinding_to = None;
+    }
+
+    pub fn rebinding(&self) -> Option<Action> {
+        self.rebinding
     }
 
     pub fn handle_event<T>(&mut self, event: &Event<T>) {
@@ -28,44 +213,162 @@ impl InputState {
                 event: DeviceEvent::Key(input),
                 ..
             } => {
-                if let Some(var) = input.virtual_keycode.and_then(|k| self.keymap(k)) {
-                    *var = input.state == ElementState::Pressed;
+                if let Some(key) = input.virtual_keycode {
+                    self.press(Binding::Key(key), input.state);
                 }
             }
 
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { state, button, .. },
+                ..
+            } => self.press(Binding::Mouse(*button), *state),
+
+            // Releases go to whichever window has focus by then
+            Event::WindowEvent {
+                event: WindowEvent::Focused(false),
+                ..
+            } => self.held.clear(),
+
+            // Unaccelerated and in the same units everywhere, unlike Motion's axes, which some
+            // backends number differently or report as absolute positions
             Event::DeviceEvent {
-                event: DeviceEvent::Motion { axis: 0, value },
+                event: DeviceEvent::MouseMotion { delta: (x, y) },
                 ..
-            } => self.mouse.x += *value,
+            } => {
+                self.move_axis(AxisBinding::MouseX, *x);
+                self.move_axis(AxisBinding::MouseY, *y);
+            }
 
             Event::DeviceEvent {
-                event: DeviceEvent::Motion { axis: 1, value },
+                event: DeviceEvent::MouseWheel { delta },
                 ..
-            } => self.mouse.y += *value,
+            } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y as f64,
+                    MouseScrollDelta::PixelDelta(position) => position.y / PIXELS_PER_LINE,
+                };
+                self.move_axis(AxisBinding::Wheel, lines);
+            }
 
             _ => (),
         }
     }
 
-    fn keymap(&mut self, key_code: VirtualKeyCode) -> Option<&mut bool> {
-        Some(match key_code {
-            VirtualKeyCode::W => &mut self.forward,
-            VirtualKeyCode::S => &mut self.backward,
-            VirtualKeyCode::A => &mut self.left,
-            VirtualKeyCode::D => &mut self.right,
-            VirtualKeyCode::Space => &mut self.up,
-            VirtualKeyCode::LControl => &mut self.down,
-            VirtualKeyCode::Escape => &mut self.escape,
-            _ => None?,
-        })
+    // Gamepads have no events of their own, so this reads them. Should be called once a tick,
+    // before anything's taken, since sticks look around by how long they've been held.
+    pub fn poll_gamepads(&mut self) {
+        let now = Instant::now();
+        let elapsed = self
+            .last_poll
+            .replace(now)
+            .map_or(0.0, |last_poll| (now - last_poll).as_secs_f64());
+        let gamepads = match &mut self.gamepads {
+            Some(gamepads) => gamepads,
+            None => return,
+        };
+
+        let changes = gamepads.changes();
+        let settings = self.bindings.gamepad;
+        self.positions.clear();
+        for (&axis, bindings) in &self.bindings.axes {
+            for binding in bindings {
+                if let AxisBinding::Stick {
+                    axis: stick,
+                    inverted,
+                } = *binding
+                {
+                    let sign = if inverted { -1.0 } else { 1.0 };
+                    let value = sign * gamepads.value(stick, settings.deadzone) as f64;
+                    if axis.is_look() {
+                        *self.axes.entry(axis).or_default() +=
+                            settings.look_sensitivity * elapsed * value;
+                    } else {
+                        *self.positions.entry(axis).or_default() +=
+                            settings.move_sensitivity * value;
+                    }
+                }
+            }
+        }
+
+        for change in changes {
+            match change {
+                GamepadChange::Button(button, state) => self.press(Binding::Gamepad(button), state),
+                GamepadChange::Disconnected => self
+                    .held
+                    .retain(|binding| !matches!(binding, Binding::Gamepad(_))),
+            }
+        }
+    }
+
+    // Keys come through as both window and device events, so either can arrive twice
+    fn press(&mut self, binding: Binding, state: ElementState) {
+        match state {
+            ElementState::Pressed if self.rebinding.is_some() => {
+                self.rebinding_to.get_or_insert(binding);
+            }
+            // Held keys repeat
+            ElementState::Pressed => {
+                if self.held.insert(binding) {
+                    self.pressed.insert(binding);
+                }
+            }
+            ElementState::Released => {
+                self.held.remove(&binding);
+                if self.rebinding_to == Some(binding) {
+                    self.rebinding_to = None;
+                    if let Some(action) = self.rebinding.take() {
+                        self.bindings.unbind(binding);
+                        self.bindings.actions.insert(action, vec![binding]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn move_axis(&mut self, binding: AxisBinding, value: f64) {
+        for (&axis, bindings) in &self.bindings.axes {
+            if bindings.contains(&binding) {
+                *self.axes.entry(axis).or_default() += value;
+            }
+        }
+    }
+
+    pub fn is_active(&self, action: Action) -> bool {
+        self.bindings
+            .of(action)
+            .iter()
+            .any(|binding| self.held.contains(binding))
+    }
+
+    // Whether it's been pressed since last taken, for actions that happen once per press
+    pub fn take_pressed(&mut self, action: Action) -> bool {
+        let mut pressed = false;
+        for binding in self.bindings.of(action) {
+            pressed |= self.pressed.remove(binding);
+        }
+        pressed
+    }
+
+    pub fn take_axis(&mut self, axis: Axis) -> f64 {
+        self.axes.remove(&axis).unwrap_or(0.0)
+    }
+
+    pub fn take_look(&mut self) -> na::Vector2<f64> {
+        na::Vector2::new(self.take_axis(Axis::LookX), self.take_axis(Axis::LookY))
     }
 
+    // Keys and sticks pushing the same way add up to no more than full tilt
     pub fn movement(&self) -> na::Vector3<f64> {
+        let limit = self.bindings.gamepad.move_sensitivity.max(1.0);
+        let axis = |positive, negative, analog| {
+            let digital = self.is_active(positive) as i8 - self.is_active(negative) as i8;
+            let analog = self.positions.get(&analog).copied().unwrap_or(0.0);
+            (digital as f64 + analog).clamp(-limit, limit)
+        };
         na::Vector3::new(
-            self.forward as i8 - self.backward as i8,
-            self.left as i8 - self.right as i8,
-            self.up as i8 - self.down as i8,
+            axis(Action::Forward, Action::Backward, Axis::MoveForward),
+            axis(Action::Left, Action::Right, Axis::MoveLeft),
+            axis(Action::Up, Action::Down, Axis::MoveUp),
         )
-        .cast()
     }
 }
@@ -0,0 +1,71 @@
+use gilrs::{EventType, Gilrs};
+use serde::{Deserialize, Serialize};
+use winit::event::ElementState;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GamepadSettings {
+    pub deadzone: f32,         // of each stick axis, 0..1
+    pub look_sensitivity: f64, // look per second at full tilt, in the units of mouse motion
+    pub move_sensitivity: f64, // movement at full tilt, where a key's 1
+}
+
+impl Default for GamepadSettings {
+    fn default() -> Self {
+        Self {
+            deadzone: 0.15,
+            look_sensitivity: 500.0,
+            move_sensitivity: 1.0,
+        }
+    }
+}
+
+pub enum GamepadChange {
+    Button(gilrs::Button, ElementState),
+    Disconnected, // anything it held is released
+}
+
+// Every connected gamepad, read as one
+pub struct Gamepads {
+    gilrs: Gilrs,
+}
+
+impl Gamepads {
+    // None where gamepads can't be read
+    pub fn new() -> Option<Self> {
+        Gilrs::new().ok().map(|gilrs| Self { gilrs })
+    }
+
+    // Since last time
+    pub fn changes(&mut self) -> Vec<GamepadChange> {
+        let mut changes = Vec::new();
+        while let Some(event) = self.gilrs.next_event() {
+            changes.push(match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    GamepadChange::Button(button, ElementState::Pressed)
+                }
+                EventType::ButtonReleased(button, _) => {
+                    GamepadChange::Button(button, ElementState::Released)
+                }
+                EventType::Disconnected => GamepadChange::Disconnected,
+                _ => continue,
+            });
+        }
+        changes
+    }
+
+    // Summed over every gamepad, with the deadzone cut out and what's left stretched to fill it
+    pub fn value(&self, axis: gilrs::Axis, deadzone: f32) -> f32 {
+        let value: f32 = self
+            .gilrs
+            .gamepads()
+            .map(|(_, gamepad)| gamepad.value(axis))
+            .sum();
+        let value = value.clamp(-1.0, 1.0);
+        if value.abs() <= deadzone {
+            0.0
+        } else {
+            value.signum() * (value.abs() - deadzone) / (1.0 - deadzone)
+        }
+    }
+}
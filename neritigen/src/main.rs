@@ -1,33 +1,132 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use winit::{
-    event::{Event, WindowEvent},
+    dpi::PhysicalSize,
+    event::{DeviceEvent, Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 
-use ng_render::Renderer;
+use nalgebra as na;
+use ng_render::{
+    egui, AlphaMode, AssetStreamer, Attachment, Camera, EnvironmentMap, Light, LineVertex,
+    Material, MaterialHandle, Mesh, MeshInstance, NodeId, ObjModel, PresentQueuePreference,
+    Renderer, RendererOptions, Scene, Streamed, Texture, Vertex, Water,
+};
 
+mod args;
+mod benchmark;
+mod config;
+mod cursor;
+mod debug_ui;
 mod input;
 mod player;
+mod profiler;
+mod test_scenes;
 
-use input::InputState;
+use args::Args;
+use config::Config;
+use cursor::CursorCapture;
+use debug_ui::DebugUi;
+use input::{Action, InputState};
 use player::Player;
+use profiler::Profiler;
+use test_scenes::TestScene;
 
 fn main() {
     env_logger::init();
 
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, args::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let model = args.obj.as_ref().map(|path| {
+        ObjModel::load(path).unwrap_or_else(|err| {
+            eprintln!("Couldn't load {}: {}", path.display(), err);
+            std::process::exit(1);
+        })
+    });
+    if let Some(options) = &args.benchmark {
+        if let Err(err) = benchmark::run(options, args.scene, model.as_ref()) {
+            eprintln!("Benchmark failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Set NERITIGEN_CONFIG to keep settings somewhere other than neritigen.ron. It's written on
+    // exit, with anything changed while running, unless it couldn't be loaded, so hand edits
+    // gone wrong can still be fixed.
+    let config_path = std::env::var_os("NERITIGEN_CONFIG")
+        .map_or_else(|| PathBuf::from("neritigen.ron"), PathBuf::from);
+    let (mut config, config_loaded) = if Path::new(&config_path).exists() {
+        match Config::load(&config_path) {
+            Ok(config) => (config, true),
+            Err(err) => {
+                log::warn!(
+                    "Couldn't load {}, so using default settings: {:?}",
+                    config_path.display(),
+                    err
+                );
+                (Default::default(), false)
+            }
+        }
+    } else {
+        (Default::default(), true)
+    };
+
     let event_loop = EventLoop::new();
-    let window = WindowBuilder::new()
-        .with_title("Hello, triangle!")
-        .build(&event_loop)
-        .unwrap();
-    let window = Arc::new(window);
+    let mut window_builder = WindowBuilder::new().with_title("Hello, triangle!");
+    if let Some([width, height]) = config.window.size {
+        window_builder = window_builder.with_inner_size(PhysicalSize::new(width, height));
+    }
+    let window = Arc::new(window_builder.build(&event_loop).unwrap());
+
+    // Set NERITIGEN_SEPARATE_PRESENT_QUEUE to present from a queue family apart from graphics,
+    // since that path goes untested on most devices otherwise. The first draw fails without one.
+    let present_queue = match std::env::var_os("NERITIGEN_SEPARATE_PRESENT_QUEUE") {
+        Some(_) => PresentQueuePreference::Separate,
+        None => PresentQueuePreference::Graphics,
+    };
+    let options = RendererOptions {
+        present_queue,
+        ..Default::default()
+    };
+    let mut renderer = Renderer::new(window.clone(), options).unwrap();
+    config.apply(&mut renderer);
+    // Set NERITIGEN_RECORD to record every frame drawn, saved there on exit, to replay later
+    let record_path = std::env::var_os("NERITIGEN_RECORD").map(PathBuf::from);
+    if record_path.is_some() {
+        renderer.start_recording();
+    }
+    set_up_renderer(&mut renderer, args.scene);
 
-    let mut renderer = Renderer::new(window.clone()).unwrap();
+    let streamer = AssetStreamer::new(1);
+    // Test scenes have no floor waiting on its textures
+    let (mut scene, floor) = match args.scene {
+        Some(test_scene) => (test_scene.create(), None),
+        None => {
+            let (scene, floor_node, floor) = create_scene(&streamer);
+            (scene, Some((floor_node, floor)))
+        }
+    };
+    if let Some(model) = &model {
+        model.add_to(&mut scene, None, na::Matrix4::identity().into());
+    }
+
+    let mut egui_ctx = egui::CtxRef::default();
+    let mut egui_state = egui_winit::State::new(&window);
+    let mut debug_ui = DebugUi::new(&renderer);
+    let mut profiler = Profiler::new();
 
-    let mut input_state = InputState::new();
+    let mut input_state = InputState::new(config.bindings.clone());
+    let mut cursor = CursorCapture::new();
+    let mut look_settings = config.look;
     let mut player = Player::new();
     player.position = [-2.0, -2.0, 2.0].into();
     player.yaw = 0.125;
@@ -37,9 +136,25 @@ fn main() {
     let tick_duration = Duration::new(0, 1_000_000_000 / 60);
 
     event_loop.run(move |event, _event_loop_target, control_flow| {
-        input_state.handle_event(&event);
+        // The mouse only looks around while it's captured, and is free for the UI otherwise
+        let mouse_motion = matches!(
+            event,
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { .. },
+                ..
+            }
+        );
+        if cursor.is_captured() || !mouse_motion {
+            input_state.handle_event(&event);
+        }
 
         match event {
+            Event::WindowEvent {
+                ref event,
+                window_id,
+            } if window_id == window.id()
+                && !cursor.is_captured()
+                && egui_state.on_event(&egui_ctx, event) => {}
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 window_id,
@@ -52,26 +167,300 @@ fn main() {
             } if window_id == window.id() => {
                 window.request_redraw();
             }
+            Event::WindowEvent {
+                ref event,
+                window_id,
+            } if window_id == window.id() => cursor.handle_window_event(&window, event),
+            Event::LoopDestroyed => {
+                config.update(&window, &renderer);
+                config.bindings = input_state.bindings().clone();
+                config.look = look_settings;
+                if !config_loaded {
+                    log::warn!(
+                        "Not saving settings over {}, since it couldn't be loaded",
+                        config_path.display()
+                    );
+                } else if let Err(err) = config.save(&config_path) {
+                    log::warn!("Couldn't save {}: {:?}", config_path.display(), err);
+                }
+                if let (Some(path), Some(recording)) = (&record_path, renderer.stop_recording()) {
+                    if let Err(err) = recording.save(path) {
+                        log::warn!("Couldn't save {}: {:?}", path.display(), err);
+                    }
+                }
+            }
+            Event::Suspended => renderer.suspend(),
+            Event::Resumed => renderer.resume(window.clone()).unwrap(),
             Event::MainEventsCleared => {
-                if Instant::now() > next_tick {
-                    player.turn((0.001 * std::mem::take(&mut input_state.mouse)).cast());
+                if renderer.is_idle() {
+                    // Minimized or suspended, so sleep until resized or resumed, without
+                    // catching up on the ticks missed meanwhile after
+                    *control_flow = ControlFlow::Wait;
+                    next_tick = Instant::now();
+                } else if Instant::now() > next_tick {
+                    input_state.poll_gamepads();
+                    player.look(input_state.take_look().cast(), &look_settings);
                     player.go((0.02 * input_state.movement()).cast());
                     next_tick += tick_duration;
-                    *control_flow = if input_state.escape {
+                    if input_state.take_pressed(Action::Profiler) {
+                        profiler.toggle();
+                    }
+                    // Exiting while the cursor's captured only releases it
+                    let exit = input_state.take_pressed(Action::Exit);
+                    *control_flow = if exit && !cursor.is_captured() {
                         ControlFlow::Exit
                     } else {
                         ControlFlow::Poll
                     };
+                    if exit {
+                        cursor.release(&window);
+                    }
                 } else {
                     window.request_redraw();
                     *control_flow = ControlFlow::WaitUntil(next_tick);
                 }
             }
             Event::RedrawRequested(window_id) if window_id == window.id() => {
-                let player_matrix = player.isometry().to_homogeneous().into();
-                renderer.draw(player_matrix).unwrap();
+                egui_ctx.begin_frame(egui_state.take_egui_input(&window));
+                debug_ui.show(
+                    &egui_ctx,
+                    &mut renderer,
+                    &mut input_state,
+                    &mut look_settings,
+                );
+                profiler.record(renderer.frame_stats());
+                profiler.show(&egui_ctx);
+                let (output, shapes) = egui_ctx.end_frame();
+                egui_state.handle_output(&window, &egui_ctx, output);
+                renderer.set_ui(&egui_ctx, shapes);
+                if let Some((floor_node, floor)) = &floor {
+                    if let Some(Attachment::Mesh(instance)) =
+                        scene.attachments_mut(*floor_node).first_mut()
+                    {
+                        instance.material = Some(floor.get());
+                    }
+                }
+                scene.update();
+                let meshes = scene.mesh_instances();
+                if debug_ui.show_bounds() {
+                    renderer.debug_lines(&bounds_lines(&meshes));
+                }
+
+                let camera = Camera::new(player.isometry().to_homogeneous().into());
+                renderer.draw(&camera, &meshes).unwrap();
             }
             _ => (),
         }
     });
 }
+
+// Lights, sky and water, besides what config applies. Test scenes bring their own lights, and go
+// without water, which would only get in the way of comparing them.
+fn set_up_renderer(renderer: &mut Renderer, test_scene: Option<TestScene>) {
+    renderer.set_environment(create_sky(64));
+    match test_scene {
+        Some(test_scene) => renderer.set_lights(&test_scene.lights()),
+        None => {
+            renderer.set_lights(&create_lights());
+            // A pool for the scene to stand in, just below the floor
+            renderer.set_water(&[Water {
+                min: [-4.0, -4.0].into(),
+                max: [4.0, 4.0].into(),
+                height: -0.3,
+                ..Default::default()
+            }]);
+        }
+    }
+}
+
+// The floor is drawn with a plain placeholder until its textures have been generated, so its node
+// is returned to swap in the real material
+fn create_scene(streamer: &AssetStreamer) -> (Scene, NodeId, Streamed<MaterialHandle>) {
+    let colors = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    let tex_coords = [[2.0, 1.0], [0.0, 0.0], [0.0, 2.0]];
+    let triangle = |positions: [[f32; 3]; 3], normal: [f32; 3]| {
+        let mut vertices: Vec<_> = positions
+            .iter()
+            .zip(colors.iter())
+            .zip(tex_coords.iter())
+            .map(|((&position, &color), &tex_coord)| Vertex {
+                position: position.into(),
+                normal: normal.into(),
+                color: color.into(),
+                tex_coord: tex_coord.into(),
+                tangent: [0.0; 4].into(),
+            })
+            .collect();
+        let indices = vec![0, 1, 2];
+        Vertex::generate_tangents(&mut vertices, &indices);
+        Arc::new(Mesh::new(vertices, indices))
+    };
+
+    let horizontal = triangle(
+        [[1.0, 0.0, 0.0], [-1.0, -1.0, 0.0], [-1.0, 1.0, 0.0]],
+        [0.0, 0.0, -1.0],
+    );
+    let vertical = triangle(
+        [[0.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0]],
+        [0.0, 1.0, 0.0],
+    );
+
+    let floor = streamer.load(
+        0,
+        MaterialHandle::new(Material {
+            albedo: [0.6; 3].into(),
+            roughness: 0.7,
+            ..Default::default()
+        }),
+        || {
+            MaterialHandle::new(Material {
+                albedo_texture: Some(Arc::new(create_checkerboard(8))),
+                normal_map: Some(Arc::new(create_bumps(8))),
+                roughness: 0.7,
+                ..Default::default()
+            })
+        },
+    );
+    let wall = MaterialHandle::new(Material {
+        metallic: 1.0,
+        roughness: 0.3,
+        ..Default::default()
+    });
+    let glass = MaterialHandle::new(Material {
+        albedo: [0.6, 0.8, 0.7].into(),
+        alpha: 0.3,
+        alpha_mode: AlphaMode::Blend,
+        roughness: 0.1,
+        ..Default::default()
+    });
+
+    let mut scene = Scene::new();
+    let mut add_mesh = |parent, translation: na::Vector3<f32>, mesh, material| {
+        let node = scene.add_node(parent, na::Matrix4::new_translation(&translation).into());
+        scene.attach(
+            node,
+            Attachment::Mesh(MeshInstance {
+                mesh,
+                material: Some(material),
+                transform: na::Matrix4::identity().into(),
+                previous_transform: None,
+                joint_matrices: None,
+                is_static: true,
+            }),
+        );
+        node
+    };
+    let floor_node = add_mesh(None, na::Vector3::zeros(), horizontal, floor.get());
+    // The glass stands in front of the wall wherever the wall is put
+    let wall_node = add_mesh(None, na::Vector3::zeros(), vertical.clone(), wall);
+    add_mesh(
+        Some(wall_node),
+        na::Vector3::new(0.0, -0.5, 0.0),
+        vertical,
+        glass,
+    );
+    (scene, floor_node, floor)
+}
+
+// Each instance's worldspace bounding box, as yellow lines
+fn bounds_lines(meshes: &[MeshInstance]) -> Vec<LineVertex> {
+    let color = [1.0, 1.0, 0.0].into();
+    meshes
+        .iter()
+        .flat_map(|instance| {
+            let transform = na::Matrix4::from(instance.transform);
+            let (min, max) = instance.mesh.vertices().iter().fold(
+                (
+                    na::Point3::from([f32::INFINITY; 3]),
+                    na::Point3::from([f32::NEG_INFINITY; 3]),
+                ),
+                |(min, max), vertex| {
+                    let position = transform.transform_point(&vertex.position.into());
+                    (min.inf(&position), max.sup(&position))
+                },
+            );
+            let corner = move |i: usize| {
+                let pick = |axis: usize| {
+                    if i & (1 << axis) == 0 {
+                        min[axis]
+                    } else {
+                        max[axis]
+                    }
+                };
+                LineVertex {
+                    position: [pick(0), pick(1), pick(2)].into(),
+                    color,
+                }
+            };
+            // Joins each pair of corners differing along exactly one axis
+            (0..8).flat_map(move |i| {
+                (0..3)
+                    .map(move |axis| (i, i | (1 << axis)))
+                    .filter(|&(i, j)| i != j)
+                    .flat_map(move |(i, j)| [corner(i), corner(j)])
+            })
+        })
+        .collect()
+}
+
+fn create_lights() -> Vec<Light> {
+    vec![
+        Light::Point {
+            position: [0.5, -0.5, 0.5].into(),
+            color: [1.0, 0.5, 0.2].into(),
+            range: 2.0,
+        },
+        Light::Spot {
+            position: [-0.5, 1.0, 1.5].into(),
+            direction: [0.0, -0.5, -1.0].into(),
+            color: [0.2, 0.5, 1.0].into(),
+            range: 3.0,
+            angle: 0.3,
+        },
+    ]
+}
+
+// A dim blue sky, paling toward the horizon, over dark brown ground
+fn create_sky(height: u32) -> EnvironmentMap {
+    let width = 2 * height;
+    let pixels = (0..height)
+        .flat_map(|y| {
+            let elevation = 1.0 - 2.0 * (y as f32 + 0.5) / height as f32;
+            let color = if elevation > 0.0 {
+                let horizon = na::Vector3::new(0.08, 0.09, 0.1);
+                let zenith = na::Vector3::new(0.02, 0.04, 0.1);
+                horizon.lerp(&zenith, elevation.sqrt())
+            } else {
+                na::Vector3::new(0.03, 0.025, 0.02)
+            };
+            (0..width).flat_map(move |_| [color.x, color.y, color.z, 1.0])
+        })
+        .collect();
+    EnvironmentMap::new(width, height, pixels)
+}
+
+fn create_checkerboard(size: u32) -> Texture {
+    let pixels = (0..size)
+        .flat_map(|y| (0..size).map(move |x| x + y))
+        .flat_map(|parity| {
+            let value = if parity % 2 == 0 { 255 } else { 64 };
+            [value, value, value, 255]
+        })
+        .collect();
+    Texture::new(size, size, pixels)
+}
+
+// A shallow bump per checkerboard square, as a tangent-space normal map
+fn create_bumps(size: u32) -> Texture {
+    let resolution = 32 * size;
+    let pixels = (0..resolution)
+        .flat_map(|y| (0..resolution).map(move |x| (x, y)))
+        .flat_map(|(x, y)| {
+            let cell = |coord: u32| 2.0 * ((coord % 32) as f32 + 0.5) / 32.0 - 1.0;
+            let normal = na::Vector3::new(cell(x), cell(y), 2.0).normalize();
+            let encode = |value: f32| (127.5 * (value + 1.0)).round() as u8;
+            [encode(normal.x), encode(normal.y), encode(normal.z), 255]
+        })
+        .collect();
+    Texture::new_linear(resolution, resolution, pixels)
+}
@@ -1,12 +1,35 @@
 use std::f32::consts::TAU;
 
 use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+// How look input, like mouse motion, turns the player
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LookSettings {
+    pub sensitivity: f32, // turns per unit of look
+    pub invert_y: bool,
+    // 0..1; how much of each tick's turn carries over into the next. It all gets turned
+    // eventually, so smoothing only spreads turns out, rather than shrinking them.
+    pub smoothing: f32,
+}
+
+impl Default for LookSettings {
+    fn default() -> Self {
+        Self {
+            sensitivity: 0.001,
+            invert_y: false,
+            smoothing: 0.0,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Player {
     pub yaw: f32,   // 0..1; 0 = +x, 0.25 = +y
     pub pitch: f32, // -0.25..0.25; -0.25 = -z, 0.25 = +z
     pub position: na::Point3<f32>,
+    smoothed_turn: na::Vector2<f32>, // last tick's
 }
 
 impl Player {
@@ -15,12 +38,24 @@ impl Player {
             yaw: 0.0,
             pitch: 0.0,
             position: na::Point3::origin(),
+            smoothed_turn: na::Vector2::zeros(),
+        }
+    }
+
+    // Should be called once a tick, even without any look, so smoothed turns can finish
+    pub fn look(&mut self, look: na::Vector2<f32>, settings: &LookSettings) {
+        let mut turn = settings.sensitivity * look;
+        if settings.invert_y {
+            turn.y = -turn.y;
         }
+        let smoothing = settings.smoothing.clamp(0.0, 1.0);
+        self.smoothed_turn = smoothing * self.smoothed_turn + (1.0 - smoothing) * turn;
+        self.turn(self.smoothed_turn);
     }
 
     pub fn turn(&mut self, direction: na::Vector2<f32>) {
         self.yaw = (self.yaw - direction.x).rem_euclid(1.0);
-        self.pitch = (self.pitch - direction.y).max(-0.25).min(0.25);
+        self.pitch = (self.pitch - direction.y).clamp(-0.25, 0.25);
     }
 
     pub fn rotation(&self) -> na::Rotation3<f32> {
@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use ng_render::{egui, FrameStats};
+
+const HISTORY: usize = 240; // frames graphed
+const GRAPH_SIZE: [f32; 2] = [2.0 * HISTORY as f32, 80.0];
+const GRAPH_MS: f32 = 1000.0 / 30.0; // at the top of the graph
+
+const FRAME_COLOR: egui::Color32 = egui::Color32::from_rgb(90, 90, 90);
+const CPU_COLOR: egui::Color32 = egui::Color32::from_rgb(80, 150, 230);
+const GPU_COLOR: egui::Color32 = egui::Color32::from_rgb(240, 150, 50);
+
+// Recent frame times graphed over each other, with how long each pass of the latest frame took
+// on the GPU, drawn over the corner of the window
+pub struct Profiler {
+    history: VecDeque<FrameStats>, // oldest first
+    visible: bool,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            history: VecDeque::with_capacity(HISTORY),
+            visible: false,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    // Should be called every draw, even while hidden, so the graph's already full once shown
+    pub fn record(&mut self, stats: FrameStats) {
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(stats);
+    }
+
+    pub fn show(&self, ctx: &egui::CtxRef) {
+        let latest = match self.history.back() {
+            Some(latest) if self.visible => latest,
+            _ => return,
+        };
+
+        egui::Area::new("profiler")
+            .anchor(egui::Align2::RIGHT_TOP, [-8.0, 8.0])
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let ms = |duration: Duration| 1000.0 * duration.as_secs_f32();
+                    let fps = 1000.0 / ms(latest.frame_time).max(f32::EPSILON);
+                    ui.horizontal(|ui| {
+                        ui.colored_label(
+                            FRAME_COLOR,
+                            format!("Frame {:.2} ms ({:.0} FPS)", ms(latest.frame_time), fps),
+                        );
+                        ui.colored_label(CPU_COLOR, format!("CPU {:.2} ms", ms(latest.cpu_time)));
+                        let gpu = match latest.gpu {
+                            Some(gpu) => format!("GPU {:.2} ms", ms(gpu.total())),
+                            None => "GPU unavailable".into(),
+                        };
+                        ui.colored_label(GPU_COLOR, gpu);
+                    });
+                    self.graph_ui(ui);
+                    if let Some(gpu) = latest.gpu {
+                        ui.separator();
+                        let total = ms(gpu.total()).max(f32::EPSILON);
+                        egui::Grid::new("passes").show(ui, |ui| {
+                            for &(name, time) in &gpu.passes() {
+                                ui.label(name);
+                                ui.add(
+                                    egui::ProgressBar::new(ms(time) / total)
+                                        .desired_width(GRAPH_SIZE[0] / 2.0)
+                                        .text(format!("{:.2} ms", ms(time))),
+                                );
+                                ui.end_row();
+                            }
+                        });
+                    }
+                });
+            });
+    }
+
+    // A column per frame, newest on the right, with its frame time behind its CPU and GPU times.
+    // Lines mark 60 and 30 FPS.
+    fn graph_ui(&self, ui: &mut egui::Ui) {
+        let (response, painter) = ui.allocate_painter(GRAPH_SIZE.into(), egui::Sense::hover());
+        let rect = response.rect;
+        let column_width = rect.width() / HISTORY as f32;
+        let y = |duration: Duration| {
+            let ms = 1000.0 * duration.as_secs_f32();
+            rect.bottom() - rect.height() * (ms / GRAPH_MS).min(1.0)
+        };
+
+        painter.rect_filled(rect, 0.0, egui::Color32::from_black_alpha(128));
+        let first_column = HISTORY - self.history.len();
+        for (index, stats) in self.history.iter().enumerate() {
+            let left = rect.left() + (first_column + index) as f32 * column_width;
+            let column = |top: f32, fraction: f32, color: egui::Color32| {
+                let x = left + column_width * (1.0 - fraction) / 2.0;
+                let min = egui::pos2(x, top);
+                let max = egui::pos2(x + column_width * fraction, rect.bottom());
+                painter.rect_filled(egui::Rect::from_min_max(min, max), 0.0, color);
+            };
+            column(y(stats.frame_time), 1.0, FRAME_COLOR);
+            column(y(stats.cpu_time), 0.5, CPU_COLOR);
+            if let Some(gpu) = stats.gpu {
+                let top = y(gpu.total());
+                painter.line_segment(
+                    [egui::pos2(left, top), egui::pos2(left + column_width, top)],
+                    (2.0, GPU_COLOR),
+                );
+            }
+        }
+
+        for &fps in &[60.0, 30.0] {
+            let top = y(Duration::from_secs_f32(1.0 / fps));
+            painter.line_segment(
+                [egui::pos2(rect.left(), top), egui::pos2(rect.right(), top)],
+                (1.0, egui::Color32::from_white_alpha(64)),
+            );
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::f32::consts::{PI, TAU};
+use std::sync::Arc;
+
+use nalgebra as na;
+use ng_render::{
+    Attachment, Light, Material, MaterialHandle, Mesh, MeshInstance, NodeId, Scene, Vertex,
+};
+
+// Built-in scenes that never change, for checking changes to lighting, shadows and materials by
+// eye, or against earlier screenshots. Each brings its own lights.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestScene {
+    Spheres, // metallic across, rough toward the back
+    Room,    // like a Cornell box, lit from the ceiling
+    Columns, // columns of varying heights shadowing each other and the floor
+}
+
+impl TestScene {
+    pub const ALL: [Self; 3] = [Self::Spheres, Self::Room, Self::Columns];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Spheres => "spheres",
+            Self::Room => "room",
+            Self::Columns => "columns",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|scene| scene.name() == name)
+    }
+
+    pub fn create(self) -> Scene {
+        let mut scene = Scene::new();
+        match self {
+            Self::Spheres => create_spheres(&mut scene),
+            Self::Room => create_room(&mut scene),
+            Self::Columns => create_columns(&mut scene),
+        }
+        scene
+    }
+
+    pub fn lights(self) -> Vec<Light> {
+        match self {
+            Self::Spheres => vec![
+                Light::Point {
+                    position: [-1.5, -1.5, 1.0].into(),
+                    color: [1.0, 0.9, 0.8].into(),
+                    range: 4.0,
+                },
+                Light::Point {
+                    position: [1.5, 1.5, 1.0].into(),
+                    color: [0.4, 0.5, 0.8].into(),
+                    range: 4.0,
+                },
+            ],
+            Self::Room => vec![Light::Point {
+                position: [0.0, 0.0, 1.8].into(),
+                color: [1.0, 0.9, 0.75].into(),
+                range: 4.0,
+            }],
+            Self::Columns => vec![
+                Light::Point {
+                    position: [0.5, 0.5, 0.4].into(),
+                    color: [1.0, 0.5, 0.2].into(),
+                    range: 2.5,
+                },
+                Light::Spot {
+                    position: [-2.5, -2.5, 2.5].into(),
+                    direction: [1.0, 1.0, -1.0].into(),
+                    color: [0.3, 0.6, 1.0].into(),
+                    range: 6.0,
+                    angle: 0.4,
+                },
+            ],
+        }
+    }
+}
+
+// Five by five, from dielectric on the left to metal on the right, and from smooth at the front to
+// rough at the back
+fn create_spheres(scene: &mut Scene) {
+    add_mesh(
+        scene,
+        na::Vector3::zeros(),
+        quad(
+            [-2.0, -2.0, 0.0].into(),
+            [4.0, 0.0, 0.0].into(),
+            [0.0, 4.0, 0.0].into(),
+        ),
+        plain([0.5; 3], 0.8),
+    );
+    let sphere = sphere(0.25, 32, 16);
+    for row in 0..5 {
+        for column in 0..5 {
+            let material = MaterialHandle::new(Material {
+                albedo: [1.0, 0.78, 0.34].into(),
+                metallic: column as f32 / 4.0,
+                // Perfectly smooth highlights are too small to see
+                roughness: (row as f32 / 4.0).max(0.05),
+                ..Default::default()
+            });
+            let translation =
+                na::Vector3::new(0.6 * (column as f32 - 2.0), 0.6 * (row as f32 - 2.0), 0.25);
+            add_mesh(scene, translation, sphere.clone(), material);
+        }
+    }
+}
+
+// Two metres on a side, open toward -y, with a red wall on the left and a green one on the right,
+// and a tall and a short box inside
+fn create_room(scene: &mut Scene) {
+    let white = plain([0.73; 3], 0.9);
+    let walls = [
+        // Floor, ceiling and back
+        ([-1.0, -1.0, 0.0], [2.0, 0.0, 0.0], [0.0, 2.0, 0.0], &white),
+        ([-1.0, 1.0, 2.0], [2.0, 0.0, 0.0], [0.0, -2.0, 0.0], &white),
+        ([-1.0, 1.0, 0.0], [2.0, 0.0, 0.0], [0.0, 0.0, 2.0], &white),
+    ];
+    for &(corner, u, v, material) in &walls {
+        let mesh = quad(corner.into(), u.into(), v.into());
+        add_mesh(scene, na::Vector3::zeros(), mesh, material.clone());
+    }
+    let red = quad(
+        [-1.0, -1.0, 0.0].into(),
+        [0.0, 2.0, 0.0].into(),
+        [0.0, 0.0, 2.0].into(),
+    );
+    add_mesh(
+        scene,
+        na::Vector3::zeros(),
+        red,
+        plain([0.63, 0.06, 0.05], 0.9),
+    );
+    let green = quad(
+        [1.0, 1.0, 0.0].into(),
+        [0.0, -2.0, 0.0].into(),
+        [0.0, 0.0, 2.0].into(),
+    );
+    add_mesh(
+        scene,
+        na::Vector3::zeros(),
+        green,
+        plain([0.14, 0.45, 0.09], 0.9),
+    );
+
+    // Glows where the light is, just below the ceiling
+    let lamp = MaterialHandle::new(Material {
+        albedo: [0.0; 3].into(),
+        emissive: [4.0, 3.6, 3.0].into(),
+        ..Default::default()
+    });
+    let panel = quad(
+        [-0.25, 0.25, 1.99].into(),
+        [0.5, 0.0, 0.0].into(),
+        [0.0, -0.5, 0.0].into(),
+    );
+    add_mesh(scene, na::Vector3::zeros(), panel, lamp);
+
+    let boxes = [
+        ([-0.35, 0.3, 0.0], [0.6, 0.6, 1.2], 0.3),
+        ([0.35, -0.3, 0.0], [0.6, 0.6, 0.6], -0.3),
+    ];
+    for &(position, size, angle) in &boxes {
+        let node = scene.add_node(
+            None,
+            na::Isometry3::new(position.into(), na::Vector3::z() * angle)
+                .to_homogeneous()
+                .into(),
+        );
+        attach_mesh(scene, node, cuboid(size.into()), white.clone());
+    }
+}
+
+// Nine by nine on a floor, their heights rising and falling across it
+fn create_columns(scene: &mut Scene) {
+    add_mesh(
+        scene,
+        na::Vector3::zeros(),
+        quad(
+            [-3.0, -3.0, 0.0].into(),
+            [6.0, 0.0, 0.0].into(),
+            [0.0, 6.0, 0.0].into(),
+        ),
+        plain([0.6; 3], 0.7),
+    );
+    let stone = plain([0.8, 0.75, 0.7], 0.6);
+    for row in 0..9 {
+        for column in 0..9 {
+            let (x, y) = (0.6 * (column as f32 - 4.0), 0.6 * (row as f32 - 4.0));
+            // Leaves the middle clear for the point light
+            if x.abs() < 0.9 && y.abs() < 0.9 {
+                continue;
+            }
+            let height = 0.8 + 0.6 * (1.3 * x).sin() * (0.9 * y).cos();
+            let mesh = cuboid([0.15, 0.15, height].into());
+            add_mesh(scene, na::Vector3::new(x, y, 0.0), mesh, stone.clone());
+        }
+    }
+}
+
+fn plain(albedo: [f32; 3], roughness: f32) -> MaterialHandle {
+    MaterialHandle::new(Material {
+        albedo: albedo.into(),
+        roughness,
+        ..Default::default()
+    })
+}
+
+fn add_mesh(
+    scene: &mut Scene,
+    translation: na::Vector3<f32>,
+    mesh: Arc<Mesh>,
+    material: MaterialHandle,
+) {
+    let node = scene.add_node(None, na::Matrix4::new_translation(&translation).into());
+    attach_mesh(scene, node, mesh, material);
+}
+
+fn attach_mesh(scene: &mut Scene, node: NodeId, mesh: Arc<Mesh>, material: MaterialHandle) {
+    scene.attach(
+        node,
+        Attachment::Mesh(MeshInstance {
+            mesh,
+            material: Some(material),
+            transform: na::Matrix4::identity().into(),
+            previous_transform: None,
+            joint_matrices: None,
+            is_static: true,
+        }),
+    );
+}
+
+fn vertex(position: na::Point3<f32>, normal: na::Vector3<f32>, tex_coord: [f32; 2]) -> Vertex {
+    Vertex {
+        position: position.into(),
+        normal: normal.into(),
+        color: [1.0; 3].into(),
+        tex_coord: tex_coord.into(),
+        tangent: [0.0; 4].into(),
+    }
+}
+
+fn mesh(mut vertices: Vec<Vertex>, indices: Vec<u32>) -> Arc<Mesh> {
+    Vertex::generate_tangents(&mut vertices, &indices);
+    Arc::new(Mesh::new(vertices, indices))
+}
+
+// Facing along u × v
+fn quad(corner: na::Point3<f32>, u: na::Vector3<f32>, v: na::Vector3<f32>) -> Arc<Mesh> {
+    let (vertices, indices) = quad_parts(corner, u, v, 0);
+    mesh(vertices, indices)
+}
+
+fn quad_parts(
+    corner: na::Point3<f32>,
+    u: na::Vector3<f32>,
+    v: na::Vector3<f32>,
+    first_index: u32,
+) -> (Vec<Vertex>, Vec<u32>) {
+    let normal = u.cross(&v).normalize();
+    let vertices = vec![
+        vertex(corner, normal, [0.0, 0.0]),
+        vertex(corner + u, normal, [1.0, 0.0]),
+        vertex(corner + u + v, normal, [1.0, 1.0]),
+        vertex(corner + v, normal, [0.0, 1.0]),
+    ];
+    let indices = [0, 1, 2, 0, 2, 3]
+        .iter()
+        .map(|index| first_index + index)
+        .collect();
+    (vertices, indices)
+}
+
+// Standing on the origin, centred over it
+fn cuboid(size: na::Vector3<f32>) -> Arc<Mesh> {
+    let min = na::Point3::new(-size.x / 2.0, -size.y / 2.0, 0.0);
+    let max = na::Point3::new(size.x / 2.0, size.y / 2.0, size.z);
+    let (x, y, z) = (
+        size.x * na::Vector3::x(),
+        size.y * na::Vector3::y(),
+        size.z * na::Vector3::z(),
+    );
+    let faces = [
+        (min, y, x),
+        (min, x, z),
+        (min, z, y),
+        (max, -x, -y),
+        (max, -z, -x),
+        (max, -y, -z),
+    ];
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for &(corner, u, v) in &faces {
+        let (face_vertices, face_indices) = quad_parts(corner, u, v, vertices.len() as u32);
+        vertices.extend(face_vertices);
+        indices.extend(face_indices);
+    }
+    mesh(vertices, indices)
+}
+
+// A UV sphere around the origin
+fn sphere(radius: f32, segments: u32, rings: u32) -> Arc<Mesh> {
+    let vertices = (0..=rings)
+        .flat_map(|ring| (0..=segments).map(move |segment| (ring, segment)))
+        .map(|(ring, segment)| {
+            let (u, v) = (segment as f32 / segments as f32, ring as f32 / rings as f32);
+            let (polar, azimuth) = (PI * v, TAU * u);
+            let normal = na::Vector3::new(
+                polar.sin() * azimuth.cos(),
+                polar.sin() * azimuth.sin(),
+                polar.cos(),
+            );
+            vertex((radius * normal).into(), normal, [u, v])
+        })
+        .collect();
+    let indices = (0..rings)
+        .flat_map(|ring| (0..segments).map(move |segment| (ring, segment)))
+        .flat_map(|(ring, segment)| {
+            let above = ring * (segments + 1) + segment;
+            let below = above + segments + 1;
+            [above, below, above + 1, above + 1, below, below + 1]
+        })
+        .collect();
+    mesh(vertices, indices)
+}
@@ -0,0 +1,10 @@
+// What alpha has to reach for a fragment to be kept. A negative cutoff asks for screen-space
+// noise instead, so overlapping dithered surfaces blend without needing to be sorted.
+float alpha_threshold(float cutoff) {
+    if (cutoff >= 0) {
+        return cutoff;
+    }
+    // Interleaved gradient noise, in (0, 1] so that zero alpha is always discarded
+    vec2 pixel = floor(gl_FragCoord.xy);
+    return 1.0 - fract(52.9829189 * fract(dot(pixel, vec2(0.06711056, 0.00583715))));
+}
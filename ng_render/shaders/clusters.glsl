@@ -0,0 +1,28 @@
+// Clustered light lists, shared by light-clusters.comp and lighting.frag. Matches the cluster
+// constants in lighting.rs.
+
+// Screen tiles across, down, then depth slices
+const uvec3 CLUSTER_GRID = uvec3(16, 9, 24);
+const uint CLUSTER_COUNT = CLUSTER_GRID.x * CLUSTER_GRID.y * CLUSTER_GRID.z;
+const uint MAX_CLUSTER_LIGHTS = 64;
+
+// Slices are spaced evenly in log depth, from the near plane out to this depth. Surfaces past it
+// share the last slice, which only holds lights that reach its far edge.
+const float CLUSTER_MIN_DEPTH = 1e-4;
+
+struct Cluster {
+    uint light_count;
+    uint light_indices[MAX_CLUSTER_LIGHTS];
+};
+
+// Screen depth of the near edge of a slice, with depth reversed
+float cluster_slice_depth(uint slice) {
+    return pow(CLUSTER_MIN_DEPTH, float(slice) / CLUSTER_GRID.z);
+}
+
+uint cluster_index(vec3 screen_position) {
+    vec2 tile = clamp((0.5 * screen_position.xy + vec2(0.5)) * CLUSTER_GRID.xy, vec2(0), CLUSTER_GRID.xy - vec2(1));
+    float slice = log(max(screen_position.z, CLUSTER_MIN_DEPTH)) / log(CLUSTER_MIN_DEPTH) * CLUSTER_GRID.z;
+    uvec3 cell = uvec3(tile, min(slice, CLUSTER_GRID.z - 1));
+    return cell.x + CLUSTER_GRID.x * (cell.y + CLUSTER_GRID.y * cell.z);
+}
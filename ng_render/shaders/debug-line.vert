@@ -0,0 +1,15 @@
+#version 450
+
+layout(push_constant) uniform ViewBuffer {
+    mat4 view;
+} view_buffer;
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 color;
+
+layout(location = 0) out vec3 vertColor;
+
+void main() {
+    gl_Position = view_buffer.view * vec4(position, 1);
+    vertColor = color;
+}
@@ -0,0 +1,50 @@
+#version 450
+
+// Taps are spread over the disc in a golden-angle spiral, which approximates a round bokeh
+const uint SAMPLE_COUNT = 48;
+const float GOLDEN_ANGLE = 2.39996323;
+const float MAX_RADIUS = 32; // pixels, bounding how sparse the taps get
+
+layout(set = 0, binding = 0) uniform sampler2D light;
+layout(set = 0, binding = 1) uniform sampler2D depth;
+
+layout(push_constant) uniform DepthOfFieldBuffer {
+    vec4 inverse_distance;
+    float focal_distance;
+    float aperture;
+} depth_of_field_buffer;
+
+layout(location = 0) in vec2 ndc;
+layout(location = 0) out vec4 fragColor;
+
+// Radius in pixels of the circle of confusion, from the thin lens equation
+float circle_of_confusion(vec2 uv) {
+    vec4 coefficients = depth_of_field_buffer.inverse_distance;
+    float d = texture(depth, uv).r;
+    float inverse_distance = (coefficients.x * d + coefficients.y) / (coefficients.z * d + coefficients.w);
+    float coc = depth_of_field_buffer.aperture * abs(1 - depth_of_field_buffer.focal_distance * inverse_distance);
+    return min(coc, MAX_RADIUS);
+}
+
+void main() {
+    vec2 uv = 0.5 * ndc + 0.5;
+    vec2 texel = 1.0 / vec2(textureSize(light, 0));
+    float radius = circle_of_confusion(uv);
+
+    vec4 sum = texture(light, uv);
+    float weight_sum = 1;
+    if (radius >= 0.5) {
+        for (uint i = 0; i < SAMPLE_COUNT; ++i) {
+            float distance = radius * sqrt((float(i) + 0.5) / float(SAMPLE_COUNT));
+            float angle = float(i) * GOLDEN_ANGLE;
+            vec2 sample_uv = uv + distance * vec2(cos(angle), sin(angle)) * texel;
+
+            // Taps only count if they'd be blurred far enough to reach here themselves, so sharp
+            // things behind don't bleed into blurry things in front
+            float weight = clamp(circle_of_confusion(sample_uv) - distance + 1, 0, 1);
+            sum += weight * texture(light, sample_uv);
+            weight_sum += weight;
+        }
+    }
+    fragColor = sum / weight_sum;
+}
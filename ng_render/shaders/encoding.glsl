@@ -0,0 +1,45 @@
+#include "srgb.glsl"
+
+// How colors are encoded for the output image, matching OutputEncoding in shared.rs
+const uint ENCODING_SRGB = 0;        // the output format does the encoding
+const uint ENCODING_SRGB_SHADER = 1; // UNORM formats need encoding here
+const uint ENCODING_HDR10 = 2;       // BT.2020 primaries with the PQ curve
+const uint ENCODING_SCRGB = 3;       // linear, with 1 as 80 nits
+
+layout(constant_id = 0) const uint ENCODING = 0;
+
+const float PAPER_WHITE_NITS = 203.0; // per ITU-R BT.2408
+const float PEAK_NITS = 1000.0;
+
+// How far above paper white the output can go
+float headroom() {
+    return ENCODING == ENCODING_HDR10 || ENCODING == ENCODING_SCRGB
+        ? PEAK_NITS / PAPER_WHITE_NITS
+        : 1.0;
+}
+
+vec3 pq_from_nits(vec3 nits) {
+    vec3 y = pow(clamp(nits / 10000.0, 0, 1), vec3(0.1593017578125));
+    return pow((0.8359375 + 18.8515625 * y) / (1 + 18.6875 * y), vec3(78.84375));
+}
+
+// Columns are the BT.709 primaries in BT.2020
+const mat3 BT2020_FROM_BT709 = mat3(
+    0.6274, 0.0691, 0.0164,
+    0.3293, 0.9195, 0.0880,
+    0.0433, 0.0114, 0.8956
+);
+
+// Takes linear BT.709, with 1 as paper white
+vec3 encode_output(vec3 color) {
+    switch (ENCODING) {
+        case ENCODING_SRGB_SHADER:
+            return srgb_from_linear(clamp(color, 0, 1));
+        case ENCODING_HDR10:
+            return pq_from_nits(PAPER_WHITE_NITS * BT2020_FROM_BT709 * max(color, 0));
+        case ENCODING_SCRGB:
+            return PAPER_WHITE_NITS / 80.0 * color;
+        default:
+            return color;
+    }
+}
@@ -0,0 +1,45 @@
+#version 450
+
+#include "light.glsl"
+
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput diffuse;
+layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput normal;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput depth;
+layout(input_attachment_index = 3, set = 0, binding = 6) uniform subpassInput metallicRoughness;
+layout(set = 0, binding = 8) uniform sampler2DArray pointShadows; // six faces per cubemap
+
+layout(std140, set = 0, binding = 4) readonly buffer LightList {
+    Light lights[];
+} light_list;
+
+layout(push_constant) uniform LightVolumeBuffer {
+    mat4 world_to_screen;
+    mat4 screen_to_world;
+} light_volume_buffer;
+
+layout(location = 0) in vec4 clipPosition;
+layout(location = 1) flat in int lightIndex;
+layout(location = 0) out vec3 fragColor;
+
+void main() {
+    Light light = light_list.lights[lightIndex];
+
+    vec2 ndc = clipPosition.xy / clipPosition.w;
+    vec4 position = light_volume_buffer.screen_to_world * vec4(ndc, subpassLoad(depth).r, 1);
+
+    // The eye projects to w = 0, so it's what screen_to_world maps the point at infinity along z to
+    vec4 eye = light_volume_buffer.screen_to_world * vec4(0, 0, 1, 0);
+    vec3 view_direction = normalize(eye.xyz / eye.w - position.xyz / position.w);
+
+    vec2 metallic_roughness = subpassLoad(metallicRoughness).rg;
+    fragColor = local_light(
+        pointShadows,
+        light,
+        position.xyz / position.w,
+        normalize(2 * subpassLoad(normal).rgb - vec3(1)),
+        view_direction,
+        subpassLoad(diffuse).rgb,
+        metallic_roughness.x,
+        metallic_roughness.y
+    );
+}
@@ -0,0 +1,37 @@
+#version 450
+
+#include "light.glsl"
+
+layout(std140, set = 0, binding = 4) readonly buffer LightList {
+    Light lights[];
+} light_list;
+
+layout(push_constant) uniform LightVolumeBuffer {
+    mat4 world_to_screen;
+    mat4 screen_to_world;
+} light_volume_buffer;
+
+layout(location = 0) out vec4 clipPosition;
+layout(location = 1) flat out int lightIndex;
+
+// Corners of a cube, with bits 0-2 of each index selecting +x, +y and +z respectively. Faces are
+// counterclockwise when seen from outside.
+const int corners[36] = int[](
+    0, 4, 6, 0, 6, 2,
+    1, 7, 5, 1, 3, 7,
+    0, 1, 5, 0, 5, 4,
+    2, 7, 3, 2, 6, 7,
+    0, 2, 3, 0, 3, 1,
+    4, 5, 7, 4, 7, 6
+);
+
+void main() {
+    Light light = light_list.lights[gl_InstanceIndex];
+    int corner = corners[gl_VertexIndex];
+    vec3 offset = 2 * vec3(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1) - vec3(1);
+    vec3 position = light.position_range.xyz + light.position_range.w * offset;
+
+    clipPosition = light_volume_buffer.world_to_screen * vec4(position, 1);
+    gl_Position = clipPosition;
+    lightIndex = gl_InstanceIndex;
+}
@@ -0,0 +1,97 @@
+// Point and spot light shading, shared by lighting.frag and light-volume.frag. Light matches
+// LightData in lighting.rs.
+
+const float PI = 3.14159265;
+
+struct Light {
+    vec4 position_range;
+    vec4 color;
+    vec4 direction;
+    vec4 cone; // x, y: cosines of the outer and inner edges of the cone
+    vec4 shadow; // x: point shadow cubemap, or negative for none; y: its near
+};
+
+// Cook-Torrance, with a GGX distribution, Schlick-GGX geometry and Schlick's Fresnel
+vec3 brdf(vec3 n, vec3 v, vec3 l, vec3 albedo, float metallic, float roughness) {
+    vec3 h = normalize(v + l);
+    float n_dot_l = max(dot(n, l), 0);
+    float n_dot_v = max(dot(n, v), 1e-4);
+    float n_dot_h = max(dot(n, h), 0);
+    float v_dot_h = max(dot(v, h), 0);
+
+    float alpha = max(roughness * roughness, 1e-3);
+    float alpha_squared = alpha * alpha;
+    float d_denominator = n_dot_h * n_dot_h * (alpha_squared - 1) + 1;
+    float d = alpha_squared / (PI * d_denominator * d_denominator);
+
+    float k = (roughness + 1) * (roughness + 1) / 8;
+    float g = n_dot_l / (n_dot_l * (1 - k) + k) * n_dot_v / (n_dot_v * (1 - k) + k);
+
+    vec3 f0 = mix(vec3(0.04), albedo, metallic);
+    vec3 f = f0 + (vec3(1) - f0) * pow(1 - v_dot_h, 5);
+
+    vec3 specular = d * g * f / max(4 * n_dot_l * n_dot_v, 1e-4);
+    vec3 diffuse = (vec3(1) - f) * (1 - metallic) * albedo / PI;
+    return (diffuse + specular) * n_dot_l;
+}
+
+// Picks a cube face the same way as CUBE_FACES in shadow.rs, then projects onto it like the
+// shadow pass did
+float point_shadow_factor(sampler2DArray point_shadows, Light light, vec3 from_light) {
+    if (light.shadow.x < 0) {
+        return 1;
+    }
+
+    vec3 axis_distances = abs(from_light);
+    int face;
+    vec3 forward;
+    vec3 up;
+    if (axis_distances.x >= axis_distances.y && axis_distances.x >= axis_distances.z) {
+        face = from_light.x > 0 ? 0 : 1;
+        forward = vec3(from_light.x > 0 ? 1 : -1, 0, 0);
+        up = vec3(0, 0, 1);
+    } else if (axis_distances.y >= axis_distances.z) {
+        face = from_light.y > 0 ? 2 : 3;
+        forward = vec3(0, from_light.y > 0 ? 1 : -1, 0);
+        up = vec3(0, 0, 1);
+    } else {
+        face = from_light.z > 0 ? 4 : 5;
+        forward = vec3(0, 0, from_light.z > 0 ? 1 : -1);
+        up = vec3(1, 0, 0);
+    }
+    vec3 left = cross(up, forward);
+
+    float distance = dot(from_light, forward);
+    vec2 ndc = -vec2(dot(from_light, left), dot(from_light, up)) / distance;
+    float geometry_depth = light.shadow.y / distance;
+    float shadow_depth = texture(point_shadows, vec3(0.5 * ndc + vec2(0.5), 6 * light.shadow.x + face)).r;
+
+    // Relative, since depth falls off with distance instead of linearly
+    float shadow_threshold_narrowness = 64;
+    return 1 - clamp(shadow_threshold_narrowness * (shadow_depth - geometry_depth) / geometry_depth, 0, 1);
+}
+
+// Light reflected towards v from a surface at position
+vec3 local_light(
+    sampler2DArray point_shadows,
+    Light light,
+    vec3 position,
+    vec3 n,
+    vec3 v,
+    vec3 albedo,
+    float metallic,
+    float roughness
+) {
+    vec3 to_light = light.position_range.xyz - position;
+    float distance = length(to_light);
+    vec3 light_direction = to_light / distance;
+
+    float range_factor = clamp(1 - distance / light.position_range.w, 0, 1);
+    if (range_factor == 0) {
+        return vec3(0);
+    }
+    float cone_factor = smoothstep(light.cone.x, light.cone.y, dot(-light_direction, light.direction.xyz));
+    float shadow_factor = point_shadow_factor(point_shadows, light, -to_light);
+    vec3 reflected = brdf(n, v, light_direction, albedo, metallic, roughness);
+    return range_factor * range_factor * cone_factor * shadow_factor * PI * light.color.rgb * reflected;
+}
@@ -1,27 +1,155 @@
 #version 450
 
+#include "clusters.glsl"
+#include "light.glsl"
+#include "shadow.glsl"
+
 layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput diffuse;
-layout(input_attachment_index = 0, set = 0, binding = 1) uniform subpassInput normal;
-layout(input_attachment_index = 0, set = 0, binding = 2) uniform subpassInput depth;
-layout(set = 0, binding = 3) uniform sampler2D shadow;
+layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput normal;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput depth;
+layout(set = 0, binding = 3) uniform sampler2DArray shadow;
+layout(set = 0, binding = 9) uniform sampler2DArrayShadow shadowCompare;
+layout(input_attachment_index = 3, set = 0, binding = 6) uniform subpassInput metallicRoughness;
+layout(input_attachment_index = 4, set = 0, binding = 7) uniform subpassInput emissive;
+layout(set = 0, binding = 8) uniform sampler2DArray pointShadows; // six faces per cubemap
+layout(set = 1, binding = 0) uniform samplerCube irradiance;
+layout(set = 1, binding = 1) uniform samplerCube prefiltered; // one mip per roughness step
+layout(set = 1, binding = 2) uniform sampler2D brdfLut;
+layout(set = 1, binding = 3) uniform sampler2D background; // equirectangular, like its source
+
+layout(std140, set = 0, binding = 5) uniform ShadowBuffer {
+    Cascade cascades[MAX_CASCADES];
+    ShadowFilter shadow_filter;
+} shadow_buffer;
+
+layout(std140, set = 0, binding = 4) readonly buffer LightList {
+    Light lights[];
+} light_list;
+
+layout(std430, set = 0, binding = 10) readonly buffer ClusterList {
+    Cluster clusters[];
+} cluster_list;
 
 layout(push_constant) uniform LightBuffer {
-    mat4 screen_to_light;
-    vec4 sunlight_direction;
+    mat4 screen_to_world;
+    vec4 sunlight_direction; // with the radius of the sun's disc in w, or 0 to draw none
+    vec4 sunlight_color;     // scaled by intensity
+    vec4 ambient_color;      // scales the environment's light
+    uint cascade_count;
+    uint transparent_background; // nonzero to leave the background clear, for transparent windows
 } light_buffer;
 
 layout(location = 0) in vec2 ndc;
-layout(location = 0) out vec3 fragColor;
+layout(location = 0) out vec4 fragColor; // alpha is coverage, premultiplying color
+
+// Only visits the lights assigned to this pixel's cluster
+vec3 clustered_lights(vec3 screen_position, vec3 position, vec3 n, vec3 v, vec3 albedo, float metallic, float roughness) {
+    uint cluster = cluster_index(screen_position);
+    vec3 light_sum = vec3(0);
+    for (uint i = 0; i < cluster_list.clusters[cluster].light_count; ++i) {
+        Light light = light_list.lights[cluster_list.clusters[cluster].light_indices[i]];
+        light_sum += local_light(pointShadows, light, position, n, v, albedo, metallic, roughness);
+    }
+    return light_sum;
+}
+
+// Split-sum image-based lighting, with Fresnel that accounts for roughness
+vec3 ambient(vec3 n, vec3 v, vec3 albedo, float metallic, float roughness) {
+    float n_dot_v = max(dot(n, v), 1e-4);
+
+    vec3 f0 = mix(vec3(0.04), albedo, metallic);
+    vec3 f = f0 + (max(vec3(1 - roughness), f0) - f0) * pow(1 - n_dot_v, 5);
+
+    vec3 diffuse = (vec3(1) - f) * (1 - metallic) * albedo * texture(irradiance, n).rgb;
+
+    float lod = roughness * (textureQueryLevels(prefiltered) - 1);
+    vec3 prefiltered_color = textureLod(prefiltered, reflect(-v, n), lod).rgb;
+    vec2 scale_bias = texture(brdfLut, vec2(n_dot_v, roughness)).rg;
+    vec3 specular = prefiltered_color * (f0 * scale_bias.x + scale_bias.y);
+
+    return (diffuse + specular) * light_buffer.ambient_color.rgb;
+}
+
+// What's seen where nothing was drawn, the environment with the sun's disc over it
+vec3 background_light() {
+    // Through the near plane from the eye, which is where screen_to_world maps the point at
+    // infinity along z to
+    vec4 eye = light_buffer.screen_to_world * vec4(0, 0, 1, 0);
+    vec4 near = light_buffer.screen_to_world * vec4(ndc, 1, 1);
+    vec3 direction = normalize(near.xyz / near.w - eye.xyz / eye.w);
+
+    float u = 0.5 - atan(direction.y, direction.x) / (2 * PI);
+    float v = acos(clamp(direction.z, -1, 1)) / PI;
+    vec3 light = textureLod(background, vec2(u, v), 0).rgb;
+
+    // The sun's irradiance, pi times its color like sunlight below, spread over its disc
+    float sun_radius = light_buffer.sunlight_direction.w;
+    float cos_sun_radius = cos(sun_radius);
+    if (sun_radius > 0 && dot(direction, -light_buffer.sunlight_direction.xyz) >= cos_sun_radius) {
+        light += light_buffer.sunlight_color.rgb / (2 * (1 - cos_sun_radius));
+    }
+    return light;
+}
 
 void main() {
-    vec4 position_in_light = light_buffer.screen_to_light * vec4(ndc, subpassLoad(depth).r, 1);
-    vec2 shadow_coords = 0.5 * position_in_light.xy / position_in_light.w + vec2(0.5);
-    float geometry_depth = position_in_light.z / position_in_light.w;
-    float shadow_depth = texture(shadow, shadow_coords, 0.0).r;
-    float shadow_threshold_narrowness = 1024;
-    float shadow_factor = 1 - clamp(shadow_threshold_narrowness * (shadow_depth - geometry_depth), 0, 1);
+    vec4 screen_position = vec4(ndc, subpassLoad(depth).r, 1);
+    // Reverse-Z leaves depth at 0 wherever nothing was drawn
+    if (screen_position.z == 0) {
+        fragColor = light_buffer.transparent_background != 0 ? vec4(0) : vec4(background_light(), 1);
+        return;
+    }
+
+    // Cascades are ordered nearest first, and depth decreases with distance
+    float shadow_factor = 1;
+    for (uint i = 0; i < light_buffer.cascade_count; ++i) {
+        Cascade cascade = shadow_buffer.cascades[i];
+        if (screen_position.z >= cascade.depth_range.x) {
+            shadow_factor = cascade_shadow_factor(
+                shadow,
+                shadowCompare,
+                cascade,
+                i,
+                shadow_buffer.shadow_filter,
+                screen_position
+            );
+            break;
+        }
+    }
+
+    // The eye projects to w = 0, so it's what screen_to_world maps the point at infinity along z to
+    vec4 eye = light_buffer.screen_to_world * vec4(0, 0, 1, 0);
+    vec4 position = light_buffer.screen_to_world * screen_position;
+    vec3 view_direction = normalize(eye.xyz / eye.w - position.xyz / position.w);
 
-    float cosine_factor = clamp(-dot(light_buffer.sunlight_direction.xyz, 2 * subpassLoad(normal).rgb - vec3(1)), 0, 1);
+    vec3 albedo = subpassLoad(diffuse).rgb;
+    vec2 metallic_roughness = subpassLoad(metallicRoughness).rg;
+    vec3 surface_normal = normalize(2 * subpassLoad(normal).rgb - vec3(1));
+    vec3 reflected = brdf(
+        surface_normal,
+        view_direction,
+        -light_buffer.sunlight_direction.xyz,
+        albedo,
+        metallic_roughness.x,
+        metallic_roughness.y
+    );
 
-    fragColor = (0.95 * shadow_factor * cosine_factor + 0.05) * subpassLoad(diffuse).rgb;
+    vec3 ambient_light = ambient(
+        surface_normal,
+        view_direction,
+        albedo,
+        metallic_roughness.x,
+        metallic_roughness.y
+    );
+    vec3 point_light = clustered_lights(
+        screen_position.xyz,
+        position.xyz / position.w,
+        surface_normal,
+        view_direction,
+        albedo,
+        metallic_roughness.x,
+        metallic_roughness.y
+    );
+    // Scaled by pi so a white diffuse surface facing the sun reflects its intensity
+    vec3 sunlight = PI * light_buffer.sunlight_color.rgb * shadow_factor * reflected;
+    fragColor = vec4(sunlight + point_light + ambient_light + subpassLoad(emissive).rgb, 1);
 }
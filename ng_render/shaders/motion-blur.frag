@@ -0,0 +1,50 @@
+#version 450
+
+// Taps are spread evenly along the velocity, centered on the pixel
+const uint SAMPLE_COUNT = 16;
+const float MAX_LENGTH = 32; // pixels, bounding how sparse the taps get
+
+layout(set = 0, binding = 0) uniform sampler2D light;
+layout(set = 0, binding = 1) uniform sampler2D velocity;
+layout(set = 0, binding = 2) uniform sampler2D depth;
+
+layout(push_constant) uniform MotionBlurBuffer {
+    mat4 reprojection; // this frame's screenspace to last frame's
+    float shutter;
+} motion_blur_buffer;
+
+layout(location = 0) in vec2 ndc;
+layout(location = 0) out vec4 fragColor;
+
+// Nothing writes velocity where nothing was drawn, but the background still moves with the camera
+vec2 pixel_velocity(vec2 uv) {
+    if (texture(depth, uv).r > 0) {
+        return texture(velocity, uv).rg;
+    }
+    vec4 previous = motion_blur_buffer.reprojection * vec4(ndc, 0, 1);
+    if (previous.w <= 0) {
+        return vec2(0);
+    }
+    return 0.5 * (ndc - previous.xy / previous.w);
+}
+
+void main() {
+    vec2 uv = 0.5 * ndc + 0.5;
+    vec2 texel = 1.0 / vec2(textureSize(light, 0));
+
+    // Only the part of the frame's motion while the shutter was open smears
+    vec2 blur = motion_blur_buffer.shutter * pixel_velocity(uv);
+    float blur_length = length(blur / texel);
+    if (blur_length < 0.5) {
+        fragColor = texture(light, uv);
+        return;
+    }
+    blur *= min(blur_length, MAX_LENGTH) / blur_length;
+
+    vec4 sum = vec4(0);
+    for (uint i = 0; i < SAMPLE_COUNT; ++i) {
+        float t = (float(i) + 0.5) / float(SAMPLE_COUNT) - 0.5;
+        sum += texture(light, uv - t * blur);
+    }
+    fragColor = sum / float(SAMPLE_COUNT);
+}
@@ -0,0 +1,62 @@
+#version 450
+
+// FidelityFX Super Resolution 1.0's robust contrast-adaptive sharpening. Sharpens with a
+// negative lobe on the 4 neighbours, limited so no channel leaves [0, 1], and backed off where
+// the center looks like noise.
+
+layout(set = 0, binding = 0) uniform sampler2D upscaled;
+
+layout(push_constant) uniform RcasBuffer {
+    float sharpening;
+} rcas_buffer;
+
+layout(location = 0) in vec2 ndc;
+layout(location = 0) out vec4 fragColor;
+
+// Strongest lobe allowed, which keeps the kernel from going negative
+const float LOBE_LIMIT = 0.25 - 1.0 / 16;
+
+float luma(vec3 color) {
+    return 0.5 * color.b + 0.5 * color.r + color.g;
+}
+
+vec3 load(ivec2 position) {
+    ivec2 max_position = textureSize(upscaled, 0) - ivec2(1);
+    return texelFetch(upscaled, clamp(position, ivec2(0), max_position), 0).rgb;
+}
+
+void main() {
+    //   b
+    // d e f
+    //   h
+    ivec2 position = ivec2(gl_FragCoord.xy);
+    vec3 b = load(position + ivec2(0, -1));
+    vec3 d = load(position + ivec2(-1, 0));
+    vec3 e = load(position);
+    vec3 f = load(position + ivec2(1, 0));
+    vec3 h = load(position + ivec2(0, 1));
+
+    float b_luma = luma(b);
+    float d_luma = luma(d);
+    float e_luma = luma(e);
+    float f_luma = luma(f);
+    float h_luma = luma(h);
+    float luma_range = max(max(max(b_luma, d_luma), max(e_luma, f_luma)), h_luma)
+        - min(min(min(b_luma, d_luma), min(e_luma, f_luma)), h_luma);
+    float noise = abs(0.25 * (b_luma + d_luma + f_luma + h_luma) - e_luma) / max(luma_range, 1e-5);
+    float denoise = 1 - 0.5 * clamp(noise, 0, 1);
+
+    // The most negative lobe that keeps each channel above 0 and below 1. Channels already past
+    // 1, like HDR highlights, aren't sharpened.
+    vec3 ring_min = min(min(b, d), min(f, h));
+    vec3 ring_max = max(max(b, d), max(f, h));
+    vec3 hit_min = min(ring_min, e) / max(4 * ring_max, 1e-5);
+    vec3 hit_max = (1 - max(ring_max, e)) / min(4 * ring_min - 4, -1e-5);
+    vec3 channel_lobes = max(-hit_min, hit_max);
+    float lobe = max(-LOBE_LIMIT, min(max(max(channel_lobes.r, channel_lobes.g), channel_lobes.b), 0));
+    lobe *= rcas_buffer.sharpening * denoise;
+
+    // Alpha is only carried through, for transparent windows
+    float alpha = texelFetch(upscaled, position, 0).a;
+    fragColor = vec4((lobe * (b + d + f + h) + e) / (4 * lobe + 1), alpha);
+}
@@ -0,0 +1,129 @@
+// Sun shadow lookups, shared by the lighting and transparency passes
+
+const uint MAX_CASCADES = 4;
+
+// Matches ShadowFilterData in lighting.rs
+const uint SHADOW_FILTER_UNFILTERED = 0;
+const uint SHADOW_FILTER_PCF = 1;
+const uint SHADOW_FILTER_POISSON_PCF = 2;
+const uint SHADOW_FILTER_PCSS = 3;
+
+struct Cascade {
+    mat4 screen_to_shadow;
+    vec4 depth_range; // x, y: screen depths of the far and near edges; z, w: depth to uv ratio
+};
+
+struct ShadowFilter {
+    uint mode;
+    float size; // PoissonPcf's radius in texels, or the tangent of Pcss's sun radius
+};
+
+const vec2 POISSON_DISK[16] = vec2[](
+    vec2(-0.94201624, -0.39906216),
+    vec2(0.94558609, -0.76890725),
+    vec2(-0.09418410, -0.92938870),
+    vec2(0.34495938, 0.29387760),
+    vec2(-0.91588581, 0.45771432),
+    vec2(-0.81544232, -0.87912464),
+    vec2(-0.38277543, 0.27676845),
+    vec2(0.97484398, 0.75648379),
+    vec2(0.44323325, -0.97511554),
+    vec2(0.53742981, -0.47373420),
+    vec2(-0.26496911, -0.41893023),
+    vec2(0.79197514, 0.19090188),
+    vec2(-0.24188840, 0.99706507),
+    vec2(-0.81409955, 0.91437590),
+    vec2(0.19984126, 0.78641367),
+    vec2(0.14383161, -0.14100790)
+);
+
+// Turns the disk differently for each pixel, trading banding for noise
+mat2 poisson_rotation() {
+    float noise = fract(52.9829189 * fract(dot(gl_FragCoord.xy, vec2(0.06711056, 0.00583715))));
+    float angle = 6.2831853 * noise;
+    return mat2(cos(angle), sin(angle), -sin(angle), cos(angle));
+}
+
+// How much of the disk around coords is lit
+float poisson_pcf(sampler2DArrayShadow shadow_compare, vec2 coords, float layer, float depth, vec2 radius) {
+    mat2 rotation = poisson_rotation();
+    float lit = 0;
+    for (int i = 0; i < 16; ++i) {
+        vec2 offset = radius * (rotation * POISSON_DISK[i]);
+        lit += textureGrad(shadow_compare, vec4(coords + offset, layer, depth), vec2(0), vec2(0));
+    }
+    return lit / 16;
+}
+
+// Percentage-closer soft shadows: the further the blockers are from the receiver, the wider the
+// penumbra
+float pcss(
+    sampler2DArray shadow_depths,
+    sampler2DArrayShadow shadow_compare,
+    vec2 coords,
+    float layer,
+    float depth,
+    vec2 depth_to_uv,
+    float sun_tangent
+) {
+    vec2 texel = 1.0 / vec2(textureSize(shadow_depths, 0).xy);
+
+    // Blockers are somewhere between the receiver and the sun's end of the cascade
+    vec2 search_radius = min(sun_tangent * depth_to_uv * (1 - depth), 32 * texel);
+    mat2 rotation = poisson_rotation();
+    float blocker_depth = 0;
+    float blocker_count = 0;
+    for (int i = 0; i < 16; ++i) {
+        vec2 offset = search_radius * (rotation * POISSON_DISK[i]);
+        float sample_depth = textureLod(shadow_depths, vec3(coords + offset, layer), 0).r;
+        if (sample_depth > depth) {
+            blocker_depth += sample_depth;
+            blocker_count += 1;
+        }
+    }
+    if (blocker_count == 0) {
+        return 1;
+    }
+    blocker_depth /= blocker_count;
+
+    vec2 penumbra = max(sun_tangent * depth_to_uv * (blocker_depth - depth), texel);
+    return poisson_pcf(shadow_compare, coords, layer, depth, penumbra);
+}
+
+// How much sunlight reaches a point inside the given cascade
+float cascade_shadow_factor(
+    sampler2DArray shadow_depths,
+    sampler2DArrayShadow shadow_compare,
+    Cascade cascade,
+    uint layer,
+    ShadowFilter shadow_filter,
+    vec4 screen_position
+) {
+    vec4 position_in_light = cascade.screen_to_shadow * screen_position;
+    vec2 shadow_coords = 0.5 * position_in_light.xy / position_in_light.w + vec2(0.5);
+    float geometry_depth = position_in_light.z / position_in_light.w;
+
+    switch (shadow_filter.mode) {
+    case SHADOW_FILTER_PCF:
+        return textureGrad(shadow_compare, vec4(shadow_coords, layer, geometry_depth), vec2(0), vec2(0));
+    case SHADOW_FILTER_POISSON_PCF: {
+        vec2 texel = 1.0 / vec2(textureSize(shadow_depths, 0).xy);
+        return poisson_pcf(shadow_compare, shadow_coords, layer, geometry_depth, shadow_filter.size * texel);
+    }
+    case SHADOW_FILTER_PCSS:
+        return pcss(
+            shadow_depths,
+            shadow_compare,
+            shadow_coords,
+            layer,
+            geometry_depth,
+            cascade.depth_range.zw,
+            shadow_filter.size
+        );
+    default: {
+        float shadow_depth = textureLod(shadow_depths, vec3(shadow_coords, layer), 0).r;
+        float shadow_threshold_narrowness = 1024;
+        return 1 - clamp(shadow_threshold_narrowness * (shadow_depth - geometry_depth), 0, 1);
+    }
+    }
+}
@@ -0,0 +1,14 @@
+// The sRGB transfer functions, for wherever formats can't apply them
+vec3 srgb_from_linear(vec3 linear) {
+    bvec3 cutoff = lessThan(linear, vec3(0.0031308));
+    vec3 lower = 12.92 * linear;
+    vec3 higher = 1.055 * pow(linear, vec3(1 / 2.4)) - 0.055;
+    return mix(higher, lower, cutoff);
+}
+
+vec3 linear_from_srgb(vec3 srgb) {
+    bvec3 cutoff = lessThan(srgb, vec3(0.04045));
+    vec3 lower = srgb / 12.92;
+    vec3 higher = pow((srgb + 0.055) / 1.055, vec3(2.4));
+    return mix(higher, lower, cutoff);
+}
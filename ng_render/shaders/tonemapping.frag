@@ -1,10 +1,96 @@
 #version 450
 
+const uint OPERATOR_LINEAR = 0;
+const uint OPERATOR_REINHARD = 1;
+const uint OPERATOR_ACES = 2;
+
+// Each operator gets its own pipeline, so the others are compiled out
+layout(constant_id = 1) const uint OPERATOR = 0;
+
+// Off means lighting happened in gamma space, so the result goes out as-is
+layout(constant_id = 2) const bool LINEAR_WORKFLOW = true;
+
+// Steps between the output's darkest and brightest values, or 0 for floating point
+layout(constant_id = 3) const uint OUTPUT_LEVELS = 255;
+
+// Carries light's coverage through as premultiplied alpha, for transparent windows
+layout(constant_id = 4) const bool TRANSPARENT = false;
+
+#include "encoding.glsl"
+
 layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput inputColor;
+layout(set = 0, binding = 1) uniform sampler2D blueNoise; // tiles the screen
+layout(set = 1, binding = 0) uniform sampler3D fromLut;
+layout(set = 2, binding = 0) uniform sampler3D toLut;
+
+layout(push_constant) uniform TonemappingBuffer {
+    float exposure;
+    float lut_blend; // from 0 for all of fromLut, to 1 for all of toLut
+} tonemapping_buffer;
 
 layout(location = 0) in vec2 ndc;
-layout(location = 0) out vec3 fragColor;
+layout(location = 0) out vec4 fragColor;
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 color) {
+    return clamp((color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14), 0, 1);
+}
+
+// Between the centres of the corner texels, so the ends of the range map exactly
+vec3 look_up(sampler3D lut, vec3 encoded) {
+    float size = textureSize(lut, 0).x;
+    return texture(lut, (encoded * (size - 1) + vec3(0.5)) / size).rgb;
+}
+
+// LUTs take and give sRGB-encoded color, like .cube files, and are crossfaded so grading can
+// change over time
+vec3 grade(vec3 encoded) {
+    return mix(look_up(fromLut, encoded), look_up(toLut, encoded), tonemapping_buffer.lut_blend);
+}
+
+// Up to half a step either way, so gradients break up into fine grain instead of bands. Steps are
+// only even once encoded, so that's where it's added.
+vec3 dither(vec3 encoded) {
+    if (OUTPUT_LEVELS == 0) {
+        return encoded;
+    }
+    ivec2 texel = ivec2(gl_FragCoord.xy) % textureSize(blueNoise, 0);
+    vec3 noise = texelFetch(blueNoise, texel, 0).rgb - vec3(0.5);
+    return encoded + noise / OUTPUT_LEVELS;
+}
 
 void main() {
-    fragColor = subpassLoad(inputColor).rgb;
+    // Light is premultiplied by its coverage, so it's unpremultiplied around tonemapping
+    vec4 light = subpassLoad(inputColor);
+    float alpha = TRANSPARENT ? clamp(light.a, 0, 1) : 1;
+    vec3 unpremultiplied = alpha > 0 ? light.rgb / alpha : vec3(0);
+
+    // Operators map onto [0, 1], which HDR outputs stretch over their headroom
+    vec3 color = tonemapping_buffer.exposure * unpremultiplied / headroom();
+
+    vec3 mapped;
+    switch (OPERATOR) {
+        case OPERATOR_REINHARD:
+            mapped = color / (color + vec3(1));
+            break;
+        case OPERATOR_ACES:
+            mapped = aces(color);
+            break;
+        default:
+            mapped = clamp(color, 0, 1);
+            break;
+    }
+    if (LINEAR_WORKFLOW) {
+        mapped = linear_from_srgb(grade(srgb_from_linear(mapped)));
+    } else {
+        mapped = linear_from_srgb(grade(mapped));
+    }
+    vec3 encoded = encode_output(headroom() * mapped);
+    if (ENCODING == ENCODING_SRGB) {
+        // The format encodes it afterwards, so dithering has to encode it first and undo that
+        vec3 dithered = clamp(dither(srgb_from_linear(clamp(encoded, 0, 1))), 0, 1);
+        fragColor = vec4(linear_from_srgb(alpha * dithered), alpha);
+    } else {
+        fragColor = vec4(alpha * dither(encoded), alpha);
+    }
 }
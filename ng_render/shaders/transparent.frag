@@ -0,0 +1,149 @@
+#version 450
+
+#include "shadow.glsl"
+#include "srgb.glsl"
+
+// Off lights albedo in gamma space instead, matching ColorWorkflow::Gamma
+layout(constant_id = 0) const bool LINEAR_WORKFLOW = true;
+
+layout(set = 0, binding = 0) uniform sampler2D albedoTexture;
+layout(set = 0, binding = 1) uniform sampler2D normalMap;
+layout(set = 0, binding = 2) uniform sampler2D metallicRoughnessTexture;
+layout(set = 0, binding = 3) uniform sampler2D emissiveTexture;
+
+layout(std140, set = 0, binding = 4) uniform MaterialBuffer {
+    vec4 albedo;
+    vec4 emissive;
+    float metallic;
+    float roughness;
+} material;
+
+layout(set = 1, binding = 0) uniform sampler2DArray shadow;
+
+layout(std140, set = 1, binding = 1) uniform ShadowBuffer {
+    Cascade cascades[MAX_CASCADES];
+    ShadowFilter shadow_filter;
+} shadow_buffer;
+
+layout(std140, set = 1, binding = 2) uniform TransparencyBuffer {
+    mat4 world_to_screen;
+    vec4 eye;
+    vec4 sunlight_direction;
+    vec4 sunlight_color; // scaled by intensity
+    vec4 ambient_color;  // scales the environment's light
+    uint cascade_count;
+} transparency_buffer;
+
+layout(set = 1, binding = 3) uniform sampler2DArrayShadow shadowCompare;
+
+layout(set = 2, binding = 0) uniform samplerCube irradiance;
+layout(set = 2, binding = 1) uniform samplerCube prefiltered; // one mip per roughness step
+layout(set = 2, binding = 2) uniform sampler2D brdfLut;
+
+layout(location = 0) in vec3 vertColor;
+layout(location = 1) in vec3 vertNormal;
+layout(location = 2) in vec2 vertTexCoord;
+layout(location = 3) in vec4 vertTangent;
+layout(location = 4) in vec3 vertPosition;
+
+layout(location = 0) out vec4 fragColor; // premultiplied by alpha
+
+const float PI = 3.14159265;
+
+// Cook-Torrance, with a GGX distribution, Schlick-GGX geometry and Schlick's Fresnel
+vec3 brdf(vec3 n, vec3 v, vec3 l, vec3 albedo, float metallic, float roughness) {
+    vec3 h = normalize(v + l);
+    float n_dot_l = max(dot(n, l), 0);
+    float n_dot_v = max(dot(n, v), 1e-4);
+    float n_dot_h = max(dot(n, h), 0);
+    float v_dot_h = max(dot(v, h), 0);
+
+    float alpha = max(roughness * roughness, 1e-3);
+    float alpha_squared = alpha * alpha;
+    float d_denominator = n_dot_h * n_dot_h * (alpha_squared - 1) + 1;
+    float d = alpha_squared / (PI * d_denominator * d_denominator);
+
+    float k = (roughness + 1) * (roughness + 1) / 8;
+    float g = n_dot_l / (n_dot_l * (1 - k) + k) * n_dot_v / (n_dot_v * (1 - k) + k);
+
+    vec3 f0 = mix(vec3(0.04), albedo, metallic);
+    vec3 f = f0 + (vec3(1) - f0) * pow(1 - v_dot_h, 5);
+
+    vec3 specular = d * g * f / max(4 * n_dot_l * n_dot_v, 1e-4);
+    vec3 diffuse = (vec3(1) - f) * (1 - metallic) * albedo / PI;
+    return (diffuse + specular) * n_dot_l;
+}
+
+// Split-sum image-based lighting, with Fresnel that accounts for roughness
+vec3 ambient(vec3 n, vec3 v, vec3 albedo, float metallic, float roughness) {
+    float n_dot_v = max(dot(n, v), 1e-4);
+
+    vec3 f0 = mix(vec3(0.04), albedo, metallic);
+    vec3 f = f0 + (max(vec3(1 - roughness), f0) - f0) * pow(1 - n_dot_v, 5);
+
+    vec3 diffuse = (vec3(1) - f) * (1 - metallic) * albedo * texture(irradiance, n).rgb;
+
+    float lod = roughness * (textureQueryLevels(prefiltered) - 1);
+    vec3 prefiltered_color = textureLod(prefiltered, reflect(-v, n), lod).rgb;
+    vec2 scale_bias = texture(brdfLut, vec2(n_dot_v, roughness)).rg;
+    vec3 specular = prefiltered_color * (f0 * scale_bias.x + scale_bias.y);
+
+    return (diffuse + specular) * transparency_buffer.ambient_color.rgb;
+}
+
+void main() {
+    vec4 albedo_alpha = material.albedo * texture(albedoTexture, vertTexCoord);
+    vec3 albedo = vertColor * albedo_alpha.rgb;
+    if (!LINEAR_WORKFLOW) {
+        albedo = srgb_from_linear(albedo);
+    }
+    float alpha = albedo_alpha.a;
+
+    // Interpolation skews the basis, so it's re-orthogonalized before use
+    float facing_scale = gl_FrontFacing ? 1.0 : -1.0;
+    vec3 n = facing_scale * normalize(vertNormal);
+    vec3 t = normalize(vertTangent.xyz - n * dot(n, vertTangent.xyz));
+    vec3 b = facing_scale * vertTangent.w * cross(n, t);
+    vec3 tangent_normal = 2.0 * texture(normalMap, vertTexCoord).xyz - vec3(1.0);
+    n = normalize(mat3(t, b, n) * tangent_normal);
+
+    vec4 metallic_roughness = texture(metallicRoughnessTexture, vertTexCoord);
+    float metallic = material.metallic * metallic_roughness.b;
+    float roughness = material.roughness * metallic_roughness.g;
+
+    vec3 emissive = material.emissive.rgb * texture(emissiveTexture, vertTexCoord).rgb;
+
+    // Same cascade selection as the lighting pass, which works in screenspace
+    vec4 screen_position = transparency_buffer.world_to_screen * vec4(vertPosition, 1);
+    screen_position /= screen_position.w;
+    float shadow_factor = 1;
+    for (uint i = 0; i < transparency_buffer.cascade_count; ++i) {
+        Cascade cascade = shadow_buffer.cascades[i];
+        if (screen_position.z >= cascade.depth_range.x) {
+            shadow_factor = cascade_shadow_factor(
+                shadow,
+                shadowCompare,
+                cascade,
+                i,
+                shadow_buffer.shadow_filter,
+                screen_position
+            );
+            break;
+        }
+    }
+
+    vec3 view_direction = normalize(transparency_buffer.eye.xyz - vertPosition);
+    vec3 reflected = brdf(
+        n,
+        view_direction,
+        -transparency_buffer.sunlight_direction.xyz,
+        albedo,
+        metallic,
+        roughness
+    );
+    vec3 ambient_light = ambient(n, view_direction, albedo, metallic, roughness);
+
+    // Coverage thins out reflected light, but glow is added at full strength
+    vec3 lit = PI * transparency_buffer.sunlight_color.rgb * shadow_factor * reflected + ambient_light;
+    fragColor = vec4(alpha * lit + emissive, alpha);
+}
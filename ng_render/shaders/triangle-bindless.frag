@@ -0,0 +1,79 @@
+#version 450
+#extension GL_EXT_nonuniform_qualifier : require
+
+#include "alpha-test.glsl"
+#include "srgb.glsl"
+#include "velocity.glsl"
+
+// triangle.frag, but with every opaque material's textures and factors bound at once
+
+// Off lights albedo in gamma space instead, matching ColorWorkflow::Gamma
+layout(constant_id = 0) const bool LINEAR_WORKFLOW = true;
+// Only masked and dithered materials pay for discarding, which defeats early depth testing
+layout(constant_id = 1) const bool ALPHA_TEST = false;
+
+layout(set = 0, binding = 0) uniform sampler2D textures[];
+
+// Matches MaterialRecord in material.rs
+struct Material {
+    vec4 albedo;
+    vec4 emissive;
+    float metallic;
+    float roughness;
+    float alpha_cutoff; // negative for a dithered cutoff
+    uint albedo_texture;
+    uint normal_map;
+    uint metallic_roughness_texture;
+    uint emissive_texture;
+};
+
+layout(std140, set = 0, binding = 1) readonly buffer MaterialRecords {
+    Material materials[];
+} material_records;
+
+// After triangle.vert's view and model
+layout(push_constant) uniform MaterialIndexBuffer {
+    layout(offset = 128) uint material_index;
+} material_index_buffer;
+
+layout(location = 0) in vec3 vertColor;
+layout(location = 1) in vec3 vertNormal;
+layout(location = 2) in vec2 vertTexCoord;
+layout(location = 3) in vec4 vertTangent;
+layout(location = 5) in vec4 vertClipPosition;
+layout(location = 6) in vec4 vertPreviousClipPosition;
+
+layout(location = 0) out vec3 diffuse; // albedo; lighting splits it into diffuse and specular
+layout(location = 1) out vec3 normal;
+layout(location = 2) out vec2 metallicRoughness;
+layout(location = 3) out vec3 emissive;
+layout(location = 4) out vec2 velocity;
+
+void main() {
+    Material material = material_records.materials[material_index_buffer.material_index];
+
+    vec4 albedo = texture(textures[material.albedo_texture], vertTexCoord);
+    if (ALPHA_TEST && material.albedo.a * albedo.a < alpha_threshold(material.alpha_cutoff)) {
+        discard;
+    }
+
+    diffuse = vertColor * material.albedo.rgb * albedo.rgb;
+    if (!LINEAR_WORKFLOW) {
+        diffuse = srgb_from_linear(diffuse);
+    }
+
+    // Interpolation skews the basis, so it's re-orthogonalized before use
+    float facing_scale = gl_FrontFacing ? 1.0 : -1.0;
+    vec3 n = facing_scale * normalize(vertNormal);
+    vec3 t = normalize(vertTangent.xyz - n * dot(n, vertTangent.xyz));
+    vec3 b = facing_scale * vertTangent.w * cross(n, t);
+    vec3 tangent_normal = 2.0 * texture(textures[material.normal_map], vertTexCoord).xyz - vec3(1.0);
+    normal = 0.5 * normalize(mat3(t, b, n) * tangent_normal) + vec3(0.5);
+
+    vec4 metallic_roughness = texture(textures[material.metallic_roughness_texture], vertTexCoord);
+    metallicRoughness = vec2(material.metallic * metallic_roughness.b, material.roughness * metallic_roughness.g);
+
+    emissive = material.emissive.rgb * texture(textures[material.emissive_texture], vertTexCoord).rgb;
+
+    velocity = screen_velocity(vertClipPosition, vertPreviousClipPosition);
+}
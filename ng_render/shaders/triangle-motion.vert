@@ -0,0 +1,58 @@
+#version 450
+
+// triangle.vert, but also passing on where each vertex was last frame for the velocity buffer,
+// and skinning for meshes that have joints
+
+// Off for meshes without joints, which leave the skin attributes and joint matrices unread
+layout(constant_id = 0) const bool SKINNED = false;
+
+layout(push_constant) uniform ViewBuffer {
+    layout(offset = 0) mat4 view;
+    layout(offset = 64) mat4 model;
+} view_buffer;
+
+// Each skinned mesh's joints are a run starting at its firstJoint
+layout(std430, set = 1, binding = 0) readonly buffer JointMatrices {
+    mat4 joint_matrices[];
+};
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec3 color;
+layout(location = 3) in vec2 texCoord;
+layout(location = 4) in vec4 tangent;
+layout(location = 5) in mat4 previousTransform; // per instance: last frame's view times model
+layout(location = 9) in uint firstJoint; // per instance
+layout(location = 10) in uvec4 joints;
+layout(location = 11) in vec4 weights;
+
+layout(location = 0) out vec3 vertColor;
+layout(location = 1) out vec3 vertNormal;
+layout(location = 2) out vec2 vertTexCoord;
+layout(location = 3) out vec4 vertTangent;
+layout(location = 5) out vec4 vertClipPosition;
+layout(location = 6) out vec4 vertPreviousClipPosition;
+
+void main() {
+    mat4 skin = mat4(1.0);
+    if (SKINNED) {
+        skin = weights.x * joint_matrices[firstJoint + joints.x]
+            + weights.y * joint_matrices[firstJoint + joints.y]
+            + weights.z * joint_matrices[firstJoint + joints.z]
+            + weights.w * joint_matrices[firstJoint + joints.w];
+    }
+    vec4 skinned_position = skin * vec4(position, 1.0);
+    mat4 model = view_buffer.model * skin;
+
+    vec4 world_position = view_buffer.model * skinned_position;
+    gl_Position = view_buffer.view * world_position;
+    vertClipPosition = gl_Position;
+    // Last frame's joints aren't kept, so only the mesh's own motion shows up in the velocity
+    vertPreviousClipPosition = previousTransform * skinned_position;
+    vertColor = color;
+    vertTexCoord = texCoord;
+
+    // Normals stay in worldspace because the light shader has a screenspace-to-lightspace matrix
+    vertNormal = mat3(transpose(inverse(model))) * normal;
+    vertTangent = vec4(mat3(model) * tangent.xyz, tangent.w);
+}
@@ -1,44 +1,30 @@
 #version 450
 
 layout(push_constant) uniform ViewBuffer {
-    mat4 view;
+    layout(offset = 0) mat4 view;
+    layout(offset = 64) mat4 model;
 } view_buffer;
 
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec3 color;
+layout(location = 3) in vec2 texCoord;
+layout(location = 4) in vec4 tangent;
+
 layout(location = 0) out vec3 vertColor;
 layout(location = 1) out vec3 vertNormal;
-
-vec3 positions[6] = vec3[](
-    vec3(1.0, 0.0, 0.0),
-    vec3(-1.0, -1.0, 0.0),
-    vec3(-1.0, 1.0, 0.0),
-
-    vec3(0.0, 0.0, 1.0),
-    vec3(1.0, 0.0, -1.0),
-    vec3(-1.0, 0.0, -1.0)
-);
-
-vec3 normals[6] = vec3[](
-    vec3(0.0, 0.0, -1.0),
-    vec3(0.0, 0.0, -1.0),
-    vec3(0.0, 0.0, -1.0),
-
-    vec3(0.0, 1.0, 0.0),
-    vec3(0.0, 1.0, 0.0),
-    vec3(0.0, 1.0, 0.0)
-);
-
-vec3 colors[3] = vec3[](
-    vec3(1.0, 0.0, 0.0),
-    vec3(0.0, 1.0, 0.0),
-    vec3(0.0, 0.0, 1.0)
-);
+layout(location = 2) out vec2 vertTexCoord;
+layout(location = 3) out vec4 vertTangent;
+layout(location = 4) out vec3 vertPosition; // worldspace, for forward shading
 
 void main() {
-    gl_Position = view_buffer.view * vec4(positions[gl_VertexIndex], 1.0);
-    vertColor = colors[gl_VertexIndex % 3];
-
-    // Map through view because the light shader has a screenspace-to-lightspace matrix
-    // vec4 view_normal = view_buffer.view * vec4(normals[gl_VertexIndex], 0);
-    // vertNormal = view_normal.xyz;
-    vertNormal = normals[gl_VertexIndex];
+    vec4 world_position = view_buffer.model * vec4(position, 1.0);
+    gl_Position = view_buffer.view * world_position;
+    vertPosition = world_position.xyz / world_position.w;
+    vertColor = color;
+    vertTexCoord = texCoord;
+
+    // Normals stay in worldspace because the light shader has a screenspace-to-lightspace matrix
+    vertNormal = mat3(transpose(inverse(view_buffer.model))) * normal;
+    vertTangent = vec4(mat3(view_buffer.model) * tangent.xyz, tangent.w);
 }
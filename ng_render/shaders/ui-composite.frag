@@ -0,0 +1,16 @@
+#version 450
+
+#include "encoding.glsl"
+
+// What egui drew, blended in sRGB space as it expects
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput overlay;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    vec4 color = subpassLoad(overlay);
+
+    // Colors are premultiplied, so they're unpremultiplied around decoding and encoding
+    vec3 unpremultiplied = color.a > 0 ? color.rgb / color.a : vec3(0);
+    outColor = vec4(color.a * encode_output(linear_from_srgb(unpremultiplied)), color.a);
+}
@@ -0,0 +1,19 @@
+#version 450
+
+layout(push_constant) uniform UiBuffer {
+    vec2 screen_size; // in points
+} ui_buffer;
+
+layout(location = 0) in vec2 inPosition;
+layout(location = 1) in vec2 inTexCoord;
+layout(location = 2) in vec4 inColor;
+
+layout(location = 0) out vec2 fragTexCoord;
+layout(location = 1) out vec4 fragColor;
+
+void main() {
+    gl_Position = vec4(2 * inPosition / ui_buffer.screen_size - vec2(1), 0, 1);
+    fragTexCoord = inTexCoord;
+    // egui hands out premultiplied sRGB colors, and expects them to be blended as they are
+    fragColor = inColor;
+}
@@ -0,0 +1,8 @@
+// How far a point moved across the screen since last frame, in uv. Points that were behind the
+// camera have no sensible previous position, so they're treated as still.
+vec2 screen_velocity(vec4 clip_position, vec4 previous_clip_position) {
+    if (previous_clip_position.w <= 0) {
+        return vec2(0);
+    }
+    return 0.5 * (clip_position.xy / clip_position.w - previous_clip_position.xy / previous_clip_position.w);
+}
@@ -0,0 +1,125 @@
+#version 450
+
+#include "water.glsl"
+
+layout(set = 0, binding = 1) uniform sampler2D refraction; // light, before any water was drawn
+layout(set = 0, binding = 2) uniform sampler2D depth;
+layout(set = 0, binding = 3) uniform sampler2D normalMap; // tiling ripples
+layout(set = 1, binding = 0) uniform samplerCube irradiance;
+layout(set = 1, binding = 1) uniform samplerCube prefiltered; // one mip per roughness step
+layout(set = 1, binding = 2) uniform sampler2D brdfLut;
+
+layout(location = 0) in vec3 vertPosition;
+
+layout(location = 0) out vec4 fragColor; // blended over light by alpha, which fades at the shore
+
+const float PI = 3.14159265;
+const float REFRACTION_SCALE = 0.03; // screen fraction per unit of normal tilt
+const int REFLECTION_STEPS = 48;
+
+// Depth is 0 infinitely far away, where there's no point to return
+vec3 world_position(vec2 uv, float screen_depth) {
+    vec4 position = water_buffer.screen_to_world * vec4(2 * uv - 1, screen_depth, 1);
+    return position.xyz / position.w;
+}
+
+// Two copies of the normal map scrolling across each other, so the ripples never visibly repeat
+vec3 wave_normal(vec2 xy) {
+    vec2 drift = surface.wave_speed * water_buffer.time * vec2(0.8, 0.6);
+    mat2 rotation = mat2(0.6, 0.8, -0.8, 0.6);
+    vec2 uv_a = (xy + drift) / surface.wave_size;
+    vec2 uv_b = rotation * (xy - 0.7 * drift) / (0.61 * surface.wave_size);
+    vec2 tilt_a = 2 * texture(normalMap, uv_a).xy - 1;
+    vec2 tilt_b = transpose(rotation) * (2 * texture(normalMap, uv_b).xy - 1);
+    return normalize(vec3(surface.wave_strength * (tilt_a + tilt_b), 1));
+}
+
+// Marches the reflected ray through the depth buffer, returning what it hits in rgb, weighted by
+// how much to trust it in a. Misses fall back to the environment.
+vec4 trace_reflection(vec3 origin, vec3 direction) {
+    float step_length = 0.05;
+    vec3 position = origin;
+    for (int i = 0; i < REFLECTION_STEPS; ++i) {
+        position += step_length * direction;
+        step_length *= 1.15;
+
+        vec4 screen_position = water_buffer.world_to_screen * vec4(position, 1);
+        if (screen_position.w <= 0) {
+            break;
+        }
+        screen_position /= screen_position.w;
+        vec2 uv = 0.5 * screen_position.xy + 0.5;
+        if (any(lessThan(uv, vec2(0))) || any(greaterThan(uv, vec2(1)))) {
+            break;
+        }
+
+        // Reverse-Z, so whatever's nearer than the ray has the greater depth
+        float scene_depth = textureLod(depth, uv, 0).r;
+        if (scene_depth > screen_position.z) {
+            vec3 scene = world_position(uv, scene_depth);
+            float eye_distance = distance(water_buffer.eye.xyz, position);
+            float behind = eye_distance - distance(water_buffer.eye.xyz, scene);
+            if (behind > 2 * step_length) {
+                break; // passed behind something, rather than into it
+            }
+            vec2 edge = min(uv, 1 - uv);
+            float weight = smoothstep(0, 0.1, min(edge.x, edge.y));
+            return vec4(textureLod(refraction, uv, 0).rgb, weight);
+        }
+    }
+    return vec4(0);
+}
+
+void main() {
+    vec2 resolution = vec2(textureSize(refraction, 0));
+    vec2 uv = gl_FragCoord.xy / resolution;
+    bool above = water_buffer.eye.z >= surface.height;
+
+    vec3 n = wave_normal(vertPosition.xy);
+    if (!above) {
+        n.z = -n.z;
+    }
+    vec3 view_direction = normalize(water_buffer.eye.xyz - vertPosition);
+
+    // How deep the water is below this point, for fading in at the shore
+    float scene_depth = texture(depth, uv).r;
+    float water_depth = 1e30;
+    if (scene_depth > 0) {
+        water_depth = above ? surface.height - world_position(uv, scene_depth).z : 1e30;
+    }
+    float shore = clamp(water_depth / max(surface.shore_fade, 1e-4), 0, 1);
+
+    // Ripples bend the view of what's below, unless that would pick up something in front
+    vec2 refracted_uv = uv + REFRACTION_SCALE * shore * n.xy;
+    float refracted_depth = texture(depth, refracted_uv).r;
+    if (refracted_depth > gl_FragCoord.z) {
+        refracted_uv = uv;
+        refracted_depth = scene_depth;
+    }
+    vec3 below = texture(refraction, refracted_uv).rgb;
+    if (above) {
+        float path = refracted_depth > 0
+            ? distance(vertPosition, world_position(refracted_uv, refracted_depth))
+            : 1e30;
+        float transmittance = exp(-path / max(surface.color.a, 1e-4));
+        below = mix(surface.color.rgb, below, transmittance);
+    }
+
+    vec3 reflected_direction = reflect(-view_direction, n);
+    vec3 reflection = textureLod(prefiltered, reflected_direction, 0).rgb * water_buffer.ambient_color.rgb;
+    vec4 traced = trace_reflection(vertPosition, reflected_direction);
+    reflection = mix(reflection, traced.rgb, traced.a);
+
+    // Schlick's Fresnel, for water's index of refraction
+    float n_dot_v = max(dot(n, view_direction), 0);
+    float fresnel = 0.02 + 0.98 * pow(1 - n_dot_v, 5);
+
+    // Sun glints, as a sharp Blinn-Phong lobe normalized like the lighting pass's sun
+    vec3 l = -water_buffer.sunlight_direction.xyz;
+    vec3 h = normalize(view_direction + l);
+    float shininess = 1024;
+    float glint = (shininess + 8) / (8 * PI) * pow(max(dot(n, h), 0), shininess);
+    vec3 sun = above ? PI * water_buffer.sunlight_color.rgb * fresnel * glint * max(dot(n, l), 0) : vec3(0);
+
+    fragColor = vec4(mix(below, reflection, fresnel) + sun, shore);
+}
@@ -0,0 +1,21 @@
+// Shared between water.vert and water.frag, matching WaterBuffer and SurfaceBuffer in water.rs
+
+layout(std140, set = 0, binding = 0) uniform WaterBuffer {
+    mat4 world_to_screen;
+    mat4 screen_to_world;
+    vec4 eye;
+    vec4 sunlight_direction;
+    vec4 sunlight_color; // scaled by intensity
+    vec4 ambient_color;  // scales the environment's light
+    float time; // seconds
+} water_buffer;
+
+layout(push_constant) uniform SurfaceBuffer {
+    vec4 bounds; // min xy, then max xy
+    vec4 color;  // deep water's in rgb, with clarity in a
+    float height;
+    float shore_fade;
+    float wave_size;
+    float wave_speed;
+    float wave_strength;
+} surface;
@@ -0,0 +1,21 @@
+#version 450
+
+#include "water.glsl"
+
+layout(location = 0) out vec3 vertPosition;
+
+// Two triangles spanning the surface's bounds, counterclockwise from above
+const vec2 corners[6] = vec2[](
+    vec2(0, 0),
+    vec2(1, 0),
+    vec2(1, 1),
+    vec2(0, 0),
+    vec2(1, 1),
+    vec2(0, 1)
+);
+
+void main() {
+    vec2 xy = mix(surface.bounds.xy, surface.bounds.zw, corners[gl_VertexIndex]);
+    vertPosition = vec3(xy, surface.height);
+    gl_Position = water_buffer.world_to_screen * vec4(vertPosition, 1);
+}
@@ -0,0 +1,263 @@
+use std::sync::Arc;
+
+use nalgebra as na;
+
+// A joint's transform relative to its parent, applied as scale, then rotation, then translation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JointTransform {
+    pub translation: mint::Vector3<f32>,
+    pub rotation: mint::Quaternion<f32>,
+    pub scale: mint::Vector3<f32>,
+}
+
+impl Default for JointTransform {
+    fn default() -> Self {
+        Self {
+            translation: [0.0; 3].into(),
+            rotation: na::UnitQuaternion::identity().into_inner().into(),
+            scale: [1.0; 3].into(),
+        }
+    }
+}
+
+impl JointTransform {
+    // Rotations take the shorter way around
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        let lerp = |a: mint::Vector3<f32>, b: mint::Vector3<f32>| {
+            na::Vector3::from(a).lerp(&b.into(), t).into()
+        };
+        Self {
+            translation: lerp(self.translation, other.translation),
+            rotation: nlerp(self.rotation, other.rotation, t),
+            scale: lerp(self.scale, other.scale),
+        }
+    }
+
+    fn matrix(&self) -> na::Matrix4<f32> {
+        let rotation = na::UnitQuaternion::new_normalize(self.rotation.into());
+        na::Matrix4::new_translation(&self.translation.into())
+            * rotation.to_homogeneous()
+            * na::Matrix4::new_nonuniform_scaling(&self.scale.into())
+    }
+}
+
+// Normalized linear interpolation, which is close enough to slerp between nearby keyframes
+fn nlerp(a: mint::Quaternion<f32>, b: mint::Quaternion<f32>, t: f32) -> mint::Quaternion<f32> {
+    let a = na::Quaternion::from(a);
+    let mut b = na::Quaternion::from(b);
+    if a.dot(&b) < 0.0 {
+        b = -b;
+    }
+    na::UnitQuaternion::new_normalize(a.lerp(&b, t))
+        .into_inner()
+        .into()
+}
+
+#[derive(Clone, Debug)]
+pub struct Joint {
+    pub name: String,
+    pub parent: Option<usize>, // must come before this joint
+    pub rest: JointTransform,
+    pub inverse_bind: mint::ColumnMatrix4<f32>, // from mesh space to the joint's, as skinned
+}
+
+#[derive(Clone, Debug)]
+pub struct Skeleton {
+    joints: Vec<Joint>,
+}
+
+impl Skeleton {
+    pub fn new(joints: Vec<Joint>) -> Self {
+        assert!(
+            joints
+                .iter()
+                .enumerate()
+                .all(|(index, joint)| joint.parent.is_none_or(|parent| parent < index)),
+            "Skeleton joints must come after their parents"
+        );
+        Self { joints }
+    }
+
+    pub fn joints(&self) -> &[Joint] {
+        &self.joints
+    }
+
+    pub fn joint_index(&self, name: &str) -> Option<usize> {
+        self.joints.iter().position(|joint| joint.name == name)
+    }
+
+    pub fn rest_pose(&self) -> Pose {
+        Pose {
+            transforms: self.joints.iter().map(|joint| joint.rest).collect(),
+        }
+    }
+}
+
+// Every joint's transform, in the skeleton's order
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pose {
+    pub transforms: Vec<JointTransform>,
+}
+
+impl Pose {
+    // Weight 0 is entirely self, and weight 1 entirely other
+    pub fn blend(&self, other: &Self, weight: f32) -> Self {
+        assert_eq!(
+            self.transforms.len(),
+            other.transforms.len(),
+            "Blended poses must be of the same skeleton"
+        );
+        Self {
+            transforms: self
+                .transforms
+                .iter()
+                .zip(&other.transforms)
+                .map(|(a, b)| a.lerp(b, weight))
+                .collect(),
+        }
+    }
+
+    // What MeshInstance::joint_matrices wants: from mesh space in the bind pose to mesh space in
+    // this one, for each joint
+    pub fn joint_matrices(&self, skeleton: &Skeleton) -> Vec<mint::ColumnMatrix4<f32>> {
+        assert_eq!(
+            self.transforms.len(),
+            skeleton.joints.len(),
+            "Pose must be of the skeleton"
+        );
+        let mut globals: Vec<na::Matrix4<f32>> = Vec::with_capacity(self.transforms.len());
+        for (transform, joint) in self.transforms.iter().zip(&skeleton.joints) {
+            let local = transform.matrix();
+            let global = match joint.parent {
+                Some(parent) => globals[parent] * local,
+                None => local,
+            };
+            globals.push(global);
+        }
+        globals
+            .iter()
+            .zip(&skeleton.joints)
+            .map(|(global, joint)| (global * na::Matrix4::from(joint.inverse_bind)).into())
+            .collect()
+    }
+}
+
+// Keyframes are (time in seconds, value), sorted by time. Tracks without any leave the joint's
+// transform as it was.
+#[derive(Clone, Debug, Default)]
+pub struct Channel {
+    pub joint: usize,
+    pub translations: Vec<(f32, mint::Vector3<f32>)>,
+    pub rotations: Vec<(f32, mint::Quaternion<f32>)>,
+    pub scales: Vec<(f32, mint::Vector3<f32>)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AnimationClip {
+    channels: Vec<Channel>,
+    duration: f32,
+}
+
+impl AnimationClip {
+    pub fn new(channels: Vec<Channel>) -> Self {
+        let duration = channels
+            .iter()
+            .flat_map(|channel| {
+                [
+                    channel.translations.last().map(|&(time, _)| time),
+                    channel.rotations.last().map(|&(time, _)| time),
+                    channel.scales.last().map(|&(time, _)| time),
+                ]
+            })
+            .flatten()
+            .fold(0.0, f32::max);
+        Self { channels, duration }
+    }
+
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    // Joints without a channel keep the rest pose
+    pub fn sample(&self, skeleton: &Skeleton, time: f32) -> Pose {
+        let mut pose = skeleton.rest_pose();
+        for channel in &self.channels {
+            let transform = &mut pose.transforms[channel.joint];
+            let lerp = |a: mint::Vector3<f32>, b: mint::Vector3<f32>, t| {
+                na::Vector3::from(a).lerp(&b.into(), t).into()
+            };
+            if let Some(translation) = sample_track(&channel.translations, time, lerp) {
+                transform.translation = translation;
+            }
+            if let Some(rotation) = sample_track(&channel.rotations, time, nlerp) {
+                transform.rotation = rotation;
+            }
+            if let Some(scale) = sample_track(&channel.scales, time, lerp) {
+                transform.scale = scale;
+            }
+        }
+        pose
+    }
+}
+
+// Holds the first and last values outside of the keyframes' times
+fn sample_track<T: Copy>(
+    keyframes: &[(f32, T)],
+    time: f32,
+    interpolate: impl Fn(T, T, f32) -> T,
+) -> Option<T> {
+    let next = keyframes.partition_point(|&(keyframe_time, _)| keyframe_time <= time);
+    match (
+        next.checked_sub(1).map(|index| keyframes[index]),
+        keyframes.get(next),
+    ) {
+        (Some((start, a)), Some(&(end, b))) => {
+            Some(interpolate(a, b, (time - start) / (end - start)))
+        }
+        (Some((_, a)), None) => Some(a),
+        (None, Some(&(_, b))) => Some(b),
+        (None, None) => None,
+    }
+}
+
+// Plays a clip back from time 0, at speed times real time
+#[derive(Clone, Debug)]
+pub struct ClipPlayer {
+    pub clip: Arc<AnimationClip>,
+    pub time: f32,
+    pub speed: f32,
+    pub looping: bool, // otherwise it holds the last frame
+}
+
+impl ClipPlayer {
+    pub fn new(clip: Arc<AnimationClip>) -> Self {
+        Self {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+        }
+    }
+
+    pub fn advance(&mut self, seconds: f32) {
+        let duration = self.clip.duration();
+        self.time += self.speed * seconds;
+        self.time = if self.looping && duration > 0.0 {
+            self.time.rem_euclid(duration)
+        } else {
+            self.time.clamp(0.0, duration)
+        };
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !self.looping && self.time >= self.clip.duration()
+    }
+
+    pub fn sample(&self, skeleton: &Skeleton) -> Pose {
+        self.clip.sample(skeleton, self.time)
+    }
+}
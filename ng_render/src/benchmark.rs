@@ -0,0 +1,151 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::stats::PassTimes;
+
+// How long one of Renderer::benchmark's draws took
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BenchmarkFrame {
+    pub frame_time: Duration, // since the previous draw began
+    pub cpu_time: Duration,
+    pub gpu: Option<PassTimes>, // this draw's own, unlike FrameStats::gpu; None without timestamps
+}
+
+// Machine-readable, for tracking performance across commits. Times are in milliseconds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BenchmarkResults {
+    pub frames: Vec<BenchmarkFrame>,
+}
+
+impl BenchmarkResults {
+    // A row per frame, with a column per pass
+    pub fn write_csv(&self, mut writer: impl Write) -> io::Result<()> {
+        write!(writer, "frame,frame_ms,cpu_ms,gpu_ms")?;
+        for (name, _) in &PassTimes::default().passes() {
+            write!(writer, ",gpu_{}_ms", key(name))?;
+        }
+        writeln!(writer)?;
+
+        for (index, frame) in self.frames.iter().enumerate() {
+            write!(
+                writer,
+                "{},{:.4},{:.4}",
+                index,
+                ms(frame.frame_time),
+                ms(frame.cpu_time)
+            )?;
+            match frame.gpu {
+                Some(gpu) => {
+                    write!(writer, ",{:.4}", ms(gpu.total()))?;
+                    for (_, time) in &gpu.passes() {
+                        write!(writer, ",{:.4}", ms(*time))?;
+                    }
+                }
+                // Left empty
+                None => write!(
+                    writer,
+                    "{}",
+                    ",".repeat(1 + PassTimes::default().passes().len())
+                )?,
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    // A summary of each time over every frame, followed by the frames themselves. GPU times are
+    // null without timestamps.
+    pub fn write_json(&self, mut writer: impl Write) -> io::Result<()> {
+        let gpu: Vec<PassTimes> = self.frames.iter().filter_map(|frame| frame.gpu).collect();
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"frame_count\": {},", self.frames.len())?;
+        writeln!(writer, "  \"summary\": {{")?;
+        let frame_times: Vec<_> = self.frames.iter().map(|frame| frame.frame_time).collect();
+        let cpu_times: Vec<_> = self.frames.iter().map(|frame| frame.cpu_time).collect();
+        writeln!(writer, "    \"frame_ms\": {},", summary(&frame_times))?;
+        writeln!(writer, "    \"cpu_ms\": {},", summary(&cpu_times))?;
+        let gpu_times: Vec<_> = gpu.iter().map(PassTimes::total).collect();
+        writeln!(writer, "    \"gpu_ms\": {},", summary(&gpu_times))?;
+        writeln!(writer, "    \"gpu_passes_ms\": {{")?;
+        let names = PassTimes::default().passes();
+        for (index, (name, _)) in names.iter().enumerate() {
+            let times: Vec<_> = gpu.iter().map(|gpu| gpu.passes()[index].1).collect();
+            let comma = if index + 1 < names.len() { "," } else { "" };
+            writeln!(
+                writer,
+                "      \"{}\": {}{}",
+                key(name),
+                summary(&times),
+                comma
+            )?;
+        }
+        writeln!(writer, "    }}")?;
+        writeln!(writer, "  }},")?;
+
+        writeln!(writer, "  \"frames\": [")?;
+        for (index, frame) in self.frames.iter().enumerate() {
+            let gpu = match frame.gpu {
+                Some(gpu) => {
+                    let passes: Vec<_> = gpu
+                        .passes()
+                        .iter()
+                        .map(|(name, time)| format!("\"{}\": {:.4}", key(name), ms(*time)))
+                        .collect();
+                    format!(
+                        "\"gpu_ms\": {:.4}, \"gpu_passes_ms\": {{{}}}",
+                        ms(gpu.total()),
+                        passes.join(", ")
+                    )
+                }
+                None => "\"gpu_ms\": null, \"gpu_passes_ms\": null".into(),
+            };
+            let comma = if index + 1 < self.frames.len() {
+                ","
+            } else {
+                ""
+            };
+            writeln!(
+                writer,
+                "    {{\"frame_ms\": {:.4}, \"cpu_ms\": {:.4}, {}}}{}",
+                ms(frame.frame_time),
+                ms(frame.cpu_time),
+                gpu,
+                comma
+            )?;
+        }
+        writeln!(writer, "  ]")?;
+        writeln!(writer, "}}")
+    }
+}
+
+fn ms(duration: Duration) -> f64 {
+    1000.0 * duration.as_secs_f64()
+}
+
+// Pass names as identifiers, like "Light clusters" as light_clusters
+fn key(name: &str) -> String {
+    name.to_lowercase().replace(' ', "_")
+}
+
+// As a JSON object, or null if there aren't any. Percentiles are the nearest rank.
+fn summary(times: &[Duration]) -> String {
+    if times.is_empty() {
+        return "null".into();
+    }
+    let mut sorted = times.to_vec();
+    sorted.sort();
+    let percentile = |p: f64| {
+        let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+        ms(sorted[rank.clamp(1, sorted.len()) - 1])
+    };
+    let mean = ms(sorted.iter().sum::<Duration>()) / sorted.len() as f64;
+    format!(
+        "{{\"mean\": {:.4}, \"min\": {:.4}, \"median\": {:.4}, \"p95\": {:.4}, \"p99\": {:.4}, \"max\": {:.4}}}",
+        mean,
+        ms(sorted[0]),
+        percentile(50.0),
+        percentile(95.0),
+        percentile(99.0),
+        ms(sorted[sorted.len() - 1]),
+    )
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+
+use crate::{
+    buffer::Buffer,
+    guard::{GuardableResource, Guarded},
+    material::MaterialRecord,
+    shared::SharedStem,
+    texture::GpuTexture,
+    upload::{self, UploadError},
+    util,
+};
+
+// Raising these only costs descriptor pool and buffer space
+pub const MAX_BINDLESS_TEXTURES: u32 = 4096;
+pub const MAX_BINDLESS_MATERIALS: u32 = 4096;
+
+// Every opaque material's textures and factors in one descriptor set, so the geometry pass binds
+// it once and picks each draw's material with a push constant. Materials refer to textures by
+// their index into the texture array.
+pub struct BindlessTable {
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_set: vk::DescriptorSet,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    material_buffer: Buffer, // a MaterialRecord per material slot
+    shared_stem: Arc<SharedStem>,
+    slots: Mutex<Slots>,
+}
+
+struct Slots {
+    free_materials: Vec<u32>,
+    free_textures: Vec<u32>,
+    textures: HashMap<(usize, vk::Sampler), (Weak<GpuTexture>, u32)>, // keyed by texture address
+}
+
+// Gives its slot back once dropped
+pub struct BindlessMaterial {
+    index: u32,
+    table: Arc<BindlessTable>,
+}
+
+impl BindlessTable {
+    pub fn new(shared_stem: Arc<SharedStem>) -> Result<Self, UploadError> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let descriptor_set_layout = Self::create_descriptor_set_layout(device)?;
+            shared_stem.set_name(*descriptor_set_layout, "bindless")?;
+
+            let descriptor_pool = util::create_descriptor_pool(
+                device,
+                1,
+                &[
+                    vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        descriptor_count: MAX_BINDLESS_TEXTURES,
+                    },
+                    vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::STORAGE_BUFFER,
+                        descriptor_count: 1,
+                    },
+                ],
+            )?;
+            shared_stem.set_name(*descriptor_pool, "bindless")?;
+
+            let material_buffer = upload::create_buffer(
+                &shared_stem,
+                MaterialRecord::std140_size_static() as vk::DeviceSize
+                    * MAX_BINDLESS_MATERIALS as vk::DeviceSize,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            shared_stem.set_name(material_buffer.buffer, "bindless materials")?;
+            shared_stem.set_name(material_buffer.memory, "bindless materials")?;
+
+            let descriptor_set =
+                util::allocate_descriptor_set(device, *descriptor_pool, *descriptor_set_layout)?;
+            util::write_descriptor_set(
+                device,
+                descriptor_set,
+                &[(1, util::Descriptor::StorageBuffer(material_buffer.buffer))],
+            );
+            shared_stem.set_name(descriptor_set, "bindless")?;
+
+            // Popped from the back, so the lowest indices go first
+            let slots = Slots {
+                free_materials: (0..MAX_BINDLESS_MATERIALS).rev().collect(),
+                free_textures: (0..MAX_BINDLESS_TEXTURES).rev().collect(),
+                textures: HashMap::new(),
+            };
+
+            Ok(Self {
+                descriptor_pool: descriptor_pool.take(),
+                descriptor_set,
+                descriptor_set_layout: descriptor_set_layout.take(),
+                material_buffer: material_buffer.take(),
+                shared_stem,
+                slots: Mutex::new(slots),
+            })
+        }
+    }
+
+    unsafe fn create_descriptor_set_layout(
+        device: &ash::Device,
+    ) -> VkResult<Guarded<(vk::DescriptorSetLayout, &ash::Device)>> {
+        // Textures, then the material records that index them
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(MAX_BINDLESS_TEXTURES)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ];
+        // Unused texture slots stay empty, and new ones are filled while earlier frames draw
+        let binding_flags = [
+            vk::DescriptorBindingFlags::PARTIALLY_BOUND
+                | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING,
+            vk::DescriptorBindingFlags::empty(),
+        ];
+        let mut binding_flags_create_info =
+            vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder().binding_flags(&binding_flags);
+        let descriptor_set_layout_create_info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&bindings)
+            .push_next(&mut binding_flags_create_info);
+        Ok(device
+            .create_descriptor_set_layout(&descriptor_set_layout_create_info, None)?
+            .guard_with(device))
+    }
+
+    pub fn descriptor_set(&self) -> vk::DescriptorSet {
+        self.descriptor_set
+    }
+
+    pub fn descriptor_set_layout(&self) -> vk::DescriptorSetLayout {
+        self.descriptor_set_layout
+    }
+
+    // Textures shared between materials with the same sampler share a slot, until the texture
+    // itself is dropped
+    pub fn texture_index(
+        &self,
+        texture: &Arc<GpuTexture>,
+        sampler: vk::Sampler,
+    ) -> Result<u32, UploadError> {
+        let mut slots = self.slots.lock().unwrap();
+        let key = (Arc::as_ptr(texture) as usize, sampler);
+        if let Some((entry, index)) = slots.textures.get(&key) {
+            if entry.strong_count() > 0 {
+                return Ok(*index);
+            }
+        }
+
+        // Dropped textures waited for the device to go idle, so their slots are safe to reuse
+        let Slots {
+            free_textures,
+            textures,
+            ..
+        } = &mut *slots;
+        textures.retain(|_, (entry, index)| {
+            let alive = entry.strong_count() > 0;
+            if !alive {
+                free_textures.push(*index);
+            }
+            alive
+        });
+
+        let index = free_textures
+            .pop()
+            .ok_or(UploadError::BindlessTableFull("texture"))?;
+        textures.insert(key, (Arc::downgrade(texture), index));
+
+        let image_info = [vk::DescriptorImageInfo {
+            sampler,
+            image_view: texture.view().get(&self.shared_stem),
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_binding(0)
+            .dst_array_element(index)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info);
+        unsafe {
+            self.shared_stem
+                .device()
+                .update_descriptor_sets(&[*write], &[]);
+        }
+
+        Ok(index)
+    }
+
+    pub fn add_material(
+        self: &Arc<Self>,
+        record: &MaterialRecord,
+    ) -> Result<BindlessMaterial, UploadError> {
+        let index = self
+            .slots
+            .lock()
+            .unwrap()
+            .free_materials
+            .pop()
+            .ok_or(UploadError::BindlessTableFull("material"))?;
+        let material = BindlessMaterial {
+            index,
+            table: self.clone(),
+        };
+
+        let offset = index as usize * MaterialRecord::std140_size_static();
+        unsafe {
+            self.material_buffer.write(
+                self.shared_stem.device(),
+                offset as _,
+                record.as_std140().as_bytes(),
+            )?;
+        }
+
+        Ok(material)
+    }
+}
+
+impl Drop for BindlessTable {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            self.material_buffer.destroy_with(device);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+impl BindlessMaterial {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+impl Drop for BindlessMaterial {
+    fn drop(&mut self) {
+        self.table
+            .slots
+            .lock()
+            .unwrap()
+            .free_materials
+            .push(self.index);
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::OnceLock;
+
+use crate::texture::Texture;
+
+const SIZE: usize = 64;
+const SIGMA: f32 = 1.5;
+
+// A tiling square of blue noise, whose values are spread evenly with no low-frequency clumps, so
+// jitter and dithering from it read as fine grain rather than blotches. Green and blue are red
+// shifted by half the tile, so each channel can be used independently.
+pub fn texture() -> &'static Texture {
+    static TEXTURE: OnceLock<Texture> = OnceLock::new();
+    TEXTURE.get_or_init(|| {
+        let ranks = void_and_cluster();
+        let value =
+            |x: usize, y: usize| (ranks[y % SIZE * SIZE + x % SIZE] * 256 / SIZE.pow(2)) as u8;
+        let pixels = (0..SIZE)
+            .flat_map(|y| (0..SIZE).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                [
+                    value(x, y),
+                    value(x + SIZE / 2, y),
+                    value(x, y + SIZE / 2),
+                    255,
+                ]
+            })
+            .collect();
+        Texture::new_linear(SIZE as _, SIZE as _, pixels)
+    })
+}
+
+// Ulichney's void-and-cluster method, ranking every pixel by the order it's placed in. Each pixel
+// goes wherever the pattern so far leaves the biggest gap, as measured by a Gaussian on the torus.
+fn void_and_cluster() -> Vec<usize> {
+    let pixel_count = SIZE * SIZE;
+
+    let kernel: Vec<f32> = (0..pixel_count)
+        .map(|i| {
+            let wrapped = |d: usize| d.min(SIZE - d) as f32;
+            let (dx, dy) = (wrapped(i % SIZE), wrapped(i / SIZE));
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+    let mut pattern = vec![false; pixel_count];
+    let mut energy = vec![0.0; pixel_count];
+    let toggle = |pattern: &mut [bool], energy: &mut [f32], i: usize| {
+        pattern[i] = !pattern[i];
+        let sign = if pattern[i] { 1.0 } else { -1.0 };
+        let (x, y) = (i % SIZE, i / SIZE);
+        for (j, energy) in energy.iter_mut().enumerate() {
+            let dx = (j % SIZE + SIZE - x) % SIZE;
+            let dy = (j / SIZE + SIZE - y) % SIZE;
+            *energy += sign * kernel[dy * SIZE + dx];
+        }
+    };
+    let tightest_cluster = |pattern: &[bool], energy: &[f32]| {
+        (0..pixel_count)
+            .filter(|&i| pattern[i])
+            .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f32]| {
+        (0..pixel_count)
+            .filter(|&i| !pattern[i])
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+
+    // A sparse random start, with a fixed seed so every run gets the same noise
+    let mut seed = 0x2545_f491_u32;
+    let initial_count = pixel_count / 10;
+    while pattern.iter().filter(|&&set| set).count() < initial_count {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        let i = seed as usize % pixel_count;
+        if !pattern[i] {
+            toggle(&mut pattern, &mut energy, i);
+        }
+    }
+
+    // Moving the most crowded pixel into the biggest gap until it's already there evens it out
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, cluster);
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; pixel_count];
+
+    // The starting pattern is ranked by taking it apart, most crowded first
+    let (mut removing, mut removing_energy) = (pattern.clone(), energy.clone());
+    for rank in (0..initial_count).rev() {
+        let cluster = tightest_cluster(&removing, &removing_energy);
+        toggle(&mut removing, &mut removing_energy, cluster);
+        ranks[cluster] = rank;
+    }
+
+    // The rest fill in the gaps
+    for rank in initial_count..pixel_count {
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        ranks[void] = rank;
+    }
+
+    ranks
+}
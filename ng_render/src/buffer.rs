@@ -0,0 +1,164 @@
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+
+use crate::{
+    frame::FRAMES_IN_FLIGHT,
+    guard::{Guardable, GuardableResource, Guarded},
+};
+
+// The default is null, so destroying it does nothing
+#[derive(Default)]
+pub struct Buffer {
+    pub buffer: vk::Buffer,
+    allocation_size: vk::DeviceSize,
+    pub memory: vk::DeviceMemory,
+    pub size: vk::DeviceSize,
+}
+
+// Bytes of memory bound to every live buffer, for RendererStats
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+pub fn allocated_bytes() -> u64 {
+    ALLOCATED_BYTES.load(Ordering::Relaxed)
+}
+
+impl Buffer {
+    pub unsafe fn new<D, E>(
+        device: D,
+        buffer_create_info: &vk::BufferCreateInfo,
+        select_memory_type: impl Fn(vk::MemoryRequirements) -> Result<u32, E>,
+    ) -> VkResult<Result<Guarded<(Self, D)>, E>>
+    where
+        D: Deref<Target = ash::Device> + Clone,
+    {
+        let buffer = device
+            .create_buffer(buffer_create_info, None)?
+            .guard_with(device.clone());
+
+        let buffer_memory_requirements = device.get_buffer_memory_requirements(*buffer);
+        let memory_type = match select_memory_type(buffer_memory_requirements) {
+            Ok(memory_type) => memory_type,
+            Err(err) => return Ok(Err(err)),
+        };
+
+        let allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(buffer_memory_requirements.size)
+            .memory_type_index(memory_type);
+        let memory = device
+            .allocate_memory(&allocate_info, None)?
+            .guard_with(device.clone());
+
+        device.bind_buffer_memory(*buffer, *memory, 0)?;
+
+        let buffer = Self {
+            buffer: buffer.take(),
+            allocation_size: buffer_memory_requirements.size,
+            memory: memory.take(),
+            size: buffer_create_info.size,
+        };
+        ALLOCATED_BYTES.fetch_add(buffer.allocation_size, Ordering::Relaxed);
+        Ok(Ok(buffer.guard_with(device)))
+    }
+
+    // Only valid for buffers whose memory is HOST_VISIBLE and HOST_COHERENT
+    pub unsafe fn write(
+        &self,
+        device: &ash::Device,
+        offset: vk::DeviceSize,
+        data: &[u8],
+    ) -> VkResult<()> {
+        let size = data.len() as vk::DeviceSize;
+        assert!(offset + size <= self.size);
+        let mapped = device.map_memory(self.memory, offset, size, vk::MemoryMapFlags::empty())?;
+        std::ptr::copy_nonoverlapping(data.as_ptr(), mapped as *mut u8, data.len());
+        device.unmap_memory(self.memory);
+        Ok(())
+    }
+
+    // Only valid for buffers whose memory is HOST_VISIBLE and HOST_COHERENT
+    pub unsafe fn read(
+        &self,
+        device: &ash::Device,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+    ) -> VkResult<Vec<u8>> {
+        assert!(offset + size <= self.size);
+        let mapped = device.map_memory(self.memory, offset, size, vk::MemoryMapFlags::empty())?;
+        let data = std::slice::from_raw_parts(mapped as *const u8, size as usize).to_vec();
+        device.unmap_memory(self.memory);
+        Ok(data)
+    }
+
+    pub unsafe fn destroy_with(&mut self, device: &ash::Device) {
+        device.destroy_buffer(self.buffer, None);
+        device.free_memory(self.memory, None);
+        ALLOCATED_BYTES.fetch_sub(self.allocation_size, Ordering::Relaxed);
+    }
+}
+
+impl<C> Guardable for (Buffer, C)
+where
+    C: Deref<Target = ash::Device>,
+{
+    type Resource = Buffer;
+
+    fn deref(&self) -> &Self::Resource {
+        &self.0
+    }
+
+    fn deref_mut(&mut self) -> &mut Self::Resource {
+        &mut self.0
+    }
+
+    fn take(self) -> Self::Resource {
+        self.0
+    }
+
+    unsafe fn drop(self) {
+        let (mut resource, context) = self;
+        resource.destroy_with(&context);
+    }
+}
+
+// Buffers dropped while frames in flight might still be reading them. Each is destroyed once every
+// frame in flight has been waited on since it was retired, so nothing submitted before can still
+// be using it, without stalling on the rest of the GPU's work.
+#[derive(Default)]
+pub struct RetiredBuffers {
+    buffers: Mutex<Vec<(Buffer, u32)>>, // with a bit for each frame in flight not yet waited on
+}
+
+impl RetiredBuffers {
+    pub fn retire(&self, buffer: Buffer) {
+        let waiting = (1 << FRAMES_IN_FLIGHT) - 1;
+        self.buffers.lock().unwrap().push((buffer, waiting));
+    }
+
+    // This frame in flight's fence was just waited on
+    pub unsafe fn recycle(&self, device: &ash::Device, frame_index: usize) {
+        self.buffers
+            .lock()
+            .unwrap()
+            .retain_mut(|(buffer, waiting)| {
+                *waiting &= !(1 << frame_index);
+                if *waiting == 0 {
+                    buffer.destroy_with(device);
+                }
+                *waiting != 0
+            });
+    }
+
+    // Only once the device is idle
+    pub unsafe fn destroy_with(&mut self, device: &ash::Device) {
+        for (mut buffer, _) in self.buffers.get_mut().unwrap().drain(..) {
+            buffer.destroy_with(device);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::f32::consts::TAU;
+
+use ash::vk;
+use nalgebra as na;
+
+// Both use reverse-Z, so depth decreases with distance from the camera
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    // Field of view is diagonal, in radians. There's no far plane.
+    Perspective {
+        fov: f32,
+        near: f32,
+    },
+    // Height is in world units; width follows from the aspect ratio
+    Orthographic {
+        height: f32,
+        near: f32,
+        far: f32,
+    },
+    // Angles in radians from the view direction to each edge, like a headset eye's, so left and
+    // down are usually negative. Ignores the aspect ratio. There's no far plane.
+    Asymmetric {
+        left: f32,
+        right: f32,
+        up: f32,
+        down: f32,
+        near: f32,
+    },
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Self::Perspective {
+            fov: TAU * 0.25,
+            near: 0.1,
+        }
+    }
+}
+
+impl Projection {
+    // Combination of coordinate swizzle and projection
+    // cameraspace +x, +y, +z maps to screenspace depth, -x, -y
+    fn matrix(&self, resolution: vk::Extent2D) -> na::Matrix4<f32> {
+        let aspect = resolution.height as f32 / resolution.width as f32;
+        match *self {
+            // cameraspace x of near..infinity maps to screenspace depth 1..0
+            Self::Perspective { fov, near } => {
+                let cotan_x = (aspect * aspect + 1.0).sqrt() / (0.5 * fov).tan();
+                let cotan_y = cotan_x / aspect;
+                [
+                    [0.0, 0.0, 0.0, 1.0],
+                    [-cotan_x, 0.0, 0.0, 0.0],
+                    [0.0, -cotan_y, 0.0, 0.0],
+                    [0.0, 0.0, near, 0.0],
+                ]
+                .into()
+            }
+            // cameraspace x of near..far maps to screenspace depth 1..0
+            Self::Orthographic { height, near, far } => {
+                let scale_x = 2.0 * aspect / height;
+                let scale_y = 2.0 / height;
+                let depth_scale = 1.0 / (far - near);
+                [
+                    [0.0, 0.0, -depth_scale, 0.0],
+                    [-scale_x, 0.0, 0.0, 0.0],
+                    [0.0, -scale_y, 0.0, 0.0],
+                    [0.0, 0.0, far * depth_scale, 1.0],
+                ]
+                .into()
+            }
+            // Like perspective, but with the center of the screen offset from the view direction
+            Self::Asymmetric {
+                left,
+                right,
+                up,
+                down,
+                near,
+            } => {
+                let (tan_left, tan_right) = (left.tan(), right.tan());
+                let (tan_up, tan_down) = (up.tan(), down.tan());
+                let width = tan_right - tan_left;
+                let height = tan_up - tan_down;
+                [
+                    [
+                        -(tan_right + tan_left) / width,
+                        (tan_up + tan_down) / height,
+                        0.0,
+                        1.0,
+                    ],
+                    [-2.0 / width, 0.0, 0.0, 0.0],
+                    [0.0, -2.0 / height, 0.0, 0.0],
+                    [0.0, 0.0, near, 0.0],
+                ]
+                .into()
+            }
+        }
+    }
+
+    pub(crate) fn near(&self) -> f32 {
+        match *self {
+            Self::Perspective { near, .. }
+            | Self::Orthographic { near, .. }
+            | Self::Asymmetric { near, .. } => near,
+        }
+    }
+
+    // Screenspace depth of things at a distance ahead of the camera
+    pub(crate) fn depth_at(&self, distance: f32) -> f32 {
+        match *self {
+            Self::Perspective { near, .. } | Self::Asymmetric { near, .. } => near / distance,
+            Self::Orthographic { near, far, .. } => ((far - distance) / (far - near)).max(0.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    pub transform: mint::ColumnMatrix4<f32>, // cameraspace to worldspace, looking along +x, +z up
+    pub projection: Projection,
+}
+
+impl Camera {
+    pub fn new(transform: mint::ColumnMatrix4<f32>) -> Self {
+        Self {
+            transform,
+            projection: Default::default(),
+        }
+    }
+
+    pub(crate) fn world_to_screen(&self, resolution: vk::Extent2D) -> na::Matrix4<f32> {
+        let transform: na::Matrix4<f32> = self.transform.into();
+        self.projection.matrix(resolution) * transform.try_inverse().unwrap()
+    }
+
+    pub(crate) fn position(&self) -> na::Point3<f32> {
+        na::Matrix4::from(self.transform).transform_point(&na::Point3::origin())
+    }
+}
@@ -0,0 +1,58 @@
+use renderdoc::{RenderDoc, V110};
+
+// Frame captures through RenderDoc's in-application API, which is only loaded when the app was
+// launched from RenderDoc or had it injected
+pub struct FrameCapture {
+    renderdoc: Option<RenderDoc<V110>>,
+    requested: bool,
+    capturing: bool,
+}
+
+impl FrameCapture {
+    pub fn new() -> Self {
+        let renderdoc = match RenderDoc::new() {
+            Ok(renderdoc) => Some(renderdoc),
+            Err(err) => {
+                log::info!(
+                    "RenderDoc unavailable, so frames won't be captured: {}",
+                    err
+                );
+                None
+            }
+        };
+        Self {
+            renderdoc,
+            requested: false,
+            capturing: false,
+        }
+    }
+
+    pub fn request(&mut self) {
+        if self.renderdoc.is_none() {
+            log::warn!("Ignoring frame capture request, since RenderDoc isn't loaded");
+        }
+        self.requested = true;
+    }
+
+    // Null device and window pointers match whichever RenderDoc sees, since there's only one of
+    // each
+    pub fn begin_frame(&mut self) {
+        if !std::mem::take(&mut self.requested) {
+            return;
+        }
+        if let Some(renderdoc) = &mut self.renderdoc {
+            renderdoc.start_frame_capture(std::ptr::null(), std::ptr::null());
+            self.capturing = true;
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        if !std::mem::take(&mut self.capturing) {
+            return;
+        }
+        if let Some(renderdoc) = &mut self.renderdoc {
+            renderdoc.end_frame_capture(std::ptr::null(), std::ptr::null());
+            log::info!("Captured frame with RenderDoc");
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ash::{version::DeviceV1_0, vk};
+use thiserror::Error;
+
+use crate::{
+    guard::GuardableResource,
+    image::Image,
+    shared::SharedStem,
+    texture::Texture,
+    upload::{self, UploadCache, UploadError},
+    util::{self, Descriptor},
+};
+
+// 10 bits a channel is plenty for smooth grades, and unlike half floats is always filterable
+const LUT_FORMAT: vk::Format = vk::Format::A2B10G10R10_UNORM_PACK32;
+
+#[derive(Error, Debug)]
+pub enum CubeError {
+    #[error("Line {0} couldn't be parsed")]
+    Malformed(usize),
+    #[error("Only 3D LUTs are supported")]
+    Not3d,
+    #[error("LUT_3D_SIZE is missing")]
+    MissingSize,
+    #[error("LUT_3D_SIZE must be at least 2, not {0}")]
+    BadSize(u32),
+    #[error("Only the default domain of [0, 1] is supported")]
+    UnsupportedDomain,
+    #[error("Expected {expected} entries but found {found}")]
+    WrongEntryCount { expected: usize, found: usize },
+}
+
+// CPU-side 3D lookup table that color grading maps the tonemapped frame through, as RGB triples
+// with red varying fastest, then green, then blue, like .cube files. It takes and gives
+// sRGB-encoded color in [0, 1]. Like textures, it's uploaded lazily the first time it's drawn.
+#[derive(Debug)]
+pub struct ColorLut {
+    id: u64,
+    pixels: Vec<f32>,
+    size: u32, // along each edge, usually 32
+}
+
+impl ColorLut {
+    pub fn new(size: u32, pixels: Vec<f32>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        assert!(size >= 2, "Color LUT must be at least 2 on each side");
+        assert_eq!(
+            pixels.len(),
+            3 * (size as usize).pow(3),
+            "Color LUT pixels must be RGB"
+        );
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            pixels,
+            size,
+        }
+    }
+
+    // Leaves every color as it was
+    pub fn identity(size: u32) -> Self {
+        let step = |i: u32| i as f32 / (size - 1) as f32;
+        let pixels = (0..size.pow(3))
+            .flat_map(|i| [step(i % size), step(i / size % size), step(i / size / size)])
+            .collect();
+        Self::new(size, pixels)
+    }
+
+    // Adobe's text format, as exported by most grading tools
+    pub fn from_cube(source: &str) -> Result<Self, CubeError> {
+        let mut size = None;
+        let mut pixels = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            let malformed = || CubeError::Malformed(index + 1);
+            let mut words = line.split_whitespace();
+            match words.next() {
+                None => continue,
+                Some(word) if word.starts_with('#') || word == "TITLE" => continue,
+                Some("LUT_1D_SIZE") => return Err(CubeError::Not3d),
+                Some("LUT_3D_SIZE") => {
+                    let parsed = words.next().and_then(|word| word.parse().ok());
+                    size = Some(parsed.ok_or_else(malformed)?);
+                }
+                Some(keyword @ ("DOMAIN_MIN" | "DOMAIN_MAX")) => {
+                    let default = if keyword == "DOMAIN_MIN" { 0.0 } else { 1.0 };
+                    for word in words {
+                        if word.parse::<f32>().map_err(|_| malformed())? != default {
+                            return Err(CubeError::UnsupportedDomain);
+                        }
+                    }
+                }
+                Some(word) => {
+                    let entry = std::iter::once(word)
+                        .chain(words)
+                        .map(str::parse)
+                        .collect::<Result<Vec<f32>, _>>()
+                        .map_err(|_| malformed())?;
+                    if entry.len() != 3 {
+                        return Err(malformed());
+                    }
+                    pixels.extend(entry);
+                }
+            }
+        }
+
+        let size: u32 = size.ok_or(CubeError::MissingSize)?;
+        if size < 2 {
+            return Err(CubeError::BadSize(size));
+        }
+        let expected = (size as usize).pow(3);
+        if pixels.len() != 3 * expected {
+            return Err(CubeError::WrongEntryCount {
+                expected,
+                found: pixels.len() / 3,
+            });
+        }
+        Ok(Self::new(size, pixels))
+    }
+
+    // The unwrapped strips game engines bake LUTs into: size slices of increasing blue side by
+    // side, each with red across and green down
+    pub fn from_strip(texture: &Texture) -> Self {
+        let size = texture.height();
+        assert_eq!(
+            texture.width(),
+            size * size,
+            "Color LUT strip must be as many squares wide as it is tall"
+        );
+
+        let row_length = 4 * texture.width() as usize;
+        let pixels = (0..size.pow(3) as usize)
+            .flat_map(|i| {
+                let size = size as usize;
+                let (red, green, blue) = (i % size, i / size % size, i / (size * size));
+                let offset = green * row_length + 4 * (blue * size + red);
+                texture.pixels()[offset..offset + 3]
+                    .iter()
+                    .map(|&value| value as f32 / 255.0)
+            })
+            .collect();
+        Self::new(size, pixels)
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn pixels(&self) -> &[f32] {
+        &self.pixels
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
+
+// Crossfades from one LUT to the next as the transition plays out. Without a LUT, the frame is
+// left ungraded.
+#[derive(Clone)]
+pub(crate) struct ColorGrading {
+    from: Option<Arc<ColorLut>>,
+    started: Instant,
+    to: Option<Arc<ColorLut>>,
+    transition: Duration,
+}
+
+impl ColorGrading {
+    // A transition that's interrupted starts the next one from its target, rather than from
+    // partway through
+    pub fn transitioned(&self, lut: Option<ColorLut>, transition: Duration, now: Instant) -> Self {
+        Self {
+            from: self.to.clone(),
+            started: now,
+            to: lut.map(Arc::new),
+            transition,
+        }
+    }
+
+    pub fn from(&self) -> Option<&Arc<ColorLut>> {
+        self.from.as_ref()
+    }
+
+    pub fn to(&self) -> Option<&Arc<ColorLut>> {
+        self.to.as_ref()
+    }
+
+    // How far the transition's gotten by then, from 0 to 1
+    pub fn blend(&self, now: Instant) -> f32 {
+        let elapsed = now.saturating_duration_since(self.started);
+        if elapsed >= self.transition {
+            1.0
+        } else {
+            elapsed.as_secs_f32() / self.transition.as_secs_f32()
+        }
+    }
+}
+
+impl Default for ColorGrading {
+    fn default() -> Self {
+        Self {
+            from: None,
+            started: Instant::now(),
+            to: None,
+            transition: Duration::ZERO,
+        }
+    }
+}
+
+// What tonemapping grades a frame with
+pub struct ColorGrade {
+    pub blend: f32, // from 0 for all of from, to 1 for all of to
+    pub from: Arc<GpuColorLut>,
+    pub to: Arc<GpuColorLut>,
+}
+
+// Uploads color LUTs as 3D textures, each bound as a set of its own with a single sampler at
+// binding 0. Missing LUTs are stood in for by the identity.
+pub struct ColorGradingStem {
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    identity: Arc<GpuColorLut>,
+    luts: Mutex<UploadCache<ColorLut, GpuColorLut>>,
+    sampler: vk::Sampler,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl ColorGradingStem {
+    pub fn new(shared_stem: Arc<SharedStem>) -> Result<Self, UploadError> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[(
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    vk::ShaderStageFlags::FRAGMENT,
+                )],
+            )?;
+            shared_stem.set_name(*descriptor_set_layout, "color grading")?;
+
+            let sampler_create_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::LINEAR)
+                .min_filter(vk::Filter::LINEAR)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+            let sampler = device
+                .create_sampler(&sampler_create_info, None)?
+                .guard_with(device);
+            shared_stem.set_name(*sampler, "color grading")?;
+
+            // Linear filtering makes the smallest identity exact
+            let identity = Arc::new(Self::upload(
+                &shared_stem,
+                &ColorLut::identity(2),
+                *descriptor_set_layout,
+                *sampler,
+            )?);
+
+            Ok(Self {
+                descriptor_set_layout: descriptor_set_layout.take(),
+                identity,
+                luts: Mutex::new(UploadCache::new()),
+                sampler: sampler.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    pub fn descriptor_set_layout(&self) -> vk::DescriptorSetLayout {
+        self.descriptor_set_layout
+    }
+
+    pub fn identity(&self) -> &Arc<GpuColorLut> {
+        &self.identity
+    }
+
+    // Ungraded until the first LUT is set
+    pub fn prepare_grade(
+        &self,
+        grading: &ColorGrading,
+        now: Instant,
+    ) -> Result<Option<ColorGrade>, UploadError> {
+        if grading.from().is_none() && grading.to().is_none() {
+            return Ok(None);
+        }
+        Ok(Some(ColorGrade {
+            blend: grading.blend(now),
+            from: self.prepare(grading.from())?,
+            to: self.prepare(grading.to())?,
+        }))
+    }
+
+    fn prepare(&self, lut: Option<&Arc<ColorLut>>) -> Result<Arc<GpuColorLut>, UploadError> {
+        let lut = match lut {
+            Some(lut) => lut,
+            None => return Ok(self.identity.clone()),
+        };
+        let mut luts = self.luts.lock().unwrap();
+        luts.evict_unused();
+        luts.get_or_upload(lut.id(), lut, |lut| unsafe {
+            Self::upload(
+                &self.shared_stem,
+                lut,
+                self.descriptor_set_layout,
+                self.sampler,
+            )
+        })
+    }
+
+    unsafe fn upload(
+        shared_stem: &Arc<SharedStem>,
+        lut: &ColorLut,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+    ) -> Result<GpuColorLut, UploadError> {
+        let device = shared_stem.device();
+
+        let packed: Vec<u32> = lut
+            .pixels()
+            .chunks_exact(3)
+            .map(|rgb| {
+                let channel = |value: f32| (value.clamp(0.0, 1.0) * 1023.0).round() as u32;
+                3 << 30 | channel(rgb[2]) << 20 | channel(rgb[1]) << 10 | channel(rgb[0])
+            })
+            .collect();
+        let staging_buffer =
+            upload::create_staging_buffer(shared_stem, &[util::as_bytes(&packed)])?;
+
+        let select_device_local_memory = |memory_requirements: vk::MemoryRequirements| {
+            shared_stem
+                .select_memory_type(memory_requirements, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+                .ok_or(UploadError::NoAcceptableMemoryType(
+                    memory_requirements,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                ))
+        };
+        let image_create_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_3D)
+            .format(LUT_FORMAT)
+            .extent(vk::Extent3D {
+                width: lut.size(),
+                height: lut.size(),
+                depth: lut.size(),
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let image = Image::new(
+            device,
+            &image_create_info,
+            select_device_local_memory,
+            vk::ImageViewType::TYPE_3D,
+            vk::ImageAspectFlags::COLOR,
+        )??;
+        shared_stem.set_name(image.image, "color LUT")?;
+        shared_stem.set_name(image.memory, "color LUT")?;
+        shared_stem.set_name(image.view, "color LUT")?;
+
+        shared_stem.submit_one_time_commands(|command_buffer| {
+            let image_memory_barriers = [util::image_barrier(
+                image.image,
+                1,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::TRANSFER_WRITE,
+            )];
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &image_memory_barriers,
+            );
+
+            let region = vk::BufferImageCopy {
+                buffer_offset: 0,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: Default::default(),
+                image_extent: image.resolution,
+            };
+            device.cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer.buffer,
+                image.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+            );
+
+            let image_memory_barriers = [util::image_barrier(
+                image.image,
+                1,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            )];
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &image_memory_barriers,
+            );
+        })?;
+
+        let descriptor_pool = util::create_descriptor_pool(
+            device,
+            1,
+            &[vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: 1,
+            }],
+        )?;
+        shared_stem.set_name(*descriptor_pool, "color LUT")?;
+
+        let descriptor_set =
+            util::allocate_descriptor_set(device, *descriptor_pool, descriptor_set_layout)?;
+        util::write_descriptor_set(
+            device,
+            descriptor_set,
+            &[(0, Descriptor::CombinedImageSampler(image.view, sampler))],
+        );
+        shared_stem.set_name(descriptor_set, "color LUT")?;
+
+        Ok(GpuColorLut {
+            descriptor_pool: descriptor_pool.take(),
+            descriptor_set,
+            image: image.take(),
+            shared_stem: shared_stem.clone(),
+        })
+    }
+}
+
+impl Drop for ColorGradingStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+pub struct GpuColorLut {
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_set: vk::DescriptorSet,
+    image: Image,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl GpuColorLut {
+    pub fn descriptor_set(&self) -> vk::DescriptorSet {
+        self.descriptor_set
+    }
+}
+
+impl Drop for GpuColorLut {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            self.image.destroy_with(device);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+
+use crate::{shared::SharedStem, util};
+
+// A compute shader with the pipeline it's dispatched through. Passes are stem-level; whoever
+// dispatches one owns the descriptor sets it reads and writes, and the barriers around it.
+pub struct ComputePass {
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    shader_module: vk::ShaderModule,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl ComputePass {
+    // Push constants, if any, are a single range starting at offset 0
+    pub fn new(
+        shared_stem: Arc<SharedStem>,
+        name: &str,
+        spirv: &[u32],
+        set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_size: usize,
+    ) -> VkResult<Self> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let push_constant_ranges = [vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::COMPUTE,
+                offset: 0,
+                size: push_constant_size as _,
+            }];
+            let push_constant_count = if push_constant_size > 0 { 1 } else { 0 };
+            let pipeline_layout = util::create_pipeline_layout(
+                device,
+                set_layouts,
+                &push_constant_ranges[..push_constant_count],
+            )?;
+            shared_stem.set_name(*pipeline_layout, name)?;
+
+            let shader_module = util::create_shader_module(device, spirv)?;
+            shared_stem.set_name(*shader_module, &format!("{} comp", name))?;
+
+            let pipeline =
+                util::create_compute_pipeline(device, *shader_module, *pipeline_layout, None)?;
+            shared_stem.set_name(*pipeline, name)?;
+
+            Ok(Self {
+                pipeline: pipeline.take(),
+                pipeline_layout: pipeline_layout.take(),
+                shader_module: shader_module.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    // Must be recorded outside of a render pass
+    pub unsafe fn dispatch(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+        workgroup_counts: [u32; 3],
+    ) {
+        let device = self.shared_stem.device();
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline,
+        );
+        if !descriptor_sets.is_empty() {
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                descriptor_sets,
+                &[],
+            );
+        }
+        if !push_constants.is_empty() {
+            device.cmd_push_constants(
+                command_buffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push_constants,
+            );
+        }
+
+        let [x, y, z] = workgroup_counts;
+        device.cmd_dispatch(command_buffer, x, y, z);
+    }
+}
+
+impl Drop for ComputePass {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_shader_module(self.shader_module, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+        }
+    }
+}
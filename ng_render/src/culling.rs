@@ -0,0 +1,348 @@
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use nalgebra as na;
+
+use crate::{
+    buffer::Buffer,
+    compute::ComputePass,
+    frame::FRAMES_IN_FLIGHT,
+    guard::GuardableResource,
+    mesh::GpuMeshInstance,
+    shaders::include_shader,
+    shared::{SharedFrond, SharedStem},
+    upload::{self, UploadError},
+    util::{self, Descriptor},
+};
+
+// Draws past this many skip GPU culling and are always drawn
+pub const MAX_INDIRECT_DRAWS: usize = 4096;
+
+const WORKGROUP_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CullingMode {
+    Cpu, // draws each visible mesh directly
+    #[default]
+    Gpu, // a compute shader writes indirect draws, zeroing the instance count of hidden ones
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct CullBuffer {
+    planes: [[f32; 4]; 6],
+    draw_count: u32,
+}
+
+// Twenty-four f32s and a u32
+unsafe impl util::Pod for CullBuffer {}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct DrawData {
+    bounds_min: [f32; 3],
+    index_count: u32,
+    bounds_max: [f32; 3],
+    _padding: u32,
+}
+
+// Six f32s and two u32s, the last of which pads explicitly
+unsafe impl util::Pod for DrawData {}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: na::Point3<f32>,
+    pub max: na::Point3<f32>,
+}
+
+impl Aabb {
+    pub fn from_points(points: impl IntoIterator<Item = na::Point3<f32>>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Self::point(first), |aabb, point| Self {
+            min: aabb.min.inf(&point),
+            max: aabb.max.sup(&point),
+        }))
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    fn point(point: na::Point3<f32>) -> Self {
+        Self {
+            min: point,
+            max: point,
+        }
+    }
+
+    pub fn center(&self) -> na::Point3<f32> {
+        na::center(&self.min, &self.max)
+    }
+
+    // Still axis-aligned, so it may be looser than the transformed box
+    pub fn transformed(&self, transform: &na::Matrix4<f32>) -> Self {
+        let center = self.center();
+        let half_extent = 0.5 * (self.max - self.min);
+
+        let center = transform.transform_point(&center);
+        let half_extent = transform.fixed_slice::<3, 3>(0, 0).abs() * half_extent;
+        Self {
+            min: center - half_extent,
+            max: center + half_extent,
+        }
+    }
+}
+
+pub struct Frustum {
+    planes: [na::Vector4<f32>; 6],
+}
+
+impl Frustum {
+    pub fn new(view: &na::Matrix4<f32>) -> Self {
+        Self {
+            planes: util::frustum_planes(view),
+        }
+    }
+
+    // Conservative: boxes near the frustum's corners may pass despite being outside
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal is the last one to leave
+            let corner = na::Point3::from(na::Vector3::from_fn(|i, _| {
+                if plane[i] >= 0.0 {
+                    aabb.max[i]
+                } else {
+                    aabb.min[i]
+                }
+            }));
+            plane.dot(&corner.to_homogeneous()) >= 0.0
+        })
+    }
+
+    pub fn cull(&self, instances: &[GpuMeshInstance]) -> Vec<GpuMeshInstance> {
+        instances
+            .iter()
+            .filter(|instance| self.intersects(&instance.world_bounds()))
+            .cloned()
+            .collect()
+    }
+}
+
+// The first count meshes are drawn from consecutive commands in buffer
+pub struct IndirectDraws {
+    pub buffer: vk::Buffer,
+    pub count: usize,
+}
+
+pub struct CullingStem {
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    pass: ComputePass,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl CullingStem {
+    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
+        unsafe {
+            let device = shared_stem.device();
+
+            // Draw data in, indirect draws out
+            let descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[
+                    (
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::COMPUTE,
+                    ),
+                    (
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::COMPUTE,
+                    ),
+                ],
+            )?;
+            shared_stem.set_name(*descriptor_set_layout, "culling")?;
+
+            let pass = ComputePass::new(
+                shared_stem.clone(),
+                "culling",
+                &include_shader!("shaders/cull.comp"),
+                &[*descriptor_set_layout],
+                std::mem::size_of::<CullBuffer>(),
+            )?;
+
+            Ok(Self {
+                descriptor_set_layout: descriptor_set_layout.take(),
+                pass,
+                shared_stem,
+            })
+        }
+    }
+}
+
+impl Drop for CullingStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+pub struct CullingFrond {
+    culling_stem: Arc<CullingStem>,
+    descriptor_sets: Vec<vk::DescriptorSet>, // per frame in flight
+    draw_buffers: Vec<Buffer>,               // per frame in flight
+    indirect_buffers: Vec<Buffer>,           // per frame in flight
+    shared_frond: Arc<SharedFrond>,
+}
+
+impl CullingFrond {
+    pub fn new(
+        culling_stem: Arc<CullingStem>,
+        shared_frond: Arc<SharedFrond>,
+    ) -> Result<Self, UploadError> {
+        let shared_stem = &culling_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+
+            let mut draw_buffers = Vec::<Buffer>::new().guard_with(device);
+            let mut indirect_buffers = Vec::<Buffer>::new().guard_with(device);
+            for _ in 0..FRAMES_IN_FLIGHT {
+                let draw_buffer = upload::create_buffer(
+                    shared_stem,
+                    (MAX_INDIRECT_DRAWS * std::mem::size_of::<DrawData>()) as _,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )?;
+                shared_stem.set_name(draw_buffer.buffer, "culling draws")?;
+                shared_stem.set_name(draw_buffer.memory, "culling draws")?;
+                draw_buffers.push(draw_buffer.take());
+
+                let indirect_buffer = upload::create_buffer(
+                    shared_stem,
+                    (MAX_INDIRECT_DRAWS * std::mem::size_of::<vk::DrawIndexedIndirectCommand>())
+                        as _,
+                    vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                )?;
+                shared_stem.set_name(indirect_buffer.buffer, "indirect draws")?;
+                shared_stem.set_name(indirect_buffer.memory, "indirect draws")?;
+                indirect_buffers.push(indirect_buffer.take());
+            }
+
+            let descriptor_allocator = shared_frond.descriptor_allocator();
+            let mut descriptor_sets = Vec::<vk::DescriptorSet>::with_capacity(FRAMES_IN_FLIGHT)
+                .guard_with((descriptor_allocator, device));
+            for (draw_buffer, indirect_buffer) in draw_buffers.iter().zip(indirect_buffers.iter()) {
+                let descriptor_set =
+                    descriptor_allocator.allocate(device, culling_stem.descriptor_set_layout)?;
+                util::write_descriptor_set(
+                    device,
+                    *descriptor_set,
+                    &[
+                        (0, Descriptor::StorageBuffer(draw_buffer.buffer)),
+                        (1, Descriptor::StorageBuffer(indirect_buffer.buffer)),
+                    ],
+                );
+                shared_stem.set_name(*descriptor_set, "culling")?;
+                descriptor_sets.push(descriptor_set.take());
+            }
+
+            Ok(Self {
+                draw_buffers: draw_buffers.take(),
+                indirect_buffers: indirect_buffers.take(),
+                culling_stem,
+                descriptor_sets: descriptor_sets.take(),
+                shared_frond,
+            })
+        }
+    }
+
+    // Must be recorded outside of a render pass, before the indirect draws it writes
+    pub unsafe fn cull(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        view: &na::Matrix4<f32>,
+        meshes: &[GpuMeshInstance],
+    ) -> VkResult<IndirectDraws> {
+        let device = self.shared_frond.device();
+
+        let meshes = &meshes[..meshes.len().min(MAX_INDIRECT_DRAWS)];
+        let indirect_draws = IndirectDraws {
+            buffer: self.indirect_buffers[frame_index].buffer,
+            count: meshes.len(),
+        };
+        if meshes.is_empty() {
+            return Ok(indirect_draws);
+        }
+
+        let draw_data: Vec<_> = meshes
+            .iter()
+            .map(|instance| {
+                let bounds = instance.world_bounds();
+                DrawData {
+                    bounds_min: bounds.min.into(),
+                    index_count: instance.mesh.index_count(),
+                    bounds_max: bounds.max.into(),
+                    _padding: 0,
+                }
+            })
+            .collect();
+        self.draw_buffers[frame_index].write(device, 0, util::as_bytes(&draw_data))?;
+
+        let cull_buffer = CullBuffer {
+            planes: util::frustum_planes(view).map(|plane| plane.into()),
+            draw_count: meshes.len() as _,
+        };
+        let workgroup_count = meshes.len().div_ceil(WORKGROUP_SIZE);
+        self.culling_stem.pass.dispatch(
+            command_buffer,
+            &[self.descriptor_sets[frame_index]],
+            util::as_bytes(&[cull_buffer]),
+            [workgroup_count as _, 1, 1],
+        );
+
+        let buffer_memory_barriers = [util::buffer_barrier(
+            indirect_draws.buffer,
+            vk::AccessFlags::SHADER_WRITE,
+            vk::AccessFlags::INDIRECT_COMMAND_READ,
+        )];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::DRAW_INDIRECT,
+            vk::DependencyFlags::empty(),
+            &[],
+            &buffer_memory_barriers,
+            &[],
+        );
+
+        Ok(indirect_draws)
+    }
+}
+
+impl Drop for CullingFrond {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_frond.device();
+            let _ = device.device_wait_idle();
+
+            self.shared_frond
+                .descriptor_allocator()
+                .free(device, &self.descriptor_sets);
+            for draw_buffer in &mut self.draw_buffers {
+                draw_buffer.destroy_with(device);
+            }
+            for indirect_buffer in &mut self.indirect_buffers {
+                indirect_buffer.destroy_with(device);
+            }
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use std::ffi::CStr;
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+use nalgebra as na;
+
+use crate::{
+    guard::{GuardableResource, Guarded},
+    shaders::include_shader,
+    shared::{SharedFrond, SharedStem, ViewBuffer},
+    sync,
+    upload::UploadError,
+    util,
+};
+
+// Consecutive pairs form lines, in worldspace. Colors are linear and unlit, so they're
+// tonemapped like everything else.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct LineVertex {
+    pub position: mint::Point3<f32>,
+    pub color: mint::Vector3<f32>,
+}
+
+impl LineVertex {
+    fn binding_descriptions() -> [vk::VertexInputBindingDescription; 1] {
+        [vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: std::mem::size_of::<Self>() as _,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }]
+    }
+
+    fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 2] {
+        let vec3_size = std::mem::size_of::<mint::Vector3<f32>>() as u32;
+        [
+            vk::VertexInputAttributeDescription {
+                location: 0,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 1,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: vec3_size,
+            },
+        ]
+    }
+}
+
+pub struct DebugDrawStem {
+    frag_shader_module: vk::ShaderModule,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    shared_stem: Arc<SharedStem>,
+    vert_shader_module: vk::ShaderModule,
+}
+
+impl DebugDrawStem {
+    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let pipeline_layout =
+                util::create_pipeline_layout(device, &[], &[ViewBuffer::push_constant_range()])?;
+            shared_stem.set_name(*pipeline_layout, "debug lines")?;
+
+            let vert_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/debug-line.vert"))?;
+            shared_stem.set_name(*vert_shader_module, "debug line vert")?;
+
+            let frag_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/debug-line.frag"))?;
+            shared_stem.set_name(*frag_shader_module, "debug line frag")?;
+
+            let render_pass = Self::create_render_pass(
+                device,
+                SharedFrond::LIGHT_FORMAT,
+                shared_stem.depth_stencil_format(),
+                shared_stem.depth_read_layout(),
+            )?;
+            shared_stem.set_name(*render_pass, "debug lines")?;
+
+            let pipeline = Self::create_pipeline(
+                device,
+                *vert_shader_module,
+                *frag_shader_module,
+                *pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*pipeline, "debug lines")?;
+
+            Ok(Self {
+                frag_shader_module: frag_shader_module.take(),
+                pipeline: pipeline.take(),
+                pipeline_layout: pipeline_layout.take(),
+                render_pass: render_pass.take(),
+                vert_shader_module: vert_shader_module.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    // Draws into the light attachment as left by the transparency pass, testing against depth
+    unsafe fn create_render_pass(
+        device: &ash::Device,
+        light_format: vk::Format,
+        depth_format: vk::Format,
+        depth_layout: vk::ImageLayout,
+    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(light_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(depth_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(depth_layout)
+                .final_layout(depth_layout)
+                .build(),
+        ];
+
+        let color_attachments = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_stencil_attachment = vk::AttachmentReference {
+            attachment: 1,
+            layout: depth_layout,
+        };
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_attachments)
+            .depth_stencil_attachment(&depth_stencil_attachment)
+            .build()];
+
+        let dependencies = [
+            sync::dependency_before(sync::COLOR_ATTACHMENT_WRITE, sync::COLOR_ATTACHMENT_BLEND),
+            sync::dependency_before(sync::DEPTH_ATTACHMENT_WRITE, sync::DEPTH_TEST),
+        ];
+
+        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+        Ok(device
+            .create_render_pass(&render_pass_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_pipeline(
+        device: &ash::Device,
+        vert_shader_module: vk::ShaderModule,
+        frag_shader_module: vk::ShaderModule,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        let vertex_binding_descriptions = LineVertex::binding_descriptions();
+        let vertex_attribute_descriptions = LineVertex::attribute_descriptions();
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_binding_descriptions)
+            .vertex_attribute_descriptions(&vertex_attribute_descriptions);
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::LINE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        // Hidden behind the scene's geometry, without writing any depth of their own
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(false)
+            .depth_compare_op(vk::CompareOp::GREATER_OR_EQUAL)
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(false);
+
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            blend_enable: vk::FALSE,
+            color_write_mask: vk::ColorComponentFlags::all(),
+            ..Default::default()
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+}
+
+impl Drop for DebugDrawStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_shader_module(self.frag_shader_module, None);
+            device.destroy_shader_module(self.vert_shader_module, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+        }
+    }
+}
+
+pub struct DebugDrawFrond {
+    debug_draw_stem: Arc<DebugDrawStem>,
+    framebuffer: vk::Framebuffer,
+    shared_frond: Arc<SharedFrond>,
+}
+
+impl DebugDrawFrond {
+    pub fn new(
+        debug_draw_stem: Arc<DebugDrawStem>,
+        shared_frond: Arc<SharedFrond>,
+    ) -> Result<Self, UploadError> {
+        let shared_stem = &debug_draw_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+
+            let framebuffer = util::create_framebuffer(
+                device,
+                debug_draw_stem.render_pass,
+                &[shared_frond.light().view, shared_frond.depth_stencil().view],
+                shared_frond.resolution(),
+            )?;
+            shared_stem.set_name(*framebuffer, "debug lines")?;
+
+            Ok(Self {
+                framebuffer: framebuffer.take(),
+                debug_draw_stem,
+                shared_frond,
+            })
+        }
+    }
+
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        view: &na::Matrix4<f32>,
+        lines: &[LineVertex],
+    ) -> VkResult<()> {
+        let device = self.shared_frond.device();
+        let resolution = self.shared_frond.resolution();
+
+        let vertex_count = lines.len() & !1;
+        if vertex_count == 0 {
+            return Ok(());
+        }
+        let vertex_data = std::slice::from_raw_parts(
+            lines.as_ptr() as *const u8,
+            vertex_count * std::mem::size_of::<LineVertex>(),
+        );
+        let vertices =
+            self.shared_frond
+                .stem()
+                .staging_belt()
+                .stage(device, frame_index, vertex_data)?;
+
+        let render_area = vk::Rect2D {
+            offset: Default::default(),
+            extent: resolution,
+        };
+
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.debug_draw_stem.render_pass)
+            .framebuffer(self.framebuffer)
+            .render_area(render_area);
+        device.cmd_begin_render_pass(
+            command_buffer,
+            &render_pass_begin_info,
+            vk::SubpassContents::INLINE,
+        );
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.debug_draw_stem.pipeline,
+        );
+        util::set_viewport_and_scissor(device, command_buffer, resolution);
+
+        let view_buffer = ViewBuffer {
+            view: (*view).into(),
+        };
+        device.cmd_push_constants(
+            command_buffer,
+            self.debug_draw_stem.pipeline_layout,
+            vk::ShaderStageFlags::VERTEX,
+            0,
+            view_buffer.as_std140().as_bytes(),
+        );
+
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[vertices.buffer], &[vertices.offset]);
+        device.cmd_draw(command_buffer, vertex_count as _, 1, 0, 0);
+        self.shared_frond.count_draw(0);
+
+        device.cmd_end_render_pass(command_buffer);
+        Ok(())
+    }
+}
+
+impl Drop for DebugDrawFrond {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_frond.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_framebuffer(self.framebuffer, None);
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use std::ffi::CStr;
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+use nalgebra as na;
+
+use crate::{
+    camera::Projection,
+    guard::{GuardableResource, Guarded},
+    shaders::include_shader,
+    shared::{SharedFrond, SharedStem},
+    sync, util,
+};
+
+// A thin lens focused at focal_distance. Blur grows with distance from the focal plane, up to the
+// aperture for things infinitely far away.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DepthOfField {
+    pub focal_distance: f32, // in world units
+    pub aperture: f32,       // blur diameter at infinity, as a fraction of the image height
+}
+
+impl Default for DepthOfField {
+    fn default() -> Self {
+        Self {
+            focal_distance: 10.0,
+            aperture: 0.01,
+        }
+    }
+}
+
+#[derive(AsStd140)]
+struct DepthOfFieldBuffer {
+    // As (a, b, c, d), with 1 / distance = (a * depth + b) / (c * depth + d)
+    pub inverse_distance: mint::Vector4<f32>,
+    pub focal_distance: f32,
+    pub aperture: f32, // blur radius at infinity, in pixels
+}
+
+impl DepthOfFieldBuffer {
+    fn new(depth_of_field: DepthOfField, projection: &Projection, height: u32) -> Self {
+        let inverse_distance = match *projection {
+            Projection::Perspective { near, .. } | Projection::Asymmetric { near, .. } => {
+                na::Vector4::new(1.0, 0.0, 0.0, near)
+            }
+            Projection::Orthographic { near, far, .. } => {
+                na::Vector4::new(0.0, 1.0, near - far, far)
+            }
+        };
+        Self {
+            inverse_distance: inverse_distance.into(),
+            focal_distance: depth_of_field.focal_distance.max(projection.near()),
+            aperture: 0.5 * depth_of_field.aperture.max(0.0) * height as f32,
+        }
+    }
+
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: Self::std140_size_static() as _,
+        }
+    }
+}
+
+pub struct DepthOfFieldStem {
+    depth_sampler: vk::Sampler,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    frag_shader_module: vk::ShaderModule,
+    light_sampler: vk::Sampler,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl DepthOfFieldStem {
+    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
+        unsafe {
+            let device = shared_stem.device();
+
+            // Light, then depth
+            let descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                ],
+            )?;
+            shared_stem.set_name(*descriptor_set_layout, "depth of field")?;
+
+            let pipeline_layout = util::create_pipeline_layout(
+                device,
+                &[*descriptor_set_layout],
+                &[DepthOfFieldBuffer::push_constant_range()],
+            )?;
+            shared_stem.set_name(*pipeline_layout, "depth of field")?;
+
+            let light_sampler = Self::create_sampler(device, vk::Filter::LINEAR)?;
+            shared_stem.set_name(*light_sampler, "depth of field light")?;
+            // Blending depths across edges would blur things that should stay sharp
+            let depth_sampler = Self::create_sampler(device, vk::Filter::NEAREST)?;
+            shared_stem.set_name(*depth_sampler, "depth of field depth")?;
+
+            let frag_shader_module = util::create_shader_module(
+                device,
+                &include_shader!("shaders/depth-of-field.frag"),
+            )?;
+            shared_stem.set_name(*frag_shader_module, "depth of field frag")?;
+
+            let render_pass = Self::create_render_pass(device, SharedFrond::LIGHT_FORMAT)?;
+            shared_stem.set_name(*render_pass, "depth of field")?;
+
+            let pipeline = Self::create_pipeline(
+                device,
+                shared_stem.fullscreen_vert_shader_module(),
+                *frag_shader_module,
+                *pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*pipeline, "depth of field")?;
+
+            Ok(Self {
+                depth_sampler: depth_sampler.take(),
+                descriptor_set_layout: descriptor_set_layout.take(),
+                frag_shader_module: frag_shader_module.take(),
+                light_sampler: light_sampler.take(),
+                pipeline: pipeline.take(),
+                pipeline_layout: pipeline_layout.take(),
+                render_pass: render_pass.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    unsafe fn create_sampler(
+        device: &ash::Device,
+        filter: vk::Filter,
+    ) -> VkResult<Guarded<(vk::Sampler, &ash::Device)>> {
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(filter)
+            .min_filter(filter)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .compare_enable(false)
+            .min_lod(0.0)
+            .max_lod(0.0)
+            .unnormalized_coordinates(false);
+        Ok(device
+            .create_sampler(&sampler_create_info, None)?
+            .guard_with(device))
+    }
+
+    // Gathers from light into the depth of field image, which tonemapping reads instead
+    unsafe fn create_render_pass(
+        device: &ash::Device,
+        light_format: vk::Format,
+    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
+        let attachments = [vk::AttachmentDescription::builder()
+            .format(light_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .build()];
+
+        let color_attachments = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_attachments)
+            .build()];
+
+        // The previous frame's tonemapping may still be reading the output
+        let dependencies = [sync::dependency_before(
+            sync::INPUT_ATTACHMENT_READ.execution(),
+            sync::COLOR_ATTACHMENT_WRITE,
+        )];
+
+        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+        Ok(device
+            .create_render_pass(&render_pass_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_pipeline(
+        device: &ash::Device,
+        vert_shader_module: vk::ShaderModule,
+        frag_shader_module: vk::ShaderModule,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        let vertex_input_state = Default::default();
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            color_write_mask: vk::ColorComponentFlags::all(),
+            ..Default::default()
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+}
+
+impl Drop for DepthOfFieldStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_shader_module(self.frag_shader_module, None);
+            device.destroy_sampler(self.light_sampler, None);
+            device.destroy_sampler(self.depth_sampler, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+pub struct DepthOfFieldFrond {
+    depth_of_field_stem: Arc<DepthOfFieldStem>,
+    descriptor_set: vk::DescriptorSet,
+    framebuffer: vk::Framebuffer,
+    shared_frond: Arc<SharedFrond>,
+}
+
+impl DepthOfFieldFrond {
+    pub fn new(
+        depth_of_field_stem: Arc<DepthOfFieldStem>,
+        shared_frond: Arc<SharedFrond>,
+    ) -> VkResult<Self> {
+        let shared_stem = &depth_of_field_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+
+            let descriptor_set = shared_frond
+                .descriptor_allocator()
+                .allocate(device, depth_of_field_stem.descriptor_set_layout)?;
+            // Depth is still attached read-only by the passes around this one
+            let image_infos = [
+                vk::DescriptorImageInfo {
+                    sampler: depth_of_field_stem.light_sampler,
+                    image_view: shared_frond.light().view,
+                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                },
+                vk::DescriptorImageInfo {
+                    sampler: depth_of_field_stem.depth_sampler,
+                    image_view: shared_frond.depth_stencil().view,
+                    image_layout: shared_stem.depth_read_layout(),
+                },
+            ];
+            let descriptor_writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*descriptor_set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&image_infos[0..1])
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*descriptor_set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&image_infos[1..2])
+                    .build(),
+            ];
+            device.update_descriptor_sets(&descriptor_writes, &[]);
+            shared_stem.set_name(*descriptor_set, "depth of field")?;
+
+            let framebuffer = util::create_framebuffer(
+                device,
+                depth_of_field_stem.render_pass,
+                &[shared_frond.depth_of_field().view],
+                shared_frond.resolution(),
+            )?;
+            shared_stem.set_name(*framebuffer, "depth of field")?;
+
+            Ok(Self {
+                descriptor_set: descriptor_set.take(),
+                framebuffer: framebuffer.take(),
+                depth_of_field_stem,
+                shared_frond,
+            })
+        }
+    }
+
+    // Leaves light in SHADER_READ_ONLY_OPTIMAL, which lighting overwrites next frame regardless
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        depth_of_field: DepthOfField,
+        projection: &Projection,
+    ) {
+        let device = self.shared_frond.device();
+        let depth_of_field_stem = &self.depth_of_field_stem;
+
+        let image_memory_barriers = [util::image_barrier(
+            self.shared_frond.light().image,
+            1,
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            sync::COLOR_ATTACHMENT_BLEND.access,
+            sync::FRAGMENT_SAMPLED.access,
+        )];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            sync::COLOR_ATTACHMENT_BLEND.stage,
+            sync::FRAGMENT_SAMPLED.stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        let resolution = self.shared_frond.resolution();
+        let render_area = vk::Rect2D {
+            offset: Default::default(),
+            extent: resolution,
+        };
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(depth_of_field_stem.render_pass)
+            .framebuffer(self.framebuffer)
+            .render_area(render_area);
+        device.cmd_begin_render_pass(
+            command_buffer,
+            &render_pass_begin_info,
+            vk::SubpassContents::INLINE,
+        );
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            depth_of_field_stem.pipeline,
+        );
+        util::set_viewport_and_scissor(device, command_buffer, resolution);
+
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            depth_of_field_stem.pipeline_layout,
+            0,
+            &[self.descriptor_set],
+            &[],
+        );
+
+        let depth_of_field_buffer =
+            DepthOfFieldBuffer::new(depth_of_field, projection, resolution.height);
+        device.cmd_push_constants(
+            command_buffer,
+            depth_of_field_stem.pipeline_layout,
+            vk::ShaderStageFlags::FRAGMENT,
+            0,
+            depth_of_field_buffer.as_std140().as_bytes(),
+        );
+
+        device.cmd_draw(
+            command_buffer,
+            3, // vertices
+            1, // instances
+            0, // first vertex
+            0, // first instance
+        );
+        self.shared_frond.count_draw(1);
+
+        device.cmd_end_render_pass(command_buffer);
+    }
+}
+
+impl Drop for DepthOfFieldFrond {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_frond.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_framebuffer(self.framebuffer, None);
+            self.shared_frond
+                .descriptor_allocator()
+                .free(device, &[self.descriptor_set]);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+
+use crate::{
+    frame::FRAMES_IN_FLIGHT,
+    guard::{Guardable, GuardableResource, Guarded},
+    util,
+};
+
+// Each pool has room for this many sets, with up to this many of each kind of descriptor per set
+// on average, which is several fronds' worth, so pools are rarely added after the first frame
+const POOL_SETS: u32 = 64;
+const DESCRIPTORS_PER_SET: u32 = 8;
+// Every kind the passes bind, plus texel buffers for plugins. Bindless has its own pool, since it
+// needs update-after-bind.
+const DESCRIPTOR_TYPES: [vk::DescriptorType; 7] = [
+    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+    vk::DescriptorType::INPUT_ATTACHMENT,
+    vk::DescriptorType::STORAGE_BUFFER,
+    vk::DescriptorType::STORAGE_IMAGE,
+    vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+    vk::DescriptorType::UNIFORM_BUFFER,
+    vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+];
+
+// Freed back to the allocator it came from if dropped before being taken
+pub type GuardedDescriptorSet<'a> = Guarded<(
+    vk::DescriptorSet,
+    (&'a DescriptorAllocator, &'a ash::Device),
+)>;
+
+// Hands out descriptor sets from pools shared by every pass, so none of them have to count up
+// descriptors by type beforehand. Pools are added as they fill up, and kept once added.
+// Persistent sets, like a frond's, are freed back to their pool one by one when their owner's
+// destroyed. Transient ones, for sets only bound in one frame, last until that frame in flight's
+// fence is next waited on, when its pools are reset all at once.
+pub struct DescriptorAllocator {
+    persistent: Mutex<PersistentPools>,
+    transient: Mutex<Vec<TransientPools>>, // per frame in flight
+}
+
+#[derive(Default)]
+struct PersistentPools {
+    pools: Vec<vk::DescriptorPool>,
+    sets: HashMap<vk::DescriptorSet, vk::DescriptorPool>, // which pool to free each back to
+}
+
+#[derive(Default)]
+struct TransientPools {
+    current: usize, // pools before it are full
+    pools: Vec<vk::DescriptorPool>,
+}
+
+// Pools are only created once something's allocated
+impl Default for DescriptorAllocator {
+    fn default() -> Self {
+        Self {
+            persistent: Default::default(),
+            transient: Mutex::new((0..FRAMES_IN_FLIGHT).map(|_| Default::default()).collect()),
+        }
+    }
+}
+
+impl DescriptorAllocator {
+    // Until freed, or the allocator's destroyed
+    pub unsafe fn allocate<'a>(
+        &'a self,
+        device: &'a ash::Device,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+    ) -> VkResult<GuardedDescriptorSet<'a>> {
+        Ok(self
+            .allocate_unguarded(device, descriptor_set_layout)?
+            .guard_with((self, device)))
+    }
+
+    unsafe fn allocate_unguarded(
+        &self,
+        device: &ash::Device,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+    ) -> VkResult<vk::DescriptorSet> {
+        let mut persistent = self.persistent.lock().unwrap();
+        let persistent = &mut *persistent;
+        // The newest pools are the likeliest to have room
+        for &pool in persistent.pools.iter().rev() {
+            if let Some(descriptor_set) = Self::allocate_from(device, pool, descriptor_set_layout)?
+            {
+                persistent.sets.insert(descriptor_set, pool);
+                return Ok(descriptor_set);
+            }
+        }
+
+        log::debug!("Adding descriptor pool {}", persistent.pools.len() + 1);
+        let pool = Self::create_pool(device, vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)?;
+        let descriptor_set = Self::allocate_from_new(device, &pool, descriptor_set_layout)?;
+        let pool = pool.take();
+        persistent.pools.push(pool);
+        persistent.sets.insert(descriptor_set, pool);
+        Ok(descriptor_set)
+    }
+
+    // Once the GPU's finished with them. Their pools are kept for whatever's allocated next.
+    pub unsafe fn free(&self, device: &ash::Device, descriptor_sets: &[vk::DescriptorSet]) {
+        let mut persistent = self.persistent.lock().unwrap();
+        for descriptor_set in descriptor_sets {
+            if let Some(pool) = persistent.sets.remove(descriptor_set) {
+                device.free_descriptor_sets(pool, std::slice::from_ref(descriptor_set));
+            }
+        }
+    }
+
+    // Only valid until this frame in flight is next waited on, so needs writing every frame
+    pub unsafe fn allocate_transient(
+        &self,
+        device: &ash::Device,
+        frame_index: usize,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+    ) -> VkResult<vk::DescriptorSet> {
+        let mut transient = self.transient.lock().unwrap();
+        let frame = &mut transient[frame_index];
+        while let Some(&pool) = frame.pools.get(frame.current) {
+            if let Some(descriptor_set) = Self::allocate_from(device, pool, descriptor_set_layout)?
+            {
+                return Ok(descriptor_set);
+            }
+            frame.current += 1;
+        }
+
+        log::debug!(
+            "Adding transient descriptor pool {} for frame {}",
+            frame.pools.len() + 1,
+            frame_index,
+        );
+        let pool = Self::create_pool(device, vk::DescriptorPoolCreateFlags::empty())?;
+        let descriptor_set = Self::allocate_from_new(device, &pool, descriptor_set_layout)?;
+        frame.pools.push(pool.take());
+        Ok(descriptor_set)
+    }
+
+    // Every transient set allocated for this frame is finished with
+    pub unsafe fn recycle(&self, device: &ash::Device, frame_index: usize) -> VkResult<()> {
+        let mut transient = self.transient.lock().unwrap();
+        let frame = &mut transient[frame_index];
+        let used = (frame.current + 1).min(frame.pools.len());
+        for &pool in &frame.pools[..used] {
+            device.reset_descriptor_pool(pool, vk::DescriptorPoolResetFlags::empty())?;
+        }
+        frame.current = 0;
+        Ok(())
+    }
+
+    // None if the pool's out of room
+    unsafe fn allocate_from(
+        device: &ash::Device,
+        pool: vk::DescriptorPool,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+    ) -> VkResult<Option<vk::DescriptorSet>> {
+        match util::allocate_descriptor_set(device, pool, descriptor_set_layout) {
+            Ok(descriptor_set) => Ok(Some(descriptor_set)),
+            Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY) | Err(vk::Result::ERROR_FRAGMENTED_POOL) => {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // A layout that doesn't fit in an empty pool never will, so rather than keep adding pools for
+    // it, the pool's destroyed again
+    unsafe fn allocate_from_new(
+        device: &ash::Device,
+        pool: &Guarded<(vk::DescriptorPool, &ash::Device)>,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+    ) -> VkResult<vk::DescriptorSet> {
+        Self::allocate_from(device, **pool, descriptor_set_layout)?.ok_or_else(|| {
+            log::warn!(
+                "Descriptor set layout {:?} needs more descriptors than a pool has, or of a kind \
+                 it doesn't have",
+                descriptor_set_layout,
+            );
+            vk::Result::ERROR_OUT_OF_POOL_MEMORY
+        })
+    }
+
+    unsafe fn create_pool(
+        device: &ash::Device,
+        flags: vk::DescriptorPoolCreateFlags,
+    ) -> VkResult<Guarded<(vk::DescriptorPool, &ash::Device)>> {
+        let pool_sizes: Vec<_> = DESCRIPTOR_TYPES
+            .iter()
+            .map(|&ty| vk::DescriptorPoolSize {
+                ty,
+                descriptor_count: POOL_SETS * DESCRIPTORS_PER_SET,
+            })
+            .collect();
+        let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo::builder()
+            .flags(flags)
+            .max_sets(POOL_SETS)
+            .pool_sizes(&pool_sizes);
+        let pool = device.create_descriptor_pool(&descriptor_pool_create_info, None)?;
+        Ok(pool.guard_with(device))
+    }
+
+    // Which frees every set still allocated
+    pub unsafe fn destroy_with(&mut self, device: &ash::Device) {
+        let persistent = self.persistent.get_mut().unwrap();
+        let transient = self.transient.get_mut().unwrap();
+        let transient_pools = transient.iter().flat_map(|frame| &frame.pools);
+        for &pool in persistent.pools.iter().chain(transient_pools) {
+            device.destroy_descriptor_pool(pool, None);
+        }
+        persistent.pools.clear();
+        persistent.sets.clear();
+        transient.clear();
+    }
+}
+
+impl<'a> Guardable
+    for (
+        vk::DescriptorSet,
+        (&'a DescriptorAllocator, &'a ash::Device),
+    )
+{
+    type Resource = vk::DescriptorSet;
+
+    fn deref(&self) -> &Self::Resource {
+        &self.0
+    }
+
+    fn deref_mut(&mut self) -> &mut Self::Resource {
+        &mut self.0
+    }
+
+    fn take(self) -> Self::Resource {
+        self.0
+    }
+
+    unsafe fn drop(self) {
+        let (descriptor_set, (descriptor_allocator, device)) = self;
+        descriptor_allocator.free(device, &[descriptor_set]);
+    }
+}
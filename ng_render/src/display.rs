@@ -0,0 +1,64 @@
+use winit::{
+    monitor::VideoMode,
+    window::{Fullscreen, Window},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisplayMode {
+    #[default]
+    Windowed,
+    Borderless, // covers the current monitor without changing its video mode
+    // Takes over the current monitor at its native resolution, with the refresh rate (Hz) closest
+    // to the one asked for, or the fastest if None
+    Exclusive {
+        refresh_rate: Option<u16>,
+    },
+}
+
+impl DisplayMode {
+    pub fn is_exclusive(self) -> bool {
+        matches!(self, Self::Exclusive { .. })
+    }
+
+    // Exclusive falls back to borderless on monitors that don't list their video modes
+    pub(crate) fn fullscreen(self, window: &Window) -> Option<Fullscreen> {
+        match self {
+            Self::Windowed => None,
+            Self::Borderless => Some(Fullscreen::Borderless(window.current_monitor())),
+            Self::Exclusive { refresh_rate } => match select_video_mode(window, refresh_rate) {
+                Some(video_mode) => {
+                    log::info!(
+                        "Going fullscreen at {}x{} {} Hz",
+                        video_mode.size().width,
+                        video_mode.size().height,
+                        video_mode.refresh_rate()
+                    );
+                    Some(Fullscreen::Exclusive(video_mode))
+                }
+                None => {
+                    log::warn!("No video modes to choose from, going borderless instead");
+                    Some(Fullscreen::Borderless(window.current_monitor()))
+                }
+            },
+        }
+    }
+}
+
+// Prefers the monitor's own resolution, then the nearest refresh rate, then the deepest color
+fn select_video_mode(window: &Window, refresh_rate: Option<u16>) -> Option<VideoMode> {
+    let monitor = window.current_monitor()?;
+    let native_size = monitor.size();
+    monitor.video_modes().min_by_key(|video_mode| {
+        let refresh_rate_distance = match refresh_rate {
+            Some(refresh_rate) => {
+                (i32::from(video_mode.refresh_rate()) - i32::from(refresh_rate)).unsigned_abs()
+            }
+            None => u32::from(u16::MAX - video_mode.refresh_rate()),
+        };
+        (
+            video_mode.size() != native_size,
+            refresh_rate_distance,
+            u16::MAX - video_mode.bit_depth(),
+        )
+    })
+}
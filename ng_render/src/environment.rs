@@ -0,0 +1,1056 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use ash::{
+    prelude::VkResult,
+    version::{DeviceV1_0, InstanceV1_0},
+    vk,
+};
+use crevice::std140::{AsStd140, Std140};
+
+use crate::{
+    compute::ComputePass,
+    guard::{GuardableResource, Guarded},
+    image::Image,
+    light::DirectionalLight,
+    shaders::include_shader,
+    shared::SharedStem,
+    sky::{Sky, SkyBuffer},
+    upload::{self, UploadCache, UploadError},
+    util::{self, Descriptor},
+};
+
+const BRDF_LUT_SIZE: u32 = 256;
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+const PREFILTERED_MIP_LEVELS: u32 = 5; // roughness 0, 0.25, 0.5, 0.75 and 1
+const SKY_HEIGHT: u32 = 128;
+const SKY_WIDTH: u32 = 2 * SKY_HEIGHT;
+const WORKGROUP_SIZE: u32 = 8;
+
+const FILTERED_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const SOURCE_FORMAT: vk::Format = vk::Format::R32G32B32A32_SFLOAT;
+
+// CPU-side HDR image of the light arriving from every direction, as equirectangular linear RGBA.
+// The centre column faces +x and the top row faces straight up. Like textures, it's uploaded
+// lazily the first time it's drawn.
+#[derive(Debug)]
+pub struct EnvironmentMap {
+    height: u32,
+    id: u64,
+    pixels: Vec<f32>,
+    width: u32,
+}
+
+impl EnvironmentMap {
+    pub fn new(width: u32, height: u32, pixels: Vec<f32>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        assert!(width > 0 && height > 0, "Environment map must not be empty");
+        assert_eq!(
+            pixels.len(),
+            4 * width as usize * height as usize,
+            "Environment map pixels must be RGBA"
+        );
+
+        Self {
+            height,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            pixels,
+            width,
+        }
+    }
+
+    // The same radiance from every direction
+    pub fn uniform(color: mint::Vector3<f32>) -> Self {
+        Self::new(1, 1, vec![color.x, color.y, color.z, 1.0])
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[f32] {
+        &self.pixels
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct FilterBuffer {
+    roughness: f32,
+}
+
+unsafe impl util::Pod for FilterBuffer {}
+
+// Turns environment maps into the irradiance and prefiltered specular cubemaps sampled for ambient
+// light, alongside the BRDF lookup table they share
+pub struct EnvironmentStem {
+    brdf_lut: Image,
+    default_environment: Arc<EnvironmentMap>,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    environments: Mutex<UploadCache<EnvironmentMap, GpuEnvironment>>,
+    filter_descriptor_set_layout: vk::DescriptorSetLayout,
+    irradiance_pass: ComputePass,
+    prefilter_pass: ComputePass,
+    sampler: vk::Sampler,
+    shared_stem: Arc<SharedStem>,
+    sky: Mutex<Option<GpuSky>>,
+    sky_pass: ComputePass,
+    sky_sampler: vk::Sampler,
+    source_sampler: vk::Sampler,
+}
+
+impl EnvironmentStem {
+    pub fn new(shared_stem: Arc<SharedStem>) -> Result<Self, UploadError> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let descriptor_set_layout = Self::create_descriptor_set_layout(device)?;
+            shared_stem.set_name(*descriptor_set_layout, "environment")?;
+            // Source environment in, one mip of every cube face out
+            let filter_descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::COMPUTE,
+                    ),
+                    (
+                        vk::DescriptorType::STORAGE_IMAGE,
+                        vk::ShaderStageFlags::COMPUTE,
+                    ),
+                ],
+            )?;
+            shared_stem.set_name(*filter_descriptor_set_layout, "environment filter")?;
+
+            let filter_pass = |name: &str, spirv: &[u32]| {
+                ComputePass::new(
+                    shared_stem.clone(),
+                    name,
+                    spirv,
+                    &[*filter_descriptor_set_layout],
+                    std::mem::size_of::<FilterBuffer>(),
+                )
+            };
+            let brdf_lut_pass = filter_pass("brdf lut", &include_shader!("shaders/brdf-lut.comp"))?;
+            let irradiance_pass = filter_pass(
+                "environment irradiance",
+                &include_shader!("shaders/environment-irradiance.comp"),
+            )?;
+            let prefilter_pass = filter_pass(
+                "environment prefilter",
+                &include_shader!("shaders/environment-prefilter.comp"),
+            )?;
+
+            let sampler = Self::create_sampler(
+                device,
+                vk::Filter::LINEAR,
+                vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            )?;
+            shared_stem.set_name(*sampler, "environment")?;
+            // Longitude wraps around, latitude doesn't
+            let source_sampler = Self::create_sampler(
+                device,
+                Self::source_filter(&shared_stem),
+                vk::SamplerAddressMode::REPEAT,
+            )?;
+            shared_stem.set_name(*source_sampler, "environment source")?;
+
+            let sky_pass = ComputePass::new(
+                shared_stem.clone(),
+                "sky",
+                &include_shader!("shaders/sky.comp"),
+                &[*filter_descriptor_set_layout],
+                SkyBuffer::std140_size_static(),
+            )?;
+            let sky_sampler =
+                Self::create_sampler(device, vk::Filter::LINEAR, vk::SamplerAddressMode::REPEAT)?;
+            shared_stem.set_name(*sky_sampler, "sky")?;
+
+            // Only needed once, so its pass is dropped straight after
+            let brdf_lut =
+                Self::create_brdf_lut(&shared_stem, *filter_descriptor_set_layout, &brdf_lut_pass)?;
+
+            // A dim, even glow, so unlit sides aren't pitch black
+            let default_environment = Arc::new(EnvironmentMap::uniform([0.05; 3].into()));
+
+            Ok(Self {
+                brdf_lut: brdf_lut.take(),
+                default_environment,
+                descriptor_set_layout: descriptor_set_layout.take(),
+                environments: Mutex::new(UploadCache::new()),
+                filter_descriptor_set_layout: filter_descriptor_set_layout.take(),
+                irradiance_pass,
+                prefilter_pass,
+                sampler: sampler.take(),
+                sky: Mutex::new(None),
+                sky_pass,
+                sky_sampler: sky_sampler.take(),
+                source_sampler: source_sampler.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    // Irradiance, prefiltered specular, the BRDF lookup table and the background, as bound for
+    // lighting
+    unsafe fn create_descriptor_set_layout(
+        device: &ash::Device,
+    ) -> VkResult<Guarded<(vk::DescriptorSetLayout, &ash::Device)>> {
+        let bindings = [0, 1, 2, 3].map(|binding| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build()
+        });
+        let descriptor_set_layout_create_info =
+            vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        Ok(device
+            .create_descriptor_set_layout(&descriptor_set_layout_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_sampler(
+        device: &ash::Device,
+        filter: vk::Filter,
+        address_mode_u: vk::SamplerAddressMode,
+    ) -> VkResult<Guarded<(vk::Sampler, &ash::Device)>> {
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(filter)
+            .min_filter(filter)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(address_mode_u)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .compare_enable(false)
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE)
+            .unnormalized_coordinates(false);
+        Ok(device
+            .create_sampler(&sampler_create_info, None)?
+            .guard_with(device))
+    }
+
+    // 32-bit floats needn't support linear filtering, in which case the source is point sampled
+    unsafe fn source_filter(shared_stem: &SharedStem) -> vk::Filter {
+        let format_properties = shared_stem
+            .crown()
+            .instance()
+            .get_physical_device_format_properties(shared_stem.physical_device(), SOURCE_FORMAT);
+        if format_properties
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+        {
+            vk::Filter::LINEAR
+        } else {
+            vk::Filter::NEAREST
+        }
+    }
+
+    unsafe fn create_image(
+        shared_stem: &SharedStem,
+        format: vk::Format,
+        width: u32,
+        height: u32,
+        mip_levels: u32,
+        usage: vk::ImageUsageFlags,
+        view_type: vk::ImageViewType,
+    ) -> Result<Guarded<(Image, &ash::Device)>, UploadError> {
+        let select_device_local_memory = |memory_requirements: vk::MemoryRequirements| {
+            shared_stem
+                .select_memory_type(memory_requirements, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+                .ok_or(UploadError::NoAcceptableMemoryType(
+                    memory_requirements,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                ))
+        };
+
+        let (flags, array_layers) = match view_type {
+            vk::ImageViewType::CUBE => (vk::ImageCreateFlags::CUBE_COMPATIBLE, 6),
+            _ => (vk::ImageCreateFlags::empty(), 1),
+        };
+        let image_create_info = vk::ImageCreateInfo::builder()
+            .flags(flags)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            })
+            .mip_levels(mip_levels)
+            .array_layers(array_layers)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        Image::new(
+            shared_stem.device(),
+            &image_create_info,
+            select_device_local_memory,
+            view_type,
+            vk::ImageAspectFlags::COLOR,
+        )?
+    }
+
+    // Compute shaders write every face of a mip at once, as layers of an array
+    unsafe fn create_storage_view(
+        device: &ash::Device,
+        image: vk::Image,
+        level: u32,
+        layers: u32,
+    ) -> VkResult<Guarded<(vk::ImageView, &ash::Device)>> {
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: level,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: layers,
+        };
+        let image_view_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D_ARRAY)
+            .format(FILTERED_FORMAT)
+            .subresource_range(subresource_range);
+        Ok(device
+            .create_image_view(&image_view_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn allocate_filter_descriptor_set(
+        device: &ash::Device,
+        descriptor_pool: vk::DescriptorPool,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        source: Option<(vk::ImageView, vk::Sampler)>,
+        target: vk::ImageView,
+    ) -> VkResult<vk::DescriptorSet> {
+        let descriptor_set =
+            util::allocate_descriptor_set(device, descriptor_pool, descriptor_set_layout)?;
+        util::write_descriptor_set(
+            device,
+            descriptor_set,
+            &[(1, Descriptor::StorageImage(target))],
+        );
+        if let Some((image_view, sampler)) = source {
+            util::write_descriptor_set(
+                device,
+                descriptor_set,
+                &[(0, Descriptor::CombinedImageSampler(image_view, sampler))],
+            );
+        }
+        Ok(descriptor_set)
+    }
+
+    unsafe fn create_brdf_lut<'a>(
+        shared_stem: &'a SharedStem,
+        filter_descriptor_set_layout: vk::DescriptorSetLayout,
+        brdf_lut_pass: &ComputePass,
+    ) -> Result<Guarded<(Image, &'a ash::Device)>, UploadError> {
+        let device = shared_stem.device();
+
+        let brdf_lut = Self::create_image(
+            shared_stem,
+            FILTERED_FORMAT,
+            BRDF_LUT_SIZE,
+            BRDF_LUT_SIZE,
+            1,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+            vk::ImageViewType::TYPE_2D,
+        )?;
+        shared_stem.set_name(brdf_lut.image, "brdf lut")?;
+        shared_stem.set_name(brdf_lut.memory, "brdf lut")?;
+        shared_stem.set_name(brdf_lut.view, "brdf lut")?;
+
+        let target = Self::create_storage_view(device, brdf_lut.image, 0, 1)?;
+        let descriptor_pool = util::create_descriptor_pool(
+            device,
+            1,
+            &[vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: 1,
+            }],
+        )?;
+        let descriptor_set = Self::allocate_filter_descriptor_set(
+            device,
+            *descriptor_pool,
+            filter_descriptor_set_layout,
+            None,
+            *target,
+        )?;
+
+        shared_stem.submit_one_time_commands(|command_buffer| {
+            let image_memory_barriers = [util::image_barrier(
+                brdf_lut.image,
+                1,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::GENERAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::SHADER_WRITE,
+            )];
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &image_memory_barriers,
+            );
+
+            let workgroup_count = BRDF_LUT_SIZE.div_ceil(WORKGROUP_SIZE);
+            brdf_lut_pass.dispatch(
+                command_buffer,
+                &[descriptor_set],
+                &[],
+                [workgroup_count, workgroup_count, 1],
+            );
+
+            let image_memory_barriers = [util::image_barrier(
+                brdf_lut.image,
+                1,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            )];
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &image_memory_barriers,
+            );
+        })?;
+
+        Ok(brdf_lut)
+    }
+
+    pub fn descriptor_set_layout(&self) -> vk::DescriptorSetLayout {
+        self.descriptor_set_layout
+    }
+
+    // A sky, lit by the sun, takes the place of any environment map. Without either, the default
+    // dim glow is used.
+    pub fn prepare(
+        &self,
+        environment: Option<&Arc<EnvironmentMap>>,
+        sky: Option<(&Sky, &DirectionalLight)>,
+    ) -> Result<Arc<GpuEnvironment>, UploadError> {
+        if let Some((sky, sun)) = sky {
+            return unsafe { self.prepare_sky(&SkyBuffer::new(sky, sun)) };
+        }
+
+        let environment = environment.unwrap_or(&self.default_environment);
+        let mut environments = self.environments.lock().unwrap();
+        environments.evict_unused();
+        environments.get_or_upload(environment.id(), environment, |environment| unsafe {
+            self.upload(environment)
+        })
+    }
+
+    unsafe fn prepare_sky(
+        &self,
+        sky_buffer: &SkyBuffer,
+    ) -> Result<Arc<GpuEnvironment>, UploadError> {
+        let mut gpu_sky = self.sky.lock().unwrap();
+        let gpu_sky = match &mut *gpu_sky {
+            Some(gpu_sky) => gpu_sky,
+            None => gpu_sky.insert(self.create_sky()?),
+        };
+        if gpu_sky.sky_buffer.as_ref() != Some(sky_buffer) {
+            // Forgotten first, so a failed refresh is retried
+            gpu_sky.sky_buffer = None;
+            self.refresh_sky(gpu_sky, sky_buffer)?;
+            gpu_sky.sky_buffer = Some(*sky_buffer);
+        }
+        Ok(gpu_sky.environment.clone())
+    }
+
+    // The irradiance and prefiltered specular cubemaps ambient light is sampled from
+    #[allow(clippy::type_complexity)]
+    unsafe fn create_cubemaps(
+        &self,
+        name: &str,
+    ) -> Result<
+        (
+            Guarded<(Image, &ash::Device)>,
+            Guarded<(Image, &ash::Device)>,
+        ),
+        UploadError,
+    > {
+        let shared_stem = &self.shared_stem;
+
+        let irradiance = Self::create_image(
+            shared_stem,
+            FILTERED_FORMAT,
+            IRRADIANCE_SIZE,
+            IRRADIANCE_SIZE,
+            1,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+            vk::ImageViewType::CUBE,
+        )?;
+        let irradiance_name = format!("{} irradiance", name);
+        shared_stem.set_name(irradiance.image, &irradiance_name)?;
+        shared_stem.set_name(irradiance.memory, &irradiance_name)?;
+        shared_stem.set_name(irradiance.view, &irradiance_name)?;
+
+        let prefiltered = Self::create_image(
+            shared_stem,
+            FILTERED_FORMAT,
+            PREFILTERED_SIZE,
+            PREFILTERED_SIZE,
+            PREFILTERED_MIP_LEVELS,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+            vk::ImageViewType::CUBE,
+        )?;
+        let prefiltered_name = format!("{} prefiltered", name);
+        shared_stem.set_name(prefiltered.image, &prefiltered_name)?;
+        shared_stem.set_name(prefiltered.memory, &prefiltered_name)?;
+        shared_stem.set_name(prefiltered.view, &prefiltered_name)?;
+
+        Ok((irradiance, prefiltered))
+    }
+
+    // Filtering needs a set per target mip, irradiance's first, with a storage view for each
+    #[allow(clippy::type_complexity)]
+    unsafe fn create_filter_descriptor_sets(
+        &self,
+        descriptor_pool: vk::DescriptorPool,
+        source: (vk::ImageView, vk::Sampler),
+        irradiance: vk::Image,
+        prefiltered: vk::Image,
+    ) -> VkResult<(
+        Guarded<(Vec<vk::ImageView>, &ash::Device)>,
+        Vec<vk::DescriptorSet>,
+    )> {
+        let device = self.shared_stem.device();
+
+        let mut targets = Vec::<vk::ImageView>::new().guard_with(device);
+        let mut descriptor_sets = Vec::new();
+        let levels = std::iter::once((irradiance, 0))
+            .chain((0..PREFILTERED_MIP_LEVELS).map(|level| (prefiltered, level)));
+        for (image, level) in levels {
+            let target = Self::create_storage_view(device, image, level, 6)?;
+            descriptor_sets.push(Self::allocate_filter_descriptor_set(
+                device,
+                descriptor_pool,
+                self.filter_descriptor_set_layout,
+                Some(source),
+                *target,
+            )?);
+            targets.push(target.take());
+        }
+
+        Ok((targets, descriptor_sets))
+    }
+
+    // Refills both cubemaps from their source, which must already be readable by compute shaders.
+    // Their old contents are discarded once any frame still reading them is done.
+    unsafe fn record_filtering(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        irradiance: vk::Image,
+        prefiltered: vk::Image,
+        filter_descriptor_sets: &[vk::DescriptorSet],
+    ) {
+        let device = self.shared_stem.device();
+
+        let image_memory_barriers = [
+            util::image_barrier(
+                irradiance,
+                1,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::GENERAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::SHADER_WRITE,
+            ),
+            util::image_barrier(
+                prefiltered,
+                PREFILTERED_MIP_LEVELS,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::GENERAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::SHADER_WRITE,
+            ),
+        ];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        let workgroup_count = IRRADIANCE_SIZE.div_ceil(WORKGROUP_SIZE);
+        self.irradiance_pass.dispatch(
+            command_buffer,
+            &filter_descriptor_sets[..1],
+            &[],
+            [workgroup_count, workgroup_count, 6],
+        );
+
+        for (level, &descriptor_set) in filter_descriptor_sets[1..].iter().enumerate() {
+            let filter_buffer = FilterBuffer {
+                roughness: level as f32 / (PREFILTERED_MIP_LEVELS - 1) as f32,
+            };
+            let size = (PREFILTERED_SIZE >> level).max(1);
+            let workgroup_count = size.div_ceil(WORKGROUP_SIZE);
+            self.prefilter_pass.dispatch(
+                command_buffer,
+                &[descriptor_set],
+                util::as_bytes(&[filter_buffer]),
+                [workgroup_count, workgroup_count, 6],
+            );
+        }
+
+        let image_memory_barriers = [
+            util::image_barrier(
+                irradiance,
+                1,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            ),
+            util::image_barrier(
+                prefiltered,
+                PREFILTERED_MIP_LEVELS,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            ),
+        ];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+    }
+
+    // Room for filter_set_count sets, each with a source and a target
+    unsafe fn create_filter_descriptor_pool(
+        device: &ash::Device,
+        filter_set_count: u32,
+    ) -> VkResult<Guarded<(vk::DescriptorPool, &ash::Device)>> {
+        util::create_descriptor_pool(
+            device,
+            filter_set_count,
+            &[
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    descriptor_count: filter_set_count,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::STORAGE_IMAGE,
+                    descriptor_count: filter_set_count,
+                },
+            ],
+        )
+    }
+
+    unsafe fn create_descriptor_pool(
+        device: &ash::Device,
+    ) -> VkResult<Guarded<(vk::DescriptorPool, &ash::Device)>> {
+        util::create_descriptor_pool(
+            device,
+            1,
+            &[vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: 4,
+            }],
+        )
+    }
+
+    unsafe fn upload(&self, environment: &EnvironmentMap) -> Result<GpuEnvironment, UploadError> {
+        let shared_stem = &self.shared_stem;
+        let device = shared_stem.device();
+
+        let staging_buffer =
+            upload::create_staging_buffer(shared_stem, &[util::as_bytes(environment.pixels())])?;
+
+        let source = Self::create_image(
+            shared_stem,
+            SOURCE_FORMAT,
+            environment.width(),
+            environment.height(),
+            1,
+            vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+            vk::ImageViewType::TYPE_2D,
+        )?;
+        shared_stem.set_name(source.image, "environment source")?;
+        shared_stem.set_name(source.memory, "environment source")?;
+        shared_stem.set_name(source.view, "environment source")?;
+
+        let (irradiance, prefiltered) = self.create_cubemaps("environment")?;
+
+        // The filters are all done with once the cubemaps are filled
+        let filter_descriptor_pool =
+            Self::create_filter_descriptor_pool(device, 1 + PREFILTERED_MIP_LEVELS)?;
+        let (_filter_targets, filter_descriptor_sets) = self.create_filter_descriptor_sets(
+            *filter_descriptor_pool,
+            (source.view, self.source_sampler),
+            irradiance.image,
+            prefiltered.image,
+        )?;
+
+        shared_stem.submit_one_time_commands(|command_buffer| {
+            let subresource_range = vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            };
+            let image_memory_barriers = [vk::ImageMemoryBarrier::builder()
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(source.image)
+                .subresource_range(subresource_range)
+                .build()];
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &image_memory_barriers,
+            );
+
+            let region = vk::BufferImageCopy {
+                buffer_offset: 0,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: Default::default(),
+                image_extent: source.resolution,
+            };
+            device.cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer.buffer,
+                source.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+            );
+
+            // Filtered, then drawn as the background
+            let image_memory_barriers = [util::image_barrier(
+                source.image,
+                1,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            )];
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &image_memory_barriers,
+            );
+
+            self.record_filtering(
+                command_buffer,
+                irradiance.image,
+                prefiltered.image,
+                &filter_descriptor_sets,
+            );
+        })?;
+
+        let descriptor_pool = Self::create_descriptor_pool(device)?;
+        shared_stem.set_name(*descriptor_pool, "environment")?;
+
+        let descriptor_set = self.allocate_descriptor_set(
+            *descriptor_pool,
+            irradiance.view,
+            prefiltered.view,
+            (source.view, self.source_sampler),
+        )?;
+        shared_stem.set_name(descriptor_set, "environment")?;
+
+        Ok(GpuEnvironment {
+            background: source.take(),
+            descriptor_pool: descriptor_pool.take(),
+            descriptor_set,
+            irradiance: irradiance.take(),
+            prefiltered: prefiltered.take(),
+            shared_stem: shared_stem.clone(),
+        })
+    }
+
+    // Left empty until the first refresh draws it
+    unsafe fn create_sky(&self) -> Result<GpuSky, UploadError> {
+        let shared_stem = &self.shared_stem;
+        let device = shared_stem.device();
+
+        let source = Self::create_image(
+            shared_stem,
+            FILTERED_FORMAT,
+            SKY_WIDTH,
+            SKY_HEIGHT,
+            1,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+            vk::ImageViewType::TYPE_2D,
+        )?;
+        shared_stem.set_name(source.image, "sky")?;
+        shared_stem.set_name(source.memory, "sky")?;
+        shared_stem.set_name(source.view, "sky")?;
+
+        let (irradiance, prefiltered) = self.create_cubemaps("sky")?;
+
+        // The sky pass's set, then the filters'
+        let filter_descriptor_pool =
+            Self::create_filter_descriptor_pool(device, 2 + PREFILTERED_MIP_LEVELS)?;
+        shared_stem.set_name(*filter_descriptor_pool, "sky")?;
+        let mut filter_targets = Vec::<vk::ImageView>::new().guard_with(device);
+        let sky_target = Self::create_storage_view(device, source.image, 0, 1)?;
+        let sky_descriptor_set = Self::allocate_filter_descriptor_set(
+            device,
+            *filter_descriptor_pool,
+            self.filter_descriptor_set_layout,
+            None,
+            *sky_target,
+        )?;
+        filter_targets.push(sky_target.take());
+        let (targets, filter_descriptor_sets) = self.create_filter_descriptor_sets(
+            *filter_descriptor_pool,
+            (source.view, self.sky_sampler),
+            irradiance.image,
+            prefiltered.image,
+        )?;
+        filter_targets.extend(targets.take());
+
+        let descriptor_pool = Self::create_descriptor_pool(device)?;
+        shared_stem.set_name(*descriptor_pool, "sky")?;
+
+        let descriptor_set = self.allocate_descriptor_set(
+            *descriptor_pool,
+            irradiance.view,
+            prefiltered.view,
+            (source.view, self.sky_sampler),
+        )?;
+        shared_stem.set_name(descriptor_set, "sky")?;
+
+        let environment = GpuEnvironment {
+            background: source.take(),
+            descriptor_pool: descriptor_pool.take(),
+            descriptor_set,
+            irradiance: irradiance.take(),
+            prefiltered: prefiltered.take(),
+            shared_stem: shared_stem.clone(),
+        };
+        Ok(GpuSky {
+            environment: Arc::new(environment),
+            filter_descriptor_pool: filter_descriptor_pool.take(),
+            filter_descriptor_sets,
+            filter_targets: filter_targets.take(),
+            sky_buffer: None,
+            sky_descriptor_set,
+        })
+    }
+
+    // Redraws the sky in place, then refilters the ambient light from it. Frames already in
+    // flight are ahead of it in the queue, so they finish with the old sky first.
+    unsafe fn refresh_sky(&self, gpu_sky: &GpuSky, sky_buffer: &SkyBuffer) -> VkResult<()> {
+        let device = self.shared_stem.device();
+        let environment = &gpu_sky.environment;
+
+        self.shared_stem.submit_one_time_commands(|command_buffer| {
+            let image_memory_barriers = [util::image_barrier(
+                environment.background.image,
+                1,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::GENERAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::SHADER_WRITE,
+            )];
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &image_memory_barriers,
+            );
+
+            self.sky_pass.dispatch(
+                command_buffer,
+                &[gpu_sky.sky_descriptor_set],
+                sky_buffer.as_std140().as_bytes(),
+                [
+                    SKY_WIDTH.div_ceil(WORKGROUP_SIZE),
+                    SKY_HEIGHT.div_ceil(WORKGROUP_SIZE),
+                    1,
+                ],
+            );
+
+            let image_memory_barriers = [util::image_barrier(
+                environment.background.image,
+                1,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            )];
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &image_memory_barriers,
+            );
+
+            self.record_filtering(
+                command_buffer,
+                environment.irradiance.image,
+                environment.prefiltered.image,
+                &gpu_sky.filter_descriptor_sets,
+            );
+        })
+    }
+
+    unsafe fn allocate_descriptor_set(
+        &self,
+        descriptor_pool: vk::DescriptorPool,
+        irradiance: vk::ImageView,
+        prefiltered: vk::ImageView,
+        background: (vk::ImageView, vk::Sampler),
+    ) -> VkResult<vk::DescriptorSet> {
+        let device = self.shared_stem.device();
+
+        let set_layouts = [self.descriptor_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&set_layouts);
+        let descriptor_set = device.allocate_descriptor_sets(&allocate_info)?[0];
+
+        let image_infos = [
+            (irradiance, self.sampler),
+            (prefiltered, self.sampler),
+            (self.brdf_lut.view, self.sampler),
+            background,
+        ]
+        .map(|(image_view, sampler)| vk::DescriptorImageInfo {
+            sampler,
+            image_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        });
+        let descriptor_writes = [0, 1, 2, 3].map(|binding| {
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(binding as _)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&image_infos[binding..binding + 1])
+                .build()
+        });
+        device.update_descriptor_sets(&descriptor_writes, &[]);
+
+        Ok(descriptor_set)
+    }
+}
+
+impl Drop for EnvironmentStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_sampler(self.sky_sampler, None);
+            device.destroy_sampler(self.source_sampler, None);
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_descriptor_set_layout(self.filter_descriptor_set_layout, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            self.brdf_lut.destroy_with(device);
+        }
+    }
+}
+
+pub struct GpuEnvironment {
+    background: Image, // the equirectangular source, drawn behind everything
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_set: vk::DescriptorSet,
+    irradiance: Image,
+    prefiltered: Image,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl GpuEnvironment {
+    pub fn descriptor_set(&self) -> vk::DescriptorSet {
+        self.descriptor_set
+    }
+}
+
+impl Drop for GpuEnvironment {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            self.background.destroy_with(device);
+            self.irradiance.destroy_with(device);
+            self.prefiltered.destroy_with(device);
+        }
+    }
+}
+
+// The sky's environment, along with the sets its source is redrawn and refiltered through
+struct GpuSky {
+    environment: Arc<GpuEnvironment>,
+    filter_descriptor_pool: vk::DescriptorPool,
+    filter_descriptor_sets: Vec<vk::DescriptorSet>,
+    filter_targets: Vec<vk::ImageView>,
+    sky_buffer: Option<SkyBuffer>, // what it was last drawn from
+    sky_descriptor_set: vk::DescriptorSet,
+}
+
+impl Drop for GpuSky {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.environment.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_descriptor_pool(self.filter_descriptor_pool, None);
+            for &filter_target in &self.filter_targets {
+                device.destroy_image_view(filter_target, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ash::{
+    prelude::VkResult,
+    version::{DeviceV1_0, DeviceV1_2},
+    vk,
+};
+
+use crate::{
+    guard::{Guardable, GuardableResource, Guarded},
+    stats::{
+        PassTimes, PipelineStatistics, Timestamp, PIPELINE_STATISTICS, STATISTICS_COUNT,
+        TIMESTAMP_COUNT,
+    },
+};
+
+pub const FRAMES_IN_FLIGHT: usize = 2;
+
+// Synchronization for one of the frames that can be recorded while earlier ones are executing
+pub struct Frame {
+    pub command_buffer: vk::CommandBuffer,
+    pub image_acquired_semaphore: vk::Semaphore,
+    pub presentation_fence: vk::Fence,
+    pub secondary_command_buffers: Vec<vk::CommandBuffer>, // one per recording thread
+    pub statistics_query_pool: Option<vk::QueryPool>,      // if the device has pipeline statistics
+    pub timeline_semaphore: Option<vk::Semaphore>, // replaces the fence, if the device has them
+    pub timestamp_query_pool: vk::QueryPool,       // must be reset before first use
+    submissions: AtomicU64, // the timeline semaphore's value once the last one finishes
+}
+
+impl Frame {
+    // Command buffers are freed along with the pools they're allocated from. Each secondary one
+    // comes from its own pool, so that threads can record into them concurrently.
+    pub unsafe fn new<D>(
+        device: D,
+        command_pool: vk::CommandPool,
+        secondary_command_pools: &[vk::CommandPool],
+        timeline: bool,
+        pipeline_statistics: bool,
+    ) -> VkResult<Guarded<(Self, D)>>
+    where
+        D: Deref<Target = ash::Device> + Clone,
+    {
+        let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(command_pool)
+            .command_buffer_count(1);
+        let command_buffer = device.allocate_command_buffers(&command_buffer_allocate_info)?[0];
+
+        let mut secondary_command_buffers = Vec::with_capacity(secondary_command_pools.len());
+        for &secondary_command_pool in secondary_command_pools {
+            let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(secondary_command_pool)
+                .level(vk::CommandBufferLevel::SECONDARY)
+                .command_buffer_count(1);
+            secondary_command_buffers
+                .push(device.allocate_command_buffers(&command_buffer_allocate_info)?[0]);
+        }
+
+        let image_acquired_semaphore = device
+            .create_semaphore(&Default::default(), None)?
+            .guard_with(device.clone());
+
+        let signaled_fence_create_info =
+            vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+        let presentation_fence = device
+            .create_fence(&signaled_fence_create_info, None)?
+            .guard_with(device.clone());
+
+        let timeline_semaphore = if timeline {
+            let mut semaphore_type_create_info = vk::SemaphoreTypeCreateInfo::builder()
+                .semaphore_type(vk::SemaphoreType::TIMELINE)
+                .initial_value(0);
+            let semaphore_create_info =
+                vk::SemaphoreCreateInfo::builder().push_next(&mut semaphore_type_create_info);
+            Some(
+                device
+                    .create_semaphore(&semaphore_create_info, None)?
+                    .guard_with(device.clone()),
+            )
+        } else {
+            None
+        };
+
+        let query_pool_create_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(TIMESTAMP_COUNT as _);
+        let timestamp_query_pool = device
+            .create_query_pool(&query_pool_create_info, None)?
+            .guard_with(device.clone());
+
+        let statistics_query_pool = if pipeline_statistics {
+            let query_pool_create_info = vk::QueryPoolCreateInfo::builder()
+                .query_type(vk::QueryType::PIPELINE_STATISTICS)
+                .query_count(STATISTICS_COUNT as _)
+                .pipeline_statistics(PIPELINE_STATISTICS);
+            Some(
+                device
+                    .create_query_pool(&query_pool_create_info, None)?
+                    .guard_with(device.clone()),
+            )
+        } else {
+            None
+        };
+
+        let frame = Self {
+            command_buffer,
+            image_acquired_semaphore: image_acquired_semaphore.take(),
+            presentation_fence: presentation_fence.take(),
+            secondary_command_buffers,
+            statistics_query_pool: statistics_query_pool.map(|query_pool| query_pool.take()),
+            timeline_semaphore: timeline_semaphore.map(|semaphore| semaphore.take()),
+            timestamp_query_pool: timestamp_query_pool.take(),
+            submissions: AtomicU64::new(0),
+        };
+        Ok(frame.guard_with(device))
+    }
+
+    // Blocks until this frame's last submission has finished executing
+    pub unsafe fn wait(&self, device: &ash::Device) -> VkResult<()> {
+        match self.timeline_semaphore {
+            Some(timeline_semaphore) => {
+                let semaphores = [timeline_semaphore];
+                let values = [self.submissions.load(Ordering::Acquire)];
+                let wait_info = vk::SemaphoreWaitInfo::builder()
+                    .semaphores(&semaphores)
+                    .values(&values);
+                device.wait_semaphores(&wait_info, u64::MAX)
+            }
+            None => device.wait_for_fences(&[self.presentation_fence], true, u64::MAX),
+        }
+    }
+
+    // Signals whatever wait() waits on once the command buffer finishes. Until then, the fence
+    // stays signaled, so bailing out of a frame before submitting it can't deadlock the next one.
+    pub unsafe fn submit(
+        &self,
+        device: &ash::Device,
+        queue: vk::Queue,
+        wait_semaphores: &[(vk::Semaphore, vk::PipelineStageFlags)],
+        signal_semaphores: &[vk::Semaphore],
+    ) -> VkResult<()> {
+        let (wait_semaphores, wait_dst_stage_mask): (Vec<_>, Vec<_>) =
+            wait_semaphores.iter().copied().unzip();
+        let mut signal_semaphores = signal_semaphores.to_vec();
+        let command_buffers = [self.command_buffer];
+
+        // Binary semaphores ignore their values, but still need one each
+        let submission = self.submissions.load(Ordering::Acquire) + 1;
+        let mut signal_values = vec![0; signal_semaphores.len()];
+        let fence = match self.timeline_semaphore {
+            Some(timeline_semaphore) => {
+                signal_semaphores.push(timeline_semaphore);
+                signal_values.push(submission);
+                vk::Fence::null()
+            }
+            None => {
+                device.reset_fences(&[self.presentation_fence])?;
+                self.presentation_fence
+            }
+        };
+
+        let mut timeline_submit_info =
+            vk::TimelineSemaphoreSubmitInfo::builder().signal_semaphore_values(&signal_values);
+        let mut submit_info = vk::SubmitInfo::builder()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_dst_stage_mask)
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&signal_semaphores);
+        if self.timeline_semaphore.is_some() {
+            submit_info = submit_info.push_next(&mut timeline_submit_info);
+        }
+        device.queue_submit(queue, &[submit_info.build()], fence)?;
+
+        self.submissions.store(submission, Ordering::Release);
+        Ok(())
+    }
+
+    // Must be recorded outside of a render pass
+    pub unsafe fn reset_queries(&self, device: &ash::Device, command_buffer: vk::CommandBuffer) {
+        device.cmd_reset_query_pool(
+            command_buffer,
+            self.timestamp_query_pool,
+            0,
+            TIMESTAMP_COUNT as _,
+        );
+        if let Some(statistics_query_pool) = self.statistics_query_pool {
+            device.cmd_reset_query_pool(
+                command_buffer,
+                statistics_query_pool,
+                0,
+                STATISTICS_COUNT as _,
+            );
+        }
+    }
+
+    pub unsafe fn write_timestamp(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        timestamp: Timestamp,
+    ) {
+        let pipeline_stage = match timestamp {
+            Timestamp::Start => vk::PipelineStageFlags::TOP_OF_PIPE,
+            _ => vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        };
+        device.cmd_write_timestamp(
+            command_buffer,
+            pipeline_stage,
+            self.timestamp_query_pool,
+            timestamp as _,
+        );
+    }
+
+    // Counts from the start of the pass that timestamp ends until end_statistics. Does nothing
+    // if the device has no pipeline statistics. Must be recorded outside of a render pass.
+    pub unsafe fn begin_statistics(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        timestamp: Timestamp,
+    ) {
+        if let Some(statistics_query_pool) = self.statistics_query_pool {
+            device.cmd_begin_query(
+                command_buffer,
+                statistics_query_pool,
+                timestamp.query() as _,
+                vk::QueryControlFlags::empty(),
+            );
+        }
+    }
+
+    pub unsafe fn end_statistics(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        timestamp: Timestamp,
+    ) {
+        if let Some(statistics_query_pool) = self.statistics_query_pool {
+            device.cmd_end_query(
+                command_buffer,
+                statistics_query_pool,
+                timestamp.query() as _,
+            );
+        }
+    }
+
+    // Like read_pass_times, and None as well if statistics weren't collected
+    pub unsafe fn read_pipeline_statistics(
+        &self,
+        device: &ash::Device,
+    ) -> VkResult<Option<PipelineStatistics>> {
+        let statistics_query_pool = match self.statistics_query_pool {
+            Some(statistics_query_pool) => statistics_query_pool,
+            None => return Ok(None),
+        };
+        // This version of ash only reads back one value per query, so it's called directly
+        let mut queries = [[0u64; 3]; STATISTICS_COUNT];
+        let result = device.fp_v1_0().get_query_pool_results(
+            device.handle(),
+            statistics_query_pool,
+            0,
+            STATISTICS_COUNT as _,
+            std::mem::size_of_val(&queries),
+            queries.as_mut_ptr() as *mut _,
+            std::mem::size_of::<[u64; 3]>() as _,
+            vk::QueryResultFlags::TYPE_64,
+        );
+        match result {
+            vk::Result::SUCCESS => Ok(Some(PipelineStatistics::from_queries(&queries))),
+            vk::Result::NOT_READY => Ok(None),
+            err => Err(err),
+        }
+    }
+
+    // Only meaningful once the frame has been waited on; None if this frame hasn't
+    // been drawn since its timestamps were last reset
+    pub unsafe fn read_pass_times(
+        &self,
+        device: &ash::Device,
+        tick_ns: f32,
+    ) -> VkResult<Option<PassTimes>> {
+        let mut timestamps = [0; TIMESTAMP_COUNT];
+        match device.get_query_pool_results(
+            self.timestamp_query_pool,
+            0,
+            TIMESTAMP_COUNT as _,
+            &mut timestamps,
+            vk::QueryResultFlags::TYPE_64,
+        ) {
+            Ok(()) => Ok(Some(PassTimes::from_timestamps(&timestamps, tick_ns))),
+            Err(vk::Result::NOT_READY) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub unsafe fn destroy_with(&mut self, device: &ash::Device) {
+        device.destroy_query_pool(self.timestamp_query_pool, None);
+        if let Some(statistics_query_pool) = self.statistics_query_pool {
+            device.destroy_query_pool(statistics_query_pool, None);
+        }
+        device.destroy_fence(self.presentation_fence, None);
+        if let Some(timeline_semaphore) = self.timeline_semaphore {
+            device.destroy_semaphore(timeline_semaphore, None);
+        }
+        device.destroy_semaphore(self.image_acquired_semaphore, None);
+    }
+}
+
+impl<C> Guardable for (Frame, C)
+where
+    C: Deref<Target = ash::Device>,
+{
+    type Resource = Frame;
+
+    fn deref(&self) -> &Self::Resource {
+        &self.0
+    }
+
+    fn deref_mut(&mut self) -> &mut Self::Resource {
+        &mut self.0
+    }
+
+    fn take(self) -> Self::Resource {
+        self.0
+    }
+
+    unsafe fn drop(self) {
+        let (mut resource, context) = self;
+        resource.destroy_with(&context);
+    }
+}
@@ -1,152 +1,389 @@
+use std::collections::{HashMap, HashSet};
 use std::ffi::CStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use ash::{prelude::VkResult, version::DeviceV1_0, vk};
 use crevice::std140::{AsStd140, Std140};
-use vk_shader_macros::include_glsl;
+use nalgebra as na;
 
 use crate::{
+    bindless::BindlessTable,
+    buffer::Buffer,
+    culling::IndirectDraws,
+    frame::FRAMES_IN_FLIGHT,
     guard::{GuardableResource, Guarded},
-    shared::{SharedFrond, SharedStem, ViewBuffer},
-    util,
+    jobs,
+    material::{GpuMaterial, Material, MaterialBinding, MaterialTextures},
+    mesh::{GpuMesh, GpuMeshInstance, Mesh, MeshInstance, SkinVertex, Vertex},
+    residency::{Residency, ResidencyChange},
+    sampler::SamplerCache,
+    shaders::include_shader,
+    shared::{MaterialIndexBuffer, ModelBuffer, SharedFrond, SharedStem, ViewBuffer},
+    stats, sync,
+    texture::{GpuTexture, Texture},
+    upload::{self, UploadBudget, UploadCache, UploadError},
+    util::{self, Descriptor},
 };
 
+// Meshes past this many are drawn as if they hadn't moved, and in their bind pose
+const MAX_INSTANCE_RECORDS: usize = 1 << 14;
+
+// Per frame, across all skinned meshes. Those that don't fit are drawn in their bind pose.
+const MAX_JOINTS: usize = 1 << 16;
+
+const JOINT_MATRIX_SIZE: usize = std::mem::size_of::<mint::ColumnMatrix4<f32>>();
+
+// Each instance's record is read from its own slot of a per-frame vertex buffer, so it doesn't
+// need push constant space beyond the guaranteed minimum
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct InstanceRecord {
+    previous_transform: mint::ColumnMatrix4<f32>, // last frame's view times model
+    first_joint: u32,                             // into the frame's joint matrices, if skinned
+}
+
+// Sixteen f32s and a u32
+unsafe impl util::Pod for InstanceRecord {}
+
+const INSTANCE_RECORD_SIZE: usize = std::mem::size_of::<InstanceRecord>();
+
+// Groups opaque draws by pipeline, then by material, so that fewer binds change between them, and
+// within a material puts the nearest first so that early depth testing rejects more of the rest.
+// Indirect draws are per mesh, so this needs doing before culling. Materials are ordered by where
+// they first appear rather than by address, so the same meshes are drawn in the same order every
+// run.
+pub fn sort_draws(meshes: &mut [GpuMeshInstance], eye: &na::Point3<f32>) {
+    let mut material_order = HashMap::new();
+    for instance in meshes.iter() {
+        let next = material_order.len();
+        material_order
+            .entry(Arc::as_ptr(&instance.material))
+            .or_insert(next);
+    }
+    meshes.sort_by_cached_key(|instance| {
+        let pipeline = (
+            instance.material.is_alpha_tested(),
+            instance.joint_matrices.is_some(),
+        );
+        let material = material_order[&Arc::as_ptr(&instance.material)];
+        // Non-negative floats order the same as their bits
+        let distance = na::distance_squared(eye, &instance.world_bounds().center());
+        (pipeline, material, distance.to_bits())
+    });
+}
+
 pub struct GeometryStem {
+    bindless: Option<Arc<BindlessTable>>, // for opaque materials, if the device supports it
+    default_material: Arc<GpuMaterial>,
+    default_textures: DefaultTextures,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    joint_descriptor_set_layout: vk::DescriptorSetLayout,
+    materials: Mutex<UploadCache<Material, GpuMaterial>>,
+    meshes: Mutex<UploadCache<Mesh, GpuMesh>>,
     pipeline_layout: vk::PipelineLayout,
+    pipelines: Vec<vk::Pipeline>, // see pipeline()
+    render_pass: vk::RenderPass,
+    residency: Mutex<Residency>, // of material textures, not render targets
+    samplers: SamplerCache,
     shared_stem: Arc<SharedStem>,
+    motion_vert_shader_module: vk::ShaderModule,
     triangle_frag_shader_module: vk::ShaderModule,
-    triangle_shadow_frag_shader_module: vk::ShaderModule,
+    textures: Mutex<UploadCache<Texture, GpuTexture>>,
     triangle_vert_shader_module: vk::ShaderModule,
 }
 
+// Stand in for missing material textures, so triangle.frag doesn't need separate paths
+struct DefaultTextures {
+    flat_normal_map: Arc<GpuTexture>,
+    white: Arc<GpuTexture>,
+}
+
 impl GeometryStem {
-    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
+    pub fn new(shared_stem: Arc<SharedStem>) -> Result<Self, UploadError> {
         unsafe {
             let device = shared_stem.device();
 
+            let descriptor_set_layout = Self::create_descriptor_set_layout(device)?;
+            shared_stem.set_name(*descriptor_set_layout, "geometry")?;
+            let joint_descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[(
+                    vk::DescriptorType::STORAGE_BUFFER,
+                    vk::ShaderStageFlags::VERTEX,
+                )],
+            )?;
+            shared_stem.set_name(*joint_descriptor_set_layout, "geometry joints")?;
+
+            let samplers = SamplerCache::new(shared_stem.clone());
+
+            // The classic layout is still needed for blended materials
+            let bindless = if shared_stem.supports_bindless() {
+                Some(Arc::new(BindlessTable::new(shared_stem.clone())?))
+            } else {
+                None
+            };
+
+            let mut push_constant_ranges = vec![
+                ViewBuffer::push_constant_range(),
+                ModelBuffer::push_constant_range(),
+            ];
+            let set_layout = match &bindless {
+                Some(bindless) => {
+                    push_constant_ranges.push(MaterialIndexBuffer::push_constant_range());
+                    bindless.descriptor_set_layout()
+                }
+                None => *descriptor_set_layout,
+            };
             let pipeline_layout = util::create_pipeline_layout(
                 device,
-                &[], // descriptor set layouts
-                &[ViewBuffer::push_constant_range()],
+                &[set_layout, *joint_descriptor_set_layout],
+                &push_constant_ranges,
             )?;
             shared_stem.set_name(*pipeline_layout, "geometry")?;
 
-            let triangle_vert_shader_module =
-                util::create_shader_module(device, include_glsl!("shaders/triangle.vert"))?;
-            shared_stem.set_name(*triangle_vert_shader_module, "triangle vert")?;
-            let triangle_frag_shader_module =
-                util::create_shader_module(device, include_glsl!("shaders/triangle.frag"))?;
-            shared_stem.set_name(*triangle_frag_shader_module, "triangle frag")?;
-            let triangle_shadow_frag_shader_module =
-                util::create_shader_module(device, include_glsl!("shaders/triangle-shadow.frag"))?;
-            shared_stem.set_name(*triangle_frag_shader_module, "triangle shadow frag")?;
+            let render_pass = Self::create_render_pass(
+                device,
+                SharedFrond::DIFFUSE_FORMAT,
+                SharedFrond::NORMAL_FORMAT,
+                shared_stem.depth_stencil_format(),
+                SharedFrond::MATERIAL_FORMAT,
+                SharedFrond::EMISSIVE_FORMAT,
+                SharedFrond::VELOCITY_FORMAT,
+            )?;
+            shared_stem.set_name(*render_pass, "geometry")?;
+
+            let (
+                [triangle_vert_shader_module, motion_vert_shader_module, triangle_frag_shader_module],
+                pipelines,
+            ) = Self::create_shader_modules_and_pipelines(
+                &shared_stem,
+                bindless.is_some(),
+                *pipeline_layout,
+                *render_pass,
+            )?;
+
+            let default_textures = DefaultTextures {
+                flat_normal_map: Arc::new(GpuTexture::new(
+                    shared_stem.clone(),
+                    &Texture::new_linear(1, 1, vec![128, 128, 255, 255]),
+                )?),
+                white: Arc::new(GpuTexture::new(
+                    shared_stem.clone(),
+                    &Texture::new(1, 1, vec![255; 4]),
+                )?),
+            };
+
+            // For meshes without a material of their own
+            let default_material = GpuMaterial::new(
+                shared_stem.clone(),
+                &Default::default(),
+                default_textures.substitute(&Default::default(), |texture| {
+                    Ok(Arc::new(GpuTexture::new(shared_stem.clone(), texture)?))
+                })?,
+                *descriptor_set_layout,
+                samplers.get(Default::default())?,
+                bindless.as_ref(),
+            )?;
 
             Ok(Self {
+                bindless,
+                default_material: Arc::new(default_material),
+                default_textures,
+                descriptor_set_layout: descriptor_set_layout.take(),
+                joint_descriptor_set_layout: joint_descriptor_set_layout.take(),
+                materials: Mutex::new(UploadCache::new()),
+                meshes: Mutex::new(UploadCache::new()),
+                motion_vert_shader_module: motion_vert_shader_module.take(),
                 pipeline_layout: pipeline_layout.take(),
+                pipelines: pipelines.take(),
+                render_pass: render_pass.take(),
+                residency: Default::default(),
+                samplers,
+                textures: Mutex::new(UploadCache::new()),
                 triangle_frag_shader_module: triangle_frag_shader_module.take(),
-                triangle_shadow_frag_shader_module: triangle_shadow_frag_shader_module.take(),
                 triangle_vert_shader_module: triangle_vert_shader_module.take(),
                 shared_stem,
             })
         }
     }
-}
 
-impl Drop for GeometryStem {
-    fn drop(&mut self) {
+    // Swaps in pipelines made from the current shader sources, keeping everything uploaded
+    #[cfg(feature = "hot-reload")]
+    pub fn reload_shaders(&mut self) -> Result<(), UploadError> {
         unsafe {
-            let device = self.shared_stem.device();
-            let _ = device.device_wait_idle();
+            let (shader_modules, pipelines) = Self::create_shader_modules_and_pipelines(
+                &self.shared_stem,
+                self.bindless.is_some(),
+                self.pipeline_layout,
+                self.render_pass,
+            )?;
+            let [triangle_vert_shader_module, motion_vert_shader_module, triangle_frag_shader_module] =
+                shader_modules.map(Guarded::take);
+            let old_shader_modules = [
+                std::mem::replace(
+                    &mut self.triangle_vert_shader_module,
+                    triangle_vert_shader_module,
+                ),
+                std::mem::replace(
+                    &mut self.motion_vert_shader_module,
+                    motion_vert_shader_module,
+                ),
+                std::mem::replace(
+                    &mut self.triangle_frag_shader_module,
+                    triangle_frag_shader_module,
+                ),
+            ];
+            let old_pipelines = std::mem::replace(&mut self.pipelines, pipelines.take());
 
-            device.destroy_shader_module(self.triangle_frag_shader_module, None);
-            device.destroy_shader_module(self.triangle_shadow_frag_shader_module, None);
-            device.destroy_shader_module(self.triangle_vert_shader_module, None);
-            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            let device = self.shared_stem.device();
+            for pipeline in old_pipelines {
+                device.destroy_pipeline(pipeline, None);
+            }
+            for shader_module in old_shader_modules {
+                device.destroy_shader_module(shader_module, None);
+            }
         }
+        Ok(())
     }
-}
 
-pub struct GeometryFrond {
-    framebuffer: vk::Framebuffer,
-    pipeline: vk::Pipeline,
-    render_pass: vk::RenderPass,
-    shadow_framebuffer: vk::Framebuffer,
-    shadow_pipeline: vk::Pipeline,
-    shadow_render_pass: vk::RenderPass,
-    shared_frond: Arc<SharedFrond>,
-    geometry_stem: Arc<GeometryStem>,
-}
-
-impl GeometryFrond {
-    pub fn new(geometry_stem: Arc<GeometryStem>, shared_frond: Arc<SharedFrond>) -> VkResult<Self> {
-        let shared_stem = &geometry_stem.shared_stem;
-        shared_stem.assert_is(&shared_frond.stem());
-        unsafe {
-            let device = shared_frond.device();
+    // The triangle, motion and fragment shader modules, and the pipelines made from them
+    #[allow(clippy::type_complexity)]
+    unsafe fn create_shader_modules_and_pipelines(
+        shared_stem: &SharedStem,
+        bindless: bool,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> Result<
+        (
+            [Guarded<(vk::ShaderModule, &ash::Device)>; 3],
+            Guarded<(Vec<vk::Pipeline>, &ash::Device)>,
+        ),
+        UploadError,
+    > {
+        let device = shared_stem.device();
+
+        let triangle_vert_shader_module =
+            util::create_shader_module(device, &include_shader!("shaders/triangle.vert"))?;
+        shared_stem.set_name(*triangle_vert_shader_module, "triangle vert")?;
+        let motion_vert_shader_module =
+            util::create_shader_module(device, &include_shader!("shaders/triangle-motion.vert"))?;
+        shared_stem.set_name(*motion_vert_shader_module, "triangle motion vert")?;
+        let triangle_frag_shader_module = if bindless {
+            util::create_shader_module(device, &include_shader!("shaders/triangle-bindless.frag"))?
+        } else {
+            util::create_shader_module(device, &include_shader!("shaders/triangle.frag"))?
+        };
+        shared_stem.set_name(*triangle_frag_shader_module, "triangle frag")?;
+
+        let color_workflow = shared_stem.color_workflow().specialization_constant();
+        let mut pipelines = Vec::<vk::Pipeline>::new().guard_with(device);
+        for skinned in [false, true] {
+            for alpha_tested in [false, true] {
+                let pipeline = Self::create_pipeline(
+                    device,
+                    *motion_vert_shader_module,
+                    &util::Specialization::new(&[skinned as _]),
+                    *triangle_frag_shader_module,
+                    &util::Specialization::new(&[color_workflow, alpha_tested as _]),
+                    pipeline_layout,
+                    render_pass,
+                )?;
+                let name = match (skinned, alpha_tested) {
+                    (false, false) => "geometry",
+                    (false, true) => "geometry alpha-tested",
+                    (true, false) => "geometry skinned",
+                    (true, true) => "geometry skinned alpha-tested",
+                };
+                shared_stem.set_name(*pipeline, name)?;
+                pipelines.push(pipeline.take());
+            }
+        }
 
-            let render_pass = Self::create_render_pass(
-                device,
-                shared_frond.diffuse().format,
-                shared_frond.normal().format,
-                shared_frond.depth_stencil().format,
-            )?;
-            shared_stem.set_name(*render_pass, "geometry")?;
+        Ok((
+            [
+                triangle_vert_shader_module,
+                motion_vert_shader_module,
+                triangle_frag_shader_module,
+            ],
+            pipelines,
+        ))
+    }
 
-            let shadow_render_pass =
-                Self::create_shadow_render_pass(device, shared_frond.shadow().format)?;
-            shared_stem.set_name(*shadow_render_pass, "shadow geometry")?;
+    // For passes that draw meshes with their own fragment shading
+    pub fn material_descriptor_set_layout(&self) -> vk::DescriptorSetLayout {
+        self.descriptor_set_layout
+    }
 
-            let pipeline = Self::create_pipeline(
-                device,
-                geometry_stem.triangle_vert_shader_module,
-                geometry_stem.triangle_frag_shader_module,
-                shared_frond.resolution(),
-                geometry_stem.pipeline_layout,
-                *render_pass,
-            )?;
-            shared_stem.set_name(*pipeline, "geometry")?;
+    pub fn vert_shader_module(&self) -> vk::ShaderModule {
+        self.triangle_vert_shader_module
+    }
 
-            let shadow_pipeline = Self::create_shadow_pipeline(
-                device,
-                geometry_stem.triangle_vert_shader_module,
-                geometry_stem.triangle_shadow_frag_shader_module,
-                shared_frond.shadow().resolution_2d(),
-                geometry_stem.pipeline_layout,
-                *shadow_render_pass,
-            )?;
-            shared_stem.set_name(*pipeline, "geometry")?;
+    // Masked and dithered materials discard fragments, and skinned meshes read joint matrices
+    fn pipeline(&self, alpha_tested: bool, skinned: bool) -> vk::Pipeline {
+        self.pipelines[2 * skinned as usize + alpha_tested as usize]
+    }
 
-            let framebuffer = util::create_framebuffer(
-                device,
-                *render_pass,
-                &[
-                    shared_frond.diffuse().view,
-                    shared_frond.normal().view,
-                    shared_frond.depth_stencil().view,
-                ],
-                shared_frond.resolution(),
-            )?;
-            shared_stem.set_name(*framebuffer, "geometry")?;
+    fn instance_binding_description() -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription {
+            binding: 1,
+            stride: INSTANCE_RECORD_SIZE as _,
+            input_rate: vk::VertexInputRate::INSTANCE,
+        }
+    }
 
-            let shadow_framebuffer = util::create_framebuffer(
-                device,
-                *shadow_render_pass,
-                &[shared_frond.shadow().view],
-                shared_frond.shadow().resolution_2d(),
-            )?;
-            shared_stem.set_name(*shadow_framebuffer, "shadow geometry")?;
+    // A matrix takes a location per column, after the vertex's own attributes
+    fn instance_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 5] {
+        let vec4_size = std::mem::size_of::<mint::Vector4<f32>>() as u32;
+        let first_location = Vertex::attribute_descriptions().len() as u32;
+        let column = |column| vk::VertexInputAttributeDescription {
+            location: first_location + column,
+            binding: 1,
+            format: vk::Format::R32G32B32A32_SFLOAT,
+            offset: column * vec4_size,
+        };
+        [
+            column(0),
+            column(1),
+            column(2),
+            column(3),
+            vk::VertexInputAttributeDescription {
+                location: first_location + 4,
+                binding: 1,
+                format: vk::Format::R32_UINT,
+                offset: JOINT_MATRIX_SIZE as _,
+            },
+        ]
+    }
 
-            Ok(Self {
-                framebuffer: framebuffer.take(),
-                pipeline: pipeline.take(),
-                render_pass: render_pass.take(),
-                shadow_framebuffer: shadow_framebuffer.take(),
-                shadow_pipeline: shadow_pipeline.take(),
-                shadow_render_pass: shadow_render_pass.take(),
-                shared_frond,
-                geometry_stem,
-            })
-        }
+    unsafe fn create_descriptor_set_layout(
+        device: &ash::Device,
+    ) -> VkResult<Guarded<(vk::DescriptorSetLayout, &ash::Device)>> {
+        // Albedo, normal map, metallic-roughness and emissive textures, then the material's factors
+        let texture_binding = |binding| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build()
+        };
+        let bindings = [
+            texture_binding(0),
+            texture_binding(1),
+            texture_binding(2),
+            texture_binding(3),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(4)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ];
+        let descriptor_set_layout_create_info =
+            vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        Ok(device
+            .create_descriptor_set_layout(&descriptor_set_layout_create_info, None)?
+            .guard_with(device))
     }
 
     unsafe fn create_render_pass(
@@ -154,6 +391,9 @@ impl GeometryFrond {
         diffuse_format: vk::Format,
         normal_format: vk::Format,
         depth_stencil_format: vk::Format,
+        material_format: vk::Format,
+        emissive_format: vk::Format,
+        velocity_format: vk::Format,
     ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
         let attachments = [
             vk::AttachmentDescription::builder()
@@ -177,11 +417,36 @@ impl GeometryFrond {
                 .samples(vk::SampleCountFlags::TYPE_1)
                 .load_op(vk::AttachmentLoadOp::CLEAR)
                 .store_op(vk::AttachmentStoreOp::STORE)
-                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                // Cleared for the lighting pass's light volumes to mark
+                .stencil_load_op(vk::AttachmentLoadOp::CLEAR)
+                .stencil_store_op(vk::AttachmentStoreOp::STORE)
                 .initial_layout(vk::ImageLayout::UNDEFINED)
                 .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
                 .build(),
+            vk::AttachmentDescription::builder()
+                .format(material_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(emissive_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(velocity_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
         ];
 
         let color_attachment_refs = [
@@ -193,6 +458,18 @@ impl GeometryFrond {
                 .attachment(1)
                 .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
                 .build(),
+            vk::AttachmentReference::builder()
+                .attachment(3)
+                .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentReference::builder()
+                .attachment(4)
+                .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentReference::builder()
+                .attachment(5)
+                .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
         ];
         let depth_stencil_attachment_ref = vk::AttachmentReference::builder()
             .attachment(2)
@@ -204,42 +481,12 @@ impl GeometryFrond {
             .depth_stencil_attachment(&depth_stencil_attachment_ref)
             .build()];
 
-        let dependencies = [];
-
-        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
-            .attachments(&attachments)
-            .subpasses(&subpasses)
-            .dependencies(&dependencies);
-        Ok(device
-            .create_render_pass(&render_pass_create_info, None)?
-            .guard_with(device))
-    }
-
-    unsafe fn create_shadow_render_pass(
-        device: &ash::Device,
-        depth_stencil_format: vk::Format,
-    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
-        let attachments = [vk::AttachmentDescription::builder()
-            .format(depth_stencil_format)
-            .samples(vk::SampleCountFlags::TYPE_1)
-            .load_op(vk::AttachmentLoadOp::CLEAR)
-            .store_op(vk::AttachmentStoreOp::STORE)
-            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-            .initial_layout(vk::ImageLayout::UNDEFINED)
-            .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .build()];
-
-        let depth_stencil_attachment_ref = vk::AttachmentReference::builder()
-            .attachment(0)
-            .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-            .build();
-        let subpasses = [vk::SubpassDescription::builder()
-            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-            .depth_stencil_attachment(&depth_stencil_attachment_ref)
-            .build()];
-
-        let dependencies = [];
+        // The previous frame's lighting may still be reading the G-buffer
+        // Earlier frames may still be reading the G-buffer and depth
+        let dependencies = [sync::dependency_before(
+            (sync::INPUT_ATTACHMENT_READ | sync::FRAGMENT_SAMPLED | sync::DEPTH_TEST).execution(),
+            sync::COLOR_ATTACHMENT_WRITE | sync::DEPTH_ATTACHMENT_WRITE,
+        )];
 
         let render_pass_create_info = vk::RenderPassCreateInfo::builder()
             .attachments(&attachments)
@@ -250,45 +497,52 @@ impl GeometryFrond {
             .guard_with(device))
     }
 
-    unsafe fn create_pipeline(
-        device: &ash::Device,
+    unsafe fn create_pipeline<'a>(
+        device: &'a ash::Device,
         triangle_vert_shader_module: vk::ShaderModule,
+        vert_specialization: &util::Specialization,
         triangle_frag_shader_module: vk::ShaderModule,
-        resolution: vk::Extent2D,
+        frag_specialization: &util::Specialization,
         pipeline_layout: vk::PipelineLayout,
         render_pass: vk::RenderPass,
-    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+    ) -> VkResult<Guarded<(vk::Pipeline, &'a ash::Device)>> {
         let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_specialization_info = vert_specialization.info();
         let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
             .module(triangle_vert_shader_module)
             .name(entry_point)
-            .stage(vk::ShaderStageFlags::VERTEX);
+            .stage(vk::ShaderStageFlags::VERTEX)
+            .specialization_info(&vert_specialization_info);
+        let frag_specialization_info = frag_specialization.info();
         let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
             .module(triangle_frag_shader_module)
             .name(entry_point)
-            .stage(vk::ShaderStageFlags::FRAGMENT);
+            .stage(vk::ShaderStageFlags::FRAGMENT)
+            .specialization_info(&frag_specialization_info);
         let shader_stages = [*vert_create_info, *frag_create_info];
 
-        let vertex_input_state = Default::default();
+        let [vertex_binding_description] = Vertex::binding_descriptions();
+        let vertex_binding_descriptions = [
+            vertex_binding_description,
+            Self::instance_binding_description(),
+            SkinVertex::binding_description(),
+        ];
+        let vertex_attribute_descriptions: Vec<_> = Vertex::attribute_descriptions()
+            .iter()
+            .chain(Self::instance_attribute_descriptions().iter())
+            .chain(SkinVertex::attribute_descriptions().iter())
+            .copied()
+            .collect();
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_binding_descriptions)
+            .vertex_attribute_descriptions(&vertex_attribute_descriptions);
 
         let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
             .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
 
-        let viewports = [vk::Viewport {
-            x: 0.0,
-            y: 0.0,
-            width: resolution.width as _,
-            height: resolution.height as _,
-            min_depth: 0.0,
-            max_depth: 1.0,
-        }];
-        let scissors = [vk::Rect2D {
-            offset: vk::Offset2D { x: 0, y: 0 },
-            extent: resolution,
-        }];
         let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors);
+            .viewport_count(1)
+            .scissor_count(1);
 
         let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
             .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
@@ -310,19 +564,17 @@ impl GeometryFrond {
             //.max_depth_bounds()
             ;
 
-        let attachments = [
-            vk::PipelineColorBlendAttachmentState {
-                color_write_mask: vk::ColorComponentFlags::all(),
-                ..Default::default()
-            },
-            vk::PipelineColorBlendAttachmentState {
-                color_write_mask: vk::ColorComponentFlags::all(),
-                ..Default::default()
-            },
-        ];
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            color_write_mask: vk::ColorComponentFlags::all(),
+            ..Default::default()
+        }; 5];
         let color_blend_state =
             vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
 
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
         let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
             .stages(&shader_stages)
             .vertex_input_state(&vertex_input_state)
@@ -333,7 +585,7 @@ impl GeometryFrond {
             .multisample_state(&multisample_state)
             .depth_stencil_state(&depth_stencil_state)
             .color_blend_state(&color_blend_state)
-            // .dynamic_state()
+            .dynamic_state(&dynamic_state)
             .layout(pipeline_layout)
             .render_pass(render_pass)
             .subpass(0)
@@ -350,98 +602,396 @@ impl GeometryFrond {
         Ok(pipelines.pop().unwrap().guard_with(device))
     }
 
-    unsafe fn create_shadow_pipeline(
-        device: &ash::Device,
-        triangle_vert_shader_module: vk::ShaderModule,
-        triangle_frag_shader_module: vk::ShaderModule,
-        resolution: vk::Extent2D,
-        pipeline_layout: vk::PipelineLayout,
-        render_pass: vk::RenderPass,
-    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
-        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
-        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
-            .module(triangle_vert_shader_module)
-            .name(entry_point)
-            .stage(vk::ShaderStageFlags::VERTEX);
-        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
-            .module(triangle_frag_shader_module)
-            .name(entry_point)
-            .stage(vk::ShaderStageFlags::FRAGMENT);
-        let shader_stages = [*vert_create_info, *frag_create_info];
+    // Meshes that don't fit the budget are left out until a frame with room for them, and
+    // materials that don't are drawn with the default one meanwhile
+    pub fn prepare_meshes(
+        &self,
+        instances: &[MeshInstance],
+        budget: &mut UploadBudget,
+    ) -> Result<Vec<GpuMeshInstance>, UploadError> {
+        let mut meshes = self.meshes.lock().unwrap();
+        let mut materials = self.materials.lock().unwrap();
+        let mut textures = self.textures.lock().unwrap();
+        let mut residency = self.residency.lock().unwrap();
+        meshes.evict_unused();
+        materials.evict_unused(); // before textures, since materials hold onto theirs
+        textures.evict_unused();
+        residency.retain(|id| textures.contains(id));
+
+        let mut prepared = Vec::with_capacity(instances.len());
+        for instance in instances {
+            if !meshes.contains(instance.mesh.id())
+                && !budget.try_spend(instance.mesh.upload_size())
+            {
+                continue;
+            }
+            let mesh = meshes.get_or_upload(instance.mesh.id(), &instance.mesh, |mesh| {
+                GpuMesh::new(self.shared_stem.clone(), mesh)
+            })?;
+            let material = match &instance.material {
+                Some(handle)
+                    if materials.contains(handle.id())
+                        || budget.try_spend(Self::upload_size(handle.material(), &textures)) =>
+                {
+                    for texture in Self::textures_of(handle.material()) {
+                        residency.touch(texture.id());
+                    }
+                    materials.get_or_upload(handle.id(), handle.shared(), |material| {
+                        let textures = self.default_textures.substitute(material, |texture| {
+                            textures.get_or_upload(texture.id(), texture, |texture| {
+                                let uploaded = GpuTexture::new(self.shared_stem.clone(), texture)?;
+                                if !texture.is_render_target() {
+                                    let size = (texture.width(), texture.height());
+                                    residency.uploaded(
+                                        texture.id(),
+                                        size,
+                                        uploaded.allocated_bytes(),
+                                        0,
+                                    );
+                                }
+                                Ok(uploaded)
+                            })
+                        })?;
+                        GpuMaterial::new(
+                            self.shared_stem.clone(),
+                            material,
+                            textures,
+                            self.descriptor_set_layout,
+                            self.samplers.get(material.sampler)?,
+                            self.bindless.as_ref(),
+                        )
+                    })?
+                }
+                _ => self.default_material.clone(),
+            };
+            // Joints are no use to a mesh without a skin, which stays unskinned
+            let joint_matrices = instance
+                .joint_matrices
+                .clone()
+                .filter(|_| mesh.is_skinned());
+            // Posed meshes change shape, however still they're held
+            let is_static = instance.is_static && joint_matrices.is_none();
+            prepared.push(GpuMeshInstance {
+                mesh,
+                material,
+                transform: instance.transform,
+                previous_transform: instance.previous_transform.unwrap_or(instance.transform),
+                joint_matrices,
+                is_static,
+            });
+        }
+        Ok(prepared)
+    }
 
-        let vertex_input_state = Default::default();
+    // Ends the frame for texture residency, bringing material textures within texture_budget by
+    // demoting or evicting those drawn least recently, and restoring demoted ones that are drawn
+    // again once there's room. Materials using a changed texture are dropped, to be rebuilt with
+    // it the next time they're drawn. Restoring full resolution waits on the upload budget.
+    pub fn enforce_texture_budget(
+        &self,
+        texture_budget: Option<u64>,
+        budget: &mut UploadBudget,
+    ) -> Result<(), UploadError> {
+        let mut materials = self.materials.lock().unwrap();
+        let mut textures = self.textures.lock().unwrap();
+        let mut residency = self.residency.lock().unwrap();
+        residency.set_budget(texture_budget);
+
+        let mut changed = HashSet::new();
+        for change in residency.end_frame() {
+            let (id, demotion) = match change {
+                ResidencyChange::Demote(id, demotion) => (id, demotion),
+                ResidencyChange::Promote(id) => (id, 0),
+                ResidencyChange::Evict(id) => {
+                    textures.remove(id);
+                    residency.forget(id);
+                    changed.insert(id);
+                    continue;
+                }
+            };
+            let texture = match textures.resource(id) {
+                Some(texture) => texture,
+                None => continue,
+            };
+            if demotion == 0 && !budget.try_spend(texture.pixels().len() as u64) {
+                continue;
+            }
+            let uploaded = if demotion == 0 {
+                GpuTexture::new(self.shared_stem.clone(), &texture)?
+            } else {
+                GpuTexture::new(self.shared_stem.clone(), &texture.downsampled(demotion))?
+            };
+            let size = (texture.width(), texture.height());
+            residency.uploaded(id, size, uploaded.allocated_bytes(), demotion);
+            textures.replace(id, &texture, Arc::new(uploaded));
+            changed.insert(id);
+        }
 
-        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+        if !changed.is_empty() {
+            materials.evict_where(|material| {
+                Self::textures_of(material).any(|texture| changed.contains(&texture.id()))
+            });
+        }
+        Ok(())
+    }
 
-        let viewports = [vk::Viewport {
-            x: 0.0,
-            y: 0.0,
-            width: resolution.width as _,
-            height: resolution.height as _,
-            min_depth: 0.0,
-            max_depth: 1.0,
-        }];
-        let scissors = [vk::Rect2D {
-            offset: vk::Offset2D { x: 0, y: 0 },
-            extent: resolution,
-        }];
-        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors);
+    // Bytes of material textures as tracked for the texture budget, and how many are demoted
+    pub fn texture_residency(&self) -> (u64, usize) {
+        let residency = self.residency.lock().unwrap();
+        (residency.bytes(), residency.demoted())
+    }
 
-        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0);
+    // Bytes of whichever of the material's textures aren't uploaded yet
+    fn upload_size(material: &Material, textures: &UploadCache<Texture, GpuTexture>) -> u64 {
+        Self::textures_of(material)
+            .filter(|texture| !textures.contains(texture.id()))
+            .map(|texture| texture.pixels().len() as u64)
+            .sum()
+    }
 
-        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+    fn textures_of(material: &Material) -> impl Iterator<Item = &Arc<Texture>> {
+        (material.albedo_texture.iter())
+            .chain(material.normal_map.iter())
+            .chain(material.metallic_roughness_texture.iter())
+            .chain(material.emissive_texture.iter())
+    }
 
-        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
-            .depth_test_enable(true)
-            .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::GREATER)
-            .depth_bounds_test_enable(false)
-            .stencil_test_enable(false)
-            //.front()
-            //.back()
-            //.min_depth_bounds()
-            //.max_depth_bounds()
-            ;
+    // Shares the materials' cache, so render targets are drawn to the same texture they sample
+    pub fn prepare_texture(&self, texture: &Arc<Texture>) -> Result<Arc<GpuTexture>, UploadError> {
+        let mut textures = self.textures.lock().unwrap();
+        textures.get_or_upload(texture.id(), texture, |texture| {
+            GpuTexture::new(self.shared_stem.clone(), texture)
+        })
+    }
+}
 
-        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder();
+impl DefaultTextures {
+    fn substitute(
+        &self,
+        material: &Material,
+        mut prepare: impl FnMut(&Arc<Texture>) -> Result<Arc<GpuTexture>, UploadError>,
+    ) -> Result<MaterialTextures, UploadError> {
+        let mut prepare_or = |texture: &Option<Arc<Texture>>, default: &Arc<GpuTexture>| {
+            texture
+                .as_ref()
+                .map_or_else(|| Ok(default.clone()), &mut prepare)
+        };
+        Ok(MaterialTextures {
+            albedo: prepare_or(&material.albedo_texture, &self.white)?,
+            normal_map: prepare_or(&material.normal_map, &self.flat_normal_map)?,
+            metallic_roughness: prepare_or(&material.metallic_roughness_texture, &self.white)?,
+            emissive: prepare_or(&material.emissive_texture, &self.white)?,
+        })
+    }
+}
 
-        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
-            .stages(&shader_stages)
-            .vertex_input_state(&vertex_input_state)
-            .input_assembly_state(&input_assembly_state)
-            // .tesselation_state()
-            .viewport_state(&viewport_state)
-            .rasterization_state(&rasterization_state)
-            .multisample_state(&multisample_state)
-            .depth_stencil_state(&depth_stencil_state)
-            .color_blend_state(&color_blend_state)
-            // .dynamic_state()
-            .layout(pipeline_layout)
-            .render_pass(render_pass)
-            .subpass(0)
-            .build()];
+impl Drop for GeometryStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
 
-        let mut pipelines = device
-            .create_graphics_pipelines(
-                vk::PipelineCache::null(),
-                &graphics_pipeline_create_infos,
-                None,
-            )
-            .map_err(|(_, err)| err)?;
+            for &pipeline in &self.pipelines {
+                device.destroy_pipeline(pipeline, None);
+            }
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_shader_module(self.triangle_frag_shader_module, None);
+            device.destroy_shader_module(self.triangle_vert_shader_module, None);
+            device.destroy_shader_module(self.motion_vert_shader_module, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            device.destroy_descriptor_set_layout(self.joint_descriptor_set_layout, None);
+        }
+    }
+}
 
-        Ok(pipelines.pop().unwrap().guard_with(device))
+pub struct GeometryFrond {
+    framebuffer: vk::Framebuffer,
+    instance_buffers: Vec<Buffer>, // per frame in flight
+    joint_buffers: Vec<Buffer>,    // per frame in flight
+    joint_descriptor_sets: Vec<vk::DescriptorSet>, // per frame in flight
+    shared_frond: Arc<SharedFrond>,
+    geometry_stem: Arc<GeometryStem>,
+}
+
+// What every draw of a frame shares, whether recorded inline or on another thread
+#[derive(Clone, Copy)]
+struct FrameInstances<'a> {
+    instance_buffer: vk::Buffer,
+    joint_descriptor_set: vk::DescriptorSet,
+    skinned: &'a [bool], // for each mesh, whether its joints made it into the joint buffer
+}
+
+impl GeometryFrond {
+    // Fewer than this per thread and it's quicker to record everything inline
+    const MIN_MESHES_PER_THREAD: usize = 256;
+
+    pub fn new(
+        geometry_stem: Arc<GeometryStem>,
+        shared_frond: Arc<SharedFrond>,
+    ) -> Result<Self, UploadError> {
+        let shared_stem = &geometry_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+
+            // The slot past the last is left zeroed, which the shaders take to mean no motion
+            let mut instance_buffers = Vec::<Buffer>::new().guard_with(device);
+            let mut joint_buffers = Vec::<Buffer>::new().guard_with(device);
+            for _ in 0..FRAMES_IN_FLIGHT {
+                let instance_buffer = upload::create_buffer(
+                    shared_stem,
+                    ((MAX_INSTANCE_RECORDS + 1) * INSTANCE_RECORD_SIZE) as _,
+                    vk::BufferUsageFlags::VERTEX_BUFFER,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )?;
+                instance_buffer.write(
+                    device,
+                    (MAX_INSTANCE_RECORDS * INSTANCE_RECORD_SIZE) as _,
+                    &[0; INSTANCE_RECORD_SIZE],
+                )?;
+                shared_stem.set_name(instance_buffer.buffer, "instance records")?;
+                shared_stem.set_name(instance_buffer.memory, "instance records")?;
+                instance_buffers.push(instance_buffer.take());
+
+                let joint_buffer = upload::create_buffer(
+                    shared_stem,
+                    (MAX_JOINTS * JOINT_MATRIX_SIZE) as _,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )?;
+                shared_stem.set_name(joint_buffer.buffer, "joint matrices")?;
+                shared_stem.set_name(joint_buffer.memory, "joint matrices")?;
+                joint_buffers.push(joint_buffer.take());
+            }
+
+            let descriptor_allocator = shared_frond.descriptor_allocator();
+            let mut joint_descriptor_sets =
+                Vec::<vk::DescriptorSet>::with_capacity(FRAMES_IN_FLIGHT)
+                    .guard_with((descriptor_allocator, device));
+            for joint_buffer in joint_buffers.iter() {
+                let descriptor_set = descriptor_allocator
+                    .allocate(device, geometry_stem.joint_descriptor_set_layout)?;
+                util::write_descriptor_set(
+                    device,
+                    *descriptor_set,
+                    &[(0, Descriptor::StorageBuffer(joint_buffer.buffer))],
+                );
+                shared_stem.set_name(*descriptor_set, "geometry joints")?;
+                joint_descriptor_sets.push(descriptor_set.take());
+            }
+
+            let framebuffer = util::create_framebuffer(
+                device,
+                geometry_stem.render_pass,
+                &[
+                    shared_frond.diffuse().view,
+                    shared_frond.normal().view,
+                    shared_frond.depth_stencil().view,
+                    shared_frond.material().view,
+                    shared_frond.emissive().view,
+                    shared_frond.velocity().view,
+                ],
+                shared_frond.resolution(),
+            )?;
+            shared_stem.set_name(*framebuffer, "geometry")?;
+
+            Ok(Self {
+                framebuffer: framebuffer.take(),
+                instance_buffers: instance_buffers.take(),
+                joint_buffers: joint_buffers.take(),
+                joint_descriptor_sets: joint_descriptor_sets.take(),
+                shared_frond,
+                geometry_stem,
+            })
+        }
     }
 
-    pub unsafe fn draw(&self, command_buffer: vk::CommandBuffer, view: mint::ColumnMatrix4<f32>) {
+    pub fn prepare_meshes(
+        &self,
+        instances: &[MeshInstance],
+        budget: &mut UploadBudget,
+    ) -> Result<Vec<GpuMeshInstance>, UploadError> {
+        self.geometry_stem.prepare_meshes(instances, budget)
+    }
+
+    pub fn prepare_texture(&self, texture: &Arc<Texture>) -> Result<Arc<GpuTexture>, UploadError> {
+        self.geometry_stem.prepare_texture(texture)
+    }
+
+    pub fn enforce_texture_budget(
+        &self,
+        texture_budget: Option<u64>,
+        budget: &mut UploadBudget,
+    ) -> Result<(), UploadError> {
+        self.geometry_stem
+            .enforce_texture_budget(texture_budget, budget)
+    }
+
+    pub fn texture_residency(&self) -> (u64, usize) {
+        self.geometry_stem.texture_residency()
+    }
+
+    // Meshes are split between the secondary command buffers, if there are any and enough meshes.
+    // Velocity is measured against previous_view, last frame's view for this one.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        secondary_command_buffers: &[vk::CommandBuffer],
+        frame_index: usize,
+        view: mint::ColumnMatrix4<f32>,
+        previous_view: &na::Matrix4<f32>,
+        meshes: &[GpuMeshInstance],
+        indirect_draws: Option<&IndirectDraws>,
+    ) -> VkResult<()> {
         let device = self.shared_frond.device();
 
+        // Skinned meshes' joints are packed one after another, for as long as they fit
+        let mut joint_matrices = Vec::new();
+        let mut skinned = vec![false; meshes.len()];
+        let mut instance_records = Vec::with_capacity(meshes.len().min(MAX_INSTANCE_RECORDS));
+        for (instance, skinned) in meshes.iter().zip(&mut skinned).take(MAX_INSTANCE_RECORDS) {
+            let first_joint = joint_matrices.len();
+            if let Some(instance_joint_matrices) = &instance.joint_matrices {
+                if first_joint + instance_joint_matrices.len() <= MAX_JOINTS {
+                    joint_matrices.extend_from_slice(instance_joint_matrices);
+                    *skinned = true;
+                }
+            }
+            instance_records.push(InstanceRecord {
+                previous_transform: (previous_view
+                    * na::Matrix4::from(instance.previous_transform))
+                .into(),
+                first_joint: first_joint as _,
+            });
+        }
+        if !instance_records.is_empty() {
+            self.instance_buffers[frame_index].write(
+                device,
+                0,
+                util::as_bytes(&instance_records),
+            )?;
+        }
+        if !joint_matrices.is_empty() {
+            self.joint_buffers[frame_index].write(device, 0, util::as_bytes(&joint_matrices))?;
+        }
+        let frame_instances = FrameInstances {
+            instance_buffer: self.instance_buffers[frame_index].buffer,
+            joint_descriptor_set: self.joint_descriptor_sets[frame_index],
+            skinned: &skinned,
+        };
+
+        let chunk_size = meshes
+            .len()
+            .div_ceil(secondary_command_buffers.len().max(1))
+            .max(Self::MIN_MESHES_PER_THREAD);
+        let contents = if !secondary_command_buffers.is_empty() && meshes.len() > chunk_size {
+            vk::SubpassContents::SECONDARY_COMMAND_BUFFERS
+        } else {
+            vk::SubpassContents::INLINE
+        };
+
         let render_area = vk::Rect2D {
             offset: Default::default(),
             extent: self.shared_frond.resolution(),
@@ -464,75 +1014,115 @@ impl GeometryFrond {
                     stencil: 0,
                 },
             },
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 1.0, 0.0, 0.0],
+                },
+            },
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 0.0],
+                },
+            },
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 0.0],
+                },
+            },
         ];
 
         let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-            .render_pass(self.render_pass)
+            .render_pass(self.geometry_stem.render_pass)
             .framebuffer(self.framebuffer)
             .render_area(render_area)
             .clear_values(&clear_values);
-        device.cmd_begin_render_pass(
-            command_buffer,
-            &render_pass_begin_info,
-            vk::SubpassContents::INLINE,
-        );
-
-        let view_buffer = ViewBuffer { view };
-        device.cmd_push_constants(
-            command_buffer,
-            self.geometry_stem.pipeline_layout,
-            vk::ShaderStageFlags::VERTEX,
-            0,
-            view_buffer.as_std140().as_bytes(),
-        );
-
-        device.cmd_bind_pipeline(
-            command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            self.pipeline,
-        );
-
-        device.cmd_draw(
-            command_buffer,
-            6, // vertices
-            1, // instances
-            0, // first vertex
-            0, // first instance
-        );
+        device.cmd_begin_render_pass(command_buffer, &render_pass_begin_info, contents);
+
+        if contents == vk::SubpassContents::INLINE {
+            self.begin_drawing(command_buffer, view, frame_instances);
+            self.draw_meshes(command_buffer, meshes, 0, frame_instances, indirect_draws);
+        } else {
+            // Each thread records a contiguous chunk into its own secondary command buffer
+            let chunks: Vec<_> = meshes
+                .chunks(chunk_size)
+                .zip(secondary_command_buffers)
+                .enumerate()
+                .collect();
+            let recorded = jobs::map(
+                &chunks,
+                |&(chunk_index, (chunk, &secondary_command_buffer))| {
+                    self.record_secondary(
+                        secondary_command_buffer,
+                        view,
+                        chunk,
+                        chunk_index * chunk_size,
+                        frame_instances,
+                        indirect_draws,
+                    )
+                    .map(|()| secondary_command_buffer)
+                },
+            )
+            .into_iter()
+            .collect::<VkResult<Vec<_>>>()?;
+            device.cmd_execute_commands(command_buffer, &recorded);
+        }
 
         device.cmd_end_render_pass(command_buffer);
+        Ok(())
     }
 
-    pub unsafe fn draw_shadow(
+    unsafe fn record_secondary(
         &self,
         command_buffer: vk::CommandBuffer,
         view: mint::ColumnMatrix4<f32>,
-    ) {
+        meshes: &[GpuMeshInstance],
+        first_index: usize,
+        frame_instances: FrameInstances,
+        indirect_draws: Option<&IndirectDraws>,
+    ) -> VkResult<()> {
         let device = self.shared_frond.device();
 
-        let render_area = vk::Rect2D {
-            offset: Default::default(),
-            extent: self.shared_frond.shadow().resolution_2d(),
+        // The frame's pipeline statistics query is active while these execute, if it has one
+        let capabilities = self.geometry_stem.shared_stem.capabilities();
+        let pipeline_statistics = if capabilities.pipeline_statistics {
+            stats::PIPELINE_STATISTICS
+        } else {
+            vk::QueryPipelineStatisticFlags::empty()
         };
+        let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
+            .render_pass(self.geometry_stem.render_pass)
+            .subpass(0)
+            .framebuffer(self.framebuffer)
+            .pipeline_statistics(pipeline_statistics);
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(
+                vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT
+                    | vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE,
+            )
+            .inheritance_info(&inheritance_info);
+        device.begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
 
-        let clear_values = [vk::ClearValue {
-            depth_stencil: vk::ClearDepthStencilValue {
-                depth: 0.0,
-                stencil: 0,
-            },
-        }];
-
-        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-            .render_pass(self.shadow_render_pass)
-            .framebuffer(self.shadow_framebuffer)
-            .render_area(render_area)
-            .clear_values(&clear_values);
-        device.cmd_begin_render_pass(
+        self.begin_drawing(command_buffer, view, frame_instances);
+        self.draw_meshes(
             command_buffer,
-            &render_pass_begin_info,
-            vk::SubpassContents::INLINE,
+            meshes,
+            first_index,
+            frame_instances,
+            indirect_draws,
         );
 
+        device.end_command_buffer(command_buffer)
+    }
+
+    // Secondary command buffers inherit none of this, so each needs it recorded again
+    unsafe fn begin_drawing(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        view: mint::ColumnMatrix4<f32>,
+        frame_instances: FrameInstances,
+    ) {
+        let device = self.shared_frond.device();
+
         let view_buffer = ViewBuffer { view };
         device.cmd_push_constants(
             command_buffer,
@@ -541,22 +1131,126 @@ impl GeometryFrond {
             0,
             view_buffer.as_std140().as_bytes(),
         );
-
-        device.cmd_bind_pipeline(
+        device.cmd_bind_descriptor_sets(
             command_buffer,
             vk::PipelineBindPoint::GRAPHICS,
-            self.shadow_pipeline,
+            self.geometry_stem.pipeline_layout,
+            1,
+            &[frame_instances.joint_descriptor_set],
+            &[],
         );
+        if let Some(bindless) = &self.geometry_stem.bindless {
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.geometry_stem.pipeline_layout,
+                0,
+                &[bindless.descriptor_set()],
+                &[],
+            );
+        }
 
-        device.cmd_draw(
+        device.cmd_bind_pipeline(
             command_buffer,
-            6, // vertices
-            1, // instances
-            0, // first vertex
-            0, // first instance
+            vk::PipelineBindPoint::GRAPHICS,
+            self.geometry_stem.pipeline(false, false),
         );
+        util::set_viewport_and_scissor(device, command_buffer, self.shared_frond.resolution());
+    }
 
-        device.cmd_end_render_pass(command_buffer);
+    // Indirect draws and instance records are indexed from the whole mesh list, which meshes
+    // starts first_index into. Meshes start out on the plain pipeline, as bound by begin_drawing.
+    unsafe fn draw_meshes(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        meshes: &[GpuMeshInstance],
+        first_index: usize,
+        frame_instances: FrameInstances,
+        indirect_draws: Option<&IndirectDraws>,
+    ) {
+        let device = self.shared_frond.device();
+
+        let mut bound = (false, false);
+        let mut bound_material = None;
+        for (index, instance) in (first_index..).zip(meshes) {
+            let variant = (
+                instance.material.is_alpha_tested(),
+                frame_instances.skinned[index],
+            );
+            if variant != bound {
+                bound = variant;
+                device.cmd_bind_pipeline(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.geometry_stem.pipeline(variant.0, variant.1),
+                );
+            }
+
+            let slot = index.min(MAX_INSTANCE_RECORDS);
+            device.cmd_bind_vertex_buffers(
+                command_buffer,
+                1,
+                &[frame_instances.instance_buffer],
+                &[(slot * INSTANCE_RECORD_SIZE) as _],
+            );
+
+            let model_buffer = ModelBuffer {
+                model: instance.transform,
+            };
+            device.cmd_push_constants(
+                command_buffer,
+                self.geometry_stem.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX,
+                ViewBuffer::std140_size_static() as _,
+                model_buffer.as_std140().as_bytes(),
+            );
+            // Consecutive draws of the same material only need it bound once
+            let material = instance
+                .material
+                .binding()
+                .get(&self.geometry_stem.shared_stem);
+            if bound_material != Some(material) {
+                bound_material = Some(material);
+                match material {
+                    MaterialBinding::DescriptorSet(descriptor_set) => {
+                        device.cmd_bind_descriptor_sets(
+                            command_buffer,
+                            vk::PipelineBindPoint::GRAPHICS,
+                            self.geometry_stem.pipeline_layout,
+                            0,
+                            &[descriptor_set],
+                            &[],
+                        );
+                    }
+                    MaterialBinding::Bindless(material_index) => {
+                        let material_index_buffer = MaterialIndexBuffer { material_index };
+                        let range = MaterialIndexBuffer::push_constant_range();
+                        device.cmd_push_constants(
+                            command_buffer,
+                            self.geometry_stem.pipeline_layout,
+                            range.stage_flags,
+                            range.offset,
+                            material_index_buffer.as_std140().as_bytes(),
+                        );
+                    }
+                }
+            }
+
+            match indirect_draws {
+                Some(indirect_draws) if index < indirect_draws.count => {
+                    let stride = std::mem::size_of::<vk::DrawIndexedIndirectCommand>();
+                    instance.mesh.draw_indirect(
+                        &self.geometry_stem.shared_stem,
+                        command_buffer,
+                        indirect_draws.buffer,
+                        (index * stride) as _,
+                    );
+                }
+                _ => instance
+                    .mesh
+                    .draw(&self.geometry_stem.shared_stem, command_buffer),
+            }
+        }
     }
 }
 
@@ -566,12 +1260,16 @@ impl Drop for GeometryFrond {
             let device = self.shared_frond.device();
             let _ = device.device_wait_idle();
 
-            device.destroy_framebuffer(self.shadow_framebuffer, None);
             device.destroy_framebuffer(self.framebuffer, None);
-            device.destroy_pipeline(self.shadow_pipeline, None);
-            device.destroy_pipeline(self.pipeline, None);
-            device.destroy_render_pass(self.shadow_render_pass, None);
-            device.destroy_render_pass(self.render_pass, None);
+            self.shared_frond
+                .descriptor_allocator()
+                .free(device, &self.joint_descriptor_sets);
+            for instance_buffer in &mut self.instance_buffers {
+                instance_buffer.destroy_with(device);
+            }
+            for joint_buffer in &mut self.joint_buffers {
+                joint_buffer.destroy_with(device);
+            }
         }
     }
 }
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use thiserror::Error;
+
+use crate::sync::Access;
+
+#[derive(Error, Debug)]
+pub enum RenderGraphError {
+    #[error("Render graph passes depend on each other in a cycle")]
+    Cycle,
+    #[error("Render graph pass reads {0:?}, which no pass writes")]
+    Unwritten(Resource),
+}
+
+// Attachments and buffers that more than one pass touches within a frame. Hazards between frames in flight
+// are still covered by each render pass's external dependencies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Resource {
+    Depth,
+    GBuffer,
+    Shadow,
+    LightClusters,
+    Light,
+    DepthOfField,
+    Velocity,
+    MotionBlur,
+    Output,
+}
+
+// Writes may include read access too, e.g. for blending. Several passes can write the same
+// resource, in the order they're declared; readers see it once every writer is done.
+pub struct PassDeclaration<P> {
+    pub pass: P,
+    pub reads: Vec<(Resource, Access)>,
+    pub writes: Vec<(Resource, Access)>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Barrier {
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ResourceState {
+    last_write: Option<Access>,
+    reads: vk::PipelineStageFlags, // since the last write
+}
+
+pub struct RenderGraph<P> {
+    steps: Vec<(Option<Barrier>, P)>,
+}
+
+impl<P: Copy> RenderGraph<P> {
+    pub fn new(declarations: &[PassDeclaration<P>]) -> Result<Self, RenderGraphError> {
+        let order = Self::sort(declarations)?;
+
+        let mut states = HashMap::<Resource, ResourceState>::new();
+        let steps = order
+            .into_iter()
+            .map(|index| {
+                let declaration = &declarations[index];
+                let mut barrier = Barrier::default();
+                for &(resource, access) in declaration.reads.iter() {
+                    let state = states.entry(resource).or_default();
+                    if let Some(write) = state.last_write {
+                        barrier.src_stage |= write.stage;
+                        barrier.src_access |= write.access;
+                        barrier.dst_stage |= access.stage;
+                        barrier.dst_access |= access.access;
+                    }
+                    state.reads |= access.stage;
+                }
+                for &(resource, access) in declaration.writes.iter() {
+                    let state = states.entry(resource).or_default();
+                    // Earlier reads only need to finish executing before this overwrites them
+                    barrier.src_stage |= state.reads;
+                    if let Some(write) = state.last_write {
+                        barrier.src_stage |= write.stage;
+                        barrier.src_access |= write.access;
+                    }
+                    if !barrier.src_stage.is_empty() {
+                        barrier.dst_stage |= access.stage;
+                        barrier.dst_access |= access.access;
+                    }
+                    *state = ResourceState {
+                        last_write: Some(access),
+                        reads: vk::PipelineStageFlags::empty(),
+                    };
+                }
+                let barrier = Some(barrier).filter(|barrier| !barrier.src_stage.is_empty());
+                (barrier, declaration.pass)
+            })
+            .collect();
+
+        Ok(Self { steps })
+    }
+
+    // Topological, preferring declaration order wherever dependencies allow
+    fn sort(declarations: &[PassDeclaration<P>]) -> Result<Vec<usize>, RenderGraphError> {
+        let mut writers = HashMap::<Resource, Vec<usize>>::new();
+        for (index, declaration) in declarations.iter().enumerate() {
+            for &(resource, _) in declaration.writes.iter() {
+                writers.entry(resource).or_default().push(index);
+            }
+        }
+
+        let mut dependencies = vec![Vec::new(); declarations.len()];
+        for (index, declaration) in declarations.iter().enumerate() {
+            for &(resource, _) in declaration.reads.iter() {
+                let resource_writers = writers
+                    .get(&resource)
+                    .ok_or(RenderGraphError::Unwritten(resource))?;
+                dependencies[index].extend(
+                    resource_writers
+                        .iter()
+                        .copied()
+                        .filter(|&writer| writer != index),
+                );
+            }
+            for &(resource, _) in declaration.writes.iter() {
+                dependencies[index].extend(
+                    writers[&resource]
+                        .iter()
+                        .copied()
+                        .take_while(|&writer| writer != index),
+                );
+            }
+        }
+
+        let mut order = Vec::with_capacity(declarations.len());
+        let mut done = vec![false; declarations.len()];
+        while order.len() < declarations.len() {
+            let next = (0..declarations.len())
+                .find(|&index| {
+                    !done[index]
+                        && dependencies[index]
+                            .iter()
+                            .all(|&dependency| done[dependency])
+                })
+                .ok_or(RenderGraphError::Cycle)?;
+            done[next] = true;
+            order.push(next);
+        }
+        Ok(order)
+    }
+
+    // Records each pass in order, preceded by whatever barrier its accesses need
+    pub unsafe fn record<F>(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        mut record_pass: F,
+    ) -> VkResult<()>
+    where
+        F: FnMut(P) -> VkResult<()>,
+    {
+        for &(barrier, pass) in self.steps.iter() {
+            if let Some(barrier) = barrier {
+                let memory_barriers = [vk::MemoryBarrier::builder()
+                    .src_access_mask(barrier.src_access)
+                    .dst_access_mask(barrier.dst_access)
+                    .build()];
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    barrier.src_stage,
+                    barrier.dst_stage,
+                    vk::DependencyFlags::empty(),
+                    &memory_barriers,
+                    &[],
+                    &[],
+                );
+            }
+            record_pass(pass)?;
+        }
+        Ok(())
+    }
+}
@@ -164,16 +164,19 @@ define_guardable!(
 define_guardable!(vk::SurfaceKHR, khr::Surface, destroy_surface);
 define_guardable!(vk::SwapchainKHR, khr::Swapchain, destroy_swapchain);
 define_guardable!(vk::Buffer, ash::Device, destroy_buffer);
+define_guardable!(vk::BufferView, ash::Device, destroy_buffer_view);
 define_guardable!(vk::CommandPool, ash::Device, destroy_command_pool);
 define_guardable!(vk::DescriptorPool, ash::Device, destroy_descriptor_pool);
 define_guardable!(vk::DescriptorSetLayout, ash::Device, destroy_descriptor_set_layout);
 define_guardable!(vk::DeviceMemory, ash::Device, free_memory);
+define_guardable!(vk::Event, ash::Device, destroy_event);
 define_guardable!(vk::Fence, ash::Device, destroy_fence);
 define_guardable!(vk::Framebuffer, ash::Device, destroy_framebuffer);
 define_guardable!(vk::Image, ash::Device, destroy_image);
 define_guardable!(vk::ImageView, ash::Device, destroy_image_view);
 define_guardable!(vk::Pipeline, ash::Device, destroy_pipeline);
 define_guardable!(vk::PipelineLayout, ash::Device, destroy_pipeline_layout);
+define_guardable!(vk::QueryPool, ash::Device, destroy_query_pool);
 define_guardable!(vk::RenderPass, ash::Device, destroy_render_pass);
 define_guardable!(vk::Sampler, ash::Device, destroy_sampler);
 define_guardable!(vk::Semaphore, ash::Device, destroy_semaphore);
@@ -1,29 +1,47 @@
-use std::ops::Deref;
+use std::{
+    ops::Deref,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use ash::{prelude::VkResult, version::DeviceV1_0, vk};
 
 use crate::guard::{Guardable, GuardableResource, Guarded};
 
+// The default is null, so destroying it does nothing
+#[derive(Default)]
 pub struct Image {
-    pub format: vk::Format,
     pub image: vk::Image,
+    allocation_size: vk::DeviceSize,
+    pub layers: u32,
     pub memory: vk::DeviceMemory,
     pub resolution: vk::Extent3D,
     pub view: vk::ImageView,
 }
 
+// Bytes of memory bound to every live image, for RendererStats
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+pub fn allocated_bytes() -> u64 {
+    ALLOCATED_BYTES.load(Ordering::Relaxed)
+}
+
 impl Image {
+    pub fn allocation_size(&self) -> vk::DeviceSize {
+        self.allocation_size
+    }
+
     pub unsafe fn new<D, E>(
         device: D,
         image_create_info: &vk::ImageCreateInfo,
         select_memory_type: impl Fn(vk::MemoryRequirements) -> Result<u32, E>,
+        view_type: vk::ImageViewType,
         aspects: vk::ImageAspectFlags,
     ) -> VkResult<Result<Guarded<(Self, D)>, E>>
     where
         D: Deref<Target = ash::Device> + Clone,
     {
         let image = device
-            .create_image(&image_create_info, None)?
+            .create_image(image_create_info, None)?
             .guard_with(device.clone());
 
         let image_memory_requirements = device.get_image_memory_requirements(*image);
@@ -41,16 +59,10 @@ impl Image {
 
         device.bind_image_memory(*image, *memory, 0)?;
 
-        let view_type = match image_create_info.image_type {
-            vk::ImageType::TYPE_1D => vk::ImageViewType::TYPE_1D,
-            vk::ImageType::TYPE_2D => vk::ImageViewType::TYPE_2D,
-            vk::ImageType::TYPE_3D => vk::ImageViewType::TYPE_3D,
-            other => panic!("Unknown vk::ImageType: {:?}", other),
-        };
         let subresource_range = vk::ImageSubresourceRange::builder()
             .aspect_mask(aspects)
-            .level_count(1)
-            .layer_count(1);
+            .level_count(image_create_info.mip_levels)
+            .layer_count(image_create_info.array_layers);
         let image_view_create_info = vk::ImageViewCreateInfo::builder()
             .image(*image)
             .view_type(view_type)
@@ -61,12 +73,14 @@ impl Image {
             .guard_with(device.clone());
 
         let image = Self {
-            format: image_create_info.format,
             image: image.take(),
+            allocation_size: image_memory_requirements.size,
+            layers: image_create_info.array_layers,
             memory: memory.take(),
             resolution: image_create_info.extent,
             view: view.take(),
         };
+        ALLOCATED_BYTES.fetch_add(image.allocation_size, Ordering::Relaxed);
         Ok(Ok(image.guard_with(device)))
     }
 
@@ -82,6 +96,7 @@ impl Image {
         device.destroy_image_view(self.view, None);
         device.destroy_image(self.image, None);
         device.free_memory(self.memory, None);
+        ALLOCATED_BYTES.fetch_sub(self.allocation_size, Ordering::Relaxed);
     }
 }
 
@@ -105,6 +120,6 @@ where
 
     unsafe fn drop(self) {
         let (mut resource, context) = self;
-        resource.destroy_with(&*context);
+        resource.destroy_with(&context);
     }
 }
@@ -0,0 +1,46 @@
+use std::panic;
+use std::thread;
+
+// Fork-join helpers for a frame's independent CPU work, like culling each view or recording
+// secondary command buffers. Jobs run on scoped threads so they can borrow the frame, and results
+// come back in the order they were given, so whatever's recorded from them doesn't depend on which
+// thread finished first.
+
+// Runs both at once, b on the calling thread
+pub fn join<RA: Send, RB>(a: impl FnOnce() -> RA + Send, b: impl FnOnce() -> RB) -> (RA, RB) {
+    thread::scope(|scope| {
+        let a = scope.spawn(a);
+        let b = b();
+        (wait(a), b)
+    })
+}
+
+// Calls f on every item, with the items split into contiguous runs between the available threads.
+// The first run stays on the calling thread, so a single item never spawns anything.
+pub fn map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let chunk_size = items.len().div_ceil(thread_count()).max(1);
+    let mut chunks = items.chunks(chunk_size);
+    let first_chunk = chunks.next().unwrap_or_default();
+    let f = &f;
+    thread::scope(|scope| {
+        let threads: Vec<_> = chunks
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect();
+        let mut results: Vec<_> = first_chunk.iter().map(f).collect();
+        for thread in threads {
+            results.extend(wait(thread));
+        }
+        results
+    })
+}
+
+pub fn thread_count() -> usize {
+    thread::available_parallelism().map_or(1, usize::from)
+}
+
+// A job's panic carries on in whoever was waiting for it
+fn wait<T>(thread: thread::ScopedJoinHandle<T>) -> T {
+    thread
+        .join()
+        .unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
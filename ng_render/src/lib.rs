@@ -1,10 +1,111 @@
+mod anim;
+mod benchmark;
+mod bindless;
+mod blue_noise;
+mod buffer;
+mod camera;
+#[cfg(feature = "renderdoc")]
+mod capture;
+mod color_grading;
+mod compute;
+mod culling;
+mod debug_draw;
+mod depth_of_field;
+mod descriptors;
+mod display;
+mod environment;
+mod frame;
 mod geometry;
+mod graph;
 mod guard;
 mod image;
+mod jobs;
+mod light;
 mod lighting;
+mod material;
+mod mesh;
+mod motion_blur;
+mod obj;
+mod owned;
+mod pacing;
+mod plugin;
+mod present;
+mod render_target;
 mod renderer;
+mod replay;
+mod residency;
+mod retained;
+mod sampler;
+mod scene;
+mod shaders;
+mod shadow;
 mod shared;
+mod sky;
+mod staging;
+mod stats;
+mod stereo;
+mod streaming;
+mod sync;
+mod terrain;
+mod texture;
 mod tonemapping;
+mod transparency;
+mod ui;
+mod upload;
+mod upscaling;
 mod util;
+mod validation;
+mod water;
+#[cfg(feature = "openxr")]
+mod xr;
 
-pub use renderer::{Renderer, RendererError};
+pub use anim::{AnimationClip, Channel, ClipPlayer, Joint, JointTransform, Pose, Skeleton};
+pub use ash;
+pub use benchmark::{BenchmarkFrame, BenchmarkResults};
+pub use camera::{Camera, Projection};
+pub use color_grading::{ColorLut, CubeError};
+pub use culling::CullingMode;
+pub use debug_draw::LineVertex;
+pub use depth_of_field::DepthOfField;
+pub use display::DisplayMode;
+pub use egui;
+pub use environment::EnvironmentMap;
+pub use light::{AmbientLight, DirectionalLight, Light, TimeOfDay};
+pub use lighting::LightCulling;
+pub use material::{AlphaMode, Material, MaterialHandle};
+pub use mesh::{Mesh, MeshInstance, SkinVertex, Vertex};
+pub use motion_blur::MotionBlur;
+pub use obj::{ImageDecodeResult, ObjError, ObjModel};
+#[cfg(feature = "openxr")]
+pub use openxr;
+pub use pacing::FrameLimit;
+pub use plugin::{FrameImage, FrameImages, PluginContext, PluginHook, RenderPassPlugin};
+pub use present::FrameHandle;
+pub use render_target::RenderTarget;
+pub use renderer::{Renderer, RendererError, RendererOptions};
+pub use replay::{RecordedFrame, Recording, ReplayError};
+pub use retained::MeshInstanceHandle;
+pub use sampler::{SamplerSettings, TextureAddressMode, TextureFilter};
+pub use scene::{Attachment, NodeId, Scene};
+pub use shadow::{
+    CascadeFit, CascadeSplitScheme, ShadowFilter, ShadowSettings, MAX_CASCADES, MAX_POINT_SHADOWS,
+};
+pub use shared::{
+    ColorWorkflow, DeviceCapabilities, PresentModePreference, PresentQueuePreference,
+    SharedCrownError, SharedFrondError, SharedStemError, SurfaceFormatPreference, ValidationMode,
+};
+pub use sky::Sky;
+pub use stats::{FrameStats, PassStatistics, PassTimes, PipelineStatistics, RendererStats};
+pub use stereo::{Eye, StereoCamera, StereoTarget};
+pub use streaming::{AssetStreamer, Streamed};
+pub use terrain::{Heightmap, Terrain, TerrainLayer, TerrainSettings};
+pub use texture::Texture;
+pub use tonemapping::TonemappingOperator;
+pub use upload::UploadError;
+pub use upscaling::Upscaling;
+pub use validation::{
+    ValidationCallback, ValidationErrorPolicy, ValidationMessage, ValidationMessageType,
+};
+pub use water::Water;
+#[cfg(feature = "openxr")]
+pub use xr::{XrContext, XrError, XrFrame, XrSession, XrState};
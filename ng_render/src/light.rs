@@ -0,0 +1,122 @@
+use nalgebra as na;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Light {
+    Point {
+        position: mint::Point3<f32>,
+        color: mint::Vector3<f32>,
+        range: f32,
+    },
+    Spot {
+        position: mint::Point3<f32>,
+        direction: mint::Vector3<f32>,
+        color: mint::Vector3<f32>,
+        range: f32,
+        angle: f32, // radians from the axis to the edge of the cone
+    },
+}
+
+// The sun, lighting everything from one direction. Its shadows come from cascades fitted around
+// the camera's frustum, so they stay put as the camera moves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DirectionalLight {
+    pub direction: mint::Vector3<f32>, // the way the light travels, so down for a noon sun
+    pub color: mint::Vector3<f32>,
+    pub intensity: f32, // how much of it a white surface facing it reflects
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        Self {
+            direction: [-0.5, -1.0, -2.0].into(),
+            color: [1.0, 1.0, 1.0].into(),
+            intensity: 0.95,
+        }
+    }
+}
+
+// Scales the environment's image-based lighting, or the dim glow lighting scenes without one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AmbientLight {
+    pub color: mint::Vector3<f32>,
+    pub intensity: f32,
+}
+
+impl Default for AmbientLight {
+    fn default() -> Self {
+        Self {
+            color: [1.0, 1.0, 1.0].into(),
+            intensity: 1.0,
+        }
+    }
+}
+
+impl AmbientLight {
+    pub(crate) fn scale(&self) -> na::Vector3<f32> {
+        na::Vector3::from(self.color) * self.intensity
+    }
+}
+
+impl DirectionalLight {
+    pub(crate) fn unit_direction(&self) -> na::Vector3<f32> {
+        na::Vector3::from(self.direction).normalize()
+    }
+
+    pub(crate) fn radiance(&self) -> na::Vector3<f32> {
+        na::Vector3::from(self.color) * self.intensity
+    }
+}
+
+// Moves the sun along its path through the day, as seen from a latitude on flat ground with +x
+// east, +y north and +z up. The declination, how far north of the equator the sun is overhead,
+// sets the season.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeOfDay {
+    pub declination: f32, // radians
+    pub hour: f32,        // from 0 to 24, with noon at 12
+    pub hours_per_second: f32,
+    pub latitude: f32, // radians
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        Self {
+            declination: 0.2,
+            hour: 10.0,
+            hours_per_second: 0.1,
+            latitude: 0.8,
+        }
+    }
+}
+
+impl TimeOfDay {
+    pub fn advanced(&self, seconds: f32) -> Self {
+        Self {
+            hour: (self.hour + self.hours_per_second * seconds).rem_euclid(24.0),
+            ..*self
+        }
+    }
+
+    // A unit vector
+    pub fn toward_sun(&self) -> na::Vector3<f32> {
+        let hour_angle = (self.hour - 12.0) / 24.0 * std::f32::consts::TAU;
+        let (sin_latitude, cos_latitude) = self.latitude.sin_cos();
+        let (sin_declination, cos_declination) = self.declination.sin_cos();
+        na::Vector3::new(
+            -cos_declination * hour_angle.sin(),
+            cos_latitude * sin_declination - sin_latitude * cos_declination * hour_angle.cos(),
+            sin_latitude * sin_declination + cos_latitude * cos_declination * hour_angle.cos(),
+        )
+    }
+
+    // Takes the sun's color and intensity, fading it out as it sets
+    pub(crate) fn sun(&self, sun: &DirectionalLight) -> DirectionalLight {
+        let toward_sun = self.toward_sun();
+        let daylight = ((toward_sun.z + 0.05) / 0.1).clamp(0.0, 1.0);
+        DirectionalLight {
+            direction: (-toward_sun).into(),
+            intensity: daylight * sun.intensity,
+            ..*sun
+        }
+    }
+}
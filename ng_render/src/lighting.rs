@@ -3,20 +3,49 @@ use std::sync::Arc;
 
 use ash::{prelude::VkResult, version::DeviceV1_0, vk};
 use crevice::std140::{AsStd140, Std140};
-use mint;
 use nalgebra as na;
-use vk_shader_macros::include_glsl;
 
 use crate::{
+    buffer::Buffer,
+    compute::ComputePass,
+    environment::{EnvironmentMap, EnvironmentStem, GpuEnvironment},
+    frame::FRAMES_IN_FLIGHT,
     guard::{GuardableResource, Guarded},
+    light::{AmbientLight, DirectionalLight, Light},
+    shaders::include_shader,
+    shadow::{ShadowCascade, ShadowFilter, MAX_CASCADES, POINT_SHADOW_NEAR},
     shared::{SharedFrond, SharedStem},
-    util,
+    sky::Sky,
+    sync,
+    upload::{self, UploadError},
+    util::{self, Descriptor},
 };
 
+// Lights past this many are ignored
+const MAX_LIGHTS: usize = 1024;
+
+// Matches clusters.glsl
+const CLUSTER_COUNT: usize = 16 * 9 * 24;
+const MAX_CLUSTER_LIGHTS: usize = 64;
+
+const WORKGROUP_SIZE: usize = 64;
+
+// How point and spot lights find the pixels they reach
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LightCulling {
+    #[default]
+    Clustered, // the lighting pass shades each pixel with the lights assigned to its cluster
+    StencilVolumes, // each light marks the pixels inside its bounding cube, then shades only those
+}
+
 #[derive(AsStd140)]
 struct LightBuffer {
-    pub screen_to_shadow: mint::ColumnMatrix4<f32>,
+    pub screen_to_world: mint::ColumnMatrix4<f32>,
     pub sunlight_direction: mint::Vector4<f32>,
+    pub sunlight_color: mint::Vector4<f32>,
+    pub ambient_color: mint::Vector4<f32>,
+    pub cascade_count: u32,
+    pub transparent_background: u32, // nonzero to leave the background clear
 }
 
 impl LightBuffer {
@@ -29,16 +58,131 @@ impl LightBuffer {
     }
 }
 
+#[derive(AsStd140)]
+struct LightVolumeBuffer {
+    pub world_to_screen: mint::ColumnMatrix4<f32>,
+    pub screen_to_world: mint::ColumnMatrix4<f32>,
+}
+
+impl LightVolumeBuffer {
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: Self::std140_size_static() as _,
+        }
+    }
+}
+
+#[derive(AsStd140)]
+struct ClusterBuffer {
+    pub screen_to_world: mint::ColumnMatrix4<f32>,
+    pub light_count: u32,
+}
+
+#[derive(AsStd140)]
+struct CascadeData {
+    pub screen_to_shadow: mint::ColumnMatrix4<f32>,
+    pub depth_range: mint::Vector4<f32>, // x, y: screen depths of the far and near edges; z, w: depth_to_uv
+}
+
+// Follows the cascades in the same buffer
+#[derive(AsStd140)]
+struct ShadowFilterData {
+    pub mode: u32, // SHADOW_FILTER_* in shadow.glsl
+    pub size: f32, // PoissonPcf's radius, or the tangent of Pcss's sun_radius
+}
+
+impl From<ShadowFilter> for ShadowFilterData {
+    fn from(filter: ShadowFilter) -> Self {
+        let (mode, size) = match filter {
+            ShadowFilter::Unfiltered => (0, 0.0),
+            ShadowFilter::Pcf => (1, 0.0),
+            ShadowFilter::PoissonPcf { radius } => (2, radius),
+            ShadowFilter::Pcss { sun_radius } => (3, sun_radius.tan()),
+        };
+        Self { mode, size }
+    }
+}
+
+#[derive(AsStd140)]
+struct LightData {
+    pub position_range: mint::Vector4<f32>,
+    pub color: mint::Vector4<f32>,
+    pub direction: mint::Vector4<f32>,
+    pub cone: mint::Vector4<f32>, // x, y: cosines of the outer and inner edges of the cone
+    pub shadow: mint::Vector4<f32>, // x: point shadow cubemap, or negative for none; y: its near
+}
+
+impl From<&Light> for LightData {
+    fn from(light: &Light) -> Self {
+        let (position, direction, color, range, cone) = match *light {
+            // The cone test always passes since the cosine can't be below -1
+            Light::Point {
+                position,
+                color,
+                range,
+            } => (
+                position,
+                na::Vector3::z(),
+                color,
+                range,
+                [-2.0, -1.0, 0.0, 0.0],
+            ),
+            Light::Spot {
+                position,
+                direction,
+                color,
+                range,
+                angle,
+            } => {
+                let direction = na::Vector3::from(direction).normalize();
+                (
+                    position,
+                    direction,
+                    color,
+                    range,
+                    [angle.cos(), (0.8 * angle).cos(), 0.0, 0.0],
+                )
+            }
+        };
+        let position = na::Point3::from(position);
+        let color = na::Vector3::from(color);
+        Self {
+            position_range: position.coords.push(range).into(),
+            color: color.push(0.0).into(),
+            direction: direction.push(0.0).into(),
+            cone: cone.into(),
+            shadow: [-1.0, POINT_SHADOW_NEAR, 0.0, 0.0].into(),
+        }
+    }
+}
+
 pub struct LightingStem {
+    cluster_descriptor_set_layout: vk::DescriptorSetLayout,
+    cluster_pass: ComputePass,
     descriptor_set_layout: vk::DescriptorSetLayout,
+    environment: Arc<EnvironmentStem>,
     frag_shader_module: vk::ShaderModule,
+    light_volume_frag_shader_module: vk::ShaderModule,
+    light_volume_pipeline_layout: vk::PipelineLayout,
+    light_volume_pipeline: vk::Pipeline,
+    light_volume_stencil_pipeline: vk::Pipeline,
+    light_volume_vert_shader_module: vk::ShaderModule,
+    pipeline: vk::Pipeline,
     pipeline_layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    shadow_compare_sampler: vk::Sampler,
     shadow_sampler: vk::Sampler,
     shared_stem: Arc<SharedStem>,
 }
 
 impl LightingStem {
-    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
+    // The environment stem is passed in so it can outlive this one, along with what it uploaded
+    pub fn new(
+        shared_stem: Arc<SharedStem>,
+        environment: Arc<EnvironmentStem>,
+    ) -> Result<Self, UploadError> {
         unsafe {
             let device = shared_stem.device();
 
@@ -47,28 +191,134 @@ impl LightingStem {
 
             let pipeline_layout = util::create_pipeline_layout(
                 device,
-                &[*descriptor_set_layout],
+                &[*descriptor_set_layout, environment.descriptor_set_layout()],
                 &[LightBuffer::push_constant_range()],
             )?;
             shared_stem.set_name(*pipeline_layout, "lighting")?;
 
+            let light_volume_pipeline_layout = util::create_pipeline_layout(
+                device,
+                &[*descriptor_set_layout],
+                &[LightVolumeBuffer::push_constant_range()],
+            )?;
+            shared_stem.set_name(*light_volume_pipeline_layout, "light volume")?;
+
+            // Lights in, per-cluster light lists out
+            let cluster_descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[
+                    (
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::COMPUTE,
+                    ),
+                    (
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::COMPUTE,
+                    ),
+                ],
+            )?;
+            shared_stem.set_name(*cluster_descriptor_set_layout, "light clusters")?;
+
+            let cluster_pass = ComputePass::new(
+                shared_stem.clone(),
+                "light clusters",
+                &include_shader!("shaders/light-clusters.comp"),
+                &[*cluster_descriptor_set_layout],
+                ClusterBuffer::std140_size_static(),
+            )?;
+
             let frag_shader_module =
-                util::create_shader_module(device, include_glsl!("shaders/lighting.frag"))?;
+                util::create_shader_module(device, &include_shader!("shaders/lighting.frag"))?;
             shared_stem.set_name(*frag_shader_module, "lighting frag")?;
+            let light_volume_vert_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/light-volume.vert"))?;
+            shared_stem.set_name(*light_volume_vert_shader_module, "light volume vert")?;
+            let light_volume_frag_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/light-volume.frag"))?;
+            shared_stem.set_name(*light_volume_frag_shader_module, "light volume frag")?;
 
-            let shadow_sampler = Self::create_sampler(device)?;
+            let shadow_sampler = Self::create_sampler(device, false)?;
             shared_stem.set_name(*shadow_sampler, "shadow")?;
+            let shadow_compare_sampler = Self::create_sampler(device, true)?;
+            shared_stem.set_name(*shadow_compare_sampler, "shadow compare")?;
+
+            let render_pass = Self::create_render_pass(
+                device,
+                SharedFrond::DIFFUSE_FORMAT,
+                SharedFrond::NORMAL_FORMAT,
+                shared_stem.depth_stencil_format(),
+                shared_stem.depth_read_layout(),
+                SharedFrond::LIGHT_FORMAT,
+                SharedFrond::MATERIAL_FORMAT,
+                SharedFrond::EMISSIVE_FORMAT,
+            )?;
+            shared_stem.set_name(*render_pass, "lighting")?;
+
+            let pipeline = Self::create_pipeline(
+                device,
+                shared_stem.fullscreen_vert_shader_module(),
+                *frag_shader_module,
+                *pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*pipeline, "lighting")?;
+
+            let light_volume_stencil_pipeline = Self::create_light_volume_pipeline(
+                device,
+                *light_volume_vert_shader_module,
+                None,
+                *light_volume_pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*light_volume_stencil_pipeline, "light volume stencil")?;
+
+            let light_volume_pipeline = Self::create_light_volume_pipeline(
+                device,
+                *light_volume_vert_shader_module,
+                Some(*light_volume_frag_shader_module),
+                *light_volume_pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*light_volume_pipeline, "light volume")?;
 
             Ok(Self {
+                cluster_descriptor_set_layout: cluster_descriptor_set_layout.take(),
+                cluster_pass,
                 descriptor_set_layout: descriptor_set_layout.take(),
+                environment,
                 frag_shader_module: frag_shader_module.take(),
+                light_volume_frag_shader_module: light_volume_frag_shader_module.take(),
+                light_volume_pipeline_layout: light_volume_pipeline_layout.take(),
+                light_volume_pipeline: light_volume_pipeline.take(),
+                light_volume_stencil_pipeline: light_volume_stencil_pipeline.take(),
+                light_volume_vert_shader_module: light_volume_vert_shader_module.take(),
+                pipeline: pipeline.take(),
                 pipeline_layout: pipeline_layout.take(),
+                render_pass: render_pass.take(),
+                shadow_compare_sampler: shadow_compare_sampler.take(),
                 shadow_sampler: shadow_sampler.take(),
                 shared_stem,
             })
         }
     }
 
+    #[cfg(feature = "hot-reload")]
+    pub fn environment(&self) -> &Arc<EnvironmentStem> {
+        &self.environment
+    }
+
+    pub fn environment_descriptor_set_layout(&self) -> vk::DescriptorSetLayout {
+        self.environment.descriptor_set_layout()
+    }
+
+    pub fn shadow_sampler(&self) -> vk::Sampler {
+        self.shadow_sampler
+    }
+
+    pub fn shadow_compare_sampler(&self) -> vk::Sampler {
+        self.shadow_compare_sampler
+    }
+
     unsafe fn create_descriptor_set_layout(
         device: &ash::Device,
     ) -> VkResult<Guarded<(vk::DescriptorSetLayout, &ash::Device)>> {
@@ -97,6 +347,48 @@ impl LightingStem {
                 .descriptor_count(1)
                 .stage_flags(vk::ShaderStageFlags::FRAGMENT)
                 .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(4)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(5)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(6)
+                .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(7)
+                .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(8)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(9)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(10)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
         ];
         let descriptor_set_layout_create_info =
             vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
@@ -105,8 +397,10 @@ impl LightingStem {
             .guard_with(device))
     }
 
+    // Comparison samplers filter how much of the area around a depth is lit, rather than depth
     unsafe fn create_sampler(
         device: &ash::Device,
+        compare: bool,
     ) -> VkResult<Guarded<(vk::Sampler, &ash::Device)>> {
         let sampler_create_info = vk::SamplerCreateInfo::builder()
             // .flags()
@@ -119,9 +413,9 @@ impl LightingStem {
             // .mipmap_lod_bias()
             // .anisotropy_enable()
             // .max_anisotropy()
-            .compare_enable(false)
+            .compare_enable(compare)
+            // Depth is reversed, so anything nearer the sun than the reference is larger
             .compare_op(vk::CompareOp::GREATER_OR_EQUAL)
-            // .compare_op(vk::CompareOp::LESS)
             .min_lod(0.0)
             .max_lod(vk::LOD_CLAMP_NONE)
             // .border_color()
@@ -130,6 +424,340 @@ impl LightingStem {
             .create_sampler(&sampler_create_info, None)?
             .guard_with(device))
     }
+
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn create_render_pass(
+        device: &ash::Device,
+        diffuse_format: vk::Format,
+        normal_format: vk::Format,
+        depth_format: vk::Format,
+        depth_layout: vk::ImageLayout,
+        light_format: vk::Format,
+        material_format: vk::Format,
+        emissive_format: vk::Format,
+    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(diffuse_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(normal_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(depth_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .stencil_load_op(vk::AttachmentLoadOp::LOAD)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .final_layout(depth_layout)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(light_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(material_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(emissive_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .build(),
+        ];
+
+        let input_attachments = [
+            vk::AttachmentReference {
+                attachment: 0,
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+            vk::AttachmentReference {
+                attachment: 1,
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+            vk::AttachmentReference {
+                attachment: 2,
+                layout: depth_layout,
+            },
+            vk::AttachmentReference {
+                attachment: 4,
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+            vk::AttachmentReference {
+                attachment: 5,
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+        ];
+        let color_attachments = [vk::AttachmentReference {
+            attachment: 3,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_stencil_attachment = vk::AttachmentReference {
+            attachment: 2,
+            layout: depth_layout,
+        };
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .input_attachments(&input_attachments)
+            .color_attachments(&color_attachments)
+            .depth_stencil_attachment(&depth_stencil_attachment)
+            .build()];
+
+        let dependencies = [
+            sync::dependency_before(sync::COLOR_ATTACHMENT_WRITE, sync::INPUT_ATTACHMENT_READ),
+            // Light volumes mark stencil, which the geometry pass cleared
+            sync::dependency_before(
+                sync::DEPTH_ATTACHMENT_WRITE,
+                sync::DEPTH_ATTACHMENT_WRITE | sync::INPUT_ATTACHMENT_READ,
+            ),
+            // The previous frame's tonemapping may still be reading the light attachment
+            sync::dependency_before(
+                sync::INPUT_ATTACHMENT_READ.execution(),
+                sync::COLOR_ATTACHMENT_WRITE,
+            ),
+        ];
+
+        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+        Ok(device
+            .create_render_pass(&render_pass_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_pipeline(
+        device: &ash::Device,
+        triangle_vert_shader_module: vk::ShaderModule,
+        triangle_frag_shader_module: vk::ShaderModule,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(triangle_vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(triangle_frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        let vertex_input_state = Default::default();
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(false)
+            .depth_compare_op(vk::CompareOp::GREATER)
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(false)
+            //.front()
+            //.back()
+            //.min_depth_bounds()
+            //.max_depth_bounds()
+            ;
+
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            color_write_mask: vk::ColorComponentFlags::all(),
+            ..Default::default()
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            // .tesselation_state()
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+
+    // Light volumes are bounding cubes, drawn twice per light. Without a fragment shader, both
+    // faces count whether there's geometry in front of them into stencil, incrementing for back
+    // faces and decrementing for front ones, so only geometry inside the cube is left nonzero. With
+    // one, back faces are additively blended over the sunlight wherever stencil is nonzero, which
+    // they reset to zero for the next light.
+    unsafe fn create_light_volume_pipeline(
+        device: &ash::Device,
+        light_volume_vert_shader_module: vk::ShaderModule,
+        light_volume_frag_shader_module: Option<vk::ShaderModule>,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(light_volume_vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let mut shader_stages = vec![*vert_create_info];
+        if let Some(light_volume_frag_shader_module) = light_volume_frag_shader_module {
+            let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+                .module(light_volume_frag_shader_module)
+                .name(entry_point)
+                .stage(vk::ShaderStageFlags::FRAGMENT);
+            shader_stages.push(*frag_create_info);
+        }
+        let shades = light_volume_frag_shader_module.is_some();
+
+        let vertex_input_state = Default::default();
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .cull_mode(if shades {
+                vk::CullModeFlags::FRONT
+            } else {
+                vk::CullModeFlags::NONE
+            })
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        // Depth is reversed, so faces pass where they're at or behind the geometry
+        let stencil_state = |compare_op, pass_op| vk::StencilOpState {
+            fail_op: vk::StencilOp::KEEP,
+            pass_op,
+            depth_fail_op: vk::StencilOp::KEEP,
+            compare_op,
+            compare_mask: !0,
+            write_mask: !0,
+            reference: 0,
+        };
+        let (front, back) = if shades {
+            let shade = stencil_state(vk::CompareOp::NOT_EQUAL, vk::StencilOp::ZERO);
+            (shade, shade)
+        } else {
+            (
+                stencil_state(vk::CompareOp::ALWAYS, vk::StencilOp::DECREMENT_AND_WRAP),
+                stencil_state(vk::CompareOp::ALWAYS, vk::StencilOp::INCREMENT_AND_WRAP),
+            )
+        };
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(!shades)
+            .depth_write_enable(false)
+            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL)
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(true)
+            .front(front)
+            .back(back);
+
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            blend_enable: vk::TRUE,
+            src_color_blend_factor: vk::BlendFactor::ONE,
+            dst_color_blend_factor: vk::BlendFactor::ONE,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor: vk::BlendFactor::ONE,
+            dst_alpha_blend_factor: vk::BlendFactor::ONE,
+            alpha_blend_op: vk::BlendOp::ADD,
+            // Alpha is coverage, which light doesn't add to
+            color_write_mask: if shades {
+                vk::ColorComponentFlags::R | vk::ColorComponentFlags::G | vk::ColorComponentFlags::B
+            } else {
+                vk::ColorComponentFlags::empty()
+            },
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
 }
 
 impl Drop for LightingStem {
@@ -138,119 +766,178 @@ impl Drop for LightingStem {
             let device = self.shared_stem.device();
             let _ = device.device_wait_idle();
 
+            device.destroy_pipeline(self.light_volume_pipeline, None);
+            device.destroy_pipeline(self.light_volume_stencil_pipeline, None);
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_sampler(self.shadow_compare_sampler, None);
             device.destroy_sampler(self.shadow_sampler, None);
+            device.destroy_shader_module(self.light_volume_frag_shader_module, None);
+            device.destroy_shader_module(self.light_volume_vert_shader_module, None);
             device.destroy_shader_module(self.frag_shader_module, None);
+            device.destroy_pipeline_layout(self.light_volume_pipeline_layout, None);
             device.destroy_pipeline_layout(self.pipeline_layout, None);
             device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            device.destroy_descriptor_set_layout(self.cluster_descriptor_set_layout, None);
         }
     }
 }
 
 pub struct LightingFrond {
-    descriptor_pool: vk::DescriptorPool,
-    descriptor_set: vk::DescriptorSet,
+    cluster_buffers: Vec<Buffer>,                    // per frame in flight
+    cluster_descriptor_sets: Vec<vk::DescriptorSet>, // per frame in flight
+    descriptor_sets: Vec<vk::DescriptorSet>,         // per frame in flight
     framebuffer: vk::Framebuffer,
-    pipeline: vk::Pipeline,
-    render_pass: vk::RenderPass,
+    light_buffers: Vec<Buffer>,  // per frame in flight
+    shadow_buffers: Vec<Buffer>, // per frame in flight
     shared_frond: Arc<SharedFrond>,
     lighting_stem: Arc<LightingStem>,
 }
 
 impl LightingFrond {
-    pub fn new(lighting_stem: Arc<LightingStem>, shared_frond: Arc<SharedFrond>) -> VkResult<Self> {
+    pub fn new(
+        lighting_stem: Arc<LightingStem>,
+        shared_frond: Arc<SharedFrond>,
+    ) -> Result<Self, UploadError> {
         let shared_stem = &lighting_stem.shared_stem;
         shared_stem.assert_is(&shared_frond.stem());
         unsafe {
             let device = shared_frond.device();
 
-            let descriptor_pool = util::create_descriptor_pool(
-                device,
-                1,
-                &[
-                    vk::DescriptorPoolSize {
-                        ty: vk::DescriptorType::INPUT_ATTACHMENT,
-                        descriptor_count: 3,
-                    },
-                    vk::DescriptorPoolSize {
-                        ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                        descriptor_count: 1,
-                    },
-                ],
-            )?;
-            shared_stem.set_name(*descriptor_pool, "lighting")?;
+            let mut light_buffers = Vec::<Buffer>::new().guard_with(device);
+            for _ in 0..FRAMES_IN_FLIGHT {
+                let light_buffer = upload::create_buffer(
+                    shared_stem,
+                    (MAX_LIGHTS * LightData::std140_size_static()) as _,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )?;
+                shared_stem.set_name(light_buffer.buffer, "lights")?;
+                shared_stem.set_name(light_buffer.memory, "lights")?;
+                light_buffers.push(light_buffer.take());
+            }
 
-            let descriptor_set = Self::allocate_descriptor_set(
-                device,
-                *descriptor_pool,
-                lighting_stem.descriptor_set_layout,
-                shared_frond.diffuse().view,
-                shared_frond.normal().view,
-                shared_frond.depth_stencil().view,
-                shared_frond.shadow().view,
-                lighting_stem.shadow_sampler,
-            )?;
-            shared_stem.set_name(descriptor_set, "lighting")?;
+            let mut shadow_buffers = Vec::<Buffer>::new().guard_with(device);
+            for _ in 0..FRAMES_IN_FLIGHT {
+                let shadow_buffer = upload::create_buffer(
+                    shared_stem,
+                    (MAX_CASCADES * CascadeData::std140_size_static()
+                        + ShadowFilterData::std140_size_static()) as _,
+                    vk::BufferUsageFlags::UNIFORM_BUFFER,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )?;
+                shared_stem.set_name(shadow_buffer.buffer, "shadow cascades")?;
+                shared_stem.set_name(shadow_buffer.memory, "shadow cascades")?;
+                shadow_buffers.push(shadow_buffer.take());
+            }
 
-            let render_pass = Self::create_render_pass(
-                device,
-                shared_frond.diffuse().format,
-                shared_frond.normal().format,
-                shared_frond.depth_stencil().format,
-                shared_frond.light().format,
-            )?;
-            shared_stem.set_name(*render_pass, "lighting")?;
+            // Only ever written and read on the device, within a frame
+            let mut cluster_buffers = Vec::<Buffer>::new().guard_with(device);
+            for _ in 0..FRAMES_IN_FLIGHT {
+                let cluster_buffer = upload::create_buffer(
+                    shared_stem,
+                    (CLUSTER_COUNT * (1 + MAX_CLUSTER_LIGHTS) * std::mem::size_of::<u32>()) as _,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                )?;
+                shared_stem.set_name(cluster_buffer.buffer, "light clusters")?;
+                shared_stem.set_name(cluster_buffer.memory, "light clusters")?;
+                cluster_buffers.push(cluster_buffer.take());
+            }
 
-            let pipeline = Self::create_pipeline(
-                device,
-                shared_frond.stem().fullscreen_vert_shader_module(),
-                lighting_stem.frag_shader_module,
-                shared_frond.resolution(),
-                lighting_stem.pipeline_layout,
-                *render_pass,
-            )?;
-            shared_stem.set_name(*pipeline, "lighting")?;
+            let descriptor_allocator = shared_frond.descriptor_allocator();
+            let mut descriptor_sets = Vec::<vk::DescriptorSet>::with_capacity(FRAMES_IN_FLIGHT)
+                .guard_with((descriptor_allocator, device));
+            let mut cluster_descriptor_sets =
+                Vec::<vk::DescriptorSet>::with_capacity(FRAMES_IN_FLIGHT)
+                    .guard_with((descriptor_allocator, device));
+            for ((light_buffer, shadow_buffer), cluster_buffer) in light_buffers
+                .iter()
+                .zip(shadow_buffers.iter())
+                .zip(cluster_buffers.iter())
+            {
+                let descriptor_set =
+                    descriptor_allocator.allocate(device, lighting_stem.descriptor_set_layout)?;
+                Self::write_descriptor_set(
+                    device,
+                    *descriptor_set,
+                    shared_frond.diffuse().view,
+                    shared_frond.normal().view,
+                    shared_frond.depth_stencil().view,
+                    shared_stem.depth_read_layout(),
+                    shared_frond.shadow().view,
+                    lighting_stem.shadow_sampler,
+                    lighting_stem.shadow_compare_sampler,
+                    light_buffer.buffer,
+                    shadow_buffer.buffer,
+                    shared_frond.material().view,
+                    shared_frond.emissive().view,
+                    shared_frond.point_shadow().view,
+                    cluster_buffer.buffer,
+                );
+                shared_stem.set_name(*descriptor_set, "lighting")?;
+                descriptor_sets.push(descriptor_set.take());
+
+                let cluster_descriptor_set = descriptor_allocator
+                    .allocate(device, lighting_stem.cluster_descriptor_set_layout)?;
+                util::write_descriptor_set(
+                    device,
+                    *cluster_descriptor_set,
+                    &[
+                        (0, Descriptor::StorageBuffer(light_buffer.buffer)),
+                        (1, Descriptor::StorageBuffer(cluster_buffer.buffer)),
+                    ],
+                );
+                shared_stem.set_name(*cluster_descriptor_set, "light clusters")?;
+                cluster_descriptor_sets.push(cluster_descriptor_set.take());
+            }
 
             let framebuffer = util::create_framebuffer(
                 device,
-                *render_pass,
+                lighting_stem.render_pass,
                 &[
                     shared_frond.diffuse().view,
                     shared_frond.normal().view,
                     shared_frond.depth_stencil().view,
                     shared_frond.light().view,
+                    shared_frond.material().view,
+                    shared_frond.emissive().view,
                 ],
                 shared_frond.resolution(),
             )?;
             shared_stem.set_name(*framebuffer, "lighting")?;
 
             Ok(Self {
-                descriptor_pool: descriptor_pool.take(),
+                cluster_buffers: cluster_buffers.take(),
+                cluster_descriptor_sets: cluster_descriptor_sets.take(),
                 framebuffer: framebuffer.take(),
-                pipeline: pipeline.take(),
-                render_pass: render_pass.take(),
-                descriptor_set,
+                light_buffers: light_buffers.take(),
+                shadow_buffers: shadow_buffers.take(),
+                descriptor_sets: descriptor_sets.take(),
                 shared_frond,
                 lighting_stem,
             })
         }
     }
 
-    unsafe fn allocate_descriptor_set(
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn write_descriptor_set(
         device: &ash::Device,
-        descriptor_pool: vk::DescriptorPool,
-        descriptor_set_layout: vk::DescriptorSetLayout,
+        descriptor_set: vk::DescriptorSet,
         diffuse_view: vk::ImageView,
         normal_view: vk::ImageView,
         depth_view: vk::ImageView,
+        depth_layout: vk::ImageLayout,
         shadow_view: vk::ImageView,
         shadow_sampler: vk::Sampler,
-    ) -> VkResult<vk::DescriptorSet> {
-        let set_layouts = [descriptor_set_layout];
-        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(&set_layouts);
-        let descriptor_set = device.allocate_descriptor_sets(&allocate_info)?[0];
-
+        shadow_compare_sampler: vk::Sampler,
+        light_buffer: vk::Buffer,
+        shadow_buffer: vk::Buffer,
+        material_view: vk::ImageView,
+        emissive_view: vk::ImageView,
+        point_shadow_view: vk::ImageView,
+        cluster_buffer: vk::Buffer,
+    ) {
         let diffuse_info = [vk::DescriptorImageInfo {
             sampler: vk::Sampler::null(),
             image_view: diffuse_view,
@@ -264,13 +951,48 @@ impl LightingFrond {
         let depth_info = [vk::DescriptorImageInfo {
             sampler: vk::Sampler::null(),
             image_view: depth_view,
-            image_layout: vk::ImageLayout::GENERAL, // TODO: vulkan 1.2 so I can do DEPTH_READ_ONLY_STENCIL_ATTACHMENT_OPTIMAL
+            image_layout: depth_layout,
         }];
         let shadow_info = [vk::DescriptorImageInfo {
             sampler: shadow_sampler,
             image_view: shadow_view,
             image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
         }];
+        let light_info = [vk::DescriptorBufferInfo {
+            buffer: light_buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let shadow_buffer_info = [vk::DescriptorBufferInfo {
+            buffer: shadow_buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let material_info = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: material_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let emissive_info = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: emissive_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let shadow_compare_info = [vk::DescriptorImageInfo {
+            sampler: shadow_compare_sampler,
+            image_view: shadow_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let point_shadow_info = [vk::DescriptorImageInfo {
+            sampler: shadow_sampler,
+            image_view: point_shadow_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let cluster_info = [vk::DescriptorBufferInfo {
+            buffer: cluster_buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
         let descriptor_writes = [
             vk::WriteDescriptorSet::builder()
                 .dst_set(descriptor_set)
@@ -300,236 +1022,162 @@ impl LightingFrond {
                 .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
                 .image_info(&shadow_info)
                 .build(),
-        ];
-        device.update_descriptor_sets(&descriptor_writes, &[]);
-
-        Ok(descriptor_set)
-    }
-
-    unsafe fn create_render_pass(
-        device: &ash::Device,
-        diffuse_format: vk::Format,
-        normal_format: vk::Format,
-        depth_format: vk::Format,
-        light_format: vk::Format,
-    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
-        let attachments = [
-            vk::AttachmentDescription::builder()
-                .format(diffuse_format)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .load_op(vk::AttachmentLoadOp::LOAD)
-                .store_op(vk::AttachmentStoreOp::DONT_CARE)
-                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(4)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&light_info)
                 .build(),
-            vk::AttachmentDescription::builder()
-                .format(normal_format)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .load_op(vk::AttachmentLoadOp::LOAD)
-                .store_op(vk::AttachmentStoreOp::DONT_CARE)
-                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(5)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&shadow_buffer_info)
                 .build(),
-            vk::AttachmentDescription::builder()
-                .format(depth_format)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .load_op(vk::AttachmentLoadOp::LOAD)
-                .store_op(vk::AttachmentStoreOp::DONT_CARE)
-                .initial_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                .final_layout(vk::ImageLayout::GENERAL)
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(6)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                .image_info(&material_info)
                 .build(),
-            vk::AttachmentDescription::builder()
-                .format(light_format)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .load_op(vk::AttachmentLoadOp::DONT_CARE)
-                .store_op(vk::AttachmentStoreOp::STORE)
-                .initial_layout(vk::ImageLayout::UNDEFINED)
-                .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(7)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                .image_info(&emissive_info)
                 .build(),
-        ];
-
-        let input_attachments = [
-            vk::AttachmentReference {
-                attachment: 0,
-                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-            },
-            vk::AttachmentReference {
-                attachment: 1,
-                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-            },
-            vk::AttachmentReference {
-                attachment: 2,
-                layout: vk::ImageLayout::GENERAL,
-            },
-        ];
-        let color_attachments = [vk::AttachmentReference {
-            attachment: 3,
-            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-        }];
-        let depth_stencil_attachment = vk::AttachmentReference {
-            attachment: 2,
-            layout: vk::ImageLayout::GENERAL,
-        };
-        let subpasses = [vk::SubpassDescription::builder()
-            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-            .input_attachments(&input_attachments)
-            .color_attachments(&color_attachments)
-            .depth_stencil_attachment(&depth_stencil_attachment)
-            .build()];
-
-        let dependencies = [
-            vk::SubpassDependency::builder()
-                .src_subpass(vk::SUBPASS_EXTERNAL)
-                .dst_subpass(0)
-                .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-                .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
-                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
-                .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ)
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(8)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&point_shadow_info)
                 .build(),
-            vk::SubpassDependency::builder()
-                .src_subpass(vk::SUBPASS_EXTERNAL)
-                .dst_subpass(0)
-                .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
-                .dst_stage_mask(vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
-                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
-                .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ)
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(9)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&shadow_compare_info)
                 .build(),
-            vk::SubpassDependency::builder()
-                .src_subpass(vk::SUBPASS_EXTERNAL)
-                .dst_subpass(0)
-                .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
-                .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
-                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
-                .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ)
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(10)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&cluster_info)
                 .build(),
         ];
-
-        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
-            .attachments(&attachments)
-            .subpasses(&subpasses)
-            .dependencies(&dependencies);
-        Ok(device
-            .create_render_pass(&render_pass_create_info, None)?
-            .guard_with(device))
+        device.update_descriptor_sets(&descriptor_writes, &[]);
     }
 
-    unsafe fn create_pipeline(
-        device: &ash::Device,
-        triangle_vert_shader_module: vk::ShaderModule,
-        triangle_frag_shader_module: vk::ShaderModule,
-        resolution: vk::Extent2D,
-        pipeline_layout: vk::PipelineLayout,
-        render_pass: vk::RenderPass,
-    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
-        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
-        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
-            .module(triangle_vert_shader_module)
-            .name(entry_point)
-            .stage(vk::ShaderStageFlags::VERTEX);
-        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
-            .module(triangle_frag_shader_module)
-            .name(entry_point)
-            .stage(vk::ShaderStageFlags::FRAGMENT);
-        let shader_stages = [*vert_create_info, *frag_create_info];
-
-        let vertex_input_state = Default::default();
-
-        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
-
-        let viewports = [vk::Viewport {
-            x: 0.0,
-            y: 0.0,
-            width: resolution.width as _,
-            height: resolution.height as _,
-            min_depth: 0.0,
-            max_depth: 1.0,
-        }];
-        let scissors = [vk::Rect2D {
-            offset: vk::Offset2D { x: 0, y: 0 },
-            extent: resolution,
-        }];
-        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors);
-
-        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0);
-
-        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+    pub fn shadow_buffer(&self, frame_index: usize) -> vk::Buffer {
+        self.shadow_buffers[frame_index].buffer
+    }
 
-        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
-            .depth_test_enable(true)
-            .depth_write_enable(false)
-            .depth_compare_op(vk::CompareOp::GREATER)
-            .depth_bounds_test_enable(false)
-            .stencil_test_enable(false)
-            //.front()
-            //.back()
-            //.min_depth_bounds()
-            //.max_depth_bounds()
-            ;
+    pub fn prepare_environment(
+        &self,
+        environment: Option<&Arc<EnvironmentMap>>,
+        sky: Option<(&Sky, &DirectionalLight)>,
+    ) -> Result<Arc<GpuEnvironment>, UploadError> {
+        self.lighting_stem.environment.prepare(environment, sky)
+    }
 
-        let attachments = [vk::PipelineColorBlendAttachmentState {
-            color_write_mask: vk::ColorComponentFlags::all(),
-            ..Default::default()
-        }];
-        let color_blend_state =
-            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+    // Sorts lights into the clusters of a 3D grid over the view frustum, for the lighting pass to
+    // shade each pixel with only those reaching its cluster. Stencil volumes shade lights
+    // themselves, so leave every cluster empty. Must be recorded outside of a render pass.
+    pub unsafe fn assign_clusters(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        view: mint::ColumnMatrix4<f32>,
+        lights: &[Light],
+        point_shadows: &[Option<usize>], // cubemap for each light, as drawn by the shadow pass
+        light_culling: LightCulling,
+    ) -> VkResult<()> {
+        let device = self.shared_frond.device();
 
-        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
-            .stages(&shader_stages)
-            .vertex_input_state(&vertex_input_state)
-            .input_assembly_state(&input_assembly_state)
-            // .tesselation_state()
-            .viewport_state(&viewport_state)
-            .rasterization_state(&rasterization_state)
-            .multisample_state(&multisample_state)
-            .depth_stencil_state(&depth_stencil_state)
-            .color_blend_state(&color_blend_state)
-            // .dynamic_state()
-            .layout(pipeline_layout)
-            .render_pass(render_pass)
-            .subpass(0)
-            .build()];
+        let lights = &lights[..lights.len().min(MAX_LIGHTS)];
+        let light_data: Vec<u8> = lights
+            .iter()
+            .enumerate()
+            .flat_map(|(i, light)| {
+                let mut light_data = LightData::from(light);
+                if let Some(&Some(slot)) = point_shadows.get(i) {
+                    light_data.shadow.x = slot as f32;
+                }
+                light_data.as_std140().as_bytes().to_vec()
+            })
+            .collect();
+        self.light_buffers[frame_index].write(device, 0, &light_data)?;
 
-        let mut pipelines = device
-            .create_graphics_pipelines(
-                vk::PipelineCache::null(),
-                &graphics_pipeline_create_infos,
-                None,
-            )
-            .map_err(|(_, err)| err)?;
+        let view: na::Matrix4<f32> = view.into();
+        let cluster_buffer = ClusterBuffer {
+            screen_to_world: view.try_inverse().unwrap().into(),
+            light_count: match light_culling {
+                LightCulling::Clustered => lights.len() as _,
+                LightCulling::StencilVolumes => 0,
+            },
+        };
+        let workgroup_count = CLUSTER_COUNT.div_ceil(WORKGROUP_SIZE);
+        self.lighting_stem.cluster_pass.dispatch(
+            command_buffer,
+            &[self.cluster_descriptor_sets[frame_index]],
+            cluster_buffer.as_std140().as_bytes(),
+            [workgroup_count as _, 1, 1],
+        );
 
-        Ok(pipelines.pop().unwrap().guard_with(device))
+        Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub unsafe fn draw(
         &self,
         command_buffer: vk::CommandBuffer,
+        frame_index: usize,
         view: mint::ColumnMatrix4<f32>,
-        draw_shadow: impl Fn(mint::ColumnMatrix4<f32>) -> (),
-    ) {
+        lights: &[Light],
+        light_culling: LightCulling,
+        sun: &DirectionalLight,
+        ambient: &AmbientLight,
+        cascades: &[ShadowCascade],
+        environment: &GpuEnvironment,
+        sky: Option<&Sky>,
+    ) -> VkResult<()> {
         let device = self.shared_frond.device();
-
-        let sunlight_to_world: na::Matrix4<f32> = [
-            [1.0, 0.0, 0.0, 0.0],
-            [0.0, 1.0, 0.0, 0.0],
-            [0.5, 1.0, 2.0, 0.0],
-            [-0.25, -0.5, -1.0, 1.0],
-        ]
-        .into();
-        let world_to_sunlight = sunlight_to_world.try_inverse().unwrap();
-        draw_shadow(world_to_sunlight.into());
+        let descriptor_set = self.descriptor_sets[frame_index];
+        let light_count = lights.len().min(MAX_LIGHTS);
 
         let view: na::Matrix4<f32> = view.into();
-        let screen_to_shadow = world_to_sunlight * view.try_inverse().unwrap();
+        let screen_to_world = view.try_inverse().unwrap();
 
-        let sunlight_direction =
-            (sunlight_to_world * na::Vector4::new(0.0, 0.0, -1.0, 0.0)).normalize();
+        let cascade_data: Vec<u8> = cascades
+            .iter()
+            .flat_map(|cascade| {
+                let cascade_data = CascadeData {
+                    screen_to_shadow: (cascade.world_to_shadow * screen_to_world).into(),
+                    depth_range: [
+                        cascade.far_depth,
+                        cascade.near_depth,
+                        cascade.depth_to_uv.x,
+                        cascade.depth_to_uv.y,
+                    ]
+                    .into(),
+                };
+                cascade_data.as_std140().as_bytes().to_vec()
+            })
+            .collect();
+        self.shadow_buffers[frame_index].write(device, 0, &cascade_data)?;
+        let filter_data = ShadowFilterData::from(self.shared_frond.shadow_settings().filter);
+        self.shadow_buffers[frame_index].write(
+            device,
+            (MAX_CASCADES * CascadeData::std140_size_static()) as _,
+            filter_data.as_std140().as_bytes(),
+        )?;
 
         let render_area = vk::Rect2D {
             offset: Default::default(),
@@ -539,7 +1187,7 @@ impl LightingFrond {
         let clear_values = [Default::default(), Default::default()];
 
         let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-            .render_pass(self.render_pass)
+            .render_pass(self.lighting_stem.render_pass)
             .framebuffer(self.framebuffer)
             .render_area(render_area)
             .clear_values(&clear_values);
@@ -550,8 +1198,15 @@ impl LightingFrond {
         );
 
         let light_buffer = LightBuffer {
-            screen_to_shadow: screen_to_shadow.into(),
-            sunlight_direction: sunlight_direction.into(),
+            screen_to_world: screen_to_world.into(),
+            sunlight_direction: sun
+                .unit_direction()
+                .push(sky.map_or(0.0, |sky| sky.sun_radius))
+                .into(),
+            sunlight_color: sun.radiance().push(0.0).into(),
+            ambient_color: ambient.scale().push(0.0).into(),
+            cascade_count: cascades.len() as _,
+            transparent_background: self.shared_frond.transparent() as _,
         };
         device.cmd_push_constants(
             command_buffer,
@@ -564,15 +1219,16 @@ impl LightingFrond {
         device.cmd_bind_pipeline(
             command_buffer,
             vk::PipelineBindPoint::GRAPHICS,
-            self.pipeline,
+            self.lighting_stem.pipeline,
         );
+        util::set_viewport_and_scissor(device, command_buffer, self.shared_frond.resolution());
 
         device.cmd_bind_descriptor_sets(
             command_buffer,
             vk::PipelineBindPoint::GRAPHICS,
             self.lighting_stem.pipeline_layout,
             0,
-            &[self.descriptor_set],
+            &[descriptor_set, environment.descriptor_set()],
             &[],
         );
 
@@ -583,8 +1239,59 @@ impl LightingFrond {
             0, // first vertex
             0, // first instance
         );
+        self.shared_frond.count_draw(1);
+
+        if light_culling == LightCulling::StencilVolumes && light_count > 0 {
+            let shared_stem = &self.lighting_stem.shared_stem;
+            shared_stem.begin_label(command_buffer, "light volumes", [1.0, 0.7, 0.3, 1.0]);
+            let light_volume_buffer = LightVolumeBuffer {
+                world_to_screen: view.into(),
+                screen_to_world: screen_to_world.into(),
+            };
+            device.cmd_push_constants(
+                command_buffer,
+                self.lighting_stem.light_volume_pipeline_layout,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                light_volume_buffer.as_std140().as_bytes(),
+            );
+
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.lighting_stem.light_volume_pipeline_layout,
+                0,
+                &[descriptor_set],
+                &[],
+            );
+
+            // Each light's marks are cleared as it's shaded, so the next starts from zero
+            for light_index in 0..light_count {
+                for pipeline in [
+                    self.lighting_stem.light_volume_stencil_pipeline,
+                    self.lighting_stem.light_volume_pipeline,
+                ] {
+                    device.cmd_bind_pipeline(
+                        command_buffer,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        pipeline,
+                    );
+                    device.cmd_draw(
+                        command_buffer,
+                        36,               // vertices
+                        1,                // instances
+                        0,                // first vertex
+                        light_index as _, // first instance
+                    );
+                    shared_stem.count_draw(12);
+                }
+            }
+            shared_stem.end_label(command_buffer);
+        }
 
         device.cmd_end_render_pass(command_buffer);
+
+        Ok(())
     }
 }
 
@@ -595,9 +1302,18 @@ impl Drop for LightingFrond {
             let _ = device.device_wait_idle();
 
             device.destroy_framebuffer(self.framebuffer, None);
-            device.destroy_pipeline(self.pipeline, None);
-            device.destroy_render_pass(self.render_pass, None);
-            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            let descriptor_allocator = self.shared_frond.descriptor_allocator();
+            descriptor_allocator.free(device, &self.descriptor_sets);
+            descriptor_allocator.free(device, &self.cluster_descriptor_sets);
+            for light_buffer in &mut self.light_buffers {
+                light_buffer.destroy_with(device);
+            }
+            for shadow_buffer in &mut self.shadow_buffers {
+                shadow_buffer.destroy_with(device);
+            }
+            for cluster_buffer in &mut self.cluster_buffers {
+                cluster_buffer.destroy_with(device);
+            }
         }
     }
 }
@@ -0,0 +1,377 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+use nalgebra as na;
+
+use crate::{
+    bindless::{BindlessMaterial, BindlessTable},
+    buffer::Buffer,
+    owned::DeviceOwned,
+    sampler::SamplerSettings,
+    shared::SharedStem,
+    texture::{GpuTexture, Texture},
+    upload::{self, UploadError},
+    util,
+};
+
+// Blended materials skip the deferred passes, and are instead drawn back-to-front over the lit scene.
+// Masked and dithered ones stay deferred, discarding fragments instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+    #[default]
+    Opaque, // alpha is ignored
+    Mask,   // fragments with alpha below alpha_cutoff are discarded
+    Dither, // fragments are discarded with probability 1 - alpha, so overlaps needn't be sorted
+    Blend,
+}
+
+// Metallic-roughness parameters, as in glTF. Each factor is multiplied by its texture, if any.
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub albedo: mint::Vector3<f32>, // also multiplied by vertex colors
+    pub albedo_texture: Option<Arc<Texture>>, // its alpha is multiplied into alpha
+    pub alpha: f32,
+    pub alpha_mode: AlphaMode,
+    pub alpha_cutoff: f32, // for AlphaMode::Mask
+    pub metallic: f32,
+    pub roughness: f32,
+    pub metallic_roughness_texture: Option<Arc<Texture>>, // linear; roughness in G, metallic in B
+    pub emissive: mint::Vector3<f32>,
+    pub emissive_texture: Option<Arc<Texture>>,
+    pub normal_map: Option<Arc<Texture>>, // tangent-space and linear; see Texture::new_linear
+    pub sampler: SamplerSettings,         // for all of the above textures
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            albedo: [1.0; 3].into(),
+            albedo_texture: None,
+            alpha: 1.0,
+            alpha_mode: Default::default(),
+            alpha_cutoff: 0.5,
+            metallic: 0.0,
+            roughness: 0.5,
+            metallic_roughness_texture: None,
+            emissive: [0.0; 3].into(),
+            emissive_texture: None,
+            normal_map: None,
+            sampler: Default::default(),
+        }
+    }
+}
+
+// Cheap to clone and share between mesh instances. Like meshes, the material is uploaded lazily
+// the first time it's drawn, so it can't be changed afterwards; make a new handle instead.
+#[derive(Clone, Debug)]
+pub struct MaterialHandle {
+    id: u64,
+    material: Arc<Material>,
+}
+
+impl MaterialHandle {
+    pub fn new(material: Material) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            material: Arc::new(material),
+        }
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn shared(&self) -> &Arc<Material> {
+        &self.material
+    }
+
+    pub fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+#[derive(AsStd140)]
+struct MaterialBuffer {
+    pub albedo: mint::Vector4<f32>,
+    pub emissive: mint::Vector4<f32>,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub alpha_cutoff: f32, // negative for a dithered cutoff
+}
+
+// MaterialBuffer plus where its textures are in the bindless table
+#[derive(AsStd140)]
+pub struct MaterialRecord {
+    pub albedo: mint::Vector4<f32>,
+    pub emissive: mint::Vector4<f32>,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub alpha_cutoff: f32,
+    pub albedo_texture: u32,
+    pub normal_map: u32,
+    pub metallic_roughness_texture: u32,
+    pub emissive_texture: u32,
+}
+
+// In binding order, with defaults already substituted for missing textures
+pub struct MaterialTextures {
+    pub albedo: Arc<GpuTexture>,
+    pub normal_map: Arc<GpuTexture>,
+    pub metallic_roughness: Arc<GpuTexture>,
+    pub emissive: Arc<GpuTexture>,
+}
+
+// How draws find a material's textures and factors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaterialBinding {
+    DescriptorSet(vk::DescriptorSet),
+    Bindless(u32), // material index into the bindless table's records
+}
+
+pub struct GpuMaterial {
+    binding: Binding,
+    shared_stem: Arc<SharedStem>,
+    alpha_tested: bool,
+    transparent: bool,
+    _textures: MaterialTextures, // referred to by the descriptor set or bindless table
+}
+
+enum Binding {
+    DescriptorSet {
+        buffer: Buffer,
+        descriptor_pool: vk::DescriptorPool,
+        descriptor_set: vk::DescriptorSet,
+    },
+    Bindless(BindlessMaterial),
+}
+
+impl GpuMaterial {
+    // Blended materials are drawn by the transparency pass, which only binds descriptor sets, so
+    // only opaque ones go into the bindless table
+    pub fn new(
+        shared_stem: Arc<SharedStem>,
+        material: &Material,
+        textures: MaterialTextures,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+        bindless: Option<&Arc<BindlessTable>>,
+    ) -> Result<Self, UploadError> {
+        let material_buffer = MaterialBuffer {
+            albedo: na::Vector3::from(material.albedo)
+                .push(material.alpha.clamp(0.0, 1.0))
+                .into(),
+            emissive: na::Vector3::from(material.emissive).push(0.0).into(),
+            metallic: material.metallic.clamp(0.0, 1.0),
+            roughness: material.roughness.clamp(0.0, 1.0),
+            alpha_cutoff: match material.alpha_mode {
+                AlphaMode::Dither => -1.0,
+                _ => material.alpha_cutoff.clamp(0.0, 1.0),
+            },
+        };
+        let alpha_tested = matches!(material.alpha_mode, AlphaMode::Mask | AlphaMode::Dither);
+        let transparent = material.alpha_mode == AlphaMode::Blend;
+
+        let binding = match bindless {
+            Some(bindless) if !transparent => {
+                let record = MaterialRecord {
+                    albedo: material_buffer.albedo,
+                    emissive: material_buffer.emissive,
+                    metallic: material_buffer.metallic,
+                    roughness: material_buffer.roughness,
+                    alpha_cutoff: material_buffer.alpha_cutoff,
+                    albedo_texture: bindless.texture_index(&textures.albedo, sampler)?,
+                    normal_map: bindless.texture_index(&textures.normal_map, sampler)?,
+                    metallic_roughness_texture: bindless
+                        .texture_index(&textures.metallic_roughness, sampler)?,
+                    emissive_texture: bindless.texture_index(&textures.emissive, sampler)?,
+                };
+                Binding::Bindless(bindless.add_material(&record)?)
+            }
+            _ => Self::create_binding(
+                &shared_stem,
+                &material_buffer,
+                &textures,
+                descriptor_set_layout,
+                sampler,
+            )?,
+        };
+
+        Ok(Self {
+            binding,
+            shared_stem,
+            alpha_tested,
+            transparent,
+            _textures: textures,
+        })
+    }
+
+    fn create_binding(
+        shared_stem: &SharedStem,
+        material_buffer: &MaterialBuffer,
+        textures: &MaterialTextures,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+    ) -> Result<Binding, UploadError> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let buffer = upload::create_buffer(
+                shared_stem,
+                MaterialBuffer::std140_size_static() as _,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            buffer.write(device, 0, material_buffer.as_std140().as_bytes())?;
+            shared_stem.set_name(buffer.buffer, "material")?;
+            shared_stem.set_name(buffer.memory, "material")?;
+
+            let descriptor_pool = util::create_descriptor_pool(
+                device,
+                1,
+                &[
+                    vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        descriptor_count: 4,
+                    },
+                    vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::UNIFORM_BUFFER,
+                        descriptor_count: 1,
+                    },
+                ],
+            )?;
+            shared_stem.set_name(*descriptor_pool, "material")?;
+
+            let descriptor_set = Self::allocate_descriptor_set(
+                shared_stem,
+                *descriptor_pool,
+                descriptor_set_layout,
+                textures,
+                sampler,
+                buffer.buffer,
+            )?;
+            shared_stem.set_name(descriptor_set, "material")?;
+
+            Ok(Binding::DescriptorSet {
+                buffer: buffer.take(),
+                descriptor_pool: descriptor_pool.take(),
+                descriptor_set,
+            })
+        }
+    }
+
+    unsafe fn allocate_descriptor_set(
+        shared_stem: &SharedStem,
+        descriptor_pool: vk::DescriptorPool,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        textures: &MaterialTextures,
+        sampler: vk::Sampler,
+        buffer: vk::Buffer,
+    ) -> VkResult<vk::DescriptorSet> {
+        let device = shared_stem.device();
+        let set_layouts = [descriptor_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&set_layouts);
+        let descriptor_set = device.allocate_descriptor_sets(&allocate_info)?[0];
+
+        let image_infos = [
+            &textures.albedo,
+            &textures.normal_map,
+            &textures.metallic_roughness,
+            &textures.emissive,
+        ]
+        .map(|texture| vk::DescriptorImageInfo {
+            sampler,
+            image_view: texture.view().get(shared_stem),
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        });
+        let buffer_info = [vk::DescriptorBufferInfo {
+            buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let descriptor_writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(0)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&image_infos[0..1])
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(1)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&image_infos[1..2])
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(2)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&image_infos[2..3])
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(3)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&image_infos[3..4])
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(4)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&buffer_info)
+                .build(),
+        ];
+        device.update_descriptor_sets(&descriptor_writes, &[]);
+
+        Ok(descriptor_set)
+    }
+
+    pub fn binding(&self) -> DeviceOwned<'_, MaterialBinding> {
+        let binding = match &self.binding {
+            Binding::DescriptorSet { descriptor_set, .. } => {
+                MaterialBinding::DescriptorSet(*descriptor_set)
+            }
+            Binding::Bindless(material) => MaterialBinding::Bindless(material.index()),
+        };
+        DeviceOwned::new(&self.shared_stem, binding)
+    }
+
+    // Drawn by the geometry pass's alpha-tested pipeline, which gives up early depth testing
+    pub fn is_alpha_tested(&self) -> bool {
+        self.alpha_tested
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        self.transparent
+    }
+}
+
+impl Drop for GpuMaterial {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            // Bindless materials give their slot back when the field drops
+            if let Binding::DescriptorSet {
+                buffer,
+                descriptor_pool,
+                ..
+            } = &mut self.binding
+            {
+                device.destroy_descriptor_pool(*descriptor_pool, None);
+                buffer.destroy_with(device);
+            }
+        }
+    }
+}
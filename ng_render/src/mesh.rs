@@ -0,0 +1,515 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use ash::{version::DeviceV1_0, vk};
+use nalgebra as na;
+
+use crate::{
+    buffer::Buffer,
+    culling::Aabb,
+    guard::Guarded,
+    material::{GpuMaterial, MaterialHandle},
+    shared::SharedStem,
+    upload::{self, UploadError},
+    util,
+};
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Vertex {
+    pub position: mint::Point3<f32>,
+    pub normal: mint::Vector3<f32>,
+    pub color: mint::Vector3<f32>,
+    pub tex_coord: mint::Vector2<f32>,
+    pub tangent: mint::Vector4<f32>, // w is the bitangent's sign, for mirrored texture coordinates
+}
+
+// Fifteen f32s
+unsafe impl util::Pod for Vertex {}
+
+impl Vertex {
+    pub fn binding_descriptions() -> [vk::VertexInputBindingDescription; 1] {
+        [vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: std::mem::size_of::<Self>() as _,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }]
+    }
+
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 5] {
+        let vec2_size = std::mem::size_of::<mint::Vector2<f32>>() as u32;
+        let vec3_size = std::mem::size_of::<mint::Vector3<f32>>() as u32;
+        [
+            vk::VertexInputAttributeDescription {
+                location: 0,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 1,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: vec3_size,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 2,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: 2 * vec3_size,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 3,
+                binding: 0,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: 3 * vec3_size,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 4,
+                binding: 0,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: 3 * vec3_size + vec2_size,
+            },
+        ]
+    }
+
+    // Fills in tangents from positions and texture coordinates, averaging over shared vertices
+    pub fn generate_tangents(vertices: &mut [Self], indices: &[u32]) {
+        let mut tangents = vec![na::Vector3::zeros(); vertices.len()];
+        let mut bitangents = vec![na::Vector3::zeros(); vertices.len()];
+
+        for triangle in indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| &vertices[triangle[i] as usize]);
+            let position = |vertex: &Self| na::Point3::from(vertex.position);
+            let tex_coord = |vertex: &Self| na::Vector2::from(vertex.tex_coord);
+
+            let edge_1 = position(b) - position(a);
+            let edge_2 = position(c) - position(a);
+            let delta_1 = tex_coord(b) - tex_coord(a);
+            let delta_2 = tex_coord(c) - tex_coord(a);
+
+            // Degenerate texture coordinates give no usable direction
+            let determinant = delta_1.x * delta_2.y - delta_2.x * delta_1.y;
+            if determinant.abs() <= f32::EPSILON {
+                continue;
+            }
+            let tangent = (edge_1 * delta_2.y - edge_2 * delta_1.y) / determinant;
+            let bitangent = (edge_2 * delta_1.x - edge_1 * delta_2.x) / determinant;
+
+            for &index in triangle {
+                tangents[index as usize] += tangent;
+                bitangents[index as usize] += bitangent;
+            }
+        }
+
+        for ((vertex, tangent), bitangent) in vertices.iter_mut().zip(tangents).zip(bitangents) {
+            let normal = na::Vector3::from(vertex.normal);
+
+            // Gram-Schmidt, falling back to any perpendicular if the texture gave no direction
+            let tangent = (tangent - normal * normal.dot(&tangent))
+                .try_normalize(f32::EPSILON)
+                .unwrap_or_else(|| {
+                    let axis = if normal.x.abs() < 0.9 {
+                        na::Vector3::x()
+                    } else {
+                        na::Vector3::y()
+                    };
+                    normal.cross(&axis).normalize()
+                });
+            let handedness = if normal.cross(&tangent).dot(&bitangent) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            vertex.tangent = tangent.push(handedness).into();
+        }
+    }
+}
+
+// Which joints move a vertex of a skinned mesh, and how much. Weights should sum to 1.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SkinVertex {
+    pub joints: [u16; 4],
+    pub weights: mint::Vector4<f32>,
+}
+
+// Four u16s end on a four-byte boundary, where the f32s start
+unsafe impl util::Pod for SkinVertex {}
+
+impl SkinVertex {
+    // After Vertex's binding and attributes, and the geometry pass's per-instance ones. Pipelines
+    // that don't skin leave it unread.
+    pub const BINDING: u32 = 2;
+    pub const FIRST_LOCATION: u32 = 10;
+
+    pub fn binding_description() -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription {
+            binding: Self::BINDING,
+            stride: std::mem::size_of::<Self>() as _,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }
+    }
+
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 2] {
+        [
+            vk::VertexInputAttributeDescription {
+                location: Self::FIRST_LOCATION,
+                binding: Self::BINDING,
+                format: vk::Format::R16G16B16A16_UINT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                location: Self::FIRST_LOCATION + 1,
+                binding: Self::BINDING,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: std::mem::size_of::<[u16; 4]>() as _,
+            },
+        ]
+    }
+}
+
+// CPU-side triangle list. GPU buffers are created lazily by the renderer the first time a mesh
+// is drawn, and are recreated automatically if the device is lost.
+#[derive(Debug)]
+pub struct Mesh {
+    bounds: Aabb,
+    id: u64,
+    indices: Vec<u32>,
+    skin: Option<Vec<SkinVertex>>,
+    vertices: Vec<Vertex>,
+}
+
+impl Mesh {
+    pub fn new(vertices: Vec<Vertex>, indices: Vec<u32>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        assert!(!indices.is_empty(), "Mesh must have at least one triangle");
+        assert_eq!(
+            indices.len() % 3,
+            0,
+            "Mesh indices must form whole triangles"
+        );
+        assert!(
+            indices
+                .iter()
+                .all(|&index| (index as usize) < vertices.len()),
+            "Mesh index out of bounds"
+        );
+
+        let bounds = Aabb::from_points(
+            vertices
+                .iter()
+                .map(|vertex| na::Point3::from(vertex.position)),
+        )
+        .unwrap(); // indices are in bounds, so there's a vertex
+
+        Self {
+            bounds,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            indices,
+            skin: None,
+            vertices,
+        }
+    }
+
+    // One per vertex. Bounds stay those of the bind pose, which instances widen by their joints.
+    pub fn with_skin(mut self, skin: Vec<SkinVertex>) -> Self {
+        assert_eq!(
+            skin.len(),
+            self.vertices.len(),
+            "Mesh skin must have one entry per vertex"
+        );
+        self.skin = Some(skin);
+        self
+    }
+
+    pub(crate) fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Bytes of vertex, index and skin data
+    pub(crate) fn upload_size(&self) -> u64 {
+        (std::mem::size_of_val(&self.vertices[..])
+            + std::mem::size_of_val(&self.indices[..])
+            + std::mem::size_of_val(self.skin().unwrap_or_default())) as _
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+
+    pub fn skin(&self) -> Option<&[SkinVertex]> {
+        self.skin.as_deref()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MeshInstance {
+    pub mesh: Arc<Mesh>,
+    pub material: Option<MaterialHandle>, // otherwise a plain white, non-metallic one
+    pub transform: mint::ColumnMatrix4<f32>,
+    pub previous_transform: Option<mint::ColumnMatrix4<f32>>, // last frame's, if it moved
+    pub joint_matrices: Option<Arc<[mint::ColumnMatrix4<f32>]>>, // see Pose::joint_matrices
+    pub is_static: bool, // never moves or changes, so its shadows can be cached
+}
+
+pub struct GpuMesh {
+    bounds: Aabb,
+    index_buffer: Buffer,
+    index_count: u32,
+    shared_stem: Arc<SharedStem>,
+    skin_buffer: Option<Buffer>,
+    vertex_buffer: Buffer,
+}
+
+impl GpuMesh {
+    pub fn new(shared_stem: Arc<SharedStem>, mesh: &Mesh) -> Result<Self, UploadError> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let vertex_data = util::as_bytes(mesh.vertices());
+            let index_data = util::as_bytes(mesh.indices());
+            let skin_data = mesh.skin().map(util::as_bytes);
+
+            let mut staging_data = vec![vertex_data, index_data];
+            staging_data.extend(skin_data);
+            let staging_buffer = upload::create_staging_buffer(&shared_stem, &staging_data)?;
+
+            let vertex_buffer = upload::create_buffer(
+                &shared_stem,
+                vertex_data.len() as _,
+                vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )?;
+            shared_stem.set_name(vertex_buffer.buffer, "mesh vertices")?;
+
+            let index_buffer = upload::create_buffer(
+                &shared_stem,
+                index_data.len() as _,
+                vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )?;
+            shared_stem.set_name(index_buffer.buffer, "mesh indices")?;
+
+            let skin_buffer = match skin_data {
+                Some(skin_data) => {
+                    let skin_buffer = upload::create_buffer(
+                        &shared_stem,
+                        skin_data.len() as _,
+                        vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                    )?;
+                    shared_stem.set_name(skin_buffer.buffer, "mesh skin")?;
+                    Some(skin_buffer)
+                }
+                None => None,
+            };
+
+            shared_stem.submit_one_time_commands(|command_buffer| {
+                let vertex_region = vk::BufferCopy {
+                    src_offset: 0,
+                    dst_offset: 0,
+                    size: vertex_buffer.size,
+                };
+                device.cmd_copy_buffer(
+                    command_buffer,
+                    staging_buffer.buffer,
+                    vertex_buffer.buffer,
+                    &[vertex_region],
+                );
+
+                let index_region = vk::BufferCopy {
+                    src_offset: vertex_buffer.size,
+                    dst_offset: 0,
+                    size: index_buffer.size,
+                };
+                device.cmd_copy_buffer(
+                    command_buffer,
+                    staging_buffer.buffer,
+                    index_buffer.buffer,
+                    &[index_region],
+                );
+
+                let mut buffer_memory_barriers = vec![
+                    vk::BufferMemoryBarrier::builder()
+                        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                        .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .buffer(vertex_buffer.buffer)
+                        .size(vk::WHOLE_SIZE)
+                        .build(),
+                    vk::BufferMemoryBarrier::builder()
+                        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                        .dst_access_mask(vk::AccessFlags::INDEX_READ)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .buffer(index_buffer.buffer)
+                        .size(vk::WHOLE_SIZE)
+                        .build(),
+                ];
+
+                if let Some(skin_buffer) = &skin_buffer {
+                    let skin_region = vk::BufferCopy {
+                        src_offset: vertex_buffer.size + index_buffer.size,
+                        dst_offset: 0,
+                        size: skin_buffer.size,
+                    };
+                    device.cmd_copy_buffer(
+                        command_buffer,
+                        staging_buffer.buffer,
+                        skin_buffer.buffer,
+                        &[skin_region],
+                    );
+                    buffer_memory_barriers.push(
+                        vk::BufferMemoryBarrier::builder()
+                            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                            .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ)
+                            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .buffer(skin_buffer.buffer)
+                            .size(vk::WHOLE_SIZE)
+                            .build(),
+                    );
+                }
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::VERTEX_INPUT,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &buffer_memory_barriers,
+                    &[],
+                );
+            })?;
+
+            let index_buffer = index_buffer.take();
+            let skin_buffer = skin_buffer.map(Guarded::take);
+            let vertex_buffer = vertex_buffer.take();
+            drop(staging_buffer);
+
+            Ok(Self {
+                bounds: mesh.bounds(),
+                index_count: mesh.indices().len() as _,
+                index_buffer,
+                shared_stem,
+                skin_buffer,
+                vertex_buffer,
+            })
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.index_count
+    }
+
+    pub fn is_skinned(&self) -> bool {
+        self.skin_buffer.is_some()
+    }
+
+    unsafe fn bind(&self, shared_stem: &SharedStem, command_buffer: vk::CommandBuffer) {
+        self.shared_stem.debug_assert_is(shared_stem);
+        let device = self.shared_stem.device();
+
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buffer], &[0]);
+        if let Some(skin_buffer) = &self.skin_buffer {
+            device.cmd_bind_vertex_buffers(
+                command_buffer,
+                SkinVertex::BINDING,
+                &[skin_buffer.buffer],
+                &[0],
+            );
+        }
+        device.cmd_bind_index_buffer(
+            command_buffer,
+            self.index_buffer.buffer,
+            0,
+            vk::IndexType::UINT32,
+        );
+    }
+
+    pub unsafe fn draw(&self, shared_stem: &SharedStem, command_buffer: vk::CommandBuffer) {
+        self.bind(shared_stem, command_buffer);
+        self.shared_stem.device().cmd_draw_indexed(
+            command_buffer,
+            self.index_count,
+            1, // instances
+            0, // first index
+            0, // vertex offset
+            0, // first instance
+        );
+        self.shared_stem.count_draw(self.index_count as u64 / 3);
+    }
+
+    // The command at offset must be a vk::DrawIndexedIndirectCommand for this mesh
+    pub unsafe fn draw_indirect(
+        &self,
+        shared_stem: &SharedStem,
+        command_buffer: vk::CommandBuffer,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+    ) {
+        self.bind(shared_stem, command_buffer);
+        self.shared_stem.device().cmd_draw_indexed_indirect(
+            command_buffer,
+            buffer,
+            offset,
+            1, // draw count
+            0, // stride
+        );
+        self.shared_stem.count_draw(self.index_count as u64 / 3);
+    }
+}
+
+// Meshes are evicted from the upload cache mid-frame, so their buffers are only destroyed once
+// no frame in flight could still be drawing them
+impl Drop for GpuMesh {
+    fn drop(&mut self) {
+        let retired_buffers = self.shared_stem.retired_buffers();
+        retired_buffers.retire(std::mem::take(&mut self.index_buffer));
+        if let Some(skin_buffer) = self.skin_buffer.take() {
+            retired_buffers.retire(skin_buffer);
+        }
+        retired_buffers.retire(std::mem::take(&mut self.vertex_buffer));
+    }
+}
+
+#[derive(Clone)]
+pub struct GpuMeshInstance {
+    pub mesh: Arc<GpuMesh>,
+    pub material: Arc<GpuMaterial>,
+    pub transform: mint::ColumnMatrix4<f32>,
+    pub previous_transform: mint::ColumnMatrix4<f32>,
+    pub joint_matrices: Option<Arc<[mint::ColumnMatrix4<f32>]>>, // only for skinned meshes
+    pub is_static: bool,
+}
+
+impl GpuMeshInstance {
+    // Skinned vertices are blends of the joints' transforms, so they stay within the bind pose's
+    // bounds as transformed by any of the joints
+    pub fn world_bounds(&self) -> Aabb {
+        let transform = na::Matrix4::from(self.transform);
+        let bounds = self.mesh.bounds();
+        match &self.joint_matrices {
+            Some(joint_matrices) if !joint_matrices.is_empty() => joint_matrices
+                .iter()
+                .map(|&joint| bounds.transformed(&(transform * na::Matrix4::from(joint))))
+                .reduce(|a, b| a.union(&b))
+                .unwrap(),
+            _ => bounds.transformed(&transform),
+        }
+    }
+}
@@ -0,0 +1,500 @@
+use std::ffi::CStr;
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+use nalgebra as na;
+
+use crate::{
+    descriptors::GuardedDescriptorSet,
+    guard::{GuardableResource, Guarded},
+    shaders::include_shader,
+    shared::{SharedFrond, SharedStem},
+    sync, util,
+};
+
+// Smears everything along its motion since last frame, from the camera and from meshes with a
+// previous transform
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MotionBlur {
+    pub shutter: f32, // fraction of the frame's motion that's blurred; 0.5 is a 180 degree shutter
+}
+
+impl Default for MotionBlur {
+    fn default() -> Self {
+        Self { shutter: 0.5 }
+    }
+}
+
+#[derive(AsStd140)]
+struct MotionBlurBuffer {
+    pub reprojection: mint::ColumnMatrix4<f32>, // this frame's screenspace to last frame's
+    pub shutter: f32,
+}
+
+impl MotionBlurBuffer {
+    fn new(
+        motion_blur: MotionBlur,
+        world_to_screen: &na::Matrix4<f32>,
+        previous_world_to_screen: &na::Matrix4<f32>,
+    ) -> Self {
+        let screen_to_world = world_to_screen.try_inverse().unwrap();
+        Self {
+            reprojection: (previous_world_to_screen * screen_to_world).into(),
+            shutter: motion_blur.shutter.max(0.0),
+        }
+    }
+
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: Self::std140_size_static() as _,
+        }
+    }
+}
+
+pub struct MotionBlurStem {
+    nearest_sampler: vk::Sampler,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    frag_shader_module: vk::ShaderModule,
+    light_sampler: vk::Sampler,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl MotionBlurStem {
+    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
+        unsafe {
+            let device = shared_stem.device();
+
+            // Light, velocity, then depth
+            let descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                ],
+            )?;
+            shared_stem.set_name(*descriptor_set_layout, "motion blur")?;
+
+            let pipeline_layout = util::create_pipeline_layout(
+                device,
+                &[*descriptor_set_layout],
+                &[MotionBlurBuffer::push_constant_range()],
+            )?;
+            shared_stem.set_name(*pipeline_layout, "motion blur")?;
+
+            let light_sampler = Self::create_sampler(device, vk::Filter::LINEAR)?;
+            shared_stem.set_name(*light_sampler, "motion blur light")?;
+            // Blending velocities or depths across edges would smear still things with moving ones
+            let nearest_sampler = Self::create_sampler(device, vk::Filter::NEAREST)?;
+            shared_stem.set_name(*nearest_sampler, "motion blur nearest")?;
+
+            let frag_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/motion-blur.frag"))?;
+            shared_stem.set_name(*frag_shader_module, "motion blur frag")?;
+
+            let render_pass = Self::create_render_pass(device, SharedFrond::LIGHT_FORMAT)?;
+            shared_stem.set_name(*render_pass, "motion blur")?;
+
+            let pipeline = Self::create_pipeline(
+                device,
+                shared_stem.fullscreen_vert_shader_module(),
+                *frag_shader_module,
+                *pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*pipeline, "motion blur")?;
+
+            Ok(Self {
+                nearest_sampler: nearest_sampler.take(),
+                descriptor_set_layout: descriptor_set_layout.take(),
+                frag_shader_module: frag_shader_module.take(),
+                light_sampler: light_sampler.take(),
+                pipeline: pipeline.take(),
+                pipeline_layout: pipeline_layout.take(),
+                render_pass: render_pass.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    unsafe fn create_sampler(
+        device: &ash::Device,
+        filter: vk::Filter,
+    ) -> VkResult<Guarded<(vk::Sampler, &ash::Device)>> {
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(filter)
+            .min_filter(filter)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .compare_enable(false)
+            .min_lod(0.0)
+            .max_lod(0.0)
+            .unnormalized_coordinates(false);
+        Ok(device
+            .create_sampler(&sampler_create_info, None)?
+            .guard_with(device))
+    }
+
+    // Gathers from light, or its depth of field copy, into the motion blur image, which tonemapping
+    // reads instead
+    unsafe fn create_render_pass(
+        device: &ash::Device,
+        light_format: vk::Format,
+    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
+        let attachments = [vk::AttachmentDescription::builder()
+            .format(light_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .build()];
+
+        let color_attachments = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_attachments)
+            .build()];
+
+        // The previous frame's tonemapping may still be reading the output
+        let dependencies = [sync::dependency_before(
+            sync::INPUT_ATTACHMENT_READ.execution(),
+            sync::COLOR_ATTACHMENT_WRITE,
+        )];
+
+        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+        Ok(device
+            .create_render_pass(&render_pass_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_pipeline(
+        device: &ash::Device,
+        vert_shader_module: vk::ShaderModule,
+        frag_shader_module: vk::ShaderModule,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        let vertex_input_state = Default::default();
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            color_write_mask: vk::ColorComponentFlags::all(),
+            ..Default::default()
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+}
+
+impl Drop for MotionBlurStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_shader_module(self.frag_shader_module, None);
+            device.destroy_sampler(self.light_sampler, None);
+            device.destroy_sampler(self.nearest_sampler, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+pub struct MotionBlurFrond {
+    depth_of_field_descriptor_set: vk::DescriptorSet,
+    descriptor_set: vk::DescriptorSet,
+    framebuffer: vk::Framebuffer,
+    motion_blur_stem: Arc<MotionBlurStem>,
+    shared_frond: Arc<SharedFrond>,
+}
+
+impl MotionBlurFrond {
+    pub fn new(
+        motion_blur_stem: Arc<MotionBlurStem>,
+        shared_frond: Arc<SharedFrond>,
+    ) -> VkResult<Self> {
+        let shared_stem = &motion_blur_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+
+            let descriptor_set = Self::allocate_descriptor_set(
+                &motion_blur_stem,
+                &shared_frond,
+                shared_frond.light().view,
+            )?;
+            shared_stem.set_name(*descriptor_set, "motion blur")?;
+
+            let depth_of_field_descriptor_set = Self::allocate_descriptor_set(
+                &motion_blur_stem,
+                &shared_frond,
+                shared_frond.depth_of_field().view,
+            )?;
+            shared_stem.set_name(*depth_of_field_descriptor_set, "motion blur depth of field")?;
+
+            let framebuffer = util::create_framebuffer(
+                device,
+                motion_blur_stem.render_pass,
+                &[shared_frond.motion_blur().view],
+                shared_frond.resolution(),
+            )?;
+            shared_stem.set_name(*framebuffer, "motion blur")?;
+
+            Ok(Self {
+                depth_of_field_descriptor_set: depth_of_field_descriptor_set.take(),
+                descriptor_set: descriptor_set.take(),
+                framebuffer: framebuffer.take(),
+                motion_blur_stem,
+                shared_frond,
+            })
+        }
+    }
+
+    unsafe fn allocate_descriptor_set<'a>(
+        motion_blur_stem: &MotionBlurStem,
+        shared_frond: &'a SharedFrond,
+        light_view: vk::ImageView,
+    ) -> VkResult<GuardedDescriptorSet<'a>> {
+        let device = shared_frond.device();
+
+        let descriptor_set = shared_frond
+            .descriptor_allocator()
+            .allocate(device, motion_blur_stem.descriptor_set_layout)?;
+        // Depth is still attached read-only by the passes around this one
+        let image_infos = [
+            vk::DescriptorImageInfo {
+                sampler: motion_blur_stem.light_sampler,
+                image_view: light_view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+            vk::DescriptorImageInfo {
+                sampler: motion_blur_stem.nearest_sampler,
+                image_view: shared_frond.velocity().view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+            vk::DescriptorImageInfo {
+                sampler: motion_blur_stem.nearest_sampler,
+                image_view: shared_frond.depth_stencil().view,
+                image_layout: motion_blur_stem.shared_stem.depth_read_layout(),
+            },
+        ];
+        let descriptor_writes: Vec<_> = image_infos
+            .iter()
+            .zip(0..)
+            .map(|(image_info, binding)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*descriptor_set)
+                    .dst_binding(binding)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(std::slice::from_ref(image_info))
+                    .build()
+            })
+            .collect();
+        device.update_descriptor_sets(&descriptor_writes, &[]);
+
+        Ok(descriptor_set)
+    }
+
+    // Blurs depth of field's copy of light if there is one. Leaves whichever it read, and
+    // velocity, in SHADER_READ_ONLY_OPTIMAL; their passes overwrite them next frame regardless.
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        motion_blur: MotionBlur,
+        depth_of_field: bool,
+        world_to_screen: &na::Matrix4<f32>,
+        previous_world_to_screen: &na::Matrix4<f32>,
+    ) {
+        let device = self.shared_frond.device();
+        let motion_blur_stem = &self.motion_blur_stem;
+
+        // Depth of field has already moved light to SHADER_READ_ONLY_OPTIMAL if it ran
+        let (input, input_access, descriptor_set) = if depth_of_field {
+            (
+                self.shared_frond.depth_of_field(),
+                sync::COLOR_ATTACHMENT_WRITE,
+                self.depth_of_field_descriptor_set,
+            )
+        } else {
+            (
+                self.shared_frond.light(),
+                sync::COLOR_ATTACHMENT_BLEND,
+                self.descriptor_set,
+            )
+        };
+        let image_memory_barriers = [
+            util::image_barrier(
+                input.image,
+                1,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                input_access.access,
+                sync::FRAGMENT_SAMPLED.access,
+            ),
+            util::image_barrier(
+                self.shared_frond.velocity().image,
+                1,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                sync::COLOR_ATTACHMENT_WRITE.access,
+                sync::FRAGMENT_SAMPLED.access,
+            ),
+        ];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            sync::COLOR_ATTACHMENT_WRITE.stage,
+            sync::FRAGMENT_SAMPLED.stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        let resolution = self.shared_frond.resolution();
+        let render_area = vk::Rect2D {
+            offset: Default::default(),
+            extent: resolution,
+        };
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(motion_blur_stem.render_pass)
+            .framebuffer(self.framebuffer)
+            .render_area(render_area);
+        device.cmd_begin_render_pass(
+            command_buffer,
+            &render_pass_begin_info,
+            vk::SubpassContents::INLINE,
+        );
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            motion_blur_stem.pipeline,
+        );
+        util::set_viewport_and_scissor(device, command_buffer, resolution);
+
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            motion_blur_stem.pipeline_layout,
+            0,
+            &[descriptor_set],
+            &[],
+        );
+
+        let motion_blur_buffer =
+            MotionBlurBuffer::new(motion_blur, world_to_screen, previous_world_to_screen);
+        device.cmd_push_constants(
+            command_buffer,
+            motion_blur_stem.pipeline_layout,
+            vk::ShaderStageFlags::FRAGMENT,
+            0,
+            motion_blur_buffer.as_std140().as_bytes(),
+        );
+
+        device.cmd_draw(
+            command_buffer,
+            3, // vertices
+            1, // instances
+            0, // first vertex
+            0, // first instance
+        );
+        self.shared_frond.count_draw(1);
+
+        device.cmd_end_render_pass(command_buffer);
+    }
+}
+
+impl Drop for MotionBlurFrond {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_frond.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_framebuffer(self.framebuffer, None);
+            self.shared_frond.descriptor_allocator().free(
+                device,
+                &[self.descriptor_set, self.depth_of_field_descriptor_set],
+            );
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use nalgebra as na;
+use thiserror::Error;
+
+use crate::{
+    material::{AlphaMode, Material, MaterialHandle},
+    mesh::{Mesh, MeshInstance, Vertex},
+    scene::{Attachment, NodeId, Scene},
+    texture::Texture,
+};
+
+#[derive(Error, Debug)]
+pub enum ObjError {
+    #[error("Couldn't read the model")]
+    Io(#[from] io::Error),
+    #[error("Line {0} couldn't be parsed")]
+    Malformed(usize),
+    #[error("Line {0} of a material library couldn't be parsed")]
+    MalformedMaterial(usize),
+    #[error("Line {0} refers to a vertex that doesn't exist")]
+    MissingVertex(usize),
+    #[error("The model has no faces")]
+    Empty,
+}
+
+// A Wavefront OBJ model, with a mesh for each material its faces use
+#[derive(Clone, Debug)]
+pub struct ObjModel {
+    pub parts: Vec<(Arc<Mesh>, Option<MaterialHandle>)>, // no material if none was named or found
+}
+
+// The faces using one material, with a vertex for each distinct position, texture coordinate and
+// normal index triple
+#[derive(Default)]
+struct Group<'a> {
+    material: Option<&'a str>,
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    lookup: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+}
+
+// Turns an image file into its width, height and RGBA8 pixels, for material textures
+pub type ImageDecodeResult = Result<(u32, u32, Vec<u8>), Box<dyn Error>>;
+
+// The material being read, whose roughness is only settled at the end, since Ns only stands in for
+// a Pr that isn't given
+struct PendingMaterial {
+    name: String,
+    material: Material,
+    roughness: Option<f32>,
+    shininess: Option<f32>,
+}
+
+impl ObjModel {
+    // Without textures, since there's nothing here to decode images with
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ObjError> {
+        Self::load_with_textures(path, |_| {
+            Err("ng_render can't decode images itself, so needs load_with_textures".into())
+        })
+    }
+
+    // Along with the material libraries it names, looked up beside it, and the textures they name,
+    // looked up beside them and decoded with decode. Missing libraries and textures are only
+    // warned about, since models are often passed around without them.
+    pub fn load_with_textures(
+        path: impl AsRef<Path>,
+        mut decode: impl FnMut(&Path) -> ImageDecodeResult,
+    ) -> Result<Self, ObjError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut materials = HashMap::new();
+        let libraries = source
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("mtllib "))
+            .flat_map(str::split_whitespace);
+        for library in libraries {
+            let library = directory.join(library);
+            match fs::read_to_string(&library) {
+                Ok(source) => {
+                    let directory = library.parent().unwrap_or_else(|| Path::new(""));
+                    materials.extend(Self::materials_from_mtl(&source, directory, &mut decode)?)
+                }
+                Err(err) => log::warn!("Couldn't read {}: {}", library.display(), err),
+            }
+        }
+        Self::from_obj(&source, &materials)
+    }
+
+    // Polygons are fanned into triangles, and faces without normals are given smooth ones where
+    // they share positions. OBJ is y-up by convention, so models are turned z-up to match
+    // everything else, and texture coordinates flipped to start at the top.
+    pub fn from_obj(
+        source: &str,
+        materials: &HashMap<String, MaterialHandle>,
+    ) -> Result<Self, ObjError> {
+        let mut positions = Vec::new();
+        let mut colors = Vec::new();
+        let mut tex_coords = Vec::new();
+        let mut normals = Vec::new();
+        let mut groups = vec![Group::default()];
+        let mut group = 0; // of the material in use
+        for (index, line) in source.lines().enumerate() {
+            let malformed = || ObjError::Malformed(index + 1);
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            let numbers = || {
+                words
+                    .clone()
+                    .map(str::parse)
+                    .collect::<Result<Vec<f32>, _>>()
+                    .map_err(|_| malformed())
+            };
+            match keyword {
+                "v" => match numbers()?[..] {
+                    // Some exporters follow positions with vertex colors
+                    [x, y, z, r, g, b] => {
+                        positions.push(z_up([x, y, z]));
+                        colors.push([r, g, b]);
+                    }
+                    [x, y, z] | [x, y, z, _] => {
+                        positions.push(z_up([x, y, z]));
+                        colors.push([1.0; 3]);
+                    }
+                    _ => return Err(malformed()),
+                },
+                "vt" => match numbers()?[..] {
+                    [u] => tex_coords.push([u, 1.0]),
+                    [u, v] | [u, v, _] => tex_coords.push([u, 1.0 - v]),
+                    _ => return Err(malformed()),
+                },
+                "vn" => match numbers()?[..] {
+                    [x, y, z] => normals.push(z_up([x, y, z])),
+                    _ => return Err(malformed()),
+                },
+                "usemtl" => {
+                    let material = Some(words.next().ok_or_else(malformed)?);
+                    group = match groups.iter().position(|group| group.material == material) {
+                        Some(group) => group,
+                        None => {
+                            groups.push(Group {
+                                material,
+                                ..Default::default()
+                            });
+                            groups.len() - 1
+                        }
+                    };
+                }
+                "f" => {
+                    let resolve = |part: Option<&str>, count: usize| match part {
+                        None | Some("") => Ok(None),
+                        Some(value) => {
+                            let value = value.parse::<isize>().map_err(|_| malformed())?;
+                            // Negative indices count back from the latest
+                            let resolved = match value {
+                                0 => None,
+                                value if value < 0 => count.checked_sub(value.unsigned_abs()),
+                                value => Some(value as usize - 1),
+                            };
+                            match resolved {
+                                Some(resolved) if resolved < count => Ok(Some(resolved)),
+                                _ => Err(ObjError::MissingVertex(index + 1)),
+                            }
+                        }
+                    };
+                    let Group {
+                        vertices,
+                        indices,
+                        lookup,
+                        ..
+                    } = &mut groups[group];
+                    let corners = words
+                        .map(|corner| {
+                            let mut parts = corner.split('/');
+                            let position =
+                                resolve(parts.next(), positions.len())?.ok_or_else(malformed)?;
+                            let tex_coord = resolve(parts.next(), tex_coords.len())?;
+                            let normal = resolve(parts.next(), normals.len())?;
+                            let key = (position, tex_coord, normal);
+                            let vertex = *lookup.entry(key).or_insert_with(|| {
+                                vertices.push(Vertex {
+                                    position: positions[position].into(),
+                                    normal: normal
+                                        .map_or([0.0; 3], |normal| normals[normal])
+                                        .into(),
+                                    color: colors[position].into(),
+                                    tex_coord: tex_coord
+                                        .map_or([0.0; 2], |tex_coord| tex_coords[tex_coord])
+                                        .into(),
+                                    tangent: [0.0; 4].into(),
+                                });
+                                vertices.len() as u32 - 1
+                            });
+                            Ok((vertex, normal.is_none()))
+                        })
+                        .collect::<Result<Vec<_>, ObjError>>()?;
+                    if corners.len() < 3 {
+                        return Err(malformed());
+                    }
+
+                    for pair in corners[1..].windows(2) {
+                        let triangle = [corners[0], pair[0], pair[1]];
+                        indices.extend(triangle.iter().map(|&(vertex, _)| vertex));
+                        // Weighted by area when summed into each corner's
+                        let [a, b, c] = triangle.map(|(vertex, _)| {
+                            na::Point3::from(vertices[vertex as usize].position)
+                        });
+                        let face_normal = (b - a).cross(&(c - a));
+                        for &(vertex, generated) in &triangle {
+                            if generated {
+                                let normal = &mut vertices[vertex as usize].normal;
+                                *normal = (na::Vector3::from(*normal) + face_normal).into();
+                            }
+                        }
+                    }
+                }
+                // Comments, libraries, objects, groups, smoothing groups, lines, points and
+                // free-form geometry
+                _ => continue,
+            }
+        }
+
+        let parts: Vec<_> = groups
+            .into_iter()
+            .filter(|group| !group.indices.is_empty())
+            .map(|mut group| {
+                for vertex in &mut group.vertices {
+                    let normal = na::Vector3::from(vertex.normal);
+                    vertex.normal = normal
+                        .try_normalize(f32::EPSILON)
+                        .unwrap_or_else(na::Vector3::z)
+                        .into();
+                }
+                Vertex::generate_tangents(&mut group.vertices, &group.indices);
+                let mesh = Arc::new(Mesh::new(group.vertices, group.indices));
+                let material = group.material.and_then(|name| {
+                    let material = materials.get(name).cloned();
+                    if material.is_none() {
+                        log::warn!("Material {} isn't in any of the model's libraries", name);
+                    }
+                    material
+                });
+                (mesh, material)
+            })
+            .collect();
+        if parts.is_empty() {
+            return Err(ObjError::Empty);
+        }
+        Ok(Self { parts })
+    }
+
+    // The materials in a Wavefront MTL library, by name. Pr and Pm are used where given, and
+    // otherwise roughness is approximated from Ns, the Blinn-Phong exponent. Diffuse and normal
+    // maps are looked up relative to directory, and bump maps are taken to be normal maps, as
+    // most exporters write them. Other maps are skipped with a warning.
+    pub fn materials_from_mtl(
+        source: &str,
+        directory: &Path,
+        mut decode: impl FnMut(&Path) -> ImageDecodeResult,
+    ) -> Result<HashMap<String, MaterialHandle>, ObjError> {
+        // Shared between materials naming the same file, and kept even if decoding failed, so
+        // it's only tried once
+        let mut textures: HashMap<(PathBuf, bool), Option<Arc<Texture>>> = HashMap::new();
+        let mut materials = HashMap::new();
+        let mut pending: Option<PendingMaterial> = None;
+        let mut finish = |pending: Option<PendingMaterial>| {
+            if let Some(mut pending) = pending {
+                let shininess = pending.shininess.map(roughness_from_shininess);
+                if let Some(roughness) = pending.roughness.or(shininess) {
+                    pending.material.roughness = roughness;
+                }
+                if pending.material.alpha < 1.0 {
+                    pending.material.alpha_mode = AlphaMode::Blend;
+                }
+                materials.insert(pending.name, MaterialHandle::new(pending.material));
+            }
+        };
+        for (index, line) in source.lines().enumerate() {
+            let malformed = || ObjError::MalformedMaterial(index + 1);
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            if keyword == "newmtl" {
+                let name = words.next().ok_or_else(malformed)?;
+                finish(pending.replace(PendingMaterial {
+                    name: name.into(),
+                    material: Default::default(),
+                    roughness: None,
+                    shininess: None,
+                }));
+                continue;
+            }
+            if keyword.starts_with('#') {
+                continue;
+            }
+
+            let numbers = || {
+                words
+                    .clone()
+                    .map(str::parse)
+                    .collect::<Result<Vec<f32>, _>>()
+                    .map_err(|_| malformed())
+            };
+            // A single component stands for all three
+            let color = || -> Result<mint::Vector3<f32>, ObjError> {
+                match numbers()?[..] {
+                    [value] => Ok([value; 3].into()),
+                    [r, g, b] => Ok([r, g, b].into()),
+                    _ => Err(malformed()),
+                }
+            };
+            let number = || match numbers()?[..] {
+                [value] => Ok(value),
+                _ => Err(malformed()),
+            };
+            // Exporters often start with settings that aren't part of any material
+            let current = match pending.as_mut() {
+                Some(current) => current,
+                None => continue,
+            };
+            let material = &mut current.material;
+            match keyword {
+                "Kd" => material.albedo = color()?,
+                "Ke" => material.emissive = color()?,
+                "d" => material.alpha = number()?,
+                "Tr" => material.alpha = 1.0 - number()?,
+                "Ns" => current.shininess = Some(number()?),
+                "Pr" => current.roughness = Some(number()?),
+                "Pm" => material.metallic = number()?,
+                "map_Kd" | "norm" | "map_Bump" | "bump" => {
+                    // The file name comes last, after any options
+                    let file = directory.join(words.clone().last().ok_or_else(malformed)?);
+                    let linear = keyword != "map_Kd";
+                    let texture = textures
+                        .entry((file, linear))
+                        .or_insert_with_key(|(file, linear)| {
+                            let decoded = decode(file).and_then(|(width, height, pixels)| {
+                                let len = 4 * width as usize * height as usize;
+                                if len == 0 || pixels.len() != len {
+                                    return Err("Decoded image isn't RGBA8".into());
+                                }
+                                Ok((width, height, pixels))
+                            });
+                            match decoded {
+                                Ok((width, height, pixels)) if *linear => {
+                                    Some(Arc::new(Texture::new_linear(width, height, pixels)))
+                                }
+                                Ok((width, height, pixels)) => {
+                                    Some(Arc::new(Texture::new(width, height, pixels)))
+                                }
+                                Err(err) => {
+                                    log::warn!("Couldn't load {}: {}", file.display(), err);
+                                    None
+                                }
+                            }
+                        })
+                        .clone();
+                    match keyword {
+                        "map_Kd" => material.albedo_texture = texture,
+                        _ => material.normal_map = texture,
+                    }
+                }
+                keyword if keyword.starts_with("map_") => {
+                    log::warn!("Skipping {} of material {}", keyword, current.name)
+                }
+                // Ambient, specular and transmission colors, refraction and lighting models
+                _ => continue,
+            }
+        }
+        finish(pending);
+        Ok(materials)
+    }
+
+    // Attached to a new node of its own, which is returned
+    pub fn add_to(
+        &self,
+        scene: &mut Scene,
+        parent: Option<NodeId>,
+        transform: mint::ColumnMatrix4<f32>,
+    ) -> NodeId {
+        let node = scene.add_node(parent, transform);
+        for (mesh, material) in &self.parts {
+            scene.attach(
+                node,
+                Attachment::Mesh(MeshInstance {
+                    mesh: mesh.clone(),
+                    material: material.clone(),
+                    transform: na::Matrix4::identity().into(),
+                    previous_transform: None,
+                    joint_matrices: None,
+                    is_static: true,
+                }),
+            );
+        }
+        node
+    }
+}
+
+// Where the Blinn-Phong lobe is about as wide as the GGX one
+fn roughness_from_shininess(shininess: f32) -> f32 {
+    (2.0 / (shininess.max(0.0) + 2.0)).powf(0.25)
+}
+
+fn z_up([x, y, z]: [f32; 3]) -> [f32; 3] {
+    [x, -z, y]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(source: &str) -> Result<ObjModel, ObjError> {
+        ObjModel::from_obj(source, &HashMap::new())
+    }
+
+    fn materials(source: &str) -> HashMap<String, MaterialHandle> {
+        ObjModel::materials_from_mtl(source, Path::new(""), |_| Err("no images here".into()))
+            .unwrap()
+    }
+
+    fn assert_close(actual: impl Into<[f32; 3]>, expected: [f32; 3]) {
+        let actual = actual.into();
+        let close = actual
+            .iter()
+            .zip(&expected)
+            .all(|(a, e)| (a - e).abs() < 1e-5);
+        assert!(close, "{:?} isn't close to {:?}", actual, expected);
+    }
+
+    #[test]
+    fn fans_quads_into_triangles() {
+        let model = model("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3 4").unwrap();
+        assert_eq!(model.parts.len(), 1);
+        let (mesh, material) = &model.parts[0];
+        assert!(material.is_none());
+        assert_eq!(mesh.vertices().len(), 4);
+        assert_eq!(mesh.indices(), &[0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn resolves_negative_indices_from_the_latest_vertex() {
+        let model = model("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1").unwrap();
+        let mesh = &model.parts[0].0;
+        assert_eq!(mesh.indices(), &[0, 1, 2]);
+        // Turned z-up, so OBJ's y becomes z
+        assert_close(mesh.vertices()[1].position, [1.0, 0.0, 0.0]);
+        assert_close(mesh.vertices()[2].position, [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn rejects_missing_vertices() {
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\n";
+        for face in ["f 1 2 4", "f -4 1 2", "f 1/1 2 3", "f 1//1 2 3"] {
+            let result = model(&format!("{}{}", source, face));
+            assert!(
+                matches!(result, Err(ObjError::MissingVertex(4))),
+                "{} gave {:?}",
+                face,
+                result,
+            );
+        }
+    }
+
+    #[test]
+    fn reads_vertex_colors() {
+        let model = model("v 0 0 0 1 0 0\nv 1 0 0\nv 0 1 0 0 0.5 1\nf 1 2 3").unwrap();
+        let vertices = model.parts[0].0.vertices();
+        assert_close(vertices[0].color, [1.0, 0.0, 0.0]);
+        assert_close(vertices[1].color, [1.0, 1.0, 1.0]);
+        assert_close(vertices[2].color, [0.0, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn generates_smooth_normals_for_shared_positions() {
+        // Two faces folded at a right angle along the edge between the first two positions
+        let model = model("v 0 0 0\nv 0 1 0\nv 1 0 0\nv 0 0 -1\nf 1 3 2\nf 1 2 4").unwrap();
+        let vertices = model.parts[0].0.vertices();
+        let diagonal = -std::f32::consts::FRAC_1_SQRT_2;
+        assert_close(vertices[0].normal, [diagonal, diagonal, 0.0]);
+        assert_close(vertices[1].normal, [0.0, -1.0, 0.0]);
+        assert_close(vertices[2].normal, [diagonal, diagonal, 0.0]);
+        assert_close(vertices[3].normal, [-1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn keeps_given_normals() {
+        let model = model("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 1 0\nf 1//1 2//1 3//1").unwrap();
+        for vertex in model.parts[0].0.vertices() {
+            assert_close(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn prefers_explicit_roughness_to_shininess() {
+        let materials = materials(
+            "illum 2\nnewmtl shiny\nNs 1000\nnewmtl rough\nPr 0.9\nNs 1000\nnewmtl plain\nKd 1 0 0",
+        );
+        let shiny = materials["shiny"].material();
+        assert!(
+            (shiny.roughness - 0.2114).abs() < 1e-3,
+            "{}",
+            shiny.roughness
+        );
+        assert_eq!(materials["rough"].material().roughness, 0.9);
+        assert_eq!(
+            materials["plain"].material().roughness,
+            Material::default().roughness
+        );
+    }
+
+    #[test]
+    fn blends_translucent_materials() {
+        let materials =
+            materials("newmtl glass\nd 0.25\nnewmtl tinted\nTr 0.75\nnewmtl solid\nd 1");
+        for name in ["glass", "tinted"] {
+            let material = materials[name].material();
+            assert_eq!(material.alpha, 0.25, "{}", name);
+            assert_eq!(material.alpha_mode, AlphaMode::Blend, "{}", name);
+        }
+        let solid = materials["solid"].material();
+        assert_eq!(solid.alpha, 1.0);
+        assert_eq!(solid.alpha_mode, AlphaMode::Opaque);
+    }
+}
@@ -0,0 +1,25 @@
+use crate::shared::SharedStem;
+
+// A handle as handed out by whatever owns it, along with the stem it was created on. The handle
+// only comes back out by naming the stem it's about to be used with, which debug builds check, so
+// binding one renderer's resources in another panics there and then, rather than turning up as
+// validation errors, or a lost device, some time later.
+#[derive(Clone, Copy)]
+pub struct DeviceOwned<'a, T> {
+    handle: T,
+    shared_stem: &'a SharedStem,
+}
+
+impl<'a, T: Copy> DeviceOwned<'a, T> {
+    pub fn new(shared_stem: &'a SharedStem, handle: T) -> Self {
+        Self {
+            handle,
+            shared_stem,
+        }
+    }
+
+    pub fn get(self, shared_stem: &SharedStem) -> T {
+        self.shared_stem.debug_assert_is(shared_stem);
+        self.handle
+    }
+}
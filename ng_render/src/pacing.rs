@@ -0,0 +1,83 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameLimit {
+    Fps(f32),
+    FrameTime(Duration),
+}
+
+impl FrameLimit {
+    pub fn frame_time(self) -> Duration {
+        match self {
+            Self::Fps(fps) => Duration::from_secs_f32(1.0 / fps),
+            Self::FrameTime(frame_time) => frame_time,
+        }
+    }
+}
+
+// Holds each draw back until its deadline, one frame time after the previous one's
+pub(crate) struct FramePacer {
+    limit: Option<FrameLimit>,
+    deadline: Option<Instant>,
+    missed_deadlines: usize,
+}
+
+impl FramePacer {
+    // Sleeps are only trusted to wake up within this much, so the rest is spent spinning
+    const SPIN_TIME: Duration = Duration::from_millis(2);
+
+    pub fn new() -> Self {
+        Self {
+            limit: None,
+            deadline: None,
+            missed_deadlines: 0,
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: Option<FrameLimit>) {
+        match limit {
+            Some(FrameLimit::Fps(fps)) => assert!(fps > 0.0, "Frame limit must be positive"),
+            Some(FrameLimit::FrameTime(frame_time)) => {
+                assert!(frame_time > Duration::ZERO, "Frame limit must be positive")
+            }
+            None => (),
+        }
+        self.limit = limit;
+        self.deadline = None;
+        self.missed_deadlines = 0;
+    }
+
+    pub fn missed_deadlines(&self) -> usize {
+        self.missed_deadlines
+    }
+
+    pub fn wait(&mut self) {
+        let frame_time = match self.limit {
+            Some(limit) => limit.frame_time(),
+            None => return,
+        };
+
+        let now = Instant::now();
+        let deadline = match self.deadline {
+            Some(deadline) if deadline >= now => {
+                if let Some(sleep) = (deadline - now).checked_sub(Self::SPIN_TIME) {
+                    thread::sleep(sleep);
+                }
+                while Instant::now() < deadline {
+                    thread::yield_now();
+                }
+                deadline
+            }
+            // A late frame starts the schedule over, rather than rushing the next few to catch up
+            Some(_) => {
+                self.missed_deadlines += 1;
+                now
+            }
+            None => now,
+        };
+        self.deadline = Some(deadline + frame_time);
+    }
+}
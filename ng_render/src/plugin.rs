@@ -0,0 +1,103 @@
+use ash::{prelude::VkResult, vk};
+
+use crate::{
+    descriptors::DescriptorAllocator,
+    image::Image,
+    shared::{DeviceCapabilities, SharedFrond},
+};
+
+// Points in each frame where plugins get to record their own commands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PluginHook {
+    // G-buffer attachments are in COLOR_ATTACHMENT_OPTIMAL, depth in DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+    AfterGeometry,
+    // Light holds lit opaque geometry, in COLOR_ATTACHMENT_OPTIMAL. Depth is in
+    // DEPTH_READ_ONLY_STENCIL_ATTACHMENT_OPTIMAL if the device capabilities allow it, or GENERAL
+    // otherwise, and the G-buffer attachments are in SHADER_READ_ONLY_OPTIMAL, except velocity,
+    // which lighting doesn't read.
+    AfterLighting,
+    // Output is in PRESENT_SRC_KHR, or TRANSFER_SRC_OPTIMAL when headless
+    BeforePresent,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FrameImage {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+}
+
+impl FrameImage {
+    fn new(image: &Image) -> Self {
+        Self {
+            image: image.image,
+            view: image.view,
+        }
+    }
+}
+
+// Recreated whenever the renderer is, e.g. on resize, so don't hold onto these between calls
+#[derive(Clone, Copy, Debug)]
+pub struct FrameImages {
+    pub depth_stencil: FrameImage,
+    pub diffuse: FrameImage,
+    pub emissive: FrameImage,
+    pub light: FrameImage,
+    pub material: FrameImage,
+    pub normal: FrameImage,
+    pub output: vk::ImageView,
+    pub output_image: vk::Image,
+    pub output_usage: vk::ImageUsageFlags, // see RendererOptions::output_usage
+    pub velocity: FrameImage,
+}
+
+impl FrameImages {
+    pub(crate) fn new(frond: &SharedFrond, image_index: u32) -> VkResult<Self> {
+        Ok(Self {
+            depth_stencil: FrameImage::new(frond.depth_stencil()),
+            diffuse: FrameImage::new(frond.diffuse()),
+            emissive: FrameImage::new(frond.emissive()),
+            light: FrameImage::new(frond.light()),
+            material: FrameImage::new(frond.material()),
+            normal: FrameImage::new(frond.normal()),
+            output: frond.output_views()?[image_index as usize],
+            output_image: frond.output_images()?[image_index as usize],
+            output_usage: frond.stem().output_usage(),
+            velocity: FrameImage::new(frond.velocity()),
+        })
+    }
+}
+
+pub struct PluginContext<'a> {
+    pub device: &'a ash::Device,
+    pub capabilities: DeviceCapabilities, // which layouts and features the device was created with
+    pub command_buffer: vk::CommandBuffer,
+    pub(crate) descriptor_allocator: &'a DescriptorAllocator,
+    pub frame_index: usize, // which frame in flight, for plugins keeping per-frame resources
+    pub images: FrameImages,
+    pub resolution: vk::Extent2D, // of the frame images, which upscaling can make smaller
+    pub output_resolution: vk::Extent2D,
+    pub view: mint::ColumnMatrix4<f32>, // worldspace to clip space
+}
+
+impl PluginContext<'_> {
+    // For sets only bound this frame, which are freed once this frame in flight comes round again,
+    // so plugins don't need descriptor pools of their own. Layouts can have up to 512 of each kind
+    // of descriptor but dynamic buffers and inline uniforms, which fail with out of pool memory.
+    pub unsafe fn allocate_transient_descriptor_set(
+        &self,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+    ) -> VkResult<vk::DescriptorSet> {
+        self.descriptor_allocator.allocate_transient(
+            self.device,
+            self.frame_index,
+            descriptor_set_layout,
+        )
+    }
+}
+
+// Plugins record outside of any render pass, and must leave every image in the layout they found
+// it in. The renderer doesn't know what they access, so they need to add their own barriers
+// against the passes on either side.
+pub trait RenderPassPlugin {
+    fn record(&mut self, hook: PluginHook, context: &PluginContext) -> VkResult<()>;
+}
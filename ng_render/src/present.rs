@@ -0,0 +1,142 @@
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+
+use ash::vk;
+
+use crate::{renderer::RendererError, shared::SharedStem};
+
+// Lets the app find out when a frame drawn with Renderer::draw_async is done with. Cheap to
+// clone; every clone sees the frame finish.
+#[derive(Clone)]
+pub struct FrameHandle {
+    state: Arc<FrameState>,
+}
+
+struct FrameState {
+    outcome: Mutex<Option<Result<bool, vk::Result>>>, // whether it was presented optimally
+    finished: Condvar,
+}
+
+impl FrameHandle {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(FrameState {
+                outcome: Mutex::new(None),
+                finished: Condvar::new(),
+            }),
+        }
+    }
+
+    fn finish(&self, outcome: Result<bool, vk::Result>) {
+        *self.state.outcome.lock().unwrap() = Some(outcome);
+        self.state.finished.notify_all();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.outcome.lock().unwrap().is_some()
+    }
+
+    // Blocks until the frame has been presented and has finished executing
+    pub fn wait(&self) -> Result<(), RendererError> {
+        self.outcome()
+            .map(drop)
+            .map_err(RendererError::in_context(RendererError::Presentation))
+    }
+
+    // Whether the swapchain still suited the surface when the frame was presented
+    pub(crate) fn outcome(&self) -> Result<bool, vk::Result> {
+        let outcome = self.state.outcome.lock().unwrap();
+        let outcome = self
+            .state
+            .finished
+            .wait_while(outcome, |outcome| outcome.is_none())
+            .unwrap();
+        outcome.unwrap()
+    }
+}
+
+// What's left of a frame once it's been submitted
+pub(crate) struct PresentJob {
+    pub frame_index: usize,
+    pub stem: Arc<SharedStem>,
+    // And image index, and the semaphore its frame signals; None if headless
+    pub swapchain: Option<(vk::SwapchainKHR, u32, vk::Semaphore)>,
+}
+
+// Presents submitted frames and waits for them to finish on a thread of its own, in the order
+// they were submitted, so drawing needn't block on either. Dropping it finishes every frame
+// already handed over, so the swapchain can't be destroyed out from under a present.
+pub(crate) struct Presenter {
+    jobs: Option<mpsc::Sender<(PresentJob, FrameHandle)>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl Presenter {
+    pub fn new() -> Self {
+        let (jobs, received) = mpsc::channel();
+        let worker = thread::Builder::new()
+            .name("present".into())
+            .spawn(move || {
+                for (job, handle) in received {
+                    handle.finish(unsafe { Self::finish_frame(job) });
+                }
+            })
+            .unwrap();
+
+        Self {
+            jobs: Some(jobs),
+            worker: Some(worker),
+        }
+    }
+
+    pub fn present(&self, job: PresentJob) -> FrameHandle {
+        let handle = FrameHandle::new();
+        // The worker only stops once the sender's dropped
+        self.jobs
+            .as_ref()
+            .unwrap()
+            .send((job, handle.clone()))
+            .unwrap();
+        handle
+    }
+
+    unsafe fn finish_frame(job: PresentJob) -> Result<bool, vk::Result> {
+        let stem = &job.stem;
+        let frame = stem.frame(job.frame_index);
+
+        let optimal = match job.swapchain {
+            Some((swapchain, image_index, render_complete_semaphore)) => {
+                let wait_semaphores = [render_complete_semaphore];
+                let swapchains = [swapchain];
+                let image_indices = [image_index];
+                let present_info = vk::PresentInfoKHR::builder()
+                    .wait_semaphores(&wait_semaphores)
+                    .swapchains(&swapchains)
+                    .image_indices(&image_indices);
+                let _queues = stem.lock_queues();
+                // Rejected presents still wait on the semaphore, so only the frame is lost
+                match stem
+                    .swapchain_fn()
+                    .queue_present(stem.queues().present, &present_info)
+                {
+                    Ok(suboptimal) => !suboptimal,
+                    Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => false,
+                    Err(err) => return Err(err),
+                }
+            }
+            None => true,
+        };
+
+        frame.wait(stem.device())?;
+        Ok(optimal)
+    }
+}
+
+impl Drop for Presenter {
+    fn drop(&mut self) {
+        self.jobs = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
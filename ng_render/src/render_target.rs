@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use ash::vk;
+
+use crate::texture::Texture;
+
+// Somewhere besides the window to render to, like a mirror or portal. Its texture can go in any
+// material, and shows whatever was last drawn to the target, or black before that.
+#[derive(Clone, Debug)]
+pub struct RenderTarget {
+    texture: Arc<Texture>,
+}
+
+impl RenderTarget {
+    pub fn new(extent: vk::Extent2D) -> Self {
+        Self {
+            texture: Arc::new(Texture::new_render_target(extent.width, extent.height)),
+        }
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        vk::Extent2D {
+            width: self.texture.width(),
+            height: self.texture.height(),
+        }
+    }
+
+    pub fn texture(&self) -> &Arc<Texture> {
+        &self.texture
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.texture.id()
+    }
+}
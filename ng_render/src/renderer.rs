@@ -1,5 +1,5 @@
-use std::f32::consts::TAU;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use ash::{prelude::VkResult, version::DeviceV1_0, vk};
 use nalgebra as na;
@@ -7,31 +7,212 @@ use thiserror::Error;
 use winit::window::Window;
 
 use crate::{
-    geometry::{GeometryFrond, GeometryStem},
-    lighting::{LightingFrond, LightingStem},
+    benchmark::{BenchmarkFrame, BenchmarkResults},
+    buffer,
+    camera::Camera,
+    color_grading::{ColorGrade, ColorGrading, ColorGradingStem, ColorLut},
+    culling::{CullingFrond, CullingMode, CullingStem, Frustum},
+    debug_draw::{DebugDrawFrond, DebugDrawStem, LineVertex},
+    depth_of_field::{DepthOfField, DepthOfFieldFrond, DepthOfFieldStem},
+    display::DisplayMode,
+    environment::{EnvironmentMap, EnvironmentStem, GpuEnvironment},
+    frame::FRAMES_IN_FLIGHT,
+    geometry::{self, GeometryFrond, GeometryStem},
+    graph::{PassDeclaration, RenderGraph, RenderGraphError, Resource},
+    image, jobs,
+    light::{AmbientLight, DirectionalLight, Light, TimeOfDay},
+    lighting::{LightCulling, LightingFrond, LightingStem},
+    mesh::{GpuMeshInstance, MeshInstance},
+    motion_blur::{MotionBlur, MotionBlurFrond, MotionBlurStem},
+    pacing::{FrameLimit, FramePacer},
+    plugin::{FrameImages, PluginContext, PluginHook, RenderPassPlugin},
+    present::{FrameHandle, PresentJob, Presenter},
+    render_target::RenderTarget,
+    replay::{RecordedFrame, Recording},
+    retained::{MeshInstanceHandle, RetainedMeshes},
+    shadow::{
+        PointShadows, ShadowFrond, ShadowSettings, ShadowStem, MAX_CASCADES, MAX_POINT_SHADOWS,
+    },
     shared::{
+        ColorWorkflow, DeviceRequirements, PresentModePreference, PresentQueuePreference,
         SharedCrown, SharedCrownError, SharedFrond, SharedFrondError, SharedFrondSwapchain,
-        SharedStem, SharedStemError,
+        SharedStem, SharedStemError, SurfaceFormatPreference, ValidationMode,
     },
-    tonemapping::{TonemappingFrond, TonemappingStem},
-    util,
+    sky::Sky,
+    stats::{FrameStats, PassTimes, PipelineStatistics, RendererStats, Timestamp},
+    stereo::{Eye, StereoCamera, StereoTarget},
+    sync,
+    texture::{GpuTexture, Texture},
+    tonemapping::{TonemappingFrond, TonemappingInput, TonemappingOperator, TonemappingStem},
+    transparency::{TransparencyFrond, TransparencyStem},
+    ui::{UiFrame, UiFrond, UiStem},
+    upload::{self, UploadBudget, UploadCache, UploadError},
+    upscaling::{Upscaling, UpscalingFrond, UpscalingStem},
+    validation::{ValidationCallback, ValidationErrorPolicy, ValidationMessage},
+    water::{Water, WaterFrond, WaterStem},
 };
 
+#[cfg(feature = "renderdoc")]
+use crate::capture::FrameCapture;
+#[cfg(feature = "hot-reload")]
+use crate::shaders::ShaderWatcher;
+
 #[derive(Error, Debug)]
 pub enum RendererError {
-    #[error("Vulkan error occurred")]
-    VkError(#[from] vk::Result), // TODO: split into contexts
+    #[error("Graphics device was lost")]
+    DeviceLost,
+    #[error("Out of device memory")]
+    OutOfDeviceMemory,
+    #[error("Out of host memory")]
+    OutOfHostMemory,
     #[error("Unable to create renderer crown")]
-    CrownCreationError(#[from] SharedCrownError),
+    CrownCreationError(#[source] SharedCrownError),
     #[error("Unable to create renderer stem")]
-    StemCreationError(#[from] SharedStemError),
+    StemCreationError(#[source] SharedStemError),
     #[error("Unable to create renderer frond")]
-    FrondCreationError(#[from] SharedFrondError),
+    FrondCreationError(#[source] SharedFrondError),
+    #[error("Unable to create {pass} pipeline")]
+    PipelineCreation {
+        pass: &'static str,
+        source: UploadError,
+    },
+    #[error("Unable to build render graph")]
+    RenderGraphError(#[from] RenderGraphError),
+    #[error("Unable to upload mesh or texture")]
+    UploadError(#[source] UploadError),
+    #[error("Unable to acquire swapchain image")]
+    Acquisition(#[source] vk::Result),
+    #[error("Unable to record frame")]
+    Recording(#[source] vk::Result),
+    #[error("Unable to submit frame")]
+    Submission(#[source] vk::Result),
+    #[error("Unable to present frame")]
+    Presentation(#[source] vk::Result),
+    #[error("Pixels can only be read back from a headless renderer")]
+    NotHeadless,
+    #[error("No frame has been drawn since the renderer was last rebuilt")]
+    NothingDrawn,
+}
+
+impl RendererError {
+    // Losing the device or running out of memory can happen anywhere, and are what apps recover
+    // from, so they're reported as such instead of under whatever was being done at the time
+    fn hoist(result: Option<vk::Result>) -> Option<Self> {
+        match result? {
+            vk::Result::ERROR_DEVICE_LOST => Some(Self::DeviceLost),
+            vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => Some(Self::OutOfDeviceMemory),
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY => Some(Self::OutOfHostMemory),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn in_context(context: fn(vk::Result) -> Self) -> impl Fn(vk::Result) -> Self {
+        move |result| Self::hoist(Some(result)).unwrap_or_else(|| context(result))
+    }
+
+    fn pipeline_creation<E: Into<UploadError>>(pass: &'static str) -> impl Fn(E) -> Self {
+        move |err| {
+            let source = err.into();
+            Self::hoist(source.vk_result()).unwrap_or(Self::PipelineCreation { pass, source })
+        }
+    }
+}
+
+impl From<SharedCrownError> for RendererError {
+    fn from(err: SharedCrownError) -> Self {
+        Self::hoist(err.vk_result()).unwrap_or(Self::CrownCreationError(err))
+    }
+}
+
+impl From<SharedStemError> for RendererError {
+    fn from(err: SharedStemError) -> Self {
+        Self::hoist(err.vk_result()).unwrap_or(Self::StemCreationError(err))
+    }
+}
+
+impl From<SharedFrondError> for RendererError {
+    fn from(err: SharedFrondError) -> Self {
+        Self::hoist(err.vk_result()).unwrap_or(Self::FrondCreationError(err))
+    }
+}
+
+impl From<UploadError> for RendererError {
+    fn from(err: UploadError) -> Self {
+        Self::hoist(err.vk_result()).unwrap_or(Self::UploadError(err))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RendererOptions {
+    pub validation: ValidationMode,
+    pub validation_error_policy: ValidationErrorPolicy,
+    pub surface_format: SurfaceFormatPreference,
+    pub color_workflow: ColorWorkflow,
+    pub present_queue: PresentQueuePreference,
+    // Wanted of the output on top of COLOR_ATTACHMENT, like TRANSFER_SRC for screenshots or
+    // STORAGE for compute post-processing. Whatever the surface allows is in FrameImages.
+    pub output_usage: vk::ImageUsageFlags,
+    // Lets the window show through wherever nothing's drawn, if the compositor supports it. The
+    // window has to be created transparent too.
+    pub transparent: bool,
 }
 
 pub struct Renderer {
+    ambient_light: AmbientLight,
+    color_grading: ColorGrading,
     crown: RendererCrown,
+    culling_mode: CullingMode,
+    debug_lines: Vec<LineVertex>,
+    depth_of_field: Option<DepthOfField>,
+    draw_counts: (u64, u64), // draw calls and triangles recorded over the last draw
+    environment: Option<Arc<EnvironmentMap>>,
+    epoch: Instant, // what animations, like water's ripples, are timed from
+    fixed_clock: Option<FixedClock>, // in deterministic mode
+    #[cfg(feature = "renderdoc")]
+    frame_capture: FrameCapture,
+    frame_index: usize,
+    frame_pacer: FramePacer,
+    frame_stats: FrameStats,
+    idle: bool, // the surface had no area last time, so nothing's rebuilt until it does
+    last_draw: Option<Instant>,
+    light_culling: LightCulling,
+    lights: Vec<Light>,
+    motion_blur: Option<MotionBlur>,
+    pipeline_statistics: bool,
+    plugins: Vec<Box<dyn RenderPassPlugin>>,
+    present_mode: PresentModePreference,
+    previous_camera: Option<Camera>, // the last drawn frame's, which velocities are measured from
+    recording: Option<Recording>,
+    resized: Option<(vk::Extent2D, Instant)>, // the surface's last seen resolution, and since when
+    retained_meshes: RetainedMeshes,
+    #[cfg(feature = "hot-reload")]
+    shader_watcher: ShaderWatcher,
+    shadow_settings: ShadowSettings,
+    sky: Option<Sky>,
     stem_and_frond: Option<RendererStemAndFrond>,
+    sun: DirectionalLight,
+    target_draws: Vec<TargetDraw>,
+    texture_budget: Option<u64>,               // bytes
+    time_of_day: Option<(TimeOfDay, Instant)>, // and when it was set
+    tonemapping: TonemappingOperator,
+    ui: Option<Arc<UiFrame>>,
+    upload_budget: Option<u64>, // bytes per frame
+    upscaling: Option<Upscaling>,
+    water: Vec<Water>,
+}
+
+struct TargetDraw {
+    camera: Camera,
+    meshes: Vec<MeshInstance>,
+    target: RenderTarget,
+}
+
+// Stands in for the clock in deterministic mode, moving on by a fixed step each draw
+#[derive(Clone, Copy)]
+struct FixedClock {
+    draws: u32, // since it started
+    started: Instant,
+    step: Duration,
 }
 
 struct RendererStemAndFrond {
@@ -40,60 +221,915 @@ struct RendererStemAndFrond {
 }
 
 impl Renderer {
-    pub fn new(window: Arc<Window>) -> Result<Self, RendererError> {
-        Ok(Self {
-            crown: RendererCrown::new(window)?,
+    // Some window managers keep resizing while we rebuild, so eventually give up on the frame
+    const MAX_SWAPCHAIN_REBUILDS: usize = 3;
+    // Outlasts the gaps between the resizes of a window edge being dragged
+    const RESIZE_DEBOUNCE: Duration = Duration::from_millis(100);
+
+    pub fn new(window: Arc<Window>, options: RendererOptions) -> Result<Self, RendererError> {
+        Self::with_requirements(window, options, Default::default())
+    }
+
+    // For sharing the device with something that has its own needs of it, like an OpenXR runtime
+    pub(crate) fn with_requirements(
+        window: Arc<Window>,
+        options: RendererOptions,
+        requirements: DeviceRequirements,
+    ) -> Result<Self, RendererError> {
+        let crown = RendererCrown::new(window, options, requirements)?;
+        Ok(Self::with_crown(crown))
+    }
+
+    pub fn new_headless(
+        width: u32,
+        height: u32,
+        options: RendererOptions,
+    ) -> Result<Self, RendererError> {
+        let crown = RendererCrown::new_headless(vk::Extent2D { width, height }, options)?;
+        Ok(Self::with_crown(crown))
+    }
+
+    // Nothing device-level is created until the first draw
+    fn with_crown(crown: RendererCrown) -> Self {
+        Self {
+            ambient_light: Default::default(),
+            color_grading: Default::default(),
+            crown,
+            culling_mode: Default::default(),
+            debug_lines: Vec::new(),
+            depth_of_field: None,
+            draw_counts: (0, 0),
+            environment: None,
+            epoch: Instant::now(),
+            fixed_clock: None,
+            #[cfg(feature = "renderdoc")]
+            frame_capture: FrameCapture::new(),
+            frame_index: 0,
+            frame_pacer: FramePacer::new(),
+            frame_stats: Default::default(),
+            idle: false,
+            last_draw: None,
+            light_culling: Default::default(),
+            lights: Vec::new(),
+            motion_blur: None,
+            pipeline_statistics: false,
+            plugins: Vec::new(),
+            present_mode: Default::default(),
+            previous_camera: None,
+            recording: None,
+            resized: None,
+            retained_meshes: Default::default(),
+            #[cfg(feature = "hot-reload")]
+            shader_watcher: ShaderWatcher::new(),
+            shadow_settings: Default::default(),
+            sky: None,
             stem_and_frond: None,
-        })
+            sun: Default::default(),
+            target_draws: Vec::new(),
+            texture_budget: None,
+            time_of_day: None,
+            tonemapping: Default::default(),
+            ui: None,
+            upload_budget: None,
+            upscaling: None,
+            water: Vec::new(),
+        }
     }
 
-    fn rebuild(&mut self) -> Result<&mut RendererFrond, RendererError> {
+    fn rebuild(&mut self) -> Result<(), RendererError> {
+        let resize_settled = self.resize_settled();
         let (stem, frond) = match self.stem_and_frond.take() {
             Some(RendererStemAndFrond { stem, frond }) => (stem, frond),
             None => {
                 let stem = RendererStem::new(&self.crown)?;
-                let frond = Ok(RendererFrond::new(&stem)?);
+                let frond = Ok(RendererFrond::new(
+                    &stem,
+                    self.shadow_settings,
+                    self.present_mode,
+                    self.render_scale(),
+                )?);
                 (stem, frond)
             }
         };
 
+        let shadow_settings = self.shadow_settings;
+        let present_mode = self.present_mode;
+        let render_scale = self.render_scale();
         let frond = match frond {
-            Ok(frond) if frond.shared.needs_resizing() => Err(frond.take_swapchain()),
+            Ok(frond)
+                if frond.stale
+                    || (frond.shared.needs_resizing() || frond.suboptimal) && resize_settled
+                    || frond.shared.shadow_settings() != shadow_settings
+                    || frond.shared.present_mode() != present_mode
+                    || frond.shared.render_scale() != render_scale =>
+            {
+                Err(frond.take_swapchain())
+            }
             x => x,
         };
 
-        let (frond, err) =
-            match frond.or_else(|swapchain| RendererFrond::resurrect(&stem, swapchain)) {
-                Ok(frond) => (Ok(frond), Ok(())),
-                Err((swapchain, err)) => (Err(swapchain), Err(err)),
-            };
-        let stem_and_frond = self
-            .stem_and_frond
-            .insert(RendererStemAndFrond { stem, frond });
-        err?;
-        match &mut stem_and_frond.frond {
-            Ok(frond) => Ok(frond),
-            _ => unreachable!(),
+        let (frond, err) = match frond.or_else(|swapchain| {
+            RendererFrond::resurrect(
+                &stem,
+                swapchain,
+                shadow_settings,
+                present_mode,
+                render_scale,
+            )
+        }) {
+            Ok(frond) => (Ok(frond), Ok(())),
+            Err((swapchain, err)) => (Err(swapchain), Err(err)),
+        };
+        self.stem_and_frond = Some(RendererStemAndFrond { stem, frond });
+        err
+    }
+
+    // Rebuilding every draw while a window edge is dragged hitches, so a swapchain that can still
+    // be presented to is kept until the surface's size holds still. Deterministic draws can't
+    // depend on how long that took.
+    fn resize_settled(&mut self) -> bool {
+        if self.fixed_clock.is_some() {
+            return true;
+        }
+        let resolution = self.crown.shared.resolution();
+        let now = Instant::now();
+        match self.resized {
+            Some((last, since)) if last == resolution => {
+                now.saturating_duration_since(since) >= Self::RESIZE_DEBOUNCE
+            }
+            _ => {
+                self.resized = Some((resolution, now));
+                false
+            }
         }
     }
 
+    fn render_scale(&self) -> Option<f32> {
+        self.upscaling.map(|upscaling| upscaling.render_scale)
+    }
+
+    // Whether draws do nothing, because the window is minimized or suspended, so the app can stop
+    // drawing until it's resized or resumed
+    pub fn is_idle(&self) -> bool {
+        self.idle || self.crown.shared.is_suspended()
+    }
+
     fn lose_device(&mut self) {
         self.stem_and_frond = None;
     }
 
+    // For platforms that destroy the window's surface while the app is in the background, like
+    // Android. The device and everything on it stays, so resuming only rebuilds the swapchain.
+    pub fn suspend(&mut self) {
+        if let Some(RendererStemAndFrond { stem, frond }) = self.stem_and_frond.take() {
+            let swapchain = match frond {
+                Ok(frond) => frond.take_swapchain(),
+                Err(swapchain) => swapchain,
+            };
+            self.stem_and_frond = Some(RendererStemAndFrond {
+                stem,
+                frond: Err(swapchain.discard()),
+            });
+        }
+        unsafe { self.crown.shared.suspend() };
+    }
+
+    // Does nothing unless suspended, since some platforms also resume apps as they start
+    pub fn resume(&mut self, window: Arc<Window>) -> Result<(), RendererError> {
+        if !self.crown.shared.is_suspended() {
+            return Ok(());
+        }
+        unsafe {
+            self.crown.shared.resume(window)?;
+            if let Some(RendererStemAndFrond { stem, .. }) = &self.stem_and_frond {
+                let supported = stem
+                    .shared
+                    .supports_surface()
+                    .map_err(SharedStemError::DeviceQuery)?;
+                if !supported {
+                    log::info!("Resumed surface doesn't suit the device, rebuilding renderer");
+                    self.stem_and_frond = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_lights(&mut self, lights: &[Light]) {
+        self.lights = lights.to_vec();
+    }
+
+    pub fn set_sun(&mut self, sun: DirectionalLight) {
+        assert!(na::Vector3::from(sun.direction).norm() > 0.0);
+        self.sun = sun;
+    }
+
+    // Moves the sun through the day from now on, in place of set_sun's direction, and fades it
+    // out at night. Its shadows, and any sky, follow it every frame.
+    pub fn set_time_of_day(&mut self, time_of_day: Option<TimeOfDay>) {
+        if let Some(time_of_day) = &time_of_day {
+            assert!((0.0..24.0).contains(&time_of_day.hour));
+        }
+        let now = self.now();
+        self.time_of_day = time_of_day.map(|time_of_day| (time_of_day, now));
+    }
+
+    // With the hour moved on since it was set
+    pub fn time_of_day(&self) -> Option<TimeOfDay> {
+        self.time_of_day.map(|(time_of_day, set)| {
+            let elapsed = self.now().saturating_duration_since(set);
+            time_of_day.advanced(elapsed.as_secs_f32())
+        })
+    }
+
+    // Draws a sky lit by the sun as both the background and the ambient light, in place of the
+    // environment. It's redrawn whenever either changes, so the time of day can be animated.
+    pub fn set_sky(&mut self, sky: Option<Sky>) {
+        if let Some(sky) = &sky {
+            assert!(sky.turbidity >= 1.0);
+            assert!(sky.sun_radius >= 0.0);
+        }
+        self.sky = sky;
+    }
+
+    pub fn set_ambient_light(&mut self, ambient_light: AmbientLight) {
+        self.ambient_light = ambient_light;
+    }
+
+    // Lights everything with the environment's ambient light, in place of the default dim glow
+    pub fn set_environment(&mut self, environment: EnvironmentMap) {
+        self.environment = Some(Arc::new(environment));
+    }
+
+    // Takes effect on the next draw, which rebuilds the shadow maps if anything changed
+    pub fn set_shadow_settings(&mut self, shadow_settings: ShadowSettings) {
+        assert!(shadow_settings.resolution > 0);
+        assert!((1..=MAX_CASCADES).contains(&shadow_settings.cascade_count));
+        assert!(shadow_settings.distance > 0.0);
+        assert!(shadow_settings.point_resolution > 0);
+        assert!(shadow_settings.point_count <= MAX_POINT_SHADOWS);
+        self.shadow_settings = shadow_settings;
+    }
+
+    pub fn shadow_settings(&self) -> ShadowSettings {
+        self.shadow_settings
+    }
+
+    // Takes effect on the next draw, which rebuilds the swapchain if the mode changed
+    pub fn set_present_mode(&mut self, present_mode: PresentModePreference) {
+        self.present_mode = present_mode;
+    }
+
+    // Goes fullscreen or back through winit, and rebuilds the swapchain on the next draw. Does
+    // nothing when headless.
+    pub fn set_display_mode(&mut self, display_mode: DisplayMode) {
+        if self.crown.shared.set_display_mode(display_mode) {
+            if let Some(RendererStemAndFrond {
+                frond: Ok(frond), ..
+            }) = &mut self.stem_and_frond
+            {
+                frond.stale = true;
+            }
+        }
+    }
+
+    pub fn display_mode(&self) -> DisplayMode {
+        self.crown.shared.display_mode()
+    }
+
+    // Makes draws come out the same every run given the same calls, on the same device and driver.
+    // Instead of following the clock, animations and the time of day move on by frame_time each
+    // draw, starting over from when this was called. None goes back to the clock.
+    pub fn set_deterministic(&mut self, frame_time: Option<Duration>) {
+        let time_of_day = self.time_of_day();
+        let started = Instant::now();
+        self.epoch = started;
+        self.fixed_clock = frame_time.map(|step| FixedClock {
+            draws: 0,
+            started,
+            step,
+        });
+        self.time_of_day = time_of_day.map(|time_of_day| (time_of_day, started));
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.fixed_clock.is_some()
+    }
+
+    fn now(&self) -> Instant {
+        match self.fixed_clock {
+            Some(clock) => clock.started + clock.step * clock.draws,
+            None => Instant::now(),
+        }
+    }
+
+    // Calls draw frames times, which should draw once with the renderer it's given, like from
+    // along a camera path or out of a Recording, and times each. The last frame's drawn again a
+    // few times at the end, so the GPU's times for every frame come back.
+    pub fn benchmark(
+        &mut self,
+        frames: usize,
+        mut draw: impl FnMut(&mut Self, usize) -> Result<bool, RendererError>,
+    ) -> Result<BenchmarkResults, RendererError> {
+        if frames == 0 {
+            return Ok(Default::default());
+        }
+        let mut stats = Vec::with_capacity(frames + FRAMES_IN_FLIGHT);
+        for frame in 0..frames + FRAMES_IN_FLIGHT {
+            draw(self, frame.min(frames - 1))?;
+            stats.push(self.frame_stats);
+        }
+        // GPU times lag behind by the frames in flight
+        let frames = stats
+            .iter()
+            .zip(&stats[FRAMES_IN_FLIGHT..])
+            .map(|(stats, later)| BenchmarkFrame {
+                frame_time: stats.frame_time,
+                cpu_time: stats.cpu_time,
+                gpu: later.gpu,
+            })
+            .collect();
+        Ok(BenchmarkResults { frames })
+    }
+
+    // Records what each draw from now on is asked to draw, until stopped, to replay later. Any
+    // recording already underway is thrown away.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recording::new());
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // Sleeps at the start of each draw until a frame time has passed since the last, so apps
+    // polling without vsync don't draw as fast as they can; None draws immediately
+    pub fn set_frame_limit(&mut self, limit: Option<FrameLimit>) {
+        self.frame_pacer.set_limit(limit);
+    }
+
+    pub fn set_culling_mode(&mut self, culling_mode: CullingMode) {
+        self.culling_mode = culling_mode;
+    }
+
+    pub fn set_light_culling(&mut self, light_culling: LightCulling) {
+        self.light_culling = light_culling;
+    }
+
+    pub fn set_tonemapping(&mut self, operator: TonemappingOperator) {
+        self.tonemapping = operator;
+    }
+
+    // Grades the tonemapped frame through the LUT, crossfading from the last one set over the
+    // transition. Without a LUT, the frame is left as tonemapped.
+    pub fn set_color_grading(&mut self, lut: Option<ColorLut>, transition: Duration) {
+        self.color_grading = self.color_grading.transitioned(lut, transition, self.now());
+    }
+
+    // Blurs the main view by distance from the focal plane; None keeps everything sharp. Cheap to
+    // change every frame, e.g. to pull focus.
+    pub fn set_depth_of_field(&mut self, depth_of_field: Option<DepthOfField>) {
+        self.depth_of_field = depth_of_field;
+    }
+
+    // Draws the main view at a lower resolution and upscales it, or at the output resolution if
+    // None. Changing the render scale rebuilds the renderer on the next draw; sharpening is cheap
+    // to change.
+    pub fn set_upscaling(&mut self, upscaling: Option<Upscaling>) {
+        if let Some(upscaling) = upscaling {
+            assert!(upscaling.render_scale > 0.0 && upscaling.render_scale <= 1.0);
+        }
+        self.upscaling = upscaling;
+    }
+
+    pub fn upscaling(&self) -> Option<Upscaling> {
+        self.upscaling
+    }
+
+    // Blurs the main view along how the camera and meshes moved since the last draw; None keeps
+    // everything sharp. Meshes only count as moving if their instances have a previous transform.
+    pub fn set_motion_blur(&mut self, motion_blur: Option<MotionBlur>) {
+        self.motion_blur = motion_blur;
+    }
+
+    // Replaces every body of water drawn by the main view and render targets
+    pub fn set_water(&mut self, water: &[Water]) {
+        self.water = water.to_vec();
+    }
+
+    // Counts each pass's vertex and fragment invocations and clipped primitives into
+    // FrameStats::pipeline, if the device can. Off by default, as it costs a little GPU time.
+    pub fn set_pipeline_statistics(&mut self, enabled: bool) {
+        self.pipeline_statistics = enabled;
+    }
+
+    // Limits how many bytes of new meshes and textures are uploaded per frame; None for no limit.
+    // Meshes waiting on the budget aren't drawn, and materials waiting on it look like the
+    // default material.
+    pub fn set_upload_budget(&mut self, bytes_per_frame: Option<u64>) {
+        self.upload_budget = bytes_per_frame;
+    }
+
+    // Caps the device memory of material textures; None for no limit. Over it, textures drawn
+    // least recently are demoted to lower resolutions, or evicted once they're small, and restored
+    // when they're drawn again and there's room.
+    pub fn set_texture_budget(&mut self, bytes: Option<u64>) {
+        self.texture_budget = bytes;
+    }
+
+    // Replaces the egui output drawn over each frame, typically right after Context::end_frame()
+    pub fn set_ui(&mut self, egui_ctx: &egui::CtxRef, shapes: Vec<egui::epaint::ClippedShape>) {
+        self.ui = Some(Arc::new(UiFrame {
+            meshes: egui_ctx.tessellate(shapes),
+            texture: egui_ctx.texture(),
+            pixels_per_point: egui_ctx.pixels_per_point(),
+        }));
+    }
+
+    // Plugins are called in the order they're added, at each hook
+    pub fn add_plugin(&mut self, plugin: Box<dyn RenderPassPlugin>) {
+        self.plugins.push(plugin);
+    }
+
+    // Queues the target to be drawn to during the next draw, before the main view, with the same
+    // lights, environment and tonemapping. Only the last one queued for each target is drawn.
+    pub fn draw_to_target(
+        &mut self,
+        target: &RenderTarget,
+        camera: &Camera,
+        meshes: &[MeshInstance],
+    ) {
+        self.target_draws
+            .retain(|target_draw| target_draw.target.id() != target.id());
+        self.target_draws.push(TargetDraw {
+            camera: *camera,
+            meshes: meshes.to_vec(),
+            target: target.clone(),
+        });
+    }
+
+    // Queues both eyes of the target to be drawn during the next draw, like draw_to_target
+    pub fn draw_stereo(
+        &mut self,
+        target: &StereoTarget,
+        camera: &StereoCamera,
+        meshes: &[MeshInstance],
+    ) {
+        for eye in [Eye::Left, Eye::Right] {
+            self.draw_to_target(target.eye(eye), camera.eye(eye), meshes);
+        }
+    }
+
+    // Kept and drawn by every draw until removed, so unchanging scenes needn't be resubmitted
+    pub fn add_mesh_instance(&mut self, instance: MeshInstance) -> MeshInstanceHandle {
+        self.retained_meshes.add(instance)
+    }
+
+    pub fn mesh_instance(&self, handle: MeshInstanceHandle) -> Option<&MeshInstance> {
+        self.retained_meshes.get(handle)
+    }
+
+    pub fn mesh_instance_mut(&mut self, handle: MeshInstanceHandle) -> Option<&mut MeshInstance> {
+        self.retained_meshes.get_mut(handle)
+    }
+
+    // Unlike changing the transform through mesh_instance_mut, this also sets the previous
+    // transform for the next draw, so the move shows up in motion blur. False if it was removed.
+    pub fn set_mesh_instance_transform(
+        &mut self,
+        handle: MeshInstanceHandle,
+        transform: mint::ColumnMatrix4<f32>,
+    ) -> bool {
+        self.retained_meshes.set_transform(handle, transform)
+    }
+
+    pub fn remove_mesh_instance(&mut self, handle: MeshInstanceHandle) -> Option<MeshInstance> {
+        self.retained_meshes.remove(handle)
+    }
+
+    pub fn clear_mesh_instances(&mut self) {
+        self.retained_meshes.clear();
+    }
+
+    // Queues lines for the next draw only, so they need to be requeued every frame
+    pub fn debug_lines(&mut self, lines: &[LineVertex]) {
+        self.debug_lines.extend_from_slice(lines);
+    }
+
+    // With ValidationMode::IfAvailable, this depends on whether the layer is installed
+    pub fn validation_enabled(&self) -> bool {
+        self.crown.shared.validation()
+    }
+
+    // Replaces any previous callback. Messages from before the renderer existed were only logged.
+    pub fn set_validation_callback(&self, callback: Option<ValidationCallback>) {
+        self.crown.shared.set_validation_callback(callback);
+    }
+
+    // Errors reported since last taken, including any from creating the renderer, with
+    // ValidationErrorPolicy::Collect. Empty with the other policies.
+    pub fn take_validation_errors(&mut self) -> Vec<ValidationMessage> {
+        match self.crown.shared.validation_error_policy() {
+            ValidationErrorPolicy::Collect => self.crown.shared.take_validation_errors(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_stats
+    }
+
+    pub fn stats(&self) -> RendererStats {
+        let (draw_calls, triangles) = self.draw_counts;
+        let swapchain_images = match &self.stem_and_frond {
+            Some(RendererStemAndFrond {
+                frond: Ok(frond), ..
+            }) => frond.shared.output_views().map_or(0, <[_]>::len),
+            _ => 0,
+        };
+        let (texture_memory, demoted_textures) = match &self.stem_and_frond {
+            Some(RendererStemAndFrond { stem, .. }) => stem.geometry.texture_residency(),
+            None => (0, 0),
+        };
+        RendererStats {
+            draw_calls,
+            triangles,
+            buffer_memory: buffer::allocated_bytes(),
+            image_memory: image::allocated_bytes(),
+            swapchain_images,
+            texture_memory,
+            demoted_textures,
+            cpu_time: self.frame_stats.cpu_time,
+            gpu_time: self.frame_stats.gpu.map(|gpu| gpu.total()),
+        }
+    }
+
+    // The graphics and presenting queue families, once the first draw has created the device
+    pub fn queue_families(&self) -> Option<(u32, u32)> {
+        let queues = self.stem_and_frond.as_ref()?.stem.shared.queues();
+        Some((queues.graphics_family, queues.present_family))
+    }
+
+    // Captures everything the next draw records and submits, if the app is running under RenderDoc
+    #[cfg(feature = "renderdoc")]
+    pub fn capture_next_frame_with_renderdoc(&mut self) {
+        self.frame_capture.request();
+    }
+
+    // Draws the retained mesh instances, followed by meshes, which are only drawn this once
     pub fn draw(
         &mut self,
-        player_transform: mint::ColumnMatrix4<f32>,
+        camera: &Camera,
+        meshes: &[MeshInstance],
     ) -> Result<bool, RendererError> {
-        let frond = match self.rebuild() {
-            Err(RendererError::FrondCreationError(SharedFrondError::NoSurfaceArea)) => {
-                return Ok(false)
+        self.draw_frame(camera, meshes, false)
+            .map(|(optimal, _)| optimal)
+    }
+
+    // Like draw, but returns as soon as the frame's submitted, leaving a thread of its own to
+    // present it and wait for it to finish. Only frames from as far back as there are frames in
+    // flight are waited on. A swapchain that's stopped suiting the surface is noticed then,
+    // and rebuilt. None if nothing was drawn.
+    pub fn draw_async(
+        &mut self,
+        camera: &Camera,
+        meshes: &[MeshInstance],
+    ) -> Result<Option<FrameHandle>, RendererError> {
+        self.draw_frame(camera, meshes, true)
+            .map(|(_, handle)| handle)
+    }
+
+    fn draw_frame(
+        &mut self,
+        camera: &Camera,
+        meshes: &[MeshInstance],
+        asynchronous: bool,
+    ) -> Result<(bool, Option<FrameHandle>), RendererError> {
+        #[cfg(feature = "renderdoc")]
+        self.frame_capture.begin_frame();
+        let result = self.record_frame(camera, meshes, asynchronous);
+        #[cfg(feature = "renderdoc")]
+        self.frame_capture.end_frame();
+        if self.crown.shared.validation_error_policy() == ValidationErrorPolicy::Panic {
+            let errors = self.crown.shared.take_validation_errors();
+            if let Some(first) = errors.first() {
+                panic!(
+                    "{} validation errors while drawing, the first being: {}",
+                    errors.len(),
+                    first.message
+                );
             }
-            x => x,
-        }?;
+        }
+        result
+    }
+
+    fn record_frame(
+        &mut self,
+        camera: &Camera,
+        meshes: &[MeshInstance],
+        asynchronous: bool,
+    ) -> Result<(bool, Option<FrameHandle>), RendererError> {
+        if self.idle {
+            let resolution = self.crown.shared.resolution();
+            self.idle = resolution.width == 0 || resolution.height == 0;
+        }
+        if self.is_idle() {
+            // Drawn to nothing, so they'd only pile up
+            self.debug_lines.clear();
+            self.target_draws.clear();
+            return Ok((false, None));
+        }
+        self.frame_pacer.wait();
+        let started = Instant::now();
+        let frame_time = match self.last_draw.replace(started) {
+            Some(last_draw) => started - last_draw,
+            None => Default::default(),
+        };
+        let now = self.now(); // animations go by this instead, so deterministic mode can fix it
+        let previous_gpu_times = self.frame_stats.gpu;
+        let previous_statistics = self.frame_stats.pipeline;
+
+        // Only stems made from edited shaders are rebuilt, and the frond is rebuilt around them
+        // below, on the same swapchain
+        #[cfg(feature = "hot-reload")]
+        {
+            let changed = self.shader_watcher.poll();
+            if !changed.is_empty() {
+                if let Some(RendererStemAndFrond { stem, frond }) = self.stem_and_frond.take() {
+                    log::info!("Reloading {}", changed.join(", "));
+                    let swapchain = match frond {
+                        Ok(frond) => frond.take_swapchain(),
+                        Err(swapchain) => swapchain,
+                    };
+                    self.stem_and_frond = Some(RendererStemAndFrond {
+                        stem: stem.reload(&changed)?,
+                        frond: Err(swapchain),
+                    });
+                }
+            }
+        }
+
+        let culling_mode = self.culling_mode;
+        let debug_lines = std::mem::take(&mut self.debug_lines);
+        let depth_of_field = self.depth_of_field;
+        let environment = self.environment.clone();
+        let frame_index = self.frame_index;
+        let light_culling = self.light_culling;
+        let lights = self.lights.clone();
+        let motion_blur = self.motion_blur;
+        let pipeline_statistics = self.pipeline_statistics;
+        let previous_camera = self.previous_camera.unwrap_or(*camera);
+        let retained_meshes = self.retained_meshes.snapshot();
+        let shadow_settings = self.shadow_settings;
+        let sky = self.sky;
+        let sun = match self.time_of_day {
+            Some((time_of_day, set)) => time_of_day
+                .advanced(now.saturating_duration_since(set).as_secs_f32())
+                .sun(&self.sun),
+            None => self.sun,
+        };
+        let ambient_light = self.ambient_light;
+        // Only kept if the draw's submitted
+        let mut recorded = self.recording.as_ref().map(|_| RecordedFrame {
+            camera: *camera,
+            sun,
+            ambient_light,
+            lights: lights.clone(),
+            instances: retained_meshes.iter().chain(meshes).cloned().collect(),
+        });
+        let color_grading = self.color_grading.clone();
+        let target_draws = std::mem::take(&mut self.target_draws);
+        let time = now.saturating_duration_since(self.epoch).as_secs_f32();
+        let tonemapping = self.tonemapping;
+        let ui = self.ui.clone();
+        let upscaling = self.upscaling;
+        let water = self.water.clone();
+        let texture_budget = self.texture_budget;
+        let mut upload_budget = UploadBudget::new(self.upload_budget);
+        let mut rebuilds = 0;
+        let (optimal, gpu_times, statistics, handle) = loop {
+            match self.rebuild() {
+                Err(RendererError::FrondCreationError(SharedFrondError::NoSurfaceArea)) => {
+                    log::debug!("Surface has no area, idling until it's resized");
+                    self.idle = true;
+                    // Time spent idle isn't a frame
+                    self.last_draw = None;
+                    return Ok((false, None));
+                }
+                x => x,
+            }?;
+            let (stem, frond) = match &mut self.stem_and_frond {
+                Some(RendererStemAndFrond {
+                    stem,
+                    frond: Ok(frond),
+                }) => (stem, frond),
+                _ => unreachable!(),
+            };
+
+            let prepared = frond
+                .geometry
+                .prepare_meshes(&retained_meshes, &mut upload_budget)
+                .and_then(|mut prepared_meshes| {
+                    let meshes = frond.geometry.prepare_meshes(meshes, &mut upload_budget)?;
+                    prepared_meshes.extend(meshes);
+                    Ok(prepared_meshes)
+                })
+                .and_then(|meshes| {
+                    let environment = frond.lighting.prepare_environment(
+                        environment.as_ref(),
+                        sky.as_ref().map(|sky| (sky, &sun)),
+                    )?;
+                    let grade = frond.tonemapping.prepare_grade(&color_grading, now)?;
+                    let ui_texture = ui
+                        .as_ref()
+                        .map(|ui| frond.ui.prepare_texture(&ui.texture))
+                        .transpose()?;
+                    Ok((meshes, environment, grade, ui_texture))
+                });
+            let (meshes, environment, grade, ui_texture) = match prepared {
+                Err(UploadError::VkError(vk::Result::ERROR_DEVICE_LOST)) => {
+                    self.lose_device();
+                    return Err(RendererError::DeviceLost);
+                }
+                x => x,
+            }?;
+
+            let geometry = &frond.geometry;
+            let target_fronds = &mut frond.targets;
+            target_fronds.evict_unused();
+            let targets = target_draws
+                .iter()
+                .map(|target_draw| {
+                    let texture = target_draw.target.texture();
+                    let target_frond =
+                        target_fronds.get_or_upload(target_draw.target.id(), texture, |_| {
+                            let gpu_texture = geometry.prepare_texture(texture)?;
+                            RenderTargetFrond::new(stem, gpu_texture, shadow_settings)
+                        })?;
+                    Ok(PreparedTargetDraw {
+                        camera: &target_draw.camera,
+                        frond: target_frond,
+                        meshes: geometry.prepare_meshes(&target_draw.meshes, &mut upload_budget)?,
+                    })
+                })
+                .collect::<Result<Vec<_>, RendererError>>()?;
+            geometry.enforce_texture_budget(texture_budget, &mut upload_budget)?;
+
+            // Only counts from the attempt that gets submitted are kept
+            stem.shared.take_draw_counts();
+            let result = unsafe {
+                frond.draw(
+                    frame_index,
+                    camera,
+                    &previous_camera,
+                    &meshes,
+                    &targets,
+                    culling_mode,
+                    light_culling,
+                    &lights,
+                    &sun,
+                    &ambient_light,
+                    &environment,
+                    sky.as_ref(),
+                    &water,
+                    time,
+                    &debug_lines,
+                    depth_of_field,
+                    motion_blur,
+                    tonemapping,
+                    grade.as_ref(),
+                    upscaling,
+                    ui.as_deref().zip(ui_texture.as_deref()),
+                    &mut self.plugins,
+                    pipeline_statistics,
+                    asynchronous,
+                )
+            };
+            frond.drawn |= result.is_ok();
+            self.draw_counts = stem.shared.take_draw_counts();
+            // Shadow caches drawn into by a frame that never got submitted hold nothing
+            if result.is_err() {
+                frond.shadow.forget_cache();
+                for target in &targets {
+                    target.frond.shadow.forget_cache();
+                }
+            }
+            match result {
+                // Nothing was submitted, so the frame can go to the rebuilt swapchain instead
+                Err(RendererError::Acquisition(vk::Result::ERROR_OUT_OF_DATE_KHR)) => {
+                    frond.stale = true;
+                    rebuilds += 1;
+                    if rebuilds > Self::MAX_SWAPCHAIN_REBUILDS {
+                        return Ok((false, None));
+                    }
+                    continue;
+                }
+                Ok((false, ..)) => frond.suboptimal = true,
+                Err(RendererError::DeviceLost) => self.lose_device(),
+                _ => (),
+            }
+            self.frame_index = (frame_index + 1) % FRAMES_IN_FLIGHT;
+            if let Some(fixed_clock) = &mut self.fixed_clock {
+                fixed_clock.draws += 1;
+            }
+            self.previous_camera = Some(*camera);
+            if result.is_ok() {
+                self.retained_meshes.end_frame();
+                if let (Some(recording), Some(recorded)) = (&mut self.recording, recorded.take()) {
+                    recording.push(recorded);
+                }
+            }
+            break result?;
+        };
+
+        self.frame_stats = FrameStats {
+            frame_time,
+            cpu_time: started.elapsed(),
+            gpu: gpu_times.or(previous_gpu_times),
+            pipeline: statistics.or(previous_statistics.filter(|_| pipeline_statistics)),
+            deferred_uploads: upload_budget.deferred(),
+            missed_deadlines: self.frame_pacer.missed_deadlines(),
+        };
+        Ok((optimal, handle))
+    }
+
+    // What an OpenXR session needs to share the device, which is created if it doesn't exist yet
+    #[cfg(feature = "openxr")]
+    pub(crate) fn vulkan_handles(
+        &mut self,
+    ) -> Result<(vk::Instance, vk::PhysicalDevice, vk::Device, u32), RendererError> {
+        use ash::version::InstanceV1_0;
+
+        if self.stem_and_frond.is_none() {
+            self.rebuild()?;
+        }
+        let shared = &self.stem_and_frond.as_ref().unwrap().stem.shared;
+        Ok((
+            self.crown.shared.instance().handle(),
+            shared.physical_device(),
+            shared.device().handle(),
+            shared.queues().graphics_family,
+        ))
+    }
+
+    // Copies what was last drawn to the target into an image from elsewhere, like a headset's
+    // swapchain, leaving it in the given layout. Blocks until the copy's done.
+    #[cfg(feature = "openxr")]
+    pub(crate) fn copy_target_to_image(
+        &mut self,
+        target: &RenderTarget,
+        image: vk::Image,
+        layout: vk::ImageLayout,
+    ) -> Result<(), RendererError> {
+        let result = match &self.stem_and_frond {
+            Some(RendererStemAndFrond {
+                stem,
+                frond: Ok(frond),
+            }) => frond
+                .geometry
+                .prepare_texture(target.texture())
+                .and_then(|texture| unsafe {
+                    upload::blit_image(
+                        &stem.shared,
+                        texture.image().get(&stem.shared),
+                        image,
+                        layout,
+                        target.extent(),
+                    )
+                }),
+            _ => return Err(RendererError::NothingDrawn),
+        };
+        if let Err(UploadError::VkError(vk::Result::ERROR_DEVICE_LOST)) = result {
+            self.lose_device();
+        }
+        Ok(result?)
+    }
+
+    // Returns the most recently drawn frame as tightly packed RGBA rows, top row first
+    pub fn read_pixels(&mut self) -> Result<Vec<u8>, RendererError> {
+        if !self.crown.shared.is_headless() {
+            return Err(RendererError::NotHeadless);
+        }
+        let shared = match &self.stem_and_frond {
+            Some(RendererStemAndFrond {
+                frond: Ok(frond), ..
+            }) if frond.drawn => frond.shared.clone(),
+            _ => return Err(RendererError::NothingDrawn),
+        };
 
-        let result = unsafe { frond.draw(player_transform.into()) };
-        if result == Err(vk::Result::ERROR_DEVICE_LOST) {
+        let result = unsafe {
+            shared
+                .device()
+                .device_wait_idle()
+                .map_err(UploadError::from)
+                .and_then(|()| upload::read_image(&shared.stem(), shared.offscreen().unwrap()))
+        };
+        if let Err(UploadError::VkError(vk::Result::ERROR_DEVICE_LOST)) = result {
+            drop(shared);
             self.lose_device();
         }
         Ok(result?)
@@ -101,146 +1137,1040 @@ impl Renderer {
 }
 
 struct RendererCrown {
+    options: RendererOptions,
     shared: Arc<SharedCrown>,
 }
 
 impl RendererCrown {
-    pub fn new(window: Arc<Window>) -> Result<Self, RendererError> {
-        let shared = Arc::new(SharedCrown::new(window)?);
-        Ok(Self { shared })
+    pub fn new(
+        window: Arc<Window>,
+        options: RendererOptions,
+        requirements: DeviceRequirements,
+    ) -> Result<Self, RendererError> {
+        let shared = Arc::new(SharedCrown::new(
+            window,
+            options.validation,
+            options.validation_error_policy,
+            requirements,
+        )?);
+        Ok(Self { options, shared })
+    }
+
+    pub fn new_headless(
+        resolution: vk::Extent2D,
+        options: RendererOptions,
+    ) -> Result<Self, RendererError> {
+        let shared = Arc::new(SharedCrown::new_headless(
+            resolution,
+            options.validation,
+            options.validation_error_policy,
+        )?);
+        Ok(Self { options, shared })
     }
 }
 
 struct RendererStem {
+    culling: Arc<CullingStem>,
+    debug_draw: Arc<DebugDrawStem>,
+    depth_of_field: Arc<DepthOfFieldStem>,
     geometry: Arc<GeometryStem>,
     lighting: Arc<LightingStem>,
+    motion_blur: Arc<MotionBlurStem>,
+    shadow: Arc<ShadowStem>,
     shared: Arc<SharedStem>,
+    target_tonemapping: Arc<TonemappingStem>, // for render targets, which needn't match the surface
     tonemapping: Arc<TonemappingStem>,
+    transparency: Arc<TransparencyStem>,
+    ui: Arc<UiStem>,
+    upscale_tonemapping: Arc<TonemappingStem>, // leaves its output for upscaling to sample
+    upscaling: Arc<UpscalingStem>,
+    water: Arc<WaterStem>,
 }
 
 impl RendererStem {
     fn new(crown: &RendererCrown) -> Result<Self, RendererError> {
-        let shared = Arc::new(SharedStem::new(crown.shared.clone())?);
-        let geometry = Arc::new(GeometryStem::new(shared.clone())?);
-        let lighting = Arc::new(LightingStem::new(shared.clone())?);
-        let tonemapping = Arc::new(TonemappingStem::new(shared.clone())?);
+        let shared = Arc::new(SharedStem::new(
+            crown.shared.clone(),
+            crown.options.surface_format,
+            crown.options.color_workflow,
+            crown.options.present_queue,
+            crown.options.output_usage,
+            crown.options.transparent,
+        )?);
+        let color_grading = Arc::new(
+            ColorGradingStem::new(shared.clone())
+                .map_err(RendererError::pipeline_creation("color grading"))?,
+        );
+        let culling = Arc::new(
+            CullingStem::new(shared.clone())
+                .map_err(RendererError::pipeline_creation("culling"))?,
+        );
+        let debug_draw = Arc::new(
+            DebugDrawStem::new(shared.clone())
+                .map_err(RendererError::pipeline_creation("debug draw"))?,
+        );
+        let depth_of_field = Arc::new(
+            DepthOfFieldStem::new(shared.clone())
+                .map_err(RendererError::pipeline_creation("depth of field"))?,
+        );
+        let geometry = Arc::new(
+            GeometryStem::new(shared.clone())
+                .map_err(RendererError::pipeline_creation("geometry"))?,
+        );
+        let environment = Arc::new(
+            EnvironmentStem::new(shared.clone())
+                .map_err(RendererError::pipeline_creation("environment"))?,
+        );
+        let lighting = Arc::new(
+            LightingStem::new(shared.clone(), environment)
+                .map_err(RendererError::pipeline_creation("lighting"))?,
+        );
+        let motion_blur = Arc::new(
+            MotionBlurStem::new(shared.clone())
+                .map_err(RendererError::pipeline_creation("motion blur"))?,
+        );
+        let shadow = Arc::new(
+            ShadowStem::new(shared.clone()).map_err(RendererError::pipeline_creation("shadow"))?,
+        );
+        let target_tonemapping = Arc::new(
+            TonemappingStem::new_render_target(shared.clone(), color_grading.clone())
+                .map_err(RendererError::pipeline_creation("tonemapping"))?,
+        );
+        let tonemapping = Arc::new(
+            TonemappingStem::new(shared.clone(), color_grading.clone())
+                .map_err(RendererError::pipeline_creation("tonemapping"))?,
+        );
+        let transparency = Arc::new(
+            TransparencyStem::new(shared.clone(), geometry.clone(), lighting.clone())
+                .map_err(RendererError::pipeline_creation("transparency"))?,
+        );
+        let ui =
+            Arc::new(UiStem::new(shared.clone()).map_err(RendererError::pipeline_creation("ui"))?);
+        let upscale_tonemapping = Arc::new(
+            TonemappingStem::new_upscale_input(shared.clone(), color_grading.clone())
+                .map_err(RendererError::pipeline_creation("tonemapping"))?,
+        );
+        let upscaling = Arc::new(
+            UpscalingStem::new(shared.clone())
+                .map_err(RendererError::pipeline_creation("upscaling"))?,
+        );
+        let water = Arc::new(
+            WaterStem::new(shared.clone(), lighting.clone())
+                .map_err(RendererError::pipeline_creation("water"))?,
+        );
+
+        Ok(Self {
+            culling,
+            debug_draw,
+            depth_of_field,
+            geometry,
+            lighting,
+            motion_blur,
+            shadow,
+            shared,
+            target_tonemapping,
+            tonemapping,
+            transparency,
+            ui,
+            upscale_tonemapping,
+            upscaling,
+            water,
+        })
+    }
+
+    // Rebuilds only the stems made from the given shader sources, along with those holding on to
+    // them, keeping the device and everything uploaded to it. The fronds hold on to stems too, so
+    // they need to have been dropped.
+    #[cfg(feature = "hot-reload")]
+    fn reload(self, changed: &[String]) -> Result<Self, RendererError> {
+        let uses = |sources: &[&str]| changed.iter().any(|path| sources.contains(&path.as_str()));
+        let fullscreen = uses(&["shaders/fullscreen.vert"]);
+        let reload_geometry = uses(&[
+            "shaders/triangle.vert",
+            "shaders/triangle-motion.vert",
+            "shaders/triangle.frag",
+            "shaders/triangle-bindless.frag",
+        ]);
+        let reload_environment = uses(&[
+            "shaders/brdf-lut.comp",
+            "shaders/environment-irradiance.comp",
+            "shaders/environment-prefilter.comp",
+            "shaders/sky.comp",
+        ]);
+        let reload_lighting = reload_environment
+            || fullscreen
+            || uses(&[
+                "shaders/light-clusters.comp",
+                "shaders/lighting.frag",
+                "shaders/light-volume.vert",
+                "shaders/light-volume.frag",
+            ]);
+        let reload_tonemapping = fullscreen || uses(&["shaders/tonemapping.frag"]);
+        let reload_transparency = reload_geometry
+            || reload_lighting
+            || uses(&["shaders/triangle.vert", "shaders/transparent.frag"]);
+
+        let Self {
+            mut culling,
+            mut debug_draw,
+            mut depth_of_field,
+            mut geometry,
+            mut lighting,
+            mut motion_blur,
+            mut shadow,
+            shared,
+            mut target_tonemapping,
+            mut tonemapping,
+            transparency,
+            mut ui,
+            mut upscale_tonemapping,
+            mut upscaling,
+            mut water,
+        } = self;
+        unsafe { shared.device().device_wait_idle() }.map_err(UploadError::from)?;
+        if fullscreen {
+            shared.reload_fullscreen_vert_shader_module()?;
+        }
+
+        if uses(&["shaders/cull.comp"]) {
+            culling = Arc::new(
+                CullingStem::new(shared.clone())
+                    .map_err(RendererError::pipeline_creation("culling"))?,
+            );
+        }
+        if uses(&["shaders/debug-line.vert", "shaders/debug-line.frag"]) {
+            debug_draw = Arc::new(
+                DebugDrawStem::new(shared.clone())
+                    .map_err(RendererError::pipeline_creation("debug draw"))?,
+            );
+        }
+        if fullscreen || uses(&["shaders/depth-of-field.frag"]) {
+            depth_of_field = Arc::new(
+                DepthOfFieldStem::new(shared.clone())
+                    .map_err(RendererError::pipeline_creation("depth of field"))?,
+            );
+        }
+        if reload_lighting {
+            // The environment keeps what it uploaded unless its own shaders changed
+            let environment = if reload_environment {
+                Arc::new(
+                    EnvironmentStem::new(shared.clone())
+                        .map_err(RendererError::pipeline_creation("environment"))?,
+                )
+            } else {
+                lighting.environment().clone()
+            };
+            lighting = Arc::new(
+                LightingStem::new(shared.clone(), environment)
+                    .map_err(RendererError::pipeline_creation("lighting"))?,
+            );
+        }
+        if fullscreen || uses(&["shaders/motion-blur.frag"]) {
+            motion_blur = Arc::new(
+                MotionBlurStem::new(shared.clone())
+                    .map_err(RendererError::pipeline_creation("motion blur"))?,
+            );
+        }
+        if uses(&["shaders/triangle.vert", "shaders/triangle-shadow.frag"]) {
+            shadow = Arc::new(
+                ShadowStem::new(shared.clone())
+                    .map_err(RendererError::pipeline_creation("shadow"))?,
+            );
+        }
+        if reload_tonemapping {
+            let color_grading = tonemapping.color_grading().clone();
+            target_tonemapping = Arc::new(
+                TonemappingStem::new_render_target(shared.clone(), color_grading.clone())
+                    .map_err(RendererError::pipeline_creation("tonemapping"))?,
+            );
+            tonemapping = Arc::new(
+                TonemappingStem::new(shared.clone(), color_grading.clone())
+                    .map_err(RendererError::pipeline_creation("tonemapping"))?,
+            );
+            upscale_tonemapping = Arc::new(
+                TonemappingStem::new_upscale_input(shared.clone(), color_grading)
+                    .map_err(RendererError::pipeline_creation("tonemapping"))?,
+            );
+        }
+        // Transparency holds on to geometry, which can only be reloaded in place once let go of
+        let transparency = if reload_transparency {
+            drop(transparency);
+            if reload_geometry {
+                match Arc::get_mut(&mut geometry) {
+                    Some(geometry) => geometry
+                        .reload_shaders()
+                        .map_err(RendererError::pipeline_creation("geometry"))?,
+                    None => {
+                        log::warn!("Geometry is still in use, so everything will be re-uploaded");
+                        geometry = Arc::new(
+                            GeometryStem::new(shared.clone())
+                                .map_err(RendererError::pipeline_creation("geometry"))?,
+                        );
+                    }
+                }
+            }
+            Arc::new(
+                TransparencyStem::new(shared.clone(), geometry.clone(), lighting.clone())
+                    .map_err(RendererError::pipeline_creation("transparency"))?,
+            )
+        } else {
+            transparency
+        };
+        if fullscreen
+            || uses(&[
+                "shaders/ui.vert",
+                "shaders/ui.frag",
+                "shaders/ui-composite.frag",
+            ])
+        {
+            ui = Arc::new(
+                UiStem::new(shared.clone()).map_err(RendererError::pipeline_creation("ui"))?,
+            );
+        }
+        if fullscreen || uses(&["shaders/easu.comp", "shaders/rcas.frag"]) {
+            upscaling = Arc::new(
+                UpscalingStem::new(shared.clone())
+                    .map_err(RendererError::pipeline_creation("upscaling"))?,
+            );
+        }
+        if reload_lighting || uses(&["shaders/water.vert", "shaders/water.frag"]) {
+            water = Arc::new(
+                WaterStem::new(shared.clone(), lighting.clone())
+                    .map_err(RendererError::pipeline_creation("water"))?,
+            );
+        }
 
         Ok(Self {
+            culling,
+            debug_draw,
+            depth_of_field,
             geometry,
             lighting,
+            motion_blur,
+            shadow,
             shared,
+            target_tonemapping,
             tonemapping,
+            transparency,
+            ui,
+            upscale_tonemapping,
+            upscaling,
+            water,
         })
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+enum Pass {
+    Geometry,
+    Shadow,
+    LightClusters,
+    Lighting,
+    Water,
+    Transparency,
+    DebugDraw,
+    DepthOfField,
+    MotionBlur,
+    Tonemapping,
+    Upscaling,
+    Ui,
+}
+
+impl Pass {
+    // Declared in the order timestamps are written, which the graph keeps
+    fn declarations() -> Vec<PassDeclaration<Self>> {
+        vec![
+            PassDeclaration {
+                pass: Self::Geometry,
+                reads: vec![],
+                writes: vec![
+                    (Resource::Depth, sync::DEPTH_ATTACHMENT_WRITE),
+                    (Resource::GBuffer, sync::COLOR_ATTACHMENT_WRITE),
+                    (Resource::Velocity, sync::COLOR_ATTACHMENT_WRITE),
+                ],
+            },
+            PassDeclaration {
+                pass: Self::Shadow,
+                reads: vec![],
+                writes: vec![(Resource::Shadow, sync::DEPTH_ATTACHMENT_WRITE)],
+            },
+            // Uploads lights along with the point shadow cubemaps the shadow pass gave them
+            PassDeclaration {
+                pass: Self::LightClusters,
+                reads: vec![],
+                writes: vec![(Resource::LightClusters, sync::COMPUTE_STORAGE_WRITE)],
+            },
+            // Light volumes mark and clear stencil, alongside depth
+            PassDeclaration {
+                pass: Self::Lighting,
+                reads: vec![
+                    (Resource::Depth, sync::INPUT_ATTACHMENT_READ),
+                    (Resource::GBuffer, sync::INPUT_ATTACHMENT_READ),
+                    (Resource::Shadow, sync::FRAGMENT_SAMPLED),
+                    (Resource::LightClusters, sync::FRAGMENT_STORAGE_READ),
+                ],
+                writes: vec![
+                    (Resource::Depth, sync::DEPTH_ATTACHMENT_WRITE),
+                    (Resource::Light, sync::COLOR_ATTACHMENT_WRITE),
+                ],
+            },
+            // Also copies light aside to sample, outside of its render pass
+            PassDeclaration {
+                pass: Self::Water,
+                reads: vec![
+                    (Resource::Depth, sync::DEPTH_TEST),
+                    (Resource::Depth, sync::FRAGMENT_SAMPLED),
+                ],
+                writes: vec![(
+                    Resource::Light,
+                    sync::TRANSFER_READ | sync::COLOR_ATTACHMENT_BLEND,
+                )],
+            },
+            PassDeclaration {
+                pass: Self::Transparency,
+                reads: vec![
+                    (Resource::Depth, sync::DEPTH_TEST),
+                    (Resource::Shadow, sync::FRAGMENT_SAMPLED),
+                ],
+                writes: vec![(Resource::Light, sync::COLOR_ATTACHMENT_BLEND)],
+            },
+            PassDeclaration {
+                pass: Self::DebugDraw,
+                reads: vec![(Resource::Depth, sync::DEPTH_TEST)],
+                writes: vec![(Resource::Light, sync::COLOR_ATTACHMENT_BLEND)],
+            },
+            PassDeclaration {
+                pass: Self::DepthOfField,
+                reads: vec![
+                    (Resource::Depth, sync::FRAGMENT_SAMPLED),
+                    (Resource::Light, sync::FRAGMENT_SAMPLED),
+                ],
+                writes: vec![(Resource::DepthOfField, sync::COLOR_ATTACHMENT_WRITE)],
+            },
+            // Reads whichever of light or its depth of field copy is the latest
+            PassDeclaration {
+                pass: Self::MotionBlur,
+                reads: vec![
+                    (Resource::Depth, sync::FRAGMENT_SAMPLED),
+                    (Resource::Light, sync::FRAGMENT_SAMPLED),
+                    (Resource::DepthOfField, sync::FRAGMENT_SAMPLED),
+                    (Resource::Velocity, sync::FRAGMENT_SAMPLED),
+                ],
+                writes: vec![(Resource::MotionBlur, sync::COLOR_ATTACHMENT_WRITE)],
+            },
+            // Reads whichever of light or its blurred copies the post-processing passes left it
+            PassDeclaration {
+                pass: Self::Tonemapping,
+                reads: vec![
+                    (Resource::Light, sync::INPUT_ATTACHMENT_READ),
+                    (Resource::DepthOfField, sync::INPUT_ATTACHMENT_READ),
+                    (Resource::MotionBlur, sync::INPUT_ATTACHMENT_READ),
+                ],
+                writes: vec![(Resource::Output, sync::COLOR_ATTACHMENT_WRITE)],
+            },
+            // With upscaling, tonemapping's output is the smaller image this reads from
+            PassDeclaration {
+                pass: Self::Upscaling,
+                reads: vec![(Resource::Output, sync::COMPUTE_SAMPLED)],
+                writes: vec![(Resource::Output, sync::COLOR_ATTACHMENT_WRITE)],
+            },
+            PassDeclaration {
+                pass: Self::Ui,
+                reads: vec![],
+                writes: vec![(Resource::Output, sync::COLOR_ATTACHMENT_BLEND)],
+            },
+        ]
+    }
+
+    // Names and colors for the debug label each pass is recorded under
+    fn label(self) -> (&'static str, [f32; 4]) {
+        match self {
+            Self::Geometry => ("geometry", [0.2, 0.6, 1.0, 1.0]),
+            Self::Shadow => ("shadow", [0.3, 0.3, 0.3, 1.0]),
+            Self::LightClusters => ("light clusters", [1.0, 0.8, 0.2, 1.0]),
+            Self::Lighting => ("lighting", [1.0, 0.9, 0.4, 1.0]),
+            Self::Water => ("water", [0.1, 0.4, 0.5, 1.0]),
+            Self::Transparency => ("transparency", [0.6, 0.8, 1.0, 1.0]),
+            Self::DebugDraw => ("debug draw", [1.0, 0.0, 1.0, 1.0]),
+            Self::DepthOfField => ("depth of field", [0.5, 0.3, 0.8, 1.0]),
+            Self::MotionBlur => ("motion blur", [0.8, 0.3, 0.5, 1.0]),
+            Self::Tonemapping => ("tonemapping", [1.0, 0.5, 0.2, 1.0]),
+            Self::Upscaling => ("upscaling", [0.3, 0.9, 0.9, 1.0]),
+            Self::Ui => ("ui", [0.8, 0.8, 0.8, 1.0]),
+        }
+    }
+
+    fn plugin_hook(self) -> Option<PluginHook> {
+        match self {
+            Self::Geometry => Some(PluginHook::AfterGeometry),
+            Self::Lighting => Some(PluginHook::AfterLighting),
+            Self::Ui => Some(PluginHook::BeforePresent),
+            _ => None,
+        }
+    }
+
+    fn timestamp(self) -> Timestamp {
+        match self {
+            Self::Geometry => Timestamp::Geometry,
+            Self::Shadow => Timestamp::Shadow,
+            Self::LightClusters => Timestamp::LightClusters,
+            Self::Lighting => Timestamp::Lighting,
+            Self::Water => Timestamp::Water,
+            Self::Transparency => Timestamp::Transparency,
+            Self::DebugDraw => Timestamp::DebugDraw,
+            Self::DepthOfField => Timestamp::DepthOfField,
+            Self::MotionBlur => Timestamp::MotionBlur,
+            Self::Tonemapping => Timestamp::Tonemapping,
+            Self::Upscaling => Timestamp::Upscaling,
+            Self::Ui => Timestamp::Ui,
+        }
+    }
+}
+
 struct RendererFrond {
+    culling: Arc<CullingFrond>,
+    debug_draw: Arc<DebugDrawFrond>,
+    depth_of_field: Arc<DepthOfFieldFrond>,
+    drawn: bool,
     geometry: Arc<GeometryFrond>,
+    graph: RenderGraph<Pass>,
+    in_flight: [Option<FrameHandle>; FRAMES_IN_FLIGHT], // by frame index, if handed to presenter
     lighting: Arc<LightingFrond>,
+    motion_blur: Arc<MotionBlurFrond>,
+    presenter: Option<Presenter>, // started by the first asynchronous draw
+    shadow: Arc<ShadowFrond>,
     shared: Arc<SharedFrond>,
+    stale: bool,      // the swapchain no longer matches the surface
+    suboptimal: bool, // the swapchain still works, but should be rebuilt once resizing settles
+    targets: UploadCache<Texture, RenderTargetFrond>,
     tonemapping: Arc<TonemappingFrond>,
+    transparency: Arc<TransparencyFrond>,
+    ui: Arc<UiFrond>,
+    upscaling: Option<Arc<UpscalingFrond>>, // if the shared frond has a render scale
+    water: Arc<WaterFrond>,
 }
 
 impl RendererFrond {
-    fn new(stem: &RendererStem) -> Result<Self, RendererError> {
-        let shared = Arc::new(SharedFrond::new(stem.shared.clone())?);
+    fn new(
+        stem: &RendererStem,
+        shadow_settings: ShadowSettings,
+        present_mode: PresentModePreference,
+        render_scale: Option<f32>,
+    ) -> Result<Self, RendererError> {
+        let shared = Arc::new(SharedFrond::new(
+            stem.shared.clone(),
+            shadow_settings,
+            present_mode,
+            render_scale,
+        )?);
         Self::new_from_shared_frond(stem, shared)
     }
 
     fn resurrect(
         stem: &RendererStem,
         swapchain: SharedFrondSwapchain,
+        shadow_settings: ShadowSettings,
+        present_mode: PresentModePreference,
+        render_scale: Option<f32>,
     ) -> Result<Self, (SharedFrondSwapchain, RendererError)> {
         let shared = Arc::new(
             swapchain
-                .resurrect()
+                .resurrect(shadow_settings, present_mode, render_scale)
                 .map_err(|(swapchain, err)| (swapchain, err.into()))?,
         );
 
-        Self::new_from_shared_frond(stem, shared.clone()).map_err(|err| {
-            let swapchain = match Arc::try_unwrap(shared.clone()) {
-                Ok(shared) => shared.take_swapchain(),
-                _ => panic!(
-                    "Cannot take swapchain from SharedFrond as something is holding onto it."
-                ),
-            };
-            (swapchain, err)
-        })
+        Self::new_from_shared_frond(stem, shared.clone())
+            .map_err(|err| (shared.take_swapchain(), err))
     }
 
     fn new_from_shared_frond(
         stem: &RendererStem,
         shared: Arc<SharedFrond>,
     ) -> Result<Self, RendererError> {
-        let geometry = Arc::new(GeometryFrond::new(stem.geometry.clone(), shared.clone())?);
-        let lighting = Arc::new(LightingFrond::new(stem.lighting.clone(), shared.clone())?);
-        let tonemapping = Arc::new(TonemappingFrond::new(
-            stem.tonemapping.clone(),
-            shared.clone(),
-        )?);
+        let culling = Arc::new(
+            CullingFrond::new(stem.culling.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("culling"))?,
+        );
+        let debug_draw = Arc::new(
+            DebugDrawFrond::new(stem.debug_draw.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("debug draw"))?,
+        );
+        let depth_of_field = Arc::new(
+            DepthOfFieldFrond::new(stem.depth_of_field.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("depth of field"))?,
+        );
+        let geometry = Arc::new(
+            GeometryFrond::new(stem.geometry.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("geometry"))?,
+        );
+        let lighting = Arc::new(
+            LightingFrond::new(stem.lighting.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("lighting"))?,
+        );
+        let motion_blur = Arc::new(
+            MotionBlurFrond::new(stem.motion_blur.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("motion blur"))?,
+        );
+        let shadow = Arc::new(
+            ShadowFrond::new(stem.shadow.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("shadow"))?,
+        );
+        let tonemapping_stem = match shared.render_scale() {
+            Some(_) => &stem.upscale_tonemapping,
+            None => &stem.tonemapping,
+        };
+        let tonemapping = Arc::new(
+            TonemappingFrond::new(tonemapping_stem.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("tonemapping"))?,
+        );
+        let transparency = Arc::new(
+            TransparencyFrond::new(stem.transparency.clone(), &lighting, shared.clone())
+                .map_err(RendererError::pipeline_creation("transparency"))?,
+        );
+        let ui = Arc::new(
+            UiFrond::new(stem.ui.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("ui"))?,
+        );
+        let upscaling = match shared.render_scale() {
+            Some(_) => Some(Arc::new(
+                UpscalingFrond::new(stem.upscaling.clone(), shared.clone())
+                    .map_err(RendererError::pipeline_creation("upscaling"))?,
+            )),
+            None => None,
+        };
+        let water = Arc::new(
+            WaterFrond::new(stem.water.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("water"))?,
+        );
+        let graph = RenderGraph::new(&Pass::declarations())?;
 
         Ok(Self {
+            culling,
+            debug_draw,
+            depth_of_field,
+            drawn: false,
             geometry,
+            graph,
+            in_flight: Default::default(),
             lighting,
+            motion_blur,
+            presenter: None,
+            shadow,
             shared,
+            stale: false,
+            suboptimal: false,
+            targets: UploadCache::new(),
             tonemapping,
+            transparency,
+            ui,
+            upscaling,
+            water,
         })
     }
 
-    unsafe fn draw(&self, player_transform: na::Matrix4<f32>) -> VkResult<bool> {
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn draw(
+        &mut self,
+        frame_index: usize,
+        camera: &Camera,
+        previous_camera: &Camera,
+        meshes: &[GpuMeshInstance],
+        targets: &[PreparedTargetDraw],
+        culling_mode: CullingMode,
+        light_culling: LightCulling,
+        lights: &[Light],
+        sun: &DirectionalLight,
+        ambient_light: &AmbientLight,
+        environment: &GpuEnvironment,
+        sky: Option<&Sky>,
+        water: &[Water],
+        time: f32,
+        debug_lines: &[LineVertex],
+        depth_of_field: Option<DepthOfField>,
+        motion_blur: Option<MotionBlur>,
+        tonemapping: TonemappingOperator,
+        grade: Option<&ColorGrade>,
+        upscaling: Option<Upscaling>,
+        ui: Option<(&UiFrame, &GpuTexture)>,
+        plugins: &mut [Box<dyn RenderPassPlugin>],
+        pipeline_statistics: bool,
+        asynchronous: bool,
+    ) -> Result<
+        (
+            bool,
+            Option<PassTimes>,
+            Option<PipelineStatistics>,
+            Option<FrameHandle>,
+        ),
+        RendererError,
+    > {
         let frond = &self.shared;
-        let swapchain = frond.swapchain();
+        let swapchain = frond
+            .swapchain()
+            .map_err(RendererError::in_context(RendererError::Acquisition))?;
 
         let stem = frond.stem();
-        let command_buffer = stem.command_buffer();
+        let frame = stem.frame(frame_index);
+        let command_buffer = frame.command_buffer;
         let device = stem.device();
-        let image_acquired_semaphore = stem.image_acquired_semaphore();
-        let presentation_fence = stem.presentation_fence();
+        let image_acquired_semaphore = frame.image_acquired_semaphore;
         let queues = stem.queues();
-        let render_complete_semaphore = stem.render_complete_semaphore();
         let swapchain_fn = stem.swapchain_fn();
 
-        let view_matrix = util::perspective_matrix(0.1, TAU * 0.25, frond.resolution())
-            * player_transform.try_inverse().unwrap();
+        let previous_world_to_screen = previous_camera.world_to_screen(frond.resolution());
+
+        // Nothing here touches the frame, so it overlaps with the GPU finishing it
+        let (target_views, view) = jobs::join(
+            || {
+                jobs::map(targets, |target| {
+                    PreparedView::new(
+                        target.camera,
+                        target.frond.shared.resolution(),
+                        &target.meshes,
+                        culling_mode,
+                        &target.frond.shadow,
+                        lights,
+                    )
+                })
+            },
+            || {
+                PreparedView::new(
+                    camera,
+                    frond.resolution(),
+                    meshes,
+                    culling_mode,
+                    &self.shadow,
+                    lights,
+                )
+            },
+        );
+
+        // Frames handed to the presenter are only finished with once it says so, which also
+        // means their presents were queued before their semaphores get signaled again. Drawing
+        // without it waits for all of them, so frames are still presented in order.
+        let in_flight: Vec<FrameHandle> = if asynchronous {
+            self.in_flight[frame_index].take().into_iter().collect()
+        } else {
+            self.in_flight.iter_mut().filter_map(Option::take).collect()
+        };
+        let mut presented_optimally = true;
+        for handle in in_flight {
+            presented_optimally &= handle
+                .outcome()
+                .map_err(RendererError::in_context(RendererError::Presentation))?;
+        }
+        frame
+            .wait(device)
+            .map_err(RendererError::in_context(RendererError::Submission))?;
+        stem.staging_belt().recycle(frame_index);
+        stem.retired_buffers().recycle(device, frame_index);
+        stem.retired_textures().recycle(device, frame_index);
+        stem.descriptor_allocator()
+            .recycle(device, frame_index)
+            .map_err(RendererError::in_context(RendererError::Submission))?;
 
-        device.wait_for_fences(&[presentation_fence], true, u64::MAX)?;
-        device.reset_fences(&[presentation_fence])?;
+        // Whatever this frame recorded last time it was in flight is finished now
+        let gpu_times = match stem.timestamp_period() {
+            Some(tick_ns) => frame
+                .read_pass_times(device, tick_ns)
+                .map_err(RendererError::in_context(RendererError::Submission))?,
+            None => None,
+        };
+        let statistics = if pipeline_statistics {
+            frame
+                .read_pipeline_statistics(device)
+                .map_err(RendererError::in_context(RendererError::Submission))?
+        } else {
+            None
+        };
+        let write_timestamp = |timestamp| {
+            if stem.timestamp_period().is_some() {
+                frame.write_timestamp(device, command_buffer, timestamp);
+            }
+        };
+        let begin_statistics = |timestamp| {
+            if pipeline_statistics {
+                frame.begin_statistics(device, command_buffer, timestamp);
+            }
+        };
+        let end_statistics = |timestamp| {
+            if pipeline_statistics {
+                frame.end_statistics(device, command_buffer, timestamp);
+            }
+        };
 
-        let (image_index, suboptimal_acquire) = swapchain_fn.acquire_next_image(
-            swapchain,
-            u64::MAX,
-            image_acquired_semaphore,
-            vk::Fence::null(),
-        )?;
+        // Headless frames have no presentation engine to synchronize with
+        let headless = stem.crown().is_headless();
+        let (image_index, suboptimal_acquire) = if headless {
+            (0, false)
+        } else {
+            swapchain_fn
+                .acquire_next_image(
+                    swapchain,
+                    u64::MAX,
+                    image_acquired_semaphore,
+                    vk::Fence::null(),
+                )
+                .map_err(RendererError::in_context(RendererError::Acquisition))?
+        };
+        let semaphore_count = if headless { 0 } else { 1 };
 
-        let command_buffer = command_buffer;
-        device.reset_command_buffer(
-            command_buffer,
-            vk::CommandBufferResetFlags::RELEASE_RESOURCES,
-        )?;
+        let recording = RendererError::in_context(RendererError::Recording);
+        device
+            .reset_command_buffer(
+                command_buffer,
+                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+            )
+            .map_err(&recording)?;
         let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
             .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
-        device.begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
+        device
+            .begin_command_buffer(command_buffer, &command_buffer_begin_info)
+            .map_err(&recording)?;
+        frame.reset_queries(device, command_buffer);
+        write_timestamp(Timestamp::Start);
+
+        // Drawn first, so the main view can sample them
+        begin_statistics(Timestamp::RenderTargets);
+        for (target, target_view) in targets.iter().zip(&target_views) {
+            stem.begin_label(command_buffer, "render target", [0.2, 0.8, 0.4, 1.0]);
+            target
+                .frond
+                .draw(
+                    command_buffer,
+                    frame_index,
+                    &self.graph,
+                    target.camera,
+                    &target.meshes,
+                    target_view,
+                    culling_mode,
+                    light_culling,
+                    lights,
+                    sun,
+                    ambient_light,
+                    environment,
+                    sky,
+                    water,
+                    time,
+                    tonemapping,
+                    grade,
+                )
+                .map_err(&recording)?;
+            stem.end_label(command_buffer);
+        }
+        end_statistics(Timestamp::RenderTargets);
+        write_timestamp(Timestamp::RenderTargets);
 
-        let view_matrix = view_matrix.into();
-        self.geometry.draw(command_buffer, view_matrix);
-        let draw_shadow = |shadow_view| self.geometry.draw_shadow(command_buffer, shadow_view);
-        self.lighting.draw(command_buffer, view_matrix, draw_shadow);
-        self.tonemapping.draw(command_buffer, image_index);
+        let tonemapping_input = match (depth_of_field, motion_blur) {
+            (_, Some(_)) => TonemappingInput::MotionBlur,
+            (Some(_), None) => TonemappingInput::DepthOfField,
+            (None, None) => TonemappingInput::Light,
+        };
+
+        let world_to_screen = view.world_to_screen;
+        let view_matrix = world_to_screen.into();
+        let eye = view.eye;
+        let mut cascades = Vec::new();
+        self.graph
+            .record(device, command_buffer, |pass| {
+                let (label, color) = pass.label();
+                stem.begin_label(command_buffer, label, color);
+                begin_statistics(pass.timestamp());
+                match pass {
+                    // Shadows still need every mesh, since ones offscreen can cast onscreen
+                    Pass::Geometry => match culling_mode {
+                        CullingMode::Cpu => {
+                            self.geometry.draw(
+                                command_buffer,
+                                &frame.secondary_command_buffers,
+                                frame_index,
+                                view_matrix,
+                                &previous_world_to_screen,
+                                &view.visible_meshes,
+                                None,
+                            )?;
+                        }
+                        CullingMode::Gpu => {
+                            let indirect_draws = self.culling.cull(
+                                command_buffer,
+                                frame_index,
+                                &world_to_screen,
+                                &view.opaque_meshes,
+                            )?;
+                            self.geometry.draw(
+                                command_buffer,
+                                &frame.secondary_command_buffers,
+                                frame_index,
+                                view_matrix,
+                                &previous_world_to_screen,
+                                &view.opaque_meshes,
+                                Some(&indirect_draws),
+                            )?;
+                        }
+                    },
+                    Pass::Shadow => {
+                        cascades = self.shadow.draw(
+                            command_buffer,
+                            view_matrix,
+                            &camera.projection,
+                            sun.unit_direction(),
+                            meshes,
+                        );
+                        self.shadow
+                            .draw_point_lights(command_buffer, &view.point_shadows);
+                    }
+                    Pass::LightClusters => self.lighting.assign_clusters(
+                        command_buffer,
+                        frame_index,
+                        view_matrix,
+                        lights,
+                        &view.point_shadows.slots,
+                        light_culling,
+                    )?,
+                    Pass::Lighting => self.lighting.draw(
+                        command_buffer,
+                        frame_index,
+                        view_matrix,
+                        lights,
+                        light_culling,
+                        sun,
+                        ambient_light,
+                        &cascades,
+                        environment,
+                        sky,
+                    )?,
+                    Pass::Water => self.water.draw(
+                        command_buffer,
+                        frame_index,
+                        &world_to_screen,
+                        &eye,
+                        sun,
+                        ambient_light,
+                        environment,
+                        water,
+                        time,
+                    )?,
+                    Pass::Transparency => self.transparency.draw(
+                        command_buffer,
+                        frame_index,
+                        &world_to_screen,
+                        &eye,
+                        sun,
+                        ambient_light,
+                        cascades.len(),
+                        environment,
+                        &view.transparent_meshes,
+                    )?,
+                    Pass::DebugDraw => self.debug_draw.draw(
+                        command_buffer,
+                        frame_index,
+                        &world_to_screen,
+                        debug_lines,
+                    )?,
+                    Pass::DepthOfField => {
+                        if let Some(depth_of_field) = depth_of_field {
+                            self.depth_of_field.draw(
+                                command_buffer,
+                                depth_of_field,
+                                &camera.projection,
+                            );
+                        }
+                    }
+                    Pass::MotionBlur => {
+                        if let Some(motion_blur) = motion_blur {
+                            self.motion_blur.draw(
+                                command_buffer,
+                                motion_blur,
+                                depth_of_field.is_some(),
+                                &world_to_screen,
+                                &previous_world_to_screen,
+                            );
+                        }
+                    }
+                    Pass::Tonemapping => self.tonemapping.draw(
+                        command_buffer,
+                        image_index,
+                        tonemapping,
+                        grade,
+                        tonemapping_input,
+                    ),
+                    Pass::Upscaling => {
+                        if let Some((frond, upscaling)) = self.upscaling.as_ref().zip(upscaling) {
+                            frond.draw(command_buffer, image_index, upscaling.sharpening);
+                        }
+                    }
+                    Pass::Ui => {
+                        if let Some((ui, ui_texture)) = ui {
+                            self.ui.draw(
+                                command_buffer,
+                                frame_index,
+                                image_index,
+                                ui,
+                                ui_texture,
+                            )?;
+                        }
+                    }
+                }
+                end_statistics(pass.timestamp());
+                stem.end_label(command_buffer);
+                write_timestamp(pass.timestamp());
+
+                if let Some(hook) = pass.plugin_hook().filter(|_| !plugins.is_empty()) {
+                    let context = PluginContext {
+                        device,
+                        capabilities: stem.capabilities(),
+                        command_buffer,
+                        descriptor_allocator: stem.descriptor_allocator(),
+                        frame_index,
+                        images: FrameImages::new(frond, image_index)?,
+                        resolution: frond.resolution(),
+                        output_resolution: frond.output_resolution(),
+                        view: view_matrix,
+                    };
+                    for plugin in plugins.iter_mut() {
+                        plugin.record(hook, &context)?;
+                    }
+                }
+                Ok(())
+            })
+            .map_err(&recording)?;
 
-        device.end_command_buffer(command_buffer)?;
+        device
+            .end_command_buffer(command_buffer)
+            .map_err(&recording)?;
 
-        let wait_semaphores = [image_acquired_semaphore];
-        let wait_dst_stage_mask = [vk::PipelineStageFlags::TOP_OF_PIPE];
-        let command_buffers = [command_buffer];
+        let render_complete_semaphore = if headless {
+            vk::Semaphore::null()
+        } else {
+            frond.render_complete(image_index)
+        };
+        let wait_semaphores = [(image_acquired_semaphore, sync::SWAPCHAIN_ACQUIRE_WAIT_STAGE)];
         let signal_semaphores = [render_complete_semaphore];
-        let submit_info = vk::SubmitInfo::builder()
-            .wait_semaphores(&wait_semaphores)
-            .wait_dst_stage_mask(&wait_dst_stage_mask)
-            .command_buffers(&command_buffers)
-            .signal_semaphores(&signal_semaphores);
-        let submit_infos = [submit_info.build()];
-        device.queue_submit(queues.graphics, &submit_infos, presentation_fence)?;
+        let queue_guard = stem.lock_queues();
+        frame
+            .submit(
+                device,
+                queues.graphics,
+                &wait_semaphores[..semaphore_count],
+                &signal_semaphores[..semaphore_count],
+            )
+            .map_err(RendererError::in_context(RendererError::Submission))?;
+        drop(queue_guard);
+
+        let optimal = !suboptimal_acquire && presented_optimally;
+        if asynchronous {
+            let presenter = self.presenter.get_or_insert_with(Presenter::new);
+            let handle = presenter.present(PresentJob {
+                frame_index,
+                stem: stem.clone(),
+                swapchain: (!headless).then(|| (swapchain, image_index, render_complete_semaphore)),
+            });
+            self.in_flight[frame_index] = Some(handle.clone());
+            return Ok((optimal, gpu_times, statistics, Some(handle)));
+        }
+        if headless {
+            return Ok((optimal, gpu_times, statistics, None));
+        }
 
         let wait_semaphores = [render_complete_semaphore];
         let swapchains = [swapchain];
@@ -249,22 +2179,312 @@ impl RendererFrond {
             .wait_semaphores(&wait_semaphores)
             .swapchains(&swapchains)
             .image_indices(&image_indices);
-        let suboptimal_present = swapchain_fn.queue_present(queues.present, &present_info)?;
+        // Rejected presents still wait on the semaphore, so only the frame is lost
+        let queue_guard = stem.lock_queues();
+        let suboptimal_present = match swapchain_fn.queue_present(queues.present, &present_info) {
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => true,
+            x => x.map_err(RendererError::in_context(RendererError::Presentation))?,
+        };
+        drop(queue_guard);
 
-        Ok(!suboptimal_acquire && !suboptimal_present)
+        Ok((optimal && !suboptimal_present, gpu_times, statistics, None))
     }
 
     fn take_swapchain(self) -> SharedFrondSwapchain {
         let Self {
+            culling,
+            debug_draw,
+            depth_of_field,
+            drawn: _,
             geometry,
+            graph: _,
+            in_flight: _,
             lighting,
+            motion_blur,
+            presenter,
+            shadow,
             shared,
+            stale: _,
+            suboptimal: _,
+            targets,
             tonemapping,
+            transparency,
+            ui,
+            upscaling,
+            water,
         } = self;
-        drop((geometry, lighting, tonemapping));
-        match Arc::try_unwrap(shared) {
-            Ok(shared) => shared.take_swapchain(),
-            _ => panic!("Cannot take swapchain from SharedFrond as something is holding onto it."),
+        // Finishes presenting to the swapchain before it's taken
+        drop(presenter);
+        drop((
+            culling,
+            debug_draw,
+            depth_of_field,
+            geometry,
+            lighting,
+            motion_blur,
+            shadow,
+            targets,
+            tonemapping,
+            transparency,
+            ui,
+            upscaling,
+            water,
+        ));
+        // Anything still holding onto the SharedFrond, like a plugin, finds its swapchain out of date
+        shared.take_swapchain()
+    }
+}
+
+// Each view's CPU side, worked out before anything is recorded
+struct PreparedView {
+    world_to_screen: na::Matrix4<f32>,
+    eye: na::Point3<f32>,
+    opaque_meshes: Vec<GpuMeshInstance>,  // sorted for drawing
+    visible_meshes: Vec<GpuMeshInstance>, // opaque meshes in view, if culled on the CPU
+    transparent_meshes: Vec<GpuMeshInstance>,
+    point_shadows: PointShadows,
+}
+
+impl PreparedView {
+    fn new(
+        camera: &Camera,
+        resolution: vk::Extent2D,
+        meshes: &[GpuMeshInstance],
+        culling_mode: CullingMode,
+        shadow: &ShadowFrond,
+        lights: &[Light],
+    ) -> Self {
+        // Blended meshes are drawn after lighting instead, though they still cast shadows
+        let (transparent_meshes, mut opaque_meshes): (Vec<_>, Vec<_>) = meshes
+            .iter()
+            .cloned()
+            .partition(|instance| instance.material.is_transparent());
+
+        let world_to_screen = camera.world_to_screen(resolution);
+        let eye = camera.position();
+        let (point_shadows, ()) = jobs::join(
+            || shadow.prepare_point_lights(lights, meshes),
+            || geometry::sort_draws(&mut opaque_meshes, &eye),
+        );
+        let visible_meshes = match culling_mode {
+            CullingMode::Cpu => Frustum::new(&world_to_screen).cull(&opaque_meshes),
+            CullingMode::Gpu => Vec::new(),
+        };
+        Self {
+            world_to_screen,
+            eye,
+            opaque_meshes,
+            visible_meshes,
+            transparent_meshes,
+            point_shadows,
         }
     }
 }
+
+struct PreparedTargetDraw<'a> {
+    camera: &'a Camera,
+    frond: Arc<RenderTargetFrond>,
+    meshes: Vec<GpuMeshInstance>,
+}
+
+// The scene passes of RendererFrond, drawing into a RenderTarget's texture instead of the surface
+struct RenderTargetFrond {
+    culling: Arc<CullingFrond>,
+    geometry: Arc<GeometryFrond>,
+    lighting: Arc<LightingFrond>,
+    shadow: Arc<ShadowFrond>,
+    shared: Arc<SharedFrond>,
+    tonemapping: Arc<TonemappingFrond>,
+    transparency: Arc<TransparencyFrond>,
+    water: Arc<WaterFrond>,
+}
+
+impl RenderTargetFrond {
+    fn new(
+        stem: &RendererStem,
+        texture: Arc<GpuTexture>,
+        shadow_settings: ShadowSettings,
+    ) -> Result<Self, RendererError> {
+        let shared = Arc::new(SharedFrond::new_render_target(
+            stem.shared.clone(),
+            shadow_settings,
+            texture,
+        )?);
+        let culling = Arc::new(
+            CullingFrond::new(stem.culling.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("culling"))?,
+        );
+        let geometry = Arc::new(
+            GeometryFrond::new(stem.geometry.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("geometry"))?,
+        );
+        let lighting = Arc::new(
+            LightingFrond::new(stem.lighting.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("lighting"))?,
+        );
+        let shadow = Arc::new(
+            ShadowFrond::new(stem.shadow.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("shadow"))?,
+        );
+        let tonemapping = Arc::new(
+            TonemappingFrond::new(stem.target_tonemapping.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("tonemapping"))?,
+        );
+        let transparency = Arc::new(
+            TransparencyFrond::new(stem.transparency.clone(), &lighting, shared.clone())
+                .map_err(RendererError::pipeline_creation("transparency"))?,
+        );
+        let water = Arc::new(
+            WaterFrond::new(stem.water.clone(), shared.clone())
+                .map_err(RendererError::pipeline_creation("water"))?,
+        );
+
+        Ok(Self {
+            culling,
+            geometry,
+            lighting,
+            shadow,
+            shared,
+            tonemapping,
+            transparency,
+            water,
+        })
+    }
+
+    // Recorded into the main view's command buffer, but with neither timestamps nor plugins. The
+    // frame's secondary command buffers are left to the main view, so geometry is recorded inline.
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        graph: &RenderGraph<Pass>,
+        camera: &Camera,
+        meshes: &[GpuMeshInstance],
+        view: &PreparedView,
+        culling_mode: CullingMode,
+        light_culling: LightCulling,
+        lights: &[Light],
+        sun: &DirectionalLight,
+        ambient_light: &AmbientLight,
+        environment: &GpuEnvironment,
+        sky: Option<&Sky>,
+        water: &[Water],
+        time: f32,
+        tonemapping: TonemappingOperator,
+        grade: Option<&ColorGrade>,
+    ) -> VkResult<()> {
+        let device = self.shared.device();
+
+        let world_to_screen = view.world_to_screen;
+        let view_matrix = world_to_screen.into();
+        let eye = view.eye;
+        let mut cascades = Vec::new();
+        let stem = self.shared.stem();
+        graph.record(device, command_buffer, |pass| {
+            let (label, color) = pass.label();
+            stem.begin_label(command_buffer, label, color);
+            match pass {
+                Pass::Geometry => match culling_mode {
+                    CullingMode::Cpu => {
+                        self.geometry.draw(
+                            command_buffer,
+                            &[],
+                            frame_index,
+                            view_matrix,
+                            &world_to_screen,
+                            &view.visible_meshes,
+                            None,
+                        )?;
+                    }
+                    CullingMode::Gpu => {
+                        let indirect_draws = self.culling.cull(
+                            command_buffer,
+                            frame_index,
+                            &world_to_screen,
+                            &view.opaque_meshes,
+                        )?;
+                        self.geometry.draw(
+                            command_buffer,
+                            &[],
+                            frame_index,
+                            view_matrix,
+                            &world_to_screen,
+                            &view.opaque_meshes,
+                            Some(&indirect_draws),
+                        )?;
+                    }
+                },
+                Pass::Shadow => {
+                    cascades = self.shadow.draw(
+                        command_buffer,
+                        view_matrix,
+                        &camera.projection,
+                        sun.unit_direction(),
+                        meshes,
+                    );
+                    self.shadow
+                        .draw_point_lights(command_buffer, &view.point_shadows);
+                }
+                Pass::LightClusters => self.lighting.assign_clusters(
+                    command_buffer,
+                    frame_index,
+                    view_matrix,
+                    lights,
+                    &view.point_shadows.slots,
+                    light_culling,
+                )?,
+                Pass::Lighting => self.lighting.draw(
+                    command_buffer,
+                    frame_index,
+                    view_matrix,
+                    lights,
+                    light_culling,
+                    sun,
+                    ambient_light,
+                    &cascades,
+                    environment,
+                    sky,
+                )?,
+                Pass::Water => self.water.draw(
+                    command_buffer,
+                    frame_index,
+                    &world_to_screen,
+                    &eye,
+                    sun,
+                    ambient_light,
+                    environment,
+                    water,
+                    time,
+                )?,
+                Pass::Transparency => self.transparency.draw(
+                    command_buffer,
+                    frame_index,
+                    &world_to_screen,
+                    &eye,
+                    sun,
+                    ambient_light,
+                    cascades.len(),
+                    environment,
+                    &view.transparent_meshes,
+                )?,
+                Pass::Tonemapping => self.tonemapping.draw(
+                    command_buffer,
+                    0,
+                    tonemapping,
+                    grade,
+                    TonemappingInput::Light,
+                ),
+                // Debug lines, post-processing and UI are only meant for the main view. Targets
+                // have no previous frame, so their velocities only cover mesh motion.
+                Pass::DebugDraw
+                | Pass::DepthOfField
+                | Pass::MotionBlur
+                | Pass::Upscaling
+                | Pass::Ui => (),
+            }
+            stem.end_label(command_buffer);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,628 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::{
+    camera::{Camera, Projection},
+    light::{AmbientLight, DirectionalLight, Light},
+    material::{AlphaMode, Material, MaterialHandle},
+    mesh::{Mesh, MeshInstance, SkinVertex, Vertex},
+    renderer::{Renderer, RendererError},
+    sampler::{SamplerSettings, TextureAddressMode, TextureFilter},
+    texture::Texture,
+};
+
+const MAGIC: &[u8; 8] = b"NGREPLAY";
+const VERSION: u32 = 1;
+const NONE: u32 = u32::MAX; // in place of an index
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Couldn't read or write the recording")]
+    Io(#[from] io::Error),
+    #[error("Not a recording, or one from another version")]
+    UnknownFormat,
+    #[error("Recording refers to something it doesn't contain")]
+    Malformed,
+}
+
+// What a draw was asked to draw, after the time of day moved the sun and retained mesh instances
+// were added to the rest
+#[derive(Clone, Debug)]
+pub struct RecordedFrame {
+    pub camera: Camera,
+    pub sun: DirectionalLight,
+    pub ambient_light: AmbientLight,
+    pub lights: Vec<Light>,
+    pub instances: Vec<MeshInstance>,
+}
+
+impl RecordedFrame {
+    // Best into a renderer of its own without retained mesh instances or a time of day, since
+    // those would be drawn on top of or in place of what was recorded
+    pub fn draw(&self, renderer: &mut Renderer) -> Result<bool, RendererError> {
+        renderer.set_sun(self.sun);
+        renderer.set_ambient_light(self.ambient_light);
+        renderer.set_lights(&self.lights);
+        renderer.draw(&self.camera, &self.instances)
+    }
+}
+
+// A run of draws, as recorded by Renderer::start_recording, to draw again later against the same
+// workload, e.g. to benchmark changes to the renderer. Only the camera, lights and meshes are
+// recorded, not the environment, sky, water, UI, render targets or any other settings.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    frames: Vec<RecordedFrame>,
+}
+
+impl Recording {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn push(&mut self, frame: RecordedFrame) {
+        self.frames.push(frame);
+    }
+
+    pub fn frames(&self) -> &[RecordedFrame] {
+        &self.frames
+    }
+
+    // Every mesh, material and texture once, however many frames share it. Render targets'
+    // textures are saved as plain white, since what was drawn into them isn't recorded.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ReplayError> {
+        let mut meshes = Shared::default();
+        let mut materials = Shared::default();
+        let mut textures = Shared::default();
+        for instance in self.frames.iter().flat_map(|frame| &frame.instances) {
+            meshes.insert(instance.mesh.id(), &instance.mesh);
+            if let Some(handle) = &instance.material {
+                materials.insert(handle.id(), handle);
+                for texture in textures_of(handle.material()).iter().copied().flatten() {
+                    textures.insert(texture.id(), texture);
+                }
+            }
+        }
+
+        let mut writer = Writer(BufWriter::new(File::create(path)?));
+        writer.bytes(MAGIC)?;
+        writer.u32(VERSION)?;
+
+        writer.len(textures.items.len())?;
+        for texture in &textures.items {
+            if texture.is_render_target() {
+                writer.u32(1)?;
+                writer.u32(1)?;
+                writer.bool(true)?;
+                writer.bytes(&[255; 4])?;
+            } else {
+                writer.u32(texture.width())?;
+                writer.u32(texture.height())?;
+                writer.bool(texture.is_srgb())?;
+                writer.bytes(texture.pixels())?;
+            }
+        }
+
+        writer.len(materials.items.len())?;
+        for handle in &materials.items {
+            let material = handle.material();
+            let [albedo_texture, metallic_roughness_texture, emissive_texture, normal_map] =
+                textures_of(material).map(|texture| texture.map(|texture| texture.id()));
+            writer.f32s(&[material.albedo.x, material.albedo.y, material.albedo.z])?;
+            writer.index(textures.index(albedo_texture))?;
+            writer.f32(material.alpha)?;
+            writer.u8(match material.alpha_mode {
+                AlphaMode::Opaque => 0,
+                AlphaMode::Mask => 1,
+                AlphaMode::Dither => 2,
+                AlphaMode::Blend => 3,
+            })?;
+            writer.f32s(&[material.alpha_cutoff, material.metallic, material.roughness])?;
+            writer.index(textures.index(metallic_roughness_texture))?;
+            writer.f32s(&[
+                material.emissive.x,
+                material.emissive.y,
+                material.emissive.z,
+            ])?;
+            writer.index(textures.index(emissive_texture))?;
+            writer.index(textures.index(normal_map))?;
+            writer.u8(match material.sampler.filter {
+                TextureFilter::Nearest => 0,
+                TextureFilter::Linear => 1,
+            })?;
+            writer.u8(match material.sampler.address_mode {
+                TextureAddressMode::Repeat => 0,
+                TextureAddressMode::MirroredRepeat => 1,
+                TextureAddressMode::ClampToEdge => 2,
+            })?;
+            writer.u32(material.sampler.max_anisotropy)?;
+        }
+
+        writer.len(meshes.items.len())?;
+        for mesh in &meshes.items {
+            writer.len(mesh.vertices().len())?;
+            for vertex in mesh.vertices() {
+                writer.f32s(&[vertex.position.x, vertex.position.y, vertex.position.z])?;
+                writer.f32s(&[vertex.normal.x, vertex.normal.y, vertex.normal.z])?;
+                writer.f32s(&[vertex.color.x, vertex.color.y, vertex.color.z])?;
+                writer.f32s(&[vertex.tex_coord.x, vertex.tex_coord.y])?;
+                let tangent = vertex.tangent;
+                writer.f32s(&[tangent.x, tangent.y, tangent.z, tangent.w])?;
+            }
+            writer.len(mesh.indices().len())?;
+            for &index in mesh.indices() {
+                writer.u32(index)?;
+            }
+            writer.bool(mesh.skin().is_some())?;
+            for skin_vertex in mesh.skin().unwrap_or_default() {
+                for &joint in &skin_vertex.joints {
+                    writer.bytes(&joint.to_le_bytes())?;
+                }
+                let weights = skin_vertex.weights;
+                writer.f32s(&[weights.x, weights.y, weights.z, weights.w])?;
+            }
+        }
+
+        writer.len(self.frames.len())?;
+        for frame in &self.frames {
+            writer.matrix(&frame.camera.transform)?;
+            match frame.camera.projection {
+                Projection::Perspective { fov, near } => {
+                    writer.u8(0)?;
+                    writer.f32s(&[fov, near])?;
+                }
+                Projection::Orthographic { height, near, far } => {
+                    writer.u8(1)?;
+                    writer.f32s(&[height, near, far])?;
+                }
+                Projection::Asymmetric {
+                    left,
+                    right,
+                    up,
+                    down,
+                    near,
+                } => {
+                    writer.u8(2)?;
+                    writer.f32s(&[left, right, up, down, near])?;
+                }
+            }
+            let sun = frame.sun;
+            writer.f32s(&[sun.direction.x, sun.direction.y, sun.direction.z])?;
+            writer.f32s(&[sun.color.x, sun.color.y, sun.color.z, sun.intensity])?;
+            let ambient = frame.ambient_light;
+            writer.f32s(&[
+                ambient.color.x,
+                ambient.color.y,
+                ambient.color.z,
+                ambient.intensity,
+            ])?;
+
+            writer.len(frame.lights.len())?;
+            for light in &frame.lights {
+                match *light {
+                    Light::Point {
+                        position,
+                        color,
+                        range,
+                    } => {
+                        writer.u8(0)?;
+                        writer.f32s(&[position.x, position.y, position.z])?;
+                        writer.f32s(&[color.x, color.y, color.z, range])?;
+                    }
+                    Light::Spot {
+                        position,
+                        direction,
+                        color,
+                        range,
+                        angle,
+                    } => {
+                        writer.u8(1)?;
+                        writer.f32s(&[position.x, position.y, position.z])?;
+                        writer.f32s(&[direction.x, direction.y, direction.z])?;
+                        writer.f32s(&[color.x, color.y, color.z, range, angle])?;
+                    }
+                }
+            }
+
+            writer.len(frame.instances.len())?;
+            for instance in &frame.instances {
+                writer.index(meshes.index(Some(instance.mesh.id())))?;
+                let material = instance.material.as_ref().map(MaterialHandle::id);
+                writer.index(materials.index(material))?;
+                writer.matrix(&instance.transform)?;
+                writer.bool(instance.previous_transform.is_some())?;
+                if let Some(previous_transform) = &instance.previous_transform {
+                    writer.matrix(previous_transform)?;
+                }
+                writer.bool(instance.joint_matrices.is_some())?;
+                if let Some(joint_matrices) = &instance.joint_matrices {
+                    writer.len(joint_matrices.len())?;
+                    for joint_matrix in joint_matrices.iter() {
+                        writer.matrix(joint_matrix)?;
+                    }
+                }
+                writer.bool(instance.is_static)?;
+            }
+        }
+
+        writer.0.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        let mut reader = Reader(BufReader::new(File::open(path)?));
+        let mut magic = [0; 8];
+        reader.0.read_exact(&mut magic)?;
+        if &magic != MAGIC || reader.u32()? != VERSION {
+            return Err(ReplayError::UnknownFormat);
+        }
+
+        let textures = (0..reader.u32()?)
+            .map(|_| {
+                let (width, height) = (reader.u32()?, reader.u32()?);
+                let srgb = reader.bool()?;
+                let len = (width as u64)
+                    .checked_mul(height as u64)
+                    .and_then(|len| len.checked_mul(4))
+                    .filter(|&len| len > 0)
+                    .ok_or(ReplayError::Malformed)?;
+                // Read rather than allocated up front, so a corrupt size can't ask for more memory
+                // than the file holds
+                let mut pixels = Vec::new();
+                reader.0.by_ref().take(len).read_to_end(&mut pixels)?;
+                if pixels.len() as u64 != len {
+                    return Err(ReplayError::Malformed);
+                }
+                Ok(Arc::new(if srgb {
+                    Texture::new(width, height, pixels)
+                } else {
+                    Texture::new_linear(width, height, pixels)
+                }))
+            })
+            .collect::<Result<Vec<_>, ReplayError>>()?;
+        let texture = |index: Option<u32>| {
+            index
+                .map(|index| textures.get(index as usize).cloned())
+                .map(|texture| texture.ok_or(ReplayError::Malformed))
+                .transpose()
+        };
+
+        let materials = (0..reader.u32()?)
+            .map(|_| {
+                let albedo = reader.vector3()?;
+                let albedo_texture = texture(reader.index()?)?;
+                let alpha = reader.f32()?;
+                let alpha_mode = match reader.u8()? {
+                    0 => AlphaMode::Opaque,
+                    1 => AlphaMode::Mask,
+                    2 => AlphaMode::Dither,
+                    3 => AlphaMode::Blend,
+                    _ => return Err(ReplayError::Malformed),
+                };
+                let alpha_cutoff = reader.f32()?;
+                let metallic = reader.f32()?;
+                let roughness = reader.f32()?;
+                let metallic_roughness_texture = texture(reader.index()?)?;
+                let emissive = reader.vector3()?;
+                let emissive_texture = texture(reader.index()?)?;
+                let normal_map = texture(reader.index()?)?;
+                let filter = match reader.u8()? {
+                    0 => TextureFilter::Nearest,
+                    1 => TextureFilter::Linear,
+                    _ => return Err(ReplayError::Malformed),
+                };
+                let address_mode = match reader.u8()? {
+                    0 => TextureAddressMode::Repeat,
+                    1 => TextureAddressMode::MirroredRepeat,
+                    2 => TextureAddressMode::ClampToEdge,
+                    _ => return Err(ReplayError::Malformed),
+                };
+                let max_anisotropy = reader.u32()?;
+                Ok(MaterialHandle::new(Material {
+                    albedo,
+                    albedo_texture,
+                    alpha,
+                    alpha_mode,
+                    alpha_cutoff,
+                    metallic,
+                    roughness,
+                    metallic_roughness_texture,
+                    emissive,
+                    emissive_texture,
+                    normal_map,
+                    sampler: SamplerSettings {
+                        filter,
+                        address_mode,
+                        max_anisotropy,
+                    },
+                }))
+            })
+            .collect::<Result<Vec<_>, ReplayError>>()?;
+
+        let meshes = (0..reader.u32()?)
+            .map(|_| {
+                let vertices = (0..reader.u32()?)
+                    .map(|_| {
+                        Ok(Vertex {
+                            position: reader.point3()?,
+                            normal: reader.vector3()?,
+                            color: reader.vector3()?,
+                            tex_coord: [reader.f32()?, reader.f32()?].into(),
+                            tangent: [reader.f32()?, reader.f32()?, reader.f32()?, reader.f32()?]
+                                .into(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, ReplayError>>()?;
+                let indices = (0..reader.u32()?)
+                    .map(|_| reader.u32())
+                    .collect::<Result<Vec<_>, ReplayError>>()?;
+                let skin = if reader.bool()? {
+                    let skin = (0..vertices.len())
+                        .map(|_| {
+                            let mut joints = [0; 4];
+                            for joint in &mut joints {
+                                let mut bytes = [0; 2];
+                                reader.0.read_exact(&mut bytes)?;
+                                *joint = u16::from_le_bytes(bytes);
+                            }
+                            let weights =
+                                [reader.f32()?, reader.f32()?, reader.f32()?, reader.f32()?];
+                            Ok(SkinVertex {
+                                joints,
+                                weights: weights.into(),
+                            })
+                        })
+                        .collect::<Result<Vec<_>, ReplayError>>()?;
+                    Some(skin)
+                } else {
+                    None
+                };
+                // Mesh::new panics on these rather than failing
+                let in_bounds = indices
+                    .iter()
+                    .all(|&index| (index as usize) < vertices.len());
+                if indices.is_empty() || indices.len() % 3 != 0 || !in_bounds {
+                    return Err(ReplayError::Malformed);
+                }
+                let mesh = Mesh::new(vertices, indices);
+                Ok(Arc::new(match skin {
+                    Some(skin) => mesh.with_skin(skin),
+                    None => mesh,
+                }))
+            })
+            .collect::<Result<Vec<_>, ReplayError>>()?;
+
+        let frames = (0..reader.u32()?)
+            .map(|_| {
+                let transform = reader.matrix()?;
+                let projection = match reader.u8()? {
+                    0 => Projection::Perspective {
+                        fov: reader.f32()?,
+                        near: reader.f32()?,
+                    },
+                    1 => Projection::Orthographic {
+                        height: reader.f32()?,
+                        near: reader.f32()?,
+                        far: reader.f32()?,
+                    },
+                    2 => Projection::Asymmetric {
+                        left: reader.f32()?,
+                        right: reader.f32()?,
+                        up: reader.f32()?,
+                        down: reader.f32()?,
+                        near: reader.f32()?,
+                    },
+                    _ => return Err(ReplayError::Malformed),
+                };
+                let sun = DirectionalLight {
+                    direction: reader.vector3()?,
+                    color: reader.vector3()?,
+                    intensity: reader.f32()?,
+                };
+                let ambient_light = AmbientLight {
+                    color: reader.vector3()?,
+                    intensity: reader.f32()?,
+                };
+
+                let lights = (0..reader.u32()?)
+                    .map(|_| match reader.u8()? {
+                        0 => Ok(Light::Point {
+                            position: reader.point3()?,
+                            color: reader.vector3()?,
+                            range: reader.f32()?,
+                        }),
+                        1 => Ok(Light::Spot {
+                            position: reader.point3()?,
+                            direction: reader.vector3()?,
+                            color: reader.vector3()?,
+                            range: reader.f32()?,
+                            angle: reader.f32()?,
+                        }),
+                        _ => Err(ReplayError::Malformed),
+                    })
+                    .collect::<Result<Vec<_>, ReplayError>>()?;
+
+                let instances = (0..reader.u32()?)
+                    .map(|_| {
+                        let mesh = reader
+                            .index()?
+                            .and_then(|index| meshes.get(index as usize))
+                            .ok_or(ReplayError::Malformed)?;
+                        let material = reader
+                            .index()?
+                            .map(|index| {
+                                materials.get(index as usize).ok_or(ReplayError::Malformed)
+                            })
+                            .transpose()?;
+                        let transform = reader.matrix()?;
+                        let previous_transform = if reader.bool()? {
+                            Some(reader.matrix()?)
+                        } else {
+                            None
+                        };
+                        let joint_matrices = if reader.bool()? {
+                            let joint_matrices = (0..reader.u32()?)
+                                .map(|_| reader.matrix())
+                                .collect::<Result<Vec<_>, ReplayError>>()?;
+                            Some(joint_matrices.into())
+                        } else {
+                            None
+                        };
+                        Ok(MeshInstance {
+                            mesh: mesh.clone(),
+                            material: material.cloned(),
+                            transform,
+                            previous_transform,
+                            joint_matrices,
+                            is_static: reader.bool()?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, ReplayError>>()?;
+
+                Ok(RecordedFrame {
+                    camera: Camera {
+                        transform,
+                        projection,
+                    },
+                    sun,
+                    ambient_light,
+                    lights,
+                    instances,
+                })
+            })
+            .collect::<Result<Vec<_>, ReplayError>>()?;
+        Ok(Self { frames })
+    }
+}
+
+// In the order the material binds them
+fn textures_of(material: &Material) -> [Option<&Arc<Texture>>; 4] {
+    [
+        material.albedo_texture.as_ref(),
+        material.metallic_roughness_texture.as_ref(),
+        material.emissive_texture.as_ref(),
+        material.normal_map.as_ref(),
+    ]
+}
+
+// Things saved once each, by ID, and referred to by their index thereafter
+struct Shared<'a, T> {
+    indices: HashMap<u64, u32>,
+    items: Vec<&'a T>,
+}
+
+impl<T> Default for Shared<'_, T> {
+    fn default() -> Self {
+        Self {
+            indices: HashMap::new(),
+            items: Vec::new(),
+        }
+    }
+}
+
+impl<'a, T> Shared<'a, T> {
+    fn insert(&mut self, id: u64, item: &'a T) {
+        let items = &mut self.items;
+        self.indices.entry(id).or_insert_with(|| {
+            items.push(item);
+            items.len() as u32 - 1
+        });
+    }
+
+    fn index(&self, id: Option<u64>) -> Option<u32> {
+        id.map(|id| self.indices[&id])
+    }
+}
+
+// Everything's little-endian, with lengths and indices as u32s
+struct Writer<W>(W);
+
+impl<W: Write> Writer<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.write_all(bytes)
+    }
+
+    fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.bytes(&[value])
+    }
+
+    fn bool(&mut self, value: bool) -> io::Result<()> {
+        self.u8(value as u8)
+    }
+
+    fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn len(&mut self, len: usize) -> io::Result<()> {
+        self.u32(len as u32)
+    }
+
+    fn index(&mut self, index: Option<u32>) -> io::Result<()> {
+        self.u32(index.unwrap_or(NONE))
+    }
+
+    fn f32(&mut self, value: f32) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn f32s(&mut self, values: &[f32]) -> io::Result<()> {
+        values.iter().try_for_each(|&value| self.f32(value))
+    }
+
+    fn matrix(&mut self, matrix: &mint::ColumnMatrix4<f32>) -> io::Result<()> {
+        let columns: [[f32; 4]; 4] = (*matrix).into();
+        columns.iter().try_for_each(|column| self.f32s(column))
+    }
+}
+
+struct Reader<R>(R);
+
+impl<R: Read> Reader<R> {
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut bytes = [0; 1];
+        self.0.read_exact(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.0.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn index(&mut self) -> io::Result<Option<u32>> {
+        Ok(Some(self.u32()?).filter(|&index| index != NONE))
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        let mut bytes = [0; 4];
+        self.0.read_exact(&mut bytes)?;
+        Ok(f32::from_le_bytes(bytes))
+    }
+
+    fn vector3(&mut self) -> io::Result<mint::Vector3<f32>> {
+        Ok([self.f32()?, self.f32()?, self.f32()?].into())
+    }
+
+    fn point3(&mut self) -> io::Result<mint::Point3<f32>> {
+        Ok([self.f32()?, self.f32()?, self.f32()?].into())
+    }
+
+    fn matrix(&mut self) -> io::Result<mint::ColumnMatrix4<f32>> {
+        let mut columns = [[0.0; 4]; 4];
+        for value in columns.iter_mut().flatten() {
+            *value = self.f32()?;
+        }
+        Ok(columns.into())
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+// Textures aren't demoted below this on their shorter side, but evicted instead
+const MIN_DEMOTED_SIZE: u32 = 32;
+
+// Tracks the device memory of material textures and when each was last drawn with, so that
+// scenes larger than the budget can still fit: the least recently used textures are demoted to
+// lower resolutions, then evicted entirely once they're small. Either way, they're restreamed at
+// full resolution once they're drawn with again and there's room.
+#[derive(Default)]
+pub struct Residency {
+    budget: Option<u64>, // bytes; unlimited if None
+    frame: u64,
+    textures: HashMap<u64, Resident>, // by texture ID
+}
+
+struct Resident {
+    bytes: u64,
+    demotion: u32,  // mips dropped from the top
+    last_used: u64, // frame
+    size: (u32, u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResidencyChange {
+    Demote(u64, u32), // texture ID and mips to drop from the top
+    Evict(u64),
+    Promote(u64), // back to full resolution
+}
+
+impl Residency {
+    pub fn set_budget(&mut self, budget: Option<u64>) {
+        self.budget = budget;
+    }
+
+    // Size is at full resolution, whatever the demotion
+    pub fn uploaded(&mut self, id: u64, size: (u32, u32), bytes: u64, demotion: u32) {
+        let last_used = self
+            .textures
+            .get(&id)
+            .map_or(self.frame, |resident| resident.last_used);
+        self.textures.insert(
+            id,
+            Resident {
+                bytes,
+                demotion,
+                last_used,
+                size,
+            },
+        );
+    }
+
+    pub fn forget(&mut self, id: u64) {
+        self.textures.remove(&id);
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(u64) -> bool) {
+        self.textures.retain(|&id, _| keep(id));
+    }
+
+    pub fn touch(&mut self, id: u64) {
+        if let Some(resident) = self.textures.get_mut(&id) {
+            resident.last_used = self.frame;
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.textures.values().map(|resident| resident.bytes).sum()
+    }
+
+    pub fn demoted(&self) -> usize {
+        self.textures
+            .values()
+            .filter(|resident| resident.demotion > 0)
+            .count()
+    }
+
+    // What to change to get within budget, ending the frame. Demoted textures drawn this frame
+    // are promoted if there's room for them at full resolution, taken to be four times their
+    // size for each mip dropped. Then textures not drawn this frame are demoted, least recently
+    // used first, until they'd be too small and are evicted instead. Nothing changes until the
+    // changes are made and reported back through uploaded and forget.
+    pub fn end_frame(&mut self) -> Vec<ResidencyChange> {
+        let frame = self.frame;
+        self.frame += 1;
+
+        let mut changes = Vec::new();
+        let budget = self.budget.unwrap_or(u64::MAX);
+        let mut bytes = self.bytes();
+        for (&id, resident) in self.textures.iter() {
+            if resident.demotion == 0 || resident.last_used != frame {
+                continue;
+            }
+            let promoted_bytes = resident.bytes << (2 * resident.demotion);
+            if bytes - resident.bytes + promoted_bytes <= budget {
+                bytes = bytes - resident.bytes + promoted_bytes;
+                changes.push(ResidencyChange::Promote(id));
+            }
+        }
+
+        let mut unused: Vec<_> = self
+            .textures
+            .iter()
+            .filter(|(_, resident)| resident.last_used != frame)
+            .collect();
+        unused.sort_by_key(|(_, resident)| resident.last_used);
+        for (&id, resident) in unused {
+            if bytes <= budget {
+                break;
+            }
+            let mut demotion = resident.demotion;
+            let mut demoted_bytes = resident.bytes;
+            let shorter_side = resident.size.0.min(resident.size.1);
+            while bytes - resident.bytes + demoted_bytes > budget
+                && shorter_side >> (demotion + 1) >= MIN_DEMOTED_SIZE
+            {
+                demotion += 1;
+                demoted_bytes /= 4;
+            }
+            if bytes - resident.bytes + demoted_bytes > budget {
+                bytes -= resident.bytes;
+                changes.push(ResidencyChange::Evict(id));
+            } else if demotion != resident.demotion {
+                bytes = bytes - resident.bytes + demoted_bytes;
+                changes.push(ResidencyChange::Demote(id, demotion));
+            }
+        }
+        changes
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::mesh::MeshInstance;
+
+// Refers to a mesh instance kept by the renderer between draws, until it's removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MeshInstanceHandle(u64);
+
+// The renderer's retained mesh instances. Draws share a sorted snapshot, which is only rebuilt
+// after something changes.
+#[derive(Default)]
+pub struct RetainedMeshes {
+    instances: HashMap<MeshInstanceHandle, MeshInstance>,
+    moved: Vec<MeshInstanceHandle>, // previous transforms to clear once drawn
+    next_handle: u64,
+    snapshot: Option<Arc<[MeshInstance]>>,
+}
+
+impl RetainedMeshes {
+    pub fn add(&mut self, instance: MeshInstance) -> MeshInstanceHandle {
+        let handle = MeshInstanceHandle(self.next_handle);
+        self.next_handle += 1;
+        self.instances.insert(handle, instance);
+        self.snapshot = None;
+        handle
+    }
+
+    pub fn get(&self, handle: MeshInstanceHandle) -> Option<&MeshInstance> {
+        self.instances.get(&handle)
+    }
+
+    pub fn get_mut(&mut self, handle: MeshInstanceHandle) -> Option<&mut MeshInstance> {
+        self.snapshot = None;
+        self.instances.get_mut(&handle)
+    }
+
+    // Keeps the transform it had when last drawn as its previous one, for a single draw
+    pub fn set_transform(
+        &mut self,
+        handle: MeshInstanceHandle,
+        transform: mint::ColumnMatrix4<f32>,
+    ) -> bool {
+        let instance = match self.instances.get_mut(&handle) {
+            Some(instance) => instance,
+            None => return false,
+        };
+        if instance.previous_transform.is_none() {
+            instance.previous_transform = Some(instance.transform);
+            self.moved.push(handle);
+        }
+        instance.transform = transform;
+        self.snapshot = None;
+        true
+    }
+
+    pub fn remove(&mut self, handle: MeshInstanceHandle) -> Option<MeshInstance> {
+        self.snapshot = None;
+        self.instances.remove(&handle)
+    }
+
+    pub fn clear(&mut self) {
+        self.instances.clear();
+        self.moved.clear();
+        self.snapshot = None;
+    }
+
+    // Sorted so instances sharing a mesh, then a material, are drawn one after another
+    pub fn snapshot(&mut self) -> Arc<[MeshInstance]> {
+        let instances = &self.instances;
+        self.snapshot
+            .get_or_insert_with(|| {
+                let mut handles: Vec<_> = instances.keys().copied().collect();
+                handles.sort_by_key(|handle| {
+                    let instance = &instances[handle];
+                    let material = instance.material.as_ref().map(|material| material.id());
+                    (instance.mesh.id(), material, *handle)
+                });
+                handles
+                    .iter()
+                    .map(|handle| instances[handle].clone())
+                    .collect()
+            })
+            .clone()
+    }
+
+    // After a successful draw, so instances that stopped moving aren't blurred
+    pub fn end_frame(&mut self) {
+        for handle in self.moved.drain(..) {
+            if let Some(instance) = self.instances.get_mut(&handle) {
+                instance.previous_transform = None;
+                self.snapshot = None;
+            }
+        }
+    }
+}
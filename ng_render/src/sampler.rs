@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+
+use crate::{
+    guard::{GuardableResource, Guarded},
+    shared::SharedStem,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TextureFilter {
+    Nearest, // also picks the nearest mip level
+    #[default]
+    Linear,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TextureAddressMode {
+    #[default]
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+// How a material's textures are sampled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerSettings {
+    pub filter: TextureFilter,
+    pub address_mode: TextureAddressMode,
+    pub max_anisotropy: u32, // 1 disables anisotropic filtering; clamped to what the device allows
+}
+
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        Self {
+            filter: Default::default(),
+            address_mode: Default::default(),
+            max_anisotropy: 16,
+        }
+    }
+}
+
+// Samplers are few and immutable, so there's one per distinct settings for as long as the cache
+// lives, rather than one per material
+pub struct SamplerCache {
+    samplers: Mutex<HashMap<SamplerSettings, vk::Sampler>>,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl SamplerCache {
+    pub fn new(shared_stem: Arc<SharedStem>) -> Self {
+        Self {
+            samplers: Mutex::new(HashMap::new()),
+            shared_stem,
+        }
+    }
+
+    pub fn get(&self, settings: SamplerSettings) -> VkResult<vk::Sampler> {
+        // Settings that the device would treat the same share a sampler
+        let settings = SamplerSettings {
+            max_anisotropy: settings
+                .max_anisotropy
+                .clamp(1, self.shared_stem.capabilities().max_sampler_anisotropy),
+            ..settings
+        };
+
+        let mut samplers = self.samplers.lock().unwrap();
+        if let Some(&sampler) = samplers.get(&settings) {
+            return Ok(sampler);
+        }
+
+        let sampler = unsafe {
+            let sampler = Self::create_sampler(self.shared_stem.device(), settings)?;
+            self.shared_stem.set_name(
+                *sampler,
+                &format!(
+                    "{:?} {:?} x{}",
+                    settings.filter, settings.address_mode, settings.max_anisotropy
+                ),
+            )?;
+            sampler.take()
+        };
+        samplers.insert(settings, sampler);
+        Ok(sampler)
+    }
+
+    unsafe fn create_sampler(
+        device: &ash::Device,
+        settings: SamplerSettings,
+    ) -> VkResult<Guarded<(vk::Sampler, &ash::Device)>> {
+        let (filter, mipmap_mode) = match settings.filter {
+            TextureFilter::Nearest => (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST),
+            TextureFilter::Linear => (vk::Filter::LINEAR, vk::SamplerMipmapMode::LINEAR),
+        };
+        let address_mode = match settings.address_mode {
+            TextureAddressMode::Repeat => vk::SamplerAddressMode::REPEAT,
+            TextureAddressMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+            TextureAddressMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        };
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(filter)
+            .min_filter(filter)
+            .mipmap_mode(mipmap_mode)
+            .address_mode_u(address_mode)
+            .address_mode_v(address_mode)
+            .address_mode_w(address_mode)
+            .anisotropy_enable(settings.max_anisotropy > 1)
+            .max_anisotropy(settings.max_anisotropy as f32)
+            .compare_enable(false)
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE)
+            .unnormalized_coordinates(false);
+        Ok(device
+            .create_sampler(&sampler_create_info, None)?
+            .guard_with(device))
+    }
+}
+
+impl Drop for SamplerCache {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            for (_, sampler) in self.samplers.get_mut().unwrap().drain() {
+                device.destroy_sampler(sampler, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use nalgebra as na;
+
+use crate::{
+    camera::{Camera, Projection},
+    light::Light,
+    mesh::MeshInstance,
+};
+
+// Stays unique after its node is removed, so stale ids can't reach a node that reused the slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    index: u32,
+    generation: u32,
+}
+
+// Whatever's attached is positioned relative to its node
+#[derive(Clone, Debug)]
+pub enum Attachment {
+    Mesh(MeshInstance), // both transforms are relative to the node's
+    Light(Light),
+    Camera(Projection), // looking along the node's +x, with +z up
+}
+
+#[derive(Debug)]
+struct Node {
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    transform: na::Matrix4<f32>, // relative to the parent
+    world_transform: na::Matrix4<f32>,
+    previous_world_transform: na::Matrix4<f32>, // as of the update before last
+    dirty: bool,                                // transform changed since the last update
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    generation: u32,
+    node: Option<Node>,
+}
+
+// A transform hierarchy. World transforms are cached, and update() recomputes only those of nodes
+// that moved, along with their descendants; reads in between see the last update's.
+#[derive(Debug, Default)]
+pub struct Scene {
+    free_slots: Vec<u32>,
+    roots: Vec<NodeId>,
+    slots: Vec<Slot>,
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // The new node's world transform is ready right away, with its parent's as of the last update
+    pub fn add_node(
+        &mut self,
+        parent: Option<NodeId>,
+        transform: mint::ColumnMatrix4<f32>,
+    ) -> NodeId {
+        let transform = na::Matrix4::from(transform);
+        let world_transform = match parent {
+            Some(parent) => self.node(parent).world_transform * transform,
+            None => transform,
+        };
+        let node = Node {
+            parent,
+            children: Vec::new(),
+            transform,
+            world_transform,
+            previous_world_transform: world_transform,
+            dirty: false,
+            attachments: Vec::new(),
+        };
+
+        let id = match self.free_slots.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.node = Some(node);
+                NodeId {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    node: Some(node),
+                });
+                NodeId {
+                    index: (self.slots.len() - 1) as _,
+                    generation: 0,
+                }
+            }
+        };
+        self.siblings_mut(parent).push(id);
+        id
+    }
+
+    // Along with all of its descendants
+    pub fn remove_node(&mut self, id: NodeId) {
+        let parent = self.node(id).parent;
+        self.siblings_mut(parent).retain(|&sibling| sibling != id);
+
+        let mut removed = vec![id];
+        while let Some(id) = removed.pop() {
+            let slot = &mut self.slots[id.index as usize];
+            let node = slot.node.take().unwrap();
+            slot.generation = slot.generation.wrapping_add(1);
+            self.free_slots.push(id.index);
+            removed.extend(node.children);
+        }
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.slots
+            .get(id.index as usize)
+            .is_some_and(|slot| slot.generation == id.generation && slot.node.is_some())
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).parent
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.node(id).children
+    }
+
+    // Keeps the node's transform relative to its parent, so it moves along with the new one
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) {
+        let mut ancestor = parent;
+        while let Some(ancestor_id) = ancestor {
+            assert_ne!(ancestor_id, id, "Scene node can't be its own ancestor");
+            ancestor = self.node(ancestor_id).parent;
+        }
+
+        let old_parent = self.node(id).parent;
+        self.siblings_mut(old_parent)
+            .retain(|&sibling| sibling != id);
+        self.siblings_mut(parent).push(id);
+        let node = self.node_mut(id);
+        node.parent = parent;
+        node.dirty = true;
+    }
+
+    pub fn transform(&self, id: NodeId) -> mint::ColumnMatrix4<f32> {
+        self.node(id).transform.into()
+    }
+
+    pub fn set_transform(&mut self, id: NodeId, transform: mint::ColumnMatrix4<f32>) {
+        let node = self.node_mut(id);
+        node.transform = transform.into();
+        node.dirty = true;
+    }
+
+    // As of the last update
+    pub fn world_transform(&self, id: NodeId) -> mint::ColumnMatrix4<f32> {
+        self.node(id).world_transform.into()
+    }
+
+    pub fn attach(&mut self, id: NodeId, attachment: Attachment) {
+        self.node_mut(id).attachments.push(attachment);
+    }
+
+    pub fn attachments(&self, id: NodeId) -> &[Attachment] {
+        &self.node(id).attachments
+    }
+
+    pub fn attachments_mut(&mut self, id: NodeId) -> &mut Vec<Attachment> {
+        &mut self.node_mut(id).attachments
+    }
+
+    // Meant to be called once per frame, so that meshes' previous transforms are last frame's
+    pub fn update(&mut self) {
+        for node in self.slots.iter_mut().filter_map(|slot| slot.node.as_mut()) {
+            node.previous_world_transform = node.world_transform;
+        }
+
+        let mut stack: Vec<_> = self
+            .roots
+            .iter()
+            .map(|&root| (root, na::Matrix4::identity(), false))
+            .collect();
+        while let Some((id, parent_world_transform, parent_moved)) = stack.pop() {
+            let node = self.node_mut(id);
+            let moved = node.dirty || parent_moved;
+            if moved {
+                node.world_transform = parent_world_transform * node.transform;
+                node.dirty = false;
+            }
+            let world_transform = node.world_transform;
+            stack.extend(
+                node.children
+                    .iter()
+                    .map(|&child| (child, world_transform, moved)),
+            );
+        }
+    }
+
+    // Ready for Renderer::draw, with previous transforms for the meshes that moved
+    pub fn mesh_instances(&self) -> Vec<MeshInstance> {
+        self.nodes()
+            .flat_map(|node| {
+                node.attachments.iter().filter_map(move |attachment| {
+                    let instance = match attachment {
+                        Attachment::Mesh(instance) => instance,
+                        _ => return None,
+                    };
+                    let transform = na::Matrix4::from(instance.transform);
+                    let previous_transform =
+                        instance.previous_transform.map_or(transform, |t| t.into());
+                    let world_transform = node.world_transform * transform;
+                    let previous_world_transform =
+                        node.previous_world_transform * previous_transform;
+                    Some(MeshInstance {
+                        transform: world_transform.into(),
+                        previous_transform: (previous_world_transform != world_transform)
+                            .then(|| previous_world_transform.into()),
+                        ..instance.clone()
+                    })
+                })
+            })
+            .collect()
+    }
+
+    // Ready for Renderer::set_lights
+    pub fn lights(&self) -> Vec<Light> {
+        self.nodes()
+            .flat_map(|node| {
+                node.attachments
+                    .iter()
+                    .filter_map(move |attachment| match *attachment {
+                        Attachment::Light(light) => {
+                            Some(Self::transform_light(&node.world_transform, light))
+                        }
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    // The node's first camera
+    pub fn camera(&self, id: NodeId) -> Option<Camera> {
+        let node = self.node(id);
+        node.attachments
+            .iter()
+            .find_map(|attachment| match *attachment {
+                Attachment::Camera(projection) => Some(Camera {
+                    transform: node.world_transform.into(),
+                    projection,
+                }),
+                _ => None,
+            })
+    }
+
+    // Ranges and angles are left unscaled
+    fn transform_light(transform: &na::Matrix4<f32>, light: Light) -> Light {
+        match light {
+            Light::Point {
+                position,
+                color,
+                range,
+            } => Light::Point {
+                position: transform.transform_point(&position.into()).into(),
+                color,
+                range,
+            },
+            Light::Spot {
+                position,
+                direction,
+                color,
+                range,
+                angle,
+            } => Light::Spot {
+                position: transform.transform_point(&position.into()).into(),
+                direction: transform
+                    .transform_vector(&direction.into())
+                    .normalize()
+                    .into(),
+                color,
+                range,
+                angle,
+            },
+        }
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.slots.iter().filter_map(|slot| slot.node.as_ref())
+    }
+
+    fn node(&self, id: NodeId) -> &Node {
+        assert!(self.contains(id), "Scene node was removed");
+        self.slots[id.index as usize].node.as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        assert!(self.contains(id), "Scene node was removed");
+        self.slots[id.index as usize].node.as_mut().unwrap()
+    }
+
+    fn siblings_mut(&mut self, parent: Option<NodeId>) -> &mut Vec<NodeId> {
+        match parent {
+            Some(parent) => &mut self.node_mut(parent).children,
+            None => &mut self.roots,
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::borrow::Cow;
+
+// Shaders are baked in at build time. With the hot-reload feature, they're instead compiled from
+// their source files each time the renderer is built, keeping the baked SPIR-V if that fails.
+macro_rules! include_shader {
+    ($path:literal) => {
+        $crate::shaders::load($path, ::vk_shader_macros::include_glsl!($path))
+    };
+}
+pub(crate) use include_shader;
+
+#[cfg(not(feature = "hot-reload"))]
+pub fn load(_path: &str, baked: &'static [u32]) -> Cow<'static, [u32]> {
+    Cow::Borrowed(baked)
+}
+
+#[cfg(feature = "hot-reload")]
+pub use hot_reload::{load, ShaderWatcher};
+
+#[cfg(feature = "hot-reload")]
+mod hot_reload {
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant, SystemTime};
+
+    use thiserror::Error;
+
+    use super::Cow;
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    #[derive(Error, Debug)]
+    enum ShaderError {
+        #[error("Unable to read shader source")]
+        IoError(#[from] std::io::Error),
+        #[error("Unrecognized shader extension")]
+        UnknownKind,
+        #[error("Unable to initialize shaderc")]
+        NoCompiler,
+        #[error("{0}")]
+        CompilationError(#[from] shaderc::Error),
+    }
+
+    pub fn load(path: &str, baked: &'static [u32]) -> Cow<'static, [u32]> {
+        match compile(&source_path(path)) {
+            Ok(spirv) => Cow::Owned(spirv),
+            Err(err) => {
+                log::error!("Keeping built-in {}: {}", path, err);
+                Cow::Borrowed(baked)
+            }
+        }
+    }
+
+    fn compile(path: &Path) -> Result<Vec<u32>, ShaderError> {
+        let kind = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("vert") => shaderc::ShaderKind::Vertex,
+            Some("frag") => shaderc::ShaderKind::Fragment,
+            Some("comp") => shaderc::ShaderKind::Compute,
+            _ => return Err(ShaderError::UnknownKind),
+        };
+        let source = fs::read_to_string(path)?;
+
+        let mut compiler = shaderc::Compiler::new().ok_or(ShaderError::NoCompiler)?;
+        let mut options = shaderc::CompileOptions::new().ok_or(ShaderError::NoCompiler)?;
+        // Resolved the same way as include_glsl! does
+        options.set_include_callback(|name, include_type, source, _depth| {
+            let path = match include_type {
+                shaderc::IncludeType::Relative => Path::new(source).parent().unwrap().join(name),
+                shaderc::IncludeType::Standard => source_path(name),
+            };
+            Ok(shaderc::ResolvedInclude {
+                resolved_name: path.to_string_lossy().into_owned(),
+                content: fs::read_to_string(&path).map_err(|err| err.to_string())?,
+            })
+        });
+        let artifact = compiler.compile_into_spirv(
+            &source,
+            kind,
+            &path.to_string_lossy(),
+            "main",
+            Some(&options),
+        )?;
+        if artifact.get_num_warnings() > 0 {
+            log::warn!("{}", artifact.get_warning_messages());
+        }
+        Ok(artifact.as_binary().to_vec())
+    }
+
+    // Relative to the crate, as with include_glsl!, so this only works from a source checkout
+    fn source_path(path: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(path)
+    }
+
+    // Notices edits to shader sources by polling their modification times
+    pub struct ShaderWatcher {
+        last_poll: Instant,
+        modified: HashMap<String, SystemTime>, // by file name within shaders
+    }
+
+    impl ShaderWatcher {
+        pub fn new() -> Self {
+            Self {
+                last_poll: Instant::now(),
+                modified: Self::modified_times(),
+            }
+        }
+
+        // The stages that changed since the last poll, as paths like include_shader! takes, along
+        // with those including a header that changed
+        pub fn poll(&mut self) -> Vec<String> {
+            if self.last_poll.elapsed() < POLL_INTERVAL {
+                return Vec::new();
+            }
+            self.last_poll = Instant::now();
+
+            let modified = Self::modified_times();
+            let changed: HashSet<&str> = modified
+                .iter()
+                .filter(|&(name, time)| self.modified.get(name) != Some(time))
+                .map(|(name, _)| name.as_str())
+                .collect();
+            let mut stages: Vec<_> = modified
+                .keys()
+                .filter(|name| !name.ends_with(".glsl"))
+                .filter(|name| includes_any(name, &changed, &mut HashSet::new()))
+                .map(|name| format!("shaders/{}", name))
+                .collect();
+            stages.sort();
+            self.modified = modified;
+            stages
+        }
+
+        fn modified_times() -> HashMap<String, SystemTime> {
+            let entries = match fs::read_dir(source_path("shaders")) {
+                Ok(entries) => entries,
+                Err(_) => return HashMap::new(),
+            };
+            entries
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let modified = entry.metadata().ok()?.modified().ok()?;
+                    Some((entry.file_name().into_string().ok()?, modified))
+                })
+                .collect()
+        }
+    }
+
+    // Whether the shader is one of those named, or includes one, however indirectly
+    fn includes_any(name: &str, changed: &HashSet<&str>, visited: &mut HashSet<String>) -> bool {
+        if changed.contains(name) {
+            return true;
+        }
+        if !visited.insert(name.into()) {
+            return false;
+        }
+        let source = fs::read_to_string(source_path("shaders").join(name)).unwrap_or_default();
+        source
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("#include"))
+            .map(|include| {
+                include
+                    .trim()
+                    .trim_matches(|c| c == '"' || c == '<' || c == '>')
+            })
+            .any(|include| includes_any(include, changed, visited))
+    }
+}
@@ -0,0 +1,952 @@
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::CStr;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+use nalgebra as na;
+
+use crate::{
+    camera::{Camera, Projection},
+    culling::Frustum,
+    guard::{GuardableResource, Guarded},
+    image::Image,
+    jobs,
+    light::Light,
+    mesh::{GpuMeshInstance, Vertex},
+    shaders::include_shader,
+    shared::{ModelBuffer, SharedFrond, SharedStem, ViewBuffer},
+    sync, util,
+};
+
+// Sized to match the cascade array in lighting.frag
+pub const MAX_CASCADES: usize = 4;
+
+// Each shadowed point light takes six layers of the point shadow array
+pub const MAX_POINT_SHADOWS: usize = 4;
+
+// Casters closer than this to a point light don't shadow it
+pub(crate) const POINT_SHADOW_NEAR: f32 = 0.05;
+
+// Forward and up for each cube face, in the usual +x, -x, +y, -y, +z, -z order. light.glsl
+// picks faces the same way.
+const CUBE_FACES: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+    ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+    ([0.0, 0.0, -1.0], [1.0, 0.0, 0.0]),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShadowSettings {
+    pub resolution: u32,      // width and height of each cascade's shadow map
+    pub cascade_count: usize, // between 1 and MAX_CASCADES
+    pub split_scheme: CascadeSplitScheme,
+    pub distance: f32, // how far from the camera the last cascade reaches
+    pub cascade_fits: [CascadeFit; MAX_CASCADES],
+    // Push casters' depths away from the sun to avoid shadow acne
+    pub depth_bias_constant: f32,
+    pub depth_bias_slope: f32,
+    pub point_resolution: u32, // width and height of each face of a point light's cubemap
+    pub point_count: usize,    // how many point lights cast shadows, up to MAX_POINT_SHADOWS
+    pub filter: ShadowFilter,  // for the sun's cascades
+    // Draw static casters into a cache that's only redrawn when its view or they change, so each
+    // frame only draws dynamic ones. Costs a second copy of every shadow map.
+    pub cache_static: bool,
+}
+
+// How the edges of the sun's shadows are smoothed, from cheapest to most expensive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShadowFilter {
+    Unfiltered, // one depth lookup, with a narrow ramp instead of a hard cutoff
+    Pcf,        // one hardware comparison, blending the nearest four texels
+    PoissonPcf { radius: f32 }, // several comparisons spread up to this many texels away
+    Pcss { sun_radius: f32 }, // edges soften away from casters, for a sun this many radians wide
+}
+
+// How tightly each cascade wraps its slice of the camera's frustum
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CascadeFit {
+    Tight, // the slice's bounding box in sunlight space, which shimmers as the camera moves
+    // A bounding sphere that only changes size with the projection, centered on a whole texel so
+    // that shadow edges don't crawl. Spends some resolution on what's outside the slice.
+    #[default]
+    Stable,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CascadeSplitScheme {
+    Uniform,
+    Logarithmic,
+    Practical(f32), // 0.0 is uniform, 1.0 is logarithmic
+}
+
+impl ShadowSettings {
+    // Distances from the camera where each cascade begins, followed by where the last one ends
+    pub(crate) fn cascade_splits(&self, near_z: f32) -> Vec<f32> {
+        let far_z = self.distance;
+        (0..=self.cascade_count)
+            .map(|i| {
+                let fraction = i as f32 / self.cascade_count as f32;
+                let uniform = near_z + (far_z - near_z) * fraction;
+                let logarithmic = near_z * (far_z / near_z).powf(fraction);
+                match self.split_scheme {
+                    CascadeSplitScheme::Uniform => uniform,
+                    CascadeSplitScheme::Logarithmic => logarithmic,
+                    CascadeSplitScheme::Practical(lambda) => {
+                        lambda * logarithmic + (1.0 - lambda) * uniform
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            cascade_count: MAX_CASCADES,
+            split_scheme: CascadeSplitScheme::Practical(0.75),
+            distance: 50.0,
+            cascade_fits: [Default::default(); MAX_CASCADES],
+            depth_bias_constant: 1.0,
+            depth_bias_slope: 1.5,
+            point_resolution: 512,
+            point_count: MAX_POINT_SHADOWS,
+            filter: ShadowFilter::Pcf,
+            cache_static: true,
+        }
+    }
+}
+
+// Where the sun's view of one slice of the camera's frustum ended up
+pub struct ShadowCascade {
+    pub world_to_shadow: na::Matrix4<f32>,
+    pub near_depth: f32, // screen depths bounding the slice
+    pub far_depth: f32,
+    pub depth_to_uv: na::Vector2<f32>, // ratio of shadow map depth to width and height, in world units
+}
+
+pub struct ShadowStem {
+    cache_render_pass: vk::RenderPass, // clears a cache layer, for drawing static casters into
+    composite_render_pass: vk::RenderPass, // draws dynamic casters over a copied cache layer
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass, // clears a shadow map layer, for drawing every caster into
+    shared_stem: Arc<SharedStem>,
+    triangle_shadow_frag_shader_module: vk::ShaderModule,
+    triangle_vert_shader_module: vk::ShaderModule,
+}
+
+impl ShadowStem {
+    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let pipeline_layout = util::create_pipeline_layout(
+                device,
+                &[], // descriptor set layouts
+                &[
+                    ViewBuffer::push_constant_range(),
+                    ModelBuffer::push_constant_range(),
+                ],
+            )?;
+            shared_stem.set_name(*pipeline_layout, "shadow")?;
+
+            let triangle_vert_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/triangle.vert"))?;
+            shared_stem.set_name(*triangle_vert_shader_module, "shadow triangle vert")?;
+            let triangle_shadow_frag_shader_module = util::create_shader_module(
+                device,
+                &include_shader!("shaders/triangle-shadow.frag"),
+            )?;
+            shared_stem.set_name(*triangle_shadow_frag_shader_module, "triangle shadow frag")?;
+
+            let render_pass = Self::create_render_pass(
+                device,
+                shared_stem.shadow_format(),
+                vk::AttachmentLoadOp::CLEAR,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                // Earlier frames may still be sampling the shadow maps
+                &[sync::dependency_before(
+                    sync::FRAGMENT_SAMPLED.execution(),
+                    sync::DEPTH_ATTACHMENT_WRITE,
+                )],
+            )?;
+            shared_stem.set_name(*render_pass, "shadow")?;
+
+            // Earlier frames may still be copying from the cache, and this frame copies after
+            let cache_render_pass = Self::create_render_pass(
+                device,
+                shared_stem.shadow_format(),
+                vk::AttachmentLoadOp::CLEAR,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                &[
+                    sync::dependency_before(
+                        sync::TRANSFER_READ.execution(),
+                        sync::DEPTH_ATTACHMENT_WRITE,
+                    ),
+                    sync::dependency_after(sync::DEPTH_ATTACHMENT_WRITE, sync::TRANSFER_READ),
+                ],
+            )?;
+            shared_stem.set_name(*cache_render_pass, "shadow cache")?;
+
+            let composite_render_pass = Self::create_render_pass(
+                device,
+                shared_stem.shadow_format(),
+                vk::AttachmentLoadOp::LOAD,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                &[sync::dependency_before(
+                    sync::TRANSFER_WRITE,
+                    sync::DEPTH_ATTACHMENT_WRITE,
+                )],
+            )?;
+            shared_stem.set_name(*composite_render_pass, "shadow composite")?;
+
+            let pipeline = Self::create_pipeline(
+                device,
+                *triangle_vert_shader_module,
+                *triangle_shadow_frag_shader_module,
+                *pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*pipeline, "shadow")?;
+
+            // All three render passes are compatible, so they share the pipeline and framebuffers
+            Ok(Self {
+                cache_render_pass: cache_render_pass.take(),
+                composite_render_pass: composite_render_pass.take(),
+                pipeline: pipeline.take(),
+                pipeline_layout: pipeline_layout.take(),
+                render_pass: render_pass.take(),
+                triangle_shadow_frag_shader_module: triangle_shadow_frag_shader_module.take(),
+                triangle_vert_shader_module: triangle_vert_shader_module.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    unsafe fn create_render_pass<'a>(
+        device: &'a ash::Device,
+        depth_stencil_format: vk::Format,
+        load_op: vk::AttachmentLoadOp,
+        initial_layout: vk::ImageLayout,
+        final_layout: vk::ImageLayout,
+        dependencies: &[vk::SubpassDependency],
+    ) -> VkResult<Guarded<(vk::RenderPass, &'a ash::Device)>> {
+        let attachments = [vk::AttachmentDescription::builder()
+            .format(depth_stencil_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(load_op)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(initial_layout)
+            .final_layout(final_layout)
+            .build()];
+
+        let depth_stencil_attachment_ref = vk::AttachmentReference::builder()
+            .attachment(0)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .build();
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .depth_stencil_attachment(&depth_stencil_attachment_ref)
+            .build()];
+
+        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(dependencies);
+        Ok(device
+            .create_render_pass(&render_pass_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_pipeline(
+        device: &ash::Device,
+        triangle_vert_shader_module: vk::ShaderModule,
+        triangle_frag_shader_module: vk::ShaderModule,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(triangle_vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(triangle_frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        let vertex_binding_descriptions = Vertex::binding_descriptions();
+        let vertex_attribute_descriptions = Vertex::attribute_descriptions();
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_binding_descriptions)
+            .vertex_attribute_descriptions(&vertex_attribute_descriptions);
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .depth_bias_enable(true)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(true)
+            .depth_compare_op(vk::CompareOp::GREATER)
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(false)
+            //.front()
+            //.back()
+            //.min_depth_bounds()
+            //.max_depth_bounds()
+            ;
+
+        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder();
+
+        let dynamic_states = [
+            vk::DynamicState::VIEWPORT,
+            vk::DynamicState::SCISSOR,
+            vk::DynamicState::DEPTH_BIAS,
+        ];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            // .tesselation_state()
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+}
+
+impl Drop for ShadowStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_render_pass(self.cache_render_pass, None);
+            device.destroy_render_pass(self.composite_render_pass, None);
+            device.destroy_shader_module(self.triangle_shadow_frag_shader_module, None);
+            device.destroy_shader_module(self.triangle_vert_shader_module, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+        }
+    }
+}
+
+// Which cubemap each point light got, and what each face of those cubemaps sees
+pub struct PointShadows {
+    pub slots: Vec<Option<usize>>,
+    faces: Vec<(na::Matrix4<f32>, Vec<GpuMeshInstance>, u64)>, // six per cubemap, see static_casters
+}
+
+// What a cache layer was drawn with, so it's only redrawn once either changes
+#[derive(Clone, Copy, PartialEq)]
+struct CacheKey {
+    world_to_shadow: na::Matrix4<f32>,
+    static_casters: u64,
+}
+
+pub struct ShadowFrond {
+    cache_framebuffers: Vec<vk::Framebuffer>, // per cascade, if caching static casters
+    cache_keys: Mutex<Vec<Option<CacheKey>>>, // per cascade, for whatever its cache layer holds
+    framebuffers: Vec<vk::Framebuffer>,       // per cascade
+    point_cache_framebuffers: Vec<vk::Framebuffer>, // per cube face, if caching static casters
+    point_cache_keys: Mutex<Vec<Option<CacheKey>>>, // per cube face
+    point_framebuffers: Vec<vk::Framebuffer>, // per cube face
+    shadow_stem: Arc<ShadowStem>,
+    shared_frond: Arc<SharedFrond>,
+}
+
+impl ShadowFrond {
+    pub fn new(shadow_stem: Arc<ShadowStem>, shared_frond: Arc<SharedFrond>) -> VkResult<Self> {
+        let shared_stem = &shadow_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+
+            let mut framebuffers = Vec::<vk::Framebuffer>::new().guard_with(device);
+            for &cascade_view in shared_frond.shadow_cascade_views() {
+                let framebuffer = util::create_framebuffer(
+                    device,
+                    shadow_stem.render_pass,
+                    &[cascade_view],
+                    shared_frond.shadow().resolution_2d(),
+                )?;
+                shared_stem.set_name(*framebuffer, "shadow")?;
+                framebuffers.push(framebuffer.take());
+            }
+
+            let mut point_framebuffers = Vec::<vk::Framebuffer>::new().guard_with(device);
+            for &face_view in shared_frond.point_shadow_face_views() {
+                let framebuffer = util::create_framebuffer(
+                    device,
+                    shadow_stem.render_pass,
+                    &[face_view],
+                    shared_frond.point_shadow().resolution_2d(),
+                )?;
+                shared_stem.set_name(*framebuffer, "point shadow")?;
+                point_framebuffers.push(framebuffer.take());
+            }
+
+            let mut cache_framebuffers = Vec::<vk::Framebuffer>::new().guard_with(device);
+            for &cache_view in shared_frond.shadow_cache_views() {
+                let framebuffer = util::create_framebuffer(
+                    device,
+                    shadow_stem.render_pass,
+                    &[cache_view],
+                    shared_frond.shadow().resolution_2d(),
+                )?;
+                shared_stem.set_name(*framebuffer, "shadow cache")?;
+                cache_framebuffers.push(framebuffer.take());
+            }
+
+            let mut point_cache_framebuffers = Vec::<vk::Framebuffer>::new().guard_with(device);
+            for &face_view in shared_frond.point_shadow_cache_face_views() {
+                let framebuffer = util::create_framebuffer(
+                    device,
+                    shadow_stem.render_pass,
+                    &[face_view],
+                    shared_frond.point_shadow().resolution_2d(),
+                )?;
+                shared_stem.set_name(*framebuffer, "point shadow cache")?;
+                point_cache_framebuffers.push(framebuffer.take());
+            }
+
+            Ok(Self {
+                cache_keys: Mutex::new(vec![None; cache_framebuffers.len()]),
+                cache_framebuffers: cache_framebuffers.take(),
+                framebuffers: framebuffers.take(),
+                point_cache_keys: Mutex::new(vec![None; point_cache_framebuffers.len()]),
+                point_cache_framebuffers: point_cache_framebuffers.take(),
+                point_framebuffers: point_framebuffers.take(),
+                shadow_stem,
+                shared_frond,
+            })
+        }
+    }
+
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        view: mint::ColumnMatrix4<f32>,
+        projection: &Projection,
+        sunlight_direction: na::Vector3<f32>,
+        meshes: &[GpuMeshInstance],
+    ) -> Vec<ShadowCascade> {
+        let view: na::Matrix4<f32> = view.into();
+        let screen_to_world = view.try_inverse().unwrap();
+
+        let up = if sunlight_direction.z.abs() > 0.99 {
+            na::Vector3::x()
+        } else {
+            na::Vector3::z()
+        };
+        let world_to_sunlight =
+            na::Rotation3::look_at_rh(&sunlight_direction, &up).to_homogeneous();
+
+        let shared_stem = &self.shadow_stem.shared_stem;
+        shared_stem.begin_label(command_buffer, "cascades", [0.5, 0.5, 0.3, 1.0]);
+        let shadow_settings = self.shared_frond.shadow_settings();
+        let static_casters = Self::static_casters(meshes);
+        let mut cache_keys = self.cache_keys.lock().unwrap();
+        let cascades = shadow_settings
+            .cascade_splits(projection.near())
+            .windows(2)
+            .enumerate()
+            .map(|(cascade, splits)| {
+                let near_depth = projection.depth_at(splits[0]);
+                let far_depth = projection.depth_at(splits[1]);
+                let (world_to_shadow, extent) = Self::fit_cascade(
+                    world_to_sunlight,
+                    screen_to_world,
+                    near_depth,
+                    far_depth,
+                    shadow_settings.distance,
+                    shadow_settings.cascade_fits[cascade],
+                    shadow_settings.resolution,
+                );
+                let cache = self
+                    .shared_frond
+                    .shadow_cache()
+                    .map(|image| (image, self.cache_framebuffers[cascade]));
+                self.draw_layer(
+                    command_buffer,
+                    self.shared_frond.shadow(),
+                    cascade as _,
+                    self.framebuffers[cascade],
+                    cache.zip(cache_keys.get_mut(cascade)),
+                    world_to_shadow,
+                    meshes,
+                    static_casters,
+                );
+                ShadowCascade {
+                    world_to_shadow,
+                    near_depth,
+                    far_depth,
+                    depth_to_uv: na::Vector2::new(extent.z / extent.x, extent.z / extent.y),
+                }
+            })
+            .collect();
+        shared_stem.end_label(command_buffer);
+        cascades
+    }
+
+    // The first few point lights each get a cubemap, and the rest go unshadowed. Every face's
+    // culling is independent, so they're spread across threads.
+    pub fn prepare_point_lights(
+        &self,
+        lights: &[Light],
+        meshes: &[GpuMeshInstance],
+    ) -> PointShadows {
+        let shadow_settings = self.shared_frond.shadow_settings();
+
+        let mut slots = Vec::with_capacity(lights.len());
+        let mut slot_count = 0;
+        let mut world_to_faces = Vec::new();
+        for light in lights {
+            match *light {
+                Light::Point { position, .. } if slot_count < shadow_settings.point_count => {
+                    world_to_faces.extend(
+                        CUBE_FACES
+                            .iter()
+                            .map(|&(forward, up)| Self::cube_face(position.into(), forward, up)),
+                    );
+                    slots.push(Some(slot_count));
+                    slot_count += 1;
+                }
+                _ => slots.push(None),
+            }
+        }
+
+        let faces = jobs::map(&world_to_faces, |world_to_face| {
+            let visible_meshes = Frustum::new(world_to_face).cull(meshes);
+            let static_casters = Self::static_casters(&visible_meshes);
+            (*world_to_face, visible_meshes, static_casters)
+        });
+        PointShadows { slots, faces }
+    }
+
+    pub unsafe fn draw_point_lights(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        point_shadows: &PointShadows,
+    ) {
+        let shared_stem = &self.shadow_stem.shared_stem;
+        shared_stem.begin_label(command_buffer, "point lights", [0.5, 0.3, 0.3, 1.0]);
+        let image = self.shared_frond.point_shadow();
+        let mut cache_keys = self.point_cache_keys.lock().unwrap();
+        let mut framebuffers = self.point_framebuffers.iter();
+        for (face, ((world_to_face, visible_meshes, static_casters), &framebuffer)) in point_shadows
+            .faces
+            .iter()
+            .zip(&mut framebuffers)
+            .enumerate()
+        {
+            let cache = self
+                .shared_frond
+                .point_shadow_cache()
+                .map(|image| (image, self.point_cache_framebuffers[face]));
+            self.draw_layer(
+                command_buffer,
+                image,
+                face as _,
+                framebuffer,
+                cache.zip(cache_keys.get_mut(face)),
+                *world_to_face,
+                visible_meshes,
+                *static_casters,
+            );
+        }
+
+        // Leftover cubemaps are still cleared, so every layer is ready to be sampled
+        for &framebuffer in framebuffers {
+            self.draw_depth(
+                command_buffer,
+                self.shadow_stem.render_pass,
+                framebuffer,
+                image.resolution_2d(),
+                na::Matrix4::identity(),
+                &[],
+            );
+        }
+        shared_stem.end_label(command_buffer);
+    }
+
+    // A square 90 degree view from a point light through one face of its cube
+    fn cube_face(position: na::Point3<f32>, forward: [f32; 3], up: [f32; 3]) -> na::Matrix4<f32> {
+        let forward = na::Vector3::from(forward);
+        let up = na::Vector3::from(up);
+        let left = up.cross(&forward);
+        let transform = na::Matrix4::from_columns(&[
+            forward.push(0.0),
+            left.push(0.0),
+            up.push(0.0),
+            position.to_homogeneous(),
+        ]);
+        let camera = Camera {
+            transform: transform.into(),
+            // Diagonal field of view whose sides are 90 degrees apart
+            projection: Projection::Perspective {
+                fov: 2.0 * 2.0f32.sqrt().atan(),
+                near: POINT_SHADOW_NEAR,
+            },
+        };
+        camera.world_to_screen(vk::Extent2D {
+            width: 1,
+            height: 1,
+        })
+    }
+
+    // Caches go unused when the frame they were drawn in isn't submitted, so have to be redrawn
+    pub fn forget_cache(&self) {
+        for keys in [&self.cache_keys, &self.point_cache_keys] {
+            keys.lock().unwrap().fill(None);
+        }
+    }
+
+    // Hashes which static casters there are and where, so caches notice any being added, moved
+    // or removed
+    fn static_casters(meshes: &[GpuMeshInstance]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for instance in meshes.iter().filter(|instance| instance.is_static) {
+            Arc::as_ptr(&instance.mesh).hash(&mut hasher);
+            for element in na::Matrix4::from(instance.transform).iter() {
+                element.to_bits().hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+
+    // Draws one layer of a shadow map. With a cache, static casters are copied from its layer,
+    // which is only redrawn when the view or the static casters change, and dynamic ones are
+    // drawn over them.
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn draw_layer(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        image: &Image,
+        layer: u32,
+        framebuffer: vk::Framebuffer,
+        cache: Option<((&Image, vk::Framebuffer), &mut Option<CacheKey>)>,
+        world_to_shadow: na::Matrix4<f32>,
+        meshes: &[GpuMeshInstance],
+        static_casters: u64,
+    ) {
+        let shadow_stem = &self.shadow_stem;
+        let resolution = image.resolution_2d();
+        let ((cache_image, cache_framebuffer), cache_key) = match cache {
+            Some(cache) => cache,
+            None => {
+                self.draw_depth(
+                    command_buffer,
+                    shadow_stem.render_pass,
+                    framebuffer,
+                    resolution,
+                    world_to_shadow,
+                    meshes,
+                );
+                return;
+            }
+        };
+
+        let key = CacheKey {
+            world_to_shadow,
+            static_casters,
+        };
+        if *cache_key != Some(key) {
+            self.draw_depth(
+                command_buffer,
+                shadow_stem.cache_render_pass,
+                cache_framebuffer,
+                resolution,
+                world_to_shadow,
+                meshes.iter().filter(|instance| instance.is_static),
+            );
+            *cache_key = Some(key);
+        }
+        self.copy_layer(command_buffer, cache_image, image, layer);
+        self.draw_depth(
+            command_buffer,
+            shadow_stem.composite_render_pass,
+            framebuffer,
+            resolution,
+            world_to_shadow,
+            meshes.iter().filter(|instance| !instance.is_static),
+        );
+    }
+
+    // Overwrites a layer with the cache's, leaving it ready for the composite render pass
+    unsafe fn copy_layer(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        cache_image: &Image,
+        image: &Image,
+        layer: u32,
+    ) {
+        let device = self.shared_frond.device();
+
+        // Earlier frames may still be sampling the layer
+        let image_memory_barriers = [vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(sync::TRANSFER_WRITE.access)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image.image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::DEPTH,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: layer,
+                layer_count: 1,
+            })
+            .build()];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            sync::FRAGMENT_SAMPLED.stage,
+            sync::TRANSFER_WRITE.stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::DEPTH,
+            mip_level: 0,
+            base_array_layer: layer,
+            layer_count: 1,
+        };
+        let regions = [vk::ImageCopy {
+            src_subresource: subresource,
+            src_offset: Default::default(),
+            dst_subresource: subresource,
+            dst_offset: Default::default(),
+            extent: image.resolution,
+        }];
+        device.cmd_copy_image(
+            command_buffer,
+            cache_image.image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image.image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &regions,
+        );
+    }
+
+    unsafe fn draw_depth<'a>(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        render_pass: vk::RenderPass,
+        framebuffer: vk::Framebuffer,
+        resolution: vk::Extent2D,
+        world_to_shadow: na::Matrix4<f32>,
+        meshes: impl IntoIterator<Item = &'a GpuMeshInstance>,
+    ) {
+        let device = self.shared_frond.device();
+        let shadow_settings = self.shared_frond.shadow_settings();
+
+        let render_area = vk::Rect2D {
+            offset: Default::default(),
+            extent: resolution,
+        };
+
+        let clear_values = [vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: 0.0,
+                stencil: 0,
+            },
+        }];
+
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(framebuffer)
+            .render_area(render_area)
+            .clear_values(&clear_values);
+        device.cmd_begin_render_pass(
+            command_buffer,
+            &render_pass_begin_info,
+            vk::SubpassContents::INLINE,
+        );
+
+        let view_buffer = ViewBuffer {
+            view: world_to_shadow.into(),
+        };
+        device.cmd_push_constants(
+            command_buffer,
+            self.shadow_stem.pipeline_layout,
+            vk::ShaderStageFlags::VERTEX,
+            0,
+            view_buffer.as_std140().as_bytes(),
+        );
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.shadow_stem.pipeline,
+        );
+        util::set_viewport_and_scissor(device, command_buffer, resolution);
+        // Depth is reversed, so away from the sun is negative
+        device.cmd_set_depth_bias(
+            command_buffer,
+            -shadow_settings.depth_bias_constant,
+            0.0, // clamp
+            -shadow_settings.depth_bias_slope,
+        );
+
+        for instance in meshes {
+            let model_buffer = ModelBuffer {
+                model: instance.transform,
+            };
+            device.cmd_push_constants(
+                command_buffer,
+                self.shadow_stem.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX,
+                ViewBuffer::std140_size_static() as _,
+                model_buffer.as_std140().as_bytes(),
+            );
+
+            instance
+                .mesh
+                .draw(&self.shadow_stem.shared_stem, command_buffer);
+        }
+
+        device.cmd_end_render_pass(command_buffer);
+    }
+
+    // Fits an orthographic projection around the slice of the view frustum between two screen
+    // depths, stretched towards the sun so that casters outside the slice still cast shadows.
+    // Also returns the size of the box it covers.
+    #[allow(clippy::too_many_arguments)]
+    fn fit_cascade(
+        world_to_sunlight: na::Matrix4<f32>,
+        screen_to_world: na::Matrix4<f32>,
+        near_depth: f32,
+        far_depth: f32,
+        caster_distance: f32,
+        fit: CascadeFit,
+        resolution: u32,
+    ) -> (na::Matrix4<f32>, na::Vector3<f32>) {
+        let screen_to_sunlight = world_to_sunlight * screen_to_world;
+        let mut corners = Vec::with_capacity(8);
+        for &x in &[-1.0, 1.0] {
+            for &y in &[-1.0, 1.0] {
+                for &depth in &[near_depth, far_depth] {
+                    corners.push(screen_to_sunlight.transform_point(&na::Point3::new(x, y, depth)));
+                }
+            }
+        }
+
+        let (min, mut max) = match fit {
+            CascadeFit::Tight => corners.iter().fold(
+                (
+                    na::Vector3::repeat(f32::INFINITY),
+                    na::Vector3::repeat(f32::NEG_INFINITY),
+                ),
+                |(min, max), corner| (min.inf(&corner.coords), max.sup(&corner.coords)),
+            ),
+            CascadeFit::Stable => {
+                let center = corners
+                    .iter()
+                    .map(|corner| corner.coords)
+                    .sum::<na::Vector3<f32>>()
+                    / corners.len() as f32;
+                let radius = corners
+                    .iter()
+                    .map(|corner| (corner.coords - center).norm())
+                    .fold(0.0, f32::max);
+                // Rounded up so that floating point error as the camera turns doesn't change it
+                let radius = (radius * 16.0).ceil() / 16.0;
+                // Sunlight space only turns when the sun does, so texels snapped here stay put
+                let texel = 2.0 * radius / resolution as f32;
+                let snapped = na::Vector3::new(
+                    (center.x / texel).floor() * texel,
+                    (center.y / texel).floor() * texel,
+                    center.z,
+                );
+                (
+                    snapped - na::Vector3::repeat(radius),
+                    snapped + na::Vector3::repeat(radius),
+                )
+            }
+        };
+        // Sunlight space looks down -z, so the sun is towards +z
+        max.z += caster_distance;
+
+        let extent = max - min;
+        let world_to_shadow = na::Matrix4::new_translation(&na::Vector3::new(-1.0, -1.0, 0.0))
+            * na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(
+                2.0 / extent.x,
+                2.0 / extent.y,
+                1.0 / extent.z,
+            ))
+            * na::Matrix4::new_translation(&-min)
+            * world_to_sunlight;
+        (world_to_shadow, extent)
+    }
+}
+
+impl Drop for ShadowFrond {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_frond.device();
+            let _ = device.device_wait_idle();
+
+            for framebuffers in [
+                &self.framebuffers,
+                &self.cache_framebuffers,
+                &self.point_framebuffers,
+                &self.point_cache_framebuffers,
+            ] {
+                for &framebuffer in framebuffers {
+                    device.destroy_framebuffer(framebuffer, None);
+                }
+            }
+        }
+    }
+}
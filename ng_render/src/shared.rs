@@ -1,75 +1,322 @@
 use std::ffi::{CStr, CString};
 use std::os::raw::c_void;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use ash::{
     extensions::{ext::DebugUtils, khr::Surface, khr::Swapchain},
     prelude::VkResult,
-    version::{DeviceV1_0, EntryV1_0, InstanceV1_0},
+    version::{DeviceV1_0, EntryV1_0, InstanceV1_0, InstanceV1_1},
     vk::{self, Handle},
 };
 use crevice::std140::AsStd140;
 use mint::ColumnMatrix4;
 use thiserror::Error;
-use vk_shader_macros::include_glsl;
 use winit::window::Window;
 
 use crate::{
+    buffer::RetiredBuffers,
+    descriptors::DescriptorAllocator,
+    display::DisplayMode,
+    frame::{Frame, FRAMES_IN_FLIGHT},
     guard::{GuardableResource, Guarded},
     image::Image,
+    jobs,
+    shaders::include_shader,
+    shadow::ShadowSettings,
+    staging::StagingBelt,
+    stats::DrawCounter,
+    texture::{GpuTexture, RetiredTextures},
     util,
+    validation::{ValidationCallback, ValidationErrorPolicy, ValidationLog, ValidationMessage},
 };
 
 pub struct SharedCrown {
-    debug_utils_fn: DebugUtils,
+    debug_utils_fn: Option<DebugUtils>, // only if the extension is available
     debug_utils_messenger: vk::DebugUtilsMessengerEXT,
-    _entry: ash::Entry,
+    entry: ash::Entry,
+    api_version: u32, // the newest the loader offers, up to Vulkan 1.2
     instance: ash::Instance,
-    surface: Mutex<vk::SurfaceKHR>, // swapchain creation needs surface to be host-synchronized
+    output: CrownOutput,
+    properties2_fn: Option<vk::KhrGetPhysicalDeviceProperties2Fn>, // only if the extension is available
+    required_device: Option<vk::PhysicalDevice>,
+    required_device_extensions: Vec<CString>,
+    surface_capabilities2: bool, // needed by VK_EXT_full_screen_exclusive
     surface_fn: Surface,
-    window: Arc<Window>,
+    validation: bool,
+    validation_log: Box<ValidationLog>, // the debug messenger's user data, so can't move
 }
 
+// What another API sharing the device, like an OpenXR runtime, needs of it. The physical device is
+// asked for once the instance exists.
+#[derive(Default)]
+pub struct DeviceRequirements {
+    pub instance_extensions: Vec<CString>,
+    pub device_extensions: Vec<CString>,
+    #[allow(clippy::type_complexity)]
+    pub physical_device:
+        Option<Box<dyn FnOnce(&ash::Instance) -> Result<vk::PhysicalDevice, SharedCrownError>>>,
+}
+
+enum CrownOutput {
+    Window {
+        surface: Mutex<vk::SurfaceKHR>, // swapchain creation needs surface to be host-synchronized
+        window: Mutex<Arc<Window>>,     // replaced on resume, along with surface
+        display_mode: Mutex<DisplayMode>,
+    },
+    Headless(vk::Extent2D),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationMode {
+    Disabled,
+    IfAvailable, // warns and carries on without validation if the layer is missing
+    Required,
+}
+
+impl Default for ValidationMode {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            Self::IfAvailable
+        } else {
+            Self::Disabled
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum SharedCrownError {
-    #[error("Vulkan error occurred")]
-    VkError(#[from] vk::Result), // TODO: split into contexts
+    #[error("Couldn't query instance layers, extensions or version")]
+    InstanceQuery(#[source] vk::Result),
+    #[error("Couldn't create surface")]
+    SurfaceCreation(#[source] vk::Result),
+    #[error("Couldn't create debug messenger")]
+    DebugMessengerCreation(#[source] vk::Result),
     #[error("Couldn't create Entry")]
     EntryError(#[from] ash::LoadingError),
     #[error("Couldn't create Instance")]
     InstanceError(#[from] ash::InstanceError),
+    #[error("Validation was required, but VK_LAYER_KHRONOS_validation isn't installed")]
+    ValidationUnavailableError,
+    #[cfg(feature = "openxr")]
+    #[error("OpenXR error occurred")]
+    XrError(#[from] openxr::sys::Result),
+}
+
+impl SharedCrownError {
+    pub fn vk_result(&self) -> Option<vk::Result> {
+        match *self {
+            Self::InstanceQuery(result)
+            | Self::SurfaceCreation(result)
+            | Self::DebugMessengerCreation(result) => Some(result),
+            Self::InstanceError(ash::InstanceError::VkError(result)) => Some(result),
+            _ => None,
+        }
+    }
 }
 
 impl SharedCrown {
-    pub fn new(window: Arc<Window>) -> Result<Self, SharedCrownError> {
+    const MAX_API_VERSION: u32 = vk::make_version(1, 2, 0);
+    const VALIDATION_LAYER: &'static [u8] = b"VK_LAYER_KHRONOS_validation\0";
+
+    pub fn new(
+        window: Arc<Window>,
+        validation: ValidationMode,
+        error_policy: ValidationErrorPolicy,
+        requirements: DeviceRequirements,
+    ) -> Result<Self, SharedCrownError> {
         unsafe {
-            let entry = ash::Entry::new()?;
-            let instance = Self::create_instance(&entry, &window)?;
+            let surface_window = window.clone();
+            Self::with_output(
+                Some(&surface_window),
+                validation,
+                error_policy,
+                requirements,
+                |entry, instance| {
+                    let surface =
+                        ash_window::create_surface(entry, instance, &*surface_window, None)
+                            .map_err(SharedCrownError::SurfaceCreation)?;
+                    Ok(CrownOutput::Window {
+                        surface: Mutex::new(surface),
+                        window: Mutex::new(window),
+                        display_mode: Default::default(),
+                    })
+                },
+            )
+        }
+    }
 
-            let debug_utils_fn = DebugUtils::new(&entry, &*instance);
-            let debug_utils_messenger = debug_utils_fn
-                .create_debug_utils_messenger(&Self::debug_utils_messenger_create_info(), None)?
-                .guard_with(&debug_utils_fn);
+    // Renders into an offscreen image instead of presenting, so needs no window system
+    pub fn new_headless(
+        resolution: vk::Extent2D,
+        validation: ValidationMode,
+        error_policy: ValidationErrorPolicy,
+    ) -> Result<Self, SharedCrownError> {
+        unsafe {
+            Self::with_output(
+                None,
+                validation,
+                error_policy,
+                Default::default(),
+                |_, _| Ok(CrownOutput::Headless(resolution)),
+            )
+        }
+    }
 
-            let surface_fn = Surface::new(&entry, &*instance);
-            let surface = ash_window::create_surface(&entry, &*instance, &*window, None)?
-                .guard_with(&surface_fn);
+    // The output is created last, so nothing can fail after it exists
+    unsafe fn with_output(
+        window: Option<&Window>,
+        validation: ValidationMode,
+        error_policy: ValidationErrorPolicy,
+        requirements: DeviceRequirements,
+        create_output: impl FnOnce(&ash::Entry, &ash::Instance) -> Result<CrownOutput, SharedCrownError>,
+    ) -> Result<Self, SharedCrownError> {
+        let entry = ash::Entry::new()?;
+        let validation_log = Box::new(ValidationLog::new(error_policy));
+
+        let validation = Self::select_validation(&entry, validation)?;
+        let available_extensions = entry
+            .enumerate_instance_extension_properties()
+            .map_err(SharedCrownError::InstanceQuery)?;
+        let has_extension = |name: &CStr| {
+            available_extensions
+                .iter()
+                .any(|extension| CStr::from_ptr(extension.extension_name.as_ptr()) == name)
+        };
+        let debug_utils = has_extension(DebugUtils::name());
+        // Without this, surfaces only offer sRGB color spaces
+        let swapchain_colorspace =
+            window.is_some() && has_extension(vk::ExtSwapchainColorspaceFn::name());
+        if !debug_utils {
+            log::info!(
+                "{:?} unavailable, so objects won't be named",
+                DebugUtils::name()
+            );
+        }
+        // Needed to ask devices about extension features, like descriptor indexing's
+        let properties2 = has_extension(vk::KhrGetPhysicalDeviceProperties2Fn::name());
+        // Lets devices offer exclusive fullscreen, which skips the compositor
+        let surface_capabilities2 = window.is_some()
+            && properties2
+            && has_extension(vk::KhrGetSurfaceCapabilities2Fn::name());
+
+        // Vulkan 1.0 loaders reject anything newer, and don't know how to say so
+        let api_version = match entry
+            .try_enumerate_instance_version()
+            .map_err(SharedCrownError::InstanceQuery)?
+        {
+            Some(version) => version_without_patch(version).min(Self::MAX_API_VERSION),
+            None => vk::make_version(1, 0, 0),
+        };
 
-            Ok(Self {
-                debug_utils_messenger: debug_utils_messenger.take(),
-                instance: instance.take(),
-                surface: Mutex::new(surface.take()),
-                debug_utils_fn,
-                _entry: entry,
-                surface_fn,
-                window,
-            })
+        let instance = Self::create_instance(
+            &entry,
+            api_version,
+            window,
+            validation,
+            debug_utils,
+            swapchain_colorspace,
+            properties2,
+            surface_capabilities2,
+            &requirements.instance_extensions,
+            &validation_log,
+        )?;
+
+        let debug_utils_fn = if debug_utils {
+            Some(DebugUtils::new(&entry, &*instance))
+        } else {
+            None
+        };
+        let debug_utils_messenger = match &debug_utils_fn {
+            Some(debug_utils_fn) => Some(
+                debug_utils_fn
+                    .create_debug_utils_messenger(&validation_log.messenger_create_info(), None)
+                    .map_err(SharedCrownError::DebugMessengerCreation)?
+                    .guard_with(debug_utils_fn),
+            ),
+            None => None,
+        };
+
+        let properties2_fn = if properties2 {
+            Some(vk::KhrGetPhysicalDeviceProperties2Fn::load(|name| {
+                std::mem::transmute(entry.get_instance_proc_addr(instance.handle(), name.as_ptr()))
+            }))
+        } else {
+            None
+        };
+
+        // Never called without a surface, but keeps the windowed and headless paths uniform
+        let surface_fn = Surface::new(&entry, &*instance);
+
+        let required_device = requirements
+            .physical_device
+            .map(|physical_device| physical_device(&instance))
+            .transpose()?;
+
+        let output = create_output(&entry, &instance)?;
+
+        Ok(Self {
+            debug_utils_messenger: debug_utils_messenger
+                .map_or_else(vk::DebugUtilsMessengerEXT::null, |messenger| {
+                    messenger.take()
+                }),
+            instance: instance.take(),
+            debug_utils_fn,
+            entry,
+            api_version,
+            output,
+            properties2_fn,
+            required_device,
+            required_device_extensions: requirements.device_extensions,
+            surface_capabilities2,
+            surface_fn,
+            validation,
+            validation_log,
+        })
+    }
+
+    unsafe fn select_validation(
+        entry: &ash::Entry,
+        validation: ValidationMode,
+    ) -> Result<bool, SharedCrownError> {
+        if validation == ValidationMode::Disabled {
+            return Ok(false);
+        }
+        let validation_layer = CStr::from_bytes_with_nul(Self::VALIDATION_LAYER).unwrap();
+        let available = entry
+            .enumerate_instance_layer_properties()
+            .map_err(SharedCrownError::InstanceQuery)?
+            .iter()
+            .any(|layer| CStr::from_ptr(layer.layer_name.as_ptr()) == validation_layer);
+        match (validation, available) {
+            (ValidationMode::Required, false) => Err(SharedCrownError::ValidationUnavailableError),
+            (_, false) => {
+                log::warn!(
+                    "{:?} unavailable, continuing without validation",
+                    validation_layer
+                );
+                Ok(false)
+            }
+            _ => {
+                log::info!("Validating with {:?}", validation_layer);
+                Ok(true)
+            }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     unsafe fn create_instance(
         entry: &ash::Entry,
-        window: &Window,
+        api_version: u32,
+        window: Option<&Window>,
+        validation: bool,
+        debug_utils: bool,
+        swapchain_colorspace: bool,
+        properties2: bool,
+        surface_capabilities2: bool,
+        required_extensions: &[CString],
+        validation_log: &ValidationLog,
     ) -> Result<Guarded<ash::Instance>, ash::InstanceError> {
         let application_name = CString::new("Nerigen").unwrap();
         let application_version = vk::make_version(
@@ -82,55 +329,56 @@ impl SharedCrown {
             .application_version(application_version)
             .engine_name(&application_name)
             .engine_version(application_version)
-            .api_version(vk::make_version(1, 0, 0));
+            .api_version(api_version);
 
-        let validation_layer = CString::new("VK_LAYER_KHRONOS_validation").unwrap();
-        let enabled_layer_names = [validation_layer.as_ptr()];
-        let mut enabled_extension_names = ash_window::enumerate_required_extensions(window)
-            .map_err(ash::InstanceError::VkError)?;
-        enabled_extension_names.push(DebugUtils::name());
+        let enabled_layer_names = Self::enabled_layer_names(validation);
+        let mut enabled_extension_names = match window {
+            Some(window) => ash_window::enumerate_required_extensions(window)
+                .map_err(ash::InstanceError::VkError)?,
+            None => Vec::new(),
+        };
+        if debug_utils {
+            enabled_extension_names.push(DebugUtils::name());
+        }
+        if swapchain_colorspace {
+            enabled_extension_names.push(vk::ExtSwapchainColorspaceFn::name());
+        }
+        if properties2 {
+            enabled_extension_names.push(vk::KhrGetPhysicalDeviceProperties2Fn::name());
+        }
+        if surface_capabilities2 {
+            enabled_extension_names.push(vk::KhrGetSurfaceCapabilities2Fn::name());
+        }
+        for name in required_extensions {
+            if !enabled_extension_names.contains(&name.as_c_str()) {
+                enabled_extension_names.push(name);
+            }
+        }
         let enabled_extension_names: Vec<_> = enabled_extension_names
             .into_iter()
             .map(|name| name.as_ptr())
             .collect();
 
-        let mut debug_utils_messenger_create_info = Self::debug_utils_messenger_create_info();
-        let create_info = vk::InstanceCreateInfo::builder()
+        // Also catches messages from instance creation and destruction
+        let mut debug_utils_messenger_create_info = validation_log.messenger_create_info();
+        let mut create_info = vk::InstanceCreateInfo::builder()
             .application_info(&application_info)
             .enabled_layer_names(&enabled_layer_names)
-            .enabled_extension_names(&enabled_extension_names)
-            .push_next(&mut debug_utils_messenger_create_info);
+            .enabled_extension_names(&enabled_extension_names);
+        if debug_utils {
+            create_info = create_info.push_next(&mut debug_utils_messenger_create_info);
+        }
 
         Ok(entry.create_instance(&create_info, None)?.guard())
     }
 
-    fn debug_utils_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXTBuilder<'static> {
-        vk::DebugUtilsMessengerCreateInfoEXT::builder()
-            .message_severity(vk::DebugUtilsMessageSeverityFlagsEXT::all())
-            .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
-            .pfn_user_callback(Some(Self::debug_utils_callback))
-    }
-
-    unsafe extern "system" fn debug_utils_callback(
-        message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
-        message_types: vk::DebugUtilsMessageTypeFlagsEXT,
-        p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-        _p_user_data: *mut c_void,
-    ) -> u32 {
-        let message_severity = match message_severity {
-            vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE => log::Level::Debug,
-            vk::DebugUtilsMessageSeverityFlagsEXT::INFO => log::Level::Info,
-            vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => log::Level::Warn,
-            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => log::Level::Error,
-            _ => log::Level::Error,
-        };
-        let message = CStr::from_ptr((*p_callback_data).p_message);
-        if let Ok(message) = message.to_str() {
-            log::log!(message_severity, "{:?}: {}", message_types, message);
+    // Devices need the same layers as their instance
+    fn enabled_layer_names(validation: bool) -> Vec<*const std::os::raw::c_char> {
+        if validation {
+            vec![Self::VALIDATION_LAYER.as_ptr() as _]
         } else {
-            log::log!(message_severity, "{:?}: {:?}", message_types, message);
+            Vec::new()
         }
-        vk::FALSE
     }
 
     pub unsafe fn set_name<T: Handle>(
@@ -139,138 +387,583 @@ impl SharedCrown {
         object: T,
         name: &str,
     ) -> VkResult<()> {
+        let debug_utils_fn = match &self.debug_utils_fn {
+            Some(debug_utils_fn) => debug_utils_fn,
+            None => return Ok(()),
+        };
         let name = CString::new(name).unwrap();
         let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
             .object_type(T::TYPE)
             .object_handle(object.as_raw())
             .object_name(&name);
-        self.debug_utils_fn
-            .debug_utils_set_object_name(device.handle(), &name_info)
+        debug_utils_fn.debug_utils_set_object_name(device.handle(), &name_info)
+    }
+
+    // Groups the commands recorded until the matching end_label under a name, for debuggers and
+    // captures to show. Color is linear RGBA, which tools may use to tint the region.
+    pub unsafe fn begin_label(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        name: &str,
+        color: [f32; 4],
+    ) {
+        if let Some(debug_utils_fn) = &self.debug_utils_fn {
+            let name = CString::new(name).unwrap();
+            let label = vk::DebugUtilsLabelEXT::builder()
+                .label_name(&name)
+                .color(color);
+            debug_utils_fn.cmd_begin_debug_utils_label(command_buffer, &label);
+        }
+    }
+
+    pub unsafe fn end_label(&self, command_buffer: vk::CommandBuffer) {
+        if let Some(debug_utils_fn) = &self.debug_utils_fn {
+            debug_utils_fn.cmd_end_debug_utils_label(command_buffer);
+        }
+    }
+
+    pub fn api_version(&self) -> u32 {
+        self.api_version
     }
 
     pub fn instance(&self) -> &ash::Instance {
         &self.instance
     }
 
-    pub fn surface(&self) -> &Mutex<vk::SurfaceKHR> {
-        &self.surface
+    pub fn is_headless(&self) -> bool {
+        self.surface().is_none()
+    }
+
+    pub fn resolution(&self) -> vk::Extent2D {
+        match &self.output {
+            CrownOutput::Window { window, .. } => {
+                let winit::dpi::PhysicalSize { width, height } =
+                    window.lock().unwrap().inner_size();
+                vk::Extent2D { width, height }
+            }
+            CrownOutput::Headless(resolution) => *resolution,
+        }
+    }
+
+    // Some platforms, like Android, destroy the native window while the app is in the background.
+    // Anything presenting to the surface has to be gone by now.
+    pub unsafe fn suspend(&self) {
+        if let Some(surface) = self.surface() {
+            let mut surface = surface.lock().unwrap();
+            self.surface_fn.destroy_surface(*surface, None);
+            *surface = vk::SurfaceKHR::null();
+        }
+    }
+
+    pub unsafe fn resume(&self, new_window: Arc<Window>) -> Result<(), SharedCrownError> {
+        if let CrownOutput::Window {
+            surface,
+            window,
+            display_mode,
+        } = &self.output
+        {
+            let mut surface = surface.lock().unwrap();
+            self.surface_fn.destroy_surface(*surface, None);
+            *surface = vk::SurfaceKHR::null(); // still suspended if creation fails
+            *surface = ash_window::create_surface(&self.entry, &self.instance, &*new_window, None)
+                .map_err(SharedCrownError::SurfaceCreation)?;
+            let display_mode = *display_mode.lock().unwrap();
+            if display_mode != DisplayMode::Windowed {
+                new_window.set_fullscreen(display_mode.fullscreen(&new_window));
+            }
+            *window.lock().unwrap() = new_window;
+        }
+        Ok(())
+    }
+
+    pub fn display_mode(&self) -> DisplayMode {
+        match &self.output {
+            CrownOutput::Window { display_mode, .. } => *display_mode.lock().unwrap(),
+            CrownOutput::Headless(_) => DisplayMode::Windowed,
+        }
+    }
+
+    // Resizes the window to suit, so the swapchain needs rebuilding after. Returns whether
+    // anything changed, which it never does when headless.
+    pub fn set_display_mode(&self, new_display_mode: DisplayMode) -> bool {
+        match &self.output {
+            CrownOutput::Window {
+                window,
+                display_mode,
+                ..
+            } => {
+                let mut display_mode = display_mode.lock().unwrap();
+                if *display_mode == new_display_mode {
+                    return false;
+                }
+                let window = window.lock().unwrap();
+                window.set_fullscreen(new_display_mode.fullscreen(&window));
+                *display_mode = new_display_mode;
+                true
+            }
+            CrownOutput::Headless(_) => false,
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.surface()
+            .is_some_and(|surface| *surface.lock().unwrap() == vk::SurfaceKHR::null())
+    }
+
+    pub fn surface(&self) -> Option<&Mutex<vk::SurfaceKHR>> {
+        match &self.output {
+            CrownOutput::Window { surface, .. } => Some(surface),
+            CrownOutput::Headless(_) => None,
+        }
+    }
+
+    pub fn properties2_fn(&self) -> Option<&vk::KhrGetPhysicalDeviceProperties2Fn> {
+        self.properties2_fn.as_ref()
+    }
+
+    pub fn required_device(&self) -> Option<vk::PhysicalDevice> {
+        self.required_device
+    }
+
+    pub fn required_device_extensions(&self) -> &[CString] {
+        &self.required_device_extensions
+    }
+
+    pub fn surface_capabilities2(&self) -> bool {
+        self.surface_capabilities2
     }
 
     pub fn surface_fn(&self) -> &Surface {
         &self.surface_fn
     }
 
-    pub fn window_resolution(&self) -> vk::Extent2D {
-        let winit::dpi::PhysicalSize { width, height } = self.window.inner_size();
-        vk::Extent2D { width, height }
+    pub fn validation(&self) -> bool {
+        self.validation
+    }
+
+    pub fn set_validation_callback(&self, callback: Option<ValidationCallback>) {
+        self.validation_log.set_callback(callback);
+    }
+
+    pub fn validation_error_policy(&self) -> ValidationErrorPolicy {
+        self.validation_log.error_policy()
+    }
+
+    // Reported since last taken, if the policy isn't Log
+    pub fn take_validation_errors(&self) -> Vec<ValidationMessage> {
+        self.validation_log.take_errors()
     }
 }
 
 impl Drop for SharedCrown {
     fn drop(&mut self) {
-        let surface = self.surface.lock().unwrap();
         unsafe {
-            self.surface_fn.destroy_surface(*surface, None);
-            self.debug_utils_fn
-                .destroy_debug_utils_messenger(self.debug_utils_messenger, None);
+            if let Some(surface) = self.surface() {
+                self.surface_fn
+                    .destroy_surface(*surface.lock().unwrap(), None);
+            }
+            if let Some(debug_utils_fn) = &self.debug_utils_fn {
+                debug_utils_fn.destroy_debug_utils_messenger(self.debug_utils_messenger, None);
+            }
             self.instance.destroy_instance(None);
         }
     }
 }
 
 pub struct SharedStem {
-    command_buffer: vk::CommandBuffer,
+    capabilities: DeviceCapabilities,
+    color_workflow: ColorWorkflow,
     command_pool: vk::CommandPool,
+    composite_alpha: vk::CompositeAlphaFlagsKHR,
     crown: Arc<SharedCrown>,
+    depth_stencil_format: vk::Format,
+    descriptor_allocator: DescriptorAllocator,
     device: ash::Device,
-    fullscreen_vert_shader_module: vk::ShaderModule,
-    image_acquired_semaphore: vk::Semaphore,
+    draw_counter: DrawCounter,
+    frames: Vec<Frame>,
+    fullscreen_vert_shader_module: Mutex<vk::ShaderModule>, // replaced when hot reloading
+    output_encoding: OutputEncoding,
+    output_usage: vk::ImageUsageFlags, // of the output, as negotiated with the surface
     physical_device: vk::PhysicalDevice,
     physical_device_memory_properties: vk::PhysicalDeviceMemoryProperties,
-    presentation_fence: vk::Fence,
+    queue_lock: Mutex<()>, // queues are shared with the present thread
     queues: Queues,
-    render_complete_semaphore: vk::Semaphore,
+    retired_buffers: RetiredBuffers,
+    retired_textures: RetiredTextures,
+    secondary_command_pools: Vec<vk::CommandPool>, // one per recording thread
+    shadow_format: vk::Format,
+    staging_belt: StagingBelt,
+    surface_format: vk::SurfaceFormatKHR,
     swapchain_fn: Swapchain,
+    timestamp_period: Option<f32>, // nanoseconds per tick, if the graphics queue has timestamps
+    transparent: bool,             // see transparent()
+}
+
+// What the instance and device negotiated, and so which optional code paths get taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceCapabilities {
+    pub api_version: u32, // the lower of the instance's and device's, without the patch version
+    pub bindless: bool,   // index opaque materials' textures from one big descriptor array
+    pub depth_read_only_stencil_attachment: bool, // Vulkan 1.1's layouts from maintenance2
+    pub full_screen_exclusive: bool, // swapchains can say whether to bypass the compositor
+    pub max_sampler_anisotropy: u32, // 1 if the device can't filter anisotropically
+    pub pipeline_statistics: bool, // count each pass's invocations, geometry included
+    pub separate_depth_stencil_layouts: bool,
+    pub timeline_semaphores: bool, // pace frames with these instead of fences
 }
 
 #[derive(Error, Debug)]
 pub enum SharedStemError {
-    #[error("Vulkan error occurred")]
-    VkError(#[from] vk::Result), // TODO: split into contexts
+    #[error("Couldn't query graphics device")]
+    DeviceQuery(#[source] vk::Result),
+    #[error("Couldn't create graphics device")]
+    DeviceCreation(#[source] vk::Result),
+    #[error("Couldn't create command pool")]
+    CommandPoolCreation(#[source] vk::Result),
+    #[error("Couldn't create frame in flight")]
+    FrameCreation(#[source] vk::Result),
+    #[error("Couldn't create staging belt")]
+    StagingBeltCreation(#[source] vk::Result),
+    #[error("Couldn't create shader module")]
+    ShaderCreation(#[source] vk::Result),
+    #[error("Couldn't submit setup commands")]
+    Submission(#[source] vk::Result),
+    #[error("Couldn't name Vulkan object")]
+    Naming(#[source] vk::Result),
     #[error("Couldn't select acceptable graphics device")]
     NoAcceptableDeviceError,
+    #[error("Couldn't find a queue family apart from the graphics one that can present")]
+    NoSeparatePresentQueue,
+    #[error("Couldn't select acceptable surface format")]
+    NoAcceptableSurfaceFormat,
+    #[error("Couldn't select acceptable depth format with {0:?}")]
+    NoAcceptableDepthFormat(vk::FormatFeatureFlags),
     #[error("Couldn't select acceptable memory type for {0:?} and {1:?}")]
     NoAcceptableMeoryType(vk::MemoryRequirements, vk::MemoryPropertyFlags),
 }
 
+impl SharedStemError {
+    pub fn vk_result(&self) -> Option<vk::Result> {
+        match *self {
+            Self::DeviceQuery(result)
+            | Self::DeviceCreation(result)
+            | Self::CommandPoolCreation(result)
+            | Self::FrameCreation(result)
+            | Self::StagingBeltCreation(result)
+            | Self::ShaderCreation(result)
+            | Self::Submission(result)
+            | Self::Naming(result) => Some(result),
+            _ => None,
+        }
+    }
+}
+
 impl SharedStem {
-    pub fn new(crown: Arc<SharedCrown>) -> Result<Self, SharedStemError> {
+    // What headless renderers output instead of a swapchain image
+    const HEADLESS_FORMAT: vk::SurfaceFormatKHR = vk::SurfaceFormatKHR {
+        format: vk::Format::R8G8B8A8_SRGB,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    };
+
+    // In order of preference. Depth is reversed, so floats spend their precision on the distance.
+    const DEPTH_STENCIL_FORMATS: [vk::Format; 3] = [
+        vk::Format::D32_SFLOAT_S8_UINT,
+        vk::Format::D24_UNORM_S8_UINT,
+        vk::Format::D16_UNORM_S8_UINT,
+    ];
+    // Shadows don't need stencil, so depth-only formats come first
+    const SHADOW_FORMATS: [vk::Format; 4] = [
+        vk::Format::D32_SFLOAT,
+        vk::Format::D32_SFLOAT_S8_UINT,
+        vk::Format::D24_UNORM_S8_UINT,
+        vk::Format::D16_UNORM,
+    ];
+
+    // Beyond this, recording is unlikely to be the bottleneck
+    const MAX_RECORDING_THREADS: usize = 8;
+
+    pub fn new(
+        crown: Arc<SharedCrown>,
+        surface_format_preference: SurfaceFormatPreference,
+        color_workflow: ColorWorkflow,
+        present_queue: PresentQueuePreference,
+        output_usage: vk::ImageUsageFlags,
+        transparent: bool,
+    ) -> Result<Self, SharedStemError> {
         let instance = crown.instance();
-        let surface = crown.surface();
-        let surface = surface.lock().unwrap();
+        let surface_lock = crown.surface().map(|surface| surface.lock().unwrap());
+        let surface = surface_lock.as_deref().copied();
         let surface_fn = crown.surface_fn();
 
         unsafe {
-            let (physical_device, device, queues) =
-                Self::create_device_and_queues(instance, surface_fn, *surface)?;
+            let (physical_device, device, queues, capabilities) = Self::create_device_and_queues(
+                &crown,
+                surface_fn,
+                surface,
+                crown.validation(),
+                present_queue,
+            )?;
+            log::info!(
+                "Using Vulkan {}.{}",
+                vk::version_major(capabilities.api_version),
+                vk::version_minor(capabilities.api_version)
+            );
+            if capabilities.bindless {
+                log::info!("Using bindless textures for opaque materials");
+            } else {
+                log::info!(
+                    "{:?} unavailable, so every material gets its own descriptor set",
+                    vk::ExtDescriptorIndexingFn::name()
+                );
+            }
+            if !capabilities.timeline_semaphores {
+                log::info!("Timeline semaphores unavailable, so frames are paced with fences");
+            }
+            if capabilities.full_screen_exclusive {
+                log::info!("Exclusive fullscreen can bypass the compositor");
+            }
 
+            // Like surface_fn, this is never called for headless stems
             let swapchain_fn = Swapchain::new(instance, &*device);
 
-            drop(surface);
-
-            let command_pool = Self::create_command_pool(&device, queues.graphics_family)?;
-            crown.set_name(&device, *command_pool, "stem primary")?;
-            let command_buffer = Self::allocate_command_buffer(&device, *command_pool)?;
-            crown.set_name(&device, *command_pool, "stem primary")?;
-
-            let image_acquired_semaphore = device
-                .create_semaphore(&Default::default(), None)?
-                .guard_with(&*device);
-            crown.set_name(&device, *image_acquired_semaphore, "image acquired")?;
-            let render_complete_semaphore = device
-                .create_semaphore(&Default::default(), None)?
-                .guard_with(&*device);
-            crown.set_name(&device, *render_complete_semaphore, "render complete")?;
-
-            let signaled_fence_create_info =
-                vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
-            let presentation_fence = device
-                .create_fence(&signaled_fence_create_info, None)?
-                .guard_with(&*device);
-            crown.set_name(&device, *presentation_fence, "presentation")?;
+            let surface_format = match surface {
+                Some(surface) => Self::select_surface_format(
+                    surface_fn,
+                    physical_device,
+                    surface,
+                    surface_format_preference,
+                )
+                .map_err(SharedStemError::DeviceQuery)?
+                .ok_or(SharedStemError::NoAcceptableSurfaceFormat)?,
+                None => Self::HEADLESS_FORMAT,
+            };
+            let output_encoding = OutputEncoding::of(surface_format).unwrap();
+            if surface.is_some() && output_encoding != surface_format_preference.encoding() {
+                log::info!(
+                    "{:?} unavailable, falling back to {:?}",
+                    surface_format_preference,
+                    surface_format,
+                );
+            }
+            if output_encoding == OutputEncoding::SrgbShader {
+                log::info!("Encoding sRGB in shaders for {:?}", surface_format.format);
+            }
+
+            let output_usage = Self::negotiate_output_usage(
+                instance,
+                surface_fn,
+                physical_device,
+                surface,
+                surface_format.format,
+                output_usage,
+            )
+            .map_err(SharedStemError::DeviceQuery)?;
+            let (composite_alpha, transparent) = match surface {
+                Some(surface) => {
+                    Self::select_composite_alpha(surface_fn, physical_device, surface, transparent)
+                        .map_err(SharedStemError::DeviceQuery)?
+                }
+                None => (vk::CompositeAlphaFlagsKHR::OPAQUE, transparent),
+            };
+
+            drop(surface_lock);
+
+            let depth_stencil_format = Self::select_depth_format(
+                instance,
+                physical_device,
+                &Self::DEPTH_STENCIL_FORMATS,
+                vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT
+                    | vk::FormatFeatureFlags::SAMPLED_IMAGE,
+            )?;
+            let shadow_format = Self::select_depth_format(
+                instance,
+                physical_device,
+                &Self::SHADOW_FORMATS,
+                vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT
+                    | vk::FormatFeatureFlags::SAMPLED_IMAGE,
+            )?;
+            log::info!(
+                "Using {:?} for depth and {:?} for shadows",
+                depth_stencil_format,
+                shadow_format,
+            );
+
+            let command_pool = Self::create_command_pool(&device, queues.graphics_family)
+                .map_err(SharedStemError::CommandPoolCreation)?;
+            crown
+                .set_name(&device, *command_pool, "stem primary")
+                .map_err(SharedStemError::Naming)?;
+
+            let recording_threads = jobs::thread_count().min(Self::MAX_RECORDING_THREADS);
+            let mut secondary_command_pools = Vec::<vk::CommandPool>::new().guard_with(&*device);
+            for thread in 0..recording_threads {
+                let secondary_command_pool =
+                    Self::create_command_pool(&device, queues.graphics_family)
+                        .map_err(SharedStemError::CommandPoolCreation)?;
+                let name = format!("stem secondary {}", thread);
+                crown
+                    .set_name(&device, *secondary_command_pool, &name)
+                    .map_err(SharedStemError::Naming)?;
+                secondary_command_pools.push(secondary_command_pool.take());
+            }
+
+            let mut frames = Vec::<Frame>::new().guard_with(&*device);
+            for index in 0..FRAMES_IN_FLIGHT {
+                let frame = Frame::new(
+                    &*device,
+                    *command_pool,
+                    &secondary_command_pools,
+                    capabilities.timeline_semaphores,
+                    capabilities.pipeline_statistics,
+                )
+                .map_err(SharedStemError::FrameCreation)?;
+                let name = |object| format!("{} {}", object, index);
+                crown
+                    .set_name(&device, frame.command_buffer, &name("stem primary"))
+                    .map_err(SharedStemError::Naming)?;
+                for (thread, &command_buffer) in frame.secondary_command_buffers.iter().enumerate()
+                {
+                    let name = format!("stem secondary {} {}", thread, index);
+                    crown
+                        .set_name(&device, command_buffer, &name)
+                        .map_err(SharedStemError::Naming)?;
+                }
+                crown
+                    .set_name(
+                        &device,
+                        frame.image_acquired_semaphore,
+                        &name("image acquired"),
+                    )
+                    .map_err(SharedStemError::Naming)?;
+                crown
+                    .set_name(&device, frame.presentation_fence, &name("presentation"))
+                    .map_err(SharedStemError::Naming)?;
+                if let Some(timeline_semaphore) = frame.timeline_semaphore {
+                    crown
+                        .set_name(&device, timeline_semaphore, &name("timeline"))
+                        .map_err(SharedStemError::Naming)?;
+                }
+                crown
+                    .set_name(&device, frame.timestamp_query_pool, &name("timestamps"))
+                    .map_err(SharedStemError::Naming)?;
+                if let Some(statistics_query_pool) = frame.statistics_query_pool {
+                    crown
+                        .set_name(&device, statistics_query_pool, &name("statistics"))
+                        .map_err(SharedStemError::Naming)?;
+                }
+                frames.push(frame.take());
+            }
 
             let physical_device_memory_properties =
                 instance.get_physical_device_memory_properties(physical_device);
 
-            let fullscreen_vert_shader_module =
-                util::create_shader_module(&device, include_glsl!("shaders/fullscreen.vert"))?;
-            crown.set_name(&device, *fullscreen_vert_shader_module, "fullscreen vert")?;
+            let staging_belt = StagingBelt::new(&device, physical_device_memory_properties)
+                .map_err(SharedStemError::StagingBeltCreation)?
+                .map_err(|(memory_requirements, memory_flags)| {
+                    SharedStemError::NoAcceptableMeoryType(memory_requirements, memory_flags)
+                })?;
+
+            let timestamp_valid_bits = instance
+                .get_physical_device_queue_family_properties(physical_device)
+                [queues.graphics_family as usize]
+                .timestamp_valid_bits;
+            let timestamp_period = if timestamp_valid_bits > 0 {
+                let properties = instance.get_physical_device_properties(physical_device);
+                Some(properties.limits.timestamp_period)
+            } else {
+                None
+            };
 
-            Ok(Self {
+            let fullscreen_vert_shader_module =
+                util::create_shader_module(&device, &include_shader!("shaders/fullscreen.vert"))
+                    .map_err(SharedStemError::ShaderCreation)?;
+            crown
+                .set_name(&device, *fullscreen_vert_shader_module, "fullscreen vert")
+                .map_err(SharedStemError::Naming)?;
+
+            let stem = Self {
+                capabilities,
+                color_workflow,
                 command_pool: command_pool.take(),
-                fullscreen_vert_shader_module: fullscreen_vert_shader_module.take(),
-                image_acquired_semaphore: image_acquired_semaphore.take(),
-                presentation_fence: presentation_fence.take(),
-                render_complete_semaphore: render_complete_semaphore.take(),
+                composite_alpha,
+                depth_stencil_format,
+                descriptor_allocator: Default::default(),
+                draw_counter: Default::default(),
+                frames: frames.take(),
+                fullscreen_vert_shader_module: Mutex::new(fullscreen_vert_shader_module.take()),
+                output_encoding,
+                output_usage,
+                secondary_command_pools: secondary_command_pools.take(),
+                shadow_format,
+                staging_belt: staging_belt.take(),
                 device: device.take(),
-                command_buffer,
                 crown,
                 physical_device,
                 physical_device_memory_properties,
+                queue_lock: Mutex::new(()),
                 queues,
+                retired_buffers: Default::default(),
+                retired_textures: Default::default(),
+                surface_format,
                 swapchain_fn,
+                timestamp_period,
+                transparent,
+            };
+
+            // Queries start out undefined, and reading them back requires them to have been reset
+            stem.submit_one_time_commands(|command_buffer| {
+                for frame in &stem.frames {
+                    frame.reset_queries(&stem.device, command_buffer);
+                }
             })
+            .map_err(SharedStemError::Submission)?;
+
+            Ok(stem)
         }
     }
 
+    // Optional features are enabled whenever the device has them
     unsafe fn create_device_and_queues(
-        instance: &ash::Instance,
+        crown: &SharedCrown,
         surface_fn: &Surface,
-        surface: vk::SurfaceKHR,
-    ) -> Result<(vk::PhysicalDevice, Guarded<ash::Device>, Queues), SharedStemError> {
+        surface: Option<vk::SurfaceKHR>,
+        validation: bool,
+        present_queue: PresentQueuePreference,
+    ) -> Result<
+        (
+            vk::PhysicalDevice,
+            Guarded<ash::Device>,
+            Queues,
+            DeviceCapabilities,
+        ),
+        SharedStemError,
+    > {
+        let instance = crown.instance();
         let (physical_device, graphics_queue_family, present_queue_family) =
-            Self::select_physical_device_and_queue_families(instance, surface_fn, surface)?
-                .ok_or(SharedStemError::NoAcceptableDeviceError)?;
+            Self::select_physical_device_and_queue_families(
+                instance,
+                surface_fn,
+                surface,
+                crown.required_device(),
+                present_queue,
+            )
+            .map_err(SharedStemError::DeviceQuery)?
+            .ok_or(match (present_queue, surface) {
+                (PresentQueuePreference::Separate, Some(_)) => {
+                    SharedStemError::NoSeparatePresentQueue
+                }
+                _ => SharedStemError::NoAcceptableDeviceError,
+            })?;
+        if graphics_queue_family != present_queue_family {
+            log::info!(
+                "Presenting from queue family {}, apart from graphics family {}",
+                present_queue_family,
+                graphics_queue_family
+            );
+        }
+        let capabilities = Self::negotiate_capabilities(crown, physical_device)
+            .map_err(SharedStemError::DeviceQuery)?;
+        let bindless = capabilities.bindless;
 
         let queue_priorities = [1.0];
         let queue_create_infos = [
@@ -289,16 +982,55 @@ impl SharedStem {
             &queue_create_infos
         };
 
-        let validation_layer = CString::new("VK_LAYER_KHRONOS_validation").unwrap();
-        let enabled_layer_names = [validation_layer.as_ptr()];
+        let enabled_layer_names = SharedCrown::enabled_layer_names(validation);
 
-        let enabled_extension_names = [Swapchain::name().as_ptr()];
-        let device_create_info = vk::DeviceCreateInfo::builder()
+        let mut enabled_extension_names = match surface {
+            Some(_) => vec![Swapchain::name().as_ptr()],
+            None => Vec::new(),
+        };
+        if bindless {
+            enabled_extension_names.extend(Self::bindless_extension_names().map(CStr::as_ptr));
+        }
+        if capabilities.full_screen_exclusive {
+            enabled_extension_names.push(vk::ExtFullScreenExclusiveFn::name().as_ptr());
+        }
+        for name in crown.required_device_extensions() {
+            let enabled = enabled_extension_names
+                .iter()
+                .any(|&enabled| CStr::from_ptr(enabled) == name.as_c_str());
+            if !enabled {
+                enabled_extension_names.push(name.as_ptr());
+            }
+        }
+
+        let enabled_features = vk::PhysicalDeviceFeatures::builder()
+            .sampler_anisotropy(capabilities.max_sampler_anisotropy > 1)
+            .pipeline_statistics_query(capabilities.pipeline_statistics)
+            .inherited_queries(capabilities.pipeline_statistics)
+            .shader_sampled_image_array_dynamic_indexing(bindless);
+        let mut descriptor_indexing_features = Self::bindless_features();
+        // Vulkan 1.2 wants its features in one struct, which can't be chained alongside the
+        // per-extension structs it absorbed
+        let mut vulkan_12_features = vk::PhysicalDeviceVulkan12Features::builder()
+            .separate_depth_stencil_layouts(capabilities.separate_depth_stencil_layouts)
+            .timeline_semaphore(capabilities.timeline_semaphores)
+            .descriptor_indexing(bindless)
+            .descriptor_binding_partially_bound(bindless)
+            .descriptor_binding_update_unused_while_pending(bindless)
+            .runtime_descriptor_array(bindless);
+        let mut device_create_info = vk::DeviceCreateInfo::builder()
             .queue_create_infos(queue_create_infos)
             .enabled_extension_names(&enabled_extension_names)
-            .enabled_layer_names(&enabled_layer_names);
+            .enabled_layer_names(&enabled_layer_names)
+            .enabled_features(&enabled_features);
+        if capabilities.api_version >= vk::make_version(1, 2, 0) {
+            device_create_info = device_create_info.push_next(&mut vulkan_12_features);
+        } else if bindless {
+            device_create_info = device_create_info.push_next(&mut descriptor_indexing_features);
+        }
         let device = instance
-            .create_device(physical_device, &device_create_info, None)?
+            .create_device(physical_device, &device_create_info, None)
+            .map_err(SharedStemError::DeviceCreation)?
             .guard();
 
         let queues = Queues {
@@ -308,37 +1040,339 @@ impl SharedStem {
             present_family: present_queue_family,
         };
 
-        Ok((physical_device, device, queues))
+        Ok((physical_device, device, queues, capabilities))
+    }
+
+    unsafe fn negotiate_capabilities(
+        crown: &SharedCrown,
+        physical_device: vk::PhysicalDevice,
+    ) -> VkResult<DeviceCapabilities> {
+        let instance = crown.instance();
+        let properties = instance.get_physical_device_properties(physical_device);
+        let api_version = version_without_patch(properties.api_version).min(crown.api_version());
+
+        let features = instance.get_physical_device_features(physical_device);
+        let max_sampler_anisotropy = match features.sampler_anisotropy {
+            vk::TRUE => properties.limits.max_sampler_anisotropy.max(1.0) as u32,
+            _ => 1,
+        };
+        // Geometry is drawn from secondary command buffers, which must inherit the queries
+        let pipeline_statistics = features.pipeline_statistics_query == vk::TRUE
+            && features.inherited_queries == vk::TRUE;
+
+        let bindless = match crown.properties2_fn() {
+            Some(properties2_fn) => {
+                Self::device_supports_bindless(instance, properties2_fn, physical_device)?
+            }
+            None => false,
+        };
+
+        let mut vulkan_12_features = vk::PhysicalDeviceVulkan12Features::default();
+        if api_version >= vk::make_version(1, 2, 0) {
+            // This version of ash can't chain onto PhysicalDeviceFeatures2's builder
+            let mut features = vk::PhysicalDeviceFeatures2 {
+                p_next: &mut vulkan_12_features as *mut _ as *mut c_void,
+                ..Default::default()
+            };
+            instance.get_physical_device_features2(physical_device, &mut features);
+        }
+
+        // Only Windows drivers offer it, and it's meaningless without a surface
+        let full_screen_exclusive = crown.surface_capabilities2()
+            && instance
+                .enumerate_device_extension_properties(physical_device)?
+                .iter()
+                .any(|extension| {
+                    CStr::from_ptr(extension.extension_name.as_ptr())
+                        == vk::ExtFullScreenExclusiveFn::name()
+                });
+
+        Ok(DeviceCapabilities {
+            api_version,
+            bindless,
+            depth_read_only_stencil_attachment: api_version >= vk::make_version(1, 1, 0),
+            full_screen_exclusive,
+            max_sampler_anisotropy,
+            pipeline_statistics,
+            separate_depth_stencil_layouts: vulkan_12_features.separate_depth_stencil_layouts
+                == vk::TRUE,
+            timeline_semaphores: vulkan_12_features.timeline_semaphore == vk::TRUE,
+        })
+    }
+
+    fn bindless_extension_names() -> [&'static CStr; 2] {
+        [
+            vk::ExtDescriptorIndexingFn::name(),
+            vk::KhrMaintenance3Fn::name(), // required by descriptor indexing
+        ]
+    }
+
+    // A partially filled, runtime-sized texture array that gains entries while earlier frames are
+    // still in flight
+    fn bindless_features() -> vk::PhysicalDeviceDescriptorIndexingFeatures {
+        vk::PhysicalDeviceDescriptorIndexingFeatures::builder()
+            .descriptor_binding_partially_bound(true)
+            .descriptor_binding_update_unused_while_pending(true)
+            .runtime_descriptor_array(true)
+            .build()
+    }
+
+    unsafe fn device_supports_bindless(
+        instance: &ash::Instance,
+        properties2_fn: &vk::KhrGetPhysicalDeviceProperties2Fn,
+        physical_device: vk::PhysicalDevice,
+    ) -> VkResult<bool> {
+        let available_extensions =
+            instance.enumerate_device_extension_properties(physical_device)?;
+        let has_extension = |name: &CStr| {
+            available_extensions
+                .iter()
+                .any(|extension| CStr::from_ptr(extension.extension_name.as_ptr()) == name)
+        };
+        if !Self::bindless_extension_names()
+            .iter()
+            .all(|&name| has_extension(name))
+        {
+            return Ok(false);
+        }
+
+        let mut descriptor_indexing_features =
+            vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+        // This version of ash can't chain onto PhysicalDeviceFeatures2's builder
+        let mut features = vk::PhysicalDeviceFeatures2 {
+            p_next: &mut descriptor_indexing_features as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        properties2_fn.get_physical_device_features2_khr(physical_device, &mut features);
+        let dynamic_indexing = features
+            .features
+            .shader_sampled_image_array_dynamic_indexing;
+
+        // Bindless draws push their material index after the view and model
+        let limits = instance
+            .get_physical_device_properties(physical_device)
+            .limits;
+        let push_constant_range = MaterialIndexBuffer::push_constant_range();
+        let push_constants_fit =
+            push_constant_range.offset + push_constant_range.size <= limits.max_push_constants_size;
+
+        // Everything bindless_features() asks for
+        let supported = descriptor_indexing_features;
+        Ok(push_constants_fit
+            && dynamic_indexing == vk::TRUE
+            && supported.descriptor_binding_partially_bound == vk::TRUE
+            && supported.descriptor_binding_update_unused_while_pending == vk::TRUE
+            && supported.runtime_descriptor_array == vk::TRUE)
     }
 
     unsafe fn select_physical_device_and_queue_families(
         instance: &ash::Instance,
         surface_fn: &Surface,
-        surface: vk::SurfaceKHR,
+        surface: Option<vk::SurfaceKHR>,
+        required_device: Option<vk::PhysicalDevice>,
+        preference: PresentQueuePreference,
     ) -> VkResult<Option<(vk::PhysicalDevice, u32, u32)>> {
-        for physical_device in instance.enumerate_physical_devices()? {
+        let physical_devices = instance
+            .enumerate_physical_devices()?
+            .into_iter()
+            .filter(|&physical_device| required_device.is_none_or(|x| x == physical_device));
+        for physical_device in physical_devices {
             let queue_families =
                 instance.get_physical_device_queue_family_properties(physical_device);
-            let graphics_queue = queue_families
-                .iter()
-                .position(|info| info.queue_flags.contains(vk::QueueFlags::GRAPHICS));
+            // Culling dispatches compute work on the same queue as the draws it feeds
+            let graphics_queue = queue_families.iter().position(|info| {
+                info.queue_flags
+                    .contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+            });
+
+            // Without a surface nothing gets presented, so the graphics queue stands in
+            let surface = match surface {
+                Some(surface) => surface,
+                None => match graphics_queue {
+                    Some(graphics_queue) => {
+                        return Ok(Some((
+                            physical_device,
+                            graphics_queue as _,
+                            graphics_queue as _,
+                        )))
+                    }
+                    None => continue,
+                },
+            };
 
-            for (present_queue, _) in queue_families.iter().enumerate() {
-                let supports_surface = surface_fn.get_physical_device_surface_support(
+            let graphics_queue = match graphics_queue {
+                Some(graphics_queue) => graphics_queue as u32,
+                None => continue,
+            };
+            let mut presenting_queues = Vec::new();
+            for present_queue in 0..queue_families.len() as u32 {
+                if surface_fn.get_physical_device_surface_support(
                     physical_device,
-                    present_queue as _,
+                    present_queue,
                     surface,
-                )?;
-                if supports_surface {
-                    return Ok(graphics_queue.map(|graphics_queue| {
-                        (physical_device, graphics_queue as _, present_queue as _)
-                    }));
+                )? {
+                    presenting_queues.push(present_queue);
                 }
             }
+            // Presenting from the graphics family spares the swapchain images from being shared
+            let present_queue = match preference {
+                PresentQueuePreference::Graphics => presenting_queues
+                    .iter()
+                    .copied()
+                    .find(|&present_queue| present_queue == graphics_queue)
+                    .or_else(|| presenting_queues.first().copied()),
+                PresentQueuePreference::Separate => presenting_queues
+                    .iter()
+                    .copied()
+                    .find(|&present_queue| present_queue != graphics_queue),
+            };
+            if let Some(present_queue) = present_queue {
+                return Ok(Some((physical_device, graphics_queue, present_queue)));
+            }
         }
         Ok(None)
     }
 
+    // Color attachment, plus whichever of the wanted usages both the surface and the format allow.
+    // Headless output is read back, so can always be copied from.
+    unsafe fn negotiate_output_usage(
+        instance: &ash::Instance,
+        surface_fn: &Surface,
+        physical_device: vk::PhysicalDevice,
+        surface: Option<vk::SurfaceKHR>,
+        format: vk::Format,
+        wanted: vk::ImageUsageFlags,
+    ) -> VkResult<vk::ImageUsageFlags> {
+        let mut supported = match surface {
+            Some(surface) => {
+                surface_fn
+                    .get_physical_device_surface_capabilities(physical_device, surface)?
+                    .supported_usage_flags
+            }
+            None => vk::ImageUsageFlags::all(),
+        };
+        let format_features = instance
+            .get_physical_device_format_properties(physical_device, format)
+            .optimal_tiling_features;
+        if !format_features.contains(vk::FormatFeatureFlags::STORAGE_IMAGE) {
+            supported &= !vk::ImageUsageFlags::STORAGE;
+        }
+
+        let usage = wanted & supported;
+        if usage != wanted {
+            log::warn!(
+                "Output can't be used for {:?}, so passes will have to do without",
+                wanted & !supported
+            );
+        }
+        let headless_usage = match surface {
+            Some(_) => vk::ImageUsageFlags::empty(),
+            None => vk::ImageUsageFlags::TRANSFER_SRC,
+        };
+        Ok(vk::ImageUsageFlags::COLOR_ATTACHMENT | usage | headless_usage)
+    }
+
+    // Transparent windows need the compositor to blend the output's premultiplied alpha, which
+    // INHERIT leaves to the window system, as Wayland does. Opaque windows take any mode, since
+    // their alpha is always 1. Also returns whether the output ends up transparent.
+    unsafe fn select_composite_alpha(
+        surface_fn: &Surface,
+        physical_device: vk::PhysicalDevice,
+        surface: vk::SurfaceKHR,
+        transparent: bool,
+    ) -> VkResult<(vk::CompositeAlphaFlagsKHR, bool)> {
+        let supported = surface_fn
+            .get_physical_device_surface_capabilities(physical_device, surface)?
+            .supported_composite_alpha;
+        let transparent_modes = [
+            vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+            vk::CompositeAlphaFlagsKHR::INHERIT,
+        ];
+        let opaque_modes = [
+            vk::CompositeAlphaFlagsKHR::OPAQUE,
+            vk::CompositeAlphaFlagsKHR::INHERIT,
+            vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+            vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED,
+        ];
+        let find = |modes: &[vk::CompositeAlphaFlagsKHR]| {
+            modes.iter().copied().find(|&mode| supported.contains(mode))
+        };
+        if transparent {
+            if let Some(mode) = find(&transparent_modes) {
+                return Ok((mode, true));
+            }
+            log::warn!(
+                "Surface only supports {:?}, so the window stays opaque",
+                supported
+            );
+        }
+        // Surfaces must support at least one mode
+        let mode = find(&opaque_modes).unwrap_or(vk::CompositeAlphaFlagsKHR::OPAQUE);
+        Ok((mode, false))
+    }
+
+    // Ties go to whichever the surface lists first
+    unsafe fn select_surface_format(
+        surface_fn: &Surface,
+        physical_device: vk::PhysicalDevice,
+        surface: vk::SurfaceKHR,
+        preference: SurfaceFormatPreference,
+    ) -> VkResult<Option<vk::SurfaceFormatKHR>> {
+        let surface_formats = Self::surface_formats(surface_fn, physical_device, surface)?;
+        Ok(surface_formats
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &surface_format)| {
+                let score = preference.score(surface_format)?;
+                Some(((score, std::cmp::Reverse(index)), surface_format))
+            })
+            .max_by_key(|&(key, _)| key)
+            .map(|(_, surface_format)| surface_format))
+    }
+
+    // A lone UNDEFINED means the surface takes any format, so it's taken to offer the usual ones
+    unsafe fn surface_formats(
+        surface_fn: &Surface,
+        physical_device: vk::PhysicalDevice,
+        surface: vk::SurfaceKHR,
+    ) -> VkResult<Vec<vk::SurfaceFormatKHR>> {
+        let surface_formats =
+            surface_fn.get_physical_device_surface_formats(physical_device, surface)?;
+        Ok(match surface_formats.as_slice() {
+            [only] if only.format == vk::Format::UNDEFINED => [
+                vk::Format::B8G8R8A8_SRGB,
+                vk::Format::R8G8B8A8_SRGB,
+                vk::Format::B8G8R8A8_UNORM,
+                vk::Format::R8G8B8A8_UNORM,
+            ]
+            .iter()
+            .map(|&format| vk::SurfaceFormatKHR {
+                format,
+                color_space: only.color_space,
+            })
+            .collect(),
+            _ => surface_formats,
+        })
+    }
+
+    unsafe fn select_depth_format(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        candidates: &[vk::Format],
+        features: vk::FormatFeatureFlags,
+    ) -> Result<vk::Format, SharedStemError> {
+        candidates
+            .iter()
+            .copied()
+            .find(|&format| {
+                instance
+                    .get_physical_device_format_properties(physical_device, format)
+                    .optimal_tiling_features
+                    .contains(features)
+            })
+            .ok_or(SharedStemError::NoAcceptableDepthFormat(features))
+    }
+
     unsafe fn create_command_pool(
         device: &ash::Device,
         queue_family_index: u32,
@@ -361,12 +1395,81 @@ impl SharedStem {
         Ok(device.allocate_command_buffers(&command_buffer_allocate_info)?[0])
     }
 
+    // Records commands into a temporary command buffer, submits it and blocks until it completes.
+    pub unsafe fn submit_one_time_commands(
+        &self,
+        record: impl FnOnce(vk::CommandBuffer),
+    ) -> VkResult<()> {
+        let device = &self.device;
+
+        let command_buffer = Self::allocate_command_buffer(device, self.command_pool)?;
+        let command_buffers = [command_buffer];
+        let _command_buffer = scopeguard::guard((), |_| {
+            device.free_command_buffers(self.command_pool, &command_buffers)
+        });
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        device.begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
+        record(command_buffer);
+        device.end_command_buffer(command_buffer)?;
+
+        let fence = device
+            .create_fence(&Default::default(), None)?
+            .guard_with(device);
+        let submit_info = vk::SubmitInfo::builder().command_buffers(&command_buffers);
+        {
+            let _queues = self.lock_queues();
+            device.queue_submit(self.queues.graphics, &[submit_info.build()], *fence)?;
+        }
+        device.wait_for_fences(&[*fence], true, u64::MAX)
+    }
+
+    // Where the final image ends up: presented, or read back when headless
+    pub fn output_layout(&self) -> vk::ImageLayout {
+        if self.crown.is_headless() {
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL
+        } else {
+            vk::ImageLayout::PRESENT_SRC_KHR
+        }
+    }
+
+    // Headless stems never load the swapchain extension, so there's nothing to destroy
+    // A resumed surface is new, so might not take the device or format chosen for the old one
+    pub unsafe fn supports_surface(&self) -> VkResult<bool> {
+        let surface = match self.crown.surface() {
+            Some(surface) => *surface.lock().unwrap(),
+            None => return Ok(true),
+        };
+        let surface_fn = self.crown.surface_fn();
+        let presentable = surface_fn.get_physical_device_surface_support(
+            self.physical_device,
+            self.queues.present_family,
+            surface,
+        )?;
+        let formats = Self::surface_formats(surface_fn, self.physical_device, surface)?;
+        Ok(presentable && formats.contains(&self.surface_format))
+    }
+
+    pub unsafe fn destroy_swapchain(&self, swapchain: vk::SwapchainKHR) {
+        if !self.crown.is_headless() {
+            self.swapchain_fn.destroy_swapchain(swapchain, None);
+        }
+    }
+
     pub fn assert_is(&self, other: &Self) {
-        if self as *const Self != other as *const Self {
+        if !std::ptr::eq(self, other) {
             panic!("Mismatched stems");
         }
     }
 
+    // For checks on every bind, which are too frequent to leave in release builds
+    pub fn debug_assert_is(&self, other: &Self) {
+        if cfg!(debug_assertions) {
+            self.assert_is(other);
+        }
+    }
+
     pub fn select_memory_type(
         &self,
         memory_requirements: vk::MemoryRequirements,
@@ -383,8 +1486,27 @@ impl SharedStem {
         self.crown.set_name(&self.device, object, name)
     }
 
-    pub fn command_buffer(&self) -> vk::CommandBuffer {
-        self.command_buffer
+    pub unsafe fn begin_label(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        name: &str,
+        color: [f32; 4],
+    ) {
+        self.crown.begin_label(command_buffer, name, color)
+    }
+
+    pub unsafe fn end_label(&self, command_buffer: vk::CommandBuffer) {
+        self.crown.end_label(command_buffer)
+    }
+
+    // Call alongside each draw recorded, for RendererStats
+    pub fn count_draw(&self, triangles: u64) {
+        self.draw_counter.count(triangles);
+    }
+
+    // Returns draw calls and triangles counted since the last call
+    pub fn take_draw_counts(&self) -> (u64, u64) {
+        self.draw_counter.take()
     }
 
     pub fn crown(&self) -> Arc<SharedCrown> {
@@ -395,28 +1517,116 @@ impl SharedStem {
         &self.device
     }
 
+    pub fn frame(&self, index: usize) -> &Frame {
+        &self.frames[index]
+    }
+
+    pub fn staging_belt(&self) -> &StagingBelt {
+        &self.staging_belt
+    }
+
+    pub fn descriptor_allocator(&self) -> &DescriptorAllocator {
+        &self.descriptor_allocator
+    }
+
+    pub fn retired_buffers(&self) -> &RetiredBuffers {
+        &self.retired_buffers
+    }
+
+    pub fn retired_textures(&self) -> &RetiredTextures {
+        &self.retired_textures
+    }
+
+    pub fn timestamp_period(&self) -> Option<f32> {
+        self.timestamp_period
+    }
+
     pub fn fullscreen_vert_shader_module(&self) -> vk::ShaderModule {
-        self.fullscreen_vert_shader_module
+        *self.fullscreen_vert_shader_module.lock().unwrap()
     }
 
-    pub fn image_acquired_semaphore(&self) -> vk::Semaphore {
-        self.image_acquired_semaphore
+    // Pipelines already made from the old module are unaffected, so only those made afterwards see
+    // the edit
+    #[cfg(feature = "hot-reload")]
+    pub fn reload_fullscreen_vert_shader_module(&self) -> Result<(), SharedStemError> {
+        unsafe {
+            let device = &self.device;
+            let fullscreen_vert_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/fullscreen.vert"))
+                    .map_err(SharedStemError::ShaderCreation)?;
+            self.crown
+                .set_name(device, *fullscreen_vert_shader_module, "fullscreen vert")
+                .map_err(SharedStemError::Naming)?;
+            let old = std::mem::replace(
+                &mut *self.fullscreen_vert_shader_module.lock().unwrap(),
+                fullscreen_vert_shader_module.take(),
+            );
+            device.destroy_shader_module(old, None);
+        }
+        Ok(())
     }
 
     pub fn physical_device(&self) -> vk::PhysicalDevice {
         self.physical_device
     }
 
-    pub fn presentation_fence(&self) -> vk::Fence {
-        self.presentation_fence
-    }
-
     pub fn queues(&self) -> &Queues {
         &self.queues
     }
 
-    pub fn render_complete_semaphore(&self) -> vk::Semaphore {
-        self.render_complete_semaphore
+    // Must be held while submitting to or presenting from either queue, since they must be
+    // externally synchronized and may be the same queue
+    pub fn lock_queues(&self) -> MutexGuard<'_, ()> {
+        self.queue_lock.lock().unwrap()
+    }
+
+    pub fn surface_format(&self) -> vk::SurfaceFormatKHR {
+        self.surface_format
+    }
+
+    // What the swapchain's images, or the offscreen image standing in for them, can be used for
+    pub fn output_usage(&self) -> vk::ImageUsageFlags {
+        self.output_usage
+    }
+
+    // Whether the output carries premultiplied alpha, letting the window show through wherever
+    // nothing was drawn
+    pub fn transparent(&self) -> bool {
+        self.transparent
+    }
+
+    pub fn depth_stencil_format(&self) -> vk::Format {
+        self.depth_stencil_format
+    }
+
+    pub fn shadow_format(&self) -> vk::Format {
+        self.shadow_format
+    }
+
+    pub fn output_encoding(&self) -> OutputEncoding {
+        self.output_encoding
+    }
+
+    pub fn capabilities(&self) -> DeviceCapabilities {
+        self.capabilities
+    }
+
+    pub fn supports_bindless(&self) -> bool {
+        self.capabilities.bindless
+    }
+
+    // How passes after geometry hold depth, when they both test against it and read it as an
+    // input attachment
+    pub fn depth_read_layout(&self) -> vk::ImageLayout {
+        if self.capabilities.depth_read_only_stencil_attachment {
+            vk::ImageLayout::DEPTH_READ_ONLY_STENCIL_ATTACHMENT_OPTIMAL
+        } else {
+            vk::ImageLayout::GENERAL
+        }
+    }
+
+    pub fn color_workflow(&self) -> ColorWorkflow {
+        self.color_workflow
     }
 
     pub fn swapchain_fn(&self) -> &Swapchain {
@@ -430,10 +1640,20 @@ impl Drop for SharedStem {
             let device = &self.device;
             let _ = device.device_wait_idle();
 
-            device.destroy_shader_module(self.fullscreen_vert_shader_module, None);
-            device.destroy_fence(self.presentation_fence, None);
-            device.destroy_semaphore(self.image_acquired_semaphore, None);
-            device.destroy_semaphore(self.render_complete_semaphore, None);
+            device.destroy_shader_module(
+                *self.fullscreen_vert_shader_module.get_mut().unwrap(),
+                None,
+            );
+            for frame in &mut self.frames {
+                frame.destroy_with(device);
+            }
+            self.staging_belt.destroy_with(device);
+            self.retired_buffers.destroy_with(device);
+            self.retired_textures.destroy_with(device);
+            self.descriptor_allocator.destroy_with(device);
+            for &secondary_command_pool in self.secondary_command_pools.iter() {
+                device.destroy_command_pool(secondary_command_pool, None);
+            }
             device.destroy_command_pool(self.command_pool, None);
             device.destroy_device(None);
         }
@@ -462,83 +1682,397 @@ impl ViewBuffer {
     }
 }
 
+#[derive(AsStd140)]
+pub struct ModelBuffer {
+    pub model: ColumnMatrix4<f32>,
+}
+
+impl ModelBuffer {
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            offset: ViewBuffer::std140_size_static() as _,
+            size: Self::std140_size_static() as _,
+        }
+    }
+}
+
+// Picks a bindless material's record
+#[derive(AsStd140)]
+pub struct MaterialIndexBuffer {
+    pub material_index: u32,
+}
+
+impl MaterialIndexBuffer {
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            offset: (ViewBuffer::std140_size_static() + ModelBuffer::std140_size_static()) as _,
+            size: Self::std140_size_static() as _,
+        }
+    }
+}
+
+// Gamma lights albedo without decoding it from sRGB, as renderers used to. Only really useful to
+// compare against.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorWorkflow {
+    #[default]
+    Linear,
+    Gamma,
+}
+
+impl ColorWorkflow {
+    pub fn specialization_constant(self) -> u32 {
+        (self == Self::Linear) as _
+    }
+}
+
+// Falls back to 8-bit sRGB when the preferred format isn't available, then to UNORM formats with
+// the shaders doing the sRGB encoding. HDR formats also need the display to be in HDR mode, and
+// VK_EXT_swapchain_colorspace to be supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SurfaceFormatPreference {
+    #[default]
+    Srgb,
+    SrgbUnorm, // 8-bit UNORM, for exercising the shader encoding on drivers with sRGB formats
+    Srgb10Bit, // less banding in gradients
+    Hdr10,     // BT.2020 with the PQ curve
+    ScRgb,     // linear, extended range 16-bit float
+}
+
+impl SurfaceFormatPreference {
+    fn encoding(self) -> OutputEncoding {
+        match self {
+            Self::Srgb => OutputEncoding::Srgb,
+            Self::SrgbUnorm | Self::Srgb10Bit => OutputEncoding::SrgbShader,
+            Self::Hdr10 => OutputEncoding::Hdr10,
+            Self::ScRgb => OutputEncoding::ScRgb,
+        }
+    }
+
+    // None for HDR formats that weren't asked for, since they look wrong outside of HDR mode
+    fn score(self, surface_format: vk::SurfaceFormatKHR) -> Option<u32> {
+        let encoding = OutputEncoding::of(surface_format)?;
+        let deep = matches!(
+            surface_format.format,
+            vk::Format::A2B10G10R10_UNORM_PACK32
+                | vk::Format::A2R10G10B10_UNORM_PACK32
+                | vk::Format::R16G16B16A16_SFLOAT
+        );
+        // Deeper is better, unless 8-bit was asked for
+        let wants_deep = self != Self::SrgbUnorm;
+        match encoding {
+            _ if encoding == self.encoding() => Some(3 + (deep == wants_deep) as u32),
+            OutputEncoding::Srgb => Some(2),
+            OutputEncoding::SrgbShader => Some(1),
+            OutputEncoding::Hdr10 | OutputEncoding::ScRgb => None,
+        }
+    }
+}
+
+// How shaders writing to the output have to encode colors, matching ENCODING_* in encoding.glsl
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputEncoding {
+    Srgb,       // done by the format
+    SrgbShader, // UNORM formats leave it to the shader
+    Hdr10,
+    ScRgb,
+}
+
+impl OutputEncoding {
+    fn of(surface_format: vk::SurfaceFormatKHR) -> Option<Self> {
+        match (surface_format.color_space, surface_format.format) {
+            (
+                vk::ColorSpaceKHR::SRGB_NONLINEAR,
+                vk::Format::B8G8R8A8_SRGB
+                | vk::Format::R8G8B8A8_SRGB
+                | vk::Format::A8B8G8R8_SRGB_PACK32,
+            ) => Some(Self::Srgb),
+            (
+                vk::ColorSpaceKHR::SRGB_NONLINEAR,
+                vk::Format::B8G8R8A8_UNORM
+                | vk::Format::R8G8B8A8_UNORM
+                | vk::Format::A8B8G8R8_UNORM_PACK32
+                | vk::Format::A2B10G10R10_UNORM_PACK32
+                | vk::Format::A2R10G10B10_UNORM_PACK32,
+            ) => Some(Self::SrgbShader),
+            (
+                vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+                vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::A2R10G10B10_UNORM_PACK32,
+            ) => Some(Self::Hdr10),
+            (vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT, vk::Format::R16G16B16A16_SFLOAT) => {
+                Some(Self::ScRgb)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn specialization_constant(self) -> u32 {
+        match self {
+            Self::Srgb => 0,
+            Self::SrgbShader => 1,
+            Self::Hdr10 => 2,
+            Self::ScRgb => 3,
+        }
+    }
+}
+
+// Which queue family presents. Separate forces presenting from a family other than the graphics
+// one, for exercising that path on devices that happen to have one, and fails on those that don't.
+// Graphics falls back to any family that can present if the graphics family can't. Headless
+// renderers present nothing, so ignore this.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PresentQueuePreference {
+    #[default]
+    Graphics,
+    Separate,
+}
+
+// Falls back to FIFO, which every surface supports, when the preferred mode isn't available
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PresentModePreference {
+    Immediate,
+    #[default]
+    Mailbox,
+    Fifo,
+    FifoRelaxed,
+}
+
+impl From<PresentModePreference> for vk::PresentModeKHR {
+    fn from(preference: PresentModePreference) -> Self {
+        match preference {
+            PresentModePreference::Immediate => Self::IMMEDIATE,
+            PresentModePreference::Mailbox => Self::MAILBOX,
+            PresentModePreference::Fifo => Self::FIFO,
+            PresentModePreference::FifoRelaxed => Self::FIFO_RELAXED,
+        }
+    }
+}
+
 pub struct SharedFrond {
+    depth_of_field: Image, // light after blurring, if depth of field is on
     depth_stencil: Image,
     diffuse: Image,
+    emissive: Image,
     light: Image,
+    material: Image,
+    motion_blur: Image, // light after blurring along velocity, if motion blur is on
     normal: Image,
+    offscreen: Option<Image>, // stands in for the swapchain when headless
+    output_images: Vec<vk::Image>, // see output_views()
+    output_resolution: vk::Extent2D, // of the swapchain, which resolution is scaled down from
+    output_views: Vec<vk::ImageView>, // see output_views()
+    point_shadow: Image,
+    point_shadow_cache: Option<Image>, // static casters' depths, if caching them
+    point_shadow_cache_face_views: Vec<vk::ImageView>,
+    point_shadow_face_views: Vec<vk::ImageView>,
+    present_mode: PresentModePreference,
+    refraction: Image, // light before water is drawn over it, which the water samples
+    render_complete_semaphores: Vec<vk::Semaphore>, // per swapchain image; see render_complete()
+    render_scale: Option<f32>,
     resolution: vk::Extent2D,
     shadow: Image,
+    shadow_cache: Option<Image>, // static casters' depths, if caching them
+    shadow_cache_views: Vec<vk::ImageView>,
+    shadow_cascade_views: Vec<vk::ImageView>,
+    shadow_settings: ShadowSettings,
     stem: Arc<SharedStem>,
-    swapchain: vk::SwapchainKHR,
-    swapchain_image_views: Vec<vk::ImageView>,
-    swapchain_format: vk::Format,
+    swapchain: vk::SwapchainKHR, // null when headless or rendering to a texture
+    swapchain_taken: AtomicBool, // see take_swapchain()
+    target: Option<Arc<GpuTexture>>, // stands in for the swapchain when rendering to a texture
+    ui: Option<Image>,           // drawn by egui then composited, unless rendering to a texture
+    upscale_input: Option<Image>, // what tonemapping writes instead of the output, if upscaling
+    upscaled: Option<Image>,     // upsampled from upscale_input, before sharpening
+    velocity: Image,
 }
 
 #[derive(Error, Debug)]
 pub enum SharedFrondError {
-    #[error("Vulkan error occurred")]
-    VkError(#[from] vk::Result), // TODO: split into contexts
-    #[error("Couldn't select acceptable surface format")]
-    NoAcceptableSurfaceFormat,
+    #[error("Couldn't create swapchain")]
+    SwapchainCreation(#[source] vk::Result),
+    #[error("Couldn't create image")]
+    ImageCreation(#[source] vk::Result),
+    #[error("Couldn't name Vulkan object")]
+    Naming(#[source] vk::Result),
     #[error("Couldn't select acceptable memory type for {0:?} and {1:?}")]
     NoAcceptableMeoryType(vk::MemoryRequirements, vk::MemoryPropertyFlags),
     #[error("Surface has no area")]
     NoSurfaceArea,
 }
 
+impl SharedFrondError {
+    pub fn vk_result(&self) -> Option<vk::Result> {
+        match *self {
+            Self::SwapchainCreation(result)
+            | Self::ImageCreation(result)
+            | Self::Naming(result) => Some(result),
+            _ => None,
+        }
+    }
+}
+
 impl SharedFrond {
-    pub fn new(stem: Arc<SharedStem>) -> Result<Self, SharedFrondError> {
+    pub const DIFFUSE_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB; // spends 8 bits perceptually
+    pub const NORMAL_FORMAT: vk::Format = vk::Format::A2B10G10R10_UNORM_PACK32; // 8 bits bands normal maps
+    pub const MATERIAL_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM; // metallic, roughness
+    pub const EMISSIVE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+    pub const LIGHT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+    pub const VELOCITY_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT; // screen uv per frame
+    pub const UPSCALED_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT; // holds any output encoding
+    pub const UI_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM; // egui blends sRGB colors as they are
+
+    // With a render scale, everything up to tonemapping is drawn at that fraction of the output
+    // resolution, to be upscaled
+    pub fn new(
+        stem: Arc<SharedStem>,
+        shadow_settings: ShadowSettings,
+        present_mode: PresentModePreference,
+        render_scale: Option<f32>,
+    ) -> Result<Self, SharedFrondError> {
+        if stem.crown().is_headless() {
+            return Self::new_with_swapchain(
+                stem,
+                shadow_settings,
+                present_mode,
+                render_scale,
+                &mut vk::SwapchainKHR::null(),
+                None,
+            );
+        }
         unsafe {
             let mut swapchain = vk::SwapchainKHR::null().guard_with(stem.swapchain_fn());
-            Self::new_with_swapchain(stem.clone(), &mut swapchain)
+            Self::new_with_swapchain(
+                stem.clone(),
+                shadow_settings,
+                present_mode,
+                render_scale,
+                &mut swapchain,
+                None,
+            )
         }
     }
 
+    // Sized to the target instead of the surface, and tonemapped straight into it
+    pub fn new_render_target(
+        stem: Arc<SharedStem>,
+        shadow_settings: ShadowSettings,
+        target: Arc<GpuTexture>,
+    ) -> Result<Self, SharedFrondError> {
+        Self::new_with_swapchain(
+            stem,
+            shadow_settings,
+            Default::default(),
+            None,
+            &mut vk::SwapchainKHR::null(),
+            Some(target),
+        )
+    }
+
     fn new_with_swapchain(
         stem: Arc<SharedStem>,
+        shadow_settings: ShadowSettings,
+        present_mode: PresentModePreference,
+        render_scale: Option<f32>,
         // Icky, but easier that map_err() for every fallible call, while ensuring that
         // SharedFrondSwapchain::ressurect() always ends up with a valid swapchain on failure.
         swapchain: &mut vk::SwapchainKHR,
+        target: Option<Arc<GpuTexture>>,
     ) -> Result<Self, SharedFrondError> {
         let crown = stem.crown();
         let device = stem.device();
 
-        let resolution = crown.window_resolution();
-        if resolution.width == 0 || resolution.height == 0 {
+        let output_resolution = match &target {
+            Some(target) => target.extent(),
+            None => crown.resolution(),
+        };
+        if output_resolution.width == 0 || output_resolution.height == 0 {
             return Err(SharedFrondError::NoSurfaceArea);
         }
+        let resolution = match render_scale {
+            Some(render_scale) => {
+                let scale = |length: u32| ((length as f32 * render_scale).round() as u32).max(1);
+                vk::Extent2D {
+                    width: scale(output_resolution.width),
+                    height: scale(output_resolution.height),
+                }
+            }
+            None => output_resolution,
+        };
 
         unsafe {
-            let surface_format = {
-                let physical_device = stem.physical_device();
-
-                let surface = crown.surface();
-                let surface = surface.lock().unwrap();
-                let surface_fn = crown.surface_fn();
-                Self::select_surface_format(surface_fn, physical_device, *surface)?
-                    .ok_or(SharedFrondError::NoAcceptableSurfaceFormat)?
-            };
-
-            *swapchain = Self::create_swapchain(&stem, surface_format, resolution, *swapchain)?;
-            for image in stem.swapchain_fn().get_swapchain_images(*swapchain)? {
-                stem.set_name(image, "presentation")?;
-            }
+            let surface_format = stem.surface_format();
+            let mut render_complete_semaphores = Vec::<vk::Semaphore>::new().guard_with(device);
+
+            let (swapchain_images, swapchain_image_views, offscreen) = if target.is_some() {
+                (
+                    Vec::new(),
+                    Vec::<vk::ImageView>::new().guard_with(device),
+                    None,
+                )
+            } else if crown.is_headless() {
+                let offscreen = Self::create_image(
+                    &stem,
+                    output_resolution,
+                    1, // layers
+                    vk::ImageViewType::TYPE_2D,
+                    surface_format.format,
+                    stem.output_usage(),
+                    vk::ImageAspectFlags::COLOR,
+                    "offscreen",
+                )?;
+                (
+                    Vec::new(),
+                    Vec::<vk::ImageView>::new().guard_with(device),
+                    Some(offscreen),
+                )
+            } else {
+                let old_swapchain = *swapchain;
+                *swapchain = Self::create_swapchain(
+                    &stem,
+                    surface_format,
+                    output_resolution,
+                    present_mode,
+                    old_swapchain,
+                )
+                .map_err(SharedFrondError::SwapchainCreation)?;
+                // Retired by the new one, but still needs destroying
+                stem.destroy_swapchain(old_swapchain);
+                let images = stem
+                    .swapchain_fn()
+                    .get_swapchain_images(*swapchain)
+                    .map_err(SharedFrondError::SwapchainCreation)?;
+                for &image in &images {
+                    stem.set_name(image, "presentation")
+                        .map_err(SharedFrondError::Naming)?;
+                    let semaphore = device
+                        .create_semaphore(&Default::default(), None)
+                        .map_err(SharedFrondError::SwapchainCreation)?;
+                    render_complete_semaphores.push(semaphore);
+                    stem.set_name(semaphore, "render complete")
+                        .map_err(SharedFrondError::Naming)?;
+                }
 
-            let swapchain_image_views = Self::create_swapchain_image_views(
-                stem.swapchain_fn(),
-                device,
-                *swapchain,
-                surface_format.format,
-            )?;
-            for image_view in swapchain_image_views.iter() {
-                stem.set_name(*image_view, "presentation")?;
-            }
+                let swapchain_image_views = Self::create_swapchain_image_views(
+                    stem.swapchain_fn(),
+                    device,
+                    *swapchain,
+                    surface_format.format,
+                )
+                .map_err(SharedFrondError::SwapchainCreation)?;
+                for image_view in swapchain_image_views.iter() {
+                    stem.set_name(*image_view, "presentation")
+                        .map_err(SharedFrondError::Naming)?;
+                }
+                (images, swapchain_image_views, None)
+            };
 
             let diffuse = Self::create_image(
                 &stem,
                 resolution,
-                vk::Format::R8G8B8A8_UNORM,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                Self::DIFFUSE_FORMAT,
                 vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT,
                 vk::ImageAspectFlags::COLOR,
                 "diffuse",
@@ -547,82 +2081,304 @@ impl SharedFrond {
             let normal = Self::create_image(
                 &stem,
                 resolution,
-                vk::Format::R8G8B8A8_UNORM,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                Self::NORMAL_FORMAT,
                 vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT,
                 vk::ImageAspectFlags::COLOR,
                 "normal",
             )?;
 
+            let material = Self::create_image(
+                &stem,
+                resolution,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                Self::MATERIAL_FORMAT,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+                vk::ImageAspectFlags::COLOR,
+                "material",
+            )?;
+
+            let emissive = Self::create_image(
+                &stem,
+                resolution,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                Self::EMISSIVE_FORMAT,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+                vk::ImageAspectFlags::COLOR,
+                "emissive",
+            )?;
+
             let depth_stencil = Self::create_image(
                 &stem,
                 resolution,
-                vk::Format::D24_UNORM_S8_UINT,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                stem.depth_stencil_format(),
                 vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
-                    | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+                    | vk::ImageUsageFlags::INPUT_ATTACHMENT
+                    | vk::ImageUsageFlags::SAMPLED,
                 vk::ImageAspectFlags::DEPTH,
                 "depth_stencil",
             )?;
 
+            // Caches are copied into the shadow maps every frame, before dynamic casters are drawn
+            let (shadow_usage, cache_usage) = (
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_DST,
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+            );
+
+            // One layer per cascade, sampled as an array but rendered to a layer at a time
+            let shadow_resolution = vk::Extent2D {
+                width: shadow_settings.resolution,
+                height: shadow_settings.resolution,
+            };
             let shadow = Self::create_image(
                 &stem,
-                vk::Extent2D {
-                    width: 1024,
-                    height: 1024,
-                },
-                vk::Format::D24_UNORM_S8_UINT,
-                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+                shadow_resolution,
+                shadow_settings.cascade_count as _,
+                vk::ImageViewType::TYPE_2D_ARRAY,
+                stem.shadow_format(),
+                shadow_usage,
                 vk::ImageAspectFlags::DEPTH,
                 "shadow",
             )?;
+            let shadow_cascade_views = Self::create_layer_views(&stem, &shadow, |layer| {
+                format!("shadow cascade {}", layer)
+            })?;
+
+            // Six layers per cubemap, one for each face. There's always at least one so that
+            // lighting has something to bind.
+            let point_shadow_layers = 6 * shadow_settings.point_count.max(1) as u32;
+            let point_shadow_resolution = vk::Extent2D {
+                width: shadow_settings.point_resolution,
+                height: shadow_settings.point_resolution,
+            };
+            let point_shadow = Self::create_image(
+                &stem,
+                point_shadow_resolution,
+                point_shadow_layers,
+                vk::ImageViewType::TYPE_2D_ARRAY,
+                stem.shadow_format(),
+                shadow_usage,
+                vk::ImageAspectFlags::DEPTH,
+                "point shadow",
+            )?;
+            let point_shadow_face_views =
+                Self::create_layer_views(&stem, &point_shadow, |layer| {
+                    format!("point shadow {} face {}", layer / 6, layer % 6)
+                })?;
+
+            let (
+                shadow_cache,
+                shadow_cache_views,
+                point_shadow_cache,
+                point_shadow_cache_face_views,
+            ) = if shadow_settings.cache_static {
+                let shadow_cache = Self::create_image(
+                    &stem,
+                    shadow_resolution,
+                    shadow_settings.cascade_count as _,
+                    vk::ImageViewType::TYPE_2D_ARRAY,
+                    stem.shadow_format(),
+                    cache_usage,
+                    vk::ImageAspectFlags::DEPTH,
+                    "shadow cache",
+                )?;
+                let shadow_cache_views = Self::create_layer_views(&stem, &shadow_cache, |layer| {
+                    format!("shadow cache cascade {}", layer)
+                })?;
+                let point_shadow_cache = Self::create_image(
+                    &stem,
+                    point_shadow_resolution,
+                    point_shadow_layers,
+                    vk::ImageViewType::TYPE_2D_ARRAY,
+                    stem.shadow_format(),
+                    cache_usage,
+                    vk::ImageAspectFlags::DEPTH,
+                    "point shadow cache",
+                )?;
+                let point_shadow_cache_face_views =
+                    Self::create_layer_views(&stem, &point_shadow_cache, |layer| {
+                        format!("point shadow cache {} face {}", layer / 6, layer % 6)
+                    })?;
+                (
+                    Some(shadow_cache),
+                    shadow_cache_views,
+                    Some(point_shadow_cache),
+                    point_shadow_cache_face_views,
+                )
+            } else {
+                (
+                    None,
+                    Vec::<vk::ImageView>::new().guard_with(device),
+                    None,
+                    Vec::<vk::ImageView>::new().guard_with(device),
+                )
+            };
 
             let light = Self::create_image(
                 &stem,
                 resolution,
-                vk::Format::R16G16B16A16_SFLOAT,
-                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                Self::LIGHT_FORMAT,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::INPUT_ATTACHMENT
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_SRC,
                 vk::ImageAspectFlags::COLOR,
                 "light",
             )?;
 
+            let refraction = Self::create_image(
+                &stem,
+                resolution,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                Self::LIGHT_FORMAT,
+                vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+                vk::ImageAspectFlags::COLOR,
+                "refraction",
+            )?;
+
+            let depth_of_field = Self::create_image(
+                &stem,
+                resolution,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                Self::LIGHT_FORMAT,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::INPUT_ATTACHMENT
+                    | vk::ImageUsageFlags::SAMPLED,
+                vk::ImageAspectFlags::COLOR,
+                "depth of field",
+            )?;
+
+            let motion_blur = Self::create_image(
+                &stem,
+                resolution,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                Self::LIGHT_FORMAT,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+                vk::ImageAspectFlags::COLOR,
+                "motion blur",
+            )?;
+
+            let velocity = Self::create_image(
+                &stem,
+                resolution,
+                1, // layers
+                vk::ImageViewType::TYPE_2D,
+                Self::VELOCITY_FORMAT,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+                vk::ImageAspectFlags::COLOR,
+                "velocity",
+            )?;
+
+            // Only ever read within the render pass that draws it
+            let ui = if target.is_some() {
+                None
+            } else {
+                Some(Self::create_image(
+                    &stem,
+                    output_resolution,
+                    1, // layers
+                    vk::ImageViewType::TYPE_2D,
+                    Self::UI_FORMAT,
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT
+                        | vk::ImageUsageFlags::INPUT_ATTACHMENT
+                        | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+                    vk::ImageAspectFlags::COLOR,
+                    "ui",
+                )?)
+            };
+
+            let (upscale_input, upscaled) = if render_scale.is_some() {
+                let upscale_input = Self::create_image(
+                    &stem,
+                    resolution,
+                    1, // layers
+                    vk::ImageViewType::TYPE_2D,
+                    surface_format.format,
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+                    vk::ImageAspectFlags::COLOR,
+                    "upscale input",
+                )?;
+                let upscaled = Self::create_image(
+                    &stem,
+                    output_resolution,
+                    1, // layers
+                    vk::ImageViewType::TYPE_2D,
+                    Self::UPSCALED_FORMAT,
+                    vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+                    vk::ImageAspectFlags::COLOR,
+                    "upscaled",
+                )?;
+                (Some(upscale_input), Some(upscaled))
+            } else {
+                (None, None)
+            };
+
+            let offscreen = offscreen.map(|offscreen| offscreen.take());
+            let (output_images, output_views) = match (&offscreen, &target) {
+                (Some(offscreen), _) => (vec![offscreen.image], vec![offscreen.view]),
+                (_, Some(target)) => (
+                    vec![target.image().get(&stem)],
+                    vec![target.view().get(&stem)],
+                ),
+                _ => (swapchain_images, swapchain_image_views.take()),
+            };
+
             Ok(Self {
+                depth_of_field: depth_of_field.take(),
                 depth_stencil: depth_stencil.take(),
                 diffuse: diffuse.take(),
+                emissive: emissive.take(),
                 light: light.take(),
+                material: material.take(),
+                motion_blur: motion_blur.take(),
                 normal: normal.take(),
+                offscreen,
+                output_images,
+                output_resolution,
+                output_views,
+                point_shadow: point_shadow.take(),
+                point_shadow_cache: point_shadow_cache.map(|cache| cache.take()),
+                point_shadow_cache_face_views: point_shadow_cache_face_views.take(),
+                point_shadow_face_views: point_shadow_face_views.take(),
+                refraction: refraction.take(),
+                render_complete_semaphores: render_complete_semaphores.take(),
                 shadow: shadow.take(),
+                shadow_cache: shadow_cache.map(|cache| cache.take()),
+                shadow_cache_views: shadow_cache_views.take(),
+                shadow_cascade_views: shadow_cascade_views.take(),
                 swapchain: std::mem::take(swapchain),
-                swapchain_image_views: swapchain_image_views.take(),
+                swapchain_taken: AtomicBool::new(false),
+                target,
+                upscale_input: upscale_input.map(|upscale_input| upscale_input.take()),
+                ui: ui.map(|ui| ui.take()),
+                upscaled: upscaled.map(|upscaled| upscaled.take()),
+                velocity: velocity.take(),
+                present_mode,
+                render_scale,
                 resolution,
+                shadow_settings,
                 stem,
-                swapchain_format: surface_format.format,
             })
         }
     }
 
-    unsafe fn select_surface_format(
-        surface_fn: &Surface,
-        physical_device: vk::PhysicalDevice,
-        surface: vk::SurfaceKHR,
-    ) -> VkResult<Option<vk::SurfaceFormatKHR>> {
-        let surface_formats =
-            surface_fn.get_physical_device_surface_formats(physical_device, surface)?;
-        let desired_formats = [
-            vk::SurfaceFormatKHR {
-                color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
-                format: vk::Format::B8G8R8A8_SRGB,
-            },
-            // TODO: Support other formats?
-        ];
-        Ok(desired_formats
-            .iter()
-            .find(|&&desired_format| surface_formats.iter().any(|&sfmt| sfmt == desired_format))
-            .copied())
-    }
-
     unsafe fn create_swapchain(
         stem: &SharedStem,
         surface_format: vk::SurfaceFormatKHR,
         default_resolution: vk::Extent2D,
+        present_mode: PresentModePreference,
         old_swapchain: vk::SwapchainKHR,
     ) -> VkResult<vk::SwapchainKHR> {
         let crown = stem.crown();
@@ -630,7 +2386,7 @@ impl SharedFrond {
         let queues = stem.queues();
         let surface_fn = crown.surface_fn();
         let swapchain_fn = stem.swapchain_fn();
-        let surface = crown.surface().lock().unwrap();
+        let surface = crown.surface().unwrap().lock().unwrap();
 
         let surface_capabilities =
             surface_fn.get_physical_device_surface_capabilities(physical_device, *surface)?;
@@ -658,12 +2414,18 @@ impl SharedFrond {
             surface_capabilities.current_transform
         };
 
-        let present_mode = surface_fn
-            .get_physical_device_surface_present_modes(physical_device, *surface)?
-            .into_iter()
-            .find(|&m| m == vk::PresentModeKHR::MAILBOX)
-            .unwrap_or(vk::PresentModeKHR::FIFO);
+        let present_modes =
+            surface_fn.get_physical_device_surface_present_modes(physical_device, *surface)?;
+        let present_mode = match present_mode.into() {
+            present_mode if present_modes.contains(&present_mode) => present_mode,
+            present_mode => {
+                log::warn!("{:?} unsupported, falling back to FIFO", present_mode);
+                vk::PresentModeKHR::FIFO
+            }
+        };
 
+        // Shared with the present family, if it's another, so images needn't change hands between
+        // drawing and presenting
         let queue_families = [queues.graphics_family, queues.present_family];
         let (image_sharing_mode, queue_families) =
             if queues.graphics_family == queues.present_family {
@@ -679,15 +2441,28 @@ impl SharedFrond {
             .image_color_space(surface_format.color_space)
             .image_extent(image_extent)
             .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_usage(stem.output_usage())
             .image_sharing_mode(image_sharing_mode)
             .queue_family_indices(queue_families)
             .pre_transform(transform)
-            .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+            .composite_alpha(stem.composite_alpha)
             .present_mode(present_mode)
             .clipped(true)
             .old_swapchain(old_swapchain);
 
+        // Windowed swapchains stay composited, rather than the driver guessing from window size
+        let mut full_screen_exclusive_info = vk::SurfaceFullScreenExclusiveInfoEXT::builder()
+            .full_screen_exclusive(if crown.display_mode().is_exclusive() {
+                vk::FullScreenExclusiveEXT::ALLOWED
+            } else {
+                vk::FullScreenExclusiveEXT::DISALLOWED
+            });
+        let swapchain_create_info = if stem.capabilities().full_screen_exclusive {
+            swapchain_create_info.push_next(&mut full_screen_exclusive_info)
+        } else {
+            swapchain_create_info
+        };
+
         swapchain_fn.create_swapchain(&swapchain_create_info, None)
     }
 
@@ -718,9 +2493,41 @@ impl SharedFrond {
         Ok(image_views)
     }
 
+    // A 2D view of each layer, for rendering to one at a time
+    unsafe fn create_layer_views<'a>(
+        stem: &'a SharedStem,
+        image: &Image,
+        name: impl Fn(u32) -> String,
+    ) -> Result<Guarded<(Vec<vk::ImageView>, &'a ash::Device)>, SharedFrondError> {
+        let device = stem.device();
+        let mut views = Vec::<vk::ImageView>::new().guard_with(device);
+        for layer in 0..image.layers {
+            let subresource_range = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                .level_count(1)
+                .base_array_layer(layer)
+                .layer_count(1);
+            let image_view_create_info = vk::ImageViewCreateInfo::builder()
+                .image(image.image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(stem.shadow_format())
+                .subresource_range(subresource_range.build());
+            let view = device
+                .create_image_view(&image_view_create_info, None)
+                .map_err(SharedFrondError::ImageCreation)?;
+            views.push(view);
+            stem.set_name(view, &name(layer))
+                .map_err(SharedFrondError::Naming)?;
+        }
+        Ok(views)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     unsafe fn create_image<'a>(
         stem: &'a SharedStem,
         resolution: vk::Extent2D,
+        layers: u32,
+        view_type: vk::ImageViewType,
         format: vk::Format,
         usage: vk::ImageUsageFlags,
         aspects: vk::ImageAspectFlags,
@@ -745,7 +2552,7 @@ impl SharedFrond {
                 depth: 1,
             })
             .mip_levels(1)
-            .array_layers(1)
+            .array_layers(layers)
             .samples(vk::SampleCountFlags::TYPE_1)
             .tiling(vk::ImageTiling::OPTIMAL)
             .usage(usage)
@@ -757,113 +2564,325 @@ impl SharedFrond {
             stem.device(),
             &image_create_info,
             select_device_local_memory,
+            view_type,
             aspects,
-        )??;
+        )
+        .map_err(SharedFrondError::ImageCreation)??;
 
-        stem.set_name(image.image, name)?;
-        stem.set_name(image.memory, name)?;
-        stem.set_name(image.view, name)?;
+        stem.set_name(image.image, name)
+            .map_err(SharedFrondError::Naming)?;
+        stem.set_name(image.memory, name)
+            .map_err(SharedFrondError::Naming)?;
+        stem.set_name(image.view, name)
+            .map_err(SharedFrondError::Naming)?;
 
         Ok(image)
     }
 
-    pub fn take_swapchain(mut self) -> SharedFrondSwapchain {
-        SharedFrondSwapchain {
+    // Takes the swapchain out from under any clones still alive, such as ones a pass frond hung
+    // onto, whose swapchain accessors are out of date from then on. Only the first take gets it.
+    // All that has to be done with the frond's work before this is presenting.
+    pub fn take_swapchain(&self) -> SharedFrondSwapchain {
+        let mut swapchain = SharedFrondSwapchain {
+            image_views: Vec::new(),
+            images: Vec::new(),
             stem: self.stem.clone(),
-            swapchain: std::mem::take(&mut self.swapchain),
+            swapchain: vk::SwapchainKHR::null(),
+        };
+        let taken = self.swapchain_taken.swap(true, Ordering::AcqRel);
+        // The offscreen image and render targets aren't the swapchain's to destroy
+        if !taken && self.swapchain != vk::SwapchainKHR::null() {
+            swapchain.image_views = self.output_views.clone();
+            swapchain.images = self.output_images.clone();
+            swapchain.swapchain = self.swapchain;
         }
+        swapchain
+    }
+
+    fn check_swapchain(&self) -> VkResult<()> {
+        if self.swapchain_taken.load(Ordering::Acquire) {
+            return Err(vk::Result::ERROR_OUT_OF_DATE_KHR);
+        }
+        Ok(())
     }
 
     pub fn needs_resizing(&self) -> bool {
-        self.resolution() != self.stem().crown().window_resolution()
+        self.output_resolution() != self.stem().crown().resolution()
+    }
+
+    pub fn depth_of_field(&self) -> &Image {
+        &self.depth_of_field
     }
 
     pub fn depth_stencil(&self) -> &Image {
         &self.depth_stencil
     }
 
+    pub fn descriptor_allocator(&self) -> &DescriptorAllocator {
+        self.stem.descriptor_allocator()
+    }
+
     pub fn device(&self) -> &ash::Device {
         self.stem.device()
     }
 
+    pub fn count_draw(&self, triangles: u64) {
+        self.stem.count_draw(triangles)
+    }
+
     pub fn diffuse(&self) -> &Image {
         &self.diffuse
     }
 
+    pub fn emissive(&self) -> &Image {
+        &self.emissive
+    }
+
     pub fn light(&self) -> &Image {
         &self.light
     }
 
+    pub fn material(&self) -> &Image {
+        &self.material
+    }
+
+    pub fn motion_blur(&self) -> &Image {
+        &self.motion_blur
+    }
+
     pub fn normal(&self) -> &Image {
         &self.normal
     }
 
+    pub fn offscreen(&self) -> Option<&Image> {
+        self.offscreen.as_ref()
+    }
+
+    // Where the finished frame goes, indexed by swapchain image, or the one offscreen image or
+    // render target standing in for them. Out of date once the swapchain's been taken.
+    pub fn output_views(&self) -> VkResult<&[vk::ImageView]> {
+        self.check_swapchain()?;
+        Ok(&self.output_views)
+    }
+
+    pub fn output_images(&self) -> VkResult<&[vk::Image]> {
+        self.check_swapchain()?;
+        Ok(&self.output_images)
+    }
+
+    pub fn point_shadow(&self) -> &Image {
+        &self.point_shadow
+    }
+
+    pub fn point_shadow_face_views(&self) -> &[vk::ImageView] {
+        &self.point_shadow_face_views
+    }
+
+    pub fn point_shadow_cache(&self) -> Option<&Image> {
+        self.point_shadow_cache.as_ref()
+    }
+
+    pub fn point_shadow_cache_face_views(&self) -> &[vk::ImageView] {
+        &self.point_shadow_cache_face_views
+    }
+
+    pub fn refraction(&self) -> &Image {
+        &self.refraction
+    }
+
+    // Everything up to tonemapping is drawn at this resolution
     pub fn resolution(&self) -> vk::Extent2D {
         self.resolution
     }
 
+    pub fn output_resolution(&self) -> vk::Extent2D {
+        self.output_resolution
+    }
+
+    pub fn render_scale(&self) -> Option<f32> {
+        self.render_scale
+    }
+
+    pub fn upscale_input(&self) -> Option<&Image> {
+        self.upscale_input.as_ref()
+    }
+
+    pub fn upscaled(&self) -> Option<&Image> {
+        self.upscaled.as_ref()
+    }
+
+    pub fn ui(&self) -> Option<&Image> {
+        self.ui.as_ref()
+    }
+
     pub fn shadow(&self) -> &Image {
         &self.shadow
     }
 
+    pub fn shadow_cascade_views(&self) -> &[vk::ImageView] {
+        &self.shadow_cascade_views
+    }
+
+    pub fn shadow_cache(&self) -> Option<&Image> {
+        self.shadow_cache.as_ref()
+    }
+
+    pub fn shadow_cache_views(&self) -> &[vk::ImageView] {
+        &self.shadow_cache_views
+    }
+
+    // As requested, even if the surface didn't support it
+    pub fn present_mode(&self) -> PresentModePreference {
+        self.present_mode
+    }
+
+    pub fn shadow_settings(&self) -> ShadowSettings {
+        self.shadow_settings
+    }
+
     pub fn stem(&self) -> Arc<SharedStem> {
         self.stem.clone()
     }
 
-    pub fn swapchain(&self) -> vk::SwapchainKHR {
-        self.swapchain
+    pub fn swapchain(&self) -> VkResult<vk::SwapchainKHR> {
+        self.check_swapchain()?;
+        Ok(self.swapchain)
+    }
+
+    // Render targets are always opaque, since they're sampled like any other texture
+    pub fn transparent(&self) -> bool {
+        self.target.is_none() && self.stem.transparent()
     }
 
-    pub fn swapchain_format(&self) -> vk::Format {
-        self.swapchain_format
+    // Signaled by the frame drawn to the swapchain image, and waited on by its present. Being per
+    // image rather than per frame, it can't be signaled again before that wait, since the image
+    // isn't reacquired until then. A frame's fence can't promise as much once presents come from
+    // a queue of their own.
+    pub fn render_complete(&self, image_index: u32) -> vk::Semaphore {
+        self.render_complete_semaphores[image_index as usize]
     }
 
-    pub fn swapchain_image_views(&self) -> &[vk::ImageView] {
-        &self.swapchain_image_views
+    pub fn velocity(&self) -> &Image {
+        &self.velocity
     }
 }
 
 impl Drop for SharedFrond {
     fn drop(&mut self) {
+        // Unless it's already been taken
+        drop(self.take_swapchain());
         let device = self.stem.device();
-        let swapchain_fn = self.stem.swapchain_fn();
         unsafe {
             let _ = device.device_wait_idle();
 
+            for &image_view in self.shadow_cascade_views.iter() {
+                device.destroy_image_view(image_view, None);
+            }
             self.shadow.destroy_with(device);
+            for &image_view in self.shadow_cache_views.iter() {
+                device.destroy_image_view(image_view, None);
+            }
+            if let Some(shadow_cache) = &mut self.shadow_cache {
+                shadow_cache.destroy_with(device);
+            }
+            for &image_view in self.point_shadow_face_views.iter() {
+                device.destroy_image_view(image_view, None);
+            }
+            self.point_shadow.destroy_with(device);
+            for &image_view in self.point_shadow_cache_face_views.iter() {
+                device.destroy_image_view(image_view, None);
+            }
+            if let Some(point_shadow_cache) = &mut self.point_shadow_cache {
+                point_shadow_cache.destroy_with(device);
+            }
             self.normal.destroy_with(device);
+            if let Some(offscreen) = &mut self.offscreen {
+                offscreen.destroy_with(device);
+            }
+            if let Some(upscale_input) = &mut self.upscale_input {
+                upscale_input.destroy_with(device);
+            }
+            if let Some(upscaled) = &mut self.upscaled {
+                upscaled.destroy_with(device);
+            }
+            if let Some(ui) = &mut self.ui {
+                ui.destroy_with(device);
+            }
+            self.material.destroy_with(device);
+            self.motion_blur.destroy_with(device);
+            self.velocity.destroy_with(device);
+            self.depth_of_field.destroy_with(device);
+            self.refraction.destroy_with(device);
             self.light.destroy_with(device);
+            self.emissive.destroy_with(device);
             self.diffuse.destroy_with(device);
             self.depth_stencil.destroy_with(device);
-            for &image_view in self.swapchain_image_views.iter() {
-                device.destroy_image_view(image_view, None);
+            for &semaphore in self.render_complete_semaphores.iter() {
+                device.destroy_semaphore(semaphore, None);
             }
-            swapchain_fn.destroy_swapchain(self.swapchain, None);
         }
     }
 }
 
 pub struct SharedFrondSwapchain {
+    image_views: Vec<vk::ImageView>,
+    images: Vec<vk::Image>, // owned by the swapchain
     stem: Arc<SharedStem>,
     swapchain: vk::SwapchainKHR,
 }
 
 impl SharedFrondSwapchain {
-    pub fn resurrect(mut self) -> Result<SharedFrond, (SharedFrondSwapchain, SharedFrondError)> {
-        SharedFrond::new_with_swapchain(self.stem.clone(), &mut self.swapchain)
-            .map_err(|err| (self, err))
+    // Destroys the swapchain now rather than waiting to replace it, for when its surface is going
+    pub fn discard(mut self) -> Self {
+        unsafe {
+            self.destroy_image_views();
+            self.stem
+                .destroy_swapchain(std::mem::take(&mut self.swapchain));
+        }
+        self
+    }
+
+    // Waits for the device first, since whoever took the swapchain may not have
+    unsafe fn destroy_image_views(&mut self) {
+        let device = self.stem.device();
+        let _ = device.device_wait_idle();
+
+        for image_view in self.image_views.drain(..) {
+            device.destroy_image_view(image_view, None);
+        }
+        self.images.clear();
+    }
+
+    pub fn resurrect(
+        mut self,
+        shadow_settings: ShadowSettings,
+        present_mode: PresentModePreference,
+        render_scale: Option<f32>,
+    ) -> Result<SharedFrond, (SharedFrondSwapchain, SharedFrondError)> {
+        // The new swapchain's images come with views of their own
+        unsafe { self.destroy_image_views() };
+        SharedFrond::new_with_swapchain(
+            self.stem.clone(),
+            shadow_settings,
+            present_mode,
+            render_scale,
+            &mut self.swapchain,
+            None,
+        )
+        .map_err(|err| (self, err))
     }
 }
 
 impl Drop for SharedFrondSwapchain {
     fn drop(&mut self) {
-        let device = self.stem.device();
-        let swapchain_fn = self.stem.swapchain_fn();
-
         unsafe {
-            let _ = device.device_wait_idle();
-
-            swapchain_fn.destroy_swapchain(self.swapchain, None);
+            self.destroy_image_views();
+            self.stem.destroy_swapchain(self.swapchain);
         }
     }
 }
+
+// Patch versions never gate features, so they'd only get in the way of comparisons
+fn version_without_patch(version: u32) -> u32 {
+    vk::make_version(vk::version_major(version), vk::version_minor(version), 0)
+}
@@ -0,0 +1,47 @@
+use crevice::std140::AsStd140;
+use nalgebra as na;
+
+use crate::light::DirectionalLight;
+
+// A clear sky from Preetham et al.'s model, lit by the sun. In place of an environment map, it's
+// both the background and the ambient light, and follows the sun through the day.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sky {
+    pub ground_albedo: mint::Vector3<f32>, // below the horizon, lit by the sun and the sky
+    pub intensity: f32,
+    pub sun_radius: f32, // radians, for the sun's disc in the background
+    pub turbidity: f32,  // haze, from about 2 for a clear sky to 10 for a murky one
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Self {
+            ground_albedo: [0.2, 0.17, 0.14].into(),
+            intensity: 1.0,
+            sun_radius: 0.01,
+            turbidity: 3.0,
+        }
+    }
+}
+
+// What the sky pass draws from, so it's only redrawn once this changes
+#[derive(AsStd140, Clone, Copy, PartialEq)]
+pub(crate) struct SkyBuffer {
+    pub sun_direction: mint::Vector4<f32>, // toward the sun
+    pub sunlight_color: mint::Vector4<f32>,
+    pub ground_albedo: mint::Vector4<f32>,
+    pub intensity: f32,
+    pub turbidity: f32,
+}
+
+impl SkyBuffer {
+    pub fn new(sky: &Sky, sun: &DirectionalLight) -> Self {
+        Self {
+            sun_direction: (-sun.unit_direction()).push(0.0).into(),
+            sunlight_color: sun.radiance().push(0.0).into(),
+            ground_albedo: na::Vector3::from(sky.ground_albedo).push(0.0).into(),
+            intensity: sky.intensity,
+            turbidity: sky.turbidity,
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::{cell::Cell, ops::Deref, sync::Mutex};
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+
+use crate::{
+    buffer::Buffer,
+    frame::FRAMES_IN_FLIGHT,
+    guard::{Guardable, GuardableResource, Guarded},
+    util,
+};
+
+// Enough for a busy frame's debug lines and UI, so chunks rarely need adding
+const CHUNK_SIZE: vk::DeviceSize = 8 << 20;
+// The largest minUniformBufferOffsetAlignment allowed, which suits vertices and indices too
+const ALIGNMENT: vk::DeviceSize = 256;
+
+// Streams data that's rewritten every frame, like debug lines and UI meshes, through a ring of
+// persistently mapped host-visible chunks. Each frame in flight has its own chunks, only reused
+// once that frame's fence has been waited on, so staging never stalls. Chunks are kept once
+// added, so after the first few frames nothing gets allocated either.
+pub struct StagingBelt {
+    frames: Mutex<Vec<Vec<Chunk>>>, // per frame in flight
+    memory_type: u32,
+}
+
+struct Chunk {
+    buffer: Buffer,
+    mapped: *mut u8,
+    used: vk::DeviceSize,
+}
+
+// The mapping is only written through the belt's lock
+unsafe impl Send for Chunk {}
+
+// Where staged data landed, valid until its frame is next waited on
+#[derive(Clone, Copy, Debug)]
+pub struct Staged {
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+}
+
+impl StagingBelt {
+    const USAGE: vk::BufferUsageFlags = vk::BufferUsageFlags::from_raw(
+        vk::BufferUsageFlags::VERTEX_BUFFER.as_raw()
+            | vk::BufferUsageFlags::INDEX_BUFFER.as_raw()
+            | vk::BufferUsageFlags::UNIFORM_BUFFER.as_raw()
+            | vk::BufferUsageFlags::STORAGE_BUFFER.as_raw()
+            | vk::BufferUsageFlags::TRANSFER_SRC.as_raw(),
+    );
+    const MEMORY_FLAGS: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::from_raw(
+        vk::MemoryPropertyFlags::HOST_VISIBLE.as_raw()
+            | vk::MemoryPropertyFlags::HOST_COHERENT.as_raw(),
+    );
+
+    // Every frame starts with one chunk, and the first one's allocation picks the memory type.
+    // Fails with what couldn't be satisfied if no memory type is host visible and coherent.
+    #[allow(clippy::type_complexity)]
+    pub unsafe fn new(
+        device: &ash::Device,
+        memory_properties: vk::PhysicalDeviceMemoryProperties,
+    ) -> VkResult<
+        Result<Guarded<(Self, &ash::Device)>, (vk::MemoryRequirements, vk::MemoryPropertyFlags)>,
+    > {
+        let memory_type = Cell::new(0);
+        let select_memory_type = |memory_requirements| {
+            let selected = util::select_memory_type(
+                memory_properties,
+                memory_requirements,
+                Self::MEMORY_FLAGS,
+            )
+            .ok_or((memory_requirements, Self::MEMORY_FLAGS))?;
+            memory_type.set(selected);
+            Ok(selected)
+        };
+        let buffer_create_info = Self::buffer_create_info(CHUNK_SIZE);
+        let first_buffer = match Buffer::new(device, &buffer_create_info, select_memory_type)? {
+            Ok(buffer) => buffer,
+            Err(err) => return Ok(Err(err)),
+        };
+
+        let mut belt = Self {
+            frames: Mutex::new((0..FRAMES_IN_FLIGHT).map(|_| Vec::new()).collect()),
+            memory_type: memory_type.get(),
+        }
+        .guard_with(device);
+        let memory_type = belt.memory_type;
+        let frames = belt.frames.get_mut().unwrap();
+        Self::push_chunk(device, &mut frames[0], first_buffer)?;
+        for chunks in &mut frames[1..] {
+            Self::add_chunk(device, chunks, memory_type, CHUNK_SIZE)?;
+        }
+        Ok(Ok(belt))
+    }
+
+    // Everything staged for this frame is finished with, so its chunks can be written again
+    pub fn recycle(&self, frame_index: usize) {
+        for chunk in self.frames.lock().unwrap()[frame_index].iter_mut() {
+            chunk.used = 0;
+        }
+    }
+
+    pub unsafe fn stage(
+        &self,
+        device: &ash::Device,
+        frame_index: usize,
+        data: &[u8],
+    ) -> VkResult<Staged> {
+        let size = data.len() as vk::DeviceSize;
+        let mut frames = self.frames.lock().unwrap();
+        let chunks = &mut frames[frame_index];
+
+        let free_chunk = chunks
+            .iter()
+            .position(|chunk| Self::align(chunk.used) + size <= chunk.buffer.size);
+        let index = match free_chunk {
+            Some(index) => index,
+            None => {
+                let chunk_size = Self::align(size).max(CHUNK_SIZE);
+                log::debug!(
+                    "Adding {} byte staging chunk for frame {}",
+                    chunk_size,
+                    frame_index,
+                );
+                Self::add_chunk(device, chunks, self.memory_type, chunk_size)?;
+                chunks.len() - 1
+            }
+        };
+        let chunk = &mut chunks[index];
+
+        let offset = Self::align(chunk.used);
+        std::ptr::copy_nonoverlapping(data.as_ptr(), chunk.mapped.add(offset as usize), data.len());
+        chunk.used = offset + size;
+        Ok(Staged {
+            buffer: chunk.buffer.buffer,
+            offset,
+        })
+    }
+
+    fn align(offset: vk::DeviceSize) -> vk::DeviceSize {
+        offset.div_ceil(ALIGNMENT) * ALIGNMENT
+    }
+
+    fn buffer_create_info(size: vk::DeviceSize) -> vk::BufferCreateInfo {
+        vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(Self::USAGE)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .build()
+    }
+
+    unsafe fn add_chunk(
+        device: &ash::Device,
+        chunks: &mut Vec<Chunk>,
+        memory_type: u32,
+        size: vk::DeviceSize,
+    ) -> VkResult<()> {
+        let buffer_create_info = Self::buffer_create_info(size);
+        let buffer = Buffer::new(device, &buffer_create_info, |_| {
+            Ok::<_, vk::Result>(memory_type)
+        })??;
+        Self::push_chunk(device, chunks, buffer)
+    }
+
+    unsafe fn push_chunk(
+        device: &ash::Device,
+        chunks: &mut Vec<Chunk>,
+        buffer: Guarded<(Buffer, &ash::Device)>,
+    ) -> VkResult<()> {
+        let mapped = device.map_memory(
+            buffer.memory,
+            0,
+            vk::WHOLE_SIZE,
+            vk::MemoryMapFlags::empty(),
+        )?;
+        chunks.push(Chunk {
+            buffer: buffer.take(),
+            mapped: mapped as *mut u8,
+            used: 0,
+        });
+        Ok(())
+    }
+
+    pub unsafe fn destroy_with(&mut self, device: &ash::Device) {
+        for chunks in self.frames.get_mut().unwrap() {
+            for chunk in chunks {
+                device.unmap_memory(chunk.buffer.memory);
+                chunk.buffer.destroy_with(device);
+            }
+        }
+    }
+}
+
+impl<C> Guardable for (StagingBelt, C)
+where
+    C: Deref<Target = ash::Device>,
+{
+    type Resource = StagingBelt;
+
+    fn deref(&self) -> &Self::Resource {
+        &self.0
+    }
+
+    fn deref_mut(&mut self) -> &mut Self::Resource {
+        &mut self.0
+    }
+
+    fn take(self) -> Self::Resource {
+        self.0
+    }
+
+    unsafe fn drop(self) {
+        let (mut resource, context) = self;
+        resource.destroy_with(&context);
+    }
+}
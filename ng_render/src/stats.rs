@@ -0,0 +1,231 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use ash::vk;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    pub frame_time: Duration, // since the previous draw began
+    pub cpu_time: Duration,   // spent recording and submitting the frame
+    // None until timings come back, or if the device can't record timestamps. Since frames are
+    // in flight, these lag FRAMES_IN_FLIGHT draws behind.
+    pub gpu: Option<PassTimes>,
+    // None unless turned on with Renderer::set_pipeline_statistics and the device can count
+    // them. Lags behind like gpu.
+    pub pipeline: Option<PipelineStatistics>,
+    pub deferred_uploads: usize, // meshes and materials left for later frames' upload budgets
+    pub missed_deadlines: usize, // draws that started late, since the frame limit was last set
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RendererStats {
+    // Recorded over the last draw, including render targets. Indirect draws count before GPU
+    // culling, and debug lines count as draws without triangles.
+    pub draw_calls: u64,
+    pub triangles: u64,
+    // Bytes of device memory currently bound to buffers and images, across every renderer
+    pub buffer_memory: u64,
+    pub image_memory: u64,
+    pub swapchain_images: usize, // 0 until the first draw, or while the window is minimized
+    // Of material textures, as counted against Renderer::set_texture_budget
+    pub texture_memory: u64,
+    pub demoted_textures: usize,
+    pub cpu_time: Duration,
+    pub gpu_time: Option<Duration>, // lags behind like FrameStats::gpu
+}
+
+// Tallies draws as they're recorded, possibly from several threads
+#[derive(Debug, Default)]
+pub(crate) struct DrawCounter {
+    draw_calls: AtomicU64,
+    triangles: AtomicU64,
+}
+
+impl DrawCounter {
+    pub fn count(&self, triangles: u64) {
+        self.draw_calls.fetch_add(1, Ordering::Relaxed);
+        self.triangles.fetch_add(triangles, Ordering::Relaxed);
+    }
+
+    // Returns draw calls and triangles since the last take
+    pub fn take(&self) -> (u64, u64) {
+        (
+            self.draw_calls.swap(0, Ordering::Relaxed),
+            self.triangles.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PassTimes {
+    pub render_targets: Duration, // every chain drawn to a RenderTarget, ahead of the main one
+    pub geometry: Duration,
+    pub shadow: Duration,
+    pub light_clusters: Duration,
+    pub lighting: Duration,
+    pub water: Duration,
+    pub transparency: Duration,
+    pub debug_draw: Duration,
+    pub depth_of_field: Duration,
+    pub motion_blur: Duration,
+    pub tonemapping: Duration,
+    pub upscaling: Duration,
+    pub ui: Duration,
+}
+
+impl PassTimes {
+    pub fn total(&self) -> Duration {
+        self.render_targets
+            + self.geometry
+            + self.shadow
+            + self.light_clusters
+            + self.lighting
+            + self.water
+            + self.transparency
+            + self.debug_draw
+            + self.depth_of_field
+            + self.motion_blur
+            + self.tonemapping
+            + self.upscaling
+            + self.ui
+    }
+
+    // Named, in the order they're recorded
+    pub fn passes(&self) -> [(&'static str, Duration); 13] {
+        [
+            ("Render targets", self.render_targets),
+            ("Geometry", self.geometry),
+            ("Shadow", self.shadow),
+            ("Light clusters", self.light_clusters),
+            ("Lighting", self.lighting),
+            ("Water", self.water),
+            ("Transparency", self.transparency),
+            ("Debug lines", self.debug_draw),
+            ("Depth of field", self.depth_of_field),
+            ("Motion blur", self.motion_blur),
+            ("Tonemapping", self.tonemapping),
+            ("Upscaling", self.upscaling),
+            ("UI", self.ui),
+        ]
+    }
+
+    // Timestamps are in device ticks, in the order given by Timestamp
+    pub(crate) fn from_timestamps(timestamps: &[u64; TIMESTAMP_COUNT], tick_ns: f32) -> Self {
+        let pass = |end: Timestamp| {
+            let ticks = timestamps[end as usize].saturating_sub(timestamps[end as usize - 1]);
+            Duration::from_nanos((ticks as f64 * tick_ns as f64) as u64)
+        };
+        Self {
+            render_targets: pass(Timestamp::RenderTargets),
+            geometry: pass(Timestamp::Geometry),
+            shadow: pass(Timestamp::Shadow),
+            light_clusters: pass(Timestamp::LightClusters),
+            lighting: pass(Timestamp::Lighting),
+            water: pass(Timestamp::Water),
+            transparency: pass(Timestamp::Transparency),
+            debug_draw: pass(Timestamp::DebugDraw),
+            depth_of_field: pass(Timestamp::DepthOfField),
+            motion_blur: pass(Timestamp::MotionBlur),
+            tonemapping: pass(Timestamp::Tonemapping),
+            upscaling: pass(Timestamp::Upscaling),
+            ui: pass(Timestamp::Ui),
+        }
+    }
+}
+
+// What the GPU counted over a pass, for telling overdraw apart from vertex-bound passes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PassStatistics {
+    pub vertex_invocations: u64,
+    pub clipping_primitives: u64, // primitives out of clipping, so ones that weren't culled
+    pub fragment_invocations: u64,
+}
+
+impl PassStatistics {
+    // In the order the query writes them, which is that of their flags' bits
+    fn from_query(query: &[u64; 3]) -> Self {
+        Self {
+            vertex_invocations: query[0],
+            clipping_primitives: query[1],
+            fragment_invocations: query[2],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStatistics {
+    pub render_targets: PassStatistics,
+    pub geometry: PassStatistics,
+    pub shadow: PassStatistics,
+    pub light_clusters: PassStatistics,
+    pub lighting: PassStatistics,
+    pub water: PassStatistics,
+    pub transparency: PassStatistics,
+    pub debug_draw: PassStatistics,
+    pub depth_of_field: PassStatistics,
+    pub motion_blur: PassStatistics,
+    pub tonemapping: PassStatistics,
+    pub upscaling: PassStatistics,
+    pub ui: PassStatistics,
+}
+
+impl PipelineStatistics {
+    // Queries are in the order given by Timestamp, without Start
+    pub(crate) fn from_queries(queries: &[[u64; 3]; STATISTICS_COUNT]) -> Self {
+        let pass = |timestamp: Timestamp| PassStatistics::from_query(&queries[timestamp.query()]);
+        Self {
+            render_targets: pass(Timestamp::RenderTargets),
+            geometry: pass(Timestamp::Geometry),
+            shadow: pass(Timestamp::Shadow),
+            light_clusters: pass(Timestamp::LightClusters),
+            lighting: pass(Timestamp::Lighting),
+            water: pass(Timestamp::Water),
+            transparency: pass(Timestamp::Transparency),
+            debug_draw: pass(Timestamp::DebugDraw),
+            depth_of_field: pass(Timestamp::DepthOfField),
+            motion_blur: pass(Timestamp::MotionBlur),
+            tonemapping: pass(Timestamp::Tonemapping),
+            upscaling: pass(Timestamp::Upscaling),
+            ui: pass(Timestamp::Ui),
+        }
+    }
+}
+
+pub(crate) const TIMESTAMP_COUNT: usize = 14;
+pub(crate) const STATISTICS_COUNT: usize = TIMESTAMP_COUNT - 1; // one per pass
+
+pub(crate) const PIPELINE_STATISTICS: vk::QueryPipelineStatisticFlags =
+    vk::QueryPipelineStatisticFlags::from_raw(
+        vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS.as_raw()
+            | vk::QueryPipelineStatisticFlags::CLIPPING_PRIMITIVES.as_raw()
+            | vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS.as_raw(),
+    );
+
+// Written at the start of each frame, then after each pass in drawing order
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Timestamp {
+    Start,
+    RenderTargets,
+    Geometry,
+    Shadow,
+    LightClusters,
+    Lighting,
+    Water,
+    Transparency,
+    DebugDraw,
+    DepthOfField,
+    MotionBlur,
+    Tonemapping,
+    Upscaling,
+    Ui,
+}
+
+impl Timestamp {
+    // Which pipeline statistics query covers the pass this ends
+    pub fn query(self) -> usize {
+        debug_assert!(!matches!(self, Self::Start));
+        self as usize - 1
+    }
+}
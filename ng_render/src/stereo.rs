@@ -0,0 +1,68 @@
+use ash::vk;
+use nalgebra as na;
+
+use crate::{camera::Camera, render_target::RenderTarget};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StereoCamera {
+    pub left: Camera,
+    pub right: Camera,
+}
+
+impl StereoCamera {
+    // Both eyes share the head's projection, and sit either side of it along its +y
+    pub fn from_head(head: &Camera, eye_separation: f32) -> Self {
+        let eye = |offset: f32| {
+            let translation = na::Matrix4::new_translation(&na::Vector3::new(0.0, offset, 0.0));
+            Camera {
+                transform: (na::Matrix4::from(head.transform) * translation).into(),
+                projection: head.projection,
+            }
+        };
+        Self {
+            left: eye(0.5 * eye_separation),
+            right: eye(-0.5 * eye_separation),
+        }
+    }
+
+    pub fn eye(&self, eye: Eye) -> &Camera {
+        match eye {
+            Eye::Left => &self.left,
+            Eye::Right => &self.right,
+        }
+    }
+}
+
+// A render target per eye, for headsets and other stereo displays. Each eye is drawn with the same
+// passes as any other render target.
+#[derive(Clone, Debug)]
+pub struct StereoTarget {
+    left: RenderTarget,
+    right: RenderTarget,
+}
+
+impl StereoTarget {
+    pub fn new(extent: vk::Extent2D) -> Self {
+        Self {
+            left: RenderTarget::new(extent),
+            right: RenderTarget::new(extent),
+        }
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.left.extent()
+    }
+
+    pub fn eye(&self, eye: Eye) -> &RenderTarget {
+        match eye {
+            Eye::Left => &self.left,
+            Eye::Right => &self.right,
+        }
+    }
+}
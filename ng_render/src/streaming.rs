@@ -0,0 +1,200 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+
+// Decodes assets on background threads, highest priority first, so the render loop only ever
+// sees finished ones. Each load hands back a placeholder until then.
+pub struct AssetStreamer {
+    shared: Arc<Shared>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    queued: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: BinaryHeap<Job>,
+    next_sequence: u64,
+    shutting_down: bool,
+}
+
+struct Job {
+    priority: i32,
+    sequence: u64, // breaks ties, so equal priorities load in the order they were requested
+    run: Box<dyn FnOnce() + Send>,
+}
+
+// Cheap to clone; every clone sees the asset once it's loaded
+pub struct Streamed<T> {
+    slot: Arc<Slot<T>>,
+}
+
+struct Slot<T> {
+    current: Mutex<T>,
+    failed: AtomicBool,
+    loaded: AtomicBool,
+}
+
+impl AssetStreamer {
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0, "Asset streamer needs at least one thread");
+
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Default::default()),
+            queued: Condvar::new(),
+        });
+        let workers = (0..threads)
+            .map(|index| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("asset streamer {}", index))
+                    .spawn(move || shared.work())
+                    .unwrap()
+            })
+            .collect();
+
+        Self { shared, workers }
+    }
+
+    // Higher priorities load first. Loads whose handles have all been dropped by the time a
+    // thread gets to them are skipped. A decode that panics leaves the placeholder in place and
+    // marks the load failed, without taking its thread down with it.
+    pub fn load<T, F>(&self, priority: i32, placeholder: T, decode: F) -> Streamed<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let slot = Arc::new(Slot {
+            current: Mutex::new(placeholder),
+            failed: AtomicBool::new(false),
+            loaded: AtomicBool::new(false),
+        });
+
+        let weak_slot = Arc::downgrade(&slot);
+        self.push(priority, move || Self::fill(weak_slot, decode));
+
+        Streamed { slot }
+    }
+
+    // How many loads haven't started yet
+    pub fn pending(&self) -> usize {
+        self.shared.queue.lock().unwrap().jobs.len()
+    }
+
+    fn push(&self, priority: i32, run: impl FnOnce() + Send + 'static) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let sequence = queue.next_sequence;
+        queue.next_sequence += 1;
+        queue.jobs.push(Job {
+            priority,
+            sequence,
+            run: Box::new(run),
+        });
+        self.shared.queued.notify_one();
+    }
+
+    fn fill<T>(slot: Weak<Slot<T>>, decode: impl FnOnce() -> T) {
+        if slot.strong_count() == 0 {
+            return;
+        }
+        let asset = panic::catch_unwind(AssertUnwindSafe(decode));
+        if let Some(slot) = slot.upgrade() {
+            match asset {
+                Ok(asset) => {
+                    *slot.current.lock().unwrap() = asset;
+                    slot.loaded.store(true, atomic::Ordering::Release);
+                }
+                Err(_) => {
+                    log::warn!("Streamed asset failed to decode, so keeps its placeholder");
+                    slot.failed.store(true, atomic::Ordering::Release);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for AssetStreamer {
+    // Loads still queued are abandoned, but ones already decoding are finished
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shutting_down = true;
+        self.shared.queued.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Shared {
+    fn work(&self) {
+        loop {
+            let job = {
+                let mut queue = self.queue.lock().unwrap();
+                loop {
+                    if queue.shutting_down {
+                        return;
+                    }
+                    if let Some(job) = queue.jobs.pop() {
+                        break job;
+                    }
+                    queue = self.queued.wait(queue).unwrap();
+                }
+            };
+            (job.run)();
+        }
+    }
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Job {}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// The heap pops the greatest job, which should be the highest priority requested earliest
+impl Ord for Job {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl<T: Clone> Streamed<T> {
+    // The placeholder until the asset is loaded, and the asset from then on
+    pub fn get(&self) -> T {
+        self.slot.current.lock().unwrap().clone()
+    }
+}
+
+impl<T> Streamed<T> {
+    pub fn is_loaded(&self) -> bool {
+        self.slot.loaded.load(atomic::Ordering::Acquire)
+    }
+
+    // If decoding panicked, in which case it'll never be loaded
+    pub fn has_failed(&self) -> bool {
+        self.slot.failed.load(atomic::Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Streamed<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::ops::BitOr;
+
+use ash::vk;
+
+// Where in the pipeline a resource is touched, and how. Passes describe their hazards with the
+// constants below rather than spelling out masks themselves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub stage: vk::PipelineStageFlags,
+    pub access: vk::AccessFlags,
+}
+
+pub const COLOR_ATTACHMENT_WRITE: Access = Access {
+    stage: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+    access: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+};
+
+pub const COLOR_ATTACHMENT_BLEND: Access = Access {
+    stage: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+    access: vk::AccessFlags::from_raw(
+        vk::AccessFlags::COLOR_ATTACHMENT_READ.as_raw()
+            | vk::AccessFlags::COLOR_ATTACHMENT_WRITE.as_raw(),
+    ),
+};
+
+// Either fragment test stage may touch depth, depending on whether the pipeline allows early tests
+const FRAGMENT_TESTS: vk::PipelineStageFlags = vk::PipelineStageFlags::from_raw(
+    vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS.as_raw()
+        | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS.as_raw(),
+);
+
+// Also covers clears, and tests against what's already there
+pub const DEPTH_ATTACHMENT_WRITE: Access = Access {
+    stage: FRAGMENT_TESTS,
+    access: vk::AccessFlags::from_raw(
+        vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ.as_raw()
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE.as_raw(),
+    ),
+};
+
+pub const DEPTH_TEST: Access = Access {
+    stage: FRAGMENT_TESTS,
+    access: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ,
+};
+
+pub const INPUT_ATTACHMENT_READ: Access = Access {
+    stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+    access: vk::AccessFlags::INPUT_ATTACHMENT_READ,
+};
+
+pub const FRAGMENT_SAMPLED: Access = Access {
+    stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+    access: vk::AccessFlags::SHADER_READ,
+};
+
+pub const COMPUTE_SAMPLED: Access = Access {
+    stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+    access: vk::AccessFlags::SHADER_READ,
+};
+
+pub const COMPUTE_STORAGE_WRITE: Access = Access {
+    stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+    access: vk::AccessFlags::SHADER_WRITE,
+};
+
+pub const FRAGMENT_STORAGE_READ: Access = Access {
+    stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+    access: vk::AccessFlags::SHADER_READ,
+};
+
+pub const TRANSFER_READ: Access = Access {
+    stage: vk::PipelineStageFlags::TRANSFER,
+    access: vk::AccessFlags::TRANSFER_READ,
+};
+
+pub const TRANSFER_WRITE: Access = Access {
+    stage: vk::PipelineStageFlags::TRANSFER,
+    access: vk::AccessFlags::TRANSFER_WRITE,
+};
+
+// Swapchain images are only ever written as color attachments, so work up to then can go ahead
+// before one is acquired. Render passes writing them need an external dependency from this stage
+// to order their layout transition after the wait.
+pub const SWAPCHAIN_ACQUIRE_WAIT_STAGE: vk::PipelineStageFlags =
+    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+
+impl Access {
+    // Overwriting something only needs earlier reads to finish, not to be made visible
+    pub const fn execution(self) -> Self {
+        Self {
+            stage: self.stage,
+            access: vk::AccessFlags::empty(),
+        }
+    }
+}
+
+impl BitOr for Access {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self {
+            stage: self.stage | rhs.stage,
+            access: self.access | rhs.access,
+        }
+    }
+}
+
+// Orders a render pass's only subpass after whatever was recorded before it
+pub fn dependency_before(src: Access, dst: Access) -> vk::SubpassDependency {
+    dependency_before_subpass(0, src, dst)
+}
+
+// Likewise for a later subpass, when it's the first to touch what was recorded before
+pub fn dependency_before_subpass(subpass: u32, src: Access, dst: Access) -> vk::SubpassDependency {
+    vk::SubpassDependency::builder()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(subpass)
+        .src_stage_mask(src.stage)
+        .dst_stage_mask(dst.stage)
+        .src_access_mask(src.access)
+        .dst_access_mask(dst.access)
+        .build()
+}
+
+// Orders whatever is recorded after a render pass after its only subpass
+pub fn dependency_after(src: Access, dst: Access) -> vk::SubpassDependency {
+    vk::SubpassDependency::builder()
+        .src_subpass(0)
+        .dst_subpass(vk::SUBPASS_EXTERNAL)
+        .src_stage_mask(src.stage)
+        .dst_stage_mask(dst.stage)
+        .src_access_mask(src.access)
+        .dst_access_mask(dst.access)
+        .build()
+}
+
+// Orders a later subpass after an earlier one of the same render pass, pixel by pixel
+pub fn dependency_between(
+    src_subpass: u32,
+    dst_subpass: u32,
+    src: Access,
+    dst: Access,
+) -> vk::SubpassDependency {
+    vk::SubpassDependency::builder()
+        .src_subpass(src_subpass)
+        .dst_subpass(dst_subpass)
+        .src_stage_mask(src.stage)
+        .dst_stage_mask(dst.stage)
+        .src_access_mask(src.access)
+        .dst_access_mask(dst.access)
+        .dependency_flags(vk::DependencyFlags::BY_REGION)
+        .build()
+}
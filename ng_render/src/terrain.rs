@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use nalgebra as na;
+
+use crate::{
+    material::{Material, MaterialHandle},
+    mesh::{Mesh, MeshInstance, Vertex},
+    sampler::{SamplerSettings, TextureAddressMode},
+    texture::Texture,
+};
+
+// Heights in world units, row by row, with x along rows and y down columns
+#[derive(Clone, Debug)]
+pub struct Heightmap {
+    width: u32,
+    height: u32,
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    pub fn new(width: u32, height: u32, heights: Vec<f32>) -> Self {
+        assert_eq!(
+            heights.len(),
+            width as usize * height as usize,
+            "Heightmap must have a height per sample"
+        );
+        Self {
+            width,
+            height,
+            heights,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // Clamped to the edges, so slopes at the border come out flat
+    fn sample(&self, x: i64, y: i64) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let y = y.clamp(0, self.height as i64 - 1) as usize;
+        self.heights[y * self.width as usize + x]
+    }
+}
+
+// A texture tiled across the terrain wherever the splat map weights it
+#[derive(Clone, Debug)]
+pub struct TerrainLayer {
+    pub texture: Arc<Texture>,
+    pub tile_size: f32, // world units per repeat
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TerrainSettings {
+    pub spacing: f32,          // world units between heightmap samples
+    pub chunk_size: u32,       // samples between chunk edges, divisible by 2^(lod_count - 1)
+    pub lod_count: u32,        // each level halving the resolution of the last
+    pub lod_distance: f32,     // beyond which chunks drop to level 1, doubling for each level after
+    pub splat_resolution: u32, // of each chunk's blended layers
+    pub roughness: f32,
+}
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        Self {
+            spacing: 1.0,
+            chunk_size: 32,
+            lod_count: 4,
+            lod_distance: 32.0,
+            splat_resolution: 128,
+            roughness: 0.9,
+        }
+    }
+}
+
+// Which of a chunk's edges border a coarser chunk, whose vertices its own have to line up with
+const WEST: u8 = 1;
+const EAST: u8 = 2;
+const SOUTH: u8 = 4;
+const NORTH: u8 = 8;
+
+struct Chunk {
+    origin: [u32; 2], // heightmap sample of the corner nearest the origin
+    center: na::Point3<f32>,
+    material: MaterialHandle,
+    meshes: HashMap<(u32, u8), Arc<Mesh>>, // by level and coarser edges, made as they're needed
+}
+
+// Heightmap geometry on the xy plane, from the origin toward +x and +y, split into chunks that are
+// each an instance of their own. The renderer's culling then culls chunk by chunk, while offscreen
+// ones can still cast shadows.
+pub struct Terrain {
+    chunk_counts: [u32; 2],
+    chunks: Vec<Chunk>,
+    heightmap: Heightmap,
+    settings: TerrainSettings,
+}
+
+impl Terrain {
+    // The splat map's red, green, blue and alpha weight up to four layers, in order, and is
+    // stretched over the whole terrain. Each chunk's share of the layers is blended up front.
+    pub fn new(
+        heightmap: Heightmap,
+        splat_map: &Texture,
+        layers: &[TerrainLayer],
+        settings: TerrainSettings,
+    ) -> Self {
+        assert!(
+            settings.lod_count > 0,
+            "Terrain must have a level of detail"
+        );
+        assert_eq!(
+            settings.chunk_size % (1 << (settings.lod_count - 1)),
+            0,
+            "Terrain chunks must divide into every level of detail"
+        );
+        assert!(
+            heightmap.width > 1
+                && heightmap.height > 1
+                && (heightmap.width - 1).is_multiple_of(settings.chunk_size)
+                && (heightmap.height - 1).is_multiple_of(settings.chunk_size),
+            "Heightmap must be a whole number of chunks, plus one sample"
+        );
+        assert!(layers.len() <= 4, "Terrain can have at most four layers");
+        assert!(
+            !splat_map.is_render_target()
+                && layers.iter().all(|layer| !layer.texture.is_render_target()),
+            "Terrain textures must have pixels"
+        );
+
+        let chunk_counts = [
+            (heightmap.width - 1) / settings.chunk_size,
+            (heightmap.height - 1) / settings.chunk_size,
+        ];
+        let mut terrain = Self {
+            chunk_counts,
+            chunks: Vec::with_capacity((chunk_counts[0] * chunk_counts[1]) as _),
+            heightmap,
+            settings,
+        };
+        for chunk_y in 0..chunk_counts[1] {
+            for chunk_x in 0..chunk_counts[0] {
+                let origin = [chunk_x * settings.chunk_size, chunk_y * settings.chunk_size];
+                let half = settings.chunk_size as i64 / 2;
+                let center_height = terrain
+                    .heightmap
+                    .sample(origin[0] as i64 + half, origin[1] as i64 + half);
+                let center = na::Point3::new(
+                    (origin[0] as f32 + half as f32) * settings.spacing,
+                    (origin[1] as f32 + half as f32) * settings.spacing,
+                    center_height,
+                );
+                let material = terrain.bake_material(origin, splat_map, layers);
+                terrain.chunks.push(Chunk {
+                    origin,
+                    center,
+                    material,
+                    meshes: HashMap::new(),
+                });
+            }
+        }
+        terrain
+    }
+
+    // In world units, interpolated between samples; None off the edge of the terrain
+    pub fn height_at(&self, x: f32, y: f32) -> Option<f32> {
+        let x = x / self.settings.spacing;
+        let y = y / self.settings.spacing;
+        let max = [
+            (self.heightmap.width - 1) as f32,
+            (self.heightmap.height - 1) as f32,
+        ];
+        if !(0.0..=max[0]).contains(&x) || !(0.0..=max[1]).contains(&y) {
+            return None;
+        }
+        let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+        let (tx, ty) = (x.fract(), y.fract());
+        let sample = |dx, dy| self.heightmap.sample(x0 + dx, y0 + dy);
+        let south = sample(0, 0) + tx * (sample(1, 0) - sample(0, 0));
+        let north = sample(0, 1) + tx * (sample(1, 1) - sample(0, 1));
+        Some(south + ty * (north - south))
+    }
+
+    // Every chunk, at a level of detail for its distance from eye. Neighbours are kept within a
+    // level of each other, so the finer one can snap its edge to the coarser one's.
+    pub fn instances(&mut self, eye: mint::Point3<f32>) -> Vec<MeshInstance> {
+        let eye = na::Point3::from(eye);
+        let max_lod = self.settings.lod_count - 1;
+        let mut lods: Vec<u32> = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let distance = na::distance(&eye, &chunk.center) / self.settings.lod_distance;
+                if distance < 1.0 {
+                    0
+                } else {
+                    (distance.log2() as u32 + 1).min(max_lod)
+                }
+            })
+            .collect();
+
+        // Coarser chunks next to finer ones are refined until no two neighbours differ by more
+        let width = self.chunk_counts[0] as usize;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in 0..lods.len() {
+                let (x, y) = (index % width, index / width);
+                let finest_neighbour = self
+                    .neighbours(x, y)
+                    .iter()
+                    .flatten()
+                    .map(|&neighbour| lods[neighbour])
+                    .min();
+                if let Some(finest_neighbour) = finest_neighbour {
+                    if lods[index] > finest_neighbour + 1 {
+                        lods[index] = finest_neighbour + 1;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let mut instances = Vec::with_capacity(lods.len());
+        for index in 0..lods.len() {
+            let (x, y) = (index % width, index / width);
+            let lod = lods[index];
+            let neighbours = self.neighbours(x, y);
+            let coarser_edges = [WEST, EAST, SOUTH, NORTH]
+                .iter()
+                .zip(&neighbours)
+                .filter(|(_, neighbour)| neighbour.is_some_and(|neighbour| lods[neighbour] > lod))
+                .fold(0, |edges, (&edge, _)| edges | edge);
+
+            if !self.chunks[index]
+                .meshes
+                .contains_key(&(lod, coarser_edges))
+            {
+                let mesh =
+                    Arc::new(self.create_mesh(self.chunks[index].origin, lod, coarser_edges));
+                self.chunks[index].meshes.insert((lod, coarser_edges), mesh);
+            }
+            let chunk = &self.chunks[index];
+            instances.push(MeshInstance {
+                mesh: chunk.meshes[&(lod, coarser_edges)].clone(),
+                material: Some(chunk.material.clone()),
+                transform: na::Matrix4::identity().into(),
+                previous_transform: None,
+                joint_matrices: None,
+                is_static: true,
+            });
+        }
+        instances
+    }
+
+    // West, east, south and north, where there are any
+    fn neighbours(&self, x: usize, y: usize) -> [Option<usize>; 4] {
+        let [width, height] = self.chunk_counts.map(|count| count as usize);
+        let index = |x: usize, y: usize| y * width + x;
+        [
+            x.checked_sub(1).map(|x| index(x, y)),
+            Some(x + 1).filter(|&x| x < width).map(|x| index(x, y)),
+            y.checked_sub(1).map(|y| index(x, y)),
+            Some(y + 1).filter(|&y| y < height).map(|y| index(x, y)),
+        ]
+    }
+
+    // Along edges shared with a coarser chunk, every other vertex is moved onto the line between
+    // its neighbours, which is where the coarser chunk's edge runs
+    fn create_mesh(&self, origin: [u32; 2], lod: u32, coarser_edges: u8) -> Mesh {
+        let step = 1 << lod;
+        let count = self.settings.chunk_size / step;
+        let spacing = self.settings.spacing;
+        let sample = |i: u32, j: u32| {
+            self.heightmap
+                .sample((origin[0] + i * step) as i64, (origin[1] + j * step) as i64)
+        };
+
+        let mut vertices = Vec::with_capacity(((count + 1) * (count + 1)) as _);
+        for j in 0..=count {
+            for i in 0..=count {
+                let snapped = |edge, along: u32| coarser_edges & edge != 0 && along % 2 == 1;
+                let height = if (i == 0 && snapped(WEST, j)) || (i == count && snapped(EAST, j)) {
+                    0.5 * (sample(i, j - 1) + sample(i, j + 1))
+                } else if (j == 0 && snapped(SOUTH, i)) || (j == count && snapped(NORTH, i)) {
+                    0.5 * (sample(i - 1, j) + sample(i + 1, j))
+                } else {
+                    sample(i, j)
+                };
+
+                // Slopes are taken at full resolution, so lighting doesn't change with detail
+                let (x, y) = ((origin[0] + i * step) as i64, (origin[1] + j * step) as i64);
+                let slope_x = (self.heightmap.sample(x + 1, y) - self.heightmap.sample(x - 1, y))
+                    / (2.0 * spacing);
+                let slope_y = (self.heightmap.sample(x, y + 1) - self.heightmap.sample(x, y - 1))
+                    / (2.0 * spacing);
+                let normal = na::Vector3::new(-slope_x, -slope_y, 1.0).normalize();
+                let tangent = na::Vector3::new(1.0, 0.0, slope_x).normalize();
+
+                vertices.push(Vertex {
+                    position: [x as f32 * spacing, y as f32 * spacing, height].into(),
+                    normal: normal.into(),
+                    color: [1.0; 3].into(),
+                    tex_coord: [i as f32 / count as f32, j as f32 / count as f32].into(),
+                    tangent: tangent.push(1.0).into(),
+                });
+            }
+        }
+
+        // Counterclockwise from above
+        let vertex = |i: u32, j: u32| j * (count + 1) + i;
+        let mut indices = Vec::with_capacity((6 * count * count) as _);
+        for j in 0..count {
+            for i in 0..count {
+                indices.extend_from_slice(&[
+                    vertex(i, j),
+                    vertex(i + 1, j),
+                    vertex(i + 1, j + 1),
+                    vertex(i, j),
+                    vertex(i + 1, j + 1),
+                    vertex(i, j + 1),
+                ]);
+            }
+        }
+        Mesh::new(vertices, indices)
+    }
+
+    // Blends the layers into one texture per chunk, so chunks draw like any other mesh
+    fn bake_material(
+        &self,
+        origin: [u32; 2],
+        splat_map: &Texture,
+        layers: &[TerrainLayer],
+    ) -> MaterialHandle {
+        let resolution = self.settings.splat_resolution;
+        let chunk_size = self.settings.chunk_size as f32;
+        let samples = [
+            (self.heightmap.width - 1) as f32,
+            (self.heightmap.height - 1) as f32,
+        ];
+
+        let mut pixels = Vec::with_capacity((4 * resolution * resolution) as _);
+        for j in 0..resolution {
+            for i in 0..resolution {
+                // In heightmap samples, at the texel's center
+                let x = origin[0] as f32 + (i as f32 + 0.5) / resolution as f32 * chunk_size;
+                let y = origin[1] as f32 + (j as f32 + 0.5) / resolution as f32 * chunk_size;
+                let weights = sample_texture(
+                    splat_map,
+                    x / samples[0],
+                    y / samples[1],
+                    TextureAddressMode::ClampToEdge,
+                );
+
+                let mut color = na::Vector4::zeros();
+                let mut weight_sum = 0.0;
+                for (layer, &weight) in layers.iter().zip(weights.iter()) {
+                    let tile = layer.tile_size / self.settings.spacing;
+                    let texel = sample_texture(
+                        &layer.texture,
+                        x / tile,
+                        y / tile,
+                        TextureAddressMode::Repeat,
+                    );
+                    color += weight * texel;
+                    weight_sum += weight;
+                }
+                let color = if weight_sum > 0.0 {
+                    color / weight_sum
+                } else {
+                    na::Vector4::repeat(1.0)
+                };
+                pixels.extend(color.iter().map(|&channel| (255.0 * channel).round() as u8));
+            }
+        }
+
+        MaterialHandle::new(Material {
+            albedo_texture: Some(Arc::new(Texture::new(resolution, resolution, pixels))),
+            roughness: self.settings.roughness,
+            sampler: SamplerSettings {
+                address_mode: TextureAddressMode::ClampToEdge,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+}
+
+// Bilinear, with channels from 0 to 1
+fn sample_texture(
+    texture: &Texture,
+    u: f32,
+    v: f32,
+    address_mode: TextureAddressMode,
+) -> na::Vector4<f32> {
+    let (width, height) = (texture.width() as i64, texture.height() as i64);
+    let x = u * width as f32 - 0.5;
+    let y = v * height as f32 - 0.5;
+    let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+    let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+    let address = |i: i64, size: i64| match address_mode {
+        TextureAddressMode::Repeat => i.rem_euclid(size),
+        TextureAddressMode::MirroredRepeat => {
+            let i = i.rem_euclid(2 * size);
+            i.min(2 * size - 1 - i)
+        }
+        TextureAddressMode::ClampToEdge => i.clamp(0, size - 1),
+    };
+    let texel = |x: i64, y: i64| {
+        let offset = 4 * (address(y, height) * width + address(x, width)) as usize;
+        na::Vector4::from_fn(|channel, _| texture.pixels()[offset + channel] as f32 / 255.0)
+    };
+    let south = texel(x0, y0).lerp(&texel(x0 + 1, y0), tx);
+    let north = texel(x0, y0 + 1).lerp(&texel(x0 + 1, y0 + 1), tx);
+    south.lerp(&north, ty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: TerrainSettings = TerrainSettings {
+        spacing: 1.0,
+        chunk_size: 8,
+        lod_count: 4,
+        lod_distance: 8.0,
+        splat_resolution: 4,
+        roughness: 0.9,
+    };
+
+    // A single row of chunks
+    fn terrain(
+        chunks: u32,
+        settings: TerrainSettings,
+        height: impl Fn(u32, u32) -> f32,
+    ) -> Terrain {
+        let size = SETTINGS.chunk_size;
+        let (width, depth) = (chunks * size + 1, size + 1);
+        let heights = (0..depth)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| height(x, y))
+            .collect();
+        let heightmap = Heightmap::new(width, depth, heights);
+        let splat_map = Texture::new(1, 1, vec![255, 0, 0, 0]);
+        Terrain::new(heightmap, &splat_map, &[], settings)
+    }
+
+    // Recovered from how many vertices a side its mesh has
+    fn lod(instance: &MeshInstance) -> u32 {
+        let side = (instance.mesh.vertices().len() as f64).sqrt() as u32;
+        (SETTINGS.chunk_size / (side - 1)).trailing_zeros()
+    }
+
+    // The heights along the mesh's edge at x, by y
+    fn edge(instance: &MeshInstance, x: f32) -> Vec<(f32, f32)> {
+        let mut edge: Vec<_> = (instance.mesh.vertices().iter())
+            .filter(|vertex| vertex.position.x == x)
+            .map(|vertex| (vertex.position.y, vertex.position.z))
+            .collect();
+        edge.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        edge
+    }
+
+    // Where the edge runs at y, between its vertices
+    fn edge_height(edge: &[(f32, f32)], y: f32) -> f32 {
+        let after = edge.iter().position(|&(edge_y, _)| edge_y >= y).unwrap();
+        let (y1, z1) = edge[after];
+        if after == 0 || y1 == y {
+            return z1;
+        }
+        let (y0, z0) = edge[after - 1];
+        z0 + (y - y0) / (y1 - y0) * (z1 - z0)
+    }
+
+    #[test]
+    fn keeps_neighbouring_lods_within_one() {
+        // Close enough to the first chunk for full detail, and far enough from the rest that
+        // they'd all be the coarsest level, unclamped
+        let settings = TerrainSettings {
+            lod_distance: 1.0,
+            ..SETTINGS
+        };
+        let mut terrain = terrain(8, settings, |_, _| 0.0);
+        let instances = terrain.instances([4.0, 4.0, 0.0].into());
+        let lods: Vec<_> = instances.iter().map(lod).collect();
+        assert_eq!(lods, [0, 1, 2, 3, 3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn lines_up_edges_between_chunks() {
+        let mut terrain = terrain(4, SETTINGS, |x, y| ((x * x + 3 * y) % 7) as f32);
+        let instances = terrain.instances([4.0, 4.0, 0.0].into());
+        let lods: Vec<_> = instances.iter().map(lod).collect();
+        assert_eq!(lods, [0, 1, 2, 2]);
+
+        for (index, pair) in instances.windows(2).enumerate() {
+            let x = ((index as u32 + 1) * SETTINGS.chunk_size) as f32;
+            let (west, east) = (edge(&pair[0], x), edge(&pair[1], x));
+            for (this, other) in [(&west, &east), (&east, &west)] {
+                for &(y, z) in this.iter() {
+                    let expected = edge_height(other, y);
+                    assert!(
+                        (z - expected).abs() < 1e-5,
+                        "chunks {} and {} part at ({}, {}): {} against {}",
+                        index,
+                        index + 1,
+                        x,
+                        y,
+                        z,
+                        expected,
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Heightmap must be a whole number of chunks, plus one sample")]
+    fn rejects_empty_heightmaps() {
+        let splat_map = Texture::new(1, 1, vec![255, 0, 0, 0]);
+        Terrain::new(Heightmap::new(0, 0, vec![]), &splat_map, &[], SETTINGS);
+    }
+
+    #[test]
+    #[should_panic(expected = "Heightmap must be a whole number of chunks, plus one sample")]
+    fn rejects_single_sample_heightmaps() {
+        let splat_map = Texture::new(1, 1, vec![255, 0, 0, 0]);
+        Terrain::new(Heightmap::new(1, 1, vec![0.0]), &splat_map, &[], SETTINGS);
+    }
+}
@@ -0,0 +1,640 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use ash::{
+    prelude::VkResult,
+    version::{DeviceV1_0, InstanceV1_0},
+    vk,
+};
+
+use crate::{
+    frame::FRAMES_IN_FLIGHT,
+    guard::Guarded,
+    image::Image,
+    owned::DeviceOwned,
+    shared::SharedStem,
+    upload::{self, UploadError},
+    util,
+};
+
+// CPU-side RGBA8 image, in sRGB unless it holds non-color data like normals. Like meshes, it's
+// uploaded lazily the first time it's drawn. Render targets' textures have no pixels, as they're
+// only ever drawn to on the GPU.
+#[derive(Debug)]
+pub struct Texture {
+    height: u32,
+    id: u64,
+    pixels: Vec<u8>,
+    render_target: bool,
+    srgb: bool,
+    width: u32,
+}
+
+impl Texture {
+    pub fn new(width: u32, height: u32, pixels: Vec<u8>) -> Self {
+        Self::with_encoding(width, height, pixels, true)
+    }
+
+    pub fn new_linear(width: u32, height: u32, pixels: Vec<u8>) -> Self {
+        Self::with_encoding(width, height, pixels, false)
+    }
+
+    pub(crate) fn new_render_target(width: u32, height: u32) -> Self {
+        assert!(width > 0 && height > 0, "Render target must not be empty");
+        Self {
+            height,
+            id: Self::next_id(),
+            pixels: Vec::new(),
+            render_target: true,
+            srgb: true,
+            width,
+        }
+    }
+
+    fn with_encoding(width: u32, height: u32, pixels: Vec<u8>, srgb: bool) -> Self {
+        assert!(width > 0 && height > 0, "Texture must not be empty");
+        assert_eq!(
+            pixels.len(),
+            4 * width as usize * height as usize,
+            "Texture pixels must be RGBA8"
+        );
+
+        Self {
+            height,
+            id: Self::next_id(),
+            pixels,
+            render_target: false,
+            srgb,
+            width,
+        }
+    }
+
+    // Halves the resolution levels times over by averaging each 2x2 block, for a stand-in that
+    // takes less memory. Averaging sRGB without decoding it darkens detail a little, which a
+    // stand-in can get away with.
+    pub(crate) fn downsampled(&self, levels: u32) -> Self {
+        let (mut width, mut height) = (self.width, self.height);
+        let mut pixels = self.pixels.clone();
+        for _ in 0..levels {
+            let (half_width, half_height) = ((width / 2).max(1), (height / 2).max(1));
+            let source = std::mem::take(&mut pixels);
+            let texel = |x: u32, y: u32, channel: usize| {
+                let (x, y) = (x.min(width - 1), y.min(height - 1));
+                source[4 * (y * width + x) as usize + channel] as u32
+            };
+            pixels = (0..half_height)
+                .flat_map(|y| (0..half_width).map(move |x| (x, y)))
+                .flat_map(|(x, y)| {
+                    (0..4).map(move |channel| {
+                        let sum = texel(2 * x, 2 * y, channel)
+                            + texel(2 * x + 1, 2 * y, channel)
+                            + texel(2 * x, 2 * y + 1, channel)
+                            + texel(2 * x + 1, 2 * y + 1, channel);
+                        ((sum + 2) / 4) as u8
+                    })
+                })
+                .collect();
+            width = half_width;
+            height = half_height;
+        }
+        Self::with_encoding(width, height, pixels, self.srgb)
+    }
+
+    fn next_id() -> u64 {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn is_srgb(&self) -> bool {
+        self.srgb
+    }
+
+    pub fn is_render_target(&self) -> bool {
+        self.render_target
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+}
+
+pub struct GpuTexture {
+    descriptor: Option<(vk::DescriptorPool, vk::DescriptorSet)>, // if bound as a set of its own
+    image: Image,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl GpuTexture {
+    pub fn new(shared_stem: Arc<SharedStem>, texture: &Texture) -> Result<Self, UploadError> {
+        if texture.is_render_target() {
+            return Self::new_render_target(shared_stem, texture);
+        }
+        unsafe {
+            let device = shared_stem.device();
+
+            let staging_buffer = upload::create_staging_buffer(&shared_stem, &[texture.pixels()])?;
+
+            let mip_levels = Self::mip_levels(&shared_stem, texture);
+            let image = Self::create_image(&shared_stem, texture, mip_levels)?;
+            shared_stem.set_name(image.image, "texture")?;
+            shared_stem.set_name(image.memory, "texture")?;
+            shared_stem.set_name(image.view, "texture")?;
+
+            shared_stem.submit_one_time_commands(|command_buffer| {
+                let subresource_range = vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: mip_levels,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                };
+                let image_memory_barriers = [vk::ImageMemoryBarrier::builder()
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .image(image.image)
+                    .subresource_range(subresource_range)
+                    .build()];
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &image_memory_barriers,
+                );
+
+                let region = vk::BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_row_length: 0,
+                    buffer_image_height: 0,
+                    image_subresource: Self::mip_layers(0),
+                    image_offset: Default::default(),
+                    image_extent: image.resolution,
+                };
+                device.cmd_copy_buffer_to_image(
+                    command_buffer,
+                    staging_buffer.buffer,
+                    image.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[region],
+                );
+
+                // Each mip is blitted from the previous one, which is then done being written to
+                let mut mip_extent = Self::mip_offset(image.resolution);
+                for level in 1..mip_levels {
+                    let image_memory_barriers = [Self::mip_barrier(
+                        image.image,
+                        level - 1,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        vk::AccessFlags::TRANSFER_WRITE,
+                        vk::AccessFlags::TRANSFER_READ,
+                    )];
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[],
+                        &image_memory_barriers,
+                    );
+
+                    let next_mip_extent = vk::Offset3D {
+                        x: (mip_extent.x / 2).max(1),
+                        y: (mip_extent.y / 2).max(1),
+                        z: 1,
+                    };
+                    let region = vk::ImageBlit {
+                        src_subresource: Self::mip_layers(level - 1),
+                        src_offsets: [Default::default(), mip_extent],
+                        dst_subresource: Self::mip_layers(level),
+                        dst_offsets: [Default::default(), next_mip_extent],
+                    };
+                    device.cmd_blit_image(
+                        command_buffer,
+                        image.image,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        image.image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &[region],
+                        vk::Filter::LINEAR,
+                    );
+                    mip_extent = next_mip_extent;
+
+                    let image_memory_barriers = [Self::mip_barrier(
+                        image.image,
+                        level - 1,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        vk::AccessFlags::TRANSFER_READ,
+                        vk::AccessFlags::SHADER_READ,
+                    )];
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::FRAGMENT_SHADER,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[],
+                        &image_memory_barriers,
+                    );
+                }
+
+                let image_memory_barriers = [Self::mip_barrier(
+                    image.image,
+                    mip_levels - 1,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::AccessFlags::SHADER_READ,
+                )];
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &image_memory_barriers,
+                );
+            })?;
+
+            let image = image.take();
+            drop(staging_buffer);
+
+            Ok(Self {
+                descriptor: None,
+                image,
+                shared_stem,
+            })
+        }
+    }
+
+    // Cleared to black, so it can be sampled before anything's been drawn to it
+    fn new_render_target(
+        shared_stem: Arc<SharedStem>,
+        texture: &Texture,
+    ) -> Result<Self, UploadError> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let image = Self::create_image(&shared_stem, texture, 1)?;
+            shared_stem.set_name(image.image, "render target")?;
+            shared_stem.set_name(image.memory, "render target")?;
+            shared_stem.set_name(image.view, "render target")?;
+
+            shared_stem.submit_one_time_commands(|command_buffer| {
+                let image_memory_barriers = [Self::mip_barrier(
+                    image.image,
+                    0,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::TRANSFER_WRITE,
+                )];
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &image_memory_barriers,
+                );
+
+                let clear_color = vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                };
+                let range = vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                };
+                device.cmd_clear_color_image(
+                    command_buffer,
+                    image.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &clear_color,
+                    &[range],
+                );
+
+                let image_memory_barriers = [Self::mip_barrier(
+                    image.image,
+                    0,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::AccessFlags::SHADER_READ,
+                )];
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &image_memory_barriers,
+                );
+            })?;
+
+            Ok(Self {
+                descriptor: None,
+                image: image.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    // For textures bound by themselves, as set layouts with a single sampler at binding 0
+    pub fn with_descriptor_set(
+        shared_stem: Arc<SharedStem>,
+        texture: &Texture,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+    ) -> Result<Self, UploadError> {
+        let mut gpu_texture = Self::new(shared_stem.clone(), texture)?;
+        unsafe {
+            let device = shared_stem.device();
+
+            let descriptor_pool = util::create_descriptor_pool(
+                device,
+                1,
+                &[vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    descriptor_count: 1,
+                }],
+            )?;
+            shared_stem.set_name(*descriptor_pool, "texture")?;
+
+            let descriptor_set = Self::allocate_descriptor_set(
+                device,
+                *descriptor_pool,
+                descriptor_set_layout,
+                gpu_texture.image.view,
+                sampler,
+            )?;
+            shared_stem.set_name(descriptor_set, "texture")?;
+
+            gpu_texture.descriptor = Some((descriptor_pool.take(), descriptor_set));
+        }
+        Ok(gpu_texture)
+    }
+
+    // Mips are generated by blitting, so fall back to a single level if the format can't be
+    // linearly filtered
+    unsafe fn mip_levels(shared_stem: &SharedStem, texture: &Texture) -> u32 {
+        let format_properties = shared_stem
+            .crown()
+            .instance()
+            .get_physical_device_format_properties(
+                shared_stem.physical_device(),
+                Self::format(texture),
+            );
+        let required_features = vk::FormatFeatureFlags::BLIT_SRC
+            | vk::FormatFeatureFlags::BLIT_DST
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+        if !texture.is_render_target()
+            && format_properties
+                .optimal_tiling_features
+                .contains(required_features)
+        {
+            32 - texture.width().max(texture.height()).leading_zeros()
+        } else {
+            1
+        }
+    }
+
+    fn format(texture: &Texture) -> vk::Format {
+        if texture.is_srgb() {
+            vk::Format::R8G8B8A8_SRGB
+        } else {
+            vk::Format::R8G8B8A8_UNORM
+        }
+    }
+
+    fn usage(texture: &Texture) -> vk::ImageUsageFlags {
+        let usage = vk::ImageUsageFlags::SAMPLED
+            | vk::ImageUsageFlags::TRANSFER_SRC
+            | vk::ImageUsageFlags::TRANSFER_DST;
+        if texture.is_render_target() {
+            usage | vk::ImageUsageFlags::COLOR_ATTACHMENT
+        } else {
+            usage
+        }
+    }
+
+    unsafe fn create_image<'a>(
+        shared_stem: &'a SharedStem,
+        texture: &Texture,
+        mip_levels: u32,
+    ) -> Result<Guarded<(Image, &'a ash::Device)>, UploadError> {
+        let select_device_local_memory = |memory_requirements: vk::MemoryRequirements| {
+            shared_stem
+                .select_memory_type(memory_requirements, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+                .ok_or(UploadError::NoAcceptableMemoryType(
+                    memory_requirements,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                ))
+        };
+
+        let image_create_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(Self::format(texture))
+            .extent(vk::Extent3D {
+                width: texture.width(),
+                height: texture.height(),
+                depth: 1,
+            })
+            .mip_levels(mip_levels)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(Self::usage(texture))
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        Image::new(
+            shared_stem.device(),
+            &image_create_info,
+            select_device_local_memory,
+            vk::ImageViewType::TYPE_2D,
+            vk::ImageAspectFlags::COLOR,
+        )?
+    }
+
+    unsafe fn allocate_descriptor_set(
+        device: &ash::Device,
+        descriptor_pool: vk::DescriptorPool,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        view: vk::ImageView,
+        sampler: vk::Sampler,
+    ) -> VkResult<vk::DescriptorSet> {
+        let set_layouts = [descriptor_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&set_layouts);
+        let descriptor_set = device.allocate_descriptor_sets(&allocate_info)?[0];
+
+        let image_info = [vk::DescriptorImageInfo {
+            sampler,
+            image_view: view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let descriptor_writes = [vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(0)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info)
+            .build()];
+        device.update_descriptor_sets(&descriptor_writes, &[]);
+
+        Ok(descriptor_set)
+    }
+
+    fn mip_barrier(
+        image: vk::Image,
+        level: u32,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+        src_access_mask: vk::AccessFlags,
+        dst_access_mask: vk::AccessFlags,
+    ) -> vk::ImageMemoryBarrier {
+        vk::ImageMemoryBarrier::builder()
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: level,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .build()
+    }
+
+    fn mip_layers(level: u32) -> vk::ImageSubresourceLayers {
+        vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: level,
+            base_array_layer: 0,
+            layer_count: 1,
+        }
+    }
+
+    fn mip_offset(extent: vk::Extent3D) -> vk::Offset3D {
+        vk::Offset3D {
+            x: extent.width as _,
+            y: extent.height as _,
+            z: extent.depth as _,
+        }
+    }
+
+    pub fn descriptor_set(&self) -> DeviceOwned<'_, Option<vk::DescriptorSet>> {
+        let descriptor_set = self
+            .descriptor
+            .map(|(_descriptor_pool, descriptor_set)| descriptor_set);
+        DeviceOwned::new(&self.shared_stem, descriptor_set)
+    }
+
+    pub fn image(&self) -> DeviceOwned<'_, vk::Image> {
+        DeviceOwned::new(&self.shared_stem, self.image.image)
+    }
+
+    pub fn view(&self) -> DeviceOwned<'_, vk::ImageView> {
+        DeviceOwned::new(&self.shared_stem, self.image.view)
+    }
+
+    pub fn allocated_bytes(&self) -> u64 {
+        self.image.allocation_size()
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.image.resolution_2d()
+    }
+}
+
+// Textures are evicted and replaced mid-frame, so their images are only destroyed once no frame
+// in flight could still be sampling them
+impl Drop for GpuTexture {
+    fn drop(&mut self) {
+        let descriptor_pool = self
+            .descriptor
+            .map(|(descriptor_pool, _descriptor_set)| descriptor_pool);
+        self.shared_stem
+            .retired_textures()
+            .retire(std::mem::take(&mut self.image), descriptor_pool);
+    }
+}
+
+// Like RetiredBuffers, but for textures' images and the pools of those with descriptor sets of
+// their own
+#[derive(Default)]
+pub struct RetiredTextures {
+    textures: Mutex<Vec<(Image, Option<vk::DescriptorPool>, u32)>>, // with a bit per frame waiting
+}
+
+impl RetiredTextures {
+    pub fn retire(&self, image: Image, descriptor_pool: Option<vk::DescriptorPool>) {
+        let waiting = (1 << FRAMES_IN_FLIGHT) - 1;
+        let mut textures = self.textures.lock().unwrap();
+        textures.push((image, descriptor_pool, waiting));
+    }
+
+    // This frame in flight's fence was just waited on
+    pub unsafe fn recycle(&self, device: &ash::Device, frame_index: usize) {
+        self.textures
+            .lock()
+            .unwrap()
+            .retain_mut(|(image, descriptor_pool, waiting)| {
+                *waiting &= !(1 << frame_index);
+                if *waiting == 0 {
+                    Self::destroy(device, image, *descriptor_pool);
+                }
+                *waiting != 0
+            });
+    }
+
+    // Only once the device is idle
+    pub unsafe fn destroy_with(&mut self, device: &ash::Device) {
+        for (mut image, descriptor_pool, _) in self.textures.get_mut().unwrap().drain(..) {
+            Self::destroy(device, &mut image, descriptor_pool);
+        }
+    }
+
+    unsafe fn destroy(
+        device: &ash::Device,
+        image: &mut Image,
+        descriptor_pool: Option<vk::DescriptorPool>,
+    ) {
+        if let Some(descriptor_pool) = descriptor_pool {
+            device.destroy_descriptor_pool(descriptor_pool, None);
+        }
+        image.destroy_with(device);
+    }
+}
@@ -1,191 +1,282 @@
 use std::ffi::CStr;
 use std::sync::Arc;
+use std::time::Instant;
 
 use ash::{prelude::VkResult, version::DeviceV1_0, vk};
-use vk_shader_macros::include_glsl;
+use crevice::std140::{AsStd140, Std140};
 
 use crate::{
+    blue_noise,
+    color_grading::{ColorGrade, ColorGrading, ColorGradingStem},
     guard::{GuardableResource, Guarded},
-    shared::{SharedFrond, SharedStem},
-    util,
+    image::Image,
+    shaders::include_shader,
+    shared::{OutputEncoding, SharedFrond, SharedStem},
+    sync,
+    texture::GpuTexture,
+    upload::UploadError,
+    util::{self, Descriptor},
 };
 
-pub struct TonemappingStem {
-    descriptor_set_layout: vk::DescriptorSetLayout,
-    pipeline_layout: vk::PipelineLayout,
-    shared_stem: Arc<SharedStem>,
-    frag_shader_module: vk::ShaderModule,
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TonemappingOperator {
+    Linear { exposure: f32 }, // clamps anything brighter than white
+    Reinhard { exposure: f32 },
+    Aces { exposure: f32 },
 }
 
-impl TonemappingStem {
-    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
-        unsafe {
-            let device = shared_stem.device();
+impl TonemappingOperator {
+    const COUNT: u32 = 3;
 
-            let descriptor_set_layout = Self::create_descriptor_set_layout(device)?;
-            shared_stem.set_name(*descriptor_set_layout, "tonemapping")?;
+    // Matches the OPERATOR_* constants in tonemapping.frag
+    fn id(&self) -> u32 {
+        match self {
+            Self::Linear { .. } => 0,
+            Self::Reinhard { .. } => 1,
+            Self::Aces { .. } => 2,
+        }
+    }
 
-            let pipeline_layout = util::create_pipeline_layout(
-                device,
-                &[*descriptor_set_layout],
-                &[], // push constant ranges
-            )?;
-            shared_stem.set_name(*pipeline_layout, "tonemapping")?;
+    fn exposure(&self) -> f32 {
+        match *self {
+            Self::Linear { exposure } | Self::Reinhard { exposure } | Self::Aces { exposure } => {
+                exposure
+            }
+        }
+    }
+}
 
-            let frag_shader_module =
-                util::create_shader_module(device, include_glsl!("shaders/tonemapping.frag"))?;
-            shared_stem.set_name(*frag_shader_module, "tonemapping frag")?;
+impl Default for TonemappingOperator {
+    fn default() -> Self {
+        Self::Linear { exposure: 1.0 }
+    }
+}
 
-            Ok(Self {
-                descriptor_set_layout: descriptor_set_layout.take(),
-                pipeline_layout: pipeline_layout.take(),
-                frag_shader_module: frag_shader_module.take(),
-                shared_stem,
-            })
+// Whichever image the last post-processing pass left the frame in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TonemappingInput {
+    Light,
+    DepthOfField,
+    MotionBlur,
+}
+
+impl TonemappingInput {
+    const ALL: [Self; 3] = [Self::Light, Self::DepthOfField, Self::MotionBlur];
+
+    fn image(self, shared_frond: &SharedFrond) -> &Image {
+        match self {
+            Self::Light => shared_frond.light(),
+            Self::DepthOfField => shared_frond.depth_of_field(),
+            Self::MotionBlur => shared_frond.motion_blur(),
         }
     }
+}
 
-    unsafe fn create_descriptor_set_layout(
-        device: &ash::Device,
-    ) -> VkResult<Guarded<(vk::DescriptorSetLayout, &ash::Device)>> {
-        let bindings = [vk::DescriptorSetLayoutBinding::builder()
-            .binding(0)
-            .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
-            .descriptor_count(1)
-            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-            .build()];
-        let descriptor_set_layout_create_info =
-            vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
-        Ok(device
-            .create_descriptor_set_layout(&descriptor_set_layout_create_info, None)?
-            .guard_with(device))
-    }
+#[derive(AsStd140)]
+struct TonemappingBuffer {
+    pub exposure: f32,
+    pub lut_blend: f32,
 }
 
-impl Drop for TonemappingStem {
-    fn drop(&mut self) {
-        unsafe {
-            let device = self.shared_stem.device();
-            let _ = device.device_wait_idle();
+impl TonemappingBuffer {
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: Self::std140_size_static() as _,
+        }
+    }
+}
 
-            device.destroy_shader_module(self.frag_shader_module, None);
-            device.destroy_pipeline_layout(self.pipeline_layout, None);
-            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+impl TonemappingBuffer {
+    fn new(operator: TonemappingOperator, lut_blend: f32) -> Self {
+        Self {
+            exposure: operator.exposure(),
+            lut_blend,
         }
     }
 }
 
-pub struct TonemappingFrond {
-    descriptor_pool: vk::DescriptorPool,
-    descriptor_set: vk::DescriptorSet,
-    framebuffers: Vec<vk::Framebuffer>,
-    pipeline: vk::Pipeline,
+pub struct TonemappingStem {
+    blue_noise: GpuTexture,
+    blue_noise_sampler: vk::Sampler,
+    color_grading: Arc<ColorGradingStem>,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    pipelines: Vec<vk::Pipeline>, // indexed by TonemappingOperator::id
+    pipeline_layout: vk::PipelineLayout,
     render_pass: vk::RenderPass,
-    shared_frond: Arc<SharedFrond>,
-    tonemapping_stem: Arc<TonemappingStem>,
+    shared_stem: Arc<SharedStem>,
+    frag_shader_module: vk::ShaderModule,
 }
 
-impl TonemappingFrond {
+impl TonemappingStem {
     pub fn new(
-        tonemapping_stem: Arc<TonemappingStem>,
-        shared_frond: Arc<SharedFrond>,
-    ) -> VkResult<Self> {
-        let shared_stem = &tonemapping_stem.shared_stem;
-        shared_stem.assert_is(&shared_frond.stem());
+        shared_stem: Arc<SharedStem>,
+        color_grading: Arc<ColorGradingStem>,
+    ) -> Result<Self, UploadError> {
+        let output_format = shared_stem.surface_format().format;
+        let output_layout = shared_stem.output_layout();
+        let output_encoding = shared_stem.output_encoding();
+        let transparent = shared_stem.transparent();
+        Self::with_output(
+            shared_stem,
+            color_grading,
+            output_format,
+            output_layout,
+            output_encoding,
+            transparent,
+        )
+    }
+
+    // Render targets are sampled as opaque sRGB textures afterwards, whatever the surface is
+    pub fn new_render_target(
+        shared_stem: Arc<SharedStem>,
+        color_grading: Arc<ColorGradingStem>,
+    ) -> Result<Self, UploadError> {
+        Self::with_output(
+            shared_stem,
+            color_grading,
+            vk::Format::R8G8B8A8_SRGB,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            OutputEncoding::Srgb,
+            false,
+        )
+    }
+
+    // Upscaling samples the result, which is still encoded like the surface
+    pub fn new_upscale_input(
+        shared_stem: Arc<SharedStem>,
+        color_grading: Arc<ColorGradingStem>,
+    ) -> Result<Self, UploadError> {
+        let output_format = shared_stem.surface_format().format;
+        let output_encoding = shared_stem.output_encoding();
+        let transparent = shared_stem.transparent();
+        Self::with_output(
+            shared_stem,
+            color_grading,
+            output_format,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            output_encoding,
+            transparent,
+        )
+    }
+
+    // So a replacement can keep the uploaded grades
+    #[cfg(feature = "hot-reload")]
+    pub fn color_grading(&self) -> &Arc<ColorGradingStem> {
+        &self.color_grading
+    }
+
+    fn with_output(
+        shared_stem: Arc<SharedStem>,
+        color_grading: Arc<ColorGradingStem>,
+        output_format: vk::Format,
+        output_layout: vk::ImageLayout,
+        output_encoding: OutputEncoding,
+        transparent: bool,
+    ) -> Result<Self, UploadError> {
+        let blue_noise = GpuTexture::new(shared_stem.clone(), blue_noise::texture())?;
         unsafe {
-            let device = shared_frond.device();
+            let device = shared_stem.device();
 
-            let descriptor_pool = util::create_descriptor_pool(
-                device,
-                1,
-                &[vk::DescriptorPoolSize {
-                    ty: vk::DescriptorType::INPUT_ATTACHMENT,
-                    descriptor_count: 1,
-                }],
-            )?;
-            shared_stem.set_name(*descriptor_pool, "tonemapping")?;
+            let descriptor_set_layout = Self::create_descriptor_set_layout(device)?;
+            shared_stem.set_name(*descriptor_set_layout, "tonemapping")?;
 
-            let descriptor_set = Self::allocate_descriptor_set(
+            // The LUTs grading is crossfaded between are bound as sets 1 and 2
+            let lut_layout = color_grading.descriptor_set_layout();
+            let pipeline_layout = util::create_pipeline_layout(
                 device,
-                *descriptor_pool,
-                tonemapping_stem.descriptor_set_layout,
-                shared_frond.light().view,
+                &[*descriptor_set_layout, lut_layout, lut_layout],
+                &[TonemappingBuffer::push_constant_range()],
             )?;
-            shared_stem.set_name(descriptor_set, "tonemapping")?;
+            shared_stem.set_name(*pipeline_layout, "tonemapping")?;
+
+            let frag_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/tonemapping.frag"))?;
+            shared_stem.set_name(*frag_shader_module, "tonemapping frag")?;
+
+            // Only ever fetched texel by texel, wrapping around by hand
+            let blue_noise_sampler = device
+                .create_sampler(&Default::default(), None)?
+                .guard_with(device);
+            shared_stem.set_name(*blue_noise_sampler, "blue noise")?;
 
             let render_pass = Self::create_render_pass(
                 device,
-                shared_frond.light().format,
-                shared_frond.swapchain_format(),
+                SharedFrond::LIGHT_FORMAT,
+                output_format,
+                output_layout,
             )?;
             shared_stem.set_name(*render_pass, "tonemapping")?;
 
-            let pipeline = Self::create_pipeline(
-                device,
-                shared_frond.stem().fullscreen_vert_shader_module(),
-                tonemapping_stem.frag_shader_module,
-                shared_frond.resolution(),
-                tonemapping_stem.pipeline_layout,
-                *render_pass,
-            )?;
-            shared_stem.set_name(*pipeline, "tonemapping")?;
-
-            let framebuffers = Self::create_framebuffers(
-                device,
-                *render_pass,
-                shared_frond.light().view,
-                shared_frond.swapchain_image_views(),
-                shared_frond.resolution(),
-            )?;
-            for framebuffer in framebuffers.iter() {
-                shared_stem.set_name(*framebuffer, "tonemapping")?;
+            let mut pipelines = Vec::<vk::Pipeline>::new().guard_with(device);
+            for operator in 0..TonemappingOperator::COUNT {
+                let pipeline = Self::create_pipeline(
+                    device,
+                    shared_stem.fullscreen_vert_shader_module(),
+                    *frag_shader_module,
+                    &util::Specialization::new(&[
+                        output_encoding.specialization_constant(),
+                        operator,
+                        shared_stem.color_workflow().specialization_constant(),
+                        Self::output_levels(output_format),
+                        transparent as u32,
+                    ]),
+                    *pipeline_layout,
+                    *render_pass,
+                )?;
+                shared_stem.set_name(*pipeline, "tonemapping")?;
+                pipelines.push(pipeline.take());
             }
 
             Ok(Self {
-                descriptor_pool: descriptor_pool.take(),
-                framebuffers: framebuffers.take(),
-                pipeline: pipeline.take(),
+                blue_noise,
+                blue_noise_sampler: blue_noise_sampler.take(),
+                color_grading,
+                descriptor_set_layout: descriptor_set_layout.take(),
+                pipelines: pipelines.take(),
+                pipeline_layout: pipeline_layout.take(),
                 render_pass: render_pass.take(),
-                descriptor_set,
-                shared_frond,
-                tonemapping_stem,
+                frag_shader_module: frag_shader_module.take(),
+                shared_stem,
             })
         }
     }
 
-    unsafe fn allocate_descriptor_set(
+    // The light to tonemap, then the blue noise dithering it
+    unsafe fn create_descriptor_set_layout(
         device: &ash::Device,
-        descriptor_pool: vk::DescriptorPool,
-        descriptor_set_layout: vk::DescriptorSetLayout,
-        light_view: vk::ImageView,
-    ) -> VkResult<vk::DescriptorSet> {
-        let set_layouts = [descriptor_set_layout];
-        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(&set_layouts);
-        let descriptor_set = device.allocate_descriptor_sets(&allocate_info)?[0];
-
-        let image_info = [vk::DescriptorImageInfo {
-            sampler: vk::Sampler::null(),
-            image_view: light_view,
-            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-        }];
-        let descriptor_writes = [vk::WriteDescriptorSet::builder()
-            .dst_set(descriptor_set)
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
-            .image_info(&image_info)
-            .build()];
-        device.update_descriptor_sets(&descriptor_writes, &[]);
+    ) -> VkResult<Guarded<(vk::DescriptorSetLayout, &ash::Device)>> {
+        util::create_descriptor_set_layout(
+            device,
+            &[
+                (
+                    vk::DescriptorType::INPUT_ATTACHMENT,
+                    vk::ShaderStageFlags::FRAGMENT,
+                ),
+                (
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    vk::ShaderStageFlags::FRAGMENT,
+                ),
+            ],
+        )
+    }
 
-        Ok(descriptor_set)
+    // Steps between the output's darkest and brightest values, which dithering spreads banding
+    // across. Floating point outputs have no banding to hide.
+    fn output_levels(output_format: vk::Format) -> u32 {
+        match output_format {
+            vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::A2R10G10B10_UNORM_PACK32 => 1023,
+            vk::Format::R16G16B16A16_SFLOAT => 0,
+            _ => 255,
+        }
     }
 
     unsafe fn create_render_pass(
         device: &ash::Device,
         light_format: vk::Format,
-        swapchain_format: vk::Format,
+        output_format: vk::Format,
+        output_layout: vk::ImageLayout,
     ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
         let attachments = [
             vk::AttachmentDescription::builder()
@@ -197,12 +288,12 @@ impl TonemappingFrond {
                 .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
                 .build(),
             vk::AttachmentDescription::builder()
-                .format(swapchain_format)
+                .format(output_format)
                 .samples(vk::SampleCountFlags::TYPE_1)
                 .load_op(vk::AttachmentLoadOp::DONT_CARE)
                 .store_op(vk::AttachmentStoreOp::STORE)
                 .initial_layout(vk::ImageLayout::UNDEFINED)
-                .final_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .final_layout(output_layout)
                 .build(),
         ];
 
@@ -220,14 +311,24 @@ impl TonemappingFrond {
             .color_attachments(&color_attachments)
             .build()];
 
-        let dependencies = [vk::SubpassDependency::builder()
-            .src_subpass(vk::SUBPASS_EXTERNAL)
-            .dst_subpass(0)
-            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
-            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
-            .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ)
-            .build()];
+        let dependencies = [
+            sync::dependency_before(sync::COLOR_ATTACHMENT_WRITE, sync::INPUT_ATTACHMENT_READ),
+            // The offscreen target is shared between frames in flight. This also orders the
+            // swapchain image's layout transition after the wait for its acquisition.
+            sync::dependency_before(sync::COLOR_ATTACHMENT_WRITE, sync::COLOR_ATTACHMENT_WRITE),
+            // Render targets are sampled by materials, both before and after they're drawn to
+            sync::dependency_before(
+                sync::FRAGMENT_SAMPLED.execution(),
+                sync::COLOR_ATTACHMENT_WRITE,
+            ),
+            sync::dependency_after(sync::COLOR_ATTACHMENT_WRITE, sync::FRAGMENT_SAMPLED),
+            // Likewise for upscaling's input, which is sampled by a compute pass
+            sync::dependency_before(
+                sync::COMPUTE_SAMPLED.execution(),
+                sync::COLOR_ATTACHMENT_WRITE,
+            ),
+            sync::dependency_after(sync::COLOR_ATTACHMENT_WRITE, sync::COMPUTE_SAMPLED),
+        ];
 
         let render_pass_create_info = vk::RenderPassCreateInfo::builder()
             .attachments(&attachments)
@@ -238,23 +339,25 @@ impl TonemappingFrond {
             .guard_with(device))
     }
 
-    unsafe fn create_pipeline(
-        device: &ash::Device,
+    unsafe fn create_pipeline<'a>(
+        device: &'a ash::Device,
         triangle_vert_shader_module: vk::ShaderModule,
         triangle_frag_shader_module: vk::ShaderModule,
-        resolution: vk::Extent2D,
+        frag_specialization: &util::Specialization,
         pipeline_layout: vk::PipelineLayout,
         render_pass: vk::RenderPass,
-    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+    ) -> VkResult<Guarded<(vk::Pipeline, &'a ash::Device)>> {
         let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
         let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
             .module(triangle_vert_shader_module)
             .name(entry_point)
             .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_specialization_info = frag_specialization.info();
         let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
             .module(triangle_frag_shader_module)
             .name(entry_point)
-            .stage(vk::ShaderStageFlags::FRAGMENT);
+            .stage(vk::ShaderStageFlags::FRAGMENT)
+            .specialization_info(&frag_specialization_info);
         let shader_stages = [*vert_create_info, *frag_create_info];
 
         let vertex_input_state = Default::default();
@@ -262,21 +365,9 @@ impl TonemappingFrond {
         let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
             .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
 
-        let viewports = [vk::Viewport {
-            x: 0.0,
-            y: 0.0,
-            width: resolution.width as _,
-            height: resolution.height as _,
-            min_depth: 0.0,
-            max_depth: 1.0,
-        }];
-        let scissors = [vk::Rect2D {
-            offset: vk::Offset2D { x: 0, y: 0 },
-            extent: resolution,
-        }];
         let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors);
+            .viewport_count(1)
+            .scissor_count(1);
 
         let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
             .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
@@ -293,6 +384,10 @@ impl TonemappingFrond {
         let color_blend_state =
             vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
 
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
         let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
             .stages(&shader_stages)
             .vertex_input_state(&vertex_input_state)
@@ -303,7 +398,7 @@ impl TonemappingFrond {
             .multisample_state(&multisample_state)
             //.depth_stencil_state()
             .color_blend_state(&color_blend_state)
-            // .dynamic_state()
+            .dynamic_state(&dynamic_state)
             .layout(pipeline_layout)
             .render_pass(render_pass)
             .subpass(0)
@@ -319,6 +414,102 @@ impl TonemappingFrond {
 
         Ok(pipelines.pop().unwrap().guard_with(device))
     }
+}
+
+impl Drop for TonemappingStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            for &pipeline in self.pipelines.iter() {
+                device.destroy_pipeline(pipeline, None);
+            }
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_shader_module(self.frag_shader_module, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            device.destroy_sampler(self.blue_noise_sampler, None);
+        }
+    }
+}
+
+pub struct TonemappingFrond {
+    descriptor_sets: Vec<vk::DescriptorSet>, // indexed by TonemappingInput
+    framebuffers: Vec<Vec<vk::Framebuffer>>, // indexed by TonemappingInput, then swapchain image
+    shared_frond: Arc<SharedFrond>,
+    tonemapping_stem: Arc<TonemappingStem>,
+}
+
+impl TonemappingFrond {
+    pub fn new(
+        tonemapping_stem: Arc<TonemappingStem>,
+        shared_frond: Arc<SharedFrond>,
+    ) -> VkResult<Self> {
+        let shared_stem = &tonemapping_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+
+            // Upscaling has tonemapping write to the same image whichever one is presented
+            let output_views = shared_frond.output_views()?;
+            let output_views = match shared_frond.upscale_input() {
+                Some(upscale_input) => vec![upscale_input.view; output_views.len()],
+                None => output_views.to_vec(),
+            };
+
+            let descriptor_allocator = shared_frond.descriptor_allocator();
+            let mut descriptor_sets =
+                Vec::<vk::DescriptorSet>::with_capacity(TonemappingInput::ALL.len())
+                    .guard_with((descriptor_allocator, device));
+            let mut framebuffers = Vec::with_capacity(TonemappingInput::ALL.len());
+            for input in TonemappingInput::ALL {
+                let name = format!("tonemapping {:?}", input);
+                let input_view = input.image(&shared_frond).view;
+
+                let descriptor_set = descriptor_allocator
+                    .allocate(device, tonemapping_stem.descriptor_set_layout)?;
+                util::write_descriptor_set(
+                    device,
+                    *descriptor_set,
+                    &[
+                        (0, Descriptor::InputAttachment(input_view)),
+                        (
+                            1,
+                            Descriptor::CombinedImageSampler(
+                                tonemapping_stem.blue_noise.view().get(shared_stem),
+                                tonemapping_stem.blue_noise_sampler,
+                            ),
+                        ),
+                    ],
+                );
+                shared_stem.set_name(*descriptor_set, &name)?;
+                descriptor_sets.push(descriptor_set.take());
+
+                let input_framebuffers = Self::create_framebuffers(
+                    device,
+                    tonemapping_stem.render_pass,
+                    input_view,
+                    &output_views,
+                    shared_frond.resolution(),
+                )?;
+                for framebuffer in input_framebuffers.iter() {
+                    shared_stem.set_name(*framebuffer, &name)?;
+                }
+                framebuffers.push(input_framebuffers);
+            }
+
+            Ok(Self {
+                descriptor_sets: descriptor_sets.take(),
+                framebuffers: framebuffers
+                    .into_iter()
+                    .map(|framebuffers| framebuffers.take())
+                    .collect(),
+                shared_frond,
+                tonemapping_stem,
+            })
+        }
+    }
 
     unsafe fn create_framebuffers<'a>(
         device: &'a ash::Device,
@@ -341,8 +532,27 @@ impl TonemappingFrond {
         Ok(framebuffers)
     }
 
-    pub unsafe fn draw(&self, command_buffer: vk::CommandBuffer, image_index: u32) {
+    pub fn prepare_grade(
+        &self,
+        grading: &ColorGrading,
+        now: Instant,
+    ) -> Result<Option<ColorGrade>, UploadError> {
+        self.tonemapping_stem
+            .color_grading
+            .prepare_grade(grading, now)
+    }
+
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        image_index: u32,
+        operator: TonemappingOperator,
+        grade: Option<&ColorGrade>, // ungraded if None
+        input: TonemappingInput,
+    ) {
         let device = self.shared_frond.device();
+        let framebuffers = &self.framebuffers[input as usize];
+        let descriptor_set = self.descriptor_sets[input as usize];
 
         let render_area = vk::Rect2D {
             offset: Default::default(),
@@ -352,8 +562,8 @@ impl TonemappingFrond {
         let clear_values = [Default::default(), Default::default()];
 
         let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-            .render_pass(self.render_pass)
-            .framebuffer(self.framebuffers[image_index as usize])
+            .render_pass(self.tonemapping_stem.render_pass)
+            .framebuffer(framebuffers[image_index as usize])
             .render_area(render_area)
             .clear_values(&clear_values);
         device.cmd_begin_render_pass(
@@ -365,18 +575,42 @@ impl TonemappingFrond {
         device.cmd_bind_pipeline(
             command_buffer,
             vk::PipelineBindPoint::GRAPHICS,
-            self.pipeline,
+            self.tonemapping_stem.pipelines[operator.id() as usize],
         );
+        util::set_viewport_and_scissor(device, command_buffer, self.shared_frond.resolution());
 
         device.cmd_bind_descriptor_sets(
             command_buffer,
             vk::PipelineBindPoint::GRAPHICS,
             self.tonemapping_stem.pipeline_layout,
             0,
-            &[self.descriptor_set],
+            &[descriptor_set],
             &[],
         );
 
+        let identity = self.tonemapping_stem.color_grading.identity();
+        let (from, to, lut_blend) = match grade {
+            Some(grade) => (&grade.from, &grade.to, grade.blend),
+            None => (identity, identity, 0.0),
+        };
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.tonemapping_stem.pipeline_layout,
+            1,
+            &[from.descriptor_set(), to.descriptor_set()],
+            &[],
+        );
+
+        let tonemapping_buffer = TonemappingBuffer::new(operator, lut_blend);
+        device.cmd_push_constants(
+            command_buffer,
+            self.tonemapping_stem.pipeline_layout,
+            vk::ShaderStageFlags::FRAGMENT,
+            0,
+            tonemapping_buffer.as_std140().as_bytes(),
+        );
+
         device.cmd_draw(
             command_buffer,
             3, // vertices
@@ -384,6 +618,7 @@ impl TonemappingFrond {
             0, // first vertex
             0, // first instance
         );
+        self.shared_frond.count_draw(1);
 
         device.cmd_end_render_pass(command_buffer);
     }
@@ -395,12 +630,12 @@ impl Drop for TonemappingFrond {
             let device = self.shared_frond.device();
             let _ = device.device_wait_idle();
 
-            for &framebuffer in self.framebuffers.iter() {
+            for &framebuffer in self.framebuffers.iter().flatten() {
                 device.destroy_framebuffer(framebuffer, None);
             }
-            device.destroy_pipeline(self.pipeline, None);
-            device.destroy_render_pass(self.render_pass, None);
-            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            self.shared_frond
+                .descriptor_allocator()
+                .free(device, &self.descriptor_sets);
         }
     }
 }
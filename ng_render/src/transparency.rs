@@ -0,0 +1,520 @@
+use std::ffi::CStr;
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+use nalgebra as na;
+
+use crate::{
+    buffer::Buffer,
+    culling::Frustum,
+    environment::GpuEnvironment,
+    frame::FRAMES_IN_FLIGHT,
+    geometry::GeometryStem,
+    guard::{GuardableResource, Guarded},
+    light::{AmbientLight, DirectionalLight},
+    lighting::{LightingFrond, LightingStem},
+    material::MaterialBinding,
+    mesh::{GpuMeshInstance, Vertex},
+    shaders::include_shader,
+    shared::{ModelBuffer, SharedFrond, SharedStem, ViewBuffer},
+    sync,
+    upload::{self, UploadError},
+    util::{self, Descriptor},
+};
+
+#[derive(AsStd140)]
+struct TransparencyBuffer {
+    pub world_to_screen: mint::ColumnMatrix4<f32>,
+    pub eye: mint::Vector4<f32>,
+    pub sunlight_direction: mint::Vector4<f32>,
+    pub sunlight_color: mint::Vector4<f32>,
+    pub ambient_color: mint::Vector4<f32>,
+    pub cascade_count: u32,
+}
+
+// Forward-shades blended materials over the lit scene, since the G-buffer only holds one surface
+// per pixel. Sets are the material, then this frame's sun and shadows, then the environment.
+pub struct TransparencyStem {
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    frag_shader_module: vk::ShaderModule,
+    _geometry_stem: Arc<GeometryStem>, // owns the material set layout and vertex shader
+    lighting_stem: Arc<LightingStem>,  // owns the environment set layout and shadow sampler
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl TransparencyStem {
+    pub fn new(
+        shared_stem: Arc<SharedStem>,
+        geometry_stem: Arc<GeometryStem>,
+        lighting_stem: Arc<LightingStem>,
+    ) -> VkResult<Self> {
+        unsafe {
+            let device = shared_stem.device();
+
+            // Shadow cascades, their matrices, the camera and sun, then the cascades again for
+            // comparison sampling
+            let descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                    (
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                    (
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                ],
+            )?;
+            shared_stem.set_name(*descriptor_set_layout, "transparency")?;
+
+            let pipeline_layout = util::create_pipeline_layout(
+                device,
+                &[
+                    geometry_stem.material_descriptor_set_layout(),
+                    *descriptor_set_layout,
+                    lighting_stem.environment_descriptor_set_layout(),
+                ],
+                &[
+                    ViewBuffer::push_constant_range(),
+                    ModelBuffer::push_constant_range(),
+                ],
+            )?;
+            shared_stem.set_name(*pipeline_layout, "transparency")?;
+
+            let frag_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/transparent.frag"))?;
+            shared_stem.set_name(*frag_shader_module, "transparent frag")?;
+
+            let render_pass = Self::create_render_pass(
+                device,
+                SharedFrond::LIGHT_FORMAT,
+                shared_stem.depth_stencil_format(),
+                shared_stem.depth_read_layout(),
+            )?;
+            shared_stem.set_name(*render_pass, "transparency")?;
+
+            let pipeline = Self::create_pipeline(
+                device,
+                geometry_stem.vert_shader_module(),
+                *frag_shader_module,
+                &util::Specialization::new(&[shared_stem
+                    .color_workflow()
+                    .specialization_constant()]),
+                *pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*pipeline, "transparency")?;
+
+            Ok(Self {
+                descriptor_set_layout: descriptor_set_layout.take(),
+                frag_shader_module: frag_shader_module.take(),
+                _geometry_stem: geometry_stem,
+                lighting_stem,
+                pipeline: pipeline.take(),
+                pipeline_layout: pipeline_layout.take(),
+                render_pass: render_pass.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    // Blends into the light attachment as left by lighting, testing against the opaque depth
+    unsafe fn create_render_pass(
+        device: &ash::Device,
+        light_format: vk::Format,
+        depth_format: vk::Format,
+        depth_layout: vk::ImageLayout,
+    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(light_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(depth_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(depth_layout)
+                .final_layout(depth_layout)
+                .build(),
+        ];
+
+        let color_attachments = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_stencil_attachment = vk::AttachmentReference {
+            attachment: 1,
+            layout: depth_layout,
+        };
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_attachments)
+            .depth_stencil_attachment(&depth_stencil_attachment)
+            .build()];
+
+        let dependencies = [
+            sync::dependency_before(sync::COLOR_ATTACHMENT_WRITE, sync::COLOR_ATTACHMENT_BLEND),
+            sync::dependency_before(sync::DEPTH_ATTACHMENT_WRITE, sync::DEPTH_TEST),
+        ];
+
+        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+        Ok(device
+            .create_render_pass(&render_pass_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_pipeline<'a>(
+        device: &'a ash::Device,
+        triangle_vert_shader_module: vk::ShaderModule,
+        transparent_frag_shader_module: vk::ShaderModule,
+        frag_specialization: &util::Specialization,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &'a ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(triangle_vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_specialization_info = frag_specialization.info();
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(transparent_frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT)
+            .specialization_info(&frag_specialization_info);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        let vertex_binding_descriptions = Vertex::binding_descriptions();
+        let vertex_attribute_descriptions = Vertex::attribute_descriptions();
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_binding_descriptions)
+            .vertex_attribute_descriptions(&vertex_attribute_descriptions);
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        // Hidden behind opaque geometry, but never hiding each other
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(false)
+            .depth_compare_op(vk::CompareOp::GREATER)
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(false);
+
+        // Premultiplied alpha over whatever's already lit, adding to its coverage
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            blend_enable: vk::TRUE,
+            src_color_blend_factor: vk::BlendFactor::ONE,
+            dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor: vk::BlendFactor::ONE,
+            dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            alpha_blend_op: vk::BlendOp::ADD,
+            color_write_mask: vk::ColorComponentFlags::all(),
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+}
+
+impl Drop for TransparencyStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_shader_module(self.frag_shader_module, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+pub struct TransparencyFrond {
+    descriptor_sets: Vec<vk::DescriptorSet>, // per frame in flight
+    framebuffer: vk::Framebuffer,
+    shared_frond: Arc<SharedFrond>,
+    transparency_buffers: Vec<Buffer>, // per frame in flight
+    transparency_stem: Arc<TransparencyStem>,
+}
+
+impl TransparencyFrond {
+    // Shares the lighting frond's shadow cascades, which it rewrites each frame before this draws
+    pub fn new(
+        transparency_stem: Arc<TransparencyStem>,
+        lighting_frond: &LightingFrond,
+        shared_frond: Arc<SharedFrond>,
+    ) -> Result<Self, UploadError> {
+        let shared_stem = &transparency_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+
+            let mut transparency_buffers = Vec::<Buffer>::new().guard_with(device);
+            for _ in 0..FRAMES_IN_FLIGHT {
+                let transparency_buffer = upload::create_buffer(
+                    shared_stem,
+                    TransparencyBuffer::std140_size_static() as _,
+                    vk::BufferUsageFlags::UNIFORM_BUFFER,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )?;
+                shared_stem.set_name(transparency_buffer.buffer, "transparency")?;
+                shared_stem.set_name(transparency_buffer.memory, "transparency")?;
+                transparency_buffers.push(transparency_buffer.take());
+            }
+
+            let descriptor_allocator = shared_frond.descriptor_allocator();
+            let mut descriptor_sets = Vec::<vk::DescriptorSet>::with_capacity(FRAMES_IN_FLIGHT)
+                .guard_with((descriptor_allocator, device));
+            for (frame_index, transparency_buffer) in transparency_buffers.iter().enumerate() {
+                let descriptor_set = descriptor_allocator
+                    .allocate(device, transparency_stem.descriptor_set_layout)?;
+                util::write_descriptor_set(
+                    device,
+                    *descriptor_set,
+                    &[
+                        (
+                            0,
+                            Descriptor::CombinedImageSampler(
+                                shared_frond.shadow().view,
+                                transparency_stem.lighting_stem.shadow_sampler(),
+                            ),
+                        ),
+                        (
+                            1,
+                            Descriptor::UniformBuffer(lighting_frond.shadow_buffer(frame_index)),
+                        ),
+                        (2, Descriptor::UniformBuffer(transparency_buffer.buffer)),
+                        (
+                            3,
+                            Descriptor::CombinedImageSampler(
+                                shared_frond.shadow().view,
+                                transparency_stem.lighting_stem.shadow_compare_sampler(),
+                            ),
+                        ),
+                    ],
+                );
+                shared_stem.set_name(*descriptor_set, "transparency")?;
+                descriptor_sets.push(descriptor_set.take());
+            }
+
+            let framebuffer = util::create_framebuffer(
+                device,
+                transparency_stem.render_pass,
+                &[shared_frond.light().view, shared_frond.depth_stencil().view],
+                shared_frond.resolution(),
+            )?;
+            shared_stem.set_name(*framebuffer, "transparency")?;
+
+            Ok(Self {
+                framebuffer: framebuffer.take(),
+                transparency_buffers: transparency_buffers.take(),
+                descriptor_sets: descriptor_sets.take(),
+                shared_frond,
+                transparency_stem,
+            })
+        }
+    }
+
+    // Draws whichever meshes are in view, furthest first so that nearer ones blend over them
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        view: &na::Matrix4<f32>,
+        eye: &na::Point3<f32>,
+        sun: &DirectionalLight,
+        ambient: &AmbientLight,
+        cascade_count: usize,
+        environment: &GpuEnvironment,
+        meshes: &[GpuMeshInstance],
+    ) -> VkResult<()> {
+        let device = self.shared_frond.device();
+
+        let mut meshes = Frustum::new(view).cull(meshes);
+        if meshes.is_empty() {
+            return Ok(());
+        }
+        let distance = |instance: &GpuMeshInstance| {
+            na::distance_squared(eye, &instance.world_bounds().center())
+        };
+        meshes.sort_by(|a, b| distance(b).total_cmp(&distance(a)));
+
+        let transparency_buffer = TransparencyBuffer {
+            world_to_screen: (*view).into(),
+            eye: eye.coords.push(1.0).into(),
+            sunlight_direction: sun.unit_direction().push(0.0).into(),
+            sunlight_color: sun.radiance().push(0.0).into(),
+            ambient_color: ambient.scale().push(0.0).into(),
+            cascade_count: cascade_count as _,
+        };
+        self.transparency_buffers[frame_index].write(
+            device,
+            0,
+            transparency_buffer.as_std140().as_bytes(),
+        )?;
+
+        let render_area = vk::Rect2D {
+            offset: Default::default(),
+            extent: self.shared_frond.resolution(),
+        };
+
+        let clear_values = [Default::default(), Default::default()];
+
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.transparency_stem.render_pass)
+            .framebuffer(self.framebuffer)
+            .render_area(render_area)
+            .clear_values(&clear_values);
+        device.cmd_begin_render_pass(
+            command_buffer,
+            &render_pass_begin_info,
+            vk::SubpassContents::INLINE,
+        );
+
+        let view_buffer = ViewBuffer {
+            view: (*view).into(),
+        };
+        device.cmd_push_constants(
+            command_buffer,
+            self.transparency_stem.pipeline_layout,
+            vk::ShaderStageFlags::VERTEX,
+            0,
+            view_buffer.as_std140().as_bytes(),
+        );
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.transparency_stem.pipeline,
+        );
+        util::set_viewport_and_scissor(device, command_buffer, self.shared_frond.resolution());
+
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.transparency_stem.pipeline_layout,
+            1,
+            &[
+                self.descriptor_sets[frame_index],
+                environment.descriptor_set(),
+            ],
+            &[],
+        );
+
+        for instance in &meshes {
+            let model_buffer = ModelBuffer {
+                model: instance.transform,
+            };
+            device.cmd_push_constants(
+                command_buffer,
+                self.transparency_stem.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX,
+                ViewBuffer::std140_size_static() as _,
+                model_buffer.as_std140().as_bytes(),
+            );
+            let shared_stem = &self.transparency_stem.shared_stem;
+            let descriptor_set = match instance.material.binding().get(shared_stem) {
+                MaterialBinding::DescriptorSet(descriptor_set) => descriptor_set,
+                MaterialBinding::Bindless(_) => unreachable!("blended materials aren't bindless"),
+            };
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.transparency_stem.pipeline_layout,
+                0,
+                &[descriptor_set],
+                &[],
+            );
+
+            instance.mesh.draw(shared_stem, command_buffer);
+        }
+
+        device.cmd_end_render_pass(command_buffer);
+
+        Ok(())
+    }
+}
+
+impl Drop for TransparencyFrond {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_frond.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_framebuffer(self.framebuffer, None);
+            self.shared_frond
+                .descriptor_allocator()
+                .free(device, &self.descriptor_sets);
+            for transparency_buffer in &mut self.transparency_buffers {
+                transparency_buffer.destroy_with(device);
+            }
+        }
+    }
+}
@@ -0,0 +1,744 @@
+use std::ffi::CStr;
+use std::sync::{Arc, Mutex};
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+use egui::{epaint, ClippedMesh, TextureId};
+
+use crate::{
+    guard::{GuardableResource, Guarded},
+    shaders::include_shader,
+    shared::{SharedFrond, SharedStem},
+    sync,
+    texture::{GpuTexture, Texture},
+    upload::UploadError,
+    util::{self, Descriptor},
+};
+
+// Everything egui produced for one frame
+pub struct UiFrame {
+    pub meshes: Vec<ClippedMesh>,
+    pub texture: Arc<epaint::Texture>,
+    pub pixels_per_point: f32,
+}
+
+#[derive(AsStd140)]
+struct UiBuffer {
+    pub screen_size: mint::Vector2<f32>, // in points
+}
+
+impl UiBuffer {
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            offset: 0,
+            size: Self::std140_size_static() as _,
+        }
+    }
+}
+
+// egui is drawn into an overlay of its own, where it blends in sRGB space like it was designed
+// for, and only the result is composited over the output, which may be linear or HDR
+pub struct UiStem {
+    composite_descriptor_set_layout: vk::DescriptorSetLayout,
+    composite_frag_shader_module: vk::ShaderModule,
+    composite_pipeline: vk::Pipeline,
+    composite_pipeline_layout: vk::PipelineLayout,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    font_sampler: vk::Sampler,
+    font_texture: Mutex<Option<(u64, Arc<GpuTexture>)>>, // tagged with egui's texture version
+    frag_shader_module: vk::ShaderModule,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    shared_stem: Arc<SharedStem>,
+    vert_shader_module: vk::ShaderModule,
+}
+
+impl UiStem {
+    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
+        unsafe {
+            let device = shared_stem.device();
+
+            let descriptor_set_layout = Self::create_descriptor_set_layout(device)?;
+            shared_stem.set_name(*descriptor_set_layout, "ui")?;
+
+            let font_sampler = Self::create_sampler(device)?;
+            shared_stem.set_name(*font_sampler, "ui font")?;
+
+            let pipeline_layout = util::create_pipeline_layout(
+                device,
+                &[*descriptor_set_layout],
+                &[UiBuffer::push_constant_range()],
+            )?;
+            shared_stem.set_name(*pipeline_layout, "ui")?;
+
+            let vert_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/ui.vert"))?;
+            shared_stem.set_name(*vert_shader_module, "ui vert")?;
+
+            let frag_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/ui.frag"))?;
+            shared_stem.set_name(*frag_shader_module, "ui frag")?;
+
+            let composite_descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[(
+                    vk::DescriptorType::INPUT_ATTACHMENT,
+                    vk::ShaderStageFlags::FRAGMENT,
+                )],
+            )?;
+            shared_stem.set_name(*composite_descriptor_set_layout, "ui composite")?;
+
+            let composite_pipeline_layout =
+                util::create_pipeline_layout(device, &[*composite_descriptor_set_layout], &[])?;
+            shared_stem.set_name(*composite_pipeline_layout, "ui composite")?;
+
+            let composite_frag_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/ui-composite.frag"))?;
+            shared_stem.set_name(*composite_frag_shader_module, "ui composite frag")?;
+
+            let render_pass = Self::create_render_pass(
+                device,
+                shared_stem.surface_format().format,
+                shared_stem.output_layout(),
+            )?;
+            shared_stem.set_name(*render_pass, "ui")?;
+
+            let pipeline = Self::create_pipeline(
+                device,
+                *vert_shader_module,
+                *frag_shader_module,
+                *pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*pipeline, "ui")?;
+
+            let composite_pipeline = Self::create_composite_pipeline(
+                device,
+                shared_stem.fullscreen_vert_shader_module(),
+                *composite_frag_shader_module,
+                &util::Specialization::new(&[shared_stem
+                    .output_encoding()
+                    .specialization_constant()]),
+                *composite_pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*composite_pipeline, "ui composite")?;
+
+            Ok(Self {
+                composite_descriptor_set_layout: composite_descriptor_set_layout.take(),
+                composite_frag_shader_module: composite_frag_shader_module.take(),
+                composite_pipeline: composite_pipeline.take(),
+                composite_pipeline_layout: composite_pipeline_layout.take(),
+                descriptor_set_layout: descriptor_set_layout.take(),
+                font_sampler: font_sampler.take(),
+                font_texture: Mutex::new(None),
+                frag_shader_module: frag_shader_module.take(),
+                pipeline: pipeline.take(),
+                pipeline_layout: pipeline_layout.take(),
+                render_pass: render_pass.take(),
+                vert_shader_module: vert_shader_module.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    unsafe fn create_descriptor_set_layout(
+        device: &ash::Device,
+    ) -> VkResult<Guarded<(vk::DescriptorSetLayout, &ash::Device)>> {
+        let bindings = [vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .build()];
+        let descriptor_set_layout_create_info =
+            vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        Ok(device
+            .create_descriptor_set_layout(&descriptor_set_layout_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_sampler(
+        device: &ash::Device,
+    ) -> VkResult<Guarded<(vk::Sampler, &ash::Device)>> {
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .compare_enable(false)
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE)
+            .unnormalized_coordinates(false);
+        Ok(device
+            .create_sampler(&sampler_create_info, None)?
+            .guard_with(device))
+    }
+
+    // Draws the overlay, then composites it on top of whatever tonemapping left in the output
+    // image
+    unsafe fn create_render_pass(
+        device: &ash::Device,
+        output_format: vk::Format,
+        output_layout: vk::ImageLayout,
+    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(SharedFrond::UI_FORMAT)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(output_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(output_layout)
+                .final_layout(output_layout)
+                .build(),
+        ];
+
+        let overlay_attachments = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let input_attachments = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let output_attachments = [vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let subpasses = [
+            vk::SubpassDescription::builder()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .color_attachments(&overlay_attachments)
+                .build(),
+            vk::SubpassDescription::builder()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .input_attachments(&input_attachments)
+                .color_attachments(&output_attachments)
+                .build(),
+        ];
+
+        let dependencies = [
+            // The overlay is shared between frames in flight
+            sync::dependency_before(
+                sync::INPUT_ATTACHMENT_READ.execution(),
+                sync::COLOR_ATTACHMENT_WRITE,
+            ),
+            sync::dependency_between(
+                0,
+                1,
+                sync::COLOR_ATTACHMENT_WRITE,
+                sync::INPUT_ATTACHMENT_READ,
+            ),
+            sync::dependency_before_subpass(
+                1,
+                sync::COLOR_ATTACHMENT_WRITE,
+                sync::COLOR_ATTACHMENT_BLEND,
+            ),
+        ];
+
+        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+        Ok(device
+            .create_render_pass(&render_pass_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_pipeline<'a>(
+        device: &'a ash::Device,
+        vert_shader_module: vk::ShaderModule,
+        frag_shader_module: vk::ShaderModule,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &'a ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        let vertex_binding_descriptions = [vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: std::mem::size_of::<epaint::Vertex>() as _,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }];
+        let vec2_size = std::mem::size_of::<epaint::Pos2>() as u32;
+        let vertex_attribute_descriptions = [
+            vk::VertexInputAttributeDescription {
+                location: 0,
+                binding: 0,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 1,
+                binding: 0,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: vec2_size,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 2,
+                binding: 0,
+                format: vk::Format::R8G8B8A8_UNORM,
+                offset: 2 * vec2_size,
+            },
+        ];
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_binding_descriptions)
+            .vertex_attribute_descriptions(&vertex_attribute_descriptions);
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        // egui outputs premultiplied alpha, which the overlay keeps
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            blend_enable: vk::TRUE,
+            src_color_blend_factor: vk::BlendFactor::ONE,
+            dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor: vk::BlendFactor::ONE,
+            dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            alpha_blend_op: vk::BlendOp::ADD,
+            color_write_mask: vk::ColorComponentFlags::all(),
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            // .tesselation_state()
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            //.depth_stencil_state()
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+
+    unsafe fn create_composite_pipeline<'a>(
+        device: &'a ash::Device,
+        fullscreen_vert_shader_module: vk::ShaderModule,
+        frag_shader_module: vk::ShaderModule,
+        frag_specialization: &util::Specialization,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &'a ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(fullscreen_vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_specialization_info = frag_specialization.info();
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT)
+            .specialization_info(&frag_specialization_info);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        let vertex_input_state = Default::default();
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        // The overlay's still premultiplied once encoded like the output
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            blend_enable: vk::TRUE,
+            src_color_blend_factor: vk::BlendFactor::ONE,
+            dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_DST_ALPHA,
+            dst_alpha_blend_factor: vk::BlendFactor::ONE,
+            alpha_blend_op: vk::BlendOp::ADD,
+            color_write_mask: vk::ColorComponentFlags::all(),
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(1)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+
+    // Reuploads the font atlas whenever egui changes it
+    pub fn prepare_texture(
+        &self,
+        texture: &epaint::Texture,
+    ) -> Result<Arc<GpuTexture>, UploadError> {
+        let mut font_texture = self.font_texture.lock().unwrap();
+        if let Some((version, gpu_texture)) = &*font_texture {
+            if *version == texture.version {
+                return Ok(gpu_texture.clone());
+            }
+        }
+
+        // egui only stores coverage, but textures are uploaded as RGBA
+        let pixels = texture
+            .pixels
+            .iter()
+            .flat_map(|&alpha| [255, 255, 255, alpha])
+            .collect();
+        let texture_rgba = Texture::new(texture.width as _, texture.height as _, pixels);
+        let gpu_texture = Arc::new(GpuTexture::with_descriptor_set(
+            self.shared_stem.clone(),
+            &texture_rgba,
+            self.descriptor_set_layout,
+            self.font_sampler,
+        )?);
+        *font_texture = Some((texture.version, gpu_texture.clone()));
+        Ok(gpu_texture)
+    }
+}
+
+impl Drop for UiStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            drop(self.font_texture.get_mut().unwrap().take());
+            device.destroy_pipeline(self.composite_pipeline, None);
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_shader_module(self.composite_frag_shader_module, None);
+            device.destroy_shader_module(self.frag_shader_module, None);
+            device.destroy_shader_module(self.vert_shader_module, None);
+            device.destroy_pipeline_layout(self.composite_pipeline_layout, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_sampler(self.font_sampler, None);
+            device.destroy_descriptor_set_layout(self.composite_descriptor_set_layout, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+pub struct UiFrond {
+    composite_descriptor_set: vk::DescriptorSet,
+    framebuffers: Vec<vk::Framebuffer>,
+    shared_frond: Arc<SharedFrond>,
+    ui_stem: Arc<UiStem>,
+}
+
+impl UiFrond {
+    pub fn new(ui_stem: Arc<UiStem>, shared_frond: Arc<SharedFrond>) -> Result<Self, UploadError> {
+        let shared_stem = &ui_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+            let overlay_view = shared_frond
+                .ui()
+                .expect("render targets don't draw egui")
+                .view;
+
+            let composite_descriptor_set = shared_frond
+                .descriptor_allocator()
+                .allocate(device, ui_stem.composite_descriptor_set_layout)?;
+            util::write_descriptor_set(
+                device,
+                *composite_descriptor_set,
+                &[(0, Descriptor::InputAttachment(overlay_view))],
+            );
+            shared_stem.set_name(*composite_descriptor_set, "ui composite")?;
+
+            let mut framebuffers = Vec::<vk::Framebuffer>::new().guard_with(device);
+            for &image_view in shared_frond.output_views()? {
+                let framebuffer = util::create_framebuffer(
+                    device,
+                    ui_stem.render_pass,
+                    &[overlay_view, image_view],
+                    shared_frond.output_resolution(),
+                )?;
+                shared_stem.set_name(*framebuffer, "ui")?;
+                framebuffers.push(framebuffer.take());
+            }
+
+            Ok(Self {
+                composite_descriptor_set: composite_descriptor_set.take(),
+                framebuffers: framebuffers.take(),
+                shared_frond,
+                ui_stem,
+            })
+        }
+    }
+
+    pub fn prepare_texture(
+        &self,
+        texture: &epaint::Texture,
+    ) -> Result<Arc<GpuTexture>, UploadError> {
+        self.ui_stem.prepare_texture(texture)
+    }
+
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        image_index: u32,
+        ui: &UiFrame,
+        font_texture: &GpuTexture,
+    ) -> VkResult<()> {
+        let device = self.shared_frond.device();
+        let resolution = self.shared_frond.output_resolution();
+
+        // Pack every mesh together, remembering where each one landed
+        let mut draws = Vec::new();
+        let mut vertex_data = Vec::new();
+        let mut index_data = Vec::new();
+        let mut vertex_count = 0;
+        let mut index_count = 0;
+        for ClippedMesh(clip_rect, mesh) in &ui.meshes {
+            if mesh.texture_id != TextureId::Egui {
+                continue;
+            }
+            let scissor = match Self::scissor(*clip_rect, ui.pixels_per_point, resolution) {
+                Some(scissor) => scissor,
+                None => continue,
+            };
+
+            vertex_data.extend_from_slice(std::slice::from_raw_parts(
+                mesh.vertices.as_ptr() as *const u8,
+                std::mem::size_of_val(mesh.vertices.as_slice()),
+            ));
+            index_data.extend(mesh.indices.iter().flat_map(|index| index.to_ne_bytes()));
+
+            draws.push((scissor, index_count, mesh.indices.len(), vertex_count));
+            vertex_count += mesh.vertices.len();
+            index_count += mesh.indices.len();
+        }
+        if draws.is_empty() {
+            return Ok(());
+        }
+        let stem = self.shared_frond.stem();
+        let staging_belt = stem.staging_belt();
+        let vertices = staging_belt.stage(device, frame_index, &vertex_data)?;
+        let indices = staging_belt.stage(device, frame_index, &index_data)?;
+
+        let render_area = vk::Rect2D {
+            offset: Default::default(),
+            extent: resolution,
+        };
+
+        let clear_values = [Default::default(), Default::default()];
+
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.ui_stem.render_pass)
+            .framebuffer(self.framebuffers[image_index as usize])
+            .render_area(render_area)
+            .clear_values(&clear_values);
+        device.cmd_begin_render_pass(
+            command_buffer,
+            &render_pass_begin_info,
+            vk::SubpassContents::INLINE,
+        );
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.ui_stem.pipeline,
+        );
+        util::set_viewport_and_scissor(device, command_buffer, resolution);
+
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.ui_stem.pipeline_layout,
+            0,
+            &[font_texture
+                .descriptor_set()
+                .get(&self.ui_stem.shared_stem)
+                .unwrap()],
+            &[],
+        );
+
+        let ui_buffer = UiBuffer {
+            screen_size: [
+                resolution.width as f32 / ui.pixels_per_point,
+                resolution.height as f32 / ui.pixels_per_point,
+            ]
+            .into(),
+        };
+        device.cmd_push_constants(
+            command_buffer,
+            self.ui_stem.pipeline_layout,
+            vk::ShaderStageFlags::VERTEX,
+            0,
+            ui_buffer.as_std140().as_bytes(),
+        );
+
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[vertices.buffer], &[vertices.offset]);
+        device.cmd_bind_index_buffer(
+            command_buffer,
+            indices.buffer,
+            indices.offset,
+            vk::IndexType::UINT32,
+        );
+
+        for (scissor, first_index, index_count, vertex_offset) in draws {
+            device.cmd_set_scissor(command_buffer, 0, &[scissor]);
+            device.cmd_draw_indexed(
+                command_buffer,
+                index_count as _,
+                1, // instances
+                first_index as _,
+                vertex_offset as _,
+                0, // first instance
+            );
+            self.shared_frond.count_draw(index_count as u64 / 3);
+        }
+
+        device.cmd_next_subpass(command_buffer, vk::SubpassContents::INLINE);
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.ui_stem.composite_pipeline,
+        );
+        util::set_viewport_and_scissor(device, command_buffer, resolution);
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.ui_stem.composite_pipeline_layout,
+            0,
+            &[self.composite_descriptor_set],
+            &[],
+        );
+        device.cmd_draw(
+            command_buffer,
+            3, // vertices
+            1, // instances
+            0, // first vertex
+            0, // first instance
+        );
+        self.shared_frond.count_draw(1);
+
+        device.cmd_end_render_pass(command_buffer);
+        Ok(())
+    }
+
+    // Converts egui's clip rectangle from points to pixels, or None if nothing would be visible
+    fn scissor(
+        clip_rect: egui::Rect,
+        pixels_per_point: f32,
+        resolution: vk::Extent2D,
+    ) -> Option<vk::Rect2D> {
+        let to_pixels =
+            |points: f32, limit: u32| (points * pixels_per_point).round().clamp(0.0, limit as _);
+        let min_x = to_pixels(clip_rect.min.x, resolution.width);
+        let min_y = to_pixels(clip_rect.min.y, resolution.height);
+        let max_x = to_pixels(clip_rect.max.x, resolution.width);
+        let max_y = to_pixels(clip_rect.max.y, resolution.height);
+        if min_x >= max_x || min_y >= max_y {
+            return None;
+        }
+        Some(vk::Rect2D {
+            offset: vk::Offset2D {
+                x: min_x as _,
+                y: min_y as _,
+            },
+            extent: vk::Extent2D {
+                width: (max_x - min_x) as _,
+                height: (max_y - min_y) as _,
+            },
+        })
+    }
+}
+
+impl Drop for UiFrond {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_frond.device();
+            let _ = device.device_wait_idle();
+
+            for &framebuffer in self.framebuffers.iter() {
+                device.destroy_framebuffer(framebuffer, None);
+            }
+            self.shared_frond
+                .descriptor_allocator()
+                .free(device, &[self.composite_descriptor_set]);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+
+use ash::{version::DeviceV1_0, vk};
+use thiserror::Error;
+
+use crate::{buffer::Buffer, guard::Guarded, image::Image, shared::SharedStem};
+
+#[derive(Error, Debug)]
+pub enum UploadError {
+    #[error("Vulkan error occurred")]
+    VkError(#[from] vk::Result),
+    #[error("Couldn't select acceptable memory type for {0:?} and {1:?}")]
+    NoAcceptableMemoryType(vk::MemoryRequirements, vk::MemoryPropertyFlags),
+    #[error("Bindless table has no free {0} slots")]
+    BindlessTableFull(&'static str),
+}
+
+impl UploadError {
+    pub fn vk_result(&self) -> Option<vk::Result> {
+        match *self {
+            Self::VkError(result) => Some(result),
+            _ => None,
+        }
+    }
+}
+
+// Maps CPU-side resources to their uploaded GPU counterparts, dropping the GPU copies once the
+// CPU-side resource itself is gone.
+pub struct UploadCache<T, G> {
+    entries: HashMap<u64, (Weak<T>, Arc<G>)>,
+}
+
+impl<T, G> UploadCache<T, G> {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn evict_unused(&mut self) {
+        self.entries
+            .retain(|_, (resource, _)| resource.strong_count() > 0);
+    }
+
+    // Drops the GPU copies of whichever resources match, to be uploaded afresh if they're needed
+    pub fn evict_where(&mut self, mut predicate: impl FnMut(&T) -> bool) {
+        self.entries.retain(|_, (resource, _)| {
+            resource
+                .upgrade()
+                .map_or(false, |resource| !predicate(&resource))
+        });
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.entries.remove(&id);
+    }
+
+    // Swaps in a different GPU copy, like one at another resolution
+    pub fn replace(&mut self, id: u64, resource: &Arc<T>, uploaded: Arc<G>) {
+        self.entries
+            .insert(id, (Arc::downgrade(resource), uploaded));
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.entries.contains_key(&id)
+    }
+
+    pub fn resource(&self, id: u64) -> Option<Arc<T>> {
+        self.entries
+            .get(&id)
+            .and_then(|(resource, _)| resource.upgrade())
+    }
+
+    pub fn get_or_upload<E>(
+        &mut self,
+        id: u64,
+        resource: &Arc<T>,
+        upload: impl FnOnce(&T) -> Result<G, E>,
+    ) -> Result<Arc<G>, E> {
+        if let Some((_, uploaded)) = self.entries.get(&id) {
+            return Ok(uploaded.clone());
+        }
+        let uploaded = Arc::new(upload(resource)?);
+        self.entries
+            .insert(id, (Arc::downgrade(resource), uploaded.clone()));
+        Ok(uploaded)
+    }
+}
+
+impl<T, G> Default for UploadCache<T, G> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Caps the bytes of new resources uploaded in one frame, so a burst of freshly streamed assets
+// doesn't hitch the frame they arrive in. The first upload of each frame always goes ahead,
+// however big, so nothing waits forever.
+pub struct UploadBudget {
+    deferred: usize,
+    remaining: Option<u64>, // unlimited if None
+    spent: bool,
+}
+
+impl UploadBudget {
+    pub fn new(bytes_per_frame: Option<u64>) -> Self {
+        Self {
+            deferred: 0,
+            remaining: bytes_per_frame,
+            spent: false,
+        }
+    }
+
+    // Whether an upload of this size fits, spending it if so
+    pub fn try_spend(&mut self, bytes: u64) -> bool {
+        let remaining = match &mut self.remaining {
+            Some(remaining) => remaining,
+            None => return true,
+        };
+        if self.spent && bytes > *remaining {
+            self.deferred += 1;
+            return false;
+        }
+        *remaining = remaining.saturating_sub(bytes);
+        self.spent = true;
+        true
+    }
+
+    // How many uploads were turned away
+    pub fn deferred(&self) -> usize {
+        self.deferred
+    }
+}
+
+pub unsafe fn create_buffer(
+    shared_stem: &SharedStem,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    memory_flags: vk::MemoryPropertyFlags,
+) -> Result<Guarded<(Buffer, &ash::Device)>, UploadError> {
+    let select_memory_type = |memory_requirements: vk::MemoryRequirements| {
+        shared_stem
+            .select_memory_type(memory_requirements, memory_flags)
+            .ok_or(UploadError::NoAcceptableMemoryType(
+                memory_requirements,
+                memory_flags,
+            ))
+    };
+
+    let buffer_create_info = vk::BufferCreateInfo::builder()
+        .size(size)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+    Buffer::new(
+        shared_stem.device(),
+        &buffer_create_info,
+        select_memory_type,
+    )?
+}
+
+pub unsafe fn create_staging_buffer<'a>(
+    shared_stem: &'a SharedStem,
+    data: &[&[u8]],
+) -> Result<Guarded<(Buffer, &'a ash::Device)>, UploadError> {
+    let size = data.iter().map(|data| data.len()).sum::<usize>();
+    let staging_buffer = create_buffer(
+        shared_stem,
+        size as _,
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+    )?;
+    shared_stem.set_name(staging_buffer.buffer, "staging")?;
+
+    let mut offset = 0;
+    for data in data {
+        staging_buffer.write(shared_stem.device(), offset, data)?;
+        offset += data.len() as vk::DeviceSize;
+    }
+
+    Ok(staging_buffer)
+}
+
+// Expects a 4-byte-per-texel color image that was last written as a color attachment and left in
+// TRANSFER_SRC_OPTIMAL
+pub unsafe fn read_image(shared_stem: &SharedStem, image: &Image) -> Result<Vec<u8>, UploadError> {
+    let device = shared_stem.device();
+    let resolution = image.resolution;
+    let size = 4 * resolution.width as vk::DeviceSize * resolution.height as vk::DeviceSize;
+    let readback_buffer = create_buffer(
+        shared_stem,
+        size,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+    )?;
+    shared_stem.set_name(readback_buffer.buffer, "readback")?;
+
+    shared_stem.submit_one_time_commands(|command_buffer| {
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let image_memory_barriers = [vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image.image)
+            .subresource_range(subresource_range)
+            .build()];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        let region = vk::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image_offset: Default::default(),
+            image_extent: resolution,
+        };
+        device.cmd_copy_image_to_buffer(
+            command_buffer,
+            image.image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            readback_buffer.buffer,
+            &[region],
+        );
+
+        let buffer_memory_barriers = [vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(readback_buffer.buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build()];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST,
+            vk::DependencyFlags::empty(),
+            &[],
+            &buffer_memory_barriers,
+            &[],
+        );
+    })?;
+
+    Ok(readback_buffer.read(device, 0, size)?)
+}
+
+// Copies a sampled color image into one from elsewhere, like a headset's swapchain, which is
+// overwritten entirely and left in dst_layout. Formats may differ, but extents must match.
+#[cfg(feature = "openxr")]
+pub unsafe fn blit_image(
+    shared_stem: &SharedStem,
+    src: vk::Image,
+    dst: vk::Image,
+    dst_layout: vk::ImageLayout,
+    extent: vk::Extent2D,
+) -> Result<(), UploadError> {
+    let device = shared_stem.device();
+    shared_stem.submit_one_time_commands(|command_buffer| {
+        let image_memory_barriers = [
+            crate::util::image_barrier(
+                src,
+                1,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                vk::AccessFlags::TRANSFER_READ,
+            ),
+            crate::util::image_barrier(
+                dst,
+                1,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::TRANSFER_WRITE,
+            ),
+        ];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let corner = vk::Offset3D {
+            x: extent.width as _,
+            y: extent.height as _,
+            z: 1,
+        };
+        let region = vk::ImageBlit {
+            src_subresource: subresource,
+            src_offsets: [Default::default(), corner],
+            dst_subresource: subresource,
+            dst_offsets: [Default::default(), corner],
+        };
+        device.cmd_blit_image(
+            command_buffer,
+            src,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            dst,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[region],
+            vk::Filter::NEAREST,
+        );
+
+        let image_memory_barriers = [
+            crate::util::image_barrier(
+                src,
+                1,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::SHADER_READ,
+            ),
+            crate::util::image_barrier(
+                dst,
+                1,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                dst_layout,
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::AccessFlags::empty(),
+            ),
+        ];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+    })?;
+    Ok(())
+}
@@ -0,0 +1,500 @@
+use std::ffi::CStr;
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+
+use crate::{
+    compute::ComputePass,
+    guard::{GuardableResource, Guarded},
+    shaders::include_shader,
+    shared::{SharedFrond, SharedStem},
+    sync,
+    util::{self, Descriptor},
+};
+
+// Renders the main view at a fraction of the output resolution, then upscales its tonemapped
+// result with FidelityFX Super Resolution 1.0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Upscaling {
+    pub render_scale: f32, // of the output's width and height, up to 1
+    pub sharpening: f32,   // 0 for none, up to 1 for the most
+}
+
+impl Default for Upscaling {
+    // FSR's "Quality" mode
+    fn default() -> Self {
+        Self {
+            render_scale: 1.0 / 1.5,
+            sharpening: 0.8,
+        }
+    }
+}
+
+const WORKGROUP_SIZE: u32 = 8;
+
+#[derive(AsStd140)]
+struct RcasBuffer {
+    pub sharpening: f32,
+}
+
+impl RcasBuffer {
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: Self::std140_size_static() as _,
+        }
+    }
+}
+
+// Upsampling is a compute pass into the upscaled image. Sharpening reads that into the output,
+// which swapchains rarely allow as a storage image.
+pub struct UpscalingStem {
+    easu_descriptor_set_layout: vk::DescriptorSetLayout,
+    easu_pass: ComputePass,
+    frag_shader_module: vk::ShaderModule,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    rcas_descriptor_set_layout: vk::DescriptorSetLayout,
+    render_pass: vk::RenderPass,
+    sampler: vk::Sampler,
+    shared_stem: Arc<SharedStem>,
+}
+
+impl UpscalingStem {
+    pub fn new(shared_stem: Arc<SharedStem>) -> VkResult<Self> {
+        unsafe {
+            let device = shared_stem.device();
+
+            // Tonemapped input, then the upscaled image
+            let easu_descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::COMPUTE,
+                    ),
+                    (
+                        vk::DescriptorType::STORAGE_IMAGE,
+                        vk::ShaderStageFlags::COMPUTE,
+                    ),
+                ],
+            )?;
+            shared_stem.set_name(*easu_descriptor_set_layout, "easu")?;
+
+            let easu_pass = ComputePass::new(
+                shared_stem.clone(),
+                "easu",
+                &include_shader!("shaders/easu.comp"),
+                &[*easu_descriptor_set_layout],
+                0,
+            )?;
+
+            let rcas_descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[(
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    vk::ShaderStageFlags::FRAGMENT,
+                )],
+            )?;
+            shared_stem.set_name(*rcas_descriptor_set_layout, "rcas")?;
+
+            let pipeline_layout = util::create_pipeline_layout(
+                device,
+                &[*rcas_descriptor_set_layout],
+                &[RcasBuffer::push_constant_range()],
+            )?;
+            shared_stem.set_name(*pipeline_layout, "rcas")?;
+
+            // Both passes fetch texels directly, so this never filters
+            let sampler = Self::create_sampler(device)?;
+            shared_stem.set_name(*sampler, "upscaling")?;
+
+            let frag_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/rcas.frag"))?;
+            shared_stem.set_name(*frag_shader_module, "rcas frag")?;
+
+            let render_pass = Self::create_render_pass(
+                device,
+                shared_stem.surface_format().format,
+                shared_stem.output_layout(),
+            )?;
+            shared_stem.set_name(*render_pass, "rcas")?;
+
+            let pipeline = Self::create_pipeline(
+                device,
+                shared_stem.fullscreen_vert_shader_module(),
+                *frag_shader_module,
+                *pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*pipeline, "rcas")?;
+
+            Ok(Self {
+                easu_descriptor_set_layout: easu_descriptor_set_layout.take(),
+                easu_pass,
+                frag_shader_module: frag_shader_module.take(),
+                pipeline: pipeline.take(),
+                pipeline_layout: pipeline_layout.take(),
+                rcas_descriptor_set_layout: rcas_descriptor_set_layout.take(),
+                render_pass: render_pass.take(),
+                sampler: sampler.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    unsafe fn create_sampler(
+        device: &ash::Device,
+    ) -> VkResult<Guarded<(vk::Sampler, &ash::Device)>> {
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::NEAREST)
+            .min_filter(vk::Filter::NEAREST)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .compare_enable(false)
+            .min_lod(0.0)
+            .max_lod(0.0)
+            .unnormalized_coordinates(false);
+        Ok(device
+            .create_sampler(&sampler_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_render_pass(
+        device: &ash::Device,
+        output_format: vk::Format,
+        output_layout: vk::ImageLayout,
+    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
+        let attachments = [vk::AttachmentDescription::builder()
+            .format(output_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(output_layout)
+            .build()];
+
+        let color_attachments = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_attachments)
+            .build()];
+
+        // As with tonemapping, this orders the swapchain image's layout transition after the wait
+        // for its acquisition
+        let dependencies = [sync::dependency_before(
+            sync::COLOR_ATTACHMENT_WRITE,
+            sync::COLOR_ATTACHMENT_WRITE,
+        )];
+
+        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+        Ok(device
+            .create_render_pass(&render_pass_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_pipeline(
+        device: &ash::Device,
+        vert_shader_module: vk::ShaderModule,
+        frag_shader_module: vk::ShaderModule,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        let vertex_input_state = Default::default();
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            color_write_mask: vk::ColorComponentFlags::all(),
+            ..Default::default()
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+}
+
+impl Drop for UpscalingStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_shader_module(self.frag_shader_module, None);
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_descriptor_set_layout(self.rcas_descriptor_set_layout, None);
+            device.destroy_descriptor_set_layout(self.easu_descriptor_set_layout, None);
+        }
+    }
+}
+
+// Only built for shared fronds with a render scale
+pub struct UpscalingFrond {
+    easu_descriptor_set: vk::DescriptorSet,
+    framebuffers: Vec<vk::Framebuffer>, // indexed by swapchain image
+    rcas_descriptor_set: vk::DescriptorSet,
+    shared_frond: Arc<SharedFrond>,
+    upscaling_stem: Arc<UpscalingStem>,
+}
+
+impl UpscalingFrond {
+    pub fn new(
+        upscaling_stem: Arc<UpscalingStem>,
+        shared_frond: Arc<SharedFrond>,
+    ) -> VkResult<Self> {
+        let shared_stem = &upscaling_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        let upscale_input = shared_frond
+            .upscale_input()
+            .expect("Upscaling needs a render scale");
+        let upscaled = shared_frond.upscaled().unwrap();
+        unsafe {
+            let device = shared_frond.device();
+
+            let descriptor_allocator = shared_frond.descriptor_allocator();
+            let easu_descriptor_set =
+                descriptor_allocator.allocate(device, upscaling_stem.easu_descriptor_set_layout)?;
+            shared_stem.set_name(*easu_descriptor_set, "easu")?;
+            util::write_descriptor_set(
+                device,
+                *easu_descriptor_set,
+                &[
+                    (
+                        0,
+                        Descriptor::CombinedImageSampler(
+                            upscale_input.view,
+                            upscaling_stem.sampler,
+                        ),
+                    ),
+                    (1, Descriptor::StorageImage(upscaled.view)),
+                ],
+            );
+
+            let rcas_descriptor_set =
+                descriptor_allocator.allocate(device, upscaling_stem.rcas_descriptor_set_layout)?;
+            shared_stem.set_name(*rcas_descriptor_set, "rcas")?;
+            util::write_descriptor_set(
+                device,
+                *rcas_descriptor_set,
+                &[(
+                    0,
+                    Descriptor::CombinedImageSampler(upscaled.view, upscaling_stem.sampler),
+                )],
+            );
+
+            let mut framebuffers = Vec::<vk::Framebuffer>::new().guard_with(device);
+            for &image_view in shared_frond.output_views()? {
+                let framebuffer = util::create_framebuffer(
+                    device,
+                    upscaling_stem.render_pass,
+                    &[image_view],
+                    shared_frond.output_resolution(),
+                )?;
+                shared_stem.set_name(*framebuffer, "rcas")?;
+                framebuffers.push(framebuffer.take());
+            }
+
+            Ok(Self {
+                easu_descriptor_set: easu_descriptor_set.take(),
+                framebuffers: framebuffers.take(),
+                rcas_descriptor_set: rcas_descriptor_set.take(),
+                shared_frond,
+                upscaling_stem,
+            })
+        }
+    }
+
+    // Tonemapping must have left its output in SHADER_READ_ONLY_OPTIMAL
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        image_index: u32,
+        sharpening: f32,
+    ) {
+        let device = self.shared_frond.device();
+        let upscaling_stem = &self.upscaling_stem;
+        let upscaled = self.shared_frond.upscaled().unwrap();
+        let resolution = self.shared_frond.output_resolution();
+
+        // Last frame's sharpening may still be reading the upscaled image
+        let image_memory_barriers = [util::image_barrier(
+            upscaled.image,
+            1,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::GENERAL,
+            vk::AccessFlags::empty(),
+            sync::COMPUTE_STORAGE_WRITE.access,
+        )];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            sync::FRAGMENT_SAMPLED.stage,
+            sync::COMPUTE_STORAGE_WRITE.stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        upscaling_stem.easu_pass.dispatch(
+            command_buffer,
+            &[self.easu_descriptor_set],
+            &[],
+            [
+                resolution.width.div_ceil(WORKGROUP_SIZE),
+                resolution.height.div_ceil(WORKGROUP_SIZE),
+                1,
+            ],
+        );
+
+        let image_memory_barriers = [util::image_barrier(
+            upscaled.image,
+            1,
+            vk::ImageLayout::GENERAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            sync::COMPUTE_STORAGE_WRITE.access,
+            sync::FRAGMENT_SAMPLED.access,
+        )];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            sync::COMPUTE_STORAGE_WRITE.stage,
+            sync::FRAGMENT_SAMPLED.stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        let render_area = vk::Rect2D {
+            offset: Default::default(),
+            extent: resolution,
+        };
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(upscaling_stem.render_pass)
+            .framebuffer(self.framebuffers[image_index as usize])
+            .render_area(render_area);
+        device.cmd_begin_render_pass(
+            command_buffer,
+            &render_pass_begin_info,
+            vk::SubpassContents::INLINE,
+        );
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            upscaling_stem.pipeline,
+        );
+        util::set_viewport_and_scissor(device, command_buffer, resolution);
+
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            upscaling_stem.pipeline_layout,
+            0,
+            &[self.rcas_descriptor_set],
+            &[],
+        );
+
+        let rcas_buffer = RcasBuffer {
+            sharpening: sharpening.clamp(0.0, 1.0),
+        };
+        device.cmd_push_constants(
+            command_buffer,
+            upscaling_stem.pipeline_layout,
+            vk::ShaderStageFlags::FRAGMENT,
+            0,
+            rcas_buffer.as_std140().as_bytes(),
+        );
+
+        device.cmd_draw(
+            command_buffer,
+            3, // vertices
+            1, // instances
+            0, // first vertex
+            0, // first instance
+        );
+        self.shared_frond.count_draw(1);
+
+        device.cmd_end_render_pass(command_buffer);
+    }
+}
+
+impl Drop for UpscalingFrond {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_frond.device();
+            let _ = device.device_wait_idle();
+
+            for &framebuffer in self.framebuffers.iter() {
+                device.destroy_framebuffer(framebuffer, None);
+            }
+            self.shared_frond.descriptor_allocator().free(
+                device,
+                &[self.easu_descriptor_set, self.rcas_descriptor_set],
+            );
+        }
+    }
+}
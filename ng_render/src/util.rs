@@ -1,3 +1,5 @@
+use std::ffi::CStr;
+
 use ash::{prelude::VkResult, version::DeviceV1_0, vk};
 use nalgebra as na;
 
@@ -16,6 +18,127 @@ pub unsafe fn create_descriptor_pool<'a>(
         .guard_with(device))
 }
 
+// Bindings are numbered in order, each a single descriptor
+pub unsafe fn create_descriptor_set_layout<'a>(
+    device: &'a ash::Device,
+    bindings: &[(vk::DescriptorType, vk::ShaderStageFlags)],
+) -> VkResult<Guarded<(vk::DescriptorSetLayout, &'a ash::Device)>> {
+    let bindings: Vec<_> = bindings
+        .iter()
+        .enumerate()
+        .map(|(binding, &(descriptor_type, stage_flags))| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding as _)
+                .descriptor_type(descriptor_type)
+                .descriptor_count(1)
+                .stage_flags(stage_flags)
+                .build()
+        })
+        .collect();
+    let descriptor_set_layout_create_info =
+        vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+    Ok(device
+        .create_descriptor_set_layout(&descriptor_set_layout_create_info, None)?
+        .guard_with(device))
+}
+
+// Freed along with its pool
+pub unsafe fn allocate_descriptor_set(
+    device: &ash::Device,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+) -> VkResult<vk::DescriptorSet> {
+    let set_layouts = [descriptor_set_layout];
+    let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(descriptor_pool)
+        .set_layouts(&set_layouts);
+    Ok(device.allocate_descriptor_sets(&allocate_info)?[0])
+}
+
+// A resource to bind to a descriptor. Images must be in the layout their kind of descriptor implies.
+#[derive(Clone, Copy, Debug)]
+pub enum Descriptor {
+    CombinedImageSampler(vk::ImageView, vk::Sampler), // SHADER_READ_ONLY_OPTIMAL
+    InputAttachment(vk::ImageView),                   // SHADER_READ_ONLY_OPTIMAL
+    StorageBuffer(vk::Buffer),
+    StorageImage(vk::ImageView), // GENERAL
+    UniformBuffer(vk::Buffer),
+}
+
+// Points each given binding at its resource; bindings left out are untouched
+pub unsafe fn write_descriptor_set(
+    device: &ash::Device,
+    descriptor_set: vk::DescriptorSet,
+    descriptors: &[(u32, Descriptor)],
+) {
+    for &(binding, descriptor) in descriptors {
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(binding)
+            .dst_array_element(0);
+        let image_info = |image_view, sampler, image_layout| {
+            [vk::DescriptorImageInfo {
+                sampler,
+                image_view,
+                image_layout,
+            }]
+        };
+        let buffer_info = |buffer| {
+            [vk::DescriptorBufferInfo {
+                buffer,
+                offset: 0,
+                range: vk::WHOLE_SIZE,
+            }]
+        };
+        match descriptor {
+            Descriptor::CombinedImageSampler(image_view, sampler) => {
+                let image_info = image_info(
+                    image_view,
+                    sampler,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                );
+                let write = write
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&image_info);
+                device.update_descriptor_sets(&[*write], &[]);
+            }
+            Descriptor::InputAttachment(image_view) => {
+                let image_info = image_info(
+                    image_view,
+                    vk::Sampler::null(),
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                );
+                let write = write
+                    .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                    .image_info(&image_info);
+                device.update_descriptor_sets(&[*write], &[]);
+            }
+            Descriptor::StorageBuffer(buffer) => {
+                let buffer_info = buffer_info(buffer);
+                let write = write
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(&buffer_info);
+                device.update_descriptor_sets(&[*write], &[]);
+            }
+            Descriptor::StorageImage(image_view) => {
+                let image_info =
+                    image_info(image_view, vk::Sampler::null(), vk::ImageLayout::GENERAL);
+                let write = write
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&image_info);
+                device.update_descriptor_sets(&[*write], &[]);
+            }
+            Descriptor::UniformBuffer(buffer) => {
+                let buffer_info = buffer_info(buffer);
+                let write = write
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .buffer_info(&buffer_info);
+                device.update_descriptor_sets(&[*write], &[]);
+            }
+        }
+    }
+}
+
 pub unsafe fn create_framebuffer<'a>(
     device: &'a ash::Device,
     render_pass: vk::RenderPass,
@@ -24,7 +147,7 @@ pub unsafe fn create_framebuffer<'a>(
 ) -> VkResult<Guarded<(vk::Framebuffer, &'a ash::Device)>> {
     let framebuffer_create_info = vk::FramebufferCreateInfo::builder()
         .render_pass(render_pass)
-        .attachments(&attachments)
+        .attachments(attachments)
         .width(resolution.width)
         .height(resolution.height)
         .layers(1);
@@ -46,6 +169,70 @@ pub unsafe fn create_pipeline_layout<'a>(
         .guard_with(device))
 }
 
+pub unsafe fn create_compute_pipeline<'a>(
+    device: &'a ash::Device,
+    shader_module: vk::ShaderModule,
+    pipeline_layout: vk::PipelineLayout,
+    specialization: Option<&Specialization>,
+) -> VkResult<Guarded<(vk::Pipeline, &'a ash::Device)>> {
+    let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+    let specialization_info = specialization.map(Specialization::info);
+    let mut stage = vk::PipelineShaderStageCreateInfo::builder()
+        .module(shader_module)
+        .name(entry_point)
+        .stage(vk::ShaderStageFlags::COMPUTE);
+    if let Some(specialization_info) = &specialization_info {
+        stage = stage.specialization_info(specialization_info);
+    }
+
+    let compute_pipeline_create_infos = [vk::ComputePipelineCreateInfo::builder()
+        .stage(*stage)
+        .layout(pipeline_layout)
+        .build()];
+
+    let mut pipelines = device
+        .create_compute_pipelines(
+            vk::PipelineCache::null(),
+            &compute_pipeline_create_infos,
+            None,
+        )
+        .map_err(|(_, err)| err)?;
+
+    Ok(pipelines.pop().unwrap().guard_with(device))
+}
+
+// Specialization constants for one shader stage, lets pipelines share a shader while compiling
+// out branches. Each constant is a u32 (or bool), with constant_ids counting up from 0.
+pub struct Specialization {
+    constants: Vec<u32>,
+    map_entries: Vec<vk::SpecializationMapEntry>,
+}
+
+impl Specialization {
+    pub fn new(constants: &[u32]) -> Self {
+        let size = std::mem::size_of::<u32>();
+        let map_entries = (0..constants.len())
+            .map(|index| vk::SpecializationMapEntry {
+                constant_id: index as _,
+                offset: (index * size) as _,
+                size,
+            })
+            .collect();
+        Self {
+            constants: constants.to_vec(),
+            map_entries,
+        }
+    }
+
+    // Borrows from self, which must outlive pipeline creation
+    pub fn info(&self) -> vk::SpecializationInfo {
+        vk::SpecializationInfo::builder()
+            .map_entries(&self.map_entries)
+            .data(as_bytes(&self.constants))
+            .build()
+    }
+}
+
 pub unsafe fn create_shader_module<'a>(
     device: &'a ash::Device,
     spirv: &[u32],
@@ -55,25 +242,83 @@ pub unsafe fn create_shader_module<'a>(
     Ok(shader_module.guard_with(device))
 }
 
-// Combination of coordinate swizzle and infinite negative z perspective matrix
-// worldspace +x, +y, +z maps to cameraspace +z, -x, -y
-// worldspace x of near_z..infinity maps to camera space 1..0
-pub fn perspective_matrix(
-    near_z: f32,
-    diagonal_fov: f32,
+// Makes writes to the whole buffer available to later accesses, on the same queue
+pub fn buffer_barrier(
+    buffer: vk::Buffer,
+    src_access_mask: vk::AccessFlags,
+    dst_access_mask: vk::AccessFlags,
+) -> vk::BufferMemoryBarrier {
+    vk::BufferMemoryBarrier::builder()
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .buffer(buffer)
+        .size(vk::WHOLE_SIZE)
+        .build()
+}
+
+// Transitions the first mip_levels of every layer of a color image, on the same queue
+pub fn image_barrier(
+    image: vk::Image,
+    mip_levels: u32,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+    src_access_mask: vk::AccessFlags,
+    dst_access_mask: vk::AccessFlags,
+) -> vk::ImageMemoryBarrier {
+    vk::ImageMemoryBarrier::builder()
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: mip_levels,
+            base_array_layer: 0,
+            layer_count: vk::REMAINING_ARRAY_LAYERS,
+        })
+        .build()
+}
+
+// Pipelines take viewport and scissor as dynamic state so they survive window resizes
+pub unsafe fn set_viewport_and_scissor(
+    device: &ash::Device,
+    command_buffer: vk::CommandBuffer,
     resolution: vk::Extent2D,
-) -> na::Matrix4<f32> {
-    let aspect = resolution.height as f32 / resolution.width as f32;
-    let cotan_x = (aspect * aspect + 1.0).sqrt() / (0.5 * diagonal_fov).tan();
-    let cotan_y = cotan_x / aspect;
+) {
+    let viewports = [vk::Viewport {
+        x: 0.0,
+        y: 0.0,
+        width: resolution.width as _,
+        height: resolution.height as _,
+        min_depth: 0.0,
+        max_depth: 1.0,
+    }];
+    let scissors = [vk::Rect2D {
+        offset: vk::Offset2D { x: 0, y: 0 },
+        extent: resolution,
+    }];
+    device.cmd_set_viewport(command_buffer, 0, &viewports);
+    device.cmd_set_scissor(command_buffer, 0, &scissors);
+}
 
+// Planes as (normal, offset), with points inside the frustum on the side the normals face. Follows
+// Vulkan's clip volume, so reverse-Z puts the near plane last and an infinite far plane second last.
+pub fn frustum_planes(view: &na::Matrix4<f32>) -> [na::Vector4<f32>; 6] {
+    let row = |i| view.row(i).transpose();
     [
-        [0.0, 0.0, 0.0, 1.0],
-        [-cotan_x, 0.0, 0.0, 0.0],
-        [0.0, -cotan_y, 0.0, 0.0],
-        [0.0, 0.0, near_z, 0.0],
+        row(3) + row(0), // -w <= x
+        row(3) - row(0), // x <= w
+        row(3) + row(1), // -w <= y
+        row(3) - row(1), // y <= w
+        row(2),          // 0 <= z
+        row(3) - row(2), // z <= w
     ]
-    .into()
 }
 
 pub fn select_memory_type(
@@ -90,3 +335,16 @@ pub fn select_memory_type(
         })
         .map(|(index, _)| index as _)
 }
+
+// Plain-old-data that can be read as bytes. Implementing it promises the type has no padding,
+// which would be uninitialized, and holds no pointers or references.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u32 {}
+unsafe impl Pod for f32 {}
+unsafe impl Pod for mint::ColumnMatrix4<f32> {} // four columns of four f32s
+
+// Reinterprets a slice of plain-old-data as bytes, e.g. for uploading to a buffer
+pub fn as_bytes<T: Pod>(data: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use ash::vk;
+
+// Each message is logged this many times per window at most, after which repeats are only
+// counted, and the count logged once the next window starts
+const REPEATS_LOGGED: u32 = 3;
+const REPEAT_WINDOW: Duration = Duration::from_secs(1);
+
+// Logged under ng_render::validation::general and so on, so each can be filtered on its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ValidationMessageType {
+    General, // mostly the loader and layers saying what they're up to
+    Validation,
+    Performance,
+}
+
+impl ValidationMessageType {
+    // Messages can have more than one type, in which case the most specific one wins
+    fn of(message_types: vk::DebugUtilsMessageTypeFlagsEXT) -> Self {
+        if message_types.contains(vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION) {
+            Self::Validation
+        } else if message_types.contains(vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE) {
+            Self::Performance
+        } else {
+            Self::General
+        }
+    }
+
+    pub fn target(self) -> &'static str {
+        match self {
+            Self::General => "ng_render::validation::general",
+            Self::Validation => "ng_render::validation::validation",
+            Self::Performance => "ng_render::validation::performance",
+        }
+    }
+}
+
+// What to do about messages at the error level, beyond logging them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationErrorPolicy {
+    Log,
+    Panic,   // once the draw they were reported during has finished, for tests to fail on
+    Collect, // until taken with Renderer::take_validation_errors
+}
+
+impl Default for ValidationErrorPolicy {
+    fn default() -> Self {
+        Self::Log
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationMessage {
+    pub id: i32, // the same for every repeat of a message, or 0 if it has none
+    pub id_name: Option<String>, // like VUID-vkCmdDraw-None-02699
+    pub level: log::Level,
+    pub message: String,
+    pub message_type: ValidationMessageType,
+}
+
+// Sees every message, repeats included, as it's reported. It's called from inside whichever
+// Vulkan call reported it, so mustn't panic; anything that should fail has to be recorded and
+// checked afterwards.
+pub type ValidationCallback = Box<dyn Fn(&ValidationMessage) + Send + Sync>;
+
+struct Repeats {
+    logged: u32,
+    suppressed: u32, // since logged was last reset
+    window_start: Instant,
+}
+
+// What the debug messenger reports to, through its user data, so needs to stay put for as long as
+// the instance exists
+pub(crate) struct ValidationLog {
+    callback: Mutex<Option<ValidationCallback>>,
+    error_policy: ValidationErrorPolicy,
+    errors: Mutex<Vec<ValidationMessage>>, // since last taken, unless the policy's Log
+    repeats: Mutex<HashMap<(i32, String), Repeats>>, // by ID, and by text for those without
+}
+
+impl ValidationLog {
+    pub fn new(error_policy: ValidationErrorPolicy) -> Self {
+        Self {
+            callback: Mutex::new(None),
+            error_policy,
+            errors: Mutex::new(Vec::new()),
+            repeats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn error_policy(&self) -> ValidationErrorPolicy {
+        self.error_policy
+    }
+
+    pub fn take_errors(&self) -> Vec<ValidationMessage> {
+        std::mem::take(&mut *self.errors.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn set_callback(&self, callback: Option<ValidationCallback>) {
+        *self.callback.lock().unwrap_or_else(PoisonError::into_inner) = callback;
+    }
+
+    pub fn messenger_create_info(&self) -> vk::DebugUtilsMessengerCreateInfoEXTBuilder<'_> {
+        vk::DebugUtilsMessengerCreateInfoEXT::builder()
+            .message_severity(vk::DebugUtilsMessageSeverityFlagsEXT::all())
+            .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
+            .pfn_user_callback(Some(debug_utils_callback))
+            .user_data(self as *const Self as *mut c_void)
+    }
+
+    // Never panics, even with a poisoned lock, since it's called from inside Vulkan
+    fn report(&self, message: ValidationMessage) {
+        if let Some(callback) = &*self.callback.lock().unwrap_or_else(PoisonError::into_inner) {
+            callback(&message);
+        }
+        // Repeats included, so tests can count them
+        if message.level == log::Level::Error && self.error_policy != ValidationErrorPolicy::Log {
+            self.errors
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(message.clone());
+        }
+
+        let key = match (message.id, &message.id_name) {
+            (0, None) => (0, message.message.clone()),
+            (id, id_name) => (id, id_name.clone().unwrap_or_default()),
+        };
+        let now = Instant::now();
+        let mut repeats = self.repeats.lock().unwrap_or_else(PoisonError::into_inner);
+        let repeats = repeats.entry(key).or_insert(Repeats {
+            logged: 0,
+            suppressed: 0,
+            window_start: now,
+        });
+        if now - repeats.window_start >= REPEAT_WINDOW {
+            if repeats.suppressed > 0 {
+                log::log!(
+                    target: message.message_type.target(),
+                    message.level,
+                    "{} repeats of {} suppressed",
+                    repeats.suppressed,
+                    message.id_name.as_deref().unwrap_or(&message.message),
+                );
+            }
+            repeats.logged = 0;
+            repeats.suppressed = 0;
+            repeats.window_start = now;
+        }
+        if repeats.logged < REPEATS_LOGGED {
+            repeats.logged += 1;
+            log::log!(
+                target: message.message_type.target(),
+                message.level,
+                "{}",
+                message.message,
+            );
+        } else {
+            repeats.suppressed += 1;
+        }
+    }
+}
+
+unsafe extern "system" fn debug_utils_callback(
+    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_types: vk::DebugUtilsMessageTypeFlagsEXT,
+    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    p_user_data: *mut c_void,
+) -> u32 {
+    // Info is mostly the loader listing what it found, which isn't worth seeing by default
+    let level = match message_severity {
+        vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE => log::Level::Trace,
+        vk::DebugUtilsMessageSeverityFlagsEXT::INFO => log::Level::Debug,
+        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => log::Level::Warn,
+        _ => log::Level::Error,
+    };
+    let callback_data = &*p_callback_data;
+    let id_name = if callback_data.p_message_id_name.is_null() {
+        None
+    } else {
+        Some(
+            CStr::from_ptr(callback_data.p_message_id_name)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    };
+    let message = ValidationMessage {
+        id: callback_data.message_id_number,
+        id_name,
+        level,
+        message: CStr::from_ptr(callback_data.p_message)
+            .to_string_lossy()
+            .into_owned(),
+        message_type: ValidationMessageType::of(message_types),
+    };
+    (*(p_user_data as *const ValidationLog)).report(message);
+    vk::FALSE
+}
@@ -0,0 +1,719 @@
+use std::f32::consts::PI;
+use std::ffi::CStr;
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, version::DeviceV1_0, vk};
+use crevice::std140::{AsStd140, Std140};
+use nalgebra as na;
+
+use crate::{
+    buffer::Buffer,
+    environment::GpuEnvironment,
+    frame::FRAMES_IN_FLIGHT,
+    guard::{GuardableResource, Guarded},
+    light::{AmbientLight, DirectionalLight},
+    lighting::LightingStem,
+    shaders::include_shader,
+    shared::{SharedFrond, SharedStem},
+    sync,
+    texture::{GpuTexture, Texture},
+    upload::{self, UploadError},
+    util::{self, Descriptor},
+};
+
+// A level, rectangular body of water. It refracts and tints whatever's beneath it, reflects what's
+// onscreen or else the environment, and fades out where it's shallow.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Water {
+    pub min: mint::Point2<f32>, // of the surface's extent in x and y
+    pub max: mint::Point2<f32>,
+    pub height: f32,
+    pub color: mint::Vector3<f32>, // that deep water fades to
+    pub clarity: f32,              // distance through the water over which the bottom fades
+    pub shore_fade: f32,           // depth over which the surface fades in from the shore
+    pub wave_size: f32,            // world units per repeat of the ripples
+    pub wave_speed: f32,           // world units per second
+    pub wave_strength: f32,        // how far ripples tilt the surface, and bend refraction with it
+}
+
+impl Default for Water {
+    fn default() -> Self {
+        Self {
+            min: [-50.0, -50.0].into(),
+            max: [50.0, 50.0].into(),
+            height: 0.0,
+            color: [0.01, 0.04, 0.05].into(),
+            clarity: 2.0,
+            shore_fade: 0.2,
+            wave_size: 4.0,
+            wave_speed: 0.3,
+            wave_strength: 0.3,
+        }
+    }
+}
+
+#[derive(AsStd140)]
+struct WaterBuffer {
+    pub world_to_screen: mint::ColumnMatrix4<f32>,
+    pub screen_to_world: mint::ColumnMatrix4<f32>,
+    pub eye: mint::Vector4<f32>,
+    pub sunlight_direction: mint::Vector4<f32>,
+    pub sunlight_color: mint::Vector4<f32>,
+    pub ambient_color: mint::Vector4<f32>,
+    pub time: f32,
+}
+
+#[derive(AsStd140)]
+struct SurfaceBuffer {
+    pub bounds: mint::Vector4<f32>,
+    pub color: mint::Vector4<f32>,
+    pub height: f32,
+    pub shore_fade: f32,
+    pub wave_size: f32,
+    pub wave_speed: f32,
+    pub wave_strength: f32,
+}
+
+impl SurfaceBuffer {
+    fn new(water: &Water) -> Self {
+        Self {
+            bounds: [water.min.x, water.min.y, water.max.x, water.max.y].into(),
+            color: [water.color.x, water.color.y, water.color.z, water.clarity].into(),
+            height: water.height,
+            shore_fade: water.shore_fade,
+            wave_size: water.wave_size,
+            wave_speed: water.wave_speed,
+            wave_strength: water.wave_strength,
+        }
+    }
+
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: Self::std140_size_static() as _,
+        }
+    }
+}
+
+// Draws water over the lit scene, before blended meshes so they can still be seen above and in it.
+// Sets are this frame's water, then the environment.
+pub struct WaterStem {
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    frag_shader_module: vk::ShaderModule,
+    _lighting_stem: Arc<LightingStem>, // owns the environment set layout
+    linear_sampler: vk::Sampler,
+    nearest_sampler: vk::Sampler,
+    normal_map: GpuTexture,
+    normal_map_sampler: vk::Sampler,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    shared_stem: Arc<SharedStem>,
+    vert_shader_module: vk::ShaderModule,
+}
+
+impl WaterStem {
+    const NORMAL_MAP_SIZE: u32 = 128;
+
+    pub fn new(
+        shared_stem: Arc<SharedStem>,
+        lighting_stem: Arc<LightingStem>,
+    ) -> Result<Self, UploadError> {
+        let normal_map = GpuTexture::new(shared_stem.clone(), &Self::create_normal_map())?;
+        unsafe {
+            let device = shared_stem.device();
+
+            // The water buffer, light before the water, depth, then the ripples
+            let descriptor_set_layout = util::create_descriptor_set_layout(
+                device,
+                &[
+                    (
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                    (
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                    ),
+                ],
+            )?;
+            shared_stem.set_name(*descriptor_set_layout, "water")?;
+
+            let pipeline_layout = util::create_pipeline_layout(
+                device,
+                &[
+                    *descriptor_set_layout,
+                    lighting_stem.environment_descriptor_set_layout(),
+                ],
+                &[SurfaceBuffer::push_constant_range()],
+            )?;
+            shared_stem.set_name(*pipeline_layout, "water")?;
+
+            let linear_sampler = Self::create_sampler(
+                device,
+                vk::Filter::LINEAR,
+                vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            )?;
+            shared_stem.set_name(*linear_sampler, "water linear")?;
+            // Blending depths across edges would find surfaces that aren't there
+            let nearest_sampler = Self::create_sampler(
+                device,
+                vk::Filter::NEAREST,
+                vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            )?;
+            shared_stem.set_name(*nearest_sampler, "water nearest")?;
+            let normal_map_sampler =
+                Self::create_sampler(device, vk::Filter::LINEAR, vk::SamplerAddressMode::REPEAT)?;
+            shared_stem.set_name(*normal_map_sampler, "water normal map")?;
+
+            let vert_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/water.vert"))?;
+            shared_stem.set_name(*vert_shader_module, "water vert")?;
+            let frag_shader_module =
+                util::create_shader_module(device, &include_shader!("shaders/water.frag"))?;
+            shared_stem.set_name(*frag_shader_module, "water frag")?;
+
+            let render_pass = Self::create_render_pass(
+                device,
+                SharedFrond::LIGHT_FORMAT,
+                shared_stem.depth_stencil_format(),
+                shared_stem.depth_read_layout(),
+            )?;
+            shared_stem.set_name(*render_pass, "water")?;
+
+            let pipeline = Self::create_pipeline(
+                device,
+                *vert_shader_module,
+                *frag_shader_module,
+                *pipeline_layout,
+                *render_pass,
+            )?;
+            shared_stem.set_name(*pipeline, "water")?;
+
+            Ok(Self {
+                descriptor_set_layout: descriptor_set_layout.take(),
+                frag_shader_module: frag_shader_module.take(),
+                _lighting_stem: lighting_stem,
+                linear_sampler: linear_sampler.take(),
+                nearest_sampler: nearest_sampler.take(),
+                normal_map,
+                normal_map_sampler: normal_map_sampler.take(),
+                pipeline: pipeline.take(),
+                pipeline_layout: pipeline_layout.take(),
+                render_pass: render_pass.take(),
+                vert_shader_module: vert_shader_module.take(),
+                shared_stem,
+            })
+        }
+    }
+
+    // Ripples as a sum of waves with whole numbers of periods across the texture, so it tiles.
+    // Each wave's amplitude is given as its steepest slope.
+    fn create_normal_map() -> Texture {
+        let waves: [(f32, f32, f32, f32); 6] = [
+            (1.0, 2.0, 0.35, 0.0),
+            (3.0, -1.0, 0.25, 1.3),
+            (-2.0, 5.0, 0.15, 4.1),
+            (7.0, 3.0, 0.1, 2.2),
+            (-5.0, -9.0, 0.08, 5.7),
+            (11.0, -6.0, 0.05, 0.6),
+        ];
+        let size = Self::NORMAL_MAP_SIZE;
+        let pixels = (0..size)
+            .flat_map(|y| (0..size).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let (u, v) = (x as f32 / size as f32, y as f32 / size as f32);
+                let slope = waves.iter().fold(
+                    na::Vector2::zeros(),
+                    |slope, &(frequency_u, frequency_v, amplitude, phase)| {
+                        let frequency = na::Vector2::new(frequency_u, frequency_v);
+                        let angle = 2.0 * PI * (frequency_u * u + frequency_v * v) + phase;
+                        slope + amplitude * angle.cos() * frequency.normalize()
+                    },
+                );
+                let normal = na::Vector3::new(-slope.x, -slope.y, 1.0).normalize();
+                let encode = |value: f32| (127.5 * (value + 1.0)).round() as u8;
+                [encode(normal.x), encode(normal.y), encode(normal.z), 255]
+            })
+            .collect();
+        Texture::new_linear(size, size, pixels)
+    }
+
+    unsafe fn create_sampler(
+        device: &ash::Device,
+        filter: vk::Filter,
+        address_mode: vk::SamplerAddressMode,
+    ) -> VkResult<Guarded<(vk::Sampler, &ash::Device)>> {
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(filter)
+            .min_filter(filter)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(address_mode)
+            .address_mode_v(address_mode)
+            .address_mode_w(address_mode)
+            .compare_enable(false)
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE)
+            .unnormalized_coordinates(false);
+        Ok(device
+            .create_sampler(&sampler_create_info, None)?
+            .guard_with(device))
+    }
+
+    // Blends into the light attachment, testing against the opaque depth it also samples
+    unsafe fn create_render_pass(
+        device: &ash::Device,
+        light_format: vk::Format,
+        depth_format: vk::Format,
+        depth_layout: vk::ImageLayout,
+    ) -> VkResult<Guarded<(vk::RenderPass, &ash::Device)>> {
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(light_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(depth_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::LOAD)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(depth_layout)
+                .final_layout(depth_layout)
+                .build(),
+        ];
+
+        let color_attachments = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_stencil_attachment = vk::AttachmentReference {
+            attachment: 1,
+            layout: depth_layout,
+        };
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_attachments)
+            .depth_stencil_attachment(&depth_stencil_attachment)
+            .build()];
+
+        // Light's copy into refraction is ordered by draw's own barriers
+        let dependencies = [sync::dependency_before(
+            sync::DEPTH_ATTACHMENT_WRITE,
+            sync::DEPTH_TEST | sync::FRAGMENT_SAMPLED,
+        )];
+
+        let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+        Ok(device
+            .create_render_pass(&render_pass_create_info, None)?
+            .guard_with(device))
+    }
+
+    unsafe fn create_pipeline(
+        device: &ash::Device,
+        vert_shader_module: vk::ShaderModule,
+        frag_shader_module: vk::ShaderModule,
+        pipeline_layout: vk::PipelineLayout,
+        render_pass: vk::RenderPass,
+    ) -> VkResult<Guarded<(vk::Pipeline, &ash::Device)>> {
+        let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+        let vert_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(vert_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::VERTEX);
+        let frag_create_info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(frag_shader_module)
+            .name(entry_point)
+            .stage(vk::ShaderStageFlags::FRAGMENT);
+        let shader_stages = [*vert_create_info, *frag_create_info];
+
+        // Corners come from the surface's bounds instead
+        let vertex_input_state = Default::default();
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        // Seen from below too, when the camera is underwater
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        // Hidden behind opaque geometry, and leaving depth to it so passes after see through
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(false)
+            .depth_compare_op(vk::CompareOp::GREATER)
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(false);
+
+        // Fades in over what's already lit, by the shore fade in alpha, adding to its coverage
+        let attachments = [vk::PipelineColorBlendAttachmentState {
+            blend_enable: vk::TRUE,
+            src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+            dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor: vk::BlendFactor::ONE,
+            dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            alpha_blend_op: vk::BlendOp::ADD,
+            color_write_mask: vk::ColorComponentFlags::all(),
+        }];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .build()];
+
+        let mut pipelines = device
+            .create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &graphics_pipeline_create_infos,
+                None,
+            )
+            .map_err(|(_, err)| err)?;
+
+        Ok(pipelines.pop().unwrap().guard_with(device))
+    }
+}
+
+impl Drop for WaterStem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_stem.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_shader_module(self.frag_shader_module, None);
+            device.destroy_shader_module(self.vert_shader_module, None);
+            device.destroy_sampler(self.normal_map_sampler, None);
+            device.destroy_sampler(self.nearest_sampler, None);
+            device.destroy_sampler(self.linear_sampler, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+pub struct WaterFrond {
+    descriptor_sets: Vec<vk::DescriptorSet>, // per frame in flight
+    framebuffer: vk::Framebuffer,
+    shared_frond: Arc<SharedFrond>,
+    water_buffers: Vec<Buffer>, // per frame in flight
+    water_stem: Arc<WaterStem>,
+}
+
+impl WaterFrond {
+    pub fn new(
+        water_stem: Arc<WaterStem>,
+        shared_frond: Arc<SharedFrond>,
+    ) -> Result<Self, UploadError> {
+        let shared_stem = &water_stem.shared_stem;
+        shared_stem.assert_is(&shared_frond.stem());
+        unsafe {
+            let device = shared_frond.device();
+
+            let mut water_buffers = Vec::<Buffer>::new().guard_with(device);
+            for _ in 0..FRAMES_IN_FLIGHT {
+                let water_buffer = upload::create_buffer(
+                    shared_stem,
+                    WaterBuffer::std140_size_static() as _,
+                    vk::BufferUsageFlags::UNIFORM_BUFFER,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )?;
+                shared_stem.set_name(water_buffer.buffer, "water")?;
+                shared_stem.set_name(water_buffer.memory, "water")?;
+                water_buffers.push(water_buffer.take());
+            }
+
+            let descriptor_allocator = shared_frond.descriptor_allocator();
+            let mut descriptor_sets = Vec::<vk::DescriptorSet>::with_capacity(FRAMES_IN_FLIGHT)
+                .guard_with((descriptor_allocator, device));
+            for water_buffer in water_buffers.iter() {
+                let descriptor_set =
+                    descriptor_allocator.allocate(device, water_stem.descriptor_set_layout)?;
+                util::write_descriptor_set(
+                    device,
+                    *descriptor_set,
+                    &[
+                        (0, Descriptor::UniformBuffer(water_buffer.buffer)),
+                        (
+                            1,
+                            Descriptor::CombinedImageSampler(
+                                shared_frond.refraction().view,
+                                water_stem.linear_sampler,
+                            ),
+                        ),
+                        (
+                            3,
+                            Descriptor::CombinedImageSampler(
+                                water_stem.normal_map.view().get(shared_stem),
+                                water_stem.normal_map_sampler,
+                            ),
+                        ),
+                    ],
+                );
+                // Depth is still attached read-only by this pass and the ones around it
+                let depth_infos = [vk::DescriptorImageInfo {
+                    sampler: water_stem.nearest_sampler,
+                    image_view: shared_frond.depth_stencil().view,
+                    image_layout: shared_stem.depth_read_layout(),
+                }];
+                let depth_write = vk::WriteDescriptorSet::builder()
+                    .dst_set(*descriptor_set)
+                    .dst_binding(2)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&depth_infos);
+                device.update_descriptor_sets(&[*depth_write], &[]);
+                shared_stem.set_name(*descriptor_set, "water")?;
+                descriptor_sets.push(descriptor_set.take());
+            }
+
+            let framebuffer = util::create_framebuffer(
+                device,
+                water_stem.render_pass,
+                &[shared_frond.light().view, shared_frond.depth_stencil().view],
+                shared_frond.resolution(),
+            )?;
+            shared_stem.set_name(*framebuffer, "water")?;
+
+            Ok(Self {
+                descriptor_sets: descriptor_sets.take(),
+                framebuffer: framebuffer.take(),
+                water_buffers: water_buffers.take(),
+                shared_frond,
+                water_stem,
+            })
+        }
+    }
+
+    // Copies light aside for the water to sample, then draws every surface over it. Does nothing
+    // without any water, leaving light as it was.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        world_to_screen: &na::Matrix4<f32>,
+        eye: &na::Point3<f32>,
+        sun: &DirectionalLight,
+        ambient: &AmbientLight,
+        environment: &GpuEnvironment,
+        water: &[Water],
+        time: f32,
+    ) -> VkResult<()> {
+        if water.is_empty() {
+            return Ok(());
+        }
+        let device = self.shared_frond.device();
+        let water_stem = &self.water_stem;
+        let light = self.shared_frond.light();
+        let refraction = self.shared_frond.refraction();
+        let resolution = self.shared_frond.resolution();
+
+        let water_buffer = WaterBuffer {
+            world_to_screen: (*world_to_screen).into(),
+            screen_to_world: world_to_screen.try_inverse().unwrap().into(),
+            eye: eye.coords.push(1.0).into(),
+            sunlight_direction: sun.unit_direction().push(0.0).into(),
+            sunlight_color: sun.radiance().push(0.0).into(),
+            ambient_color: ambient.scale().push(0.0).into(),
+            time,
+        };
+        self.water_buffers[frame_index].write(device, 0, water_buffer.as_std140().as_bytes())?;
+
+        // Last frame's water may still be sampling refraction, which is overwritten regardless
+        let image_memory_barriers = [
+            util::image_barrier(
+                light.image,
+                1,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                sync::COLOR_ATTACHMENT_WRITE.access,
+                sync::TRANSFER_READ.access,
+            ),
+            util::image_barrier(
+                refraction.image,
+                1,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::AccessFlags::empty(),
+                sync::TRANSFER_WRITE.access,
+            ),
+        ];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            sync::COLOR_ATTACHMENT_WRITE.stage | sync::FRAGMENT_SAMPLED.stage,
+            sync::TRANSFER_READ.stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let regions = [vk::ImageCopy {
+            src_subresource: subresource,
+            src_offset: Default::default(),
+            dst_subresource: subresource,
+            dst_offset: Default::default(),
+            extent: vk::Extent3D {
+                width: resolution.width,
+                height: resolution.height,
+                depth: 1,
+            },
+        }];
+        device.cmd_copy_image(
+            command_buffer,
+            light.image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            refraction.image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &regions,
+        );
+
+        let image_memory_barriers = [
+            util::image_barrier(
+                light.image,
+                1,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                sync::TRANSFER_READ.access,
+                sync::COLOR_ATTACHMENT_BLEND.access,
+            ),
+            util::image_barrier(
+                refraction.image,
+                1,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                sync::TRANSFER_WRITE.access,
+                sync::FRAGMENT_SAMPLED.access,
+            ),
+        ];
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            sync::TRANSFER_WRITE.stage,
+            sync::COLOR_ATTACHMENT_BLEND.stage | sync::FRAGMENT_SAMPLED.stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_memory_barriers,
+        );
+
+        let render_area = vk::Rect2D {
+            offset: Default::default(),
+            extent: resolution,
+        };
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(water_stem.render_pass)
+            .framebuffer(self.framebuffer)
+            .render_area(render_area);
+        device.cmd_begin_render_pass(
+            command_buffer,
+            &render_pass_begin_info,
+            vk::SubpassContents::INLINE,
+        );
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            water_stem.pipeline,
+        );
+        util::set_viewport_and_scissor(device, command_buffer, resolution);
+
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            water_stem.pipeline_layout,
+            0,
+            &[
+                self.descriptor_sets[frame_index],
+                environment.descriptor_set(),
+            ],
+            &[],
+        );
+
+        for water in water {
+            let surface_buffer = SurfaceBuffer::new(water);
+            device.cmd_push_constants(
+                command_buffer,
+                water_stem.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                surface_buffer.as_std140().as_bytes(),
+            );
+            device.cmd_draw(
+                command_buffer,
+                6, // vertices
+                1, // instances
+                0, // first vertex
+                0, // first instance
+            );
+            self.shared_frond.count_draw(2);
+        }
+
+        device.cmd_end_render_pass(command_buffer);
+
+        Ok(())
+    }
+}
+
+impl Drop for WaterFrond {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.shared_frond.device();
+            let _ = device.device_wait_idle();
+
+            device.destroy_framebuffer(self.framebuffer, None);
+            self.shared_frond
+                .descriptor_allocator()
+                .free(device, &self.descriptor_sets);
+            for water_buffer in &mut self.water_buffers {
+                water_buffer.destroy_with(device);
+            }
+        }
+    }
+}
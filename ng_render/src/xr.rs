@@ -0,0 +1,387 @@
+use std::ffi::CString;
+use std::sync::Arc;
+
+use ash::{
+    version::InstanceV1_0,
+    vk::{self, Handle},
+};
+use nalgebra as na;
+use openxr as xr;
+use thiserror::Error;
+use winit::window::Window;
+
+use crate::{
+    camera::{Camera, Projection},
+    renderer::{Renderer, RendererError, RendererOptions},
+    shared::DeviceRequirements,
+    stereo::{Eye, StereoCamera, StereoTarget},
+};
+
+const VIEW_CONFIGURATION: xr::ViewConfigurationType = xr::ViewConfigurationType::PRIMARY_STEREO;
+
+// Closer than a flat screen's, since hands come right up to the eyes
+const NEAR_PLANE: f32 = 0.05;
+
+// In order of preference. Eyes are blitted from sRGB render targets, which decodes them, so UNORM
+// images end up linear, as runtimes take them to be.
+const SWAPCHAIN_FORMATS: [vk::Format; 4] = [
+    vk::Format::R8G8B8A8_SRGB,
+    vk::Format::B8G8R8A8_SRGB,
+    vk::Format::R8G8B8A8_UNORM,
+    vk::Format::B8G8R8A8_UNORM,
+];
+
+#[derive(Error, Debug)]
+pub enum XrError {
+    #[error("Couldn't load the OpenXR loader")]
+    LoadError(#[from] xr::LoadError),
+    #[error("OpenXR error occurred")]
+    XrError(#[from] xr::sys::Result),
+    #[error("The OpenXR runtime doesn't support Vulkan")]
+    VulkanUnsupported,
+    #[error("The OpenXR runtime offers no 8-bit RGBA swapchain format")]
+    NoAcceptableSwapchainFormat,
+    #[error("The OpenXR runtime offers no stereo views")]
+    NoStereoViews,
+    #[error("Renderer error occurred")]
+    RendererError(#[from] RendererError),
+}
+
+// An OpenXR instance and the headset it found. Renderers that draw to the headset must be created
+// through this, so they use the device and extensions the runtime asks for.
+pub struct XrContext {
+    instance: xr::Instance,
+    system: xr::SystemId,
+}
+
+impl XrContext {
+    pub fn new(application_name: &str) -> Result<Self, XrError> {
+        let entry = unsafe { xr::Entry::load()? };
+        if !entry.enumerate_extensions()?.khr_vulkan_enable {
+            return Err(XrError::VulkanUnsupported);
+        }
+        let mut extensions = xr::ExtensionSet::default();
+        extensions.khr_vulkan_enable = true;
+        let application_info = xr::ApplicationInfo {
+            application_name,
+            application_version: 0,
+            engine_name: "Neritigen",
+            engine_version: 0,
+        };
+        let instance = entry.create_instance(&application_info, &extensions, &[])?;
+        let system = instance.system(xr::FormFactor::HEAD_MOUNTED_DISPLAY)?;
+        Ok(Self { instance, system })
+    }
+
+    // The renderer still draws to the window as usual, which can mirror the headset
+    pub fn create_renderer(
+        &self,
+        window: Arc<Window>,
+        options: RendererOptions,
+    ) -> Result<Renderer, XrError> {
+        // Runtimes refuse to create sessions for anyone who hasn't asked
+        let requirements = self
+            .instance
+            .graphics_requirements::<xr::Vulkan>(self.system)?;
+        log::info!(
+            "OpenXR runtime supports Vulkan {} to {}",
+            requirements.min_api_version_supported,
+            requirements.max_api_version_supported
+        );
+
+        // Space separated
+        let names = |names: String| {
+            names
+                .split(' ')
+                .filter(|name| !name.is_empty())
+                .map(|name| CString::new(name).unwrap())
+                .collect()
+        };
+        let instance = self.instance.clone();
+        let system = self.system;
+        let device_requirements = DeviceRequirements {
+            instance_extensions: names(self.instance.vulkan_legacy_instance_extensions(system)?),
+            device_extensions: names(self.instance.vulkan_legacy_device_extensions(system)?),
+            physical_device: Some(Box::new(move |vk_instance: &ash::Instance| {
+                let vk_instance = vk_instance.handle().as_raw() as usize as _;
+                let physical_device =
+                    unsafe { instance.vulkan_graphics_device(system, vk_instance)? };
+                Ok(vk::PhysicalDevice::from_raw(physical_device as usize as _))
+            })),
+        };
+        Ok(Renderer::with_requirements(
+            window,
+            options,
+            device_requirements,
+        )?)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XrState {
+    Idle,    // not showing anything, so frames shouldn't be drawn
+    Running, // frames should be drawn, through begin_frame and end_frame
+    Exiting, // the session is over, and should be dropped
+}
+
+// What to draw for the headset's next frame, which must be handed back to end_frame
+pub struct XrFrame {
+    pub camera: Option<StereoCamera>, // None if the runtime isn't showing this frame
+    display_time: xr::Time,
+    views: Vec<xr::View>,
+}
+
+struct EyeSwapchain {
+    swapchain: xr::Swapchain<xr::Vulkan>,
+    images: Vec<vk::Image>,
+}
+
+// Draws to a headset by copying each eye's render target into the runtime's swapchain images.
+// The renderer it was created with must outlive it, and keep its device: if the device is lost,
+// the session needs recreating.
+pub struct XrSession {
+    blend_mode: xr::EnvironmentBlendMode,
+    event_storage: xr::EventDataBuffer,
+    frame_stream: xr::FrameStream<xr::Vulkan>,
+    frame_waiter: xr::FrameWaiter,
+    running: bool,
+    session: xr::Session<xr::Vulkan>,
+    space: xr::Space,
+    swapchains: [EyeSwapchain; 2],
+    target: StereoTarget,
+}
+
+impl XrSession {
+    pub fn new(context: &XrContext, renderer: &mut Renderer) -> Result<Self, XrError> {
+        let XrContext { instance, system } = context;
+        let system = *system;
+        let (vk_instance, physical_device, device, queue_family_index) =
+            renderer.vulkan_handles()?;
+        let (session, frame_waiter, frame_stream) = unsafe {
+            instance.create_session::<xr::Vulkan>(
+                system,
+                &xr::vulkan::SessionCreateInfo {
+                    instance: vk_instance.as_raw() as usize as _,
+                    physical_device: physical_device.as_raw() as usize as _,
+                    device: device.as_raw() as usize as _,
+                    queue_family_index,
+                    queue_index: 0,
+                },
+            )?
+        };
+
+        let blend_mode = instance
+            .enumerate_environment_blend_modes(system, VIEW_CONFIGURATION)?
+            .first()
+            .copied()
+            .unwrap_or(xr::EnvironmentBlendMode::OPAQUE);
+        // Standing-scale if the runtime has it, with the floor at z = 0
+        let space_type = if session
+            .enumerate_reference_spaces()?
+            .contains(&xr::ReferenceSpaceType::STAGE)
+        {
+            xr::ReferenceSpaceType::STAGE
+        } else {
+            xr::ReferenceSpaceType::LOCAL
+        };
+        let space = session.create_reference_space(space_type, xr::Posef::IDENTITY)?;
+
+        let views = instance.enumerate_view_configuration_views(system, VIEW_CONFIGURATION)?;
+        let view = views.first().ok_or(XrError::NoStereoViews)?;
+        let extent = vk::Extent2D {
+            width: view.recommended_image_rect_width,
+            height: view.recommended_image_rect_height,
+        };
+        let available_formats = session.enumerate_swapchain_formats()?;
+        let format = SWAPCHAIN_FORMATS
+            .iter()
+            .map(|format| format.as_raw() as xr::sys::platform::VkFormat)
+            .find(|format| available_formats.contains(format))
+            .ok_or(XrError::NoAcceptableSwapchainFormat)?;
+        let create_swapchain = || -> Result<EyeSwapchain, XrError> {
+            let swapchain = session.create_swapchain(&xr::SwapchainCreateInfo {
+                create_flags: xr::SwapchainCreateFlags::EMPTY,
+                usage_flags: xr::SwapchainUsageFlags::COLOR_ATTACHMENT
+                    | xr::SwapchainUsageFlags::TRANSFER_DST,
+                format,
+                sample_count: 1,
+                width: extent.width,
+                height: extent.height,
+                face_count: 1,
+                array_size: 1,
+                mip_count: 1,
+            })?;
+            let images = swapchain
+                .enumerate_images()?
+                .into_iter()
+                .map(vk::Image::from_raw)
+                .collect();
+            Ok(EyeSwapchain { swapchain, images })
+        };
+        let swapchains = [create_swapchain()?, create_swapchain()?];
+
+        Ok(Self {
+            blend_mode,
+            event_storage: xr::EventDataBuffer::new(),
+            frame_stream,
+            frame_waiter,
+            running: false,
+            session,
+            space,
+            swapchains,
+            target: StereoTarget::new(extent),
+        })
+    }
+
+    // Handles what the runtime has said since last time. Should be called every frame, before
+    // anything else.
+    pub fn poll_events(&mut self) -> Result<XrState, XrError> {
+        while let Some(event) = self
+            .session
+            .instance()
+            .poll_event(&mut self.event_storage)?
+        {
+            match event {
+                xr::Event::SessionStateChanged(event) => match event.state() {
+                    xr::SessionState::READY => {
+                        self.session.begin(VIEW_CONFIGURATION)?;
+                        self.running = true;
+                    }
+                    xr::SessionState::STOPPING => {
+                        self.session.end()?;
+                        self.running = false;
+                    }
+                    xr::SessionState::EXITING | xr::SessionState::LOSS_PENDING => {
+                        return Ok(XrState::Exiting)
+                    }
+                    _ => (),
+                },
+                xr::Event::InstanceLossPending(_) => return Ok(XrState::Exiting),
+                _ => (),
+            }
+        }
+        Ok(if self.running {
+            XrState::Running
+        } else {
+            XrState::Idle
+        })
+    }
+
+    pub fn target(&self) -> &StereoTarget {
+        &self.target
+    }
+
+    // Blocks until the runtime wants the next frame. The eyes' cameras are relative to origin,
+    // which is where the floor's center, or the headset's starting point, goes in the world.
+    pub fn begin_frame(&mut self, origin: mint::ColumnMatrix4<f32>) -> Result<XrFrame, XrError> {
+        let frame_state = self.frame_waiter.wait()?;
+        self.frame_stream.begin()?;
+        if !frame_state.should_render {
+            return Ok(XrFrame {
+                camera: None,
+                display_time: frame_state.predicted_display_time,
+                views: Vec::new(),
+            });
+        }
+
+        let (_, views) = self.session.locate_views(
+            VIEW_CONFIGURATION,
+            frame_state.predicted_display_time,
+            &self.space,
+        )?;
+        let origin = na::Matrix4::from(origin);
+        let camera = match views.as_slice() {
+            [left, right, ..] => Some(StereoCamera {
+                left: Self::eye_camera(&origin, left),
+                right: Self::eye_camera(&origin, right),
+            }),
+            _ => None,
+        };
+        Ok(XrFrame {
+            camera,
+            display_time: frame_state.predicted_display_time,
+            views,
+        })
+    }
+
+    // Hands the frame to the runtime. Call after the renderer's draw, which is what draws the
+    // eyes queued with draw_stereo.
+    pub fn end_frame(&mut self, renderer: &mut Renderer, frame: XrFrame) -> Result<(), XrError> {
+        if frame.camera.is_none() {
+            self.frame_stream
+                .end(frame.display_time, self.blend_mode, &[])?;
+            return Ok(());
+        }
+
+        for (eye, swapchain) in [Eye::Left, Eye::Right].iter().zip(&mut self.swapchains) {
+            let index = swapchain.swapchain.acquire_image()?;
+            swapchain.swapchain.wait_image(xr::Duration::INFINITE)?;
+            let copied = renderer.copy_target_to_image(
+                self.target.eye(*eye),
+                swapchain.images[index as usize],
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            );
+            // Released either way, since the runtime won't hand out another image until it is
+            swapchain.swapchain.release_image()?;
+            copied?;
+        }
+
+        let extent = self.target.extent();
+        let image_rect = xr::Rect2Di {
+            offset: xr::Offset2Di { x: 0, y: 0 },
+            extent: xr::Extent2Di {
+                width: extent.width as _,
+                height: extent.height as _,
+            },
+        };
+        let projection_views: Vec<_> = frame
+            .views
+            .iter()
+            .zip(&self.swapchains)
+            .map(|(view, swapchain)| {
+                xr::CompositionLayerProjectionView::new()
+                    .pose(view.pose)
+                    .fov(view.fov)
+                    .sub_image(
+                        xr::SwapchainSubImage::new()
+                            .swapchain(&swapchain.swapchain)
+                            .image_rect(image_rect),
+                    )
+            })
+            .collect();
+        let layer = xr::CompositionLayerProjection::new()
+            .space(&self.space)
+            .views(&projection_views);
+        self.frame_stream
+            .end(frame.display_time, self.blend_mode, &[&layer])?;
+        Ok(())
+    }
+
+    // OpenXR looks along -z with +y up, where cameras and the world have +x ahead and +z up
+    fn eye_camera(origin: &na::Matrix4<f32>, view: &xr::View) -> Camera {
+        #[rustfmt::skip]
+        let xr_to_world = na::Matrix4::new(
+            0.0, 0.0, -1.0, 0.0,
+            -1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let orientation: mint::Quaternion<f32> = view.pose.orientation.into();
+        let position: mint::Vector3<f32> = view.pose.position.into();
+        let pose = na::Isometry3::from_parts(
+            na::Vector3::from(position).into(),
+            na::UnitQuaternion::new_normalize(orientation.into()),
+        );
+        let transform = origin * xr_to_world * pose.to_homogeneous() * xr_to_world.transpose();
+        Camera {
+            transform: transform.into(),
+            projection: Projection::Asymmetric {
+                left: view.fov.angle_left,
+                right: view.fov.angle_right,
+                up: view.fov.angle_up,
+                down: view.fov.angle_down,
+                near: NEAR_PLANE,
+            },
+        }
+    }
+}
@@ -0,0 +1,61 @@
+// Presents from a queue family apart from the graphics one, which only some devices have. Skipped
+// without a display, Vulkan or such a family, since there's nothing to check then.
+
+#![cfg(target_os = "linux")]
+
+use std::sync::Arc;
+
+use nalgebra as na;
+use ng_render::{
+    Camera, PresentQueuePreference, Renderer, RendererError, RendererOptions, SharedStemError,
+    ValidationErrorPolicy,
+};
+use winit::{event_loop::EventLoop, platform::unix::EventLoopExtUnix, window::WindowBuilder};
+
+#[test]
+fn separate_present_queue() {
+    // Tests run off the main thread, which only X11 lets an event loop be made on
+    let event_loop = match EventLoop::<()>::new_x11_any_thread() {
+        Ok(event_loop) => event_loop,
+        Err(err) => {
+            eprintln!(
+                "Skipping separate_present_queue, since there's no display: {}",
+                err
+            );
+            return;
+        }
+    };
+    let window = WindowBuilder::new()
+        .with_visible(false)
+        .build(&event_loop)
+        .unwrap();
+    let options = RendererOptions {
+        present_queue: PresentQueuePreference::Separate,
+        validation_error_policy: ValidationErrorPolicy::Panic,
+        ..Default::default()
+    };
+    let mut renderer = match Renderer::new(Arc::new(window), options) {
+        Ok(renderer) => renderer,
+        Err(RendererError::CrownCreationError(err)) => {
+            eprintln!(
+                "Skipping separate_present_queue, since Vulkan's unavailable: {}",
+                err
+            );
+            return;
+        }
+        Err(err) => panic!("Couldn't create renderer: {}", err),
+    };
+
+    // The device is only picked on the first draw
+    let camera = Camera::new(na::Matrix4::identity().into());
+    match renderer.draw(&camera, &[]) {
+        Ok(_) => {}
+        Err(RendererError::StemCreationError(SharedStemError::NoSeparatePresentQueue)) => {
+            eprintln!("Skipping separate_present_queue, since no other queue family can present");
+            return;
+        }
+        Err(err) => panic!("Couldn't draw: {}", err),
+    }
+    let (graphics_family, present_family) = renderer.queue_families().unwrap();
+    assert_ne!(graphics_family, present_family);
+}
@@ -0,0 +1,302 @@
+// Renders canned scenes headlessly and compares them against the images in tests/reference, so
+// changes to lighting, shadows or tonemapping don't go unnoticed. A scene without a reference
+// image fails, unless NG_RENDER_BLESS is set, which saves what every scene rendered as its
+// reference instead, to be looked over and committed. Without Vulkan they're skipped, unless
+// NG_RENDER_REQUIRE_VULKAN is set, as it should be wherever there's a device to test on.
+//
+// The references still need rendering on a machine with Vulkan, by running these with
+// NG_RENDER_BLESS set and --ignored, then committing tests/reference and dropping the ignores.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use nalgebra as na;
+use ng_render::{
+    Camera, DirectionalLight, Light, Material, MaterialHandle, Mesh, MeshInstance, Renderer,
+    RendererError, RendererOptions, TonemappingOperator, ValidationErrorPolicy, Vertex,
+};
+
+const WIDTH: u32 = 256;
+const HEIGHT: u32 = 192;
+
+// Color differences (CIE76 ΔE) below this are too small to see. Pixels differing by more are
+// allowed to make up a small fraction of the image, since drivers round differently along edges.
+const JUST_NOTICEABLE: f32 = 3.0;
+const MAX_NOTICEABLE_FRACTION: f32 = 0.005;
+
+#[test]
+#[ignore = "no reference images have been blessed yet"]
+fn point_and_spot_lights() {
+    check("point_and_spot_lights", |renderer| {
+        renderer.set_sun(DirectionalLight {
+            intensity: 0.0,
+            ..Default::default()
+        });
+        renderer.set_lights(&[
+            Light::Point {
+                position: [0.5, -0.5, 0.5].into(),
+                color: [1.0, 0.5, 0.2].into(),
+                range: 2.0,
+            },
+            Light::Spot {
+                position: [-0.5, 1.0, 1.5].into(),
+                direction: [0.0, -0.5, -1.0].into(),
+                color: [0.2, 0.5, 1.0].into(),
+                range: 3.0,
+                angle: 0.3,
+            },
+        ]);
+    });
+}
+
+#[test]
+#[ignore = "no reference images have been blessed yet"]
+fn sun_shadows() {
+    check("sun_shadows", |renderer| {
+        renderer.set_sun(DirectionalLight {
+            direction: [1.0, 0.5, -1.0].into(),
+            ..Default::default()
+        });
+    });
+}
+
+#[test]
+#[ignore = "no reference images have been blessed yet"]
+fn tonemapping_operators() {
+    for &(name, operator) in &[
+        (
+            "tonemapping_linear",
+            TonemappingOperator::Linear { exposure: 2.0 },
+        ),
+        (
+            "tonemapping_reinhard",
+            TonemappingOperator::Reinhard { exposure: 2.0 },
+        ),
+        (
+            "tonemapping_aces",
+            TonemappingOperator::Aces { exposure: 2.0 },
+        ),
+    ] {
+        check(name, |renderer| renderer.set_tonemapping(operator));
+    }
+}
+
+// Draws a floor with a metal wall standing on it, lit however setup likes, once, from the same
+// place and at the same time every time
+fn check(name: &str, setup: impl FnOnce(&mut Renderer)) {
+    let options = RendererOptions {
+        validation_error_policy: ValidationErrorPolicy::Panic,
+        ..Default::default()
+    };
+    let mut renderer = match Renderer::new_headless(WIDTH, HEIGHT, options) {
+        Ok(renderer) => renderer,
+        Err(RendererError::CrownCreationError(err))
+            if std::env::var_os("NG_RENDER_REQUIRE_VULKAN").is_none() =>
+        {
+            eprintln!("Skipping {}, since Vulkan's unavailable: {}", name, err);
+            return;
+        }
+        Err(err) => panic!("Couldn't create renderer for {}: {}", name, err),
+    };
+    renderer.set_deterministic(Some(Duration::from_secs(1) / 60));
+    setup(&mut renderer);
+
+    let camera = Camera::new(
+        (na::Matrix4::new_translation(&na::Vector3::new(-3.0, 0.0, 1.2))
+            * na::Matrix4::from_euler_angles(0.0, 0.3, 0.0))
+        .into(),
+    );
+    renderer.draw(&camera, &scene()).unwrap();
+    let pixels = renderer.read_pixels().unwrap();
+
+    let reference_path = reference_dir().join(format!("{}.png", name));
+    if std::env::var_os("NG_RENDER_BLESS").is_some() {
+        write_png(&reference_path, &pixels).unwrap();
+        eprintln!(
+            "Saved {} as the reference for {}",
+            reference_path.display(),
+            name
+        );
+        return;
+    }
+    let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("reference_images");
+    let actual_path = output_dir.join(format!("{}.png", name));
+    if !reference_path.exists() {
+        write_png(&actual_path, &pixels).unwrap();
+        panic!(
+            "{} has no reference at {}; look over {} and set NG_RENDER_BLESS to save it",
+            name,
+            reference_path.display(),
+            actual_path.display(),
+        );
+    }
+    let reference = read_png(&reference_path).unwrap();
+
+    let differences: Vec<f32> = pixels
+        .chunks_exact(4)
+        .zip(reference.chunks_exact(4))
+        .map(|(pixel, reference)| delta_e(pixel, reference))
+        .collect();
+    let noticeable = differences
+        .iter()
+        .filter(|&&difference| difference > JUST_NOTICEABLE)
+        .count();
+    let fraction = noticeable as f32 / differences.len() as f32;
+    if fraction > MAX_NOTICEABLE_FRACTION {
+        // Noticeably different pixels in red, over the reference, dimmed
+        let diff: Vec<u8> = differences
+            .iter()
+            .zip(reference.chunks_exact(4))
+            .flat_map(|(&difference, reference)| {
+                if difference > JUST_NOTICEABLE {
+                    [255, 0, 0, 255]
+                } else {
+                    let gray =
+                        (reference[0] as u16 + reference[1] as u16 + reference[2] as u16) / 12;
+                    [gray as u8, gray as u8, gray as u8, 255]
+                }
+            })
+            .collect();
+        let diff_path = output_dir.join(format!("{}.diff.png", name));
+        write_png(&actual_path, &pixels).unwrap();
+        write_png(&diff_path, &diff).unwrap();
+        panic!(
+            "{} differs noticeably from its reference in {:.2}% of pixels; see {} and {}",
+            name,
+            100.0 * fraction,
+            actual_path.display(),
+            diff_path.display(),
+        );
+    }
+}
+
+fn scene() -> Vec<MeshInstance> {
+    let quad = |corners: [[f32; 3]; 4], normal: [f32; 3]| {
+        let tex_coords = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+        let mut vertices: Vec<_> = corners
+            .iter()
+            .zip(tex_coords.iter())
+            .map(|(&position, &tex_coord)| Vertex {
+                position: position.into(),
+                normal: normal.into(),
+                color: [1.0; 3].into(),
+                tex_coord: tex_coord.into(),
+                tangent: [0.0; 4].into(),
+            })
+            .collect();
+        let indices = vec![0, 1, 2, 0, 2, 3];
+        Vertex::generate_tangents(&mut vertices, &indices);
+        Arc::new(Mesh::new(vertices, indices))
+    };
+    let instance = |mesh, material| MeshInstance {
+        mesh,
+        material: Some(MaterialHandle::new(material)),
+        transform: na::Matrix4::identity().into(),
+        previous_transform: None,
+        joint_matrices: None,
+        is_static: true,
+    };
+
+    let floor = quad(
+        [
+            [-2.0, -2.0, 0.0],
+            [2.0, -2.0, 0.0],
+            [2.0, 2.0, 0.0],
+            [-2.0, 2.0, 0.0],
+        ],
+        [0.0, 0.0, 1.0],
+    );
+    let wall = quad(
+        [
+            [0.0, 1.0, 0.0],
+            [0.0, -1.0, 0.0],
+            [0.0, -1.0, 1.5],
+            [0.0, 1.0, 1.5],
+        ],
+        [-1.0, 0.0, 0.0],
+    );
+    vec![
+        instance(
+            floor,
+            Material {
+                albedo: [0.6; 3].into(),
+                roughness: 0.7,
+                ..Default::default()
+            },
+        ),
+        instance(
+            wall,
+            Material {
+                albedo: [0.9, 0.6, 0.3].into(),
+                metallic: 1.0,
+                roughness: 0.3,
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+fn reference_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("reference")
+}
+
+fn read_png(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (info, mut reader) = png::Decoder::new(File::open(path)?).read_info()?;
+    if (info.width, info.height) != (WIDTH, HEIGHT)
+        || info.color_type != png::ColorType::RGBA
+        || info.bit_depth != png::BitDepth::Eight
+    {
+        return Err(format!("{} isn't {}x{} 8-bit RGBA", path.display(), WIDTH, HEIGHT).into());
+    }
+    let mut pixels = vec![0; info.buffer_size()];
+    reader.next_frame(&mut pixels)?;
+    Ok(pixels)
+}
+
+fn write_png(path: &Path, pixels: &[u8]) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), WIDTH, HEIGHT);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(pixels)?;
+    Ok(())
+}
+
+// How different two sRGB pixels look, as the distance between them in CIELAB. Alpha's ignored.
+fn delta_e(a: &[u8], b: &[u8]) -> f32 {
+    let (a, b) = (lab(a), lab(b));
+    (a - b).norm()
+}
+
+fn lab(pixel: &[u8]) -> na::Vector3<f32> {
+    let linear = |value: u8| {
+        let value = value as f32 / 255.0;
+        if value <= 0.04045 {
+            value / 12.92
+        } else {
+            ((value + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let rgb = na::Vector3::new(linear(pixel[0]), linear(pixel[1]), linear(pixel[2]));
+    // To XYZ, relative to D65 white
+    #[rustfmt::skip]
+    let to_xyz = na::Matrix3::new(
+        0.4124 / 0.9505, 0.3576 / 0.9505, 0.1805 / 0.9505,
+        0.2126,          0.7152,          0.0722,
+        0.0193 / 1.0890, 0.1192 / 1.0890, 0.9505 / 1.0890,
+    );
+    let f = (to_xyz * rgb).map(|t| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    });
+    na::Vector3::new(116.0 * f.y - 16.0, 500.0 * (f.x - f.y), 200.0 * (f.y - f.z))
+}